@@ -1,21 +1,42 @@
 pub mod host;
+mod latency;
+mod limiter;
 mod model;
 mod protocol;
+mod retry;
+mod single_flight;
+mod transport;
 
 use model::AgentInfo;
 
-use protocol::{Request, Response};
+use crate::cache::Cache;
+use chrono::{DateTime, Local};
+use protocol::{Request, Response, SeqAllocator};
 use serde::Serialize;
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU16};
 use std::sync::Arc;
 use tokio::sync::{broadcast, mpsc, oneshot, Mutex};
 
 pub type Result<T> = anyhow::Result<T>;
 
-pub use protocol::{RequestPayload, ResponsePayload};
+pub use latency::{LatencyReport, PayloadStats};
+pub use limiter::RequestLimiter;
+pub use model::{CourseScore, CourseScoreLine, CourseScoreRequest};
+#[cfg(test)]
+pub use model::{ItemError, PartialList};
+#[cfg(test)]
+pub use transport::mock::MockTransport;
+pub use transport::AgentTransport;
 
-#[derive(Debug, ToPrimitive, thiserror::Error)]
+tokio::task_local! {
+    /// Id of the HTTP request on whose behalf agents are requested, for tracing in logs.
+    pub static TRACE_ID: String;
+}
+pub use protocol::{check_read_sizes, ErrorResponse, RequestPayload, ResponsePayload};
+
+#[derive(Debug, Clone, Copy, ToPrimitive, thiserror::Error)]
 /// Business error of web socket host
 pub enum HostError {
     #[error("无可用的代理节点，无法连接到校园网")]
@@ -32,6 +53,26 @@ pub enum HostError {
     InvalidAgent = 125,
     #[error("Payload 过大")]
     TooLargePayload = 126,
+    #[error("找不到该代理节点")]
+    NoSuchAgent = 127,
+    #[error("服务正在关闭")]
+    ShuttingDown = 128,
+    #[error("响应校验失败")]
+    ChecksumMismatch = 129,
+    #[error("校园网账户认证失败, 请检查账号密码")]
+    CampusAuthFailed = 130,
+    #[error("校园网系统中未找到相关数据")]
+    CampusNotFound = 131,
+    #[error("数据帧格式错误")]
+    BadFrame = 132,
+    #[error("请求序列化失败")]
+    Serialize = 133,
+    #[error("响应解析失败")]
+    Deserialize = 134,
+    #[error("请求过多，请稍后再试")]
+    Overloaded = 135,
+    #[error("校园网系统返回未知错误")]
+    UnknownAgentError = 136,
 }
 
 /// Request queue in agent cache. When response received, use this queue to found the requester.
@@ -54,10 +95,16 @@ pub struct Agent {
     addr: SocketAddr,
     /// Request queue, used to callback when the response is received.
     queue: Arc<Mutex<RequestQueue>>,
+    /// Seqs of requests on the connection.
+    seqs: Arc<SeqAllocator>,
     /// Request channel to sender loop.
     channel: Option<mpsc::Sender<Request>>,
     /// Halt channel
     halt: Option<HaltChannel>,
+    /// Time the agent last responded a ping.
+    last_pong: Arc<std::sync::Mutex<Option<DateTime<Local>>>>,
+    /// Protocol version of frames sent on the connection, negotiated on the handshake.
+    version: Arc<AtomicU16>,
 }
 
 /// Agent state
@@ -75,8 +122,43 @@ pub struct AgentStatus {
     pub queue: u16,
 }
 
+/// State of an agent connection, for administrators.
+#[derive(Debug, Serialize)]
+pub struct AgentConnection {
+    /// Remote address of the agent, which is used as its id.
+    pub id: String,
+    pub name: String,
+    /// `busy` if any request is in flight, or `idle`.
+    pub state: &'static str,
+    /// Time the agent last responded a ping, `null` before the first heartbeat.
+    #[serde(rename = "lastHeartbeat")]
+    pub last_heartbeat: Option<DateTime<Local>>,
+    /// Requests sent and waiting for responses.
+    #[serde(rename = "inFlight")]
+    pub in_flight: usize,
+}
+
+/// Result of draining agent connections.
+#[derive(Debug, Default, Serialize)]
+pub struct DrainReport {
+    /// Connections closed.
+    pub drained: usize,
+    /// Connections closed with requests still in flight, after the drain timed out.
+    pub forced: usize,
+}
+
 /// Local, host.
 #[derive(Clone)]
 pub struct AgentManager {
     agents: Arc<Mutex<AgentMap>>,
+    /// Latest info reported by each agent, which rarely changes.
+    infos: Cache<SocketAddr, AgentInfo>,
+    /// Latency of recent requests.
+    latency: latency::LatencyRecorder,
+    /// Set on shutdown, to refuse new requests and agent connections.
+    closing: Arc<AtomicBool>,
+    /// Identical requests in flight, which share one round-trip.
+    flights: single_flight::SingleFlight,
+    /// Limit of concurrent requests.
+    limiter: RequestLimiter,
 }
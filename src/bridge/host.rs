@@ -1,14 +1,26 @@
-use super::model::{AgentInfo, AgentInfoRequest};
-use super::protocol::{Request, RequestPayload, Response, ResponsePayload};
-use super::{Agent, AgentManager, AgentStatus, HostError, RequestQueue};
+use super::model::{AgentInfo, AgentInfoRequest, PingRequest};
+use super::protocol::{
+    negotiate_version, Request, RequestPayload, Response, ResponsePayload, MIN_PROTOCOL_VERSION,
+    PING_VERSION, PROTOCOL_VERSION,
+};
+use super::retry::{with_retry, RetryPolicy};
+use super::single_flight::key_of;
+use super::{
+    Agent, AgentConnection, AgentManager, AgentStatus, DrainReport, HostError, LatencyReport,
+    PayloadStats, RequestLimiter, RequestQueue, TRACE_ID,
+};
+use crate::cache::Cache;
 use crate::config::CONFIG;
+use crate::metrics;
+use chrono::Local;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU16, Ordering};
 use std::sync::Arc;
 use std::time::Instant;
-use tokio::io::{AsyncWriteExt, BufReader, BufWriter};
+use tokio::io::{BufReader, BufWriter};
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{broadcast, mpsc, oneshot, Mutex, RwLock};
+use tokio::sync::{broadcast, mpsc, oneshot, Mutex};
 use tokio::time::Duration;
 
 use super::Result;
@@ -24,6 +36,38 @@ impl Clone for HaltChannel {
     }
 }
 
+/// Waiter of a request in the queue, which is removed once dropped: when the response arrives, the
+/// request times out, or the requester is cancelled, like when the HTTP client disconnects. The
+/// protocol has no cancel frame, so the agent still processes the request and its response is
+/// dropped.
+struct Pending {
+    queue: Arc<Mutex<RequestQueue>>,
+    seq: u64,
+    /// Taken and dropped first when dropped, which closes the waiter.
+    rx: Option<oneshot::Receiver<Response>>,
+}
+
+/// Remove the waiter of the seq if its receiver is gone. A waiter with an open receiver belongs to
+/// a new request given the same seq.
+fn remove_closed(queue: &mut RequestQueue, seq: u64) {
+    if queue.get(&seq).is_some_and(|x| x.is_closed()) {
+        queue.remove(&seq);
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.rx.take();
+        match self.queue.try_lock() {
+            Ok(mut queue) => remove_closed(&mut queue, self.seq),
+            Err(_) => {
+                let (queue, seq) = (self.queue.clone(), self.seq);
+                tokio::spawn(async move { remove_closed(&mut *queue.lock().await, seq) });
+            }
+        }
+    }
+}
+
 impl Agent {
     /// An agent instance.
     pub fn new(basic: AgentInfo, addr: SocketAddr) -> Self {
@@ -31,46 +75,77 @@ impl Agent {
             basic,
             addr,
             queue: Default::default(),
+            seqs: Default::default(),
             channel: None,
             halt: None,
+            last_pong: Default::default(),
+            version: Arc::new(AtomicU16::new(PROTOCOL_VERSION)),
         }
     }
 
+    /// Protocol version spoken on the connection.
+    fn version(&self) -> u16 {
+        self.version.load(Ordering::Relaxed)
+    }
+
     /// Send request to the agent
-    async fn send(&mut self, request: Request) -> Result<()> {
+    async fn send(&mut self, mut request: Request) -> Result<()> {
         let mut channel = self.channel.clone().ok_or(HostError::AgentUnavailable)?;
 
+        request.version = self.version();
         // Send request packet to the sender loop to post, if the process failed, set channel to None
         // so that the next try could return immediately.
-        channel.send(request).await.or_else(move |_| {
+        if channel.send(request).await.is_err() {
             self.channel = None;
-            Err(HostError::AgentUnavailable)
-        });
+            return Err(HostError::AgentUnavailable.into());
+        }
         Ok(())
     }
 
     /// Request to agent, and add an oneshot sender and it can be used when the response received.
     /// Return HostError timeout if the agent doesn't respond in a reasonal time.
     pub async fn request(&mut self, request: RequestPayload) -> Result<Response> {
-        let request = Request::new(request);
-        let seq = request.seq;
+        let request = Request::new(request, CONFIG.host.compress_threshold)?;
 
-        // Send the request to the sender loop
-        self.send(request).await?;
+        self.send_request(request).await
+    }
 
-        // Result channel, return rx to the caller and save the tx to the queue.
+    /// Send the request and wait for the response with the same seq. Requests are multiplexed over
+    /// the connection, and responses can arrive in any order.
+    async fn send_request(&mut self, mut request: Request) -> Result<Response> {
+        // Result channel, return rx to the caller and save the tx to the queue. Register it before
+        // sending, in case the response arrives first. The seq is allocated under the lock, so that
+        // it's unique among requests in flight on the connection.
         let (tx, rx) = oneshot::channel();
-        // Use a pair of big parentheses, to drop queue automatically.
-        {
+        let seq = {
             let mut queue = self.queue.lock().await;
+            let seq = self.seqs.next(|x| queue.contains_key(&x));
             queue.insert(seq, tx);
-        }
+            seq
+        };
+        let mut pending = Pending {
+            queue: self.queue.clone(),
+            seq,
+            rx: Some(rx),
+        };
+        request.seq = seq;
+        let trace_id = TRACE_ID
+            .try_with(Clone::clone)
+            .unwrap_or_else(|_| "-".to_string());
+
+        info!("Request {} to agent {} for {}", seq, self.addr, trace_id);
+
+        // Send the request to the sender loop
+        self.send(request).await?;
+        let rx = pending.rx.as_mut().expect("Receiver is taken only when dropped");
         match tokio::time::timeout(Duration::from_millis(5000), rx).await {
-            Ok(result) => Ok(result?),
+            // The sender is dropped if the connection is closed before response.
+            Ok(result) => Ok(result.map_err(|_| HostError::Disconnected)?),
             Err(_) => {
-                let mut queue = self.queue.lock().await;
-                queue.remove(&seq);
-
+                warn!(
+                    "Request {} to agent {} for {} timed out",
+                    seq, self.addr, trace_id
+                );
                 Err(HostError::Timeout.into())
             }
         }
@@ -83,7 +158,8 @@ impl Agent {
         if let Some(sender) = queue.remove(&response.ack) {
             sender.send(response);
         } else {
-            warn!("Received a response message without corresponding request.");
+            // Maybe the requester has timed out or been cancelled.
+            warn!("Dropped response {} without corresponding request.", response.ack);
         }
     }
 
@@ -99,11 +175,12 @@ impl Agent {
         loop {
             tokio::select! {
                 Some(request) = request_rx.recv() => {
-                    buffer.write_u64(request.seq).await?;
-                    buffer.write_u32(request.size).await?;
-                    buffer.write_all(&request.payload).await?;
-                    buffer.flush().await?;
-
+                    if let Err(e) = request.write_to(&mut buffer).await {
+                        // Mark the connection dead so that the agent can be reclaimed.
+                        warn!("Failed to send packet: {:?}", e);
+                        let _ = halt.sender.send(());
+                        break;
+                    }
                     info!("Send packet");
                 }
                 _ = halt.receiver.recv() => {
@@ -122,11 +199,12 @@ impl Agent {
         mut halt: HaltChannel,
     ) -> Result<()> {
         info!("Receiver loop started");
-        let mut buffer = BufReader::new(socket_rx);
+        let mut buffer = BufReader::with_capacity(CONFIG.host.read_buffer_size, socket_rx);
+        let read_timeout = Duration::from_secs(CONFIG.host.read_timeout);
 
         loop {
             tokio::select! {
-                result = Response::from_stream(&mut buffer) => {
+                result = Response::from_stream(&mut buffer, read_timeout, CONFIG.host.max_payload, CONFIG.host.read_chunk_size) => {
                     match result {
                         Ok(response) => {
                             info!("Packet received: {:?}", response);
@@ -144,27 +222,72 @@ impl Agent {
                 }
             }
         }
+        // Fail all pending requesters, rather than letting them wait until timeout.
+        queue.lock().await.clear();
         info!("Receiver loop exited.");
         Ok(())
     }
 
-    async fn heartbeat_loop(last_update: Arc<RwLock<Instant>>, mut tx: mpsc::Sender<Request>) {
-        let timeout = Duration::from_secs(20);
-        let mut f = false;
+    /// Ping the agent, and return whether it pongs in `timeout`. Agents of versions without ping
+    /// are taken as alive, whose dead connections are found on reading only.
+    async fn ping(&mut self, timeout: Duration) -> bool {
+        if self.version() < PING_VERSION {
+            return true;
+        }
+        // Ping is small, and never compressed.
+        let ping = async {
+            let ping = Request::new(PingRequest.into(), u32::MAX)?;
+            self.send_request(ping).await
+        };
+        let pong = match tokio::time::timeout(timeout, ping).await {
+            Ok(Ok(response)) => matches!(response.payload_of::<PingRequest>(), Ok(Ok(()))),
+            _ => false,
+        };
+        if pong {
+            *self.last_pong.lock().unwrap() = Some(Local::now());
+        }
+        pong
+    }
 
-        loop {
-            let elapsed = { last_update.read().await.elapsed().as_secs() };
-            if elapsed > 30 {
-                if !f {
-                    tx.send(Request::default()).await;
-                    f = true;
-                } else {
+    /// Wait until no request is in flight on the agents, for `grace` at most. Return whether they
+    /// are all idle.
+    async fn wait_idle(agents: &[Agent], grace: Duration) -> bool {
+        let drain = async {
+            loop {
+                let mut pending = 0;
+                for agent in agents {
+                    pending += agent.queue.lock().await.len();
+                }
+                if pending == 0 {
                     break;
                 }
-            } else {
-                f = false;
+                tokio::time::delay_for(Duration::from_millis(100)).await;
+            }
+        };
+        tokio::time::timeout(grace, drain).await.is_ok()
+    }
+
+    /// Heartbeat loop: ping the agent periodically, and close the connection if it doesn't pong in
+    /// time. Pings are multiplexed with other requests, so live traffic is not blocked.
+    async fn heartbeat_loop(mut agent: Agent, interval: Duration, timeout: Duration) {
+        let mut halt = match agent.halt.clone() {
+            Some(halt) => halt,
+            None => return,
+        };
+
+        loop {
+            tokio::select! {
+                _ = tokio::time::delay_for(interval) => (),
+                _ = halt.receiver.recv() => break,
+            }
+            if !agent.ping(timeout).await {
+                warn!(
+                    "Agent {} didn't respond to ping, closing the connection.",
+                    agent.addr
+                );
+                let _ = halt.sender.send(());
+                break;
             }
-            tokio::time::delay_for(timeout).await;
         }
         info!("Heartbeat loop exited.");
     }
@@ -191,12 +314,16 @@ impl Agent {
                 receiver: halt_tx.subscribe(),
             },
         ));
-        // tokio::spawn(Self::heartbeat_loop(self.last_update.clone(), tx.clone()));
         self.channel = Some(tx);
         self.halt = Some(HaltChannel {
             sender: halt_tx,
             receiver: halt_rx,
         });
+        tokio::spawn(Self::heartbeat_loop(
+            self.clone(),
+            Duration::from_secs(CONFIG.host.heartbeat_interval),
+            Duration::from_secs(CONFIG.host.heartbeat_timeout),
+        ));
     }
 
     pub fn available(&self) -> bool {
@@ -224,63 +351,330 @@ impl Agent {
 }
 
 impl AgentManager {
-    /// Create a new host instance.
-    pub fn new() -> Self {
+    /// Create a new host instance. Agent info is cached for `info_ttl`, and concurrent requests are
+    /// limited by `limiter`.
+    pub fn new(info_ttl: Duration, limiter: RequestLimiter) -> Self {
         info!("A Host instance created.");
         Self {
             agents: Arc::new(Default::default()),
+            infos: Cache::new(info_ttl),
+            latency: Default::default(),
+            closing: Default::default(),
+            flights: Default::default(),
+            limiter,
         }
     }
 
-    /// Select an agent randomly and send request packet.
+    /// Select an agent randomly and send request packet. Idempotent requests failed transiently are
+    /// retried, maybe on another agent, and share the response with identical ones in flight.
     pub async fn request(&self, request: RequestPayload) -> Result<Response> {
+        if self.closing.load(Ordering::Relaxed) {
+            return Err(HostError::ShuttingDown.into());
+        }
+        let policy = RetryPolicy::from(&CONFIG.host);
+        let idempotent = request.is_idempotent();
+        let key = if idempotent { key_of(&request) } else { None };
+        let mut tries = 0;
+
+        let result = with_retry(&policy, idempotent, || {
+            tries += 1;
+            async {
+                match &key {
+                    Some(key) => {
+                        self.flights
+                            .run(key.clone(), || self.request_once(request.clone()))
+                            .await
+                    }
+                    None => self.request_once(request.clone()).await,
+                }
+            }
+        })
+        .await;
+        if tries > 1 {
+            let kind = request.kind();
+            self.latency.record_retries(kind, tries - 1);
+            metrics::AGENT_RETRIES
+                .with_label_values(&[kind])
+                .inc_by(tries as u64 - 1);
+        }
+        result.map_err(into_host_error)
+    }
+
+    /// Select an agent randomly. If `probe_timeout` is given, the agent is pinged first, and
+    /// evicted if it doesn't pong in time, and then another one is selected. A busy agent may pong
+    /// late, so it's kept and selected anyway while requests are in flight on it, rather than
+    /// failing them all.
+    async fn checkout(&self, probe_timeout: Option<Duration>) -> Option<Agent> {
         use rand::prelude::IteratorRandom;
 
-        let mut rng = rand::thread_rng();
-        let mut agents = self.agents.lock().await;
+        loop {
+            // Take a handle of the agent, so that other requests are not blocked by this one.
+            let mut agent = {
+                let agents = self.agents.lock().await;
+                agents.values().choose(&mut rand::thread_rng()).cloned()?
+            };
+            let timeout = match probe_timeout {
+                Some(timeout) => timeout,
+                None => return Some(agent),
+            };
+            if agent.ping(timeout).await {
+                return Some(agent);
+            }
+            if !agent.queue.lock().await.is_empty() {
+                warn!("Agent {} is busy and failed the probe on checkout.", agent.addr);
+                return Some(agent);
+            }
+            warn!("Agent {} failed the probe on checkout, evicting it.", agent.addr);
+            // Maybe evicted by a concurrent checkout already.
+            let _ = self.disconnect(&agent.addr).await;
+        }
+    }
 
-        let agent = agents.iter_mut().choose(&mut rng);
+    async fn request_once(&self, request: RequestPayload) -> Result<Response> {
+        let _permit = self.limiter.acquire().await?;
+        let probe_timeout = if CONFIG.host.checkout_probe {
+            Some(Duration::from_millis(CONFIG.host.checkout_probe_timeout))
+        } else {
+            None
+        };
         // Send to an agent and record this request.
-        if let Some((_, agent)) = agent {
-            agent.request(request).await
+        if let Some(mut agent) = self.checkout(probe_timeout).await {
+            let kind = request.kind();
+            let request = Request::new(request, CONFIG.host.compress_threshold)?;
+
+            self.round_trip(&mut agent, kind, request).await
         } else {
             Err(HostError::NoAgentAvailable.into())
         }
     }
 
-    /// Get agent list
-    pub async fn get_agent_list(&self) -> Vec<AgentStatus> {
-        let agents = self.agents.lock().await;
+    /// Send the request to the agent, and record the time until the response by payload type.
+    async fn round_trip(
+        &self,
+        agent: &mut Agent,
+        kind: &'static str,
+        request: Request,
+    ) -> Result<Response> {
+        let start_time = Instant::now();
+        let in_flight = metrics::InFlight::new();
+        let result = agent.send_request(request).await;
+        drop(in_flight);
+
+        let elapsed = start_time.elapsed();
+        self.latency.record(kind, elapsed, result.is_ok());
+        metrics::AGENT_REQUESTS
+            .with_label_values(&[kind, if result.is_ok() { "ok" } else { "error" }])
+            .observe(elapsed.as_secs_f64());
+        result
+    }
+
+    /// Get latency of recent requests.
+    pub fn latency(&self) -> LatencyReport {
+        self.latency.report()
+    }
+
+    /// Get the summary of requests of each payload type.
+    pub fn stats(&self) -> Vec<PayloadStats> {
+        self.latency.stats()
+    }
+
+    /// Requests in flight and requests waiting, limited by the limiter.
+    pub fn utilization(&self) -> (usize, usize) {
+        (self.limiter.in_use(), self.limiter.waiting())
+    }
 
-        agents
-            .iter()
-            .map(|(_, agent)| AgentStatus {
+    /// Whether agents have spare capacity for background requests.
+    pub fn is_idle(&self) -> bool {
+        self.limiter.is_idle()
+    }
+
+    /// Close the connection to the agent, and fail its in-flight requests. The agent is expected to
+    /// reconnect on its own.
+    pub async fn disconnect(&self, addr: &SocketAddr) -> Result<()> {
+        let agent = {
+            let mut agents = self.agents.lock().await;
+            agents.remove(addr)
+        };
+        let mut agent = agent.ok_or(HostError::NoSuchAgent)?;
+
+        self.infos.remove(addr);
+        agent.stop();
+        // Drop all the senders so that requesters waiting for responses get errors.
+        agent.queue.lock().await.clear();
+        Ok(())
+    }
+
+    /// Refuse new requests, wait for in-flight requests to complete in `grace` at most, and then
+    /// close connections to agents, so that no response is cut off halfway.
+    pub async fn shutdown(&self, grace: Duration) {
+        self.closing.store(true, Ordering::Relaxed);
+
+        let agents: Vec<Agent> = self.agents.lock().await.values().cloned().collect();
+        if !Agent::wait_idle(&agents, grace).await {
+            warn!("Closing agent connections with requests in flight.");
+        }
+        for agent in self.agents.lock().await.values_mut() {
+            agent.stop();
+        }
+        info!("Agent connections closed.");
+    }
+
+    /// Take all agents out of the pool, so that new requests aren't sent to them, wait for their
+    /// in-flight requests to complete in `grace` at most, and then close the connections. Agents
+    /// are expected to reconnect on their own, which rebuilds the pool. Requests still in flight at
+    /// last fail.
+    pub async fn drain(&self, grace: Duration) -> DrainReport {
+        let mut agents: Vec<Agent> = self.agents.lock().await.drain().map(|(_, x)| x).collect();
+        for agent in &agents {
+            self.infos.remove(&agent.addr);
+        }
+
+        Agent::wait_idle(&agents, grace).await;
+        let mut report = DrainReport::default();
+        for agent in agents.iter_mut() {
+            let mut queue = agent.queue.lock().await;
+            if !queue.is_empty() {
+                warn!(
+                    "Closing agent {} with {} requests in flight.",
+                    agent.addr,
+                    queue.len()
+                );
+                report.forced += 1;
+                queue.clear();
+            }
+            drop(queue);
+            agent.stop();
+            report.drained += 1;
+        }
+        info!("{} agent connections drained.", report.drained);
+        report
+    }
+
+    /// State of each agent connection.
+    pub async fn connections(&self) -> Vec<AgentConnection> {
+        let agents: Vec<Agent> = self.agents.lock().await.values().cloned().collect();
+        let mut connections = Vec::new();
+
+        for agent in agents {
+            let in_flight = agent.queue.lock().await.len();
+            connections.push(AgentConnection {
+                id: agent.addr.to_string(),
                 name: agent.basic.name.clone(),
+                state: if in_flight > 0 { "busy" } else { "idle" },
+                last_heartbeat: *agent.last_pong.lock().unwrap(),
+                in_flight,
+            });
+        }
+        connections.sort_by(|a, b| a.id.cmp(&b.id));
+        connections
+    }
+
+    /// Get info of the agent. Cached info is answered at once, and refreshed in background if
+    /// expired. `force` queries the agent and waits for it.
+    pub async fn agent_info(&self, addr: &SocketAddr, force: bool) -> Result<AgentInfo> {
+        if !force {
+            if let Some(info) = self.infos.get(addr) {
+                return Ok(info);
+            }
+            if let Some(info) = self.infos.get_stale(addr) {
+                let this = self.clone();
+                let addr = *addr;
+                tokio::spawn(async move {
+                    if let Err(e) = this.refresh_info(&addr).await {
+                        warn!("Failed to refresh info of agent {}: {}", addr, e);
+                    }
+                });
+                return Ok(info);
+            }
+        }
+        self.refresh_info(addr).await
+    }
+
+    /// Query the agent for its info, and cache it.
+    async fn refresh_info(&self, addr: &SocketAddr) -> Result<AgentInfo> {
+        let agent = self.agents.lock().await.get(addr).cloned();
+        let mut agent = agent.ok_or(HostError::NoSuchAgent)?;
+
+        // The request is small, and never compressed.
+        let request = Request::new(AgentInfoRequest.into(), u32::MAX)?;
+        let info = agent
+            .send_request(request)
+            .await?
+            .payload_of::<AgentInfoRequest>()??;
+        // Not cached again if the agent is evicted meanwhile.
+        let agents = self.agents.lock().await;
+        if agents.contains_key(addr) {
+            self.infos.insert(*addr, info.clone());
+        }
+        Ok(info)
+    }
+
+    /// Count of agents connected.
+    pub async fn agent_count(&self) -> usize {
+        self.agents.lock().await.len()
+    }
+
+    /// Get agent list, with cached agent info. `refresh` queries each agent for its info.
+    pub async fn get_agent_list(&self, refresh: bool) -> Vec<AgentStatus> {
+        let addrs: Vec<SocketAddr> = self.agents.lock().await.keys().copied().collect();
+        let mut list = Vec::new();
+
+        for addr in addrs {
+            let info = match self.agent_info(&addr, refresh).await {
+                Ok(info) => info,
+                // Agents which fail to refresh are listed with the last info.
+                Err(_) => match self.infos.get_stale(&addr) {
+                    Some(info) => info,
+                    None => continue,
+                },
+            };
+            list.push(AgentStatus {
+                name: info.name,
                 intranet_addr: "".to_string(),
-                external_addr: agent.addr.to_string(),
+                external_addr: addr.to_string(),
                 queue: 0u16,
-            })
-            .collect()
+            });
+        }
+        list
     }
 
     async fn start(&self, stream: TcpStream, peer: SocketAddr) -> Result<()> {
         info!("New agent connection established: {}", peer.to_string());
         let mut agent = Agent::new(AgentInfo { name: "".to_string() }, peer);
 
+        // The handshake is in the oldest version, which all agents understand.
+        agent.version.store(MIN_PROTOCOL_VERSION, Ordering::Relaxed);
         agent.start(stream).await;
-        let response = agent
-            .request(RequestPayload::AgentInfo(AgentInfoRequest))
-            .await
-            .map_err(|_| HostError::AgentUnavailable)?;
-        if let ResponsePayload::AgentInfo(base_info) = response.payload()?? {
-            agent.basic = base_info;
+        let handshake = agent.request(RequestPayload::AgentInfo(AgentInfoRequest));
+        let response =
+            match tokio::time::timeout(Duration::from_secs(CONFIG.host.handshake_timeout), handshake)
+                .await
             {
-                let mut agents = self.agents.lock().await;
-                agents.insert(peer, agent);
+                Ok(Ok(response)) => response,
+                _ => {
+                    // Close the connection, or its loops are left running.
+                    agent.stop();
+                    return Err(HostError::AgentUnavailable.into());
+                }
+            };
+        let version = negotiate_version(response.version);
+        agent.version.store(version, Ordering::Relaxed);
+        info!("Agent {} speaks protocol version {}.", peer, version);
+        match response.payload()?? {
+            ResponsePayload::AgentInfo(base_info) => {
+                agent.basic = base_info.clone();
+                {
+                    let mut agents = self.agents.lock().await;
+                    agents.insert(peer, agent);
+                }
+                self.infos.insert(peer, base_info);
+                Ok(())
             }
-            Ok(())
-        } else {
-            Err(HostError::AgentUnavailable.into())
+            ResponsePayload::Unknown(payload) => {
+                warn!("Unknown payload (tag {}) received from {}.", payload.tag, peer);
+                Err(HostError::BadResponse.into())
+            }
+            _ => Err(HostError::AgentUnavailable.into()),
         }
     }
 
@@ -297,15 +691,35 @@ impl AgentManager {
                 // Clear agent in agent list and return
                 let mut agents = self.agents.lock().await;
                 agents.remove(&peer);
+                self.infos.remove(&peer);
+                if agents.len() < CONFIG.host.min_idle {
+                    warn!("Only {} agents connected, fewer than expected.", agents.len());
+                }
             }
             None => (),
         }
     }
 
+    /// Whether another agent can be accepted, with at most `max` agents connected.
+    async fn has_vacancy(&self, max: usize) -> bool {
+        self.agents.lock().await.len() < max
+    }
+
     pub async fn agent_main(&self) -> Result<()> {
         let mut listener = TcpListener::bind(&CONFIG.host.bind).await?;
+        info!(
+            "Waiting for agents, with protocol versions {} to {}.",
+            MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        );
 
         while let Ok((stream, peer)) = listener.accept().await {
+            if self.closing.load(Ordering::Relaxed) {
+                break;
+            }
+            if !self.has_vacancy(CONFIG.host.max as usize).await {
+                warn!("Refused agent {}, since there are too many agents.", peer);
+                continue;
+            }
             info!("New agent connection established, with {}", peer);
 
             let new_handler = self.clone();
@@ -324,3 +738,584 @@ impl AgentManager {
         Ok(())
     }
 }
+
+/// Agent error of failures while talking to the agent, like IO errors and timeouts, so that they're
+/// reported as agent errors rather than internal ones.
+fn into_host_error(e: anyhow::Error) -> anyhow::Error {
+    use std::io::ErrorKind;
+
+    if e.is::<tokio::time::Elapsed>() {
+        return HostError::Timeout.into();
+    }
+    let kind = match e.downcast_ref::<std::io::Error>() {
+        Some(io_error) => io_error.kind(),
+        None => return e,
+    };
+    match kind {
+        ErrorKind::TimedOut | ErrorKind::WouldBlock => HostError::Timeout.into(),
+        ErrorKind::ConnectionReset
+        | ErrorKind::ConnectionAborted
+        | ErrorKind::BrokenPipe
+        | ErrorKind::UnexpectedEof => HostError::Disconnected.into(),
+        ErrorKind::InvalidData => HostError::BadFrame.into(),
+        _ => e,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_into_host_error() {
+        use std::io::{Error, ErrorKind};
+
+        let error_of = |kind| into_host_error(Error::from(kind).into());
+
+        let e = error_of(ErrorKind::TimedOut);
+        assert!(matches!(e.downcast_ref::<HostError>(), Some(HostError::Timeout)));
+        let e = error_of(ErrorKind::ConnectionReset);
+        assert!(matches!(
+            e.downcast_ref::<HostError>(),
+            Some(HostError::Disconnected)
+        ));
+        let e = error_of(ErrorKind::InvalidData);
+        assert!(matches!(e.downcast_ref::<HostError>(), Some(HostError::BadFrame)));
+        // Other IO errors are kept.
+        let e = error_of(ErrorKind::PermissionDenied);
+        assert!(e.downcast_ref::<Error>().is_some());
+    }
+
+    fn manager() -> AgentManager {
+        AgentManager::new(
+            Duration::from_secs(60),
+            RequestLimiter::new(16, 16, Duration::from_secs(1)),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_agent() {
+        let manager = manager();
+        let addr: SocketAddr = "127.0.0.1:1040".parse().unwrap();
+        let agent = Agent::new(
+            AgentInfo {
+                name: "test".to_string(),
+            },
+            addr,
+        );
+        let (tx, rx) = oneshot::channel();
+
+        agent.queue.lock().await.insert(1, tx);
+        manager.agents.lock().await.insert(addr, agent);
+
+        manager.disconnect(&addr).await.unwrap();
+        assert_eq!(manager.agent_count().await, 0);
+        assert!(rx.await.is_err());
+        assert!(manager.disconnect(&addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_detects_dead_agent() {
+        let mut agent = Agent::new(
+            AgentInfo {
+                name: "test".to_string(),
+            },
+            "127.0.0.1:1040".parse().unwrap(),
+        );
+        // The agent never responds.
+        let (tx, _rx) = mpsc::channel::<Request>(8);
+        let (halt_tx, halt_rx) = broadcast::channel(1);
+        agent.channel = Some(tx);
+        agent.halt = Some(HaltChannel {
+            sender: halt_tx,
+            receiver: halt_rx,
+        });
+
+        let heartbeat = Agent::heartbeat_loop(
+            agent.clone(),
+            Duration::from_millis(50),
+            Duration::from_millis(100),
+        );
+        tokio::time::timeout(Duration::from_secs(1), heartbeat)
+            .await
+            .unwrap();
+        // The connection is closed, and `AgentManager::wait` will remove the agent.
+        assert!(agent.halt.unwrap().receiver.try_recv().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_old_agent_not_pinged() {
+        let mut agent = Agent::new(
+            AgentInfo {
+                name: "test".to_string(),
+            },
+            "127.0.0.1:1040".parse().unwrap(),
+        );
+        let (tx, mut rx) = mpsc::channel::<Request>(8);
+        agent.channel = Some(tx);
+        agent.version.store(MIN_PROTOCOL_VERSION, Ordering::Relaxed);
+
+        // Taken as alive, without sending a ping it doesn't understand.
+        assert!(agent.ping(Duration::from_millis(50)).await);
+        assert!(rx.try_recv().is_err());
+
+        // Requests are sent in the version of the agent.
+        let request = Request::new(RequestPayload::AgentInfo(AgentInfoRequest), u32::MAX).unwrap();
+        agent.send(request).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap().version, MIN_PROTOCOL_VERSION);
+    }
+
+    #[tokio::test]
+    async fn test_cached_agent_info() {
+        let manager = manager();
+        let addr: SocketAddr = "127.0.0.1:1040".parse().unwrap();
+        let mut agent = Agent::new(
+            AgentInfo {
+                name: "test".to_string(),
+            },
+            addr,
+        );
+        let (tx, mut rx) = mpsc::channel::<Request>(8);
+        agent.channel = Some(tx);
+
+        // Mock agent which counts requests, and reports its name "a".
+        let queue = agent.queue.clone();
+        let round_trips = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = round_trips.clone();
+        tokio::spawn(async move {
+            while let Some(request) = rx.recv().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let payload = vec![0u8, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, b'a'];
+                let response = Response {
+                    ack: request.seq,
+                    size: payload.len() as u32,
+                    payload,
+                    ..Default::default()
+                };
+                Agent::dispatch_response(queue.clone(), response).await;
+            }
+        });
+        manager.agents.lock().await.insert(addr, agent);
+
+        assert_eq!(manager.agent_info(&addr, false).await.unwrap().name, "a");
+        assert_eq!(manager.agent_info(&addr, false).await.unwrap().name, "a");
+        assert_eq!(round_trips.load(Ordering::SeqCst), 1);
+        // Forced to refresh.
+        manager.agent_info(&addr, true).await.unwrap();
+        assert_eq!(round_trips.load(Ordering::SeqCst), 2);
+
+        // Dropped with the agent.
+        manager.disconnect(&addr).await.unwrap();
+        assert!(manager.infos.get_stale(&addr).is_none());
+        assert!(manager.agent_info(&addr, false).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_round_trip_updates_stats() {
+        let manager = manager();
+        let addr: SocketAddr = "127.0.0.1:1040".parse().unwrap();
+        let mut agent = Agent::new(
+            AgentInfo {
+                name: "test".to_string(),
+            },
+            addr,
+        );
+        let (tx, mut rx) = mpsc::channel::<Request>(8);
+        agent.channel = Some(tx);
+
+        // Mock agent which pongs.
+        let queue = agent.queue.clone();
+        tokio::spawn(async move {
+            while let Some(request) = rx.recv().await {
+                let payload = vec![3u8, 0, 0, 0];
+                let response = Response {
+                    ack: request.seq,
+                    size: payload.len() as u32,
+                    payload,
+                    ..Default::default()
+                };
+                Agent::dispatch_response(queue.clone(), response).await;
+            }
+        });
+        assert!(manager.stats().is_empty());
+
+        let request = Request::new(PingRequest.into(), u32::MAX).unwrap();
+        manager.round_trip(&mut agent, "Ping", request).await.unwrap();
+        let stats = manager.stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(
+            (stats[0].payload, stats[0].ok, stats[0].errors, stats[0].retries),
+            ("Ping", 1, 0, 0)
+        );
+        assert!(stats[0].p50.is_some());
+        assert_eq!(stats[0].max, stats[0].p50);
+    }
+
+    #[tokio::test]
+    async fn test_checkout_evicts_dead_agent() {
+        let manager = manager();
+        let (dead_addr, live_addr): (SocketAddr, SocketAddr) = (
+            "127.0.0.1:1040".parse().unwrap(),
+            "127.0.0.1:1041".parse().unwrap(),
+        );
+        let info = || AgentInfo {
+            name: "test".to_string(),
+        };
+
+        // The dead agent never responds.
+        let mut dead = Agent::new(info(), dead_addr);
+        let (tx, _dead_rx) = mpsc::channel::<Request>(8);
+        dead.channel = Some(tx);
+        // The live agent pongs.
+        let mut live = Agent::new(info(), live_addr);
+        let (tx, mut rx) = mpsc::channel::<Request>(8);
+        live.channel = Some(tx);
+        let queue = live.queue.clone();
+        tokio::spawn(async move {
+            while let Some(request) = rx.recv().await {
+                let payload = vec![3u8, 0, 0, 0];
+                let response = Response {
+                    ack: request.seq,
+                    size: payload.len() as u32,
+                    payload,
+                    ..Default::default()
+                };
+                Agent::dispatch_response(queue.clone(), response).await;
+            }
+        });
+        manager.agents.lock().await.insert(dead_addr, dead);
+        manager.agents.lock().await.insert(live_addr, live);
+
+        // Whichever is selected first, the live one is returned, and the dead one is evicted.
+        for _ in 0..2 {
+            let agent = manager.checkout(Some(Duration::from_millis(100))).await.unwrap();
+            assert_eq!(agent.addr, live_addr);
+        }
+        // The dead one is evicted at last, if not selected above.
+        manager.agents.lock().await.remove(&live_addr);
+        assert!(manager.checkout(Some(Duration::from_millis(100))).await.is_none());
+        assert_eq!(manager.agent_count().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_checkout_keeps_busy_agent() {
+        let manager = manager();
+        let addr: SocketAddr = "127.0.0.1:1040".parse().unwrap();
+        // The agent is too busy to respond the probe in time.
+        let mut agent = Agent::new(
+            AgentInfo {
+                name: "test".to_string(),
+            },
+            addr,
+        );
+        let (tx, _rx) = mpsc::channel::<Request>(8);
+        agent.channel = Some(tx);
+        let (pending, _) = oneshot::channel();
+        agent.queue.lock().await.insert(1000, pending);
+        manager.agents.lock().await.insert(addr, agent);
+
+        let agent = manager.checkout(Some(Duration::from_millis(50))).await.unwrap();
+        assert_eq!(agent.addr, addr);
+        assert_eq!(manager.agent_count().await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_agent_vacancy() {
+        let manager = manager();
+        let addr: SocketAddr = "127.0.0.1:1040".parse().unwrap();
+        let agent = Agent::new(
+            AgentInfo {
+                name: "test".to_string(),
+            },
+            addr,
+        );
+
+        assert!(manager.has_vacancy(1).await);
+        manager.agents.lock().await.insert(addr, agent);
+        assert!(!manager.has_vacancy(1).await);
+        assert!(manager.has_vacancy(2).await);
+    }
+
+    #[tokio::test]
+    async fn test_multiplexed_requests() {
+        let mut agent = Agent::new(
+            AgentInfo {
+                name: "test".to_string(),
+            },
+            "127.0.0.1:1040".parse().unwrap(),
+        );
+        let (tx, mut rx) = mpsc::channel::<Request>(8);
+        agent.channel = Some(tx);
+
+        // Mock agent which responds the two requests in reverse order, plus an unknown one. The code
+        // is the payload size of the request.
+        let queue = agent.queue.clone();
+        tokio::spawn(async move {
+            let first = rx.recv().await.unwrap();
+            let second = rx.recv().await.unwrap();
+
+            for (seq, code) in &[(0, 0), (second.seq, second.size), (first.seq, first.size)] {
+                let response = Response {
+                    ack: *seq,
+                    code: *code as u16,
+                    ..Default::default()
+                };
+                Agent::dispatch_response(queue.clone(), response).await;
+            }
+        });
+
+        let (mut agent1, mut agent2) = (agent.clone(), agent.clone());
+        let (r1, r2) = tokio::join!(
+            agent1.send_request(make_request(1)),
+            agent2.send_request(make_request(2))
+        );
+        assert_eq!(r1.unwrap().code, 1);
+        assert_eq!(r2.unwrap().code, 2);
+        assert!(agent.queue.lock().await.is_empty());
+    }
+
+    fn make_request(size: u32) -> Request {
+        Request {
+            size,
+            payload: vec![0u8; size as usize],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_seqs_per_connection() {
+        // Mock agents which respond in reverse order of every batch, with the code
+        // `connection * 100 + request payload size`.
+        let mut agents = Vec::new();
+        for connection in 1..=2u32 {
+            let mut agent = Agent::new(
+                AgentInfo {
+                    name: "test".to_string(),
+                },
+                format!("127.0.0.1:104{}", connection).parse().unwrap(),
+            );
+            let (tx, mut rx) = mpsc::channel::<Request>(16);
+            agent.channel = Some(tx);
+            let queue = agent.queue.clone();
+            tokio::spawn(async move {
+                loop {
+                    let mut batch = Vec::new();
+                    for _ in 0..8 {
+                        match rx.recv().await {
+                            Some(request) => batch.push(request),
+                            None => return,
+                        }
+                    }
+                    for request in batch.into_iter().rev() {
+                        let response = Response {
+                            ack: request.seq,
+                            code: (connection * 100 + request.size) as u16,
+                            ..Default::default()
+                        };
+                        Agent::dispatch_response(queue.clone(), response).await;
+                    }
+                }
+            });
+            agents.push(agent);
+        }
+
+        // Both connections allocate seqs concurrently, and they overlap.
+        let requests = (0..16u32).map(|i| {
+            let connection = i % 2;
+            let mut agent = agents[connection as usize].clone();
+            async move {
+                let response = agent.send_request(make_request(i)).await.unwrap();
+                assert_eq!(response.code as u32, (connection + 1) * 100 + i);
+            }
+        });
+        futures::future::join_all(requests).await;
+        for agent in &agents {
+            assert!(agent.queue.lock().await.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_requests() {
+        let manager = manager();
+        let addr: SocketAddr = "127.0.0.1:1040".parse().unwrap();
+        let agent = Agent::new(
+            AgentInfo {
+                name: "test".to_string(),
+            },
+            addr,
+        );
+        let (tx, _rx) = oneshot::channel();
+        let queue = agent.queue.clone();
+
+        queue.lock().await.insert(1, tx);
+        manager.agents.lock().await.insert(addr, agent);
+        // The response arrives during shutdown.
+        tokio::spawn(async move {
+            tokio::time::delay_for(Duration::from_millis(200)).await;
+            queue.lock().await.clear();
+        });
+
+        let start_time = Instant::now();
+        manager.shutdown(Duration::from_secs(5)).await;
+        let elapsed = start_time.elapsed();
+        assert!(elapsed >= Duration::from_millis(200) && elapsed < Duration::from_secs(5));
+        assert!(manager
+            .request(RequestPayload::AgentInfo(AgentInfoRequest))
+            .await
+            .is_err());
+    }
+
+    /// Agent which pongs, and holds other requests until `release` is sent.
+    fn holding_agent(addr: &str) -> (Agent, mpsc::Sender<()>) {
+        let mut agent = Agent::new(
+            AgentInfo {
+                name: "test".to_string(),
+            },
+            addr.parse().unwrap(),
+        );
+        let (tx, mut rx) = mpsc::channel::<Request>(8);
+        let (release_tx, mut release_rx) = mpsc::channel::<()>(1);
+        let (halt_tx, halt_rx) = broadcast::channel(1);
+        agent.channel = Some(tx);
+        agent.halt = Some(HaltChannel {
+            sender: halt_tx,
+            receiver: halt_rx,
+        });
+
+        let queue = agent.queue.clone();
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            loop {
+                tokio::select! {
+                    Some(request) = rx.recv() => {
+                        if request.payload == vec![3u8, 0, 0, 0] {
+                            let response = Response {
+                                ack: request.seq,
+                                size: 4,
+                                payload: vec![3u8, 0, 0, 0],
+                                ..Default::default()
+                            };
+                            Agent::dispatch_response(queue.clone(), response).await;
+                        } else {
+                            held.push(request.seq);
+                        }
+                    }
+                    Some(()) = release_rx.recv() => {
+                        for seq in held.drain(..) {
+                            let response = Response { ack: seq, ..Default::default() };
+                            Agent::dispatch_response(queue.clone(), response).await;
+                        }
+                    }
+                    else => break,
+                }
+            }
+        });
+        (agent, release_tx)
+    }
+
+    #[tokio::test]
+    async fn test_connection_status() {
+        let manager = manager();
+        let (mut busy, _release) = holding_agent("127.0.0.1:1041");
+        let (mut idle, _) = holding_agent("127.0.0.1:1040");
+        manager.agents.lock().await.insert(busy.addr, busy.clone());
+        manager.agents.lock().await.insert(idle.addr, idle.clone());
+
+        assert!(idle.ping(Duration::from_millis(100)).await);
+        tokio::spawn(async move { busy.send_request(make_request(1)).await });
+        tokio::time::delay_for(Duration::from_millis(20)).await;
+
+        let connections = manager.connections().await;
+        assert_eq!(connections.len(), 2);
+        assert_eq!(connections[0].id, "127.0.0.1:1040");
+        assert_eq!(connections[0].state, "idle");
+        assert_eq!(connections[0].in_flight, 0);
+        assert!(connections[0].last_heartbeat.is_some());
+        assert_eq!(connections[1].state, "busy");
+        assert_eq!(connections[1].in_flight, 1);
+        assert!(connections[1].last_heartbeat.is_none());
+
+        let json = serde_json::to_value(&connections[1]).unwrap();
+        assert_eq!(json["inFlight"], 1);
+        assert!(json["lastHeartbeat"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_drain_agents() {
+        let manager = manager();
+        let (agent, mut release) = holding_agent("127.0.0.1:1040");
+        manager.agents.lock().await.insert(agent.addr, agent.clone());
+
+        let mut requester = agent.clone();
+        let request = tokio::spawn(async move { requester.send_request(make_request(1)).await });
+        tokio::time::delay_for(Duration::from_millis(20)).await;
+
+        let drainer = manager.clone();
+        let drain = tokio::spawn(async move { drainer.drain(Duration::from_secs(5)).await });
+        tokio::time::delay_for(Duration::from_millis(20)).await;
+        // Out of the pool at once, but not closed while the request is in flight.
+        assert_eq!(manager.agent_count().await, 0);
+        let mut halt = agent.halt.clone().unwrap();
+        assert!(halt.receiver.try_recv().is_err());
+
+        // The request completes, and then the connection is closed.
+        release.send(()).await.unwrap();
+        assert!(request.await.unwrap().is_ok());
+        let report = drain.await.unwrap();
+        assert_eq!((report.drained, report.forced), (1, 0));
+        assert!(halt.receiver.try_recv().is_ok());
+
+        // Requests still in flight after the timeout fail.
+        let (agent, _release) = holding_agent("127.0.0.1:1041");
+        manager.agents.lock().await.insert(agent.addr, agent.clone());
+        let mut requester = agent.clone();
+        let request = tokio::spawn(async move { requester.send_request(make_request(1)).await });
+        tokio::time::delay_for(Duration::from_millis(20)).await;
+        let report = manager.drain(Duration::from_millis(200)).await;
+        assert_eq!((report.drained, report.forced), (1, 1));
+        assert!(request.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_request_releases_slot() {
+        let mut agent = Agent::new(
+            AgentInfo {
+                name: "test".to_string(),
+            },
+            "127.0.0.1:1040".parse().unwrap(),
+        );
+        // Mock agent which never responds.
+        let (tx, mut rx) = mpsc::channel::<Request>(8);
+        agent.channel = Some(tx);
+        let limiter = RequestLimiter::new(1, 0, Duration::from_secs(1));
+        let queue = agent.queue.clone();
+
+        for &contended in &[false, true] {
+            let mut agent = agent.clone();
+            let permits = limiter.clone();
+            let mut request = Box::pin(async move {
+                let _permit = permits.acquire().await?;
+                agent.send_request(make_request(1)).await
+            });
+            // Wait until the request is sent, and cancel it, like when the HTTP client disconnects.
+            tokio::select! {
+                _ = &mut request => unreachable!(),
+                _ = rx.recv() => (),
+            }
+            assert_eq!(queue.lock().await.len(), 1);
+            assert_eq!(limiter.in_use(), 1);
+
+            if contended {
+                // The waiter is removed once the queue is unlocked.
+                let locked = queue.lock().await;
+                drop(request);
+                drop(locked);
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            } else {
+                drop(request);
+            }
+            assert!(queue.lock().await.is_empty());
+            assert_eq!(limiter.in_use(), 0);
+        }
+    }
+}
@@ -0,0 +1,211 @@
+//! Timing of recent agent requests, for performance investigation and capacity planning.
+
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Count of latest samples kept.
+const MAX_SAMPLES: usize = 100;
+/// Count of latest latencies kept of each payload type.
+const MAX_SAMPLES_PER_PAYLOAD: usize = 100;
+
+/// Timing of an agent request.
+#[derive(Clone, Serialize)]
+pub struct LatencySample {
+    /// Request payload type.
+    pub payload: &'static str,
+    /// Latency in milliseconds.
+    pub latency: f64,
+    /// Whether the request succeeded.
+    pub ok: bool,
+}
+
+/// Recent samples and percentiles of latency in milliseconds.
+#[derive(Serialize)]
+pub struct LatencyReport {
+    pub samples: Vec<LatencySample>,
+    pub p50: Option<f64>,
+    pub p95: Option<f64>,
+    pub p99: Option<f64>,
+}
+
+/// Summary of requests of a payload type. Counts are since the start of the process, and
+/// percentiles are of the latest requests, in milliseconds.
+#[derive(Debug, Serialize)]
+pub struct PayloadStats {
+    pub payload: &'static str,
+    /// Requests succeeded.
+    pub ok: u64,
+    /// Requests failed, each try counted.
+    pub errors: u64,
+    /// Tries after transient failures.
+    pub retries: u64,
+    pub p50: Option<f64>,
+    pub p95: Option<f64>,
+    pub max: Option<f64>,
+}
+
+#[derive(Default)]
+struct PayloadTotals {
+    ok: u64,
+    errors: u64,
+    retries: u64,
+    /// Latest latencies in milliseconds.
+    latencies: VecDeque<f64>,
+}
+
+/// Ring buffer of the latest request latencies, and a summary of each payload type.
+#[derive(Clone)]
+pub struct LatencyRecorder {
+    samples: Arc<Mutex<VecDeque<LatencySample>>>,
+    capacity: usize,
+    by_payload: Arc<Mutex<HashMap<&'static str, PayloadTotals>>>,
+}
+
+impl Default for LatencyRecorder {
+    fn default() -> Self {
+        Self::new(MAX_SAMPLES)
+    }
+}
+
+impl LatencyRecorder {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            samples: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+            by_payload: Default::default(),
+        }
+    }
+
+    /// Record a request, and drop the oldest sample if the buffer is full.
+    pub fn record(&self, payload: &'static str, elapsed: Duration, ok: bool) {
+        let latency = elapsed.as_secs_f64() * 1000.0;
+        {
+            let mut samples = self.samples.lock().unwrap();
+
+            if samples.len() == self.capacity {
+                samples.pop_front();
+            }
+            samples.push_back(LatencySample { payload, latency, ok });
+        }
+        let mut by_payload = self.by_payload.lock().unwrap();
+        let totals = by_payload.entry(payload).or_default();
+
+        if ok {
+            totals.ok += 1;
+        } else {
+            totals.errors += 1;
+        }
+        if totals.latencies.len() == MAX_SAMPLES_PER_PAYLOAD {
+            totals.latencies.pop_front();
+        }
+        totals.latencies.push_back(latency);
+    }
+
+    /// Record retries of a request.
+    pub fn record_retries(&self, payload: &'static str, count: u32) {
+        if count > 0 {
+            self.by_payload
+                .lock()
+                .unwrap()
+                .entry(payload)
+                .or_default()
+                .retries += count as u64;
+        }
+    }
+
+    /// Get the summary of each payload type, by name.
+    pub fn stats(&self) -> Vec<PayloadStats> {
+        let by_payload = self.by_payload.lock().unwrap();
+        let mut stats: Vec<PayloadStats> = by_payload
+            .iter()
+            .map(|(payload, totals)| {
+                let mut latencies: Vec<f64> = totals.latencies.iter().copied().collect();
+                latencies.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+                PayloadStats {
+                    payload,
+                    ok: totals.ok,
+                    errors: totals.errors,
+                    retries: totals.retries,
+                    p50: percentile(&latencies, 50),
+                    p95: percentile(&latencies, 95),
+                    max: latencies.last().copied(),
+                }
+            })
+            .collect();
+        stats.sort_by_key(|x| x.payload);
+        stats
+    }
+
+    /// Get recorded samples and their percentiles.
+    pub fn report(&self) -> LatencyReport {
+        let samples: Vec<LatencySample> = self.samples.lock().unwrap().iter().cloned().collect();
+        let mut latencies: Vec<f64> = samples.iter().map(|x| x.latency).collect();
+        latencies.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        LatencyReport {
+            p50: percentile(&latencies, 50),
+            p95: percentile(&latencies, 95),
+            p99: percentile(&latencies, 99),
+            samples,
+        }
+    }
+}
+
+/// Get percentile of sorted values by the nearest-rank method.
+fn percentile(sorted: &[f64], p: usize) -> Option<f64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (p * sorted.len()).div_ceil(100).max(1);
+    Some(sorted[rank - 1])
+}
+
+#[cfg(test)]
+mod test {
+    use super::LatencyRecorder;
+    use std::time::Duration;
+
+    #[test]
+    pub fn test_latency_percentiles() {
+        let recorder = LatencyRecorder::new(100);
+        assert_eq!(recorder.report().p50, None);
+
+        // The first 10 samples are dropped when the buffer is full.
+        for ms in (1..=10).chain(1..=100) {
+            recorder.record("ScoreList", Duration::from_millis(ms), true);
+        }
+        let report = recorder.report();
+
+        assert_eq!(report.samples.len(), 100);
+        assert_eq!(report.p50, Some(50.0));
+        assert_eq!(report.p95, Some(95.0));
+        assert_eq!(report.p99, Some(99.0));
+    }
+
+    #[test]
+    pub fn test_payload_stats() {
+        let recorder = LatencyRecorder::new(10);
+
+        for ms in 1..=200 {
+            recorder.record("ScoreList", Duration::from_millis(ms), ms % 50 != 0);
+        }
+        recorder.record("Ping", Duration::from_millis(3), true);
+        recorder.record_retries("ScoreList", 2);
+        let stats = recorder.stats();
+
+        assert_eq!(stats.len(), 2);
+        assert_eq!((stats[0].payload, stats[0].ok, stats[0].retries), ("Ping", 1, 0));
+        let score_list = &stats[1];
+        assert_eq!(
+            (score_list.ok, score_list.errors, score_list.retries),
+            (196, 4, 2)
+        );
+        // Of the latest 100.
+        assert_eq!(score_list.p50, Some(150.0));
+        assert_eq!(score_list.p95, Some(195.0));
+        assert_eq!(score_list.max, Some(200.0));
+    }
+}
@@ -0,0 +1,133 @@
+//! Limit concurrent agent requests, so that a traffic spike doesn't overwhelm agents and the campus
+//! system behind them.
+
+use super::{HostError, Result};
+use crate::config::HostConfig;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Requests beyond `max_concurrent` wait for a permit, for `wait_timeout` at most. Once
+/// `max_waiting` requests are waiting, more requests fail at once.
+#[derive(Clone)]
+pub struct RequestLimiter {
+    semaphore: Arc<Semaphore>,
+    max_concurrent: usize,
+    max_waiting: usize,
+    wait_timeout: Duration,
+    /// Requests waiting for a permit.
+    waiting: Arc<AtomicUsize>,
+}
+
+impl From<&HostConfig> for RequestLimiter {
+    fn from(config: &HostConfig) -> Self {
+        Self::new(
+            config.max_concurrent_requests,
+            config.max_waiting_requests,
+            Duration::from_millis(config.request_wait_timeout),
+        )
+    }
+}
+
+/// Count a request waiting until dropped, so that requests cancelled halfway are not counted
+/// forever.
+struct Waiting(Arc<AtomicUsize>);
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl RequestLimiter {
+    pub fn new(max_concurrent: usize, max_waiting: usize, wait_timeout: Duration) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(max_concurrent)),
+            max_concurrent,
+            max_waiting,
+            wait_timeout,
+            waiting: Default::default(),
+        }
+    }
+
+    /// Get a permit to send a request, which is returned when dropped. Return
+    /// `HostError::Overloaded` if there are too many requests.
+    pub async fn acquire(&self) -> Result<OwnedSemaphorePermit> {
+        if let Ok(permit) = self.semaphore.clone().try_acquire_owned() {
+            return Ok(permit);
+        }
+        if self.waiting.fetch_add(1, Ordering::SeqCst) >= self.max_waiting {
+            self.waiting.fetch_sub(1, Ordering::SeqCst);
+            return Err(HostError::Overloaded.into());
+        }
+        let _waiting = Waiting(self.waiting.clone());
+
+        tokio::time::timeout(self.wait_timeout, self.semaphore.clone().acquire_owned())
+            .await
+            .map_err(|_| HostError::Overloaded.into())
+    }
+
+    /// Requests holding permits.
+    pub fn in_use(&self) -> usize {
+        self.max_concurrent - self.semaphore.available_permits()
+    }
+
+    /// Requests waiting for permits.
+    pub fn waiting(&self) -> usize {
+        self.waiting.load(Ordering::SeqCst)
+    }
+
+    /// Whether less than half of permits are in use and no request is waiting, so that background
+    /// requests can be sent without delaying live ones.
+    pub fn is_idle(&self) -> bool {
+        self.waiting() == 0 && self.in_use() * 2 < self.max_concurrent
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_fail_fast_when_queue_full() {
+        let limiter = RequestLimiter::new(2, 0, Duration::from_secs(1));
+
+        let first = limiter.acquire().await.unwrap();
+        assert!(!limiter.is_idle());
+        let _second = limiter.acquire().await.unwrap();
+        assert_eq!(limiter.in_use(), 2);
+        // No room to wait.
+        let e = limiter.acquire().await.unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<HostError>(),
+            Some(HostError::Overloaded)
+        ));
+
+        drop(first);
+        assert!(limiter.acquire().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_permit() {
+        let limiter = RequestLimiter::new(1, 1, Duration::from_millis(200));
+
+        let permit = limiter.acquire().await.unwrap();
+        // Times out waiting.
+        assert!(limiter.acquire().await.is_err());
+        assert_eq!(limiter.waiting(), 0);
+
+        // Gets the permit once released.
+        tokio::spawn(async move {
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+            drop(permit);
+        });
+        let waiter = limiter.clone();
+        let waiting = tokio::spawn(async move { waiter.acquire().await.is_ok() });
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+        assert_eq!(limiter.waiting(), 1);
+        // The queue is full.
+        assert!(limiter.acquire().await.is_err());
+        assert!(waiting.await.unwrap());
+    }
+}
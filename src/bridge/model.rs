@@ -1,14 +1,19 @@
-use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
 
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 pub struct AgentInfoRequest;
 
+/// Heartbeat, to which the agent responds `ResponsePayload::Pong`.
+#[derive(Clone, Serialize)]
+pub struct PingRequest;
+
 #[derive(Clone, Deserialize)]
 pub struct AgentInfo {
     pub name: String,
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 pub struct ActivityListRequest {
     /// Count of activities per page.
     pub count: u16,
@@ -16,7 +21,7 @@ pub struct ActivityListRequest {
     pub index: u16,
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 pub struct CourseScoreRequest {
     pub account: String,
     pub credential: String,
@@ -39,22 +44,24 @@ pub struct CourseScoreInner {
     pub make_up_total: f32,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug)]
 pub struct Activity {
     pub title: String,
     pub id: String,
     pub link: String,
 }
 
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Deserialize, PartialEq)]
 pub enum CourseScoreLine {
     /// Have commented the teacher
     Normal(CourseScoreInner),
     /// Comment (评教) is needed
     Uncomment,
+    /// Course graded in pass or fail, without a mark.
+    PassFail(bool),
 }
 
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct CourseScore {
     /// Unique ID of the course
     pub course_code: String,
@@ -65,3 +72,99 @@ pub struct CourseScore {
     /// score data.
     pub detail: CourseScoreLine,
 }
+
+/// Item of a list which is checked one by one, so that a malformed item doesn't fail the list.
+pub trait ListItem: Sized {
+    /// Item as sent, in the same layout with fields that can't fail to deserialize.
+    type Raw: DeserializeOwned;
+
+    fn from_raw(raw: Self::Raw) -> Result<Self, String>;
+}
+
+/// Malformed item left out of a list.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ItemError {
+    /// Index of the item in the list sent.
+    pub index: usize,
+    pub reason: String,
+}
+
+/// Valid items of a list, and errors of the malformed ones. Strings are read as bytes, which take
+/// the same layout in bincode, so that an item with invalid UTF-8 is left out alone. Other errors,
+/// like an unknown enum variant, still fail the whole list, since the rest can't be located.
+#[derive(Debug)]
+pub struct PartialList<T> {
+    pub items: Vec<T>,
+    pub errors: Vec<ItemError>,
+}
+
+impl<T> From<Vec<T>> for PartialList<T> {
+    fn from(items: Vec<T>) -> Self {
+        Self {
+            items,
+            errors: vec![],
+        }
+    }
+}
+
+impl<'de, T: ListItem> Deserialize<'de> for PartialList<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw: Vec<T::Raw> = Vec::deserialize(deserializer)?;
+        let mut list = PartialList {
+            items: Vec::with_capacity(raw.len()),
+            errors: Vec::new(),
+        };
+
+        for (index, raw) in raw.into_iter().enumerate() {
+            match T::from_raw(raw) {
+                Ok(item) => list.items.push(item),
+                Err(reason) => list.errors.push(ItemError { index, reason }),
+            }
+        }
+        Ok(list)
+    }
+}
+
+fn utf8(bytes: Vec<u8>, field: &str) -> Result<String, String> {
+    String::from_utf8(bytes).map_err(|e| format!("{} is not UTF-8: {}", field, e))
+}
+
+#[derive(Deserialize)]
+pub struct RawCourseScore {
+    course_code: Vec<u8>,
+    course_name: Vec<u8>,
+    course_credit: f32,
+    detail: CourseScoreLine,
+}
+
+impl ListItem for CourseScore {
+    type Raw = RawCourseScore;
+
+    fn from_raw(raw: RawCourseScore) -> Result<Self, String> {
+        Ok(CourseScore {
+            course_code: utf8(raw.course_code, "course_code")?,
+            course_name: utf8(raw.course_name, "course_name")?,
+            course_credit: raw.course_credit,
+            detail: raw.detail,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RawActivity {
+    title: Vec<u8>,
+    id: Vec<u8>,
+    link: Vec<u8>,
+}
+
+impl ListItem for Activity {
+    type Raw = RawActivity;
+
+    fn from_raw(raw: RawActivity) -> Result<Self, String> {
+        Ok(Activity {
+            title: utf8(raw.title, "title")?,
+            id: utf8(raw.id, "id")?,
+            link: utf8(raw.link, "link")?,
+        })
+    }
+}
@@ -1,14 +1,74 @@
 use super::model::*;
 use super::Result;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use log::warn;
 use serde::{Deserialize, Serialize};
+use std::io::{ErrorKind, Read, Write};
+use std::ops::RangeInclusive;
+use std::pin::Pin;
 use std::sync::atomic::AtomicU64;
 use std::sync::atomic::Ordering;
-use tokio::io::{AsyncReadExt, BufReader};
-use tokio::net::tcp::OwnedReadHalf;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::Duration;
 
-lazy_static! {
-    /// Last seq of request packet
-    static ref LAST_SEQ: AtomicU64 = AtomicU64::new(1u64);
+/// Version of the framing. Version 2 adds the payload checksum, version 3 adds the compression
+/// flag, version 4 adds the magic and version fields to frames, and version 5 adds `Ping`.
+pub const PROTOCOL_VERSION: u16 = 5;
+/// Oldest version the host speaks. The handshake is sent in it, and the agent responds in its own,
+/// and then the lower one of the two is used on the connection.
+pub const MIN_PROTOCOL_VERSION: u16 = 4;
+/// First version with `Ping`, which older agents don't understand.
+pub const PING_VERSION: u16 = 5;
+
+/// Version to use on the connection, given the one the agent responds in.
+pub fn negotiate_version(agent_version: u16) -> u16 {
+    agent_version.clamp(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION)
+}
+
+/// Magic at the beginning of each frame, "KT". A frame without it means the stream is misaligned
+/// or not from an agent.
+const FRAME_MAGIC: u16 = 0x4b54;
+
+/// The highest bit of size field, set if the payload is compressed in zlib.
+const COMPRESSED_FLAG: u32 = 1 << 31;
+
+/// Bounds in bytes of the chunk size and the buffer capacity to read responses.
+const READ_SIZE_BOUNDS: RangeInclusive<usize> = 512..=(1 << 20);
+
+/// Check the chunk size and the buffer capacity configured to read responses.
+pub fn check_read_sizes(chunk_size: usize, buffer_size: usize) -> std::result::Result<(), String> {
+    for (name, size) in &[("read_chunk_size", chunk_size), ("read_buffer_size", buffer_size)] {
+        if !READ_SIZE_BOUNDS.contains(size) {
+            return Err(format!(
+                "{} {} is out of {} to {} bytes",
+                name,
+                size,
+                READ_SIZE_BOUNDS.start(),
+                READ_SIZE_BOUNDS.end()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Allocator of request seqs on a connection. Responses are matched to requests by seq within the
+/// connection, so a seq is never reused while a request with it is in flight on the connection.
+/// Zero is never allocated, which marks heartbeat frames. The counter wraps around after `u64::MAX`.
+#[derive(Default)]
+pub struct SeqAllocator(AtomicU64);
+
+impl SeqAllocator {
+    /// Allocate a seq for which `in_flight` is false.
+    pub fn next(&self, in_flight: impl Fn(u64) -> bool) -> u64 {
+        loop {
+            let seq = self.0.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+            if seq != 0 && !in_flight(seq) {
+                return seq;
+            }
+        }
+    }
 }
 
 // Result has two sides, Ok(ResponsePayload) and Err(ResponseError)
@@ -18,23 +78,34 @@ pub type ResponseResult = std::result::Result<ResponsePayload, ErrorResponse>;
 // Implement Debug for error handling.
 #[derive(Default, Serialize)]
 pub struct Request {
+    /// Protocol version of the frame, the one of the connection it's sent on.
+    pub version: u16,
     /// Request sequence
     pub seq: u64,
     /// Packet size
     pub size: u32,
+    /// Whether the payload is compressed
+    pub compressed: bool,
+    /// CRC32 of payload
+    pub checksum: u32,
     /// Payload
     pub payload: Vec<u8>,
 }
 
 /// Agent response
-#[derive(Debug, Default, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct Response {
+    /// Protocol version the agent speaks.
+    #[serde(default)]
+    pub version: u16,
     /// Response sequence
     pub ack: u64,
     /// Response size
     pub size: u32,
     /// Status code
     pub code: u16,
+    /// CRC32 of payload
+    pub checksum: u32,
     /// Payload
     pub payload: Vec<u8>,
 }
@@ -49,83 +120,356 @@ pub struct ErrorResponse {
 
 use crate::bridge::HostError;
 use crate::error::ApiError;
+use crate::models::CommonError;
 
 /// Response payload
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 pub enum RequestPayload {
     AgentInfo(AgentInfoRequest),
     ActivityList(ActivityListRequest),
     ScoreList(CourseScoreRequest),
+    Ping(PingRequest),
 }
 
 /// Response payload
 #[derive(Deserialize)]
 pub enum ResponsePayload {
     AgentInfo(AgentInfo),
-    ActivityList(Vec<Activity>),
-    ScoreList(Vec<CourseScore>),
+    ActivityList(PartialList<Activity>),
+    ScoreList(PartialList<CourseScore>),
+    Pong,
+    /// Payload variant unknown to the host, which must be the last variant.
+    #[serde(skip_deserializing)]
+    Unknown(UnknownPayload),
+    /// Successful response without payload, as an acknowledgement.
+    #[serde(skip_deserializing)]
+    Empty,
+}
+
+/// Count of response payload variants known by the host, excluding `Unknown` and `Empty`.
+const KNOWN_RESPONSE_PAYLOADS: u32 = 4;
+
+/// Response payload with a variant tag the host doesn't know, maybe sent by a newer agent.
+#[derive(Debug)]
+pub struct UnknownPayload {
+    /// Variant tag.
+    pub tag: u32,
+    /// Raw payload, including the tag.
+    pub raw: Vec<u8>,
+}
+
+/// Agent request with the expected response. Request and response payloads of the same kind
+/// share the variant tag, so a response with another tag is rejected.
+pub trait AgentRequest: Into<RequestPayload> {
+    /// Response data.
+    type Response;
+    /// Variant tag in both `RequestPayload` and `ResponsePayload`.
+    const TAG: u8;
+
+    /// Take the response data out of the payload.
+    fn extract(payload: ResponsePayload) -> Option<Self::Response>;
+}
+
+macro_rules! agent_request {
+    ($request: ty, $variant: ident, $response: ty, $tag: expr) => {
+        impl From<$request> for RequestPayload {
+            fn from(request: $request) -> Self {
+                RequestPayload::$variant(request)
+            }
+        }
+
+        impl AgentRequest for $request {
+            type Response = $response;
+            const TAG: u8 = $tag;
+
+            fn extract(payload: ResponsePayload) -> Option<Self::Response> {
+                match payload {
+                    ResponsePayload::$variant(response) => Some(response),
+                    _ => None,
+                }
+            }
+        }
+    };
+    // Request whose response carries no data, in a unit variant.
+    ($request: ty, $variant: ident => $response_variant: ident, $tag: expr) => {
+        impl From<$request> for RequestPayload {
+            fn from(request: $request) -> Self {
+                RequestPayload::$variant(request)
+            }
+        }
+
+        impl AgentRequest for $request {
+            type Response = ();
+            const TAG: u8 = $tag;
+
+            fn extract(payload: ResponsePayload) -> Option<Self::Response> {
+                match payload {
+                    ResponsePayload::$response_variant => Some(()),
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+agent_request!(AgentInfoRequest, AgentInfo, AgentInfo, 0);
+agent_request!(ActivityListRequest, ActivityList, PartialList<Activity>, 1);
+agent_request!(CourseScoreRequest, ScoreList, PartialList<CourseScore>, 2);
+agent_request!(PingRequest, Ping => Pong, 3);
+
+impl RequestPayload {
+    /// Name of the payload type.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RequestPayload::AgentInfo(_) => "AgentInfo",
+            RequestPayload::ActivityList(_) => "ActivityList",
+            RequestPayload::ScoreList(_) => "ScoreList",
+            RequestPayload::Ping(_) => "Ping",
+        }
+    }
+
+    /// Whether the request can be retried safely. Requests which change something on the campus
+    /// systems should return false.
+    pub fn is_idempotent(&self) -> bool {
+        match self {
+            RequestPayload::AgentInfo(_)
+            | RequestPayload::ActivityList(_)
+            | RequestPayload::ScoreList(_)
+            | RequestPayload::Ping(_) => true,
+        }
+    }
+}
+
+/// CRC32 of the payload.
+fn checksum(payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+/// Compress the payload in zlib.
+fn compress(payload: &[u8]) -> Vec<u8> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+
+    // Writing to a vector never fails.
+    encoder.write_all(payload).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// Decompress the payload, which should be no larger than `max_size` after decompression.
+fn decompress(payload: &[u8], max_size: u32) -> Result<Vec<u8>> {
+    let mut decompressed = Vec::new();
+
+    ZlibDecoder::new(payload)
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut decompressed)?;
+    if decompressed.len() > max_size as usize {
+        return Err(HostError::TooLargePayload.into());
+    }
+    Ok(decompressed)
+}
+
+/// Serialize the payload, and compress it if it's larger than `compress_threshold` bytes. Return
+/// the payload and whether it's compressed.
+fn encode<T: Serialize>(payload: &T, compress_threshold: u32) -> Result<(Vec<u8>, bool)> {
+    let payload = bincode::serialize(payload).map_err(|e| {
+        warn!("Failed to serialize request payload: {}", e);
+        HostError::Serialize
+    })?;
+    let compressed = payload.len() > compress_threshold as usize;
+
+    if compressed {
+        Ok((compress(&payload), true))
+    } else {
+        Ok((payload, false))
+    }
 }
 
 impl Request {
-    pub fn new(payload: RequestPayload) -> Self {
-        let seq = LAST_SEQ.fetch_add(1, Ordering::Relaxed);
-        let payload = bincode::serialize(&payload).unwrap();
+    /// Make a request, whose payload is compressed if it's larger than `compress_threshold` bytes.
+    /// Build a request. Its seq is allocated by the connection it's sent on.
+    pub fn new(payload: RequestPayload, compress_threshold: u32) -> Result<Self> {
+        let (payload, compressed) = encode(&payload, compress_threshold)?;
 
-        Self {
-            seq,
-            // We will not construct a message more than 2^32 bytes
+        Ok(Self {
+            version: PROTOCOL_VERSION,
+            seq: 0,
+            // We will not construct a message more than 2^31 bytes
             size: payload.len() as u32,
+            compressed,
+            checksum: checksum(&payload),
             payload,
+        })
+    }
+
+    /// Serialize the request to a frame: magic, version, seq, size with compression flag, checksum
+    /// and payload in big endian.
+    fn to_frame(&self) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(19 + self.payload.len());
+        let size = if self.compressed {
+            self.size | COMPRESSED_FLAG
+        } else {
+            self.size
+        };
+
+        frame.extend_from_slice(&FRAME_MAGIC.to_be_bytes());
+        frame.push(self.version as u8);
+        frame.extend_from_slice(&self.seq.to_be_bytes());
+        frame.extend_from_slice(&size.to_be_bytes());
+        frame.extend_from_slice(&self.checksum.to_be_bytes());
+        frame.extend_from_slice(&self.payload);
+        frame
+    }
+
+    /// Write the whole frame to the writer. Partial writes are continued until all bytes are written,
+    /// and interrupted or would-block errors are retried, so that a half-written frame never desyncs
+    /// the stream. Other errors are returned and the connection should be regarded as dead.
+    pub async fn write_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<()> {
+        let frame = self.to_frame();
+        let mut written = 0usize;
+
+        while written < frame.len() {
+            match writer.write(&frame[written..]).await {
+                Ok(0) => return Err(std::io::Error::from(ErrorKind::WriteZero).into()),
+                Ok(n) => written += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted || e.kind() == ErrorKind::WouldBlock => {
+                    tokio::time::delay_for(Duration::from_millis(1)).await;
+                }
+                Err(e) => return Err(e.into()),
+            }
         }
+        writer.flush().await?;
+        Ok(())
     }
 }
 
 impl Response {
-    async fn read_header(buffer: &mut BufReader<OwnedReadHalf>) -> Result<Self> {
-        // Default response header is 14 bytes.
+    async fn read_header<R: AsyncRead + Unpin>(buffer: &mut R) -> Result<Self> {
         let mut response = Response::default();
 
+        // Check the magic and version before trusting the rest of the header.
+        if buffer.read_u16().await? != FRAME_MAGIC {
+            return Err(HostError::BadFrame.into());
+        }
+        response.version = buffer.read_u8().await? as u16;
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&response.version) {
+            return Err(HostError::BadFrame.into());
+        }
         // Read the control fields
         response.ack = buffer.read_u64().await?;
         response.size = buffer.read_u32().await?;
         response.code = buffer.read_u16().await?;
+        response.checksum = buffer.read_u32().await?;
 
         Ok(response)
     }
 
-    pub async fn from_stream(buffer: &mut BufReader<OwnedReadHalf>) -> Result<Self> {
+    async fn read_frame<R: AsyncRead + Unpin>(
+        buffer: &mut R,
+        max_payload: u32,
+        chunk_size: usize,
+    ) -> Result<Self> {
         let mut response = Self::read_header(buffer).await?;
+        let compressed = response.size & COMPRESSED_FLAG != 0;
+
+        response.size &= !COMPRESSED_FLAG;
 
         if response.size == 0 {
             return Ok(response);
         }
-        if response.size > 10 * 1024 * 1024 {
+        // Check before allocating, since the size is from the wire.
+        if response.size > max_payload {
             return Err(HostError::TooLargePayload.into());
         }
         response.payload = vec![0u8; response.size as usize];
         // Read body
         let mut p = 0usize; // read len
         while p < response.size as usize {
-            let mut read_currently = response.size as usize - p;
-            if read_currently > 2048 {
-                read_currently = 2048usize;
-            }
+            let read_currently = (response.size as usize - p).min(chunk_size);
             p += buffer
                 .read_exact(&mut response.payload[p..(p + read_currently)])
                 .await?;
         }
+        // Corrupted payload may deserialize to garbage silently.
+        if checksum(&response.payload) != response.checksum {
+            return Err(HostError::ChecksumMismatch.into());
+        }
+        if compressed {
+            response.payload = decompress(&response.payload, max_payload)?;
+        }
         Ok(response)
     }
 
+    /// Read a response. The connection may be idle for long, but once a response starts arriving,
+    /// the whole frame must be read in `timeout`, or `HostError::Timeout` is returned, the partial
+    /// frame is discarded and the stream can't be used any more. Payload larger than `max_payload`,
+    /// before or after decompression, is refused with `HostError::TooLargePayload`. The payload is
+    /// read in chunks of `chunk_size` bytes at most.
+    pub async fn from_stream<R: AsyncBufRead + Unpin>(
+        buffer: &mut R,
+        timeout: Duration,
+        max_payload: u32,
+        chunk_size: usize,
+    ) -> Result<Self> {
+        // Wait until the response starts arriving.
+        let eof = futures::future::poll_fn(|cx| {
+            Pin::new(&mut *buffer).poll_fill_buf(cx).map_ok(|x| x.is_empty())
+        })
+        .await?;
+        if eof {
+            return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into());
+        }
+        tokio::time::timeout(timeout, Self::read_frame(buffer, max_payload, chunk_size))
+            .await
+            .map_err(|_| HostError::Timeout)?
+    }
+
     pub async fn is_ok(&self) -> bool {
         self.code == 0
     }
 
+    /// Variant tag of the payload, which bincode encodes as a little-endian u32 at the beginning.
+    fn tag(&self) -> Option<u32> {
+        let mut tag = [0u8; 4];
+
+        if self.payload.len() < 4 {
+            return None;
+        }
+        tag.copy_from_slice(&self.payload[..4]);
+        Some(u32::from_le_bytes(tag))
+    }
+
+    /// Deserialize the response data of request `R`. Successful responses with a tag other than
+    /// `R::TAG` are rejected as `HostError::BadResponse`.
+    pub fn payload_of<R: AgentRequest>(self) -> Result<std::result::Result<R::Response, ErrorResponse>> {
+        if self.code == 0 && self.tag() != Some(R::TAG as u32) {
+            return Err(HostError::BadResponse.into());
+        }
+        match self.payload()? {
+            Ok(payload) => Ok(Ok(R::extract(payload).ok_or(HostError::BadResponse)?)),
+            Err(e) => Ok(Err(e)),
+        }
+    }
+
     pub fn payload(self) -> Result<ResponseResult> {
         if self.code == 0 {
-            Ok(Ok(bincode::deserialize(&self.payload)?))
+            // Zero-size response carries nothing to deserialize.
+            if self.payload.is_empty() {
+                return Ok(Ok(ResponsePayload::Empty));
+            }
+            let tag = self.tag().unwrap_or_default();
+            if tag >= KNOWN_RESPONSE_PAYLOADS {
+                let raw = self.payload;
+                return Ok(Ok(ResponsePayload::Unknown(UnknownPayload { tag, raw })));
+            }
+            let payload = bincode::deserialize(&self.payload).map_err(|e| {
+                warn!("Failed to deserialize response {}: {}", self.ack, e);
+                HostError::Deserialize
+            })?;
+            Ok(Ok(payload))
         } else {
-            let err_string = std::str::from_utf8(&self.payload)?;
+            let err_string = std::str::from_utf8(&self.payload).map_err(|_| HostError::Deserialize)?;
             Ok(Err(ErrorResponse {
                 code: self.code,
                 msg: String::from(err_string),
@@ -134,12 +478,535 @@ impl Response {
     }
 }
 
+/// Kind of error reported by agents in `Response::code`. See `docs/通信协议.md` for the code table.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AgentCode {
+    /// The campus system rejected the account or the credential.
+    AuthFailed,
+    /// The campus system is down or unreachable.
+    UpstreamUnavailable,
+    /// The campus system limits requests.
+    RateLimited,
+    /// Requested data doesn't exist in the campus system.
+    NotFound,
+    /// Other errors with the raw code.
+    Other(u16),
+}
+
+impl From<u16> for AgentCode {
+    fn from(code: u16) -> Self {
+        match code {
+            1 => AgentCode::AuthFailed,
+            2 => AgentCode::UpstreamUnavailable,
+            3 => AgentCode::RateLimited,
+            4 => AgentCode::NotFound,
+            code => AgentCode::Other(code),
+        }
+    }
+}
+
 impl From<ErrorResponse> for ApiError {
     fn from(resp: ErrorResponse) -> Self {
+        let e: ApiError = match AgentCode::from(resp.code) {
+            AgentCode::AuthFailed => ApiError::new(HostError::CampusAuthFailed),
+            AgentCode::UpstreamUnavailable => CommonError::Unavailable.into(),
+            AgentCode::RateLimited => CommonError::TooManyRequests.into(),
+            AgentCode::NotFound => ApiError::new(HostError::CampusNotFound),
+            // The raw code may collide with codes of the server, so it's kept for logs only.
+            AgentCode::Other(code) => {
+                return ApiError {
+                    inner_msg: Some(format!("agent code {}: {}", code, resp.msg)),
+                    ..ApiError::new(HostError::UnknownAgentError)
+                }
+            }
+        };
+        // Keep the message from the campus system for logs.
         ApiError {
-            code: resp.code,
-            inner_msg: None,
-            error_msg: Some(resp.msg),
+            inner_msg: Some(resp.msg),
+            ..e
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        check_read_sizes, negotiate_version, AgentRequest, Request, RequestPayload, Response,
+        ResponsePayload, SeqAllocator, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    };
+    use crate::bridge::model::{ActivityListRequest, AgentInfoRequest, CourseScoreRequest, PingRequest};
+    use crate::bridge::HostError;
+    use std::io::ErrorKind;
+    use std::pin::Pin;
+    use std::sync::atomic::AtomicU64;
+    use std::task::{Context, Poll};
+    use tokio::io::{AsyncRead, AsyncWrite, BufReader};
+    use tokio::time::Duration;
+
+    /// A writer which accepts only a few bytes per call, and fails temporarily sometimes.
+    #[derive(Default)]
+    struct SlowWriter {
+        data: Vec<u8>,
+        calls: usize,
+    }
+
+    impl AsyncWrite for SlowWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _: &mut Context,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            self.calls += 1;
+            match self.calls % 3 {
+                0 => Poll::Ready(Err(ErrorKind::WouldBlock.into())),
+                1 => Poll::Ready(Err(ErrorKind::Interrupted.into())),
+                _ => {
+                    let n = buf.len().min(3);
+                    self.data.extend_from_slice(&buf[..n]);
+                    Poll::Ready(Ok(n))
+                }
+            }
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// A reader which stalls after the given bytes.
+    struct StallingReader {
+        data: Vec<u8>,
+    }
+
+    impl AsyncRead for StallingReader {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _: &mut Context,
+            buf: &mut [u8],
+        ) -> Poll<std::io::Result<usize>> {
+            if self.data.is_empty() {
+                return Poll::Pending;
+            }
+            let n = buf.len().min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data.drain(..n);
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    #[test]
+    fn test_agent_code_to_api_error() {
+        use super::ErrorResponse;
+        use crate::error::ApiError;
+        use actix_http::http::StatusCode;
+        use actix_web::ResponseError;
+
+        let status_of = |code| {
+            let msg = "error".to_string();
+            ApiError::from(ErrorResponse { code, msg }).status_code()
+        };
+        assert_eq!(status_of(1), StatusCode::UNAUTHORIZED);
+        assert_eq!(status_of(2), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(status_of(3), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(status_of(4), StatusCode::OK);
+        // Not taken as the precondition failure of the server coded 7.
+        assert_eq!(status_of(7), StatusCode::BAD_GATEWAY);
+
+        let e = ApiError::from(ErrorResponse {
+            code: 4,
+            msg: "no such room".to_string(),
+        });
+        assert_eq!(e.code, 131);
+        assert_eq!(e.inner_msg.as_deref(), Some("no such room"));
+        // Unknown codes are kept in the inner message only.
+        let e = ApiError::from(ErrorResponse {
+            code: 999,
+            msg: "error".to_string(),
+        });
+        assert_eq!(
+            e,
+            ApiError {
+                inner_msg: e.inner_msg.clone(),
+                ..ApiError::new(HostError::UnknownAgentError)
+            }
+        );
+        assert_eq!(e.inner_msg.as_deref(), Some("agent code 999: error"));
+    }
+
+    #[tokio::test]
+    async fn test_read_timeout() {
+        // Header of a 10-byte payload, but only 2 bytes arrive.
+        let mut data = vec![
+            0x4b, 0x54, 4, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 10, 0, 0, 0, 0, 0, 0,
+        ];
+        data.extend_from_slice(&[1, 2]);
+        let mut reader = BufReader::new(StallingReader { data });
+
+        let e = Response::from_stream(&mut reader, Duration::from_millis(100), 1024, 2048)
+            .await
+            .unwrap_err();
+        assert!(matches!(e.downcast_ref::<HostError>(), Some(HostError::Timeout)));
+
+        // Idle connection is not timed out.
+        let mut reader = BufReader::new(StallingReader { data: vec![] });
+        let read = Response::from_stream(&mut reader, Duration::from_millis(100), 1024, 2048);
+        assert!(tokio::time::timeout(Duration::from_millis(300), read)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_payload_in_chunks() {
+        // Incompressible payload, larger than chunks and the buffer.
+        let account: String = (0..20000)
+            .map(|i| ((i * 7919 % 26) as u8 + b'a') as char)
+            .collect();
+        let request = Request::new(
+            RequestPayload::ScoreList(CourseScoreRequest {
+                account,
+                credential: String::new(),
+                term: String::new(),
+            }),
+            u32::MAX,
+        )
+        .unwrap();
+
+        for chunk_size in &[1, 512, 2048, 4096, 1 << 20] {
+            let mut reader = BufReader::with_capacity(
+                512,
+                StallingReader {
+                    data: as_response_frame(&request),
+                },
+            );
+            let response =
+                Response::from_stream(&mut reader, Duration::from_secs(1), 1 << 20, *chunk_size)
+                    .await
+                    .unwrap();
+            assert_eq!(response.payload, request.payload);
+        }
+    }
+
+    #[test]
+    fn test_check_read_sizes() {
+        assert!(check_read_sizes(2048, 8192).is_ok());
+        assert!(check_read_sizes(512, 1 << 20).is_ok());
+        assert!(check_read_sizes(0, 8192).is_err());
+        assert!(check_read_sizes(2048, (1 << 20) + 1).is_err());
+    }
+
+    /// A request frame has the same layout as a response frame except for the code field.
+    fn as_response_frame(request: &Request) -> Vec<u8> {
+        let mut frame = request.to_frame();
+
+        frame.splice(15..15, vec![0u8, 0]);
+        frame
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        let payload = || {
+            RequestPayload::ScoreList(CourseScoreRequest {
+                account: "1".repeat(1000),
+                credential: String::new(),
+                term: String::new(),
+            })
+        };
+        let raw = bincode::serialize(&payload()).unwrap();
+
+        for threshold in &[0, u32::MAX] {
+            let request = Request::new(payload(), *threshold).unwrap();
+            assert_eq!(request.compressed, *threshold == 0);
+            assert_eq!(request.compressed, request.payload.len() < raw.len());
+
+            let mut reader = BufReader::new(StallingReader {
+                data: as_response_frame(&request),
+            });
+            let response = Response::from_stream(&mut reader, Duration::from_secs(1), 4096, 2048)
+                .await
+                .unwrap();
+            assert_eq!(response.payload, raw);
+        }
+
+        // Too large after decompression.
+        let request = Request::new(payload(), 0).unwrap();
+        let mut reader = BufReader::new(StallingReader {
+            data: as_response_frame(&request),
+        });
+        let e = Response::from_stream(&mut reader, Duration::from_secs(1), 512, 2048)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<HostError>(),
+            Some(HostError::TooLargePayload)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_checksum() {
+        let request = Request::new(RequestPayload::AgentInfo(AgentInfoRequest), 1024).unwrap();
+        let frame = |tamper: bool| {
+            let mut frame = as_response_frame(&request);
+            if tamper {
+                *frame.last_mut().unwrap() ^= 1;
+            }
+            BufReader::new(StallingReader { data: frame })
+        };
+
+        let response = Response::from_stream(&mut frame(false), Duration::from_secs(1), 1024, 2048)
+            .await
+            .unwrap();
+        assert_eq!(response.payload, request.payload);
+
+        let e = Response::from_stream(&mut frame(true), Duration::from_secs(1), 1024, 2048)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<HostError>(),
+            Some(HostError::ChecksumMismatch)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_too_large_payload() {
+        // Header claiming a 4GB payload, with nothing after it.
+        let header = vec![
+            0x4b, 0x54, 4, 0, 0, 0, 0, 0, 0, 0, 1, 0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0, 0, 0,
+        ];
+        let mut reader = BufReader::new(StallingReader { data: header });
+
+        let e = Response::from_stream(&mut reader, Duration::from_secs(1), 1024, 2048)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<HostError>(),
+            Some(HostError::TooLargePayload)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_partial_write() {
+        let request = Request {
+            version: PROTOCOL_VERSION,
+            seq: 1,
+            size: 5,
+            compressed: false,
+            checksum: 0,
+            payload: vec![1, 2, 3, 4, 5],
+        };
+        let mut writer = SlowWriter::default();
+
+        request.write_to(&mut writer).await.unwrap();
+        assert_eq!(writer.data, request.to_frame());
+        assert_eq!(writer.data.len(), 24);
+    }
+
+    #[tokio::test]
+    async fn test_frame_magic() {
+        let request = Request::new(RequestPayload::AgentInfo(AgentInfoRequest), 1024).unwrap();
+        let read = |frame: Vec<u8>| async move {
+            let mut reader = BufReader::new(StallingReader { data: frame });
+            Response::from_stream(&mut reader, Duration::from_secs(1), 1024, 2048).await
+        };
+
+        let frame = as_response_frame(&request);
+        assert_eq!(&frame[..3], &[0x4b, 0x54, PROTOCOL_VERSION as u8]);
+        let response = read(frame).await.unwrap();
+        assert_eq!(response.version, PROTOCOL_VERSION);
+        assert_eq!(response.ack, request.seq);
+        assert_eq!(response.payload, request.payload);
+        // An older agent.
+        let mut frame = as_response_frame(&request);
+        frame[2] = MIN_PROTOCOL_VERSION as u8;
+        assert_eq!(read(frame).await.unwrap().version, MIN_PROTOCOL_VERSION);
+
+        // Corrupted magic, like a misaligned stream.
+        let mut frame = as_response_frame(&request);
+        frame[0] ^= 0xff;
+        let e = read(frame).await.unwrap_err();
+        assert!(matches!(e.downcast_ref::<HostError>(), Some(HostError::BadFrame)));
+        // Versions the host doesn't speak.
+        for version in &[MIN_PROTOCOL_VERSION - 1, PROTOCOL_VERSION + 1] {
+            let mut frame = as_response_frame(&request);
+            frame[2] = *version as u8;
+            let e = read(frame).await.unwrap_err();
+            assert!(matches!(e.downcast_ref::<HostError>(), Some(HostError::BadFrame)));
+        }
+    }
+
+    #[test]
+    fn test_negotiate_version() {
+        assert_eq!(negotiate_version(MIN_PROTOCOL_VERSION), MIN_PROTOCOL_VERSION);
+        assert_eq!(negotiate_version(PROTOCOL_VERSION), PROTOCOL_VERSION);
+        // A newer agent speaks older versions too.
+        assert_eq!(negotiate_version(PROTOCOL_VERSION + 1), PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn test_unknown_payload() {
+        let payload = vec![99u8, 0, 0, 0, 1, 2, 3];
+        let response = Response {
+            version: PROTOCOL_VERSION,
+            ack: 1,
+            size: payload.len() as u32,
+            code: 0,
+            checksum: 0,
+            payload: payload.clone(),
+        };
+
+        match response.payload().unwrap() {
+            Ok(ResponsePayload::Unknown(unknown)) => {
+                assert_eq!(unknown.tag, 99);
+                assert_eq!(unknown.raw, payload);
+            }
+            _ => panic!("Unknown payload expected."),
+        }
+    }
+
+    #[test]
+    fn test_serialize_error() {
+        struct Unserializable;
+
+        impl serde::Serialize for Unserializable {
+            fn serialize<S: serde::Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+                Err(serde::ser::Error::custom("unserializable"))
+            }
         }
+
+        let e = super::encode(&Unserializable, 1024).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<HostError>(),
+            Some(HostError::Serialize)
+        ));
+        let (payload, compressed) =
+            super::encode(&RequestPayload::AgentInfo(AgentInfoRequest), 1024).unwrap();
+        assert_eq!(payload, vec![0u8, 0, 0, 0]);
+        assert!(!compressed);
+    }
+
+    #[test]
+    fn test_deserialize_error() {
+        // An AgentInfo response whose name is cut off.
+        let payload = vec![0u8, 0, 0, 0, 9, 0, 0, 0, 0, 0, 0, 0, b'a'];
+        let response = Response {
+            version: PROTOCOL_VERSION,
+            ack: 1,
+            size: payload.len() as u32,
+            code: 0,
+            checksum: 0,
+            payload,
+        };
+
+        let e = response.payload().err().unwrap();
+        assert!(matches!(
+            e.downcast_ref::<HostError>(),
+            Some(HostError::Deserialize)
+        ));
+    }
+
+    #[test]
+    fn test_empty_payload() {
+        let response = Response {
+            version: PROTOCOL_VERSION,
+            ack: 1,
+            size: 0,
+            code: 0,
+            checksum: 0,
+            payload: vec![],
+        };
+
+        assert!(matches!(response.payload().unwrap(), Ok(ResponsePayload::Empty)));
+    }
+
+    /// Tag of each request type should be its variant index in `RequestPayload`.
+    #[test]
+    fn test_request_tags() {
+        fn tag_of<R: AgentRequest>(request: R) -> u8 {
+            let payload: RequestPayload = request.into();
+            bincode::serialize(&payload).unwrap()[0]
+        }
+
+        assert_eq!(tag_of(AgentInfoRequest), AgentInfoRequest::TAG);
+        assert_eq!(
+            tag_of(ActivityListRequest { count: 1, index: 1 }),
+            ActivityListRequest::TAG
+        );
+        let score_request = CourseScoreRequest {
+            account: String::new(),
+            credential: String::new(),
+            term: String::new(),
+        };
+        assert_eq!(tag_of(score_request), CourseScoreRequest::TAG);
+        assert_eq!(tag_of(PingRequest), PingRequest::TAG);
+    }
+
+    #[test]
+    fn test_mismatched_tag() {
+        // An AgentInfo response, with tag 0 and name "a".
+        let payload = vec![0u8, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, b'a'];
+        let response = || Response {
+            version: PROTOCOL_VERSION,
+            ack: 1,
+            size: payload.len() as u32,
+            code: 0,
+            checksum: 0,
+            payload: payload.clone(),
+        };
+
+        assert!(response().payload_of::<ActivityListRequest>().is_err());
+        let info = response().payload_of::<AgentInfoRequest>().unwrap().unwrap();
+        assert_eq!(info.name, "a");
+    }
+
+    #[test]
+    fn test_partial_score_list() {
+        // Course in bincode: code, name, credit and a pass/fail score.
+        fn course(code: &[u8], name: &[u8]) -> Vec<u8> {
+            let mut bytes = Vec::new();
+            for s in [code, name].iter() {
+                bytes.extend_from_slice(&(s.len() as u64).to_le_bytes());
+                bytes.extend_from_slice(s);
+            }
+            bytes.extend_from_slice(&2.0f32.to_le_bytes());
+            bytes.extend_from_slice(&[2, 0, 0, 0, 1]);
+            bytes
+        }
+        let mut payload = vec![2u8, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0];
+        payload.extend(course(b"B1001", b"Calculus"));
+        // Name is not UTF-8.
+        payload.extend(course(b"B1002", &[0xff, 0xfe]));
+        payload.extend(course(b"B1003", b"Physics"));
+        let response = Response {
+            version: PROTOCOL_VERSION,
+            ack: 1,
+            size: payload.len() as u32,
+            code: 0,
+            checksum: 0,
+            payload,
+        };
+
+        let list = response.payload_of::<CourseScoreRequest>().unwrap().unwrap();
+        let codes: Vec<_> = list.items.iter().map(|x| x.course_code.as_str()).collect();
+        assert_eq!(codes, ["B1001", "B1003"]);
+        assert_eq!(list.items[1].course_name, "Physics");
+        assert_eq!(list.errors.len(), 1);
+        assert_eq!(list.errors[0].index, 1);
+        assert!(list.errors[0].reason.starts_with("course_name"));
+    }
+
+    #[test]
+    fn test_seq_wraps_around() {
+        let seqs = SeqAllocator(AtomicU64::new(u64::MAX - 2));
+        // Seqs 1 and 2 are still in flight.
+        let in_flight = |seq| seq == 1 || seq == 2;
+
+        assert_eq!(seqs.next(in_flight), u64::MAX - 1);
+        assert_eq!(seqs.next(in_flight), u64::MAX);
+        // Zero is for heartbeats.
+        assert_eq!(seqs.next(in_flight), 3);
+        assert_eq!(seqs.next(in_flight), 4);
     }
 }
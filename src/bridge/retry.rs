@@ -0,0 +1,124 @@
+//! Retry agent requests which fail transiently, with exponential backoff and jitter.
+
+use super::{HostError, Result};
+use crate::config::HostConfig;
+use rand::Rng;
+use std::future::Future;
+use std::time::Duration;
+
+/// How to retry failed requests.
+#[derive(Clone, Copy)]
+pub struct RetryPolicy {
+    /// Max retry count, not including the first try.
+    pub count: u32,
+    /// Delay before the first retry, doubled each retry.
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl From<&HostConfig> for RetryPolicy {
+    fn from(config: &HostConfig) -> Self {
+        Self {
+            count: config.retry_count,
+            base_delay: Duration::from_millis(config.retry_base_delay),
+            max_delay: Duration::from_millis(config.retry_max_delay),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before the retry, with up to half of it as random jitter, so that requests failed
+    /// together don't retry together.
+    fn delay(&self, retry_count: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .checked_mul(1 << retry_count.min(16))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        let jitter = rand::thread_rng().gen_range(0..=delay.as_millis() as u64 / 2);
+
+        delay - Duration::from_millis(jitter)
+    }
+}
+
+/// Whether the error is transient, like a lost connection, so that another try may succeed.
+/// Errors reported by agents are not.
+fn is_transient(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<HostError>(),
+        Some(HostError::NoAgentAvailable)
+            | Some(HostError::Timeout)
+            | Some(HostError::Disconnected)
+            | Some(HostError::AgentUnavailable)
+            | Some(HostError::ChecksumMismatch)
+    ) || e.downcast_ref::<std::io::Error>().is_some()
+}
+
+/// Call `f` and retry on transient errors by the policy. Requests not idempotent are tried once.
+pub async fn with_retry<T, F, Fut>(policy: &RetryPolicy, idempotent: bool, mut f: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let max_retry = if idempotent { policy.count } else { 0 };
+    let mut retry_count = 0;
+
+    loop {
+        match f().await {
+            Err(e) if retry_count < max_retry && is_transient(&e) => {
+                tokio::time::delay_for(policy.delay(retry_count)).await;
+                retry_count += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::Cell;
+
+    const POLICY: RetryPolicy = RetryPolicy {
+        count: 3,
+        base_delay: Duration::from_millis(1),
+        max_delay: Duration::from_millis(10),
+    };
+
+    #[tokio::test]
+    async fn test_retry() {
+        // Fails twice, and then succeeds.
+        let tries = Cell::new(0);
+        let flaky = || {
+            tries.set(tries.get() + 1);
+            let result = if tries.get() <= 2 {
+                Err(HostError::Disconnected.into())
+            } else {
+                Ok(tries.get())
+            };
+            async move { result }
+        };
+
+        assert_eq!(with_retry(&POLICY, true, flaky).await.unwrap(), 3);
+        tries.set(0);
+        assert!(with_retry(&POLICY, false, flaky).await.is_err());
+        assert_eq!(tries.get(), 1);
+
+        // Not transient.
+        tries.set(0);
+        let result: Result<()> = with_retry(&POLICY, true, || {
+            tries.set(tries.get() + 1);
+            async { Err(HostError::BadResponse.into()) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(tries.get(), 1);
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        assert!(POLICY.delay(0) <= Duration::from_millis(1));
+        assert!(POLICY.delay(3) >= Duration::from_millis(4));
+        assert!(POLICY.delay(30) <= Duration::from_millis(10));
+    }
+}
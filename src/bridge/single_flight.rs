@@ -0,0 +1,188 @@
+//! Collapse concurrent identical agent requests into one round-trip, so that a cold cache doesn't
+//! cause a stampede of the same request. Requests are identified by the serialized payload, which
+//! doesn't include the sequence number.
+
+use super::protocol::{RequestPayload, Response};
+use super::{HostError, Result};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+/// Error shared with waiting requesters, which can be converted back to the original one, so that
+/// transient errors are still retried.
+#[derive(Clone)]
+enum SharedError {
+    Host(HostError),
+    Io(std::io::ErrorKind, String),
+    Other(String),
+}
+
+impl From<&anyhow::Error> for SharedError {
+    fn from(e: &anyhow::Error) -> Self {
+        if let Some(e) = e.downcast_ref::<HostError>() {
+            return SharedError::Host(*e);
+        }
+        if let Some(e) = e.downcast_ref::<std::io::Error>() {
+            return SharedError::Io(e.kind(), e.to_string());
+        }
+        SharedError::Other(e.to_string())
+    }
+}
+
+impl From<SharedError> for anyhow::Error {
+    fn from(e: SharedError) -> Self {
+        match e {
+            SharedError::Host(e) => e.into(),
+            SharedError::Io(kind, msg) => std::io::Error::new(kind, msg).into(),
+            SharedError::Other(msg) => anyhow::anyhow!(msg),
+        }
+    }
+}
+
+type SharedResult = std::result::Result<Response, SharedError>;
+
+/// Requesters waiting for the request in flight, by the key of the request.
+type Waiters = HashMap<Vec<u8>, Vec<oneshot::Sender<SharedResult>>>;
+
+#[derive(Clone, Default)]
+pub struct SingleFlight {
+    calls: Arc<Mutex<Waiters>>,
+}
+
+/// Removes the call when the requester in flight completes or is cancelled. Waiters of a cancelled
+/// call get `HostError::Disconnected`, and may retry.
+struct Leader<'a> {
+    calls: &'a Mutex<Waiters>,
+    key: Vec<u8>,
+}
+
+impl Leader<'_> {
+    fn take_waiters(&self) -> Vec<oneshot::Sender<SharedResult>> {
+        self.calls.lock().unwrap().remove(&self.key).unwrap_or_default()
+    }
+}
+
+impl Drop for Leader<'_> {
+    fn drop(&mut self) {
+        self.take_waiters();
+    }
+}
+
+/// Key of the request.
+pub fn key_of(request: &RequestPayload) -> Option<Vec<u8>> {
+    bincode::serialize(request).ok()
+}
+
+impl SingleFlight {
+    /// Call `f` for the request with the key, unless the same one is in flight, in which case wait
+    /// for its response instead.
+    pub async fn run<F, Fut>(&self, key: Vec<u8>, f: F) -> Result<Response>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Response>>,
+    {
+        let receiver = {
+            let mut calls = self.calls.lock().unwrap();
+            match calls.get_mut(&key) {
+                Some(waiters) => {
+                    let (tx, rx) = oneshot::channel();
+                    waiters.push(tx);
+                    Some(rx)
+                }
+                None => {
+                    calls.insert(key.clone(), Vec::new());
+                    None
+                }
+            }
+        };
+        if let Some(receiver) = receiver {
+            return match receiver.await {
+                Ok(result) => result.map_err(Into::into),
+                Err(_) => Err(HostError::Disconnected.into()),
+            };
+        }
+
+        let leader = Leader {
+            calls: &self.calls,
+            key,
+        };
+        let result = f().await;
+        for waiter in leader.take_waiters() {
+            let shared = match &result {
+                Ok(response) => Ok(response.clone()),
+                Err(e) => Err(SharedError::from(e)),
+            };
+            let _ = waiter.send(shared);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bridge::model::ActivityListRequest;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_concurrent_identical_requests() {
+        let flight = SingleFlight::default();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let key = key_of(&ActivityListRequest { count: 10, index: 1 }.into()).unwrap();
+
+        let requests = (0..5).map(|_| {
+            let (flight, calls, key) = (flight.clone(), calls.clone(), key.clone());
+            tokio::spawn(async move {
+                flight
+                    .run(key, || async {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        tokio::time::delay_for(Duration::from_millis(100)).await;
+                        Ok(Response {
+                            code: 7,
+                            ..Default::default()
+                        })
+                    })
+                    .await
+            })
+        });
+        for response in futures::future::join_all(requests).await {
+            assert_eq!(response.unwrap().unwrap().code, 7);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // Another page is another request.
+        let other = key_of(&ActivityListRequest { count: 10, index: 2 }.into()).unwrap();
+        assert_ne!(key, other);
+        // Not in flight any more.
+        flight
+            .run(key, || async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Ok(Response::default())
+            })
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_shared_error() {
+        let flight = SingleFlight::default();
+        let key = vec![1u8];
+
+        let leader = flight.run(key.clone(), || async {
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+            Err(HostError::Timeout.into())
+        });
+        let waiter = flight.run(key.clone(), || async { Ok(Response::default()) });
+        let (leader, waiter) = tokio::join!(leader, waiter);
+
+        assert!(leader.is_err());
+        // Still a `HostError`, so that it's retried.
+        assert!(matches!(
+            waiter.unwrap_err().downcast_ref::<HostError>(),
+            Some(HostError::Timeout)
+        ));
+    }
+}
@@ -0,0 +1,104 @@
+//! Transport of agent requests. Models and handlers depend on `AgentTransport` rather than on
+//! `AgentManager`, so that they can be tested with canned responses instead of a live agent.
+
+use super::protocol::{AgentRequest, RequestPayload, ResponseResult};
+use super::{AgentManager, HostError, Result};
+use futures::future::BoxFuture;
+
+pub trait AgentTransport: Send + Sync {
+    /// Send the request to an agent, and get the response payload or the error the agent answered.
+    fn send(&self, request: RequestPayload) -> BoxFuture<'_, Result<ResponseResult>>;
+}
+
+impl dyn AgentTransport {
+    /// Send a typed request, and get the response data of the expected type.
+    pub async fn call<R: AgentRequest>(&self, request: R) -> Result<R::Response> {
+        let payload = self.send(request.into()).await??;
+
+        Ok(R::extract(payload).ok_or(HostError::BadResponse)?)
+    }
+}
+
+impl AgentTransport for AgentManager {
+    fn send(&self, request: RequestPayload) -> BoxFuture<'_, Result<ResponseResult>> {
+        Box::pin(async move { self.request(request).await?.payload() })
+    }
+}
+
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Transport answering each request by the function given, like
+    ///
+    /// ```ignore
+    /// let transport = MockTransport::new(|request| match request {
+    ///     RequestPayload::ScoreList(_) => Ok(ResponsePayload::ScoreList(vec![].into())),
+    ///     _ => Err(ErrorResponse { code: 2, msg: "Unavailable".to_string() }),
+    /// });
+    /// ```
+    pub struct MockTransport {
+        answer: Box<dyn Fn(&RequestPayload) -> ResponseResult + Send + Sync>,
+        sent: AtomicUsize,
+    }
+
+    impl MockTransport {
+        pub fn new(answer: impl Fn(&RequestPayload) -> ResponseResult + Send + Sync + 'static) -> Self {
+            Self {
+                answer: Box::new(answer),
+                sent: AtomicUsize::new(0),
+            }
+        }
+
+        /// Requests sent so far.
+        pub fn sent(&self) -> usize {
+            self.sent.load(Ordering::SeqCst)
+        }
+    }
+
+    impl AgentTransport for MockTransport {
+        fn send(&self, request: RequestPayload) -> BoxFuture<'_, Result<ResponseResult>> {
+            self.sent.fetch_add(1, Ordering::SeqCst);
+            let result = (self.answer)(&request);
+            Box::pin(async move { Ok(result) })
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::mock::MockTransport;
+    use super::*;
+    use crate::bridge::model::{AgentInfo, AgentInfoRequest, PingRequest};
+    use crate::bridge::{ErrorResponse, ResponsePayload};
+
+    #[tokio::test]
+    async fn test_call_through_transport() {
+        let transport: Box<dyn AgentTransport> = Box::new(MockTransport::new(|request| match request {
+            RequestPayload::AgentInfo(_) => Ok(ResponsePayload::AgentInfo(AgentInfo {
+                name: "mock".to_string(),
+            })),
+            RequestPayload::Ping(_) => Ok(ResponsePayload::Empty),
+            _ => Err(ErrorResponse {
+                code: 2,
+                msg: "Unavailable".to_string(),
+            }),
+        }));
+
+        let info = transport.call(AgentInfoRequest).await.unwrap();
+        assert_eq!(info.name, "mock");
+        // A response of another kind.
+        let e = transport.call(PingRequest).await.unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<HostError>(),
+            Some(HostError::BadResponse)
+        ));
+        // Errors answered by agents.
+        let e = transport
+            .call(crate::bridge::model::ActivityListRequest { count: 1, index: 1 })
+            .await
+            .unwrap_err();
+        assert_eq!(e.downcast_ref::<ErrorResponse>().unwrap().code, 2);
+    }
+}
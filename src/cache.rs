@@ -0,0 +1,185 @@
+//! This module has a simple in-memory cache with expiration time, which is used to reduce
+//! queries to database and agents.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Cached value with the time it was stored.
+struct CacheItem<V> {
+    value: V,
+    update_time: Instant,
+}
+
+/// A thread-safe cache. Expired items are kept so that they can be served when the source
+/// is unavailable.
+#[derive(Clone)]
+pub struct Cache<K, V> {
+    items: Arc<Mutex<HashMap<K, CacheItem<V>>>>,
+    ttl: Duration,
+}
+
+impl<K: Eq + Hash, V: Clone> Cache<K, V> {
+    /// Create an empty cache, items in which expire after `ttl`.
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            items: Default::default(),
+            ttl,
+        }
+    }
+
+    /// Get a fresh value.
+    pub fn get(&self, key: &K) -> Option<V> {
+        let items = self.items.lock().unwrap();
+
+        items
+            .get(key)
+            .filter(|item| item.update_time.elapsed() < self.ttl)
+            .map(|item| item.value.clone())
+    }
+
+    /// Get a value even if it has expired.
+    pub fn get_stale(&self, key: &K) -> Option<V> {
+        let items = self.items.lock().unwrap();
+
+        items.get(key).map(|item| item.value.clone())
+    }
+
+    /// Insert or replace a value.
+    pub fn insert(&self, key: K, value: V) {
+        let mut items = self.items.lock().unwrap();
+
+        items.insert(
+            key,
+            CacheItem {
+                value,
+                update_time: Instant::now(),
+            },
+        );
+    }
+
+    /// Remove a value.
+    pub fn remove(&self, key: &K) {
+        let mut items = self.items.lock().unwrap();
+
+        items.remove(key);
+    }
+
+    /// Whether the value is cached, and expired or expires within the duration.
+    pub fn expires_within(&self, key: &K, duration: Duration) -> bool {
+        let items = self.items.lock().unwrap();
+
+        items
+            .get(key)
+            .map(|item| item.update_time.elapsed() + duration >= self.ttl)
+            .unwrap_or(false)
+    }
+}
+
+/// Max keys counted by hit counters of caches to warm.
+pub const MAX_HOT_KEYS: usize = 10000;
+
+/// Request counts of keys, to find the most requested ones.
+#[derive(Clone)]
+pub struct HitCounter<K> {
+    hits: Arc<Mutex<HashMap<K, u64>>>,
+    /// Max keys counted. New keys are ignored once full, until cold ones fade out.
+    capacity: usize,
+}
+
+impl<K: Eq + Hash + Clone + Ord> HitCounter<K> {
+    /// Count at most `capacity` keys, or nothing if 0.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            hits: Default::default(),
+            capacity,
+        }
+    }
+
+    pub fn hit(&self, key: &K) {
+        let mut hits = self.hits.lock().unwrap();
+
+        if let Some(n) = hits.get_mut(key) {
+            *n += 1;
+        } else if hits.len() < self.capacity {
+            hits.insert(key.clone(), 1);
+        }
+    }
+
+    /// Get at most `count` keys requested most, and halve counts of all keys, so that keys no
+    /// longer requested fade out.
+    pub fn hottest(&self, count: usize) -> Vec<K> {
+        let mut hits = self.hits.lock().unwrap();
+
+        let mut keys: Vec<(&K, &u64)> = hits.iter().collect();
+        keys.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+        let hottest = keys.into_iter().take(count).map(|(k, _)| k.clone()).collect();
+
+        hits.retain(|_, n| {
+            *n /= 2;
+            *n > 0
+        });
+        hottest
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Cache, HitCounter};
+    use std::time::Duration;
+
+    #[test]
+    pub fn test_cache_expiration() {
+        let cache = Cache::new(Duration::from_millis(0));
+
+        cache.insert(1, "a");
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.get_stale(&1), Some("a"));
+
+        cache.remove(&1);
+        assert_eq!(cache.get_stale(&1), None);
+    }
+
+    #[test]
+    pub fn test_expires_within() {
+        let cache = Cache::new(Duration::from_secs(60));
+
+        cache.insert(1, "a");
+        assert!(!cache.expires_within(&1, Duration::from_secs(30)));
+        assert!(cache.expires_within(&1, Duration::from_secs(60)));
+        assert!(!cache.expires_within(&2, Duration::from_secs(60)));
+    }
+
+    #[test]
+    pub fn test_hottest_keys() {
+        let counter = HitCounter::new(10);
+        for (key, n) in &[("a", 1), ("b", 4), ("c", 2)] {
+            for _ in 0..*n {
+                counter.hit(key);
+            }
+        }
+
+        assert_eq!(counter.hottest(2), ["b", "c"]);
+        // Counts are halved, and "a" fades out.
+        assert_eq!(counter.hottest(3), ["b", "c"]);
+        assert_eq!(counter.hottest(3), ["b"]);
+    }
+
+    #[test]
+    pub fn test_hit_counter_capacity() {
+        let counter = HitCounter::new(2);
+        for key in &["a", "b", "c", "a"] {
+            counter.hit(key);
+        }
+        // "c" is ignored since full.
+        assert_eq!(counter.hottest(3), ["a", "b"]);
+        // "b" faded out, and there is room again.
+        counter.hit(&"c");
+        assert_eq!(counter.hottest(3), ["a", "c"]);
+
+        let counter = HitCounter::new(0);
+        counter.hit(&"a");
+        assert!(counter.hottest(3).is_empty());
+    }
+}
@@ -0,0 +1,157 @@
+//! Local time of the deployment, in the timezone configured rather than the one of the server
+//! clock, so that days begin at midnight where students are.
+
+use chrono::{DateTime, FixedOffset, NaiveDate, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
+use std::sync::RwLock;
+
+/// Timezone used if neither `timezone` nor `server.utc_offset` is configured.
+pub const DEFAULT_TIMEZONE: Tz = chrono_tz::Asia::Shanghai;
+/// Range of offsets in hours of timezones in use.
+const UTC_OFFSETS: std::ops::RangeInclusive<i32> = -12..=14;
+
+/// Timezone where calendar days are counted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Zone {
+    /// IANA timezone, like `Asia/Shanghai`.
+    Named(Tz),
+    /// Fixed offset from UTC.
+    Fixed(FixedOffset),
+}
+
+impl Zone {
+    /// Zone of the config: `timezone` first, and then `server.utc_offset` in hours. Unknown names
+    /// and offsets out of -12 to 14 hours are refused with the setting to blame.
+    pub fn configured(timezone: Option<&str>, utc_offset: Option<i32>) -> Result<Self, String> {
+        match (timezone, utc_offset) {
+            (Some(name), _) => name
+                .parse()
+                .map(Zone::Named)
+                .map_err(|_| format!("timezone: unknown timezone \"{}\"", name)),
+            (None, Some(hours)) => UTC_OFFSETS
+                .contains(&hours)
+                .then(|| FixedOffset::east_opt(hours * 3600))
+                .flatten()
+                .map(Zone::Fixed)
+                .ok_or_else(|| {
+                    format!(
+                        "server.utc_offset: {} is out of {} to {} hours",
+                        hours,
+                        UTC_OFFSETS.start(),
+                        UTC_OFFSETS.end()
+                    )
+                }),
+            (None, None) => Ok(Zone::Named(DEFAULT_TIMEZONE)),
+        }
+    }
+
+    /// Local time of the instant in the zone.
+    pub fn local<T: TimeZone>(&self, time: &DateTime<T>) -> DateTime<FixedOffset> {
+        match self {
+            Zone::Named(tz) => {
+                let time = time.with_timezone(tz);
+                time.with_timezone(&time.offset().fix())
+            }
+            Zone::Fixed(offset) => time.with_timezone(offset),
+        }
+    }
+
+    /// Argument of `SET TIME ZONE`, so that dates computed in SQL agree with the service.
+    pub fn to_sql(self) -> String {
+        match self {
+            Zone::Named(tz) => format!("'{}'", tz.name()),
+            Zone::Fixed(offset) => {
+                let seconds = offset.local_minus_utc();
+                let sign = if seconds < 0 { '-' } else { '+' };
+                let minutes = seconds.abs() / 60;
+                format!(
+                    "INTERVAL '{}{:02}:{:02}' HOUR TO MINUTE",
+                    sign,
+                    minutes / 60,
+                    minutes % 60
+                )
+            }
+        }
+    }
+}
+
+lazy_static! {
+    /// Zone set on startup.
+    static ref ZONE: RwLock<Zone> = RwLock::new(Zone::Named(DEFAULT_TIMEZONE));
+}
+
+/// Set the zone on startup.
+pub fn init(zone: Zone) {
+    *ZONE.write().unwrap() = zone;
+}
+
+pub fn zone() -> Zone {
+    *ZONE.read().unwrap()
+}
+
+/// Current time in the configured zone.
+pub fn now_local() -> DateTime<FixedOffset> {
+    zone().local(&Utc::now())
+}
+
+/// Date of today in the configured zone.
+pub fn today() -> NaiveDate {
+    now_local().naive_local().date()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_day_boundary_in_zone() {
+        // 16:30 UTC is 00:30 the next day in China, but still the day in London.
+        let time = Utc.ymd(2021, 8, 31).and_hms(16, 30, 0);
+        let date = |zone: Zone| zone.local(&time).naive_local().date();
+
+        let configured = |timezone, utc_offset| Zone::configured(timezone, utc_offset).unwrap();
+
+        assert_eq!(date(configured(None, None)), NaiveDate::from_ymd(2021, 9, 1));
+        assert_eq!(
+            date(configured(Some("Europe/London"), Some(8))),
+            NaiveDate::from_ymd(2021, 8, 31)
+        );
+        assert_eq!(date(configured(None, Some(8))), NaiveDate::from_ymd(2021, 9, 1));
+        assert_eq!(
+            date(configured(None, Some(-10))),
+            NaiveDate::from_ymd(2021, 8, 31)
+        );
+        // Daylight saving time of the named zone is followed.
+        let winter = Utc.ymd(2021, 1, 1).and_hms(23, 30, 0);
+        let london = Zone::Named(chrono_tz::Europe::London);
+        assert_eq!(
+            london.local(&winter).naive_local().date(),
+            NaiveDate::from_ymd(2021, 1, 1)
+        );
+        assert_eq!(london.local(&time).offset().local_minus_utc(), 3600);
+    }
+
+    #[test]
+    fn test_invalid_zone() {
+        assert_eq!(
+            Zone::configured(Some("Mars/Olympus"), None),
+            Err("timezone: unknown timezone \"Mars/Olympus\"".to_string())
+        );
+        assert_eq!(
+            Zone::configured(None, Some(24)),
+            Err("server.utc_offset: 24 is out of -12 to 14 hours".to_string())
+        );
+        assert!(Zone::configured(None, Some(-13)).is_err());
+        assert!(Zone::configured(None, Some(i32::MAX)).is_err());
+        assert!(Zone::configured(None, Some(14)).is_ok());
+    }
+
+    #[test]
+    fn test_zone_to_sql() {
+        let configured = |utc_offset| Zone::configured(None, utc_offset).unwrap();
+
+        assert_eq!(configured(None).to_sql(), "'Asia/Shanghai'");
+        assert_eq!(configured(Some(8)).to_sql(), "INTERVAL '+08:00' HOUR TO MINUTE");
+        assert_eq!(configured(Some(-5)).to_sql(), "INTERVAL '-05:00' HOUR TO MINUTE");
+    }
+}
@@ -1,4 +1,15 @@
+use crate::bridge::{ErrorResponse, HostError};
+use crate::models::edu::EduError;
+use crate::models::event::EventError;
+use crate::models::file::AttachmentError;
+use crate::models::freshman::FreshmanError;
+use crate::models::motto::MottoError;
+use crate::models::pay::BalanceError;
+use crate::models::search::SearchError;
 use crate::models::user::wechat::WxErr;
+use crate::models::user::UserError;
+use crate::models::CommonError;
+use crate::services::ApiResponse;
 use actix_http::error::PayloadError;
 use actix_http::{http::StatusCode, ResponseBuilder};
 use actix_web::{error::ResponseError, HttpResponse};
@@ -12,9 +23,31 @@ use std::fmt;
 use std::fmt::Formatter;
 use std::io::Error as StdIoError;
 
+mod locale;
+
+pub use locale::{Language, Translate, LANGUAGE};
+
 pub type Result<T> = std::result::Result<T, ApiError>;
 pub type Error = ApiError;
 
+/// Agent errors meaning no agent can serve the request for now.
+const AGENT_UNAVAILABLE_ERRORS: &[HostError] = &[
+    HostError::NoAgentAvailable,
+    HostError::ShuttingDown,
+    HostError::Overloaded,
+];
+
+/// Agent errors meaning the agent or the campus system behind it failed to answer properly.
+const BAD_GATEWAY_ERRORS: &[HostError] = &[
+    HostError::Disconnected,
+    HostError::AgentUnavailable,
+    HostError::BadResponse,
+    HostError::ChecksumMismatch,
+    HostError::BadFrame,
+    HostError::Deserialize,
+    HostError::UnknownAgentError,
+];
+
 // Reference:
 // [Actix error handler](https://actix.rs/docs/errors/)
 // [fmt::Display](https://doc.rust-lang.org/std/fmt/trait.Display.html)
@@ -29,6 +62,12 @@ pub struct ApiError {
     pub inner_msg: Option<String>,
     #[serde(rename(serialize = "msg"), skip_serializing_if = "Option::is_none")]
     pub error_msg: Option<String>,
+    /// Message in English, if the error is a known variant.
+    #[serde(skip_serializing)]
+    pub english_msg: Option<&'static str>,
+    /// Type name of the known variant, since codes of modules may collide.
+    #[serde(skip_serializing)]
+    pub kind: Option<&'static str>,
 }
 
 impl fmt::Display for ApiError {
@@ -43,22 +82,118 @@ impl fmt::Display for ApiError {
 }
 
 impl ResponseError for ApiError {
-    // Always return 200 ok and prompt real code at json body.
+    // Always return 200 ok and prompt real code at json body, except that the service is unavailable
+    // temporarily, so that the gateway and the client can retry later, and that `If-Match` fails,
+    // requests are too frequent or the campus credential is wrong, which clients handle by HTTP status.
+    // Agent failures are reported as gateway errors, so that they're not taken as bugs of the server.
     fn status_code(&self) -> StatusCode {
+        if self.is_unavailable() || self.is_host_error(AGENT_UNAVAILABLE_ERRORS) {
+            return StatusCode::SERVICE_UNAVAILABLE;
+        }
+        if self.is_host_error(&[HostError::Timeout]) {
+            return StatusCode::GATEWAY_TIMEOUT;
+        }
+        if self.is_host_error(BAD_GATEWAY_ERRORS) {
+            return StatusCode::BAD_GATEWAY;
+        }
+        if self.is(CommonError::PreconditionFailed) {
+            return StatusCode::PRECONDITION_FAILED;
+        }
+        if self.is(CommonError::TooManyRequests) {
+            return StatusCode::TOO_MANY_REQUESTS;
+        }
+        if self.is(CommonError::RequestInProgress) {
+            return StatusCode::CONFLICT;
+        }
+        if self.is(AttachmentError::TooLarge) || self.is(CommonError::PayloadTooLarge) {
+            return StatusCode::PAYLOAD_TOO_LARGE;
+        }
+        if self.is(AttachmentError::NotFound)
+            || self.is(AttachmentError::NoThumbnail)
+            || self.is(EventError::NoSuchEvent)
+        {
+            return StatusCode::NOT_FOUND;
+        }
+        // So that clients prompt for the campus credential again.
+        if self.is(HostError::CampusAuthFailed) {
+            return StatusCode::UNAUTHORIZED;
+        }
         StatusCode::OK
     }
     // Make json response body for error.
     fn error_response(&self) -> HttpResponse {
-        ResponseBuilder::new(self.status_code()).json(self)
+        self.response(self.status_code())
     }
 }
 
 impl ApiError {
-    pub fn new<T: ToPrimitive + std::error::Error>(sub_err: T) -> Self {
+    pub fn new<T: ToPrimitive + std::error::Error + Translate>(sub_err: T) -> Self {
+        Self::from_ref(&sub_err)
+    }
+
+    fn from_ref<T: ToPrimitive + std::error::Error + Translate>(sub_err: &T) -> Self {
         Self {
             code: sub_err.to_u16().unwrap(),
             inner_msg: None,
             error_msg: Some(sub_err.to_string()),
+            english_msg: Some(sub_err.english()),
+            kind: Some(std::any::type_name::<T>()),
+        }
+    }
+
+    /// Message in the language of the current request. Errors without translation, like those
+    /// from libraries or agents, are shown as is.
+    pub fn localized_msg(&self) -> Option<String> {
+        match (Language::current(), self.english_msg) {
+            (Language::English, Some(msg)) => Some(msg.to_string()),
+            _ => self.error_msg.clone(),
+        }
+    }
+
+    /// Response with the error in the common body, and the given status, for middlewares and
+    /// extractors which reject requests with a specific status.
+    pub fn response(&self, status: StatusCode) -> HttpResponse {
+        ResponseBuilder::new(status).json(ApiResponse::from(self))
+    }
+
+    /// Whether the error is caused by a temporarily unavailable dependency, like the database.
+    pub fn is_unavailable(&self) -> bool {
+        self.is(CommonError::Unavailable)
+    }
+
+    /// Whether agents or the campus system behind failed, rather than the request is refused.
+    pub fn is_upstream_failure(&self) -> bool {
+        self.is_host_error(AGENT_UNAVAILABLE_ERRORS)
+            || self.is_host_error(BAD_GATEWAY_ERRORS)
+            || self.is_host_error(&[HostError::Timeout])
+    }
+
+    /// Whether it's the variant, checked by type as well because codes of modules may collide.
+    fn is<T: ToPrimitive>(&self, error: T) -> bool {
+        self.kind == Some(std::any::type_name::<T>()) && error.to_u16() == Some(self.code)
+    }
+
+    fn is_host_error(&self, errors: &[HostError]) -> bool {
+        self.kind == Some(std::any::type_name::<HostError>())
+            && errors.iter().any(|e| e.to_u16() == Some(self.code))
+    }
+}
+
+impl From<SqlError> for ApiError {
+    fn from(sub_err: SqlError) -> Self {
+        match sub_err {
+            // Failed to acquire a connection from the pool.
+            SqlError::PoolTimedOut | SqlError::PoolClosed | SqlError::Io(_) => Self {
+                inner_msg: Some(sub_err.to_string()),
+                ..CommonError::Unavailable.into()
+            },
+            _ => Self {
+                code: 1,
+                inner_msg: None,
+                error_msg: Some(sub_err.to_string()),
+                english_msg: None,
+                kind: None,
+            },
         }
     }
 }
@@ -69,6 +204,8 @@ impl From<WxErr> for ApiError {
             code: e.errcode,
             inner_msg: Some(e.errmsg),
             error_msg: None,
+            english_msg: None,
+            kind: None,
         }
     }
 }
@@ -82,6 +219,8 @@ macro_rules! convert_inner_errors {
                     code: 1,
                     inner_msg: None,
                     error_msg: Some(sub_err.to_string()),
+                    english_msg: None,
+                    kind: None,
                 }
             }
         }
@@ -92,6 +231,154 @@ convert_inner_errors!(String);
 convert_inner_errors!(PayloadError);
 convert_inner_errors!(JsonError);
 convert_inner_errors!(JwtError);
-convert_inner_errors!(SqlError);
 convert_inner_errors!(StdIoError);
-convert_inner_errors!(AnyError);
+
+/// Render module errors as `ApiError`, so that they can be returned by handlers directly.
+macro_rules! impl_response_error {
+    ($($err_type: ident),*) => {
+        $(
+            impl ResponseError for $err_type {
+                fn status_code(&self) -> StatusCode {
+                    ApiError::from_ref(self).status_code()
+                }
+
+                fn error_response(&self) -> HttpResponse {
+                    ApiError::from_ref(self).error_response()
+                }
+            }
+        )*
+    };
+}
+
+impl_response_error!(
+    CommonError,
+    UserError,
+    MottoError,
+    AttachmentError,
+    BalanceError,
+    SearchError,
+    EventError,
+    EduError,
+    FreshmanError
+);
+
+impl From<AnyError> for ApiError {
+    fn from(e: AnyError) -> Self {
+        // Errors from agent requests are reported as is.
+        let e = match e.downcast::<ErrorResponse>() {
+            Ok(resp) => return resp.into(),
+            Err(e) => e,
+        };
+        let e = match e.downcast::<HostError>() {
+            Ok(e) => return ApiError::new(e),
+            Err(e) => e,
+        };
+        Self {
+            code: 1,
+            inner_msg: None,
+            error_msg: Some(e.to_string()),
+            english_msg: None,
+            kind: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_pool_timeout_to_unavailable() {
+        let e = ApiError::from(SqlError::PoolTimedOut);
+
+        assert!(e.is_unavailable());
+        assert_eq!(e.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            ApiError::from(SqlError::RowNotFound).status_code(),
+            StatusCode::OK
+        );
+    }
+
+    #[test]
+    pub fn test_precondition_failed_status() {
+        let e = ApiError::new(CommonError::PreconditionFailed);
+        assert_eq!(e.status_code(), StatusCode::PRECONDITION_FAILED);
+    }
+
+    #[test]
+    pub fn test_too_large_status() {
+        let e = ApiError::new(AttachmentError::TooLarge);
+        assert_eq!(e.status_code(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    pub fn test_payload_too_large_status() {
+        let e = ApiError::new(CommonError::PayloadTooLarge);
+        assert_eq!(e.status_code(), StatusCode::PAYLOAD_TOO_LARGE);
+        // The same code in another module.
+        let e = ApiError::new(EventError::NoSuchEvent);
+        assert_eq!(e.code, CommonError::PayloadTooLarge.to_u16().unwrap());
+        assert_eq!(e.status_code(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    pub fn test_agent_error_status() {
+        let status_of = |e: HostError| ApiError::from(AnyError::from(e)).status_code();
+
+        assert_eq!(status_of(HostError::Timeout), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(status_of(HostError::Disconnected), StatusCode::BAD_GATEWAY);
+        assert_eq!(status_of(HostError::ChecksumMismatch), StatusCode::BAD_GATEWAY);
+        assert_eq!(status_of(HostError::BadFrame), StatusCode::BAD_GATEWAY);
+        assert_eq!(
+            status_of(HostError::NoAgentAvailable),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(status_of(HostError::Overloaded), StatusCode::SERVICE_UNAVAILABLE);
+        // Rejected by the server itself.
+        assert_eq!(status_of(HostError::TooLargePayload), StatusCode::OK);
+    }
+
+    #[test]
+    pub fn test_io_error_is_internal() {
+        use std::io::ErrorKind;
+
+        // Agent IO errors are mapped in the bridge, others are internal errors.
+        let e = ApiError::from(AnyError::from(StdIoError::from(ErrorKind::TimedOut)));
+        assert_eq!(e.code, 1);
+        assert_eq!(e.status_code(), StatusCode::OK);
+    }
+
+    #[test]
+    pub fn test_colliding_code_not_agent_error() {
+        use crate::models::freshman::FreshmanError;
+
+        // Codes of freshman errors overlap agent errors.
+        let e = ApiError::new(FreshmanError::NoSuchAccount);
+        assert_eq!(e.code, HostError::NoAgentAvailable.to_u16().unwrap());
+        assert_eq!(e.status_code(), StatusCode::OK);
+        assert!(!e.is_upstream_failure());
+    }
+
+    #[test]
+    pub fn test_status_by_type() {
+        // Errors from libraries and other services may carry any code.
+        let e = ApiError::from(WxErr {
+            errcode: CommonError::PreconditionFailed.to_u16().unwrap(),
+            errmsg: "error".to_string(),
+        });
+        assert_eq!(e.status_code(), StatusCode::OK);
+        let e = ApiError::from(WxErr {
+            errcode: HostError::CampusAuthFailed.to_u16().unwrap(),
+            errmsg: "error".to_string(),
+        });
+        assert_eq!(e.status_code(), StatusCode::OK);
+    }
+
+    #[test]
+    pub fn test_not_found_status() {
+        let e = ApiError::new(AttachmentError::NotFound);
+        assert_eq!(e.status_code(), StatusCode::NOT_FOUND);
+        let e = ApiError::new(EventError::NoSuchEvent);
+        assert_eq!(e.status_code(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,249 @@
+//! Error messages in languages other than Chinese. The language of a request is chosen by the
+//! `lang` parameter or the `Accept-Language` header, and error messages are rendered in it when the
+//! response is serialized. Codes are the same in all languages.
+
+use crate::bridge::HostError;
+use crate::models::edu::EduError;
+use crate::models::event::EventError;
+use crate::models::file::AttachmentError;
+use crate::models::freshman::FreshmanError;
+use crate::models::motto::MottoError;
+use crate::models::pay::BalanceError;
+use crate::models::search::SearchError;
+use crate::models::user::UserError;
+use crate::models::CommonError;
+
+tokio::task_local! {
+    /// Language of the request being handled.
+    pub static LANGUAGE: Language;
+}
+
+/// Languages of error messages.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Language {
+    /// Messages defined with error variants.
+    #[default]
+    Chinese,
+    English,
+}
+
+impl Language {
+    /// Language of a tag like `en-US` or `zh`, or `None` if unsupported.
+    pub fn from_tag(tag: &str) -> Option<Self> {
+        let primary = tag.trim().split(&['-', '_'][..]).next().unwrap_or_default();
+
+        match primary.to_ascii_lowercase().as_str() {
+            "zh" => Some(Language::Chinese),
+            "en" => Some(Language::English),
+            _ => None,
+        }
+    }
+
+    /// The supported language with the highest quality in an `Accept-Language` header, like
+    /// `en-US,en;q=0.9,zh;q=0.8`.
+    pub fn from_accept_language(header: &str) -> Option<Self> {
+        let mut best: Option<(Self, f32)> = None;
+
+        for item in header.split(',') {
+            let mut parts = item.split(';');
+            let language = match parts.next().and_then(Language::from_tag) {
+                Some(language) => language,
+                None => continue,
+            };
+            let quality = parts
+                .find_map(|x| x.trim().strip_prefix("q="))
+                .and_then(|x| x.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            // The first one wins on equal quality.
+            if quality > 0.0 && best.is_none_or(|(_, q)| quality > q) {
+                best = Some((language, quality));
+            }
+        }
+        best.map(|(language, _)| language)
+    }
+
+    /// Language of the request being handled, or the default one out of requests.
+    pub fn current() -> Self {
+        LANGUAGE.try_with(|x| *x).unwrap_or_default()
+    }
+}
+
+/// Messages of error variants in other languages.
+pub trait Translate {
+    fn english(&self) -> &'static str;
+}
+
+impl Translate for CommonError {
+    fn english(&self) -> &'static str {
+        match self {
+            CommonError::Success => "OK.",
+            CommonError::Internal => "Internal error, please retry or contact us.",
+            CommonError::Parameter => "Invalid parameters.",
+            CommonError::AddrNotSupported => "Service is not available in your region.",
+            CommonError::LoginNeeded => "Login needed.",
+            CommonError::Forbidden => "Permission denied.",
+            CommonError::Unavailable => "Service is temporarily unavailable, please retry later.",
+            CommonError::PreconditionFailed => "Data has been modified, please refresh and retry.",
+            CommonError::TooManyRequests => "Too many requests, please retry later.",
+            CommonError::PayloadTooLarge => "Request is too large.",
+            CommonError::RequestInProgress => "The same request is in progress, please retry later.",
+        }
+    }
+}
+
+impl Translate for UserError {
+    fn english(&self) -> &'static str {
+        match self {
+            UserError::Disabled => "Account is disabled.",
+            UserError::NoSuchUser => "No such user.",
+            UserError::OaNetworkFailed => "Failed to connect to the campus network for authentication.",
+            UserError::OaSecretFailed => "OA password is incorrect.",
+            UserError::InvalidIdNumber => "Invalid ID card number.",
+            UserError::AuthTypeNotAllowed => "Login with username and password is not allowed.",
+            UserError::LoginFailed => "Invalid credential.",
+            UserError::InvalidStudentId => "Invalid student ID.",
+            UserError::DefaultSecret => {
+                "Default password is not allowed, please change it on the campus network first."
+            }
+            UserError::IdentityNeeded => "Real-name identity is needed.",
+            UserError::WechatNetworkFailed => "Failed to connect to WeChat.",
+            UserError::VerifyCodeMismatch => "Incorrect verification code.",
+            UserError::VerifyCodeExpired => "Verification code expired, please request a new one.",
+            UserError::InvalidContact => "Invalid phone number or email.",
+            UserError::LastAdmin => "The last administrator can't be revoked, deleted or disabled.",
+            UserError::AccountExists => "Username is taken.",
+            UserError::NoSuchStudentNo => "No student ID for the campus card number.",
+            UserError::CaptchaMismatch => "Incorrect captcha.",
+            UserError::CaptchaExpired => "Captcha expired, please refresh.",
+            UserError::TotpNeeded => "Two-factor authentication code is needed.",
+            UserError::TotpMismatch => "Two-factor authentication code is incorrect or used.",
+            UserError::TotpEnabled => "Two-factor authentication is enabled already.",
+            UserError::TotpNotEnrolled => "Two-factor authentication is not enrolled.",
+            UserError::IdentityNotOwned => "The student id is not bound to the user.",
+            UserError::AlreadyBound => "The student id is bound to another user.",
+            UserError::AccountLocked => "Too many failed logins, the account is locked for a while.",
+        }
+    }
+}
+
+impl Translate for MottoError {
+    fn english(&self) -> &'static str {
+        match self {
+            MottoError::NoMoreItem => "No data.",
+            MottoError::NotFound => "No such motto.",
+            MottoError::InvalidContent => "Content is empty or too long.",
+        }
+    }
+}
+
+impl Translate for AttachmentError {
+    fn english(&self) -> &'static str {
+        match self {
+            AttachmentError::FilenameRefused => "Invalid filename.",
+            AttachmentError::NotFound => "File not found.",
+            AttachmentError::FailedToWrite => "Failed to write the file.",
+            AttachmentError::Interrupted => "Upload interrupted.",
+            AttachmentError::NoPayload => "No file to upload.",
+            AttachmentError::TooLarge => "File is too large.",
+            AttachmentError::DeniedType => "File type is not allowed.",
+            AttachmentError::InvalidSignature => "Link is invalid or expired.",
+            AttachmentError::ChunkOutOfOrder => "Chunks are out of order.",
+            AttachmentError::IncompleteUpload => "File is not uploaded completely.",
+            AttachmentError::ChecksumMismatch => "File checksum mismatch.",
+            AttachmentError::Infected => "File failed the security scan.",
+            AttachmentError::ScanFailed => "Failed to scan the file, please retry later.",
+            AttachmentError::NoThumbnail => "The file has no thumbnail.",
+        }
+    }
+}
+
+impl Translate for BalanceError {
+    fn english(&self) -> &'static str {
+        match self {
+            BalanceError::NoSuchRoom => "No data of the room.",
+        }
+    }
+}
+
+impl Translate for SearchError {
+    fn english(&self) -> &'static str {
+        match self {
+            SearchError::NeedIdentity => "Real-name identity is needed to view this category.",
+        }
+    }
+}
+
+impl Translate for EventError {
+    fn english(&self) -> &'static str {
+        match self {
+            EventError::DuplicatedEvent => "Event already exists.",
+            EventError::NoSuchEvent => "No such event.",
+            EventError::DuplicatedApply => "Already applied.",
+            EventError::AlreadySigned => "Already signed in.",
+        }
+    }
+}
+
+impl Translate for EduError {
+    fn english(&self) -> &'static str {
+        match self {
+            EduError::OaAccountNeeded => "Real-name identity with an OA account is needed.",
+        }
+    }
+}
+
+impl Translate for FreshmanError {
+    fn english(&self) -> &'static str {
+        match self {
+            FreshmanError::NoSuchAccount => "No matching freshman.",
+            FreshmanError::DismatchAccount => "Account mismatch.",
+            FreshmanError::BoundAlready => "Already bound.",
+            FreshmanError::SecretNeeded => "Secret needed.",
+            FreshmanError::MissingColumns => "Required columns are missing in the file.",
+        }
+    }
+}
+
+impl Translate for HostError {
+    fn english(&self) -> &'static str {
+        match self {
+            HostError::NoAgentAvailable => "No agent available to connect to the campus network.",
+            HostError::Timeout => "Request timed out.",
+            HostError::Disconnected => "Connection closed.",
+            HostError::AgentUnavailable => "The agent is unavailable.",
+            HostError::BadResponse => "Response doesn't match the request.",
+            HostError::InvalidAgent => "Invalid agent.",
+            HostError::TooLargePayload => "Payload is too large.",
+            HostError::NoSuchAgent => "No such agent.",
+            HostError::ShuttingDown => "Service is shutting down.",
+            HostError::ChecksumMismatch => "Response checksum mismatch.",
+            HostError::CampusAuthFailed => {
+                "Campus account authentication failed, please check the credential."
+            }
+            HostError::CampusNotFound => "No related data in the campus system.",
+            HostError::BadFrame => "Malformed frame.",
+            HostError::Serialize => "Failed to serialize the request.",
+            HostError::Deserialize => "Failed to parse the response.",
+            HostError::Overloaded => "Too many requests, please try again later.",
+            HostError::UnknownAgentError => "Unknown error from the campus system.",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_accept_language() {
+        assert_eq!(Language::from_tag("en-US"), Some(Language::English));
+        assert_eq!(Language::from_tag("zh_CN"), Some(Language::Chinese));
+        assert_eq!(Language::from_tag("fr"), None);
+
+        let parse = Language::from_accept_language;
+        assert_eq!(parse("en-US,en;q=0.9,zh;q=0.8"), Some(Language::English));
+        assert_eq!(parse("fr, zh-CN;q=0.8, en;q=0.5"), Some(Language::Chinese));
+        assert_eq!(parse("en;q=0, zh;q=0.1"), Some(Language::Chinese));
+        assert_eq!(parse("fr, *"), None);
+    }
+}
@@ -1,41 +1,211 @@
-use jsonwebtoken;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 
-use crate::config::CONFIG;
+use crate::config::{JwtAlgorithm, JwtConfig, JwtKeyConfig, CONFIG};
 use crate::error::Result;
 
-pub fn encode_jwt<T: Serialize>(claims: &T) -> Result<String> {
-    let key = &CONFIG.server.secret.as_ref();
-    let encoding_key = jsonwebtoken::EncodingKey::from_secret(key);
-
-    Ok(jsonwebtoken::encode(
-        &jsonwebtoken::Header::default(),
-        &claims,
-        &encoding_key,
-    )?)
+lazy_static! {
+    static ref KEYS: JwtKeys = JwtKeys::from_config(&CONFIG.jwt, &CONFIG.server.secret)
+        .unwrap_or_else(|e| panic!("Invalid JWT config: {}", e));
+}
+
+/// Load keys on startup, so that a bad config fails loudly before any request.
+pub fn init_keys() {
+    lazy_static::initialize(&KEYS);
+}
+
+/// Key to sign new tokens, and keys to verify tokens.
+pub struct JwtKeys {
+    algorithm: Algorithm,
+    encoding: EncodingKey,
+    /// The current key first, and then retired ones.
+    decoding: Vec<(Algorithm, DecodingKey<'static>)>,
+}
+
+fn read_pem(path: &Option<String>, name: &str) -> std::result::Result<Vec<u8>, String> {
+    let path = path.as_ref().ok_or(format!("{} is required by RS256", name))?;
+    std::fs::read(path).map_err(|e| format!("Could not read {}: {}", path, e))
+}
+
+/// Secret of HS256, which must not be empty.
+fn secret_of<'a>(
+    config: &'a JwtKeyConfig,
+    default: Option<&'a str>,
+) -> std::result::Result<&'a [u8], String> {
+    match config.secret.as_deref().or(default) {
+        Some(secret) if !secret.is_empty() => Ok(secret.as_bytes()),
+        _ => Err("No secret is configured for HS256".to_string()),
+    }
 }
 
-pub fn decode_jwt<'a, T: DeserializeOwned>(token: &str) -> Option<T> {
-    let key = &CONFIG.server.secret.as_ref();
-    let decoding_key = jsonwebtoken::DecodingKey::from_secret(key);
-    let option = jsonwebtoken::Validation {
-        validate_exp: false,
-        ..jsonwebtoken::Validation::default()
-    };
-    let t = jsonwebtoken::decode::<T>(&token, &decoding_key, &option);
-
-    if let Ok(token_data) = t {
-        Some(token_data.claims)
-    } else {
-        None
+fn decoding_key(
+    config: &JwtKeyConfig,
+    default_secret: Option<&str>,
+) -> std::result::Result<(Algorithm, DecodingKey<'static>), String> {
+    match config.algorithm {
+        JwtAlgorithm::HS256 => {
+            let secret = secret_of(config, default_secret)?;
+            Ok((Algorithm::HS256, DecodingKey::from_secret(secret).into_static()))
+        }
+        JwtAlgorithm::RS256 => {
+            let pem = read_pem(&config.public_key, "public_key")?;
+            let key = DecodingKey::from_rsa_pem(&pem).map_err(|e| e.to_string())?;
+            Ok((Algorithm::RS256, key.into_static()))
+        }
     }
 }
 
+impl JwtKeys {
+    /// Load keys. For HS256, `default_secret` is used if the current key has no secret.
+    pub fn from_config(config: &JwtConfig, default_secret: &str) -> std::result::Result<Self, String> {
+        let current = &config.current;
+        let encoding = match current.algorithm {
+            JwtAlgorithm::HS256 => EncodingKey::from_secret(secret_of(current, Some(default_secret))?),
+            JwtAlgorithm::RS256 => {
+                let pem = read_pem(&current.private_key, "private_key")?;
+                EncodingKey::from_rsa_pem(&pem).map_err(|e| e.to_string())?
+            }
+        };
+        let (algorithm, key) = decoding_key(current, Some(default_secret))?;
+        let mut decoding = vec![(algorithm, key)];
+        for previous in &config.previous {
+            decoding.push(decoding_key(previous, None)?);
+        }
+
+        Ok(Self {
+            algorithm,
+            encoding,
+            decoding,
+        })
+    }
+
+    /// Sign the claims with the current key.
+    pub fn encode<T: Serialize>(&self, claims: &T) -> Result<String> {
+        Ok(jsonwebtoken::encode(
+            &Header::new(self.algorithm),
+            &claims,
+            &self.encoding,
+        )?)
+    }
+
+    /// Decode the token which may have expired within `leeway` seconds, by any of the keys.
+    pub fn decode<T: DeserializeOwned>(&self, token: &str, leeway: u64) -> Option<T> {
+        self.decoding.iter().find_map(|(algorithm, key)| {
+            // Validate `exp` field by default.
+            let option = Validation {
+                leeway,
+                algorithms: vec![*algorithm],
+                ..Validation::default()
+            };
+            jsonwebtoken::decode::<T>(token, key, &option)
+                .ok()
+                .map(|x| x.claims)
+        })
+    }
+}
+
+pub fn encode_jwt<T: Serialize>(claims: &T) -> Result<String> {
+    KEYS.encode(claims)
+}
+
+/// Decode the token, and return `None` if it's invalid or expired.
+pub fn decode_jwt<T: DeserializeOwned>(token: &str) -> Option<T> {
+    KEYS.decode(token, 0)
+}
+
+/// Decode the token which may have expired within `leeway` seconds.
+pub fn decode_jwt_with_leeway<T: DeserializeOwned>(token: &str, leeway: u64) -> Option<T> {
+    KEYS.decode(token, leeway)
+}
+
 pub fn validate_jwt<T: DeserializeOwned>(token: &str) -> bool {
     decode_jwt::<T>(token).is_some()
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::services::JwtToken;
+
+    fn hs256(secret: &str) -> JwtKeyConfig {
+        JwtKeyConfig {
+            secret: Some(secret.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn keys(secret: &str) -> JwtKeys {
+        JwtKeys::from_config(&JwtConfig::default(), secret).unwrap()
+    }
+
+    #[test]
+    pub fn test_fresh_token() {
+        let keys = keys("secret");
+        let token = JwtToken::with_ttl(10, false, 60);
+        let jwt_string = keys.encode(&token).unwrap();
+
+        assert_eq!(keys.decode::<JwtToken>(&jwt_string, 0), Some(token));
+    }
+
+    #[test]
+    pub fn test_expired_token() {
+        let keys = keys("secret");
+        let token = JwtToken::with_ttl(10, false, -60);
+        let jwt_string = keys.encode(&token).unwrap();
+
+        assert_eq!(keys.decode::<JwtToken>(&jwt_string, 0), None);
+    }
+
+    #[test]
+    pub fn test_token_in_grace_window() {
+        let keys = keys("secret");
+        let token = JwtToken::with_ttl(10, false, -60);
+        let jwt_string = keys.encode(&token).unwrap();
+
+        assert_eq!(keys.decode::<JwtToken>(&jwt_string, 120), Some(token));
+        assert_eq!(keys.decode::<JwtToken>(&jwt_string, 30), None);
+    }
+
+    #[test]
+    pub fn test_key_rotation() {
+        let old_keys = keys("old");
+        let rotated = JwtKeys::from_config(
+            &JwtConfig {
+                current: hs256("new"),
+                previous: vec![hs256("old")],
+            },
+            "",
+        )
+        .unwrap();
+        let token = JwtToken::with_ttl(10, false, 60);
+
+        // Tokens signed with the retired key still verify.
+        let jwt_string = old_keys.encode(&token).unwrap();
+        assert_eq!(rotated.decode::<JwtToken>(&jwt_string, 0), Some(token.clone()));
+        // New tokens are signed with the current key.
+        let jwt_string = rotated.encode(&token).unwrap();
+        assert_eq!(old_keys.decode::<JwtToken>(&jwt_string, 0), None);
+        assert_eq!(keys("new").decode::<JwtToken>(&jwt_string, 0), Some(token));
+        // Keys not configured are rejected.
+        let jwt_string = keys("other").encode(&JwtToken::with_ttl(10, false, 60)).unwrap();
+        assert_eq!(rotated.decode::<JwtToken>(&jwt_string, 0), None);
+    }
+
+    #[test]
+    pub fn test_missing_secret() {
+        assert!(JwtKeys::from_config(&JwtConfig::default(), "").is_err());
+        let config = JwtConfig {
+            current: JwtKeyConfig {
+                algorithm: JwtAlgorithm::RS256,
+                ..Default::default()
+            },
+            previous: vec![],
+        };
+        assert!(JwtKeys::from_config(&config, "secret").is_err());
+    }
+}
+
 // #[cfg(test)]
 // mod tests {
 //     use super::*;
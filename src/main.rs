@@ -8,10 +8,13 @@ extern crate num_derive;
 extern crate log;
 
 mod bridge;
+mod cache;
+mod clock;
 mod config;
 mod error;
 mod ipset;
 mod jwt;
+mod metrics;
 mod models;
 mod services;
 
@@ -0,0 +1,119 @@
+//! Metrics in Prometheus format, for monitoring. HTTP requests are recorded by the metrics
+//! middleware, and agent requests by the host. Gauges which are cheap to read on demand, like the
+//! database pool, are set when metrics are scraped.
+
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+
+/// Buckets of agent round-trips in seconds, which are much slower than local requests.
+const AGENT_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+lazy_static! {
+    pub static ref REGISTRY: Registry = Registry::new_custom(Some("kite".to_string()), None).unwrap();
+    /// Duration of HTTP requests by method, route pattern, status and result, which is `error` if
+    /// an API error is returned, even with HTTP 200.
+    pub static ref HTTP_REQUESTS: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new("http_request_duration_seconds", "Duration of HTTP requests."),
+        &["method", "route", "status", "result"],
+    ));
+    /// Round-trip time of agent requests by payload type and result.
+    pub static ref AGENT_REQUESTS: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new("agent_request_duration_seconds", "Round-trip time of agent requests.")
+            .buckets(AGENT_BUCKETS.to_vec()),
+        &["payload", "result"],
+    ));
+    /// Tries of agent requests after transient failures, by payload type.
+    pub static ref AGENT_RETRIES: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("agent_request_retries_total", "Tries of agent requests after transient failures."),
+        &["payload"],
+    ));
+    /// Agent requests waiting for responses.
+    pub static ref AGENT_IN_FLIGHT: IntGauge = register(IntGauge::new(
+        "agent_requests_in_flight",
+        "Agent requests waiting for responses.",
+    ));
+    /// Agent requests holding permits of the concurrency limit.
+    pub static ref AGENT_PERMITS_IN_USE: IntGauge = register(IntGauge::new(
+        "agent_request_permits_in_use",
+        "Agent requests holding permits of the concurrency limit.",
+    ));
+    /// Agent requests waiting for permits. Requests fail once the queue is full.
+    pub static ref AGENT_REQUESTS_WAITING: IntGauge = register(IntGauge::new(
+        "agent_requests_waiting",
+        "Agent requests waiting for permits.",
+    ));
+    /// Cached keys refreshed by the warmer before expiry, by cache.
+    pub static ref CACHE_KEYS_WARMED: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("cache_keys_warmed_total", "Cached keys refreshed before expiry."),
+        &["cache"],
+    ));
+    /// Agents connected.
+    pub static ref AGENTS_CONNECTED: IntGauge =
+        register(IntGauge::new("agents_connected", "Agents connected."));
+    /// Open connections in the database pool.
+    pub static ref DB_POOL_SIZE: IntGauge = register(IntGauge::new(
+        "db_pool_connections",
+        "Open connections in the database pool.",
+    ));
+    /// Idle connections in the database pool. The pool is saturated when it's zero.
+    pub static ref DB_POOL_IDLE: IntGauge = register(IntGauge::new(
+        "db_pool_idle_connections",
+        "Idle connections in the database pool.",
+    ));
+}
+
+fn register<T>(metric: prometheus::Result<T>) -> T
+where
+    T: prometheus::core::Collector + Clone + 'static,
+{
+    let metric = metric.unwrap();
+    REGISTRY.register(Box::new(metric.clone())).unwrap();
+    metric
+}
+
+/// Count an agent request in flight until dropped, so that requests cancelled halfway are not
+/// counted forever.
+pub struct InFlight;
+
+impl InFlight {
+    pub fn new() -> Self {
+        AGENT_IN_FLIGHT.inc();
+        InFlight
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        AGENT_IN_FLIGHT.dec();
+    }
+}
+
+/// Serialize all metrics in the text exposition format.
+pub fn gather() -> String {
+    let mut buffer = Vec::new();
+
+    TextEncoder::new()
+        .encode(&REGISTRY.gather(), &mut buffer)
+        .unwrap();
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_gather() {
+        HTTP_REQUESTS
+            .with_label_values(&["GET", "/api/v1/motto", "200", "ok"])
+            .observe(0.01);
+        drop(InFlight::new());
+
+        let text = gather();
+        assert!(text.contains(
+            "kite_http_request_duration_seconds_count{method=\"GET\",result=\"ok\",route=\"/api/v1/motto\",status=\"200\"}"
+        ));
+        assert!(text.contains("kite_agent_requests_in_flight 0"));
+    }
+}
@@ -1,5 +1,7 @@
 //! This module contains all the abstract models required by the business.
 
+/// Audit log of administrative actions.
+pub mod audit;
 /// Course and score management.
 pub mod edu;
 /// Event display, sign-in and statistics
@@ -8,25 +10,38 @@ pub mod event;
 pub mod file;
 /// Freshman query.
 pub mod freshman;
+/// Idempotency keys of requests creating things.
+pub mod idempotency;
 /// Show some mottos.
 pub mod motto;
 /// Miniprogram index notice;
 pub mod notice;
+/// Notifications to a user.
+pub mod notification;
 /// Querying electricity bill and expenses record.
 pub mod pay;
+/// Counters for rate limits and quotas.
+pub mod quota;
 /// Search mod
 pub mod search;
 /// User management.
 pub mod user;
 
-use crate::error::ApiError;
+use crate::error::{ApiError, Result, Translate};
+use chrono::{DateTime, Local, NaiveDateTime, Utc};
 use num_traits::ToPrimitive;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, Serializer};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::future::Future;
 use thiserror::Error;
+use tokio::time::Duration;
 
 const DEFAULT_PAGE_INDEX: u16 = 1;
 const DEFAULT_ITEM_COUNT: u16 = 20;
 
+/// Max retry count when the database is unavailable.
+const DB_RETRY_COUNT: u32 = 3;
+
 #[derive(Debug, Error, ToPrimitive)]
 pub enum CommonError {
     #[error("请求成功")]
@@ -41,6 +56,16 @@ pub enum CommonError {
     LoginNeeded = 4,
     #[error("请求的权限不足")]
     Forbidden = 5,
+    #[error("服务暂时不可用, 请稍后再试")]
+    Unavailable = 6,
+    #[error("数据已被修改, 请刷新后重试")]
+    PreconditionFailed = 7,
+    #[error("请求过于频繁, 请稍后再试")]
+    TooManyRequests = 12,
+    #[error("请求的内容过大")]
+    PayloadTooLarge = 9,
+    #[error("相同的请求正在处理中, 请稍后再试")]
+    RequestInProgress = 11,
 }
 
 impl Into<ApiError> for CommonError {
@@ -49,10 +74,22 @@ impl Into<ApiError> for CommonError {
             code: self.to_u16().unwrap(),
             inner_msg: None,
             error_msg: Some(self.to_string()),
+            english_msg: Some(self.english()),
+            kind: Some(std::any::type_name::<Self>()),
         }
     }
 }
 
+/// Serialize a naive time in UTC, as stored in older columns, in RFC 3339 and local time zone, the
+/// same as `DateTime<Local>`.
+pub fn serialize_utc_time<S: Serializer>(
+    time: &NaiveDateTime,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    let time: DateTime<Local> = DateTime::<Utc>::from_utc(*time, Utc).into();
+    time.serialize(serializer)
+}
+
 /// Page parameters for list pagination
 #[derive(Serialize, Deserialize, Default)]
 pub struct PageView {
@@ -85,8 +122,100 @@ impl PageView {
         }
         return DEFAULT_ITEM_COUNT;
     }
-    /// Calculate offset
-    pub fn offset(&self, max_count: u16) -> u16 {
-        self.count(max_count) * (self.index() - 1)
+    /// Calculate offset, in a wider type so that large page indexes never overflow.
+    pub fn offset(&self, max_count: u16) -> i64 {
+        i64::from(self.count(max_count)) * (i64::from(self.index()) - 1)
+    }
+}
+
+/// Escape wildcards in the string to match it literally in LIKE patterns.
+pub fn escape_like(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
+
+/// Run a database operation, and retry with exponential backoff (100ms, 200ms, ...) if the database is
+/// unavailable for a transient blip.
+pub async fn with_db_retry<T, F, Fut>(mut f: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut retry_count = 0;
+    loop {
+        match f().await {
+            Err(e) if e.is_unavailable() && retry_count < DB_RETRY_COUNT => {
+                tokio::time::delay_for(Duration::from_millis(100 << retry_count)).await;
+                retry_count += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Begin a transaction, retrying like `with_db_retry` if no connection can be acquired from the pool.
+pub async fn begin(pool: &PgPool) -> Result<Transaction<'_, Postgres>> {
+    with_db_retry(|| async { Ok(pool.begin().await?) }).await
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    /// Connect to the database of `DATABASE_URL`, for tests ignored by default.
+    pub async fn db_pool() -> PgPool {
+        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL is required by database tests");
+        PgPool::connect(&url).await.unwrap()
+    }
+
+    #[test]
+    pub fn test_escape_like() {
+        assert_eq!(escape_like("report"), "report");
+        assert_eq!(escape_like("100%_done\\"), "100\\%\\_done\\\\");
+    }
+
+    #[test]
+    pub fn test_page_offset() {
+        let page = PageView {
+            index: Some(3),
+            count: Some(10),
+        };
+        assert_eq!(page.offset(50), 20);
+        assert_eq!(PageView::new().offset(50), 0);
+
+        let page = PageView {
+            index: Some(u16::MAX),
+            count: Some(u16::MAX - 1),
+        };
+        assert_eq!(page.offset(u16::MAX), 65534 * 65534);
+    }
+
+    #[test]
+    pub fn test_serialize_utc_time() {
+        #[derive(Serialize)]
+        struct Record {
+            #[serde(serialize_with = "serialize_utc_time")]
+            time: NaiveDateTime,
+        }
+
+        let record = Record {
+            time: NaiveDateTime::from_timestamp(1_600_000_000, 0),
+        };
+        let value = serde_json::to_value(&record).unwrap();
+        let time = DateTime::parse_from_rfc3339(value["time"].as_str().unwrap()).unwrap();
+        assert_eq!(time.timestamp(), 1_600_000_000);
+    }
+
+    #[tokio::test]
+    pub async fn test_begin_retry() {
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .connect_timeout(Duration::from_millis(10))
+            .connect_lazy("postgres://postgres@127.0.0.1:1/postgres")
+            .unwrap();
+
+        let start = std::time::Instant::now();
+        let e = begin(&pool).await.unwrap_err();
+        assert!(e.is_unavailable());
+        // Backoff of 100ms, 200ms and 400ms before giving up.
+        assert!(start.elapsed() >= Duration::from_millis(700));
     }
 }
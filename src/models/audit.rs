@@ -0,0 +1,206 @@
+//! Audit log of administrative actions, in table `public.audit_log`. Records are written after the
+//! action is done, and a failed record never fails the action.
+
+use crate::error::Result;
+use crate::models::PageView;
+use chrono::{NaiveDateTime, Utc};
+use log::warn;
+use serde::Serialize;
+use sqlx::PgPool;
+
+/// Actor of actions not done by a signed-in user, such as registration.
+pub const ANONYMOUS: i32 = 0;
+
+/// Actions recorded in the audit log.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuditAction {
+    CreateUser,
+    DeleteUser,
+    RestoreUser,
+    /// Grant the user administrator.
+    GrantAdmin,
+    /// Revoke administrator of the user.
+    RevokeAdmin,
+    /// Disable the user, who can't log in any more.
+    DisableUser,
+    /// Enable the user disabled before.
+    EnableUser,
+    /// Enable two-factor authentication of the administrator.
+    EnableTotp,
+    CreateMotto,
+    UpdateMotto,
+    DeleteMotto,
+    DeleteAttachment,
+    /// An upload rejected by the content scan.
+    RejectUpload,
+    /// Move the identity of a student id to another user.
+    ReassignIdentity,
+    /// Import freshmen from a file.
+    ImportFreshmen,
+    /// Drain agent connections.
+    DrainAgents,
+    /// Reset rate-limit and quota counters of the user.
+    ClearLimits,
+    /// Kick an agent connection.
+    DisconnectAgent,
+    /// Unlock an account locked after failed logins.
+    UnlockAccount,
+}
+
+impl AuditAction {
+    /// Name stored in the `action` column, which is also used to filter records.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::CreateUser => "user.create",
+            AuditAction::DeleteUser => "user.delete",
+            AuditAction::RestoreUser => "user.restore",
+            AuditAction::GrantAdmin => "user.grant_admin",
+            AuditAction::RevokeAdmin => "user.revoke_admin",
+            AuditAction::DisableUser => "user.disable",
+            AuditAction::EnableUser => "user.enable",
+            AuditAction::EnableTotp => "user.enable_2fa",
+            AuditAction::CreateMotto => "motto.create",
+            AuditAction::UpdateMotto => "motto.update",
+            AuditAction::DeleteMotto => "motto.delete",
+            AuditAction::DeleteAttachment => "attachment.delete",
+            AuditAction::RejectUpload => "attachment.reject",
+            AuditAction::ReassignIdentity => "user.reassign_identity",
+            AuditAction::ImportFreshmen => "freshman.import",
+            AuditAction::DrainAgents => "agent.drain",
+            AuditAction::ClearLimits => "user.clear_limits",
+            AuditAction::DisconnectAgent => "agent.disconnect",
+            AuditAction::UnlockAccount => "user.unlock",
+        }
+    }
+}
+
+/// One audit record.
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct AuditRecord {
+    pub id: i64,
+    /// Uid of the user who did it, or `ANONYMOUS`.
+    pub actor: i32,
+    pub action: String,
+    /// Id of the user, motto or attachment acted on. Identities reassigned are recorded as
+    /// `student id->uid`, imports as the count of rows imported, drains as the count of
+    /// connections closed, agents disconnected as their addresses, and unlocks as the accounts.
+    pub target: String,
+    #[serde(rename = "time")]
+    pub ts: NaiveDateTime,
+}
+
+/// Conditions of audit records to list. `None` matches all.
+#[derive(Default)]
+pub struct AuditFilter {
+    pub actor: Option<i32>,
+    pub action: Option<String>,
+}
+
+/// Record the action. Errors are logged only, because the action has been done anyway.
+pub async fn record(client: &PgPool, actor: i32, action: AuditAction, target: impl ToString) {
+    let target = target.to_string();
+    let result = sqlx::query(
+        "INSERT INTO public.audit_log (actor, action, target, ts)
+            VALUES ($1, $2, $3, $4)",
+    )
+    .bind(actor)
+    .bind(action.as_str())
+    .bind(&target)
+    .bind(Utc::now().naive_local())
+    .execute(client)
+    .await;
+
+    if let Err(e) = result {
+        warn!(
+            "Failed to record {} on {} by user {}: {}",
+            action.as_str(),
+            target,
+            actor,
+            e
+        );
+    }
+}
+
+/// List audit records matching the filter, the latest first.
+pub async fn list(
+    client: &PgPool,
+    filter: &AuditFilter,
+    page: &PageView,
+    max_count: u16,
+) -> Result<Vec<AuditRecord>> {
+    let records: Vec<AuditRecord> = sqlx::query_as(
+        "SELECT id, actor, action, target, ts FROM public.audit_log
+            WHERE ($1::int IS NULL OR actor = $1) AND ($2::text IS NULL OR action = $2)
+            ORDER BY ts DESC, id DESC OFFSET $3 LIMIT $4",
+    )
+    .bind(filter.actor)
+    .bind(&filter.action)
+    .bind(page.offset(max_count))
+    .bind(page.count(max_count) as i64)
+    .fetch_all(client)
+    .await?;
+    Ok(records)
+}
+
+/// Count audit records matching the filter.
+pub async fn count(client: &PgPool, filter: &AuditFilter) -> Result<i64> {
+    let (count,): (i64,) = sqlx::query_as(
+        "SELECT COUNT(*) FROM public.audit_log
+            WHERE ($1::int IS NULL OR actor = $1) AND ($2::text IS NULL OR action = $2)",
+    )
+    .bind(filter.actor)
+    .bind(&filter.action)
+    .fetch_one(client)
+    .await?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::test::db_pool;
+    use crate::models::user::Person;
+
+    #[tokio::test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    async fn test_record_user_creation() {
+        let pool = db_pool().await;
+        let mut user = Person::new();
+        user.nick_name = "audit-test".to_string();
+        user.register(&pool).await.unwrap();
+
+        // Registered by the user itself, who is not signed in yet.
+        record(&pool, ANONYMOUS, AuditAction::CreateUser, user.uid).await;
+
+        let filter = AuditFilter {
+            actor: Some(ANONYMOUS),
+            action: Some(AuditAction::CreateUser.as_str().to_string()),
+        };
+        let records = list(&pool, &filter, &PageView::new(), 100).await.unwrap();
+        assert_eq!(records[0].target, user.uid.to_string());
+        assert!(count(&pool, &filter).await.unwrap() >= 1);
+        // Filtered by actor and action.
+        let filter = AuditFilter {
+            actor: Some(user.uid),
+            action: None,
+        };
+        assert_eq!(count(&pool, &filter).await.unwrap(), 0);
+        let filter = AuditFilter {
+            actor: Some(ANONYMOUS),
+            action: Some(AuditAction::DeleteUser.as_str().to_string()),
+        };
+        assert_eq!(count(&pool, &filter).await.unwrap(), 0);
+
+        sqlx::query("DELETE FROM public.audit_log WHERE actor = $1 AND target = $2")
+            .bind(ANONYMOUS)
+            .bind(user.uid.to_string())
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM public.person WHERE uid = $1")
+            .bind(user.uid)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+}
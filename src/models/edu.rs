@@ -1,11 +1,20 @@
 mod course;
 mod major;
+mod score;
+pub mod transcript;
 
 use serde::Serialize;
 
-pub use course::{get_current_term, is_valid_term};
+pub use course::{get_current_term, is_valid_term, terms_since};
 pub use course::{CourseBase, CourseClass};
 pub use major::{Major, PlannedCourse};
+pub use score::{compute_gpa, fetch_scores, CachedScores, GpaReport, ScoreCache, ScoreError};
+
+#[derive(thiserror::Error, Debug, ToPrimitive)]
+pub enum EduError {
+    #[error("需要先完成实名认证并绑定OA账户")]
+    OaAccountNeeded = 270,
+}
 
 #[derive(Debug, Serialize)]
 pub struct Course {}
@@ -48,12 +48,32 @@ pub struct CourseClass {
 }
 
 pub fn get_current_term() -> String {
-    let today = chrono::Local::today();
+    let today = crate::clock::today();
     let (year, month) = (today.year(), today.month() as i32);
 
     format!("{}{}", year, if (2..7).contains(&month) { "A" } else { "B" })
 }
 
+/// Terms from the one a student enrolled in (with the student id starting with the year), to
+/// `current`, in time order.
+pub fn terms_since(student_id: &str, current: &str) -> Vec<String> {
+    let entry_year = match student_id.get(..2).and_then(|x| x.parse::<i32>().ok()) {
+        Some(year) => 2000 + year,
+        None => return vec![],
+    };
+    let current_year = current
+        .get(..4)
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(entry_year);
+
+    (entry_year..=current_year)
+        .flat_map(|year| vec![format!("{}A", year), format!("{}B", year)])
+        // Students enroll in autumn.
+        .skip(1)
+        .filter(|term| term.as_str() <= current)
+        .collect()
+}
+
 pub fn is_valid_term(term: &str) -> bool {
     let re = regex::Regex::new(r"^20[\d]{2}[AB]$").unwrap();
     return re.is_match(term);
@@ -99,7 +119,7 @@ impl CourseBase {
         .bind(format!("%{}%", query_string))
         .bind(term)
         .bind(page.count(20) as i16)
-        .bind(page.offset(20) as i16)
+        .bind(page.offset(20))
         .fetch_all(pool)
         .await?;
         Ok(results)
@@ -132,4 +152,16 @@ mod test {
         assert_eq!(false, is_valid_term("2020AB"));
         assert_eq!(false, is_valid_term("0019B"));
     }
+
+    #[test]
+    pub fn test_terms_since() {
+        use super::terms_since;
+
+        assert_eq!(
+            terms_since("2110400106", "2022B"),
+            vec!["2021B", "2022A", "2022B"]
+        );
+        assert_eq!(terms_since("21100106", "2022A"), vec!["2021B", "2022A"]);
+        assert!(terms_since("x1100106", "2022A").is_empty());
+    }
 }
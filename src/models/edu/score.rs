@@ -0,0 +1,658 @@
+//! GPA computed from course scores fetched by agents, and the cache of scores.
+
+use crate::bridge::{AgentTransport, CourseScore, CourseScoreLine, CourseScoreRequest};
+use crate::cache::{Cache, HitCounter};
+use crate::config::GpaScheme;
+use crate::error::Result;
+use chrono::{DateTime, Local};
+use log::warn;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Scores of each term, in time order.
+pub type TermScores = Vec<(String, Vec<CourseScore>)>;
+
+/// Course the agent sent malformed, which is left out of the scores.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ScoreError {
+    pub term: String,
+    /// Index of the course in the list of the term.
+    pub index: usize,
+    pub reason: String,
+}
+
+/// Scores fetched, and courses left out.
+#[derive(Debug, Default)]
+pub struct FetchedScores {
+    pub terms: TermScores,
+    pub errors: Vec<ScoreError>,
+}
+
+impl From<TermScores> for FetchedScores {
+    fn from(terms: TermScores) -> Self {
+        Self {
+            terms,
+            errors: vec![],
+        }
+    }
+}
+
+/// Scores with the time fetched.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedScores {
+    pub terms: Arc<TermScores>,
+    /// Courses left out because they are malformed.
+    pub errors: Arc<Vec<ScoreError>>,
+    pub fetch_time: DateTime<Local>,
+    /// Served from the cache because the campus system can't be reached.
+    pub stale: bool,
+}
+
+/// Course scores of each student id. Fetching scores takes a request to the campus system per term,
+/// so at most one fetch for a student is in flight.
+#[derive(Clone)]
+pub struct ScoreCache {
+    cache: Cache<String, CachedScores>,
+    /// Locks of students whose scores are being fetched.
+    fetching: Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>,
+    /// Requests of each student, to warm the cache for the most requested ones.
+    hits: HitCounter<String>,
+}
+
+impl ScoreCache {
+    /// Requests of at most `hot_keys` students are counted to warm the cache, or none if 0.
+    pub fn new(ttl: Duration, hot_keys: usize) -> Self {
+        Self {
+            cache: Cache::new(ttl),
+            fetching: Default::default(),
+            hits: HitCounter::new(hot_keys),
+        }
+    }
+
+    /// Get scores of the student. Stale scores are served while refreshed in background, and `force`
+    /// skips the cache. If `stale_on_error` is set, the last scores are served when agents fail, and
+    /// marked stale.
+    pub async fn get<F, Fut>(
+        &self,
+        student_id: &str,
+        force: bool,
+        stale_on_error: bool,
+        fetch: F,
+    ) -> Result<CachedScores>
+    where
+        F: FnOnce() -> Fut + 'static,
+        Fut: Future<Output = Result<FetchedScores>> + 'static,
+    {
+        let result = self.lookup(student_id, force, stale_on_error, fetch).await;
+        // Students without scores, like made-up ones, are not warmed.
+        if result.is_ok() {
+            self.hits.hit(&student_id.to_string());
+        }
+        result
+    }
+
+    async fn lookup<F, Fut>(
+        &self,
+        student_id: &str,
+        force: bool,
+        stale_on_error: bool,
+        fetch: F,
+    ) -> Result<CachedScores>
+    where
+        F: FnOnce() -> Fut + 'static,
+        Fut: Future<Output = Result<FetchedScores>> + 'static,
+    {
+        let student_id = student_id.to_string();
+        if !force {
+            if let Some(scores) = self.cache.get(&student_id) {
+                return Ok(scores);
+            }
+            if let Some(scores) = self.cache.get_stale(&student_id) {
+                let this = self.clone();
+                actix_web::rt::spawn(async move {
+                    if let Err(e) = this.refresh(&student_id, false, fetch).await {
+                        warn!("Failed to refresh scores of student {}: {}", student_id, e);
+                    }
+                });
+                return Ok(scores);
+            }
+        }
+        match self.refresh(&student_id, force, fetch).await {
+            Err(e) if stale_on_error && e.is_upstream_failure() => {
+                let scores = self.cache.get_stale(&student_id).ok_or(e)?;
+                warn!("Served stale scores of student {}.", student_id);
+                Ok(CachedScores {
+                    stale: true,
+                    ..scores
+                })
+            }
+            result => result,
+        }
+    }
+
+    /// Refresh cached scores of at most `count` students requested most, which expire within
+    /// `within`, so that they don't go cold. It stops once `idle` says agents are busy with live
+    /// requests. Return the number of students refreshed.
+    pub async fn warm<F, Fut>(
+        &self,
+        count: usize,
+        within: Duration,
+        idle: impl Fn() -> bool,
+        fetch: F,
+    ) -> usize
+    where
+        F: Fn(String) -> Fut,
+        Fut: Future<Output = Result<FetchedScores>>,
+    {
+        let mut refreshed = 0;
+
+        for student_id in self.hits.hottest(count) {
+            if !self.cache.expires_within(&student_id, within) {
+                continue;
+            }
+            if !idle() {
+                break;
+            }
+            match self
+                .refresh(&student_id, true, || fetch(student_id.clone()))
+                .await
+            {
+                Ok(_) => refreshed += 1,
+                Err(e) => warn!("Failed to warm scores of student {}: {}", student_id, e),
+            }
+        }
+        refreshed
+    }
+
+    /// Fetch and cache scores. Requests waiting for the fetch of another share its result, unless
+    /// `force` is set.
+    async fn refresh<F, Fut>(&self, student_id: &str, force: bool, fetch: F) -> Result<CachedScores>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<FetchedScores>>,
+    {
+        let entry = FetchingEntry::new(&self.fetching, student_id);
+        let _guard = entry.lock.lock().await;
+
+        let result = match self.cache.get(&student_id.to_string()).filter(|_| !force) {
+            Some(scores) => Ok(scores),
+            None => fetch().await.map(|fetched| {
+                let scores = CachedScores {
+                    terms: Arc::new(fetched.terms),
+                    errors: Arc::new(fetched.errors),
+                    fetch_time: Local::now(),
+                    stale: false,
+                };
+                self.cache.insert(student_id.to_string(), scores.clone());
+                scores
+            }),
+        };
+        result
+    }
+}
+
+/// Lock of a student in `ScoreCache::fetching`, which is removed once nobody holds or waits for
+/// it, even if the fetch is cancelled.
+struct FetchingEntry<'a> {
+    fetching: &'a Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+    student_id: &'a str,
+    lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl<'a> FetchingEntry<'a> {
+    fn new(
+        fetching: &'a Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+        student_id: &'a str,
+    ) -> Self {
+        let lock = fetching
+            .lock()
+            .unwrap()
+            .entry(student_id.to_string())
+            .or_default()
+            .clone();
+        Self {
+            fetching,
+            student_id,
+            lock,
+        }
+    }
+}
+
+impl Drop for FetchingEntry<'_> {
+    fn drop(&mut self) {
+        let mut fetching = self.fetching.lock().unwrap();
+        // Nobody else is waiting.
+        if Arc::strong_count(&self.lock) == 2 {
+            fetching.remove(self.student_id);
+        }
+    }
+}
+
+/// Fetch scores of each term since enrollment through agents. Malformed courses are left out and
+/// reported, rather than failing the whole transcript.
+pub async fn fetch_scores(
+    agents: Arc<dyn AgentTransport>,
+    account: String,
+    credential: String,
+) -> Result<FetchedScores> {
+    let terms = super::terms_since(&account, &super::get_current_term());
+    // Requests are multiplexed over agent connections.
+    let requests = terms.iter().map(|term| {
+        agents.call(CourseScoreRequest {
+            account: account.clone(),
+            credential: credential.clone(),
+            term: term.clone(),
+        })
+    });
+    let lists = futures::future::try_join_all(requests).await?;
+
+    let mut fetched = FetchedScores::default();
+    for (term, list) in terms.into_iter().zip(lists) {
+        for e in list.errors {
+            warn!(
+                "Malformed course {} of student {} in term {}: {}",
+                e.index, account, term, e.reason
+            );
+            fetched.errors.push(ScoreError {
+                term: term.clone(),
+                index: e.index,
+                reason: e.reason,
+            });
+        }
+        fetched.terms.push((term, list.items));
+    }
+    Ok(fetched)
+}
+
+/// GPA of a term.
+#[derive(Debug, Serialize)]
+pub struct TermGpa {
+    pub term: String,
+    pub gpa: f32,
+    /// Credits counted in the GPA.
+    pub credits: f32,
+}
+
+/// Overall GPA with per-term breakdown.
+#[derive(Debug, Serialize)]
+pub struct GpaReport {
+    pub gpa: f32,
+    pub credits: f32,
+    pub terms: Vec<TermGpa>,
+}
+
+/// Map a mark in the hundred-mark system to grade points by the table, with the highest minimum
+/// mark reached.
+pub fn grade_point(mark: f32, scheme: &GpaScheme) -> f32 {
+    if !(0.0..=100.0).contains(&mark) {
+        return scheme.fallback;
+    }
+    let below = match scheme
+        .points
+        .iter()
+        .filter(|x| mark >= x.mark)
+        .max_by(|a, b| a.mark.total_cmp(&b.mark))
+    {
+        Some(below) => below,
+        None => return scheme.fallback,
+    };
+    if !scheme.interpolate {
+        return below.point;
+    }
+    match scheme
+        .points
+        .iter()
+        .filter(|x| x.mark > mark)
+        .min_by(|a, b| a.mark.total_cmp(&b.mark))
+    {
+        Some(above) => {
+            below.point + (mark - below.mark) * (above.point - below.point) / (above.mark - below.mark)
+        }
+        None => below.point,
+    }
+}
+
+/// Credit and final mark of a course counted in GPA. Pass/fail courses, courses not commented,
+/// and courses with missing credits are not counted.
+pub(super) fn gradable(score: &CourseScore) -> Option<(f32, f32)> {
+    match &score.detail {
+        CourseScoreLine::Normal(inner)
+            if score.course_credit.is_finite() && score.course_credit > 0.0 =>
+        {
+            Some((score.course_credit, inner.total_mark.max(inner.make_up_total)))
+        }
+        _ => None,
+    }
+}
+
+fn round(x: f32) -> f32 {
+    (x * 100.0).round() / 100.0
+}
+
+/// Weighted GPA of (credit, grade point) pairs.
+fn weighted_gpa<'a>(courses: impl Iterator<Item = &'a (f32, f32)>) -> (f32, f32) {
+    let (credits, points) =
+        courses.fold((0.0, 0.0), |(c, p), (credit, gp)| (c + credit, p + credit * gp));
+    if credits > 0.0 {
+        (round(points / credits), credits)
+    } else {
+        (0.0, 0.0)
+    }
+}
+
+/// Compute GPA from scores of each term, given in time order. A retaken course counts only the
+/// best attempt, in the term it's taken.
+pub fn compute_gpa(terms: &[(String, Vec<CourseScore>)], scheme: &GpaScheme) -> GpaReport {
+    // Best attempt of each course, as (term index, credit, mark).
+    let mut best: HashMap<&str, (usize, f32, f32)> = HashMap::new();
+
+    for (index, (_, scores)) in terms.iter().enumerate() {
+        for score in scores {
+            if let Some((credit, mark)) = gradable(score) {
+                let entry = best.entry(&score.course_code).or_insert((index, credit, mark));
+                // Later attempts win ties.
+                if mark >= entry.2 {
+                    *entry = (index, credit, mark);
+                }
+            }
+        }
+    }
+
+    let mut by_term: Vec<Vec<(f32, f32)>> = vec![Vec::new(); terms.len()];
+    for (index, credit, mark) in best.values() {
+        by_term[*index].push((*credit, grade_point(*mark, scheme)));
+    }
+
+    let (gpa, credits) = weighted_gpa(by_term.iter().flatten());
+    let terms = terms
+        .iter()
+        .zip(by_term.iter())
+        .filter(|(_, courses)| !courses.is_empty())
+        .map(|((term, _), courses)| {
+            let (gpa, credits) = weighted_gpa(courses.iter());
+            TermGpa {
+                term: term.clone(),
+                gpa,
+                credits,
+            }
+        })
+        .collect();
+
+    GpaReport { gpa, credits, terms }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn score(code: &str, credit: f32, mark: f32) -> CourseScore {
+        let mut score = CourseScore {
+            course_code: code.to_string(),
+            course_name: code.to_string(),
+            course_credit: credit,
+            detail: CourseScoreLine::Normal(Default::default()),
+        };
+        if let CourseScoreLine::Normal(inner) = &mut score.detail {
+            inner.total_mark = mark;
+        }
+        score
+    }
+
+    #[test]
+    pub fn test_score_cache() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+
+        actix_web::rt::System::new("test").block_on(async {
+            let cache = ScoreCache::new(Duration::from_secs(60), crate::cache::MAX_HOT_KEYS);
+            let fetched = Arc::new(AtomicU32::new(0));
+            let fetch = || {
+                let fetched = fetched.clone();
+                move || async move {
+                    tokio::time::delay_for(Duration::from_millis(50)).await;
+                    fetched.fetch_add(1, Ordering::SeqCst);
+                    Ok(FetchedScores::from(vec![(
+                        "2021B".to_string(),
+                        vec![score("A", 4.0, 90.0)],
+                    )]))
+                }
+            };
+
+            // Concurrent requests on a cold cache are fetched once.
+            let (a, b) = futures::join!(
+                cache.get("2110400106", false, false, fetch()),
+                cache.get("2110400106", false, false, fetch())
+            );
+            assert_eq!(a.unwrap(), b.unwrap());
+            assert_eq!(fetched.load(Ordering::SeqCst), 1);
+
+            cache.get("2110400106", false, false, fetch()).await.unwrap();
+            assert_eq!(fetched.load(Ordering::SeqCst), 1);
+            cache.get("2110400106", true, false, fetch()).await.unwrap();
+            assert_eq!(fetched.load(Ordering::SeqCst), 2);
+            assert!(cache.fetching.lock().unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    pub fn test_cancel_score_fetch() {
+        actix_web::rt::System::new("test").block_on(async {
+            let cache = ScoreCache::new(Duration::from_secs(60), crate::cache::MAX_HOT_KEYS);
+            let fetch = || async {
+                tokio::time::delay_for(Duration::from_secs(10)).await;
+                Ok(FetchedScores::from(vec![]))
+            };
+
+            // The request is dropped while fetching, like the client disconnects.
+            let result = tokio::time::timeout(
+                Duration::from_millis(20),
+                cache.get("2110400106", false, false, fetch),
+            )
+            .await;
+            assert!(result.is_err());
+            assert!(cache.fetching.lock().unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    pub fn test_warm_scores() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+
+        actix_web::rt::System::new("test").block_on(async {
+            let cache = ScoreCache::new(Duration::from_millis(300), crate::cache::MAX_HOT_KEYS);
+            let fetched = Arc::new(AtomicU32::new(0));
+            let fetch = {
+                let fetched = fetched.clone();
+                move |_: String| {
+                    let fetched = fetched.clone();
+                    async move {
+                        fetched.fetch_add(1, Ordering::SeqCst);
+                        Ok(FetchedScores::from(vec![(
+                            "2021B".to_string(),
+                            vec![score("A", 4.0, 90.0)],
+                        )]))
+                    }
+                }
+            };
+            let get = || {
+                let fetch = fetch.clone();
+                cache.get("2110400106", false, false, move || fetch(String::new()))
+            };
+            let within = Duration::from_millis(200);
+
+            // Requested often enough to be tracked after counts are halved twice.
+            for _ in 0..4 {
+                get().await.unwrap();
+            }
+            assert_eq!(fetched.load(Ordering::SeqCst), 1);
+            // Fresh for long, not refreshed.
+            assert_eq!(cache.warm(10, within, || true, &fetch).await, 0);
+
+            // Refreshed before it expires, unless agents are busy.
+            tokio::time::delay_for(Duration::from_millis(150)).await;
+            assert_eq!(cache.warm(10, within, || false, &fetch).await, 0);
+            assert_eq!(cache.warm(10, within, || true, &fetch).await, 1);
+            assert_eq!(fetched.load(Ordering::SeqCst), 2);
+            // Requests after the original ttl hit the cache.
+            tokio::time::delay_for(Duration::from_millis(200)).await;
+            get().await.unwrap();
+            assert_eq!(fetched.load(Ordering::SeqCst), 2);
+        });
+    }
+
+    #[test]
+    pub fn test_stale_scores_on_error() {
+        use crate::bridge::HostError;
+        use crate::error::ApiError;
+
+        actix_web::rt::System::new("test").block_on(async {
+            let cache = ScoreCache::new(Duration::from_secs(60), crate::cache::MAX_HOT_KEYS);
+            let terms = || FetchedScores::from(vec![("2021B".to_string(), vec![score("A", 4.0, 90.0)])]);
+            let fail = |e: HostError| move || async move { Err(ApiError::new(e)) };
+
+            let fresh = cache
+                .get("2110400106", false, true, move || async move { Ok(terms()) })
+                .await
+                .unwrap();
+            assert!(!fresh.stale);
+
+            // The agent times out, and the last scores are served.
+            let stale = cache
+                .get("2110400106", true, true, fail(HostError::Timeout))
+                .await
+                .unwrap();
+            assert!(stale.stale);
+            assert_eq!(stale.terms, fresh.terms);
+            assert_eq!(stale.fetch_time, fresh.fetch_time);
+
+            // Not opted in.
+            assert!(cache
+                .get("2110400106", true, false, fail(HostError::Timeout))
+                .await
+                .is_err());
+            // Errors reported by the campus system are returned as is.
+            assert!(cache
+                .get("2110400106", true, true, fail(HostError::CampusAuthFailed))
+                .await
+                .is_err());
+            // Nothing cached.
+            assert!(cache
+                .get("2110400107", true, true, fail(HostError::Timeout))
+                .await
+                .is_err());
+        });
+    }
+
+    #[tokio::test]
+    async fn test_fetch_scores_with_malformed_courses() {
+        use crate::bridge::{ItemError, MockTransport, PartialList, RequestPayload, ResponsePayload};
+
+        // The course at index 1 of the first term is malformed.
+        let agents = Arc::new(MockTransport::new(|request| match request {
+            RequestPayload::ScoreList(request) => {
+                let mut list = PartialList::from(vec![score(&request.term, 2.0, 80.0)]);
+                if request.term == "2025B" {
+                    list.errors.push(ItemError {
+                        index: 1,
+                        reason: "course_name is not UTF-8".to_string(),
+                    });
+                }
+                Ok(ResponsePayload::ScoreList(list))
+            }
+            _ => unreachable!(),
+        }));
+
+        let fetched = fetch_scores(agents, "25TEST0349".to_string(), String::new())
+            .await
+            .unwrap();
+        let terms = super::super::terms_since("25TEST0349", &super::super::get_current_term());
+        // Valid courses of every term are kept.
+        assert_eq!(fetched.terms.len(), terms.len());
+        assert!(fetched
+            .terms
+            .iter()
+            .all(|(term, scores)| scores[0].course_code == *term));
+        assert_eq!(
+            fetched.errors,
+            [ScoreError {
+                term: "2025B".to_string(),
+                index: 1,
+                reason: "course_name is not UTF-8".to_string(),
+            }]
+        );
+    }
+
+    fn scheme(name: &str) -> GpaScheme {
+        crate::config::default_gpa_schemes()[name].clone()
+    }
+
+    #[test]
+    pub fn test_grade_point() {
+        let four = scheme("4.0");
+        assert_eq!(grade_point(95.0, &four), 4.0);
+        assert_eq!(grade_point(83.0, &four), 3.3);
+        assert_eq!(grade_point(59.5, &four), 0.0);
+        let five = scheme("5.0");
+        assert_eq!(grade_point(95.0, &five), 4.5);
+        assert_eq!(grade_point(60.0, &five), 1.0);
+        assert_eq!(grade_point(100.0, &five), 5.0);
+        assert_eq!(grade_point(59.5, &five), 0.0);
+        // Continuous, as (mark - 50) / 10.
+        assert!((grade_point(95.5, &five) - 4.55).abs() < 1e-5);
+        assert!((grade_point(60.5, &five) - 1.05).abs() < 1e-5);
+        // Steps unless interpolated.
+        let steps = GpaScheme::from_pairs(&[(60.0, 1.0), (100.0, 5.0)]);
+        assert_eq!(grade_point(95.5, &steps), 1.0);
+        // Out of range or missing.
+        let pass = GpaScheme {
+            fallback: -1.0,
+            ..GpaScheme::from_pairs(&[(60.0, 1.0)])
+        };
+        assert_eq!(grade_point(60.0, &pass), 1.0);
+        assert_eq!(grade_point(59.0, &pass), -1.0);
+        assert_eq!(grade_point(101.0, &pass), -1.0);
+        assert_eq!(grade_point(f32::NAN, &four), 0.0);
+    }
+
+    #[test]
+    pub fn test_compute_gpa() {
+        let terms = vec![
+            (
+                "2021B".to_string(),
+                vec![
+                    score("A", 4.0, 90.0),
+                    score("B", 2.0, 50.0),
+                    // Missing credit.
+                    score("C", 0.0, 90.0),
+                    CourseScore {
+                        course_code: "D".to_string(),
+                        course_name: "D".to_string(),
+                        course_credit: 1.0,
+                        detail: CourseScoreLine::PassFail(true),
+                    },
+                ],
+            ),
+            // B is retaken and passed.
+            ("2022A".to_string(), vec![score("B", 2.0, 78.0)]),
+        ];
+
+        let report = compute_gpa(&terms, &scheme("4.0"));
+        assert_eq!(report.credits, 6.0);
+        assert_eq!(report.gpa, round((4.0 * 4.0 + 2.0 * 3.0) / 6.0));
+        assert_eq!(report.terms.len(), 2);
+        // The failed attempt is dropped.
+        assert_eq!(report.terms[0].credits, 4.0);
+        assert_eq!(report.terms[0].gpa, 4.0);
+        assert_eq!(report.terms[1].gpa, 3.0);
+
+        // Another scheme gives another GPA from the same scores.
+        let report = compute_gpa(&terms, &scheme("5.0"));
+        assert_eq!(report.gpa, round((4.0 * 4.0 + 2.0 * 2.8) / 6.0));
+
+        let report = compute_gpa(&[], &scheme("5.0"));
+        assert_eq!(report.gpa, 0.0);
+        assert!(report.terms.is_empty());
+    }
+}
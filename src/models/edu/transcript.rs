@@ -0,0 +1,86 @@
+//! Export course scores as a CSV transcript, one line per course with the grade point, followed by
+//! the GPA summary.
+
+use super::score::{gradable, grade_point, GpaReport};
+use crate::bridge::{CourseScore, CourseScoreLine};
+use crate::config::GpaScheme;
+use crate::models::user::export::csv_field;
+
+/// Header line of the course section.
+pub const CSV_HEADER: &str = "term,courseCode,courseName,credit,score,gradePoint\r\n";
+
+/// Lines of courses in the term. Pass/fail courses are scored `pass` or `fail`, and courses not
+/// commented have no score. Grade points are left empty for courses not counted in GPA.
+pub fn csv_term(term: &str, scores: &[CourseScore], scheme: &GpaScheme) -> String {
+    scores
+        .iter()
+        .map(|score| {
+            let mark = match &score.detail {
+                CourseScoreLine::Normal(inner) => inner.total_mark.max(inner.make_up_total).to_string(),
+                CourseScoreLine::PassFail(true) => "pass".to_string(),
+                CourseScoreLine::PassFail(false) => "fail".to_string(),
+                CourseScoreLine::Uncomment => String::new(),
+            };
+            let point = gradable(score)
+                .map(|(_, mark)| grade_point(mark, scheme).to_string())
+                .unwrap_or_default();
+            let fields = [
+                csv_field(term),
+                csv_field(&score.course_code),
+                csv_field(&score.course_name),
+                score.course_credit.to_string(),
+                mark,
+                point,
+            ];
+            format!("{}\r\n", fields.join(","))
+        })
+        .collect()
+}
+
+/// GPA section after the courses, separated by an empty line. The last line is the overall GPA.
+pub fn csv_summary(report: &GpaReport) -> String {
+    let mut summary = String::from("\r\nterm,credits,gpa\r\n");
+    for term in &report.terms {
+        summary += &format!("{},{},{}\r\n", csv_field(&term.term), term.credits, term.gpa);
+    }
+    summary += &format!("total,{},{}\r\n", report.credits, report.gpa);
+    summary
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::edu::compute_gpa;
+
+    #[test]
+    pub fn test_csv_transcript() {
+        let mut score = CourseScore {
+            course_code: "B1001".to_string(),
+            course_name: "Calculus, Part 1".to_string(),
+            course_credit: 4.0,
+            detail: CourseScoreLine::Normal(Default::default()),
+        };
+        if let CourseScoreLine::Normal(inner) = &mut score.detail {
+            inner.total_mark = 55.0;
+            inner.make_up_total = 86.0;
+        }
+        let pass = CourseScore {
+            course_code: "B1002".to_string(),
+            course_name: "Physical Education".to_string(),
+            course_credit: 1.0,
+            detail: CourseScoreLine::PassFail(true),
+        };
+        let terms = vec![("2021B".to_string(), vec![score, pass])];
+        let scheme = &crate::config::default_gpa_schemes()["4.0"];
+
+        assert!(CSV_HEADER.starts_with("term,courseCode,courseName,credit,score,gradePoint"));
+        let rows = csv_term(&terms[0].0, &terms[0].1, scheme);
+        let rows: Vec<&str> = rows.split("\r\n").collect();
+        // The make-up mark counts.
+        assert_eq!(rows[0], "2021B,B1001,\"Calculus, Part 1\",4,86,3.7");
+        assert_eq!(rows[1], "2021B,B1002,Physical Education,1,pass,");
+
+        let summary = csv_summary(&compute_gpa(&terms, scheme));
+        assert_eq!(summary, "\r\nterm,credits,gpa\r\n2021B,4,3.7\r\ntotal,4,3.7\r\n");
+    }
+}
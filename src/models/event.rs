@@ -1,14 +1,28 @@
 //! This module provides the ability to create, update and delete events, records and other about signs.
-use crate::error::Result;
-use chrono::{NaiveDateTime, Utc};
+use super::{escape_like, PageView};
+use crate::cache::{Cache, HitCounter};
+use crate::error::{ApiError, Result};
+use chrono::{Datelike, NaiveDate, NaiveDateTime, Utc};
+use log::warn;
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
 
 /// Event that imported from OA.
 const EVENT_TYPE_OA: i32 = 0;
 /// Event which user pub in kite.
 const EVENT_TYPE_INNER: i32 = 1;
 
+/// Event for everyone.
+pub const AUDIENCE_ALL: i32 = 0;
+/// Event for freshmen only.
+pub const AUDIENCE_FRESHMAN: i32 = 1;
+/// Event for upperclassmen only.
+pub const AUDIENCE_SENIOR: i32 = 2;
+
 #[derive(thiserror::Error, Debug, ToPrimitive)]
 pub enum EventError {
     #[error("重复创建活动")]
@@ -48,7 +62,7 @@ struct Applicant {
 }
 
 /// A event which corresponds to one activity.
-#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
 pub struct Event {
     /// Event type.
     pub source: i32,
@@ -74,10 +88,12 @@ pub struct Event {
     pub image: Option<String>,
     /// Description
     pub description: String,
+    /// Who the event is for, see AUDIENCE_* constants.
+    pub audience: i32,
 }
 
 /// Summary of event. See strcut Event for details.
-#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, sqlx::FromRow)]
 pub struct EventSummary {
     pub source: i32,
     pub id: i32,
@@ -91,8 +107,31 @@ pub struct EventSummary {
     pub tags: Option<Vec<String>>,
     pub place: String,
     pub image: Option<String>,
+    pub audience: i32,
+}
+
+/// Conditions to list events. Events matching all the given conditions are listed.
+#[derive(Default)]
+pub struct EventFilter {
+    /// Audience of the viewer. Events for the audience and for everyone are listed.
+    pub audience: Option<i32>,
+    /// Tag, like a club name or an activity classification.
+    pub category: Option<String>,
+    /// Fragment of the title.
+    pub keyword: Option<String>,
+    /// Events which end on or after the day.
+    pub from: Option<NaiveDate>,
+    /// Events which start on or before the day.
+    pub to: Option<NaiveDate>,
 }
 
+/// Conditions of `EventFilter` in SQL, with parameters from $1 to $5.
+const FILTER_CONDITIONS: &str = "($1::int[] IS NULL OR audience = ANY($1))
+    AND ($2::text IS NULL OR $2 = ANY(tags))
+    AND ($3::text IS NULL OR title ILIKE '%' || $3 || '%')
+    AND ($4::date IS NULL OR COALESCE(end_time, start_time) >= $4)
+    AND ($5::date IS NULL OR start_time < $5 + 1)";
+
 trait Summarize<T> {
     fn summarize(self) -> T;
 }
@@ -110,6 +149,7 @@ impl Summarize<EventSummary> for Event {
             start_time: self.start_time,
             end_time: self.end_time,
             image: self.image,
+            audience: self.audience,
         }
     }
 }
@@ -122,21 +162,173 @@ impl Event {
     /// Save event to database
     pub async fn create(_client: &PgPool) {}
 
-    pub async fn list(client: &PgPool, page_index: u32, count: u32) -> Result<Vec<EventSummary>> {
-        let count = if count > 50 { 50 } else { count };
-
-        let events: Vec<EventSummary> = sqlx::query_as(
-            "SELECT source, id, publisher_uid, publisher_name, title, start_time, end_time, tags, place, image
+    /// List events matching the filter, the latest first.
+    pub async fn list(
+        client: &PgPool,
+        filter: &EventFilter,
+        page: &PageView,
+        max_count: u16,
+    ) -> Result<Vec<EventSummary>> {
+        let sql = format!(
+            "SELECT source, id, publisher_uid, publisher_name, title, start_time, end_time, tags, place, image, audience
                 FROM events.all_events
-                OFFSET $1 LIMIT $2;")
-            .bind(((page_index - 1) * count) as i32)
-            .bind(count as i32)
+                WHERE {}
+                ORDER BY start_time DESC, id
+                OFFSET $6 LIMIT $7",
+            FILTER_CONDITIONS
+        );
+        let events: Vec<EventSummary> = sqlx::query_as(&sql)
+            .bind(filter.audience.map(visible_audiences))
+            .bind(filter.category.as_deref())
+            .bind(filter.keyword.as_deref().map(escape_like))
+            .bind(filter.from)
+            .bind(filter.to)
+            .bind(page.offset(max_count))
+            .bind(page.count(max_count) as i64)
             .fetch_all(client)
             .await?;
         Ok(events)
     }
 
-    pub async fn get_event_detail(_source: i32) {}
+    /// Count events matching the filter.
+    pub async fn count(client: &PgPool, filter: &EventFilter) -> Result<i64> {
+        let sql = format!(
+            "SELECT COUNT(*) FROM events.all_events WHERE {}",
+            FILTER_CONDITIONS
+        );
+        let (total,): (i64,) = sqlx::query_as(&sql)
+            .bind(filter.audience.map(visible_audiences))
+            .bind(filter.category.as_deref())
+            .bind(filter.keyword.as_deref().map(escape_like))
+            .bind(filter.from)
+            .bind(filter.to)
+            .fetch_one(client)
+            .await?;
+        Ok(total)
+    }
+
+    /// Get the event with its description.
+    pub async fn get(client: &PgPool, id: i32) -> Result<Event> {
+        let event: Option<Event> = sqlx::query_as(
+            "SELECT source, id, publisher_uid, publisher_name, title, start_time, end_time, tags, place, image, description, audience
+                FROM events.all_events
+                WHERE id = $1
+                LIMIT 1",
+        )
+        .bind(id)
+        .fetch_optional(client)
+        .await?;
+        event.ok_or_else(|| ApiError::new(EventError::NoSuchEvent))
+    }
+
+    /// Recommend events which the user hasn't applied and not ended, ranked by `rank_events`.
+    pub async fn recommend(client: &PgPool, uid: i32) -> Result<Vec<EventSummary>> {
+        let history: Vec<(Option<Vec<String>>,)> = sqlx::query_as(
+            "SELECT e.tags FROM events.applicants a
+                INNER JOIN events.all_events e ON a.event_id = e.id
+                WHERE a.uid = $1",
+        )
+        .bind(uid)
+        .fetch_all(client)
+        .await?;
+        let college: Option<(String,)> =
+            sqlx::query_as("SELECT college FROM freshman.students WHERE uid = $1 LIMIT 1")
+                .bind(uid)
+                .fetch_optional(client)
+                .await?;
+        let candidates: Vec<EventSummary> = sqlx::query_as(
+            "SELECT source, id, publisher_uid, publisher_name, title, start_time, end_time, tags, place, image, audience
+                FROM events.all_events
+                WHERE (end_time IS NULL OR end_time > now())
+                    AND id NOT IN (SELECT event_id FROM events.applicants WHERE uid = $1)
+                ORDER BY start_time
+                LIMIT 200",
+        )
+        .bind(uid)
+        .fetch_all(client)
+        .await?;
+
+        let mut history_tags = HashMap::new();
+        for tag in history.into_iter().filter_map(|x| x.0).flatten() {
+            *history_tags.entry(tag).or_insert(0) += 1;
+        }
+        Ok(rank_events(
+            candidates,
+            &history_tags,
+            college.map(|x| x.0).as_deref(),
+        ))
+    }
+}
+
+/// Event details by id, so that repeated views of an event don't query it again.
+#[derive(Clone)]
+pub struct EventCache {
+    cache: Cache<i32, Arc<Event>>,
+    /// Requests of each event, to warm the cache for the most requested ones.
+    hits: HitCounter<i32>,
+}
+
+impl EventCache {
+    /// Requests of at most `hot_keys` events are counted to warm the cache, or none if 0.
+    pub fn new(ttl: Duration, hot_keys: usize) -> Self {
+        Self {
+            cache: Cache::new(ttl),
+            hits: HitCounter::new(hot_keys),
+        }
+    }
+
+    /// Get the event from cache, or fetch and cache it if missing or expired.
+    pub async fn get<F, Fut>(&self, id: i32, fetch: F) -> Result<Arc<Event>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Event>>,
+    {
+        if let Some(event) = self.cache.get(&id) {
+            self.hits.hit(&id);
+            return Ok(event);
+        }
+        let event = Arc::new(fetch().await?);
+        self.cache.insert(id, event.clone());
+        // Counted once it exists, so that made-up ids are not warmed.
+        self.hits.hit(&id);
+        Ok(event)
+    }
+
+    /// Refresh cached events of at most `count` ones requested most, which expire within `within`.
+    /// Return the number of events refreshed.
+    pub async fn warm<F, Fut>(&self, count: usize, within: Duration, fetch: F) -> usize
+    where
+        F: Fn(i32) -> Fut,
+        Fut: Future<Output = Result<Event>>,
+    {
+        let mut refreshed = 0;
+
+        for id in self.hits.hottest(count) {
+            if !self.cache.expires_within(&id, within) {
+                continue;
+            }
+            match fetch(id).await {
+                Ok(event) => {
+                    self.cache.insert(id, Arc::new(event));
+                    refreshed += 1;
+                }
+                Err(e) => warn!("Failed to warm event {}: {}", id, e),
+            }
+        }
+        refreshed
+    }
+
+    /// Drop cached events which differ from their summaries in a fresh list, so that changes
+    /// are seen before the cache expires.
+    pub fn invalidate_changed(&self, summaries: &[EventSummary]) {
+        for summary in summaries {
+            if let Some(event) = self.cache.get_stale(&summary.id) {
+                if Event::clone(&event).summarize() != *summary {
+                    self.cache.remove(&summary.id);
+                }
+            }
+        }
+    }
 }
 
 impl Event {
@@ -153,6 +345,250 @@ impl Event {
             tags: None,
             place: "".to_string(),
             image: None,
+            audience: AUDIENCE_ALL,
         }
     }
 }
+
+/// Score an event for recommendation. Each tag scores the times it appears in the user's applied events,
+/// and a tag of the user's college scores extra 3.
+pub fn recommend_score(
+    tags: &[String],
+    history_tags: &HashMap<String, u32>,
+    college: Option<&str>,
+) -> u32 {
+    tags.iter()
+        .map(|tag| {
+            let college_score = if Some(tag.as_str()) == college { 3 } else { 0 };
+            history_tags.get(tag).copied().unwrap_or(0) + college_score
+        })
+        .sum()
+}
+
+/// Rank events by recommend score in descending order. Events with the same score keep their order.
+pub fn rank_events(
+    events: Vec<EventSummary>,
+    history_tags: &HashMap<String, u32>,
+    college: Option<&str>,
+) -> Vec<EventSummary> {
+    let mut scored: Vec<(u32, EventSummary)> = events
+        .into_iter()
+        .map(|e| {
+            let tags = e.tags.as_deref().unwrap_or_default();
+            (recommend_score(tags, history_tags, college), e)
+        })
+        .collect();
+
+    scored.sort_by_key(|x| std::cmp::Reverse(x.0));
+    scored.into_iter().map(|x| x.1).collect()
+}
+
+/// Get audiences whose events can be seen by the viewer.
+pub fn visible_audiences(viewer: i32) -> Vec<i32> {
+    if viewer == AUDIENCE_ALL {
+        vec![AUDIENCE_ALL, AUDIENCE_FRESHMAN, AUDIENCE_SENIOR]
+    } else {
+        vec![AUDIENCE_ALL, viewer]
+    }
+}
+
+/// Get the audience of a student by the enrollment year in student id.
+/// Students enrolled in the current academic year (since August) are freshmen.
+pub fn audience_of(student_id: &str, today: NaiveDate) -> i32 {
+    let enrollment_year = student_id.get(0..2).and_then(|x| x.parse::<i32>().ok());
+    let academic_year = if today.month() >= 8 {
+        today.year()
+    } else {
+        today.year() - 1
+    };
+
+    match enrollment_year {
+        Some(year) if year + 2000 == academic_year => AUDIENCE_FRESHMAN,
+        Some(_) => AUDIENCE_SENIOR,
+        None => AUDIENCE_ALL,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::test::db_pool;
+
+    #[test]
+    pub fn test_audience_of() {
+        let today = NaiveDate::from_ymd(2020, 9, 1);
+
+        assert_eq!(audience_of("2010100101", today), AUDIENCE_FRESHMAN);
+        assert_eq!(audience_of("1910100101", today), AUDIENCE_SENIOR);
+        assert_eq!(
+            audience_of("1910100101", NaiveDate::from_ymd(2020, 3, 1)),
+            AUDIENCE_FRESHMAN
+        );
+        assert_eq!(audience_of("", today), AUDIENCE_ALL);
+    }
+
+    #[test]
+    pub fn test_freshman_default_list() {
+        let events = vec![AUDIENCE_ALL, AUDIENCE_FRESHMAN, AUDIENCE_SENIOR];
+        let visible = visible_audiences(audience_of("2010100101", NaiveDate::from_ymd(2020, 9, 1)));
+        let listed: Vec<i32> = events.into_iter().filter(|x| visible.contains(x)).collect();
+
+        assert_eq!(listed, vec![AUDIENCE_ALL, AUDIENCE_FRESHMAN]);
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    async fn test_filter_events() {
+        let pool = db_pool().await;
+        // Ids unlikely to exist.
+        let events = [
+            (-100, "校园歌手大赛", "2021-10-01 19:00", "文艺"),
+            (-101, "篮球新生杯", "2021-10-02 14:00", "体育"),
+            (-102, "歌唱比赛彩排", "2021-10-05 19:00", "文艺"),
+            (-103, "100% 志愿服务", "2021-10-09 08:00", "志愿"),
+        ];
+        for (id, title, start_time, tag) in &events {
+            sqlx::query(
+                "INSERT INTO events.all_events (source, id, title, start_time, tags, place, description, audience)
+                    VALUES ($1, $2, $3, $4::timestamp, $5, '', '', $6)",
+            )
+            .bind(EVENT_TYPE_INNER)
+            .bind(id)
+            .bind(title)
+            .bind(start_time)
+            .bind(vec![tag.to_string(), "测试".to_string()])
+            .bind(AUDIENCE_ALL)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        let day = |s: &str| NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap();
+        let ids = |filter: EventFilter| {
+            let pool = &pool;
+            async move {
+                let page = PageView::new();
+                let list = Event::list(pool, &filter, &page, 50).await.unwrap();
+                assert_eq!(Event::count(pool, &filter).await.unwrap(), list.len() as i64);
+                list.into_iter()
+                    .map(|x| x.id)
+                    .filter(|x| *x < 0)
+                    .collect::<Vec<_>>()
+            }
+        };
+        let category = |x: &str| Some(x.to_string());
+
+        // Category only.
+        let filter = EventFilter {
+            category: category("文艺"),
+            ..Default::default()
+        };
+        assert_eq!(ids(filter).await, [-102, -100]);
+        // Keyword only, with wildcards matched literally.
+        let filter = EventFilter {
+            keyword: Some("篮球".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(ids(filter).await, [-101]);
+        let filter = EventFilter {
+            keyword: Some("%".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(ids(filter).await, [-103]);
+        // All conditions must match.
+        let filter = EventFilter {
+            category: category("文艺"),
+            keyword: Some("比赛".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(ids(filter).await, [-102]);
+        let filter = EventFilter {
+            category: category("测试"),
+            from: Some(day("2021-10-02")),
+            to: Some(day("2021-10-05")),
+            ..Default::default()
+        };
+        assert_eq!(ids(filter).await, [-102, -101]);
+        let filter = EventFilter {
+            category: category("体育"),
+            keyword: Some("歌".to_string()),
+            ..Default::default()
+        };
+        assert!(ids(filter).await.is_empty());
+
+        sqlx::query("DELETE FROM events.all_events WHERE id IN (-100, -101, -102, -103)")
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    pub fn test_event_cache() {
+        actix_web::rt::System::new("test").block_on(async {
+            let cache = EventCache::new(Duration::from_secs(60), crate::cache::MAX_HOT_KEYS);
+            let fetched = std::cell::Cell::new(0);
+            let fetch = |title: &'static str| {
+                let fetched = &fetched;
+                move || async move {
+                    fetched.set(fetched.get() + 1);
+                    Ok(Event {
+                        id: 1,
+                        title: title.to_string(),
+                        ..Event::default()
+                    })
+                }
+            };
+
+            let event = cache.get(1, fetch("讲座")).await.unwrap();
+            assert_eq!(event.title, "讲座");
+            // Hit, without fetching again.
+            let event = cache.get(1, fetch("讲座")).await.unwrap();
+            assert_eq!(event.title, "讲座");
+            assert_eq!(fetched.get(), 1);
+
+            // The same in the list.
+            cache.invalidate_changed(&[Event::clone(&event).summarize()]);
+            cache.get(1, fetch("讲座")).await.unwrap();
+            assert_eq!(fetched.get(), 1);
+            // Changed in the list.
+            let summary = EventSummary {
+                title: "讲座（延期）".to_string(),
+                ..Event::clone(&event).summarize()
+            };
+            cache.invalidate_changed(&[summary]);
+            let event = cache.get(1, fetch("讲座（延期）")).await.unwrap();
+            assert_eq!(event.title, "讲座（延期）");
+            assert_eq!(fetched.get(), 2);
+
+            // Not found is not cached.
+            let missing = cache
+                .get(2, || async { Err(ApiError::new(EventError::NoSuchEvent)) })
+                .await;
+            assert!(missing.is_err());
+        });
+    }
+
+    #[test]
+    pub fn test_recommend_order() {
+        let event = |id: i32, tags: &[&str]| EventSummary {
+            id,
+            tags: Some(tags.iter().map(|x| x.to_string()).collect()),
+            ..Event::default().summarize()
+        };
+        let events = vec![
+            event(1, &["讲座"]),
+            event(2, &["体育"]),
+            event(3, &["志愿", "体育"]),
+            event(4, &["计算机学院"]),
+            event(5, &[]),
+        ];
+        // The user applied two volunteer events and one sports event before.
+        let history_tags: HashMap<String, u32> = vec![("志愿".to_string(), 2), ("体育".to_string(), 1)]
+            .into_iter()
+            .collect();
+
+        let ranked = rank_events(events, &history_tags, Some("计算机学院"));
+        let ids: Vec<i32> = ranked.iter().map(|x| x.id).collect();
+        assert_eq!(ids, vec![3, 4, 2, 1, 5]);
+    }
+}
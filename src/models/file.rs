@@ -1,18 +1,27 @@
 //! This module manages attachments, storage, upload and provides some interfaces for administrators.
-//! At current time, file will be stored in local storage.
+//! Files are stored in local directory or object storage, see `storage`.
 
 mod attachment;
 mod avatar;
+pub mod scanner;
+mod signature;
+pub mod signed_url;
+pub mod storage;
+mod thumbnail;
+mod upload;
 
-use chrono::NaiveDateTime;
+use chrono::{DateTime, Local, NaiveDateTime};
 use serde::Serialize;
 use sqlx::PgPool;
 use uuid::Uuid;
 
-pub use attachment::get_attachment_url_prefix;
-pub use attachment::get_file_extension;
+pub use attachment::{blob_key, get_file_extension, sanitize_filename};
+pub use signature::UploadCheck;
+pub use storage::{Storage, STORAGE};
+pub use thumbnail::{encode_png, has_thumbnail, spawn_thumbnail, thumbnail_key, PNG_GRAYSCALE};
+pub use upload::{expire_uploads, is_valid_hash, part_path, ChunkAction, UploadManager};
 
-#[derive(Debug, thiserror::Error, Serialize, ToPrimitive)]
+#[derive(Debug, PartialEq, thiserror::Error, Serialize, ToPrimitive)]
 pub enum AttachmentError {
     #[error("文件名不正确")]
     FilenameRefused = 170,
@@ -26,6 +35,22 @@ pub enum AttachmentError {
     NoPayload = 174,
     #[error("文件大小超过限制")]
     TooLarge = 175,
+    #[error("不允许上传该类型的文件")]
+    DeniedType = 176,
+    #[error("链接无效或已过期")]
+    InvalidSignature = 177,
+    #[error("分片顺序错误")]
+    ChunkOutOfOrder = 178,
+    #[error("文件未上传完整")]
+    IncompleteUpload = 179,
+    #[error("文件校验失败")]
+    ChecksumMismatch = 180,
+    #[error("文件未通过安全检查")]
+    Infected = 181,
+    #[error("文件安全检查失败, 请稍后重试")]
+    ScanFailed = 182,
+    #[error("该文件没有缩略图")]
+    NoThumbnail = 183,
 }
 
 /// Attachment struct for the public.
@@ -50,8 +75,14 @@ pub struct Attachment {
     /// UID of uploader.
     pub uploader: i32,
     /// Upload time.
-    #[serde(rename(serialize = "uploadTime"))]
+    #[serde(
+        rename(serialize = "uploadTime"),
+        serialize_with = "crate::models::serialize_utc_time"
+    )]
     pub upload_time: NaiveDateTime,
+    /// Last time the record was changed.
+    #[serde(rename(serialize = "updateTime"))]
+    pub updated_at: DateTime<Local>,
     /// Storage path
     #[serde(skip_serializing)]
     pub path: Option<String>,
@@ -63,6 +94,15 @@ pub struct Attachment {
     pub url: Option<String>,
 }
 
+/// Conditions to list attachments.
+#[derive(Default)]
+pub struct AttachmentFilter {
+    /// Uid of uploader.
+    pub uploader: Option<i32>,
+    /// Fragment of file name.
+    pub name: Option<String>,
+}
+
 pub struct AttachmentManager<'a> {
     pool: &'a PgPool,
 }
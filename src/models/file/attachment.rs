@@ -1,24 +1,19 @@
-use super::{Attachment, AttachmentBasic, AttachmentError, AttachmentManager};
+use super::{
+    thumbnail_key, Attachment, AttachmentBasic, AttachmentError, AttachmentFilter, AttachmentManager,
+    Storage,
+};
 use crate::error::{ApiError, Result};
-use crate::models::PageView;
-use chrono::Utc;
-use sqlx::PgPool;
+use crate::models::{begin, escape_like, PageView};
+use chrono::{Local, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
 use uuid::Uuid;
 
-/// Url prefix for attachment.
-static URL_PREFIX: &'static str = "https://kite.sunnysab.cn/static/upload/";
-
 /// Allowed file extension
 static ALLOWED_EXT: &[&str] = &[
     "jpg", "png", "svg", "gif", "jpeg", // Images
     "doc", "docx", "xls", "xlsx", "ppt", "pptx", "txt", // Documents
 ];
 
-/// Get url prefix for attachment.
-pub fn get_attachment_url_prefix() -> &'static str {
-    URL_PREFIX
-}
-
 impl Attachment {
     pub fn new() -> Self {
         Default::default()
@@ -42,14 +37,23 @@ impl Attachment {
     }
 
     pub fn set_file(mut self, prefix: &str, path: String, size: i32) -> Self {
-        let ext = get_file_extension(&path);
-        self.url = Some(format!("{}{}.{}", prefix, self.id.to_string(), ext));
+        // Files are served by their names in the upload directory.
+        let file_name = path.rsplit('/').next().unwrap_or_default();
+        self.url = Some(format!("{}{}", prefix, file_name));
 
         self.path = Some(path);
         self.size = size;
 
         self
     }
+
+    /// Set the download link by the storage, for links of some storages expire.
+    pub fn with_url_from(mut self, storage: &dyn Storage) -> Self {
+        if let Some(path) = &self.path {
+            self.url = Some(storage.url(path));
+        }
+        self
+    }
 }
 
 /// Get file extension from file name.
@@ -60,7 +64,54 @@ pub fn get_file_extension(filename: &str) -> String {
     if last_terminator == 0usize || last_terminator == filename.len() - 1 {
         return "".to_string();
     }
-    filename[(last_terminator + 1)..].to_string()
+    let ext = &filename[(last_terminator + 1)..];
+    // The extension is a part of the storage key, so only plain ones are kept.
+    if ext.len() > MAX_EXTENSION_LENGTH || !ext.bytes().all(|c| c.is_ascii_alphanumeric()) {
+        return "".to_string();
+    }
+    ext.to_string()
+}
+
+/// Max length in chars of file names kept.
+const MAX_FILENAME_LENGTH: usize = 255;
+/// Max length of file extensions kept.
+const MAX_EXTENSION_LENGTH: usize = 16;
+
+/// Name of the uploaded file for display. Files are stored by content hashes, and the name given
+/// by the client is kept as metadata only. Directory components, like those of Windows paths
+/// sent by some browsers, are stripped, and so are control characters and spaces around. Names
+/// with `..` components, or left empty, are refused.
+pub fn sanitize_filename(name: &str) -> std::result::Result<String, AttachmentError> {
+    let components: Vec<&str> = name.split(['/', '\\']).collect();
+    if components.iter().any(|x| x.trim() == "..") {
+        return Err(AttachmentError::FilenameRefused);
+    }
+    let base = components.last().copied().unwrap_or_default();
+    let base: String = base.chars().filter(|c| !c.is_control()).collect();
+    let base = base.split_whitespace().collect::<Vec<_>>().join(" ");
+    if base.chars().all(|c| c == '.') {
+        return Err(AttachmentError::FilenameRefused);
+    }
+    Ok(base.chars().take(MAX_FILENAME_LENGTH).collect())
+}
+
+/// Storage key of the file with the given content hash. Attachments with the same content share it.
+pub fn blob_key(hash: &str, ext: &str) -> String {
+    if ext.is_empty() {
+        hash.to_string()
+    } else {
+        format!("{}.{}", hash, ext)
+    }
+}
+
+/// Serialize uploads and deletions of the same content, so that a file is never removed while
+/// another attachment is linked to it.
+async fn lock_blob(tx: &mut Transaction<'_, Postgres>, hash: &str) -> Result<()> {
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtext($1))")
+        .bind(hash)
+        .execute(tx)
+        .await?;
+    Ok(())
 }
 
 /// Add file extension check to avoid attacking.
@@ -75,19 +126,41 @@ impl<'a> AttachmentManager<'a> {
         Self { pool }
     }
 
-    /// Get attachment list for administrators.
-    pub async fn list(&self, page: PageView) -> Result<Vec<Attachment>> {
+    /// Get attachments matching the filter, the latest uploaded first.
+    pub async fn list(
+        &self,
+        filter: &AttachmentFilter,
+        page: &PageView,
+        max_count: u16,
+    ) -> Result<Vec<Attachment>> {
         let attachments: Vec<Attachment> = sqlx::query_as(
-            "SELECT id, name, path, uploader, is_deleted, size, upload_time, url
-                FROM public.attachments OFFSET $1 LIMIT $2",
+            "SELECT id, name, path, uploader, is_deleted, size, upload_time, updated_at, url
+                FROM public.attachments
+                WHERE ($1::int IS NULL OR uploader = $1) AND ($2::text IS NULL OR name ILIKE '%' || $2 || '%')
+                ORDER BY upload_time DESC, id OFFSET $3 LIMIT $4",
         )
-        .bind(page.offset(20) as i64)
-        .bind(page.count(20) as i64)
+        .bind(filter.uploader)
+        .bind(filter.name.as_deref().map(escape_like))
+        .bind(page.offset(max_count))
+        .bind(page.count(max_count) as i64)
         .fetch_all(self.pool)
         .await?;
         Ok(attachments)
     }
 
+    /// Count attachments matching the filter.
+    pub async fn count(&self, filter: &AttachmentFilter) -> Result<i64> {
+        let (count,): (i64,) = sqlx::query_as(
+            "SELECT COUNT(*) FROM public.attachments
+                WHERE ($1::int IS NULL OR uploader = $1) AND ($2::text IS NULL OR name ILIKE '%' || $2 || '%')",
+        )
+        .bind(filter.uploader)
+        .bind(filter.name.as_deref().map(escape_like))
+        .fetch_one(self.pool)
+        .await?;
+        Ok(count)
+    }
+
     /// Insert attachment record to database.
     pub async fn create(&self, attachment: &Attachment) -> Result<()> {
         let _ = sqlx::query(
@@ -107,17 +180,96 @@ impl<'a> AttachmentManager<'a> {
         Ok(())
     }
 
-    pub async fn delete(&self, attachment_id: Uuid) -> Result<()> {
-        let _ = sqlx::query("UPDATE public.attachment SET is_deleted = true WHERE attachment_id = $1")
+    /// Insert attachment record, with the uploaded file at `temp_path` stored by its content hash
+    /// under `key`. If the same content is stored already, the attachment is linked to it and the
+    /// uploaded file is dropped.
+    pub async fn create_deduplicated(
+        &self,
+        attachment: Attachment,
+        hash: &str,
+        temp_path: &str,
+        key: &str,
+        storage: &dyn Storage,
+    ) -> Result<Attachment> {
+        let mut tx = begin(self.pool).await?;
+        lock_blob(&mut tx, hash).await?;
+
+        let existing: Option<(Option<String>,)> =
+            sqlx::query_as("SELECT path FROM public.attachments WHERE hash = $1 LIMIT 1")
+                .bind(hash)
+                .fetch_optional(&mut tx)
+                .await?;
+        let path = match existing.and_then(|(path,)| path) {
+            Some(path) => {
+                let _ = tokio::fs::remove_file(temp_path).await;
+                path
+            }
+            None => {
+                storage.put(key, temp_path).await?;
+                key.to_string()
+            }
+        };
+        let mut attachment = attachment;
+        attachment.path = Some(path);
+        let attachment = attachment.with_url_from(storage);
+
+        sqlx::query(
+            "INSERT INTO public.attachments (id, name, path, uploader, upload_time, size, url, hash)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+        )
+        .bind(attachment.id)
+        .bind(&attachment.name)
+        .bind(&attachment.path)
+        .bind(attachment.uploader)
+        .bind(attachment.upload_time)
+        .bind(attachment.size)
+        .bind(&attachment.url)
+        .bind(hash)
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(attachment)
+    }
+
+    /// Delete attachment record, and the file with its thumbnail if no other attachment is linked
+    /// to it.
+    pub async fn delete(&self, attachment_id: Uuid, storage: &dyn Storage) -> Result<()> {
+        let mut tx = begin(self.pool).await?;
+
+        let (hash, path): (Option<String>, Option<String>) =
+            sqlx::query_as("SELECT hash, path FROM public.attachments WHERE id = $1")
+                .bind(attachment_id)
+                .fetch_optional(&mut tx)
+                .await?
+                .ok_or(ApiError::new(AttachmentError::NotFound))?;
+        // Attachments uploaded before deduplication have no hash, and are never linked to.
+        if let Some(hash) = &hash {
+            lock_blob(&mut tx, hash).await?;
+        }
+        sqlx::query("DELETE FROM public.attachments WHERE id = $1")
             .bind(attachment_id)
-            .execute(self.pool)
+            .execute(&mut tx)
             .await?;
+
+        if let Some(path) = path {
+            let (references,): (i64,) =
+                sqlx::query_as("SELECT COUNT(*) FROM public.attachments WHERE path = $1")
+                    .bind(&path)
+                    .fetch_one(&mut tx)
+                    .await?;
+            if references == 0 {
+                storage.delete(&path).await?;
+                storage.delete(&thumbnail_key(&path)).await?;
+            }
+        }
+        tx.commit().await?;
         Ok(())
     }
 
     pub async fn query(&self, id: Uuid) -> Result<Attachment> {
         let basic_info: Option<Attachment> = sqlx::query_as(
-            "SELECT id, name, path, uploader, upload_time, is_deleted, size, url 
+            "SELECT id, name, path, uploader, upload_time, updated_at, is_deleted, size, url
                 FROM public.attachments WHERE id = $1 LIMIT 1",
         )
         .bind(id)
@@ -137,6 +289,7 @@ impl Default for Attachment {
             name: "".to_string(),
             uploader: 0,
             upload_time: Utc::now().naive_local(),
+            updated_at: Local::now(),
             path: None,
             size: 0,
             is_deleted: false,
@@ -157,10 +310,156 @@ impl From<Attachment> for AttachmentBasic {
 
 #[cfg(test)]
 mod test {
+    use super::*;
+    use crate::models::file::storage::LocalStorage;
+    use crate::models::test::db_pool;
+
     #[test]
     pub fn get_file_extension() {
         assert_eq!(super::get_file_extension("a.jpg"), "jpg");
         assert_eq!(super::get_file_extension("a."), "");
         assert_eq!(super::get_file_extension("a"), "");
+        assert_eq!(super::get_file_extension("a./../../etc/passwd"), "");
+        assert_eq!(super::get_file_extension("a.tar%00.gz"), "gz");
+        assert_eq!(super::get_file_extension("a.j p g"), "");
+    }
+
+    #[test]
+    pub fn test_sanitize_filename() {
+        let refused = Err(AttachmentError::FilenameRefused);
+
+        assert_eq!(sanitize_filename("../../etc/x"), refused);
+        assert_eq!(sanitize_filename("..\\..\\windows\\x.ini"), refused);
+        assert_eq!(sanitize_filename("a/ .. /b"), refused);
+        assert_eq!(sanitize_filename(".."), refused);
+        assert_eq!(sanitize_filename("dir/"), refused);
+        assert_eq!(sanitize_filename(" \t"), refused);
+        // Directories of the client are stripped.
+        assert_eq!(sanitize_filename("/etc/passwd").unwrap(), "passwd");
+        assert_eq!(
+            sanitize_filename("C:\\Users\\张三\\照片.jpg").unwrap(),
+            "照片.jpg"
+        );
+        // Unicode is kept, and spaces are normalized.
+        assert_eq!(
+            sanitize_filename("  期末 复习\u{3000}资料\n(1).docx ").unwrap(),
+            "期末 复习 资料(1).docx"
+        );
+        assert_eq!(sanitize_filename("a\u{0}b.txt").unwrap(), "ab.txt");
+        assert_eq!(sanitize_filename(&"长".repeat(300)).unwrap().chars().count(), 255);
+    }
+
+    #[test]
+    pub fn test_blob_key() {
+        assert_eq!(blob_key("ab12", "pdf"), "ab12.pdf");
+        assert_eq!(blob_key("ab12", ""), "ab12");
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    async fn test_filter_attachments() {
+        let pool = db_pool().await;
+        let manager = AttachmentManager::new(&pool);
+        // Uploaders unlikely to exist.
+        let (alice, bob) = (-100, -101);
+        let attachments = vec![
+            Attachment::new().set_uploader(alice).set_name("Report 2021.pdf"),
+            Attachment::new().set_uploader(alice).set_name("photo.png"),
+            Attachment::new().set_uploader(bob).set_name("report_draft.docx"),
+            Attachment::new().set_uploader(bob).set_name("100% done.txt"),
+        ];
+        for attachment in &attachments {
+            manager.create(attachment).await.unwrap();
+        }
+
+        let filter = |uploader: Option<i32>, name: Option<&str>| AttachmentFilter {
+            uploader,
+            name: name.map(ToString::to_string),
+        };
+        let names = |filter: AttachmentFilter| {
+            let manager = &manager;
+            async move {
+                let page = PageView::new();
+                let list = manager.list(&filter, &page, 20).await.unwrap();
+                assert_eq!(manager.count(&filter).await.unwrap(), list.len() as i64);
+                let mut names: Vec<String> = list.into_iter().map(|x| x.name).collect();
+                names.sort();
+                names
+            }
+        };
+
+        assert_eq!(
+            names(filter(Some(alice), None)).await,
+            ["Report 2021.pdf", "photo.png"]
+        );
+        // Case insensitive.
+        assert_eq!(
+            names(filter(Some(bob), Some("REPORT"))).await,
+            ["report_draft.docx"]
+        );
+        assert_eq!(
+            names(filter(Some(alice), Some("report"))).await,
+            ["Report 2021.pdf"]
+        );
+        // Wildcards are matched literally.
+        assert_eq!(names(filter(Some(bob), Some("%"))).await, ["100% done.txt"]);
+        assert!(names(filter(Some(alice), Some("_"))).await.is_empty());
+        // Paged.
+        let page = PageView {
+            index: Some(2),
+            count: Some(1),
+        };
+        let second = manager.list(&filter(Some(alice), None), &page, 20).await.unwrap();
+        assert_eq!(second.len(), 1);
+
+        sqlx::query("DELETE FROM public.attachments WHERE uploader IN ($1, $2)")
+            .bind(alice)
+            .bind(bob)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    async fn test_deduplicated_attachments() {
+        let pool = db_pool().await;
+        let manager = AttachmentManager::new(&pool);
+        let dir = std::env::temp_dir().join(format!("kite-test-{}", Uuid::new_v4()));
+        let dir = dir.to_str().unwrap();
+        std::fs::create_dir_all(format!("{}/upload", dir)).unwrap();
+        let hash = Uuid::new_v4().to_string();
+        let storage = LocalStorage::new(dir, "");
+        let key = blob_key(&hash, "pdf");
+        let path = format!("{}/upload/{}", dir, key);
+
+        let upload = |name: &str| {
+            let temp_path = format!("{}/upload/{}.part", dir, name);
+            std::fs::write(&temp_path, b"%PDF-1.4").unwrap();
+            temp_path
+        };
+        let first = upload("first");
+        let a = manager
+            .create_deduplicated(Attachment::new().set_uploader(1), &hash, &first, &key, &storage)
+            .await
+            .unwrap();
+        // The same content is linked to the stored file.
+        let second = upload("second");
+        let b = manager
+            .create_deduplicated(Attachment::new().set_uploader(2), &hash, &second, &key, &storage)
+            .await
+            .unwrap();
+        assert_eq!(a.path, Some(key.clone()));
+        assert_eq!(b.path, Some(key.clone()));
+        assert!(!std::path::Path::new(&second).exists());
+
+        // The file is kept until the last attachment is deleted.
+        manager.delete(a.id, &storage).await.unwrap();
+        assert!(std::path::Path::new(&path).exists());
+        manager.delete(b.id, &storage).await.unwrap();
+        assert!(!std::path::Path::new(&path).exists());
+        assert!(manager.delete(b.id, &storage).await.is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
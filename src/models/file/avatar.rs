@@ -17,7 +17,7 @@ impl<'a> AvatarManager<'a> {
 
     pub async fn query(&self, original_url: &str) -> Result<AvatarImage> {
         let avatar = sqlx::query_as(
-            "SELECT id, name, path, uploader, is_deleted, size, upload_time, url
+            "SELECT id, name, path, uploader, is_deleted, size, upload_time, updated_at, url
                 FROM public.attachments WHERE name = $1 LIMIT 1",
         )
         .bind(original_url)
@@ -0,0 +1,233 @@
+//! Content scan of uploaded files, required by the deployment policy before they are stored. The
+//! scanner is selected by config, which is ClamAV over its socket, or none for development.
+
+use crate::config::{ScannerBackend, CONFIG};
+use crate::error::{ApiError, Result};
+use futures::future::{ok, LocalBoxFuture};
+use log::warn;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::AttachmentError;
+
+/// Bytes sent to clamd in a chunk.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+lazy_static! {
+    /// Scanner selected by config.
+    pub static ref SCANNER: Box<dyn ContentScanner> = scanner_from_config();
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ScanResult {
+    Clean,
+    /// Name of the threat detected.
+    Infected(String),
+}
+
+/// Scan file contents for viruses or other threats.
+pub trait ContentScanner: Send + Sync {
+    fn scan<'a>(&'a self, content: &'a [u8]) -> LocalBoxFuture<'a, Result<ScanResult>>;
+}
+
+/// Scanner which accepts everything.
+pub struct NoopScanner;
+
+impl ContentScanner for NoopScanner {
+    fn scan<'a>(&'a self, _: &'a [u8]) -> LocalBoxFuture<'a, Result<ScanResult>> {
+        Box::pin(ok(ScanResult::Clean))
+    }
+}
+
+/// Scanner which streams contents to clamd by the `INSTREAM` command.
+pub struct ClamavScanner {
+    /// Path of the unix socket, or address of the TCP socket like `127.0.0.1:3310`.
+    addr: String,
+}
+
+impl ClamavScanner {
+    pub fn new(addr: &str) -> Self {
+        Self {
+            addr: addr.to_string(),
+        }
+    }
+}
+
+/// Send the content in chunks prefixed with their lengths, terminated by an empty chunk, and read
+/// the reply.
+async fn instream<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    content: &[u8],
+) -> std::io::Result<String> {
+    stream.write_all(b"zINSTREAM\0").await?;
+    for chunk in content.chunks(CHUNK_SIZE) {
+        stream.write_all(&(chunk.len() as u32).to_be_bytes()).await?;
+        stream.write_all(chunk).await?;
+    }
+    stream.write_all(&[0u8; 4]).await?;
+
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).await?;
+    Ok(String::from_utf8_lossy(&reply)
+        .trim_end_matches('\0')
+        .trim()
+        .to_string())
+}
+
+/// Parse replies like `stream: OK` and `stream: Eicar-Signature FOUND`.
+fn parse_reply(reply: &str) -> Result<ScanResult> {
+    let result = reply.strip_prefix("stream:").map(str::trim);
+
+    match result {
+        Some("OK") => Ok(ScanResult::Clean),
+        Some(x) if x.ends_with(" FOUND") => {
+            Ok(ScanResult::Infected(x.trim_end_matches(" FOUND").to_string()))
+        }
+        _ => Err(ApiError::from(format!("Unexpected reply from clamd: {}", reply))),
+    }
+}
+
+impl ContentScanner for ClamavScanner {
+    fn scan<'a>(&'a self, content: &'a [u8]) -> LocalBoxFuture<'a, Result<ScanResult>> {
+        Box::pin(async move {
+            let reply = if self.addr.starts_with('/') {
+                let stream = tokio::net::UnixStream::connect(&self.addr).await?;
+                instream(stream, content).await?
+            } else {
+                let stream = tokio::net::TcpStream::connect(&self.addr).await?;
+                instream(stream, content).await?
+            };
+            parse_reply(&reply)
+        })
+    }
+}
+
+/// Scan the content within `timeout`. Errors and timeouts of the scanner fail the scan, so that no
+/// file is stored without being scanned.
+pub async fn scan(
+    scanner: &dyn ContentScanner,
+    content: &[u8],
+    timeout: Duration,
+) -> Result<ScanResult> {
+    match tokio::time::timeout(timeout, scanner.scan(content)).await {
+        Ok(Ok(result)) => Ok(result),
+        Ok(Err(e)) => {
+            warn!("Failed to scan the upload: {}", e);
+            Err(ApiError::new(AttachmentError::ScanFailed))
+        }
+        Err(_) => {
+            warn!("Scanning the upload timed out after {:?}.", timeout);
+            Err(ApiError::new(AttachmentError::ScanFailed))
+        }
+    }
+}
+
+fn scanner_from_config() -> Box<dyn ContentScanner> {
+    match CONFIG.scan.scanner {
+        ScannerBackend::Noop => Box::new(NoopScanner),
+        ScannerBackend::Clamav => {
+            let addr = CONFIG
+                .scan
+                .clamav
+                .as_ref()
+                .expect("scan.clamav is required by the clamav scanner");
+            Box::new(ClamavScanner::new(addr))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Scanner which finds a threat in contents with the marker.
+    struct StubScanner {
+        delay: Duration,
+    }
+
+    impl ContentScanner for StubScanner {
+        fn scan<'a>(&'a self, content: &'a [u8]) -> LocalBoxFuture<'a, Result<ScanResult>> {
+            Box::pin(async move {
+                tokio::time::delay_for(self.delay).await;
+                match content.windows(6).any(|x| x == b"EICAR!") {
+                    true => Ok(ScanResult::Infected("Stub-Signature".to_string())),
+                    false => Ok(ScanResult::Clean),
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scan_with_stub() {
+        let scanner = StubScanner {
+            delay: Duration::from_millis(0),
+        };
+        let timeout = Duration::from_secs(1);
+
+        assert_eq!(scan(&scanner, b"hello", timeout).await, Ok(ScanResult::Clean));
+        assert_eq!(
+            scan(&scanner, b"X5O!EICAR!", timeout).await,
+            Ok(ScanResult::Infected("Stub-Signature".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_scan_timeout() {
+        let scanner = StubScanner {
+            delay: Duration::from_secs(5),
+        };
+
+        let result = scan(&scanner, b"hello", Duration::from_millis(50)).await;
+        assert_eq!(result, Err(ApiError::new(AttachmentError::ScanFailed)));
+    }
+
+    #[test]
+    pub fn test_parse_reply() {
+        assert_eq!(parse_reply("stream: OK"), Ok(ScanResult::Clean));
+        assert_eq!(
+            parse_reply("stream: Eicar-Test-Signature FOUND"),
+            Ok(ScanResult::Infected("Eicar-Test-Signature".to_string()))
+        );
+        assert!(parse_reply("INSTREAM size limit exceeded. ERROR").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_clamav_protocol() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // A fake clamd, which receives the stream and reports a threat.
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut command = [0u8; 10];
+            socket.read_exact(&mut command).await.unwrap();
+            assert_eq!(&command, b"zINSTREAM\0");
+
+            let mut received = Vec::new();
+            loop {
+                let mut length = [0u8; 4];
+                socket.read_exact(&mut length).await.unwrap();
+                let mut chunk = vec![0u8; u32::from_be_bytes(length) as usize];
+                if chunk.is_empty() {
+                    break;
+                }
+                socket.read_exact(&mut chunk).await.unwrap();
+                received.extend(chunk);
+            }
+            socket
+                .write_all(b"stream: Eicar-Test-Signature FOUND\0")
+                .await
+                .unwrap();
+            received
+        });
+
+        let content = vec![7u8; CHUNK_SIZE + 10];
+        let scanner = ClamavScanner::new(&addr.to_string());
+        let result = scan(&scanner, &content, Duration::from_secs(5)).await;
+        assert_eq!(
+            result,
+            Ok(ScanResult::Infected("Eicar-Test-Signature".to_string()))
+        );
+        assert_eq!(server.await.unwrap(), content);
+    }
+}
@@ -0,0 +1,227 @@
+//! File type detection by leading magic bytes, so that uploaded executables and scripts can be
+//! refused whatever the declared content type is, and only allowed content types are accepted.
+
+use super::AttachmentError;
+
+/// Known file signatures, in the form of (type name, magic bytes).
+static SIGNATURES: &[(&str, &[u8])] = &[
+    ("elf", b"\x7fELF"),
+    ("pe", b"MZ"),
+    ("mach-o", b"\xfe\xed\xfa\xce"),
+    ("mach-o", b"\xfe\xed\xfa\xcf"),
+    ("mach-o", b"\xce\xfa\xed\xfe"),
+    ("mach-o", b"\xcf\xfa\xed\xfe"),
+    ("java-class", b"\xca\xfe\xba\xbe"),
+    ("script", b"#!"),
+];
+
+/// Known content types, in the form of (MIME type, extensions, offset, magic bytes). The first
+/// extension is used if the file name doesn't have one of them.
+static CONTENT_TYPES: &[(&str, &[&str], usize, &[u8])] = &[
+    ("image/png", &["png"], 0, b"\x89PNG"),
+    ("image/jpeg", &["jpg", "jpeg"], 0, b"\xff\xd8\xff"),
+    ("image/gif", &["gif"], 0, b"GIF8"),
+    ("image/webp", &["webp"], 8, b"WEBP"),
+    ("application/pdf", &["pdf"], 0, b"%PDF"),
+    // Office Open XML documents are zip archives.
+    (
+        "application/zip",
+        &["zip", "docx", "xlsx", "pptx"],
+        0,
+        b"PK\x03\x04",
+    ),
+    // Legacy office documents.
+    (
+        "application/x-ole-storage",
+        &["doc", "xls", "ppt"],
+        0,
+        b"\xd0\xcf\x11\xe0",
+    ),
+];
+
+/// Bytes needed to match any signature or content type.
+const SNIFF_LEN: usize = 12;
+
+/// Detect file type over a stream, with only the first `SNIFF_LEN` bytes kept.
+#[derive(Default)]
+pub struct SignatureSniffer {
+    prefix: Vec<u8>,
+}
+
+impl SignatureSniffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed the next chunk of the file, and return the type name if it's in the denylist.
+    pub fn feed(&mut self, chunk: &[u8], denylist: &[String]) -> Option<&'static str> {
+        if self.prefix.len() < SNIFF_LEN {
+            let n = chunk.len().min(SNIFF_LEN - self.prefix.len());
+            self.prefix.extend_from_slice(&chunk[..n]);
+        }
+        detect(&self.prefix).filter(|name| denylist.iter().any(|x| x == name))
+    }
+
+    /// Whether enough bytes are fed to detect the content type.
+    pub fn is_complete(&self) -> bool {
+        self.prefix.len() >= SNIFF_LEN
+    }
+
+    /// Detected MIME type and extensions.
+    pub fn content_type(&self) -> Option<(&'static str, &'static [&'static str])> {
+        detect_content_type(&self.prefix)
+    }
+}
+
+/// Detect MIME type and extensions by leading bytes.
+pub fn detect_content_type(prefix: &[u8]) -> Option<(&'static str, &'static [&'static str])> {
+    CONTENT_TYPES
+        .iter()
+        .find(|(_, _, offset, magic)| prefix.get(*offset..).map(|x| x.starts_with(magic)) == Some(true))
+        .map(|(mime, extensions, _, _)| (*mime, *extensions))
+}
+
+/// Checks on a file being uploaded, fed chunk by chunk.
+pub struct UploadCheck<'a> {
+    sniffer: SignatureSniffer,
+    size: usize,
+    max_size: usize,
+    /// Allowed MIME types, or any type if empty.
+    allowed_types: &'a [String],
+    denied_signatures: &'a [String],
+}
+
+impl<'a> UploadCheck<'a> {
+    pub fn new(max_size: usize, allowed_types: &'a [String], denied_signatures: &'a [String]) -> Self {
+        Self {
+            sniffer: SignatureSniffer::new(),
+            size: 0,
+            max_size,
+            allowed_types,
+            denied_signatures,
+        }
+    }
+
+    /// Size fed so far.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Feed the next chunk, and fail as soon as the file is known to be refused.
+    pub fn feed(&mut self, chunk: &[u8]) -> Result<(), AttachmentError> {
+        self.size += chunk.len();
+        if self.size > self.max_size {
+            return Err(AttachmentError::TooLarge);
+        }
+        if self.sniffer.feed(chunk, self.denied_signatures).is_some() {
+            return Err(AttachmentError::DeniedType);
+        }
+        if self.sniffer.is_complete() {
+            self.check_type()?;
+        }
+        Ok(())
+    }
+
+    fn check_type(&self) -> Result<(), AttachmentError> {
+        if self.allowed_types.is_empty() {
+            return Ok(());
+        }
+        match self.sniffer.content_type() {
+            Some((mime, _)) if self.allowed_types.iter().any(|x| x == mime) => Ok(()),
+            _ => Err(AttachmentError::DeniedType),
+        }
+    }
+
+    /// Check the whole file, and return the extension to store it with. The extension of file
+    /// name is kept only if it matches the detected type.
+    pub fn finish(&self, filename_ext: &str) -> Result<String, AttachmentError> {
+        self.check_type()?;
+
+        let ext = match self.sniffer.content_type() {
+            Some((_, extensions))
+                if !extensions.iter().any(|x| x.eq_ignore_ascii_case(filename_ext)) =>
+            {
+                extensions[0].to_string()
+            }
+            _ => filename_ext.to_ascii_lowercase(),
+        };
+        Ok(ext)
+    }
+}
+
+/// Detect file type by leading bytes.
+pub fn detect(prefix: &[u8]) -> Option<&'static str> {
+    SIGNATURES
+        .iter()
+        .find(|(_, magic)| prefix.starts_with(magic))
+        .map(|(name, _)| *name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_sniff_executable() {
+        let denylist = vec!["elf".to_string(), "pe".to_string()];
+
+        // A PE executable split across chunks, which claims to be image/png on upload.
+        let mut sniffer = SignatureSniffer::new();
+        assert_eq!(sniffer.feed(b"M", &denylist), None);
+        assert_eq!(sniffer.feed(b"Z\x90\x00", &denylist), Some("pe"));
+
+        let mut sniffer = SignatureSniffer::new();
+        assert_eq!(sniffer.feed(b"\x89PNG\r\n\x1a\n", &denylist), None);
+        assert_eq!(sniffer.feed(b"MZ", &denylist), None);
+
+        // Script is known but not denied here.
+        let mut sniffer = SignatureSniffer::new();
+        assert_eq!(sniffer.feed(b"#!/bin/sh", &denylist), None);
+    }
+
+    #[test]
+    pub fn test_detect_content_type() {
+        assert_eq!(detect_content_type(b"\x89PNG\r\n\x1a\n").unwrap().0, "image/png");
+        assert_eq!(
+            detect_content_type(b"RIFF\x00\x00\x00\x00WEBPVP8 ").unwrap().0,
+            "image/webp"
+        );
+        assert_eq!(detect_content_type(b"RIFF"), None);
+        assert_eq!(detect_content_type(b"hello, world"), None);
+    }
+
+    #[test]
+    pub fn test_upload_check() {
+        let allowed = vec!["image/png".to_string(), "application/zip".to_string()];
+        let denied = vec!["elf".to_string()];
+
+        // Oversized upload, found before the file is fully read.
+        let mut check = UploadCheck::new(16, &allowed, &denied);
+        assert!(check.feed(b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0d").is_ok());
+        assert!(matches!(check.feed(&[0; 8]), Err(AttachmentError::TooLarge)));
+
+        // A shell script named as an image.
+        let mut check = UploadCheck::new(1024, &allowed, &denied);
+        assert!(matches!(
+            check.feed(b"#!/bin/sh\nrm -rf /\n"),
+            Err(AttachmentError::DeniedType)
+        ));
+
+        // Short text file, refused at the end.
+        let mut check = UploadCheck::new(1024, &allowed, &denied);
+        assert!(check.feed(b"hi").is_ok());
+        assert!(matches!(check.finish("png"), Err(AttachmentError::DeniedType)));
+
+        // The extension follows the content.
+        let mut check = UploadCheck::new(1024, &allowed, &denied);
+        assert!(check.feed(b"PK\x03\x04\x14\x00\x06\x00\x08\x00\x00\x00").is_ok());
+        assert_eq!(check.finish("DOCX").unwrap(), "docx");
+        assert_eq!(check.finish("png").unwrap(), "zip");
+        assert_eq!(check.size(), 12);
+
+        // Any type when no allowlist given.
+        let mut check = UploadCheck::new(1024, &[], &denied);
+        assert!(check.feed(b"plain text").is_ok());
+        assert_eq!(check.finish("txt").unwrap(), "txt");
+    }
+}
@@ -0,0 +1,75 @@
+//! Signed and time-limited links of attachments, so that a private attachment can be shared
+//! without the access token.
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use uuid::Uuid;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Purpose of the key derived from the server secret to sign links, so that the secret of access
+/// tokens is not used directly.
+pub const KEY_PURPOSE: &str = "signed_url";
+
+/// Seconds before a signed link expires.
+pub const SIGNED_URL_TTL: i64 = 600;
+
+fn mac(id: &Uuid, expires: i64, key: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_varkey(key).expect("HMAC accepts keys of any size");
+
+    mac.update(format!("{}:{}", id, expires).as_bytes());
+    mac
+}
+
+/// Sign the attachment id with the expiry time in unix timestamp.
+pub fn sign(id: &Uuid, expires: i64, key: &[u8]) -> String {
+    let code = mac(id, expires, key).finalize().into_bytes();
+
+    base64::encode_config(code, base64::URL_SAFE_NO_PAD)
+}
+
+/// Verify the signature of the attachment id and expiry time.
+pub fn verify(id: &Uuid, expires: i64, signature: &str, now: i64, key: &[u8]) -> bool {
+    if expires < now {
+        return false;
+    }
+    match base64::decode_config(signature, base64::URL_SAFE_NO_PAD) {
+        // Compare in constant time.
+        Ok(code) => mac(id, expires, key).verify(&code).is_ok(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const KEY: &[u8] = b"secret";
+
+    #[test]
+    pub fn test_valid_signature() {
+        let id = Uuid::new_v4();
+        let signature = sign(&id, 1000, KEY);
+
+        assert!(verify(&id, 1000, &signature, 1000, KEY));
+    }
+
+    #[test]
+    pub fn test_expired_signature() {
+        let id = Uuid::new_v4();
+        let signature = sign(&id, 1000, KEY);
+
+        assert!(!verify(&id, 1000, &signature, 1001, KEY));
+    }
+
+    #[test]
+    pub fn test_tampered_signature() {
+        let id = Uuid::new_v4();
+        let signature = sign(&id, 1000, KEY);
+
+        assert!(!verify(&Uuid::new_v4(), 1000, &signature, 0, KEY));
+        assert!(!verify(&id, 2000, &signature, 0, KEY));
+        assert!(!verify(&id, 1000, &signature, 0, b"another"));
+        assert!(!verify(&id, 1000, "not-a-signature", 0, KEY));
+    }
+}
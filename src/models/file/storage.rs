@@ -0,0 +1,228 @@
+//! Storage of attachment files, in local directory or an S3-compatible object storage. Files are
+//! addressed by keys, which are stored as `path` of attachments.
+
+mod s3;
+
+use super::AttachmentError;
+use crate::config::{StorageBackend, CONFIG};
+use crate::error::{ApiError, Result};
+use actix_web::web::Bytes;
+use futures::future::LocalBoxFuture;
+use futures::stream::{self, LocalBoxStream, StreamExt};
+use log::warn;
+use std::io::SeekFrom;
+use std::path::{Component, Path};
+use tokio::io::AsyncReadExt;
+
+pub use s3::S3Storage;
+
+/// Url prefix for attachments in local storage.
+static URL_PREFIX: &str = "https://kite.sunnysab.cn/static/upload/";
+
+/// Bytes read from a local file at a time, when streamed.
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Content of a file, streamed in chunks so that it's never read into memory as a whole.
+pub type ByteStream = LocalBoxStream<'static, Result<Bytes>>;
+
+lazy_static! {
+    /// Storage selected by config.
+    pub static ref STORAGE: Box<dyn Storage> = storage_from_config();
+}
+
+pub trait Storage: Send + Sync {
+    /// Move the local file to the storage under the key.
+    fn put<'a>(&'a self, key: &'a str, local_path: &'a str) -> LocalBoxFuture<'a, Result<()>>;
+    fn get<'a>(&'a self, key: &'a str) -> LocalBoxFuture<'a, Result<Vec<u8>>>;
+    /// Size of the file in bytes.
+    fn size<'a>(&'a self, key: &'a str) -> LocalBoxFuture<'a, Result<u64>>;
+    /// Stream `len` bytes of the file from `start`.
+    fn read_range<'a>(
+        &'a self,
+        key: &'a str,
+        start: u64,
+        len: u64,
+    ) -> LocalBoxFuture<'a, Result<ByteStream>>;
+    /// Delete the file. It's not an error if the file is missing.
+    fn delete<'a>(&'a self, key: &'a str) -> LocalBoxFuture<'a, Result<()>>;
+    /// Link to download the file, which may expire.
+    fn url(&self, key: &str) -> String;
+}
+
+/// Files in the upload directory, served by a static file server.
+pub struct LocalStorage {
+    dir: String,
+    url_prefix: String,
+}
+
+impl LocalStorage {
+    pub fn new(dir: &str, url_prefix: &str) -> Self {
+        Self {
+            dir: dir.to_string(),
+            url_prefix: url_prefix.to_string(),
+        }
+    }
+
+    /// Attachments uploaded before are keyed by full paths, which are in the upload directory too.
+    fn file_name(key: &str) -> &str {
+        key.rsplit('/').next().unwrap_or_default()
+    }
+
+    /// Path of the file, which is always in the upload directory.
+    fn path(&self, key: &str) -> Result<String> {
+        let name = Self::file_name(key);
+        match Path::new(name).components().collect::<Vec<_>>()[..] {
+            [Component::Normal(_)] => Ok(format!("{}/upload/{}", self.dir, name)),
+            _ => {
+                warn!("Refused attachment key out of the upload directory: {}", key);
+                Err(ApiError::new(AttachmentError::FilenameRefused))
+            }
+        }
+    }
+}
+
+impl Storage for LocalStorage {
+    fn put<'a>(&'a self, key: &'a str, local_path: &'a str) -> LocalBoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            tokio::fs::rename(local_path, self.path(key)?).await.map_err(|e| {
+                warn!("Failed to store attachment file {}: {}", key, e);
+                ApiError::new(AttachmentError::FailedToWrite)
+            })
+        })
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> LocalBoxFuture<'a, Result<Vec<u8>>> {
+        Box::pin(async move {
+            tokio::fs::read(self.path(key)?)
+                .await
+                .map_err(|_| ApiError::new(AttachmentError::NotFound))
+        })
+    }
+
+    fn size<'a>(&'a self, key: &'a str) -> LocalBoxFuture<'a, Result<u64>> {
+        Box::pin(async move {
+            tokio::fs::metadata(self.path(key)?)
+                .await
+                .map(|x| x.len())
+                .map_err(|_| ApiError::new(AttachmentError::NotFound))
+        })
+    }
+
+    fn read_range<'a>(
+        &'a self,
+        key: &'a str,
+        start: u64,
+        len: u64,
+    ) -> LocalBoxFuture<'a, Result<ByteStream>> {
+        Box::pin(async move {
+            let mut file = tokio::fs::File::open(self.path(key)?)
+                .await
+                .map_err(|_| ApiError::new(AttachmentError::NotFound))?;
+            file.seek(SeekFrom::Start(start)).await?;
+
+            let chunks = stream::unfold((file, len), |(mut file, remaining)| async move {
+                if remaining == 0 {
+                    return None;
+                }
+                let mut buffer = vec![0u8; remaining.min(CHUNK_SIZE) as usize];
+                match file.read(&mut buffer).await {
+                    // The file is truncated.
+                    Ok(0) => None,
+                    Ok(n) => {
+                        buffer.truncate(n);
+                        Some((Ok(Bytes::from(buffer)), (file, remaining - n as u64)))
+                    }
+                    Err(e) => Some((Err(ApiError::from(e)), (file, 0))),
+                }
+            });
+            Ok(chunks.boxed_local())
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> LocalBoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            match tokio::fs::remove_file(self.path(key)?).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    warn!("Failed to remove attachment file {}: {}", key, e);
+                    Err(ApiError::new(AttachmentError::FailedToWrite))
+                }
+                _ => Ok(()),
+            }
+        })
+    }
+
+    fn url(&self, key: &str) -> String {
+        format!("{}{}", self.url_prefix, Self::file_name(key))
+    }
+}
+
+fn storage_from_config() -> Box<dyn Storage> {
+    match CONFIG.storage.backend {
+        StorageBackend::Local => Box::new(LocalStorage::new(&CONFIG.server.attachment, URL_PREFIX)),
+        StorageBackend::S3 => {
+            let config = CONFIG
+                .storage
+                .s3
+                .as_ref()
+                .expect("storage.s3 is required by the S3 backend");
+            Box::new(S3Storage::new(config, CONFIG.server.max_attachment_size))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_local_storage() {
+        let dir = std::env::temp_dir().join(format!("kite-test-{}", uuid::Uuid::new_v4()));
+        let dir = dir.to_str().unwrap();
+        std::fs::create_dir_all(format!("{}/upload", dir)).unwrap();
+        let storage = LocalStorage::new(dir, "https://example.com/upload/");
+
+        let temp_path = format!("{}/upload/a.part", dir);
+        std::fs::write(&temp_path, b"hello").unwrap();
+        storage.put("ab12.txt", &temp_path).await.unwrap();
+        assert!(!std::path::Path::new(&temp_path).exists());
+        assert_eq!(storage.get("ab12.txt").await.unwrap(), b"hello");
+        // Keyed by full path before.
+        let legacy_key = format!("{}/upload/ab12.txt", dir);
+        assert_eq!(storage.get(&legacy_key).await.unwrap(), b"hello");
+        assert_eq!(storage.url(&legacy_key), "https://example.com/upload/ab12.txt");
+
+        assert_eq!(storage.size("ab12.txt").await.unwrap(), 5);
+        let local = &storage;
+        let read = |start, len| async move {
+            let chunks: Vec<_> = local
+                .read_range("ab12.txt", start, len)
+                .await
+                .unwrap()
+                .collect()
+                .await;
+            chunks
+                .into_iter()
+                .map(|x| x.unwrap())
+                .collect::<Vec<_>>()
+                .concat()
+        };
+        assert_eq!(read(1, 3).await, b"ell");
+        assert_eq!(read(0, 5).await, b"hello");
+        assert_eq!(read(0, 0).await, b"");
+        // Stop at the end of the file.
+        assert_eq!(read(3, 10).await, b"lo");
+
+        storage.delete("ab12.txt").await.unwrap();
+        assert!(storage.get("ab12.txt").await.is_err());
+        assert!(storage.size("ab12.txt").await.is_err());
+        // Deleted already.
+        storage.delete("ab12.txt").await.unwrap();
+        // Keys never escape the directory.
+        for key in &["..", "ab12/..", ".", ""] {
+            let e = storage.get(key).await.unwrap_err();
+            assert_eq!(e, ApiError::new(AttachmentError::FilenameRefused));
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
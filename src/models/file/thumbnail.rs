@@ -0,0 +1,350 @@
+//! Thumbnails of image attachments, so that list views don't download images in full resolution.
+//! A thumbnail is generated in background after the image is stored, and stored alongside it.
+//! Only 8-bit non-interlaced PNG is decoded now, and other images have no thumbnail.
+
+use super::Storage;
+use crate::error::Result;
+use actix_web::web;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use log::warn;
+use std::convert::TryInto;
+use std::io::{Read, Write};
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+/// PNG color type of 8-bit grayscale, with 1 byte per pixel.
+pub const PNG_GRAYSCALE: u8 = 0;
+/// PNG color type of 8-bit RGBA, with 4 bytes per pixel.
+pub const PNG_RGBA: u8 = 6;
+
+/// Max pixels of an image to decode, against decompression bombs. It's checked against the header
+/// before the image data is inflated, and an image of the max size takes 16 MB in RGBA.
+const MAX_PIXELS: usize = 4_000_000;
+
+/// File extensions of images which thumbnails are generated for.
+static THUMBNAIL_EXT: &[&str] = &["png"];
+
+/// Decoded image in 8-bit RGBA.
+struct Image {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+/// Storage key of the thumbnail of the file.
+pub fn thumbnail_key(key: &str) -> String {
+    let stem = match key.rfind('.') {
+        Some(i) if !key[i..].contains('/') => &key[..i],
+        _ => key,
+    };
+    format!("{}.thumb.png", stem)
+}
+
+pub fn has_thumbnail(ext: &str) -> bool {
+    THUMBNAIL_EXT.contains(&ext)
+}
+
+fn be_u32(bytes: &[u8]) -> Option<u32> {
+    Some(u32::from_be_bytes(bytes.get(..4)?.try_into().ok()?))
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = ((p - a as i16).abs(), (p - b as i16).abs(), (p - c as i16).abs());
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+fn decode_png(content: &[u8]) -> Option<Image> {
+    if !content.starts_with(PNG_SIGNATURE) {
+        return None;
+    }
+    // The header is the first chunk. Images too large are refused before reading further.
+    let rest = &content[PNG_SIGNATURE.len()..];
+    if rest.get(4..8)? != b"IHDR" || be_u32(rest)? != 13 {
+        return None;
+    }
+    let header = rest.get(8..21)?;
+    let (width, height) = (be_u32(header)? as usize, be_u32(&header[4..])? as usize);
+    let (bit_depth, color_type, interlace) = (header[8], header[9], header[12]);
+    let too_large = width.checked_mul(height).map(|x| x > MAX_PIXELS) != Some(false);
+    if bit_depth != 8 || interlace != 0 || width == 0 || height == 0 || too_large {
+        return None;
+    }
+
+    let mut palette: &[u8] = &[];
+    let mut transparency: &[u8] = &[];
+    let mut data = Vec::new();
+    let mut rest = rest.get(25..)?;
+    while rest.len() >= 12 {
+        let len = be_u32(rest)? as usize;
+        let body = rest.get(8..8 + len)?;
+        match &rest[4..8] {
+            b"PLTE" => palette = body,
+            b"tRNS" => transparency = body,
+            b"IDAT" => data.extend_from_slice(body),
+            b"IEND" => break,
+            _ => (),
+        }
+        // Skip the chunk with its CRC.
+        rest = rest.get(12 + len..)?;
+    }
+
+    let channels = match color_type {
+        0 | 3 => 1,
+        2 => 3,
+        4 => 2,
+        6 => 4,
+        _ => return None,
+    };
+
+    let stride = width * channels;
+    let mut raw = Vec::new();
+    ZlibDecoder::new(&data[..])
+        .take(((stride + 1) * height) as u64)
+        .read_to_end(&mut raw)
+        .ok()?;
+    if raw.len() != (stride + 1) * height {
+        return None;
+    }
+
+    // Reverse the filter of each line.
+    let mut samples = vec![0u8; stride * height];
+    for (y, line) in raw.chunks(stride + 1).enumerate() {
+        let (done, current) = samples.split_at_mut(y * stride);
+        let prior = if y > 0 { &done[(y - 1) * stride..] } else { &[][..] };
+        let current = &mut current[..stride];
+
+        for x in 0..stride {
+            let a = if x >= channels { current[x - channels] } else { 0 };
+            let b = prior.get(x).copied().unwrap_or(0);
+            let c = if x >= channels {
+                prior.get(x - channels).copied().unwrap_or(0)
+            } else {
+                0
+            };
+            let predictor = match line[0] {
+                0 => 0,
+                1 => a,
+                2 => b,
+                3 => ((a as u16 + b as u16) / 2) as u8,
+                4 => paeth(a, b, c),
+                _ => return None,
+            };
+            current[x] = line[1 + x].wrapping_add(predictor);
+        }
+    }
+
+    let mut pixels = Vec::with_capacity(width * height * 4);
+    for p in samples.chunks(channels) {
+        let rgba = match color_type {
+            0 => [p[0], p[0], p[0], 255],
+            2 => [p[0], p[1], p[2], 255],
+            3 => {
+                let i = p[0] as usize;
+                let rgb = palette.get(i * 3..i * 3 + 3)?;
+                [
+                    rgb[0],
+                    rgb[1],
+                    rgb[2],
+                    transparency.get(i).copied().unwrap_or(255),
+                ]
+            }
+            4 => [p[0], p[0], p[0], p[1]],
+            _ => [p[0], p[1], p[2], p[3]],
+        };
+        pixels.extend_from_slice(&rgba);
+    }
+    Some(Image {
+        width,
+        height,
+        pixels,
+    })
+}
+
+fn write_chunk(out: &mut Vec<u8>, kind: &[u8], body: &[u8]) {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(kind);
+    hasher.update(body);
+
+    out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(body);
+    out.extend_from_slice(&hasher.finalize().to_be_bytes());
+}
+
+/// Encode 8-bit pixels of the color type, `PNG_GRAYSCALE` or `PNG_RGBA`, row by row.
+pub fn encode_png(width: usize, height: usize, color_type: u8, pixels: &[u8]) -> Vec<u8> {
+    let channels = if color_type == PNG_RGBA { 4 } else { 1 };
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    // Writing to a vector never fails. Each line starts with filter type 0.
+    for line in pixels.chunks(width * channels) {
+        encoder.write_all(&[0]).unwrap();
+        encoder.write_all(line).unwrap();
+    }
+    let data = encoder.finish().unwrap();
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // 8-bit, non-interlaced.
+    header.extend_from_slice(&[8, color_type, 0, 0, 0]);
+
+    let mut out = PNG_SIGNATURE.to_vec();
+    write_chunk(&mut out, b"IHDR", &header);
+    write_chunk(&mut out, b"IDAT", &data);
+    write_chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+/// Scale the image down to fit in `max_size`, averaging source pixels in each target pixel.
+fn resize(image: &Image, max_size: usize) -> Image {
+    let longer = image.width.max(image.height);
+    if longer <= max_size {
+        return Image {
+            pixels: image.pixels.clone(),
+            ..*image
+        };
+    }
+    let width = (image.width * max_size / longer).max(1);
+    let height = (image.height * max_size / longer).max(1);
+
+    let mut pixels = Vec::with_capacity(width * height * 4);
+    for y in 0..height {
+        let (top, bottom) = (y * image.height / height, (y + 1) * image.height / height);
+        for x in 0..width {
+            let (left, right) = (x * image.width / width, (x + 1) * image.width / width);
+            let mut sum = [0u64; 4];
+            for sy in top..bottom {
+                let row = &image.pixels[(sy * image.width + left) * 4..(sy * image.width + right) * 4];
+                for p in row.chunks(4) {
+                    for (s, v) in sum.iter_mut().zip(p) {
+                        *s += *v as u64;
+                    }
+                }
+            }
+            let count = ((bottom - top) * (right - left)) as u64;
+            pixels.extend(sum.iter().map(|s| (s / count) as u8));
+        }
+    }
+    Image {
+        width,
+        height,
+        pixels,
+    }
+}
+
+/// Generate a thumbnail in PNG, or `None` if the content isn't a supported image.
+pub fn generate(content: &[u8], max_size: u32) -> Option<Vec<u8>> {
+    let image = decode_png(content)?;
+    let image = resize(&image, max_size as usize);
+    Some(encode_png(image.width, image.height, PNG_RGBA, &image.pixels))
+}
+
+async fn store_thumbnail(storage: &dyn Storage, dir: &str, key: &str, max_size: u32) -> Result<()> {
+    let content = storage.get(key).await?;
+    // Decoding takes a while, so it's done out of the event loop.
+    let thumbnail = match web::block(move || generate(&content, max_size).ok_or(())).await {
+        Ok(thumbnail) => thumbnail,
+        Err(_) => return Ok(()),
+    };
+    let temp_path = format!("{}/upload/{}.part", dir, uuid::Uuid::new_v4());
+    tokio::fs::write(&temp_path, thumbnail).await?;
+
+    storage.put(&thumbnail_key(key), &temp_path).await
+}
+
+/// Generate and store the thumbnail of the stored image in background.
+pub fn spawn_thumbnail(storage: &'static dyn Storage, dir: String, key: String, max_size: u32) {
+    actix_web::rt::spawn(async move {
+        if let Err(e) = store_thumbnail(storage, &dir, &key, max_size).await {
+            warn!("Failed to generate thumbnail of {}: {}", key, e);
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Encode an 8-bit image with raw lines, each prefixed with its filter type.
+    fn png(width: u32, height: u32, color_type: u8, lines: &[u8], palette: &[u8]) -> Vec<u8> {
+        let mut header = width.to_be_bytes().to_vec();
+        header.extend_from_slice(&height.to_be_bytes());
+        header.extend_from_slice(&[8, color_type, 0, 0, 0]);
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(lines).unwrap();
+
+        let mut out = PNG_SIGNATURE.to_vec();
+        write_chunk(&mut out, b"IHDR", &header);
+        if !palette.is_empty() {
+            write_chunk(&mut out, b"PLTE", palette);
+        }
+        write_chunk(&mut out, b"IDAT", &encoder.finish().unwrap());
+        write_chunk(&mut out, b"IEND", &[]);
+        out
+    }
+
+    #[test]
+    pub fn test_thumbnail_key() {
+        assert_eq!(thumbnail_key("ab12.png"), "ab12.thumb.png");
+        assert_eq!(thumbnail_key("ab12"), "ab12.thumb.png");
+        // Attachments keyed by full path before.
+        assert_eq!(
+            thumbnail_key("/srv/v1.0/upload/ab12.png"),
+            "/srv/v1.0/upload/ab12.thumb.png"
+        );
+        assert_eq!(
+            thumbnail_key("/srv/v1.0/upload/ab12"),
+            "/srv/v1.0/upload/ab12.thumb.png"
+        );
+    }
+
+    #[test]
+    pub fn test_decode_png() {
+        // Gray 3x3 with Sub, Up and Paeth filters.
+        let lines = [1, 10, 5, 5, 2, 1, 1, 1, 4, 0, 0, 0];
+        let image = decode_png(&png(3, 3, 0, &lines, &[])).unwrap();
+        let gray: Vec<u8> = image.pixels.chunks(4).map(|p| p[0]).collect();
+        assert_eq!(gray, [10, 15, 20, 11, 16, 21, 11, 16, 21]);
+        assert!(image.pixels.chunks(4).all(|p| p[3] == 255));
+
+        // Palette 2x1.
+        let image = decode_png(&png(2, 1, 3, &[0, 1, 0], &[0, 0, 0, 255, 0, 0])).unwrap();
+        assert_eq!(image.pixels, [255, 0, 0, 255, 0, 0, 0, 255]);
+
+        assert!(decode_png(b"%PDF-1.4").is_none());
+        // Truncated.
+        let content = png(3, 3, 0, &lines, &[]);
+        assert!(decode_png(&content[..content.len() - 20]).is_none());
+        // Too large by the header, refused without inflating the data.
+        assert!(decode_png(&png(3000, 2000, 6, &[], &[])).is_none());
+        assert!(decode_png(&png(u32::MAX, u32::MAX, 0, &[], &[])).is_none());
+    }
+
+    #[test]
+    pub fn test_generate() {
+        // RGBA 4x2, left half black and right half white.
+        let mut lines = Vec::new();
+        for _ in 0..2 {
+            lines.push(0);
+            lines.extend_from_slice(&[0, 0, 0, 255, 0, 0, 0, 255]);
+            lines.extend_from_slice(&[255, 255, 255, 255, 255, 255, 255, 255]);
+        }
+        let thumbnail = generate(&png(4, 2, 6, &lines, &[]), 2).unwrap();
+        let image = decode_png(&thumbnail).unwrap();
+        assert_eq!((image.width, image.height), (2, 1));
+        assert_eq!(image.pixels, [0, 0, 0, 255, 255, 255, 255, 255]);
+
+        // Small images are kept in size.
+        let image = decode_png(&generate(&thumbnail, 256).unwrap()).unwrap();
+        assert_eq!((image.width, image.height), (2, 1));
+        assert!(generate(b"GIF89a", 256).is_none());
+    }
+}
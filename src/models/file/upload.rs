@@ -0,0 +1,205 @@
+//! Chunked uploads, so that a large file can be uploaded over a flaky network and resumed from the
+//! last received chunk. Chunks are appended to a part file in order, and the progress is kept in
+//! database.
+
+use super::AttachmentError;
+use crate::error::{ApiError, Result};
+use chrono::{DateTime, Local};
+use log::{error, info};
+use serde::Serialize;
+use sqlx::{Done, PgPool};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Interval to clean up expired uploads.
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// An upload in progress.
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct UploadSession {
+    #[serde(rename = "uploadId")]
+    pub id: Uuid,
+    #[serde(skip_serializing)]
+    pub uploader: i32,
+    /// File name.
+    pub name: String,
+    /// Declared file size.
+    pub size: i64,
+    /// Declared SHA-256 in hex.
+    #[serde(skip_serializing)]
+    pub hash: String,
+    /// Bytes received.
+    pub received: i64,
+    /// Index of the chunk expected next.
+    #[serde(rename = "nextChunk")]
+    pub next_chunk: i32,
+    #[serde(rename = "createTime")]
+    pub create_time: DateTime<Local>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ChunkAction {
+    /// The expected chunk.
+    Append,
+    /// The last chunk sent again, for the client missed the response.
+    Duplicate,
+}
+
+impl UploadSession {
+    /// Decide what to do with the n-th chunk.
+    pub fn check_chunk(&self, n: i32) -> std::result::Result<ChunkAction, AttachmentError> {
+        if n == self.next_chunk {
+            Ok(ChunkAction::Append)
+        } else if n + 1 == self.next_chunk {
+            Ok(ChunkAction::Duplicate)
+        } else {
+            Err(AttachmentError::ChunkOutOfOrder)
+        }
+    }
+}
+
+/// Path of the part file of an upload.
+pub fn part_path(dir: &str, id: &Uuid) -> String {
+    format!("{}/upload/{}.part", dir, id)
+}
+
+/// Whether the string is a SHA-256 in lowercase hex.
+pub fn is_valid_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+pub struct UploadManager<'a> {
+    pool: &'a PgPool,
+}
+
+impl<'a> UploadManager<'a> {
+    pub fn new(pool: &'a PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn create(
+        &self,
+        uploader: i32,
+        name: &str,
+        size: i64,
+        hash: &str,
+    ) -> Result<UploadSession> {
+        let session = sqlx::query_as(
+            "INSERT INTO public.attachment_uploads (id, uploader, name, size, hash) VALUES ($1, $2, $3, $4, $5)
+                RETURNING id, uploader, name, size, hash, received, next_chunk, create_time",
+        )
+        .bind(Uuid::new_v4())
+        .bind(uploader)
+        .bind(name)
+        .bind(size)
+        .bind(hash)
+        .fetch_one(self.pool)
+        .await?;
+        Ok(session)
+    }
+
+    /// Get an upload of the uploader.
+    pub async fn get(&self, id: Uuid, uploader: i32) -> Result<UploadSession> {
+        let session: Option<UploadSession> = sqlx::query_as(
+            "SELECT id, uploader, name, size, hash, received, next_chunk, create_time
+                FROM public.attachment_uploads WHERE id = $1 AND uploader = $2",
+        )
+        .bind(id)
+        .bind(uploader)
+        .fetch_optional(self.pool)
+        .await?;
+
+        session.ok_or(ApiError::new(AttachmentError::NotFound))
+    }
+
+    /// Record the n-th chunk received, with `received` bytes in total. Fails if another request
+    /// has recorded it.
+    pub async fn advance(&self, id: Uuid, n: i32, received: i64) -> Result<()> {
+        let result = sqlx::query(
+            "UPDATE public.attachment_uploads SET received = $3, next_chunk = $2 + 1
+                WHERE id = $1 AND next_chunk = $2",
+        )
+        .bind(id)
+        .bind(n)
+        .bind(received)
+        .execute(self.pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(ApiError::new(AttachmentError::ChunkOutOfOrder));
+        }
+        Ok(())
+    }
+
+    pub async fn remove(&self, id: Uuid) -> Result<()> {
+        sqlx::query("DELETE FROM public.attachment_uploads WHERE id = $1")
+            .bind(id)
+            .execute(self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Remove uploads created before `ttl`, and return their ids.
+    pub async fn expire(&self, ttl: Duration) -> Result<Vec<Uuid>> {
+        let ids: Vec<(Uuid,)> = sqlx::query_as(
+            "DELETE FROM public.attachment_uploads WHERE create_time < now() - make_interval(secs => $1)
+                RETURNING id",
+        )
+        .bind(ttl.as_secs_f64())
+        .fetch_all(self.pool)
+        .await?;
+
+        Ok(ids.into_iter().map(|(id,)| id).collect())
+    }
+}
+
+/// Remove incomplete uploads and their part files after `ttl`, until the process exits.
+pub async fn expire_uploads(pool: PgPool, dir: String, ttl: Duration) {
+    loop {
+        tokio::time::delay_for(EXPIRE_INTERVAL).await;
+
+        match UploadManager::new(&pool).expire(ttl).await {
+            Ok(ids) => {
+                for id in &ids {
+                    let _ = tokio::fs::remove_file(part_path(&dir, id)).await;
+                }
+                if !ids.is_empty() {
+                    info!("Removed {} expired uploads.", ids.len());
+                }
+            }
+            Err(e) => error!("Failed to remove expired uploads: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_check_chunk() {
+        let session = UploadSession {
+            id: Uuid::new_v4(),
+            uploader: 1,
+            name: "a.pdf".to_string(),
+            size: 1024,
+            hash: String::new(),
+            received: 512,
+            next_chunk: 2,
+            create_time: Local::now(),
+        };
+
+        assert_eq!(session.check_chunk(2), Ok(ChunkAction::Append));
+        // Resent after the response lost.
+        assert_eq!(session.check_chunk(1), Ok(ChunkAction::Duplicate));
+        assert!(session.check_chunk(0).is_err());
+        assert!(session.check_chunk(3).is_err());
+    }
+
+    #[test]
+    pub fn test_valid_hash() {
+        assert!(is_valid_hash(&"0a".repeat(32)));
+        assert!(!is_valid_hash(&"0A".repeat(32)));
+        assert!(!is_valid_hash("0a"));
+    }
+}
@@ -6,10 +6,12 @@
 //! Usually, secret is the six right characters of their id card number.
 
 mod familiar;
+pub mod import;
 mod myself;
 
 use chrono::NaiveDateTime;
 use serde::Serialize;
+use serde_json::Value;
 
 pub use familiar::*;
 pub use myself::*;
@@ -24,6 +26,8 @@ pub enum FreshmanError {
     BoundAlready = 122,
     #[error("需要凭据")]
     SecretNeeded = 123,
+    #[error("导入文件缺少必需的列")]
+    MissingColumns = 300,
 }
 
 /// FreshmanBasic
@@ -90,11 +94,25 @@ pub struct NewMate {
     pub avatar: Option<String>,
     /// Contact detail like wechat, qq, telephone...
     pub contact: Option<serde_json::Value>,
+    #[serde(skip_serializing)]
+    pub visible_phone: bool,
+    #[serde(skip_serializing)]
+    pub visible_contact: bool,
+}
+
+/// Conditions to narrow classmates down. Empty values are no condition.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MateFilter {
+    pub college: Option<String>,
+    pub major: Option<String>,
 }
 
 /// Information about people you might know
 #[derive(sqlx::FromRow, Serialize)]
 pub struct PeopleFamiliar {
+    /// Used to order people of the same score.
+    #[serde(skip_serializing)]
+    pub student_id: String,
     /// Name of the people may recognize.
     pub name: String,
     /// College
@@ -110,6 +128,72 @@ pub struct PeopleFamiliar {
     pub avatar: Option<String>,
     /// Contact details.
     pub contact: Option<serde_json::Value>,
+    #[serde(skip_serializing)]
+    pub visible_phone: bool,
+    #[serde(skip_serializing)]
+    pub visible_contact: bool,
+    /* Attributes shared with the freshman querying, which are scored by `CONFIG.familiar`. Not
+    queried in search. */
+    #[serde(skip_serializing)]
+    #[sqlx(default)]
+    pub same_province: bool,
+    #[serde(skip_serializing)]
+    #[sqlx(default)]
+    pub same_city: bool,
+    #[serde(skip_serializing)]
+    #[sqlx(default)]
+    pub same_high_school: bool,
+    #[serde(skip_serializing)]
+    #[sqlx(default)]
+    pub same_college: bool,
+    #[serde(skip_serializing)]
+    #[sqlx(default)]
+    pub same_class: bool,
+    /// How likely they know each other, higher first.
+    #[sqlx(default)]
+    pub score: i32,
+}
+
+/// Which details of a freshman are shown to others. By default, all details are shown.
+#[derive(Debug, Clone, Copy, PartialEq, sqlx::FromRow, Serialize)]
+pub struct Privacy {
+    /// Show telephone in contact details.
+    #[serde(rename = "visiblePhone")]
+    pub visible_phone: bool,
+    /// Show other contact details, like wechat and qq.
+    #[serde(rename = "visibleContact")]
+    pub visible_contact: bool,
+    /// Not shown in roommates, classmates, people familiar and search results at all.
+    #[serde(rename = "hideMe")]
+    pub hide_me: bool,
+}
+
+/// Key of telephone in contact details.
+const PHONE_KEY: &str = "tel";
+
+/// Remove contact details hidden by the freshman.
+pub fn redact_contact(
+    contact: Option<Value>,
+    visible_phone: bool,
+    visible_contact: bool,
+) -> Option<Value> {
+    match contact {
+        Some(Value::Object(details)) => {
+            let details = details
+                .into_iter()
+                .filter(|(key, _)| {
+                    if key == PHONE_KEY {
+                        visible_phone
+                    } else {
+                        visible_contact
+                    }
+                })
+                .collect();
+            Some(Value::Object(details))
+        }
+        // Contact details in other forms can't be told apart.
+        other => other.filter(|_| visible_phone && visible_contact),
+    }
 }
 
 #[derive(Serialize)]
@@ -136,6 +220,10 @@ trait MapDefaultAvatar {
     fn map_default_avatar(self) -> Self;
 }
 
+trait RedactContact {
+    fn redact_contact(self) -> Self;
+}
+
 use super::user::get_default_avatar;
 
 macro_rules! impl_default_avatar {
@@ -153,5 +241,45 @@ macro_rules! impl_default_avatar {
     };
 }
 
+macro_rules! impl_redact_contact {
+    ($structure: ident) => {
+        impl RedactContact for Vec<$structure> {
+            fn redact_contact(self) -> Vec<$structure> {
+                self.into_iter()
+                    .map(|mut x| {
+                        x.contact = redact_contact(x.contact, x.visible_phone, x.visible_contact);
+                        x
+                    })
+                    .collect()
+            }
+        }
+    };
+}
+
 impl_default_avatar!(NewMate);
 impl_default_avatar!(PeopleFamiliar);
+impl_redact_contact!(NewMate);
+impl_redact_contact!(PeopleFamiliar);
+
+#[cfg(test)]
+mod test {
+    use super::redact_contact;
+    use serde_json::json;
+
+    #[test]
+    pub fn test_redact_contact() {
+        let contact = || Some(json!({"tel": "13800000000", "qq": "10000", "wechat": "kite"}));
+
+        assert_eq!(redact_contact(contact(), true, true), contact());
+        assert_eq!(
+            redact_contact(contact(), false, true),
+            Some(json!({"qq": "10000", "wechat": "kite"}))
+        );
+        assert_eq!(
+            redact_contact(contact(), true, false),
+            Some(json!({"tel": "13800000000"}))
+        );
+        assert_eq!(redact_contact(Some(json!("13800000000")), false, true), None);
+        assert_eq!(redact_contact(None, true, true), None);
+    }
+}
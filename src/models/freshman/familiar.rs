@@ -1,15 +1,101 @@
-use super::{FreshmanBasic, NewMate, PeopleFamiliar};
+use super::{FreshmanBasic, MateFilter, NewMate, PeopleFamiliar};
+use crate::config::{FamiliarWeights, CONFIG};
 use crate::error::Result;
-use crate::models::freshman::{FreshmanAnalysis, MapDefaultAvatar};
+use crate::models::freshman::{FreshmanAnalysis, MapDefaultAvatar, RedactContact};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
 use sqlx::PgPool;
 
+/// Max people familiar returned.
+const MAX_FAMILIAR_COUNT: usize = 20;
+
+impl MateFilter {
+    /// Drop unknown colleges or majors, which no freshman is in, so that a stale or mistyped value
+    /// doesn't empty the list.
+    async fn known_only(self, client: &PgPool) -> Result<Self> {
+        async fn exists(client: &PgPool, column: &str, value: Option<String>) -> Result<Option<String>> {
+            let value = match value.as_deref().map(str::trim) {
+                Some(x) if !x.is_empty() => x.to_string(),
+                _ => return Ok(None),
+            };
+            let sql = format!(
+                "SELECT EXISTS(SELECT 1 FROM freshman.students WHERE {} = $1)",
+                column
+            );
+            let (exists,): (bool,) = sqlx::query_as(&sql).bind(&value).fetch_one(client).await?;
+            Ok(if exists { Some(value) } else { None })
+        }
+
+        Ok(Self {
+            college: exists(client, "college", self.college).await?,
+            major: exists(client, "major", self.major).await?,
+        })
+    }
+
+    fn apply(&self, mates: Vec<NewMate>) -> Vec<NewMate> {
+        let matches =
+            |condition: &Option<String>, value: &str| condition.as_deref().is_none_or(|x| x == value);
+
+        mates
+            .into_iter()
+            .filter(|x| matches(&self.college, &x.college) && matches(&self.major, &x.major))
+            .collect()
+    }
+}
+
+impl PeopleFamiliar {
+    /// Sum of weights of attributes shared.
+    fn score_by(&self, weights: &FamiliarWeights) -> i32 {
+        let shared = [
+            (self.same_province, weights.province),
+            (self.same_city, weights.city),
+            (self.same_high_school, weights.high_school),
+            (self.same_college, weights.college),
+            (self.same_class, weights.class),
+        ];
+        shared.iter().filter(|(x, _)| *x).map(|(_, weight)| weight).sum()
+    }
+}
+
+/// Score people and sort them, the highest score first and then by student id, and keep at most
+/// `limit` ones.
+fn rank(
+    mut people: Vec<PeopleFamiliar>,
+    weights: &FamiliarWeights,
+    limit: usize,
+) -> Vec<PeopleFamiliar> {
+    for person in people.iter_mut() {
+        person.score = person.score_by(weights);
+    }
+    people.sort_by(|a, b| {
+        b.score
+            .cmp(&a.score)
+            .then_with(|| a.student_id.cmp(&b.student_id))
+    });
+    people.truncate(limit);
+    people
+}
+
+/// Pick at most `limit` items at random. The same seed picks the same items in the same order, so
+/// that clients can keep a preview stable, and reshuffle it with another seed.
+pub fn sample<T>(mut items: Vec<T>, seed: u64, limit: usize) -> Vec<T> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let limit = limit.min(items.len());
+
+    items.partial_shuffle(&mut rng, limit);
+    items.truncate(limit);
+    items
+}
+
 impl FreshmanBasic {
     /* Classmates, roommates, and familiar people */
 
-    /// Get classmate.
-    pub async fn get_classmates(&self, client: &PgPool) -> Result<Vec<NewMate>> {
+    /// Get classmates, in the college and major of the filter if given.
+    pub async fn get_classmates(&self, client: &PgPool, filter: MateFilter) -> Result<Vec<NewMate>> {
         let classmates: Vec<NewMate> = sqlx::query_as(
-            "SELECT college, major, name, stu.province, building, room, bed, stu.gender, last_seen, avatar, contact
+            "SELECT college, major, name, stu.province, building, room, bed, stu.gender, last_seen, avatar, contact,
+                visible_phone, visible_contact
             FROM freshman.students AS stu
             LEFT JOIN public.person AS person
             ON stu.uid = person.uid
@@ -19,18 +105,27 @@ impl FreshmanBasic {
             ON
                 stu.class = self.class
                 AND stu.student_id <> $1
+                AND stu.hide_me = false
             ORDER BY stu.student_id",
         )
         .bind(&self.student_id)
         .fetch_all(client)
         .await?;
 
-        Ok(classmates.map_default_avatar())
+        let classmates = match filter {
+            MateFilter {
+                college: None,
+                major: None,
+            } => classmates,
+            filter => filter.known_only(client).await?.apply(classmates),
+        };
+        Ok(classmates.map_default_avatar().redact_contact())
     }
 
     pub async fn get_roommates(&self, client: &PgPool) -> Result<Vec<NewMate>> {
         let roommates: Vec<NewMate> = sqlx::query_as(
-            "SELECT college, major, name, stu.province, stu.building, stu.room, bed, stu.gender, last_seen, avatar, contact
+            "SELECT college, major, name, stu.province, stu.building, stu.room, bed, stu.gender, last_seen, avatar, contact,
+                visible_phone, visible_contact
             FROM freshman.students AS stu
             LEFT JOIN public.person AS person
             ON stu.uid = person.uid
@@ -40,40 +135,73 @@ impl FreshmanBasic {
             ON
                 stu.room = self.room
                 AND stu.building = self.building
-                AND stu.student_id <> $1",
+                AND stu.student_id <> $1
+                AND stu.hide_me = false",
         )
         .bind(&self.student_id)
         .fetch_all(client)
         .await?;
 
-        Ok(roommates.map_default_avatar())
+        Ok(roommates.map_default_avatar().redact_contact())
     }
 
+    /// Get people of the same high school or from nearby, the most likely familiar first.
     pub async fn get_people_familiar(&self, client: &PgPool) -> Result<Vec<PeopleFamiliar>> {
+        self.rank_people_familiar(client, &CONFIG.familiar, MAX_FAMILIAR_COUNT)
+            .await
+    }
+
+    /// Get at most `limit` people familiar with the highest scores. They're scored and limited in
+    /// the query as `rank` does, so that only the ones returned are loaded.
+    async fn rank_people_familiar(
+        &self,
+        client: &PgPool,
+        weights: &FamiliarWeights,
+        limit: usize,
+    ) -> Result<Vec<PeopleFamiliar>> {
         let people_familiar: Vec<PeopleFamiliar> = sqlx::query_as(
-            "WITH origin AS (
-            SELECT DISTINCT ON(student_id) name, college, stu.city, stu.gender, last_seen, avatar, contact
-            FROM freshman.students AS stu
-            LEFT JOIN public.person AS person
-            ON stu.uid = person.uid
-            INNER JOIN (
-                    SELECT graduated_from, city, postcode FROM freshman.students WHERE student_id = $1 LIMIT 1
-                ) self
-            ON
-                ((stu.graduated_from = self.graduated_from)
-                OR stu.city = self.city
-                OR stu.postcode / 1000 = self.postcode / 1000)
-                AND stu.visible = true
-                AND stu.student_id <> $1
-            LIMIT 20)
-            SELECT * FROM origin 
-            ORDER BY random();",
+            "SELECT * FROM (
+                SELECT DISTINCT ON (stu.student_id)
+                    stu.student_id, name, stu.college, stu.city, stu.gender, last_seen, avatar, contact,
+                    visible_phone, visible_contact,
+                    COALESCE(stu.province = self.province, false) AS same_province,
+                    COALESCE(stu.city = self.city, false) AS same_city,
+                    COALESCE(stu.graduated_from = self.graduated_from, false) AS same_high_school,
+                    COALESCE(stu.college = self.college, false) AS same_college,
+                    COALESCE(stu.class = self.class, false) AS same_class
+                FROM freshman.students AS stu
+                LEFT JOIN public.person AS person
+                ON stu.uid = person.uid
+                INNER JOIN (
+                        SELECT graduated_from, province, city, postcode, college, class
+                        FROM freshman.students WHERE student_id = $1 LIMIT 1
+                    ) self
+                ON
+                    ((stu.graduated_from = self.graduated_from)
+                    OR stu.city = self.city
+                    OR stu.postcode / 1000 = self.postcode / 1000)
+                    AND stu.visible = true
+                    AND stu.hide_me = false
+                    AND stu.student_id <> $1
+            ) people
+            ORDER BY
+                same_province::int * $2 + same_city::int * $3 + same_high_school::int * $4
+                    + same_college::int * $5 + same_class::int * $6 DESC,
+                student_id
+            LIMIT $7",
         )
-            .bind(&self.student_id)
-            .fetch_all(client)
-            .await?;
+        .bind(&self.student_id)
+        .bind(weights.province)
+        .bind(weights.city)
+        .bind(weights.high_school)
+        .bind(weights.college)
+        .bind(weights.class)
+        .bind(limit as i64)
+        .fetch_all(client)
+        .await?;
 
-        Ok(people_familiar.map_default_avatar())
+        let people_familiar = rank(people_familiar, weights, limit);
+        Ok(people_familiar.map_default_avatar().redact_contact())
     }
 
     /* Get statistics */
@@ -166,3 +294,281 @@ impl FreshmanBasic {
         Ok(result.0)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::freshman::FreshmanManager;
+    use crate::models::test::db_pool;
+    use serde_json::json;
+
+    fn person(student_id: &str, shared: [bool; 5]) -> PeopleFamiliar {
+        PeopleFamiliar {
+            student_id: student_id.to_string(),
+            name: student_id.to_string(),
+            college: String::new(),
+            city: None,
+            gender: "M".to_string(),
+            last_seen: None,
+            avatar: None,
+            contact: None,
+            visible_phone: true,
+            visible_contact: true,
+            same_province: shared[0],
+            same_city: shared[1],
+            same_high_school: shared[2],
+            same_college: shared[3],
+            same_class: shared[4],
+            score: 0,
+        }
+    }
+
+    fn mate(name: &str, college: &str, major: &str) -> NewMate {
+        NewMate {
+            college: college.to_string(),
+            major: major.to_string(),
+            name: name.to_string(),
+            province: None,
+            building: String::new(),
+            room: 0,
+            bed: String::new(),
+            gender: "M".to_string(),
+            last_seen: None,
+            avatar: None,
+            contact: None,
+            visible_phone: true,
+            visible_contact: true,
+        }
+    }
+
+    #[test]
+    pub fn test_filter_mates() {
+        let mates = || {
+            vec![
+                mate("甲", "计算机学院", "软件工程"),
+                mate("乙", "计算机学院", "网络工程"),
+                mate("丙", "材料学院", "软件工程"),
+            ]
+        };
+        let names = |mates: Vec<NewMate>| mates.into_iter().map(|x| x.name).collect::<Vec<_>>();
+
+        assert_eq!(names(MateFilter::default().apply(mates())), ["甲", "乙", "丙"]);
+        let filter = MateFilter {
+            college: Some("计算机学院".to_string()),
+            major: None,
+        };
+        assert_eq!(names(filter.apply(mates())), ["甲", "乙"]);
+        let filter = MateFilter {
+            college: Some("计算机学院".to_string()),
+            major: Some("软件工程".to_string()),
+        };
+        assert_eq!(names(filter.apply(mates())), ["甲"]);
+    }
+
+    fn ids(people: &[PeopleFamiliar]) -> Vec<&str> {
+        people.iter().map(|x| x.student_id.as_str()).collect()
+    }
+
+    #[test]
+    pub fn test_sample_by_seed() {
+        let items: Vec<i32> = (0..50).collect();
+
+        let first = sample(items.clone(), 42, 6);
+        assert_eq!(first.len(), 6);
+        assert_eq!(first, sample(items.clone(), 42, 6));
+        assert_ne!(first, sample(items.clone(), 43, 6));
+        // No duplicates.
+        let mut sorted = first.clone();
+        sorted.sort_unstable();
+        sorted.dedup();
+        assert_eq!(sorted.len(), 6);
+        // All of them if not enough.
+        assert_eq!(sample(vec![1, 2], 42, 6).len(), 2);
+    }
+
+    #[test]
+    pub fn test_rank_by_shared_attributes() {
+        let weights = FamiliarWeights::default();
+        let people = vec![
+            person("01", [true, false, false, false, false]),
+            person("02", [true, true, true, true, false]),
+            person("03", [true, true, false, false, false]),
+        ];
+
+        let ranked = rank(people, &weights, MAX_FAMILIAR_COUNT);
+        assert_eq!(ids(&ranked), ["02", "03", "01"]);
+        assert!(ranked.windows(2).all(|x| x[0].score > x[1].score));
+        assert_eq!(ranked[2].score, weights.province);
+    }
+
+    #[test]
+    pub fn test_rank_ties_and_weights() {
+        let people = || {
+            vec![
+                person("03", [false, false, true, false, false]),
+                person("01", [false, false, false, false, true]),
+                person("02", [false, false, true, false, false]),
+            ]
+        };
+
+        // Ties are broken by student id.
+        let weights = FamiliarWeights {
+            high_school: 1,
+            class: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            ids(&rank(people(), &weights, MAX_FAMILIAR_COUNT)),
+            ["01", "02", "03"]
+        );
+        let weights = FamiliarWeights {
+            high_school: 2,
+            class: 1,
+            ..Default::default()
+        };
+        assert_eq!(ids(&rank(people(), &weights, 2)), ["02", "03"]);
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    async fn test_people_familiar_limited() {
+        let pool = db_pool().await;
+        sqlx::query(
+            "INSERT INTO freshman.students (student_id, name, secret, city, college, visible)
+                VALUES ('TESTPF0000', '自己', '123456', '测试市', '测试学院', true)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        // The same city, and the last ones in the same college too.
+        for i in 1..=25 {
+            let college = if i > 22 { "测试学院" } else { "其他学院" };
+            sqlx::query(
+                "INSERT INTO freshman.students (student_id, name, secret, city, college, visible)
+                    VALUES ($1, '同乡', '123456', '测试市', $2, true)",
+            )
+            .bind(format!("TESTPF{:04}", i))
+            .bind(college)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        let freshman = FreshmanManager::new(&pool)
+            .query("TESTPF0000", "123456")
+            .await
+            .unwrap();
+        let weights = FamiliarWeights::default();
+        let people = freshman.rank_people_familiar(&pool, &weights, 20).await.unwrap();
+        assert_eq!(people.len(), 20);
+        let ids: Vec<_> = people.iter().map(|x| x.student_id.as_str()).collect();
+        assert_eq!(ids[..4], ["TESTPF0023", "TESTPF0024", "TESTPF0025", "TESTPF0001"]);
+        assert_eq!(people[0].score, weights.city + weights.college);
+
+        sqlx::query("DELETE FROM freshman.students WHERE student_id LIKE 'TESTPF%'")
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    async fn test_roommates_privacy() {
+        let pool = db_pool().await;
+        let students = [
+            ("TEST000021", "自己", true, false),
+            ("TEST000022", "隐藏电话", false, false),
+            ("TEST000023", "隐藏自己", true, true),
+        ];
+        for (student_id, name, visible_phone, hide_me) in students.iter() {
+            sqlx::query(
+                "INSERT INTO freshman.students (student_id, name, secret, building, room, contact, visible_phone, hide_me)
+                    VALUES ($1, $2, '123456', '测试楼', 101, $3, $4, $5)",
+            )
+            .bind(student_id)
+            .bind(name)
+            .bind(json!({"tel": "13800000000", "qq": "10000"}))
+            .bind(visible_phone)
+            .bind(hide_me)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        let freshman = FreshmanManager::new(&pool)
+            .query("TEST000021", "123456")
+            .await
+            .unwrap();
+        let roommates = freshman.get_roommates(&pool).await.unwrap();
+        assert_eq!(roommates.len(), 1);
+        assert_eq!(roommates[0].name, "隐藏电话");
+        assert_eq!(roommates[0].contact, Some(json!({"qq": "10000"})));
+
+        sqlx::query("DELETE FROM freshman.students WHERE student_id LIKE 'TEST0000%'")
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    async fn test_filter_classmates() {
+        let pool = db_pool().await;
+        let students = [
+            ("TESTCM0031", "自己", "测试学院", "测试专业"),
+            ("TESTCM0032", "同专业", "测试学院", "测试专业"),
+            ("TESTCM0033", "同学院", "测试学院", "另一专业"),
+            ("TESTCM0034", "别的学院", "另一学院", "测试专业"),
+        ];
+        for (student_id, name, college, major) in students.iter() {
+            sqlx::query(
+                "INSERT INTO freshman.students (student_id, name, secret, college, major, class)
+                    VALUES ($1, $2, '123456', $3, $4, 'TEST-CLASS')",
+            )
+            .bind(student_id)
+            .bind(name)
+            .bind(college)
+            .bind(major)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        let freshman = FreshmanManager::new(&pool)
+            .query("TESTCM0031", "123456")
+            .await
+            .unwrap();
+        let names = |filter: MateFilter| {
+            let (freshman, pool) = (&freshman, &pool);
+            async move {
+                let mates = freshman.get_classmates(pool, filter).await.unwrap();
+                mates.into_iter().map(|x| x.name).collect::<Vec<_>>()
+            }
+        };
+        let filter = |college: Option<&str>, major: Option<&str>| MateFilter {
+            college: college.map(ToString::to_string),
+            major: major.map(ToString::to_string),
+        };
+
+        assert_eq!(names(filter(None, None)).await, ["同专业", "同学院", "别的学院"]);
+        assert_eq!(names(filter(Some("测试学院"), None)).await, ["同专业", "同学院"]);
+        assert_eq!(
+            names(filter(None, Some("测试专业"))).await,
+            ["同专业", "别的学院"]
+        );
+        // Unknown values are ignored.
+        assert_eq!(
+            names(filter(Some("没有的学院"), Some(" "))).await,
+            ["同专业", "同学院", "别的学院"]
+        );
+        assert_eq!(
+            names(filter(Some("测试学院"), Some("没有的专业"))).await,
+            ["同专业", "同学院"]
+        );
+
+        sqlx::query("DELETE FROM freshman.students WHERE student_id LIKE 'TESTCM%'")
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,402 @@
+//! Import freshman data from CSV. Columns are named as in table `freshman.students`, or as in the
+//! original spreadsheet, like "学号" and "身份证". Rows are upserted by student id, so importing
+//! the same file again changes nothing.
+
+use super::FreshmanError;
+use crate::error::{ApiError, Result};
+use crate::models::begin;
+use serde::Serialize;
+use sqlx::PgPool;
+
+/// Fields of a freshman in CSV.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    StudentId,
+    Name,
+    Secret,
+    /// ID card number, whose six right characters are the secret.
+    IdNumber,
+    Ticket,
+    College,
+    Major,
+    Class,
+    CounselorName,
+    CounselorTel,
+    Campus,
+    Building,
+    Room,
+    Bed,
+    Province,
+    City,
+    GraduatedFrom,
+    Postcode,
+    Gender,
+}
+
+/// Header names of each field.
+const HEADERS: &[(Field, &[&str])] = &[
+    (Field::StudentId, &["student_id", "学号"]),
+    (Field::Name, &["name", "姓名"]),
+    (Field::Secret, &["secret"]),
+    (Field::IdNumber, &["身份证", "身份证号"]),
+    (Field::Ticket, &["ticket", "考生号"]),
+    (Field::College, &["college", "学院"]),
+    (Field::Major, &["major", "专业"]),
+    (Field::Class, &["class", "班级号"]),
+    (Field::CounselorName, &["counselor_name", "辅导员"]),
+    (Field::CounselorTel, &["counselor_tel", "辅导员电话"]),
+    (Field::Campus, &["campus", "校区"]),
+    (Field::Building, &["building", "寝室楼号"]),
+    (Field::Room, &["room", "寝室号"]),
+    (Field::Bed, &["bed", "床位号"]),
+    (Field::Province, &["province", "省市"]),
+    (Field::City, &["city", "地区名称"]),
+    (Field::GraduatedFrom, &["graduated_from", "中学名称"]),
+    (Field::Postcode, &["postcode", "邮政编码"]),
+    (Field::Gender, &["gender", "性别"]),
+];
+
+/// Fields every row must have. The secret may also be given by the ID card number.
+const REQUIRED: &[Field] = &[
+    Field::StudentId,
+    Field::Name,
+    Field::Class,
+    Field::Building,
+    Field::Room,
+];
+
+impl Field {
+    fn from_header(header: &str) -> Option<Self> {
+        let header = header.trim();
+        HEADERS
+            .iter()
+            .find(|(_, names)| names.contains(&header))
+            .map(|(field, _)| *field)
+    }
+
+    fn name(&self) -> &'static str {
+        HEADERS
+            .iter()
+            .find(|(field, _)| field == self)
+            .map(|(_, names)| names[0])
+            .unwrap_or_default()
+    }
+}
+
+/// Freshman parsed from a row. Optional fields are `None` if the column or the value is absent,
+/// and they are left unchanged when the freshman exists.
+#[derive(Debug, Default, PartialEq)]
+pub struct FreshmanRow {
+    pub student_id: String,
+    pub name: String,
+    pub secret: String,
+    pub class: String,
+    pub building: String,
+    pub room: i32,
+    pub ticket: Option<String>,
+    pub college: Option<String>,
+    pub major: Option<String>,
+    pub counselor_name: Option<String>,
+    pub counselor_tel: Option<String>,
+    pub campus: Option<String>,
+    pub bed: Option<String>,
+    pub province: Option<String>,
+    pub city: Option<String>,
+    pub graduated_from: Option<String>,
+    pub postcode: Option<i32>,
+    /// "M" or "F".
+    pub gender: Option<String>,
+}
+
+/// Error of a row, which is skipped.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct RowError {
+    /// Line number in the file, starting from 1 at the header.
+    pub line: u64,
+    /// Column of the bad value, or empty if the row can't be parsed.
+    pub column: &'static str,
+    pub reason: String,
+}
+
+impl RowError {
+    fn new(line: u64, field: Field, reason: &str) -> Self {
+        Self {
+            line,
+            column: field.name(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+/// Parse a row by the fields of columns.
+fn parse_row(
+    record: &csv::StringRecord,
+    fields: &[Option<Field>],
+    line: u64,
+) -> std::result::Result<FreshmanRow, RowError> {
+    let mut row = FreshmanRow::default();
+    let mut room = None;
+
+    for (value, field) in record.iter().zip(fields) {
+        let field = match field {
+            Some(field) => *field,
+            None => continue,
+        };
+        let value = value.trim();
+        if value.is_empty() {
+            continue;
+        }
+        let text = Some(value.to_string());
+
+        match field {
+            Field::StudentId => row.student_id = value.to_string(),
+            Field::Name => row.name = value.to_string(),
+            Field::Secret => row.secret = value.to_string(),
+            Field::IdNumber if row.secret.is_empty() => {
+                let chars: Vec<char> = value.chars().collect();
+                if chars.len() < 6 {
+                    return Err(RowError::new(line, field, "too short"));
+                }
+                row.secret = chars[chars.len() - 6..].iter().collect::<String>().to_uppercase();
+            }
+            Field::IdNumber => (),
+            Field::Class => row.class = value.to_string(),
+            Field::Building => row.building = value.to_string(),
+            Field::Room => {
+                room = Some(
+                    value
+                        .parse()
+                        .map_err(|_| RowError::new(line, field, "not a number"))?,
+                )
+            }
+            Field::Ticket => row.ticket = text,
+            Field::College => row.college = text,
+            Field::Major => row.major = text,
+            Field::CounselorName => row.counselor_name = text,
+            Field::CounselorTel => row.counselor_tel = text,
+            Field::Campus => row.campus = text,
+            Field::Bed => row.bed = text,
+            Field::Province => row.province = text,
+            Field::City => row.city = text,
+            Field::GraduatedFrom => row.graduated_from = text,
+            Field::Postcode => {
+                row.postcode = Some(
+                    value
+                        .parse()
+                        .map_err(|_| RowError::new(line, field, "not a number"))?,
+                )
+            }
+            Field::Gender => {
+                let gender = match value {
+                    "M" | "m" | "男" => "M",
+                    "F" | "f" | "女" => "F",
+                    _ => return Err(RowError::new(line, field, "neither M nor F")),
+                };
+                row.gender = Some(gender.to_string());
+            }
+        }
+    }
+
+    let missing = REQUIRED.iter().find(|field| match field {
+        Field::StudentId => row.student_id.is_empty(),
+        Field::Name => row.name.is_empty(),
+        Field::Class => row.class.is_empty(),
+        Field::Building => row.building.is_empty(),
+        _ => room.is_none(),
+    });
+    if let Some(field) = missing {
+        return Err(RowError::new(line, *field, "missing"));
+    }
+    if row.secret.is_empty() {
+        return Err(RowError::new(line, Field::Secret, "missing"));
+    }
+    row.room = room.unwrap_or_default();
+    Ok(row)
+}
+
+/// Parse the CSV file. The whole file is refused if any required column is missing, while bad
+/// rows are returned as errors.
+pub fn parse_csv(data: &[u8]) -> Result<(Vec<FreshmanRow>, Vec<RowError>)> {
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(data);
+    let fields: Vec<Option<Field>> = reader
+        .headers()
+        .map_err(|_| ApiError::new(FreshmanError::MissingColumns))?
+        .iter()
+        // Strip the BOM written by Excel.
+        .map(|x| Field::from_header(x.trim_start_matches('\u{feff}')))
+        .collect();
+
+    let has_secret = fields.contains(&Some(Field::Secret)) || fields.contains(&Some(Field::IdNumber));
+    if !has_secret || REQUIRED.iter().any(|x| !fields.contains(&Some(*x))) {
+        return Err(ApiError::new(FreshmanError::MissingColumns));
+    }
+
+    let (mut rows, mut errors) = (Vec::new(), Vec::new());
+    for record in reader.records() {
+        let result = record
+            .map_err(|e| RowError {
+                line: e.position().map(|x| x.line()).unwrap_or_default(),
+                column: "",
+                reason: e.to_string(),
+            })
+            .and_then(|record| {
+                let line = record.position().map(|x| x.line()).unwrap_or_default();
+                parse_row(&record, &fields, line)
+            });
+        match result {
+            Ok(row) => rows.push(row),
+            Err(e) => errors.push(e),
+        }
+    }
+    Ok((rows, errors))
+}
+
+/// Insert freshmen or update them by student id, in a transaction. Binding and privacy settings
+/// of existing freshmen are kept.
+pub async fn upsert_freshmen(pool: &PgPool, rows: &[FreshmanRow]) -> Result<()> {
+    let mut tx = begin(pool).await?;
+
+    for row in rows {
+        sqlx::query(
+            "INSERT INTO freshman.students
+                (student_id, name, secret, class, building, room, ticket, college, major, counselor_name,
+                counselor_tel, campus, bed, province, city, graduated_from, postcode, gender)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, COALESCE($8, ''), COALESCE($9, ''), COALESCE($10, ''),
+                COALESCE($11, ''), COALESCE($12, ''), COALESCE($13, ''), $14, $15, $16, $17, COALESCE($18, 'M'))
+            ON CONFLICT (student_id) DO UPDATE SET
+                name = $2, secret = $3, class = $4, building = $5, room = $6,
+                ticket = COALESCE($7, students.ticket),
+                college = COALESCE($8, students.college),
+                major = COALESCE($9, students.major),
+                counselor_name = COALESCE($10, students.counselor_name),
+                counselor_tel = COALESCE($11, students.counselor_tel),
+                campus = COALESCE($12, students.campus),
+                bed = COALESCE($13, students.bed),
+                province = COALESCE($14, students.province),
+                city = COALESCE($15, students.city),
+                graduated_from = COALESCE($16, students.graduated_from),
+                postcode = COALESCE($17, students.postcode),
+                gender = COALESCE($18, students.gender)",
+        )
+        .bind(&row.student_id)
+        .bind(&row.name)
+        .bind(&row.secret)
+        .bind(&row.class)
+        .bind(&row.building)
+        .bind(row.room)
+        .bind(&row.ticket)
+        .bind(&row.college)
+        .bind(&row.major)
+        .bind(&row.counselor_name)
+        .bind(&row.counselor_tel)
+        .bind(&row.campus)
+        .bind(&row.bed)
+        .bind(&row.province)
+        .bind(&row.city)
+        .bind(&row.graduated_from)
+        .bind(row.postcode)
+        .bind(&row.gender)
+        .execute(&mut tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::test::db_pool;
+
+    const HEADER: &str = "学号,姓名,身份证,班级号,寝室楼号,寝室号,性别,邮政编码\n";
+
+    #[test]
+    pub fn test_parse_valid() {
+        let data = format!(
+            "{}TEST000031,张三,31010119990101123x,2021001,1号楼,101,男,200000\n\
+            TEST000032,\"李,四\",310101199901011234,2021001,1号楼,101,女,\n",
+            HEADER
+        );
+        let (rows, errors) = parse_csv(data.as_bytes()).unwrap();
+
+        assert!(errors.is_empty());
+        assert_eq!(
+            rows[0],
+            FreshmanRow {
+                student_id: "TEST000031".to_string(),
+                name: "张三".to_string(),
+                secret: "01123X".to_string(),
+                class: "2021001".to_string(),
+                building: "1号楼".to_string(),
+                room: 101,
+                postcode: Some(200000),
+                gender: Some("M".to_string()),
+                ..Default::default()
+            }
+        );
+        assert_eq!(rows[1].name, "李,四");
+        assert_eq!(rows[1].postcode, None);
+    }
+
+    #[test]
+    pub fn test_parse_malformed() {
+        let data = format!(
+            "{}TEST000031,张三,31010119990101123X,2021001,1号楼,101,男,200000\n\
+            TEST000032,李四,310101199901011234,2021001,1号楼,一零一,女,200000\n\
+            TEST000033,王五,310101199901011234,2021001\n",
+            HEADER
+        );
+        let (rows, errors) = parse_csv(data.as_bytes()).unwrap();
+
+        assert_eq!(rows.len(), 1);
+        assert_eq!(errors[0], RowError::new(3, Field::Room, "not a number"));
+        assert_eq!(errors[1], RowError::new(4, Field::Building, "missing"));
+    }
+
+    #[test]
+    pub fn test_missing_columns() {
+        let result = parse_csv("学号,姓名,班级号,寝室楼号,寝室号\n".as_bytes());
+        assert_eq!(result.err(), Some(ApiError::new(FreshmanError::MissingColumns)));
+        let result = parse_csv("学号,姓名,身份证,寝室楼号,寝室号\n".as_bytes());
+        assert_eq!(result.err(), Some(ApiError::new(FreshmanError::MissingColumns)));
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    async fn test_import_idempotent() {
+        let pool = db_pool().await;
+        let data = format!(
+            "{}TEST000031,张三,31010119990101123X,2021001,1号楼,101,男,\n",
+            HEADER
+        );
+        let (rows, _) = parse_csv(data.as_bytes()).unwrap();
+        let count = || {
+            sqlx::query_as::<_, (i64,)>(
+                "SELECT COUNT(*) FROM freshman.students WHERE student_id = 'TEST000031'",
+            )
+        };
+
+        upsert_freshmen(&pool, &rows).await.unwrap();
+        // Privacy settings, and values absent in the file, are kept on import.
+        sqlx::query("UPDATE freshman.students SET visible = true, postcode = 200000 WHERE student_id = 'TEST000031'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        upsert_freshmen(&pool, &rows).await.unwrap();
+        assert_eq!(count().fetch_one(&pool).await.unwrap().0, 1);
+        let (visible, postcode, college): (bool, Option<i32>, String) = sqlx::query_as(
+            "SELECT visible, postcode, college FROM freshman.students WHERE student_id = 'TEST000031'",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert!(visible);
+        assert_eq!(postcode, Some(200000));
+        assert_eq!(college, "");
+
+        sqlx::query("DELETE FROM freshman.students WHERE student_id LIKE 'TEST0000%'")
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+}
@@ -1,5 +1,8 @@
-use super::{FreshmanBasic, FreshmanError};
+use super::{FreshmanBasic, FreshmanError, MapDefaultAvatar, PeopleFamiliar, Privacy, RedactContact};
 use crate::error::{ApiError, Result};
+use crate::models::notification;
+use crate::models::user::Identity;
+use crate::models::{begin, escape_like};
 use sqlx::postgres::PgPool;
 
 impl FreshmanBasic {
@@ -41,6 +44,17 @@ impl FreshmanBasic {
     // End of impl FreshmanBasic
 }
 
+/// Title of the notification sent to roommates when a freshman joins.
+const ROOMMATE_JOINED_TITLE: &str = "室友加入了";
+
+/// Content of the notification sent to roommates. The name is left out if the freshman hides.
+fn roommate_joined_content(name: Option<&str>) -> String {
+    match name {
+        Some(name) => format!("你的室友 {} 已绑定账号，快去打个招呼吧。", name),
+        None => "你的一位室友已绑定账号。".to_string(),
+    }
+}
+
 pub struct FreshmanManager<'a> {
     pool: &'a PgPool,
 }
@@ -83,6 +97,87 @@ impl<'a> FreshmanManager<'a> {
         Ok(())
     }
 
+    /// Create the freshman record of a newly bound student from the identity, with the college and
+    /// class from the portal if known, so that the student can query it immediately. If the record
+    /// exists, only the uid and the missing college and class are filled.
+    pub async fn init_from_identity(
+        &self,
+        identity: &Identity,
+        college: Option<&str>,
+        class: Option<&str>,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO freshman.students (student_id, name, uid, secret, college, class)
+                VALUES ($1, $2, $3, $4, COALESCE($5, ''), $6)
+                ON CONFLICT (student_id)
+                DO UPDATE SET uid = COALESCE(students.uid, $3),
+                    college = COALESCE(NULLIF(students.college, ''), $5, students.college),
+                    class = COALESCE(students.class, $6)",
+        )
+        .bind(&identity.student_id)
+        .bind(&identity.real_name)
+        .bind(identity.uid)
+        // Secret is the right 6 characters of id number, the same as that in identity.
+        .bind(identity.identity_number.as_deref().unwrap_or_default())
+        .bind(college)
+        .bind(class)
+        .execute(self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Notify roommates bound to their accounts that the freshman has joined. Each freshman is
+    /// announced only once, however many times it's bound. Returns count of roommates notified.
+    pub async fn notify_roommates(&self, student_id: &str) -> Result<usize> {
+        let mut tx = begin(self.pool).await?;
+        // The row is locked, so that concurrent binding waits and then finds it notified.
+        let joined: Option<(String, bool)> = sqlx::query_as(
+            "SELECT name, visible AND NOT hide_me FROM freshman.students
+                WHERE student_id = $1 AND uid IS NOT NULL AND roommates_notified = false
+                FOR UPDATE",
+        )
+        .bind(student_id)
+        .fetch_optional(&mut tx)
+        .await?;
+        let (name, show_name) = match joined {
+            Some(x) => x,
+            None => return Ok(0),
+        };
+
+        let roommates: Vec<(i32,)> = sqlx::query_as(
+            "SELECT stu.uid
+            FROM freshman.students AS stu
+            INNER JOIN (
+                    SELECT building, room FROM freshman.students WHERE student_id = $1 LIMIT 1
+                ) self
+            ON
+                stu.room = self.room
+                AND stu.building = self.building
+                AND stu.student_id <> $1
+                AND stu.uid IS NOT NULL",
+        )
+        .bind(student_id)
+        .fetch_all(&mut tx)
+        .await?;
+
+        let content = roommate_joined_content(if show_name { Some(&name) } else { None });
+        let mut notifications = Vec::with_capacity(roommates.len());
+        for (uid,) in &roommates {
+            notifications.push(
+                notification::create_in(&mut tx, *uid, ROOMMATE_JOINED_TITLE, Some(&content)).await?,
+            );
+        }
+        // Marked with the notifications, so that it's notified again if any of them fails.
+        sqlx::query("UPDATE freshman.students SET roommates_notified = true WHERE student_id = $1")
+            .bind(student_id)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+
+        notifications.into_iter().for_each(notification::dispatch);
+        Ok(roommates.len())
+    }
+
     pub async fn is_bound(&self, uid: i32) -> Result<bool> {
         let r: Option<(bool,)> =
             sqlx::query_as("SELECT TRUE FROM freshman.students WHERE uid = $1 LIMIT 1")
@@ -91,4 +186,215 @@ impl<'a> FreshmanManager<'a> {
                 .await?;
         Ok(r.is_some())
     }
+
+    /// Update privacy settings of the freshman bound to the user. Settings not given are kept.
+    pub async fn set_privacy(
+        &self,
+        uid: i32,
+        visible_phone: Option<bool>,
+        visible_contact: Option<bool>,
+        hide_me: Option<bool>,
+    ) -> Result<Privacy> {
+        let privacy: Option<Privacy> = sqlx::query_as(
+            "UPDATE freshman.students
+                SET visible_phone = COALESCE($2, visible_phone),
+                    visible_contact = COALESCE($3, visible_contact),
+                    hide_me = COALESCE($4, hide_me)
+                WHERE uid = $1
+                RETURNING visible_phone, visible_contact, hide_me",
+        )
+        .bind(uid)
+        .bind(visible_phone)
+        .bind(visible_contact)
+        .bind(hide_me)
+        .fetch_optional(self.pool)
+        .await?;
+
+        privacy.ok_or(ApiError::new(FreshmanError::NoSuchAccount))
+    }
+
+    /// Search freshmen who allow to be found by name, except the user. Exact matches go first,
+    /// then names starting with the keyword, and then names containing it.
+    pub async fn search(&self, keyword: &str, uid: i32, limit: i64) -> Result<Vec<PeopleFamiliar>> {
+        let people: Vec<PeopleFamiliar> = sqlx::query_as(
+            "SELECT stu.student_id, name, college, stu.city, stu.gender, last_seen, avatar, contact, visible_phone,
+                visible_contact
+                FROM freshman.students AS stu
+                LEFT JOIN public.person AS person
+                ON stu.uid = person.uid
+                WHERE stu.name ILIKE '%' || $1 || '%'
+                    AND stu.visible = true
+                    AND stu.hide_me = false
+                    AND stu.uid IS DISTINCT FROM $3
+                ORDER BY
+                    CASE
+                        WHEN lower(stu.name) = lower($2) THEN 0
+                        WHEN stu.name ILIKE $1 || '%' THEN 1
+                        ELSE 2
+                    END,
+                    length(stu.name), stu.student_id
+                LIMIT $4",
+        )
+        .bind(escape_like(keyword))
+        .bind(keyword)
+        .bind(uid)
+        .bind(limit)
+        .fetch_all(self.pool)
+        .await?;
+
+        Ok(people.map_default_avatar().redact_contact())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::FreshmanManager;
+    use crate::models::test::db_pool;
+    use crate::models::user::Identity;
+
+    #[tokio::test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    async fn test_init_from_identity() {
+        let pool = db_pool().await;
+        let manager = FreshmanManager::new(&pool);
+        let identity = Identity {
+            uid: 99999,
+            real_name: "张三".to_string(),
+            student_id: "TEST000001".to_string(),
+            identity_number: Some("123456".to_string()),
+            ..Identity::default()
+        };
+
+        manager
+            .init_from_identity(&identity, Some("计算机学院"), Some("21104011"))
+            .await
+            .unwrap();
+        let freshman = manager.query("TEST000001", "123456").await.unwrap();
+        assert_eq!(freshman.name, "张三");
+        assert_eq!(freshman.uid, Some(99999));
+        assert_eq!(freshman.college, "计算机学院");
+        assert!(manager.is_bound(99999).await.unwrap());
+
+        // Imported values are kept.
+        manager
+            .init_from_identity(&identity, Some("材料学院"), None)
+            .await
+            .unwrap();
+        let (college, class): (String, Option<String>) = sqlx::query_as(
+            "SELECT college, class FROM freshman.students WHERE student_id = 'TEST000001'",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(college, "计算机学院");
+        assert_eq!(class.as_deref(), Some("21104011"));
+
+        sqlx::query("DELETE FROM freshman.students WHERE student_id = 'TEST000001'")
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    async fn test_search() {
+        let pool = db_pool().await;
+        let manager = FreshmanManager::new(&pool);
+        let students = [
+            ("TEST000011", "测试同学", true, None),
+            ("TEST000012", "测试", true, None),
+            ("TEST000013", "小测试", true, None),
+            // Not visible.
+            ("TEST000014", "测试隐身", false, None),
+            // The user searching.
+            ("TEST000015", "测试自己", true, Some(99998)),
+        ];
+        for (student_id, name, visible, uid) in students.iter() {
+            sqlx::query(
+                "INSERT INTO freshman.students (student_id, name, secret, visible, uid) VALUES ($1, $2, '', $3, $4)",
+            )
+            .bind(student_id)
+            .bind(name)
+            .bind(visible)
+            .bind(uid)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+        let search = |keyword: &'static str| {
+            let manager = &manager;
+            async move {
+                let people = manager.search(keyword, 99998, 20).await.unwrap();
+                people.into_iter().map(|x| x.name).collect::<Vec<String>>()
+            }
+        };
+
+        // Exact match first, then prefix match.
+        assert_eq!(search("测试").await, ["测试", "测试同学", "小测试"]);
+        assert_eq!(search("测试同").await, ["测试同学"]);
+        assert_eq!(search("小").await, ["小测试"]);
+        assert!(search("不存在").await.is_empty());
+        assert!(search("%").await.is_empty());
+
+        sqlx::query("DELETE FROM freshman.students WHERE student_id LIKE 'TEST0000%'")
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+    #[test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    fn test_notify_roommates() {
+        // Notifications are delivered in background, which needs an actix system.
+        actix_web::rt::System::new("test").block_on(async {
+            let pool = db_pool().await;
+            let manager = FreshmanManager::new(&pool);
+            let students = [
+                // The freshman binding, who hides from others.
+                ("TESTRM0001", "新室友", 101, Some(99991), true),
+                ("TESTRM0002", "老室友", 101, Some(99992), false),
+                // Not bound yet.
+                ("TESTRM0003", "未绑定", 101, None, false),
+                // In another room.
+                ("TESTRM0004", "隔壁", 102, Some(99994), false),
+            ];
+            for (student_id, name, room, uid, hide_me) in students.iter() {
+                sqlx::query(
+                    "INSERT INTO freshman.students (student_id, name, secret, building, room, uid, hide_me)
+                        VALUES ($1, $2, '', 'TEST', $3, $4, $5)",
+                )
+                .bind(student_id)
+                .bind(name)
+                .bind(room)
+                .bind(uid)
+                .bind(hide_me)
+                .execute(&pool)
+                .await
+                .unwrap();
+            }
+
+            assert_eq!(manager.notify_roommates("TESTRM0001").await.unwrap(), 1);
+            // Binding again notifies nobody.
+            assert_eq!(manager.notify_roommates("TESTRM0001").await.unwrap(), 0);
+
+            let notifications: Vec<(i32, Option<String>)> = sqlx::query_as(
+                "SELECT uid, content FROM public.notifications WHERE uid BETWEEN 99991 AND 99994",
+            )
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+            assert_eq!(notifications.len(), 1);
+            assert_eq!(notifications[0].0, 99992);
+            // Name is hidden.
+            assert!(!notifications[0].1.as_deref().unwrap().contains("新室友"));
+
+            sqlx::query("DELETE FROM public.notifications WHERE uid BETWEEN 99991 AND 99994")
+                .execute(&pool)
+                .await
+                .unwrap();
+            sqlx::query("DELETE FROM freshman.students WHERE student_id LIKE 'TESTRM%'")
+                .execute(&pool)
+                .await
+                .unwrap();
+        });
+    }
 }
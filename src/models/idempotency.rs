@@ -0,0 +1,112 @@
+//! This module stores idempotency keys, so that requests retried on flaky networks don't create
+//! things twice. The result of the first request is stored with the key, and answered to retries.
+use crate::error::Result;
+use sqlx::{Done, PgPool};
+use std::time::Duration;
+
+/// Result of claiming an idempotency key.
+#[derive(Debug, PartialEq)]
+pub enum Claim {
+    /// The key is new, and the request should be processed.
+    New,
+    /// The request has been processed, with the result stored.
+    Done(String),
+    /// The first request is still being processed.
+    Pending,
+    /// The key has been used by another request.
+    Mismatch,
+}
+
+pub struct IdempotencyManager<'a> {
+    pool: &'a PgPool,
+}
+
+impl<'a> IdempotencyManager<'a> {
+    pub fn new(pool: &'a PgPool) -> Self {
+        Self { pool }
+    }
+
+    /// Claim the key in the scope, which is like "POST /user ip:127.0.0.1", for the request of the
+    /// fingerprint. Keys claimed more than `window` ago are forgotten, and a claim whose request
+    /// hasn't finished in `lease` can be taken over by a retry, in case the first one was dropped.
+    pub async fn claim(
+        &self,
+        scope: &str,
+        key: &str,
+        fingerprint: &str,
+        window: Duration,
+        lease: Duration,
+    ) -> Result<Claim> {
+        sqlx::query(
+            "DELETE FROM public.idempotency_key WHERE create_time < now() - $1 * interval '1 second'",
+        )
+        .bind(window.as_secs() as i64)
+        .execute(self.pool)
+        .await?;
+
+        let inserted = sqlx::query(
+            "INSERT INTO public.idempotency_key (scope, key, fingerprint) VALUES ($1, $2, $3)
+                ON CONFLICT DO NOTHING",
+        )
+        .bind(scope)
+        .bind(key)
+        .bind(fingerprint)
+        .execute(self.pool)
+        .await?
+        .rows_affected();
+        if inserted == 1 {
+            return Ok(Claim::New);
+        }
+        let taken_over = sqlx::query(
+            "UPDATE public.idempotency_key SET create_time = now()
+                WHERE scope = $1 AND key = $2 AND fingerprint = $3 AND response IS NULL
+                    AND create_time < now() - $4 * interval '1 second'",
+        )
+        .bind(scope)
+        .bind(key)
+        .bind(fingerprint)
+        .bind(lease.as_secs() as i64)
+        .execute(self.pool)
+        .await?
+        .rows_affected();
+        if taken_over == 1 {
+            return Ok(Claim::New);
+        }
+
+        let claimed: Option<(Option<String>, Option<String>)> = sqlx::query_as(
+            "SELECT fingerprint, response FROM public.idempotency_key WHERE scope = $1 AND key = $2",
+        )
+        .bind(scope)
+        .bind(key)
+        .fetch_optional(self.pool)
+        .await?;
+        Ok(match claimed {
+            Some((claimed, _)) if claimed.as_deref() != Some(fingerprint) => Claim::Mismatch,
+            Some((_, Some(response))) => Claim::Done(response),
+            Some((_, None)) => Claim::Pending,
+            // Expired and deleted meanwhile, which is rare. Let the client retry.
+            None => Claim::Pending,
+        })
+    }
+
+    /// Store the result of the request claiming the key.
+    pub async fn complete(&self, scope: &str, key: &str, response: &str) -> Result<()> {
+        sqlx::query("UPDATE public.idempotency_key SET response = $3 WHERE scope = $1 AND key = $2")
+            .bind(scope)
+            .bind(key)
+            .bind(response)
+            .execute(self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Release the key after the request failed, so that it can be retried with the same key.
+    pub async fn release(&self, scope: &str, key: &str) -> Result<()> {
+        sqlx::query("DELETE FROM public.idempotency_key WHERE scope = $1 AND key = $2")
+            .bind(scope)
+            .bind(key)
+            .execute(self.pool)
+            .await?;
+        Ok(())
+    }
+}
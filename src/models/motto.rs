@@ -1,5 +1,7 @@
 use crate::error::{ApiError, Result};
+use chrono::{DateTime, Local, NaiveDate};
 use serde::Serialize;
+use sha2::{Digest, Sha256};
 use sqlx::PgPool;
 
 /* Constants. */
@@ -7,17 +9,23 @@ use sqlx::PgPool;
 // If a max size which less than MIN_SIZE is set, that's ok. But the function may not return record any more.
 pub const MOTTO_MIN_SIZE: u8 = 5;
 pub const MOTTO_MAX_SIZE: u8 = 255;
+/// Max count of mottos in one page when browsing.
+pub const MOTTO_MAX_PAGE_SIZE: u16 = 50;
 
 /// Error handled in motto module.
 #[derive(thiserror::Error, Debug, ToPrimitive)]
 pub enum MottoError {
     #[error("无数据")]
     NoMoreItem = 100,
+    #[error("找不到该格言")]
+    NotFound = 101,
+    #[error("内容为空或过长")]
+    InvalidContent = 102,
 }
 
 /* Model */
 /// Motto structure, as a motto item.
-#[derive(Default, Serialize, sqlx::FromRow)]
+#[derive(Debug, Serialize, sqlx::FromRow)]
 pub struct Motto {
     /// Motto id, as a serial column in table.
     pub id: i32,
@@ -27,25 +35,52 @@ pub struct Motto {
     pub content: String,
     /// Impression count, self increment once when select.
     pub impressions: i32,
+    /// Occasion the motto fits, like "exam" and "graduation".
+    pub category: Option<String>,
+    #[serde(rename = "createTime")]
+    pub created_at: DateTime<Local>,
+    /// Last time the content was changed. Impressions don't count.
+    #[serde(rename = "updateTime")]
+    pub updated_at: DateTime<Local>,
+}
+
+impl Default for Motto {
+    fn default() -> Self {
+        Self {
+            id: 0,
+            source: None,
+            content: String::new(),
+            impressions: 0,
+            category: None,
+            created_at: Local::now(),
+            updated_at: Local::now(),
+        }
+    }
 }
 
 impl Motto {
-    /// Choice one motto randomly from database.
-    pub async fn random_choice(client: &PgPool, min_length: u8, max_length: u8) -> Result<Self> {
+    /// Choice one motto randomly from database, in the category if given.
+    pub async fn random_choice(
+        client: &PgPool,
+        min_length: u8,
+        max_length: u8,
+        category: Option<&str>,
+    ) -> Result<Self> {
         let motto: Option<Motto> = sqlx::query_as(
             "WITH 
                 whole_fitted AS 
-                    (SELECT * FROM motto WHERE length BETWEEN $1 AND $2),
+                    (SELECT * FROM motto WHERE approved AND length BETWEEN $1 AND $2 AND ($3::text IS NULL OR category = $3)),
                 selected AS 
                     (SELECT * FROM whole_fitted OFFSET floor(random() * (SELECT count(*) FROM whole_fitted)) LIMIT 1)
                 UPDATE motto
                     SET impressions = selected.impressions + 1
                     FROM selected
                     WHERE selected.id = motto.id
-                RETURNING motto.id, motto.source, motto.content, motto.impressions"
+                RETURNING motto.id, motto.source, motto.content, motto.impressions, motto.category, motto.created_at, motto.updated_at"
         )
         .bind(min_length as i32)
         .bind(max_length as i32)
+        .bind(category)
         .fetch_optional(client)
         .await?;
         if let Some(motto) = motto {
@@ -53,4 +88,292 @@ impl Motto {
         }
         Err(ApiError::new(MottoError::NoMoreItem))
     }
+
+    /// Choice the motto of the day, which is the same all the day, in the category if given.
+    pub async fn daily_choice(
+        client: &PgPool,
+        min_length: u8,
+        max_length: u8,
+        category: Option<&str>,
+        date: NaiveDate,
+    ) -> Result<Self> {
+        let motto: Option<Motto> = sqlx::query_as(
+            "WITH
+                whole_fitted AS
+                    (SELECT * FROM motto WHERE approved AND length BETWEEN $1 AND $2 AND ($4::text IS NULL OR category = $4)),
+                selected AS
+                    (SELECT * FROM whole_fitted ORDER BY id
+                        OFFSET $3 % GREATEST((SELECT count(*) FROM whole_fitted), 1) LIMIT 1)
+                UPDATE motto
+                    SET impressions = selected.impressions + 1
+                    FROM selected
+                    WHERE selected.id = motto.id
+                RETURNING motto.id, motto.source, motto.content, motto.impressions, motto.category, motto.created_at, motto.updated_at",
+        )
+        .bind(min_length as i32)
+        .bind(max_length as i32)
+        .bind(daily_seed(date))
+        .bind(category)
+        .fetch_optional(client)
+        .await?;
+        motto.ok_or(ApiError::new(MottoError::NoMoreItem))
+    }
+
+    /// Add a motto. Content is checked by `check_content`.
+    pub async fn create(
+        client: &PgPool,
+        source: Option<&str>,
+        category: Option<&str>,
+        content: &str,
+    ) -> Result<Self> {
+        let content = check_content(content)?;
+        let motto: Motto = sqlx::query_as(
+            "INSERT INTO motto (source, content, length, category) VALUES ($1, $2, $3, $4)
+                RETURNING id, source, content, impressions, category, created_at, updated_at",
+        )
+        .bind(source)
+        .bind(content)
+        .bind(content.chars().count() as i32)
+        .bind(category)
+        .fetch_one(client)
+        .await?;
+        Ok(motto)
+    }
+
+    /// Replace source, category and content of the motto.
+    pub async fn update(
+        client: &PgPool,
+        id: i32,
+        source: Option<&str>,
+        category: Option<&str>,
+        content: &str,
+    ) -> Result<Self> {
+        let content = check_content(content)?;
+        let motto: Option<Motto> = sqlx::query_as(
+            "UPDATE motto SET source = $2, content = $3, length = $4, category = $5, updated_at = now() WHERE id = $1
+                RETURNING id, source, content, impressions, category, created_at, updated_at",
+        )
+        .bind(id)
+        .bind(source)
+        .bind(content)
+        .bind(content.chars().count() as i32)
+        .bind(category)
+        .fetch_optional(client)
+        .await?;
+        motto.ok_or(ApiError::new(MottoError::NotFound))
+    }
+
+    /// Delete the motto, and return the deleted one.
+    pub async fn delete(client: &PgPool, id: i32) -> Result<Self> {
+        let motto: Option<Motto> = sqlx::query_as(
+            "DELETE FROM motto WHERE id = $1 RETURNING id, source, content, impressions, category, created_at, updated_at",
+        )
+        .bind(id)
+        .fetch_optional(client)
+        .await?;
+        motto.ok_or(ApiError::new(MottoError::NotFound))
+    }
+}
+
+/// Trim the content, which should not be empty or longer than `MOTTO_MAX_SIZE` characters.
+fn check_content(content: &str) -> Result<&str> {
+    let content = content.trim();
+    let length = content.chars().count();
+
+    if length == 0 || length > MOTTO_MAX_SIZE as usize {
+        return Err(ApiError::new(MottoError::InvalidContent));
+    }
+    Ok(content)
+}
+
+/// Non-negative seed of the date, which is the same on every server and changes day by day.
+fn daily_seed(date: NaiveDate) -> i64 {
+    let digest = Sha256::digest(date.format("%Y-%m-%d").to_string().as_bytes());
+    let mut bytes = [0u8; 8];
+
+    bytes.copy_from_slice(&digest[..8]);
+    (u64::from_be_bytes(bytes) >> 1) as i64
+}
+
+/// A page of mottos in id order.
+#[derive(Serialize)]
+pub struct MottoPage {
+    pub mottos: Vec<Motto>,
+    /// Cursor for the next page, or None if there is no more motto.
+    #[serde(rename = "nextCursor")]
+    pub next_cursor: Option<i32>,
+}
+
+impl MottoPage {
+    /// Make a page from mottos fetched with a limit of `size`.
+    pub fn new(mottos: Vec<Motto>, size: u16) -> Self {
+        let next_cursor = if mottos.len() == size as usize {
+            mottos.last().map(|x| x.id)
+        } else {
+            None
+        };
+        Self { mottos, next_cursor }
+    }
+
+    /// List approved mottos with id greater than the cursor in stable id order.
+    pub async fn list(client: &PgPool, cursor: i32, size: u16) -> Result<Self> {
+        let mottos: Vec<Motto> = sqlx::query_as(
+            "SELECT id, source, content, impressions, category, created_at, updated_at FROM motto
+                WHERE approved AND id > $1 ORDER BY id LIMIT $2",
+        )
+        .bind(cursor)
+        .bind(size as i32)
+        .fetch_all(client)
+        .await?;
+        Ok(Self::new(mottos, size))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::test::db_pool;
+
+    #[tokio::test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    async fn test_browse_mottos() {
+        let pool = db_pool().await;
+        let mut seeded = Vec::new();
+        for content in &[
+            "学而时习之。",
+            "温故而知新。",
+            "三人行必有我师。",
+            "知之为知之。",
+            "不知为不知。",
+        ] {
+            seeded.push(Motto::create(&pool, None, None, content).await.unwrap().id);
+        }
+        let unapproved = seeded.remove(2);
+        sqlx::query("UPDATE motto SET approved = false WHERE id = $1")
+            .bind(unapproved)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let mut browsed = Vec::new();
+        let mut cursor = Some(seeded[0] - 1);
+        while let Some(c) = cursor {
+            let page = MottoPage::list(&pool, c, 2).await.unwrap();
+            browsed.extend(page.mottos.iter().map(|x| x.id));
+            cursor = page.next_cursor;
+        }
+        // In id order without duplicates, gaps or the unapproved one.
+        assert!(browsed.windows(2).all(|x| x[0] < x[1]));
+        assert!(seeded.iter().all(|id| browsed.contains(id)));
+        assert!(!browsed.contains(&unapproved));
+
+        for id in seeded.into_iter().chain(Some(unapproved)) {
+            Motto::delete(&pool, id).await.unwrap();
+        }
+    }
+
+    #[test]
+    pub fn test_serialize_timestamps() {
+        let value = serde_json::to_value(Motto::default()).unwrap();
+
+        for key in &["createTime", "updateTime"] {
+            let time = value[key].as_str().unwrap();
+            assert!(DateTime::parse_from_rfc3339(time).is_ok(), "{}: {}", key, time);
+        }
+    }
+
+    #[test]
+    pub fn test_check_content() {
+        assert_eq!(check_content(" 知足长乐。\n").unwrap(), "知足长乐。");
+        assert!(check_content("  ").is_err());
+        assert!(check_content(&"长".repeat(MOTTO_MAX_SIZE as usize)).is_ok());
+        assert!(check_content(&"长".repeat(MOTTO_MAX_SIZE as usize + 1)).is_err());
+    }
+
+    #[test]
+    pub fn test_daily_seed() {
+        let day = NaiveDate::from_ymd(2020, 9, 1);
+
+        // Same on the same day.
+        assert_eq!(daily_seed(day), daily_seed(NaiveDate::from_ymd(2020, 9, 1)));
+        assert!(daily_seed(day) >= 0);
+        // Rotates day by day.
+        let indexes: Vec<i64> = (0..7)
+            .map(|x| daily_seed(day + chrono::Duration::days(x)) % 100)
+            .collect();
+        assert!(indexes.windows(2).any(|x| x[0] != x[1]));
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    async fn test_daily_choice() {
+        let pool = db_pool().await;
+        let day = NaiveDate::from_ymd(2020, 9, 1);
+
+        let first = Motto::daily_choice(&pool, MOTTO_MIN_SIZE, MOTTO_MAX_SIZE, None, day)
+            .await
+            .unwrap();
+        let second = Motto::daily_choice(&pool, MOTTO_MIN_SIZE, MOTTO_MAX_SIZE, None, day)
+            .await
+            .unwrap();
+        assert_eq!(first.id, second.id);
+        assert_eq!(second.impressions, first.impressions + 1);
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    async fn test_manage_mottos() {
+        let pool = db_pool().await;
+
+        let motto = Motto::create(&pool, None, None, " 知足长乐。").await.unwrap();
+        assert_eq!(motto.content, "知足长乐。");
+        let motto = Motto::update(&pool, motto.id, Some("《老子》"), Some("test"), "知足者富。")
+            .await
+            .unwrap();
+        assert_eq!(motto.source.as_deref(), Some("《老子》"));
+        assert_eq!(motto.category.as_deref(), Some("test"));
+        assert!(motto.updated_at > motto.created_at);
+        let (length,): (i32,) = sqlx::query_as("SELECT length FROM motto WHERE id = $1")
+            .bind(motto.id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(length, 5);
+
+        Motto::delete(&pool, motto.id).await.unwrap();
+        let result = Motto::delete(&pool, motto.id).await;
+        assert_eq!(result.err(), Some(ApiError::new(MottoError::NotFound)));
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    async fn test_choice_in_category() {
+        let pool = db_pool().await;
+        let exam = Motto::create(&pool, None, Some("test-exam"), "书山有路勤为径。")
+            .await
+            .unwrap();
+        let welcome = Motto::create(&pool, None, Some("test-welcome"), "有朋自远方来。")
+            .await
+            .unwrap();
+
+        // Mottos in other categories are excluded.
+        for _ in 0..5 {
+            let motto = Motto::random_choice(&pool, MOTTO_MIN_SIZE, MOTTO_MAX_SIZE, Some("test-exam"))
+                .await
+                .unwrap();
+            assert_eq!(motto.id, exam.id);
+        }
+        let day = NaiveDate::from_ymd(2020, 9, 1);
+        let motto =
+            Motto::daily_choice(&pool, MOTTO_MIN_SIZE, MOTTO_MAX_SIZE, Some("test-welcome"), day)
+                .await
+                .unwrap();
+        assert_eq!(motto.id, welcome.id);
+        let result =
+            Motto::random_choice(&pool, MOTTO_MIN_SIZE, MOTTO_MAX_SIZE, Some("test-none")).await;
+        assert_eq!(result.err(), Some(ApiError::new(MottoError::NoMoreItem)));
+
+        Motto::delete(&pool, exam.id).await.unwrap();
+        Motto::delete(&pool, welcome.id).await.unwrap();
+    }
 }
@@ -0,0 +1,215 @@
+//! This module provides notifications sent to a certain user, which is different from notices
+//! shown on the home page for everyone.
+
+mod notifier;
+
+use crate::cache::Cache;
+use crate::error::Result;
+use crate::models::begin;
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+
+pub use notifier::NOTIFIER;
+
+/// Notifications buffered for slow subscribers. Subscribers lagging behind more than that load
+/// missed ones from the database.
+const BROADCAST_CAPACITY: usize = 256;
+
+lazy_static! {
+    /// Unread notification count of each user.
+    static ref UNREAD_COUNT: Cache<i32, i64> = Cache::new(Duration::from_secs(30));
+    /// Notifications of all users, as they're created.
+    static ref CREATED: broadcast::Sender<Notification> = broadcast::channel(BROADCAST_CAPACITY).0;
+    /// Notifications to deliver, taken by `deliver_all` on the actix system, so that they can be
+    /// dispatched from any task.
+    static ref DELIVERIES: (
+        mpsc::UnboundedSender<Notification>,
+        Mutex<Option<mpsc::UnboundedReceiver<Notification>>>
+    ) = {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (sender, Mutex::new(Some(receiver)))
+    };
+}
+
+/// Receive notifications of all users created from now on.
+pub fn subscribe() -> broadcast::Receiver<Notification> {
+    CREATED.subscribe()
+}
+
+/// Publish the notification to subscribers. It's fine if there is none.
+fn publish(notification: &Notification) {
+    let _ = CREATED.send(notification.clone());
+}
+
+/// Insert the notification in the transaction, which is dispatched by `dispatch` after committed.
+pub async fn create_in(
+    tx: &mut Transaction<'_, Postgres>,
+    uid: i32,
+    title: &str,
+    content: Option<&str>,
+) -> Result<Notification> {
+    let notification: Notification = sqlx::query_as(
+        "INSERT INTO public.notifications (uid, title, content) VALUES ($1, $2, $3)
+            RETURNING id, uid, title, content, is_read, create_time",
+    )
+    .bind(uid)
+    .bind(title)
+    .bind(content)
+    .fetch_one(tx)
+    .await?;
+    Ok(notification)
+}
+
+/// Publish the notification inserted, and queue it to be delivered by `NOTIFIER` in background.
+/// It never spawns, so it's safe in tasks out of the actix system, like balance polling.
+pub fn dispatch(notification: Notification) {
+    invalidate_unread_count(notification.uid);
+    publish(&notification);
+    let _ = DELIVERIES.0.send(notification);
+}
+
+/// Deliver notifications dispatched, each in a local task, until the process exits. It must run
+/// on the actix system, since notifiers are not `Send`.
+pub async fn deliver_all() {
+    let mut receiver = match DELIVERIES.1.lock().unwrap().take() {
+        Some(receiver) => receiver,
+        None => return,
+    };
+    while let Some(notification) = receiver.recv().await {
+        actix_web::rt::spawn(async move {
+            notifier::deliver(NOTIFIER.as_ref(), &notification).await;
+        });
+    }
+}
+
+/// Notification to a user.
+#[derive(Clone, Serialize, sqlx::FromRow)]
+pub struct Notification {
+    /// Notification id.
+    pub id: i32,
+    /// Receiver uid.
+    pub uid: i32,
+    /// Title.
+    pub title: String,
+    /// Content.
+    pub content: Option<String>,
+    /// Whether the user has read it.
+    #[serde(rename = "isRead")]
+    pub is_read: bool,
+    /// Create time.
+    #[serde(rename = "createTime")]
+    pub create_time: NaiveDateTime,
+}
+
+/// Drop the cached count when the user's notifications changed.
+fn invalidate_unread_count(uid: i32) {
+    UNREAD_COUNT.remove(&uid);
+}
+
+pub struct NotificationManager<'a> {
+    pool: &'a PgPool,
+}
+
+impl<'a> NotificationManager<'a> {
+    pub fn new(pool: &'a PgPool) -> Self {
+        Self { pool }
+    }
+
+    /// Send a notification to the user. It's delivered by `NOTIFIER` in background after inserted.
+    pub async fn create(&self, uid: i32, title: &str, content: Option<&str>) -> Result<i32> {
+        let mut tx = begin(self.pool).await?;
+        let notification = create_in(&mut tx, uid, title, content).await?;
+        tx.commit().await?;
+
+        let id = notification.id;
+        dispatch(notification);
+        Ok(id)
+    }
+
+    /// Mark the notification read.
+    pub async fn mark_read(&self, uid: i32, id: i32) -> Result<()> {
+        sqlx::query("UPDATE public.notifications SET is_read = true WHERE id = $1 AND uid = $2")
+            .bind(id)
+            .bind(uid)
+            .execute(self.pool)
+            .await?;
+
+        invalidate_unread_count(uid);
+        Ok(())
+    }
+
+    /// List notifications of the user whose id is greater than `after`, in order of id.
+    pub async fn list_after(&self, uid: i32, after: i32, limit: i64) -> Result<Vec<Notification>> {
+        let notifications = sqlx::query_as(
+            "SELECT id, uid, title, content, is_read, create_time FROM public.notifications
+                WHERE uid = $1 AND id > $2 ORDER BY id LIMIT $3",
+        )
+        .bind(uid)
+        .bind(after)
+        .bind(limit)
+        .fetch_all(self.pool)
+        .await?;
+        Ok(notifications)
+    }
+
+    /// Id of the latest notification of the user, or 0 if none.
+    pub async fn latest_id(&self, uid: i32) -> Result<i32> {
+        let (id,): (i32,) =
+            sqlx::query_as("SELECT COALESCE(MAX(id), 0) FROM public.notifications WHERE uid = $1")
+                .bind(uid)
+                .fetch_one(self.pool)
+                .await?;
+        Ok(id)
+    }
+
+    /// Get unread notification count. The result is cached for a short while.
+    pub async fn count_unread(&self, uid: i32) -> Result<i64> {
+        if let Some(count) = UNREAD_COUNT.get(&uid) {
+            return Ok(count);
+        }
+        let (count,): (i64,) = sqlx::query_as(
+            "SELECT COUNT(*) FROM public.notifications WHERE uid = $1 AND is_read = false",
+        )
+        .bind(uid)
+        .fetch_one(self.pool)
+        .await?;
+
+        UNREAD_COUNT.insert(uid, count);
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{dispatch, invalidate_unread_count, subscribe, Notification, UNREAD_COUNT};
+
+    #[test]
+    pub fn test_unread_count_invalidation() {
+        // Count cached after the first query.
+        UNREAD_COUNT.insert(1, 2);
+        assert_eq!(UNREAD_COUNT.get(&1), Some(2));
+        // Inserting or marking read invalidates the cached count, so it will be queried again.
+        invalidate_unread_count(1);
+        assert_eq!(UNREAD_COUNT.get(&1), None);
+    }
+
+    #[tokio::test]
+    pub async fn test_dispatch_out_of_actix() {
+        let mut created = subscribe();
+        let notification = Notification {
+            id: 1,
+            uid: 2,
+            title: "电费余额不足".to_string(),
+            content: None,
+            is_read: false,
+            create_time: chrono::Local::now().naive_local(),
+        };
+        // Dispatched in a plain tokio task, as balance polling does, without an actix system.
+        tokio::spawn(async move { dispatch(notification) }).await.unwrap();
+        assert_eq!(created.recv().await.unwrap().uid, 2);
+    }
+}
@@ -0,0 +1,127 @@
+use super::Notification;
+use crate::config::CONFIG;
+use crate::error::{ApiError, Result};
+use actix_web::client::Client;
+use futures::future::{ok, LocalBoxFuture};
+use log::{error, warn};
+use std::time::Duration;
+
+/// Max retry count when delivery failed.
+const DELIVERY_RETRY_COUNT: u32 = 3;
+
+lazy_static! {
+    /// Notifier selected by config.
+    pub static ref NOTIFIER: Box<dyn Notifier> = notifier_from_config();
+}
+
+/// Deliver notifications to users by push, email, webhook and so on.
+pub trait Notifier: Send + Sync {
+    fn send<'a>(&'a self, notification: &'a Notification) -> LocalBoxFuture<'a, Result<()>>;
+}
+
+/// Notifier which does nothing.
+pub struct NoopNotifier;
+
+impl Notifier for NoopNotifier {
+    fn send<'a>(&'a self, _: &'a Notification) -> LocalBoxFuture<'a, Result<()>> {
+        Box::pin(ok(()))
+    }
+}
+
+/// Notifier which posts the notification in JSON to a configured url.
+pub struct WebhookNotifier {
+    url: String,
+}
+
+impl WebhookNotifier {
+    pub fn new(url: &str) -> Self {
+        Self { url: url.to_string() }
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn send<'a>(&'a self, notification: &'a Notification) -> LocalBoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let response = Client::default()
+                .post(&self.url)
+                .send_json(notification)
+                .await
+                .map_err(|e| ApiError::from(format!("While posting to webhook: {}", e)))?;
+
+            if !response.status().is_success() {
+                return Err(ApiError::from(format!("Webhook responded {}", response.status())));
+            }
+            Ok(())
+        })
+    }
+}
+
+fn notifier_from_config() -> Box<dyn Notifier> {
+    match &CONFIG.notifier.webhook {
+        Some(url) => Box::new(WebhookNotifier::new(url)),
+        None => Box::new(NoopNotifier),
+    }
+}
+
+/// Deliver the notification, and retry with backoff if failed.
+pub async fn deliver(notifier: &dyn Notifier, notification: &Notification) {
+    for retry_count in 0..=DELIVERY_RETRY_COUNT {
+        match notifier.send(notification).await {
+            Ok(_) => return,
+            Err(e) => warn!(
+                "Failed to deliver notification {} (try {}): {}",
+                notification.id, retry_count, e
+            ),
+        }
+        tokio::time::delay_for(Duration::from_millis(500 << retry_count)).await;
+    }
+    error!(
+        "Gave up delivering notification {} to user {}.",
+        notification.id, notification.uid
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::Utc;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    #[test]
+    pub fn test_webhook_notifier() {
+        // Mock webhook receiver which accepts one request.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let receiver = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0u8; 1024];
+            while !String::from_utf8_lossy(&request).ends_with('}') {
+                let n = stream.read(&mut buffer).unwrap();
+                request.extend_from_slice(&buffer[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let notification = Notification {
+            id: 1,
+            uid: 10,
+            title: "Hello".to_string(),
+            content: None,
+            is_read: false,
+            create_time: Utc::now().naive_local(),
+        };
+        let notifier = WebhookNotifier::new(&url);
+        actix_web::rt::System::new("test")
+            .block_on(async move { notifier.send(&notification).await })
+            .unwrap();
+
+        let request = receiver.join().unwrap();
+        assert!(request.starts_with("POST /hook"));
+        assert!(request.contains("\"title\":\"Hello\""));
+    }
+}
@@ -1,5 +1,14 @@
+mod live;
+mod subscription;
+
 use crate::error::{ApiError, Result};
 use chrono::{DateTime, Local, NaiveDateTime};
+use futures::{stream, StreamExt};
+use std::collections::BTreeMap;
+use std::future::Future;
+
+pub use live::BalanceFeed;
+pub use subscription::{watch_balances, SubscriptionManager};
 
 #[derive(serde::Serialize, sqlx::FromRow)]
 /// Electricity Balance for FengXian dormitory.
@@ -57,6 +66,32 @@ pub enum BalanceError {
     NoSuchRoom = 200,
 }
 
+/// Max rooms in a batch query.
+pub const MAX_BATCH_ROOMS: usize = 100;
+/// Rooms of a batch queried at the same time, so that a batch doesn't take up the database pool.
+const BATCH_CONCURRENCY: usize = 8;
+
+/// Fetch each room with at most `BATCH_CONCURRENCY` fetches at a time. Each room has its own
+/// result, so that a failing room doesn't fail the others. Duplicated rooms are fetched once.
+pub async fn fetch_each<T, F, Fut>(rooms: &[i32], fetch: F) -> BTreeMap<i32, Result<T>>
+where
+    F: Fn(i32) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut rooms = rooms.to_vec();
+    rooms.sort_unstable();
+    rooms.dedup();
+
+    stream::iter(rooms)
+        .map(|room| {
+            let fut = fetch(room);
+            async move { (room, fut.await) }
+        })
+        .buffer_unordered(BATCH_CONCURRENCY)
+        .collect()
+        .await
+}
+
 impl<'a> BalanceManager<'a> {
     pub fn new(db: &'a sqlx::PgPool) -> Self {
         Self { db }
@@ -74,6 +109,28 @@ impl<'a> BalanceManager<'a> {
         balance.ok_or(ApiError::new(BalanceError::NoSuchRoom))
     }
 
+    /// Query the last balance of each room, see `fetch_each`.
+    pub async fn query_last_balances(self, rooms: &[i32]) -> BTreeMap<i32, Result<ElectricityBalance>> {
+        let db = self.db;
+        fetch_each(rooms, |room| BalanceManager::new(db).query_last_balance(room)).await
+    }
+
+    /// Query balance snapshots of the room in last `days` days, in time order.
+    pub async fn query_balance_history(self, room: i32, days: i32) -> Result<Vec<ElectricityBalance>> {
+        let history = sqlx::query_as(
+            "SELECT room, total_balance AS balance, CAST(total_balance / 0.6 AS real) AS power, ts
+                FROM dormitory.balance
+                WHERE room = $1 AND ts >= now() - make_interval(days => $2)
+                ORDER BY ts",
+        )
+        .bind(room)
+        .bind(days)
+        .fetch_all(self.db)
+        .await?;
+
+        Ok(history)
+    }
+
     pub async fn query_statistics_by_day(
         self,
         room: i32,
@@ -133,3 +190,45 @@ impl<'a> BalanceManager<'a> {
         rank.ok_or(ApiError::new(BalanceError::NoSuchRoom))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::CommonError;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_fetch_each() {
+        let (running, most) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        let rooms: Vec<i32> = (101101..101121).chain(vec![101101, 0]).collect();
+
+        let results = fetch_each(&rooms, |room| {
+            let (running, most) = (&running, &most);
+            async move {
+                most.fetch_max(running.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                tokio::time::delay_for(std::time::Duration::from_millis(1)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                match room {
+                    0 => Err(ApiError::new(CommonError::Parameter)),
+                    room if room % 2 == 0 => Err(ApiError::new(BalanceError::NoSuchRoom)),
+                    room => Ok(room * 10),
+                }
+            }
+        })
+        .await;
+
+        // Duplicated rooms are fetched once, and failing rooms don't fail the others.
+        assert_eq!(results.len(), 21);
+        assert_eq!(results[&101101].as_ref().unwrap(), &1011010);
+        assert_eq!(
+            results[&101102].as_ref().unwrap_err().code,
+            BalanceError::NoSuchRoom as u16
+        );
+        assert_eq!(
+            results[&0].as_ref().unwrap_err().code,
+            CommonError::Parameter as u16
+        );
+        assert_eq!(results.values().filter(|x| x.is_ok()).count(), 10);
+        assert!(most.load(Ordering::SeqCst) <= BATCH_CONCURRENCY);
+    }
+}
@@ -0,0 +1,144 @@
+//! Live balance updates of rooms watched by clients. Balances of watched rooms are polled along
+//! with subscribed ones, and pushed to watchers once they change.
+
+use super::{BalanceManager, ElectricityBalance};
+use chrono::{DateTime, Local};
+use log::error;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+/// Watchers of a room, and the balance last pushed.
+#[derive(Default)]
+struct WatchedRoom {
+    watchers: HashMap<u64, UnboundedSender<String>>,
+    last: Option<(f32, DateTime<Local>)>,
+}
+
+/// Rooms watched, shared by websocket sessions and the poller.
+#[derive(Default)]
+pub struct BalanceFeed {
+    rooms: Mutex<HashMap<i32, WatchedRoom>>,
+    next_id: AtomicU64,
+}
+
+impl BalanceFeed {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Watch the room. Balances in JSON are received once changed, until unsubscribed with the
+    /// returned id.
+    pub fn subscribe(&self, room: i32) -> (u64, UnboundedReceiver<String>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = unbounded_channel();
+
+        self.rooms
+            .lock()
+            .unwrap()
+            .entry(room)
+            .or_default()
+            .watchers
+            .insert(id, sender);
+        (id, receiver)
+    }
+
+    pub fn unsubscribe(&self, room: i32, id: u64) {
+        let mut rooms = self.rooms.lock().unwrap();
+
+        if let Some(watched) = rooms.get_mut(&room) {
+            watched.watchers.remove(&id);
+            if watched.watchers.is_empty() {
+                rooms.remove(&room);
+            }
+        }
+    }
+
+    /// Rooms being watched.
+    pub fn rooms(&self) -> Vec<i32> {
+        self.rooms.lock().unwrap().keys().copied().collect()
+    }
+
+    /// Push the balance to watchers of the room if it changed since last pushed. Return the
+    /// count of watchers pushed.
+    pub fn publish(&self, balance: &ElectricityBalance) -> usize {
+        let mut rooms = self.rooms.lock().unwrap();
+        let watched = match rooms.get_mut(&balance.room) {
+            Some(watched) => watched,
+            None => return 0,
+        };
+        let current = Some((balance.balance, balance.ts));
+        if watched.last == current {
+            return 0;
+        }
+        watched.last = current;
+
+        let message = serde_json::to_string(balance).unwrap_or_default();
+        // Watchers whose sessions are gone are dropped.
+        watched
+            .watchers
+            .retain(|_, sender| sender.send(message.clone()).is_ok());
+        watched.watchers.len()
+    }
+
+    /// Query balances of watched rooms, and push changed ones.
+    pub async fn poll(&self, pool: &PgPool) {
+        let balances = BalanceManager::new(pool).query_last_balances(&self.rooms()).await;
+
+        for (room, balance) in balances {
+            match balance {
+                Ok(balance) => {
+                    self.publish(&balance);
+                }
+                Err(e) => error!("Failed to query balance of room {}: {}", room, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn balance(balance: f32, ts: i64) -> ElectricityBalance {
+        ElectricityBalance {
+            room: 10101,
+            balance,
+            power: balance / 0.6,
+            ts: Local.timestamp(ts, 0),
+        }
+    }
+
+    #[test]
+    pub fn test_push_on_change() {
+        let feed = BalanceFeed::new();
+        let (id, mut receiver) = feed.subscribe(10101);
+
+        assert_eq!(feed.publish(&balance(10.0, 1_600_000_000)), 1);
+        let message: serde_json::Value = serde_json::from_str(&receiver.try_recv().unwrap()).unwrap();
+        assert_eq!(message["room"], 10101);
+        assert_eq!(message["balance"], 10.0);
+        // Unchanged.
+        assert_eq!(feed.publish(&balance(10.0, 1_600_000_000)), 0);
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(feed.publish(&balance(9.5, 1_600_003_600)), 1);
+        assert!(receiver.try_recv().is_ok());
+
+        feed.unsubscribe(10101, id);
+        assert!(feed.rooms().is_empty());
+        assert_eq!(feed.publish(&balance(9.0, 1_600_007_200)), 0);
+    }
+
+    #[test]
+    pub fn test_drop_closed_watcher() {
+        let feed = BalanceFeed::new();
+        let (_, receiver) = feed.subscribe(10101);
+        let (_, _alive) = feed.subscribe(10101);
+
+        drop(receiver);
+        assert_eq!(feed.publish(&balance(10.0, 1_600_000_000)), 1);
+    }
+}
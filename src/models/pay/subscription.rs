@@ -0,0 +1,262 @@
+//! Low balance alerts of rooms subscribed by users. Balances are checked periodically, and an
+//! alert is sent once the balance drops below the threshold, until it's recharged above again.
+
+use super::{BalanceFeed, BalanceManager};
+use crate::error::Result;
+use crate::models::notification::NotificationManager;
+use chrono::{DateTime, Local};
+use log::{error, info};
+use serde::Serialize;
+use sqlx::PgPool;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Subscribed room with its latest balance.
+#[derive(sqlx::FromRow)]
+struct WatchedRoom {
+    uid: i32,
+    room: i32,
+    threshold: f32,
+    /// Whether an alert is sent in the current low-balance episode.
+    alerted: bool,
+    balance: f32,
+}
+
+/// Pending low balance alert.
+#[derive(Serialize, sqlx::FromRow)]
+pub struct LowBalanceAlert {
+    pub room: i32,
+    pub threshold: f32,
+    /// Latest balance.
+    pub balance: f32,
+    /// Last update time of balance.
+    pub ts: DateTime<Local>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AlertAction {
+    None,
+    /// Balance drops below the threshold, send an alert.
+    Alert,
+    /// Balance is recharged, so that the next drop is alerted again.
+    Reset,
+}
+
+/// Decide what to do with a subscription by its latest balance.
+pub fn check_balance(balance: f32, threshold: f32, alerted: bool) -> AlertAction {
+    match (balance < threshold, alerted) {
+        (true, false) => AlertAction::Alert,
+        (false, true) => AlertAction::Reset,
+        _ => AlertAction::None,
+    }
+}
+
+pub struct SubscriptionManager<'a> {
+    pool: &'a PgPool,
+}
+
+impl<'a> SubscriptionManager<'a> {
+    pub fn new(pool: &'a PgPool) -> Self {
+        Self { pool }
+    }
+
+    /// Subscribe the room, or update the threshold if subscribed already.
+    pub async fn subscribe(&self, uid: i32, room: i32, threshold: f32) -> Result<()> {
+        // Make sure the room exists.
+        BalanceManager::new(self.pool).query_last_balance(room).await?;
+
+        sqlx::query(
+            "INSERT INTO dormitory.balance_subscription (uid, room, threshold) VALUES ($1, $2, $3)
+                ON CONFLICT (uid, room) DO UPDATE SET threshold = $3, alerted = false",
+        )
+        .bind(uid)
+        .bind(room)
+        .bind(threshold)
+        .execute(self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Get alerts of subscribed rooms which are still low in balance.
+    pub async fn list_alerts(&self, uid: i32) -> Result<Vec<LowBalanceAlert>> {
+        let alerts = sqlx::query_as(
+            "SELECT s.room, s.threshold, b.total_balance AS balance, b.ts
+                FROM dormitory.balance_subscription s,
+                LATERAL (
+                    SELECT total_balance, ts FROM dormitory.balance
+                    WHERE room = s.room ORDER BY ts DESC LIMIT 1
+                ) b
+                WHERE s.uid = $1 AND s.alerted = true",
+        )
+        .bind(uid)
+        .fetch_all(self.pool)
+        .await?;
+
+        Ok(alerts)
+    }
+
+    async fn set_alerted(&self, uid: i32, room: i32, alerted: bool) -> Result<()> {
+        sqlx::query(
+            "UPDATE dormitory.balance_subscription SET alerted = $3 WHERE uid = $1 AND room = $2",
+        )
+        .bind(uid)
+        .bind(room)
+        .bind(alerted)
+        .execute(self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Check latest balances of all subscribed rooms, and alert the users.
+    pub async fn check_all(&self) -> Result<()> {
+        let rooms: Vec<WatchedRoom> = sqlx::query_as(
+            "SELECT s.uid, s.room, s.threshold, s.alerted, b.total_balance AS balance
+                FROM dormitory.balance_subscription s,
+                LATERAL (
+                    SELECT total_balance FROM dormitory.balance
+                    WHERE room = s.room ORDER BY ts DESC LIMIT 1
+                ) b",
+        )
+        .fetch_all(self.pool)
+        .await?;
+
+        for room in rooms {
+            match check_balance(room.balance, room.threshold, room.alerted) {
+                AlertAction::Alert => {
+                    let content = format!(
+                        "房间 {} 的电费余额为 {:.2} 元, 已低于您设置的 {:.2} 元, 请及时充值.",
+                        room.room, room.balance, room.threshold
+                    );
+                    NotificationManager::new(self.pool)
+                        .create(room.uid, "电费余额不足", Some(&content))
+                        .await?;
+                    self.set_alerted(room.uid, room.room, true).await?;
+                }
+                AlertAction::Reset => self.set_alerted(room.uid, room.room, false).await?,
+                AlertAction::None => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check subscribed balances and push balances of rooms watched in `feed` every `interval`, until
+/// the process exits.
+pub async fn watch_balances(pool: PgPool, feed: Arc<BalanceFeed>, interval: Duration) {
+    info!("Watching subscribed room balances every {:?}.", interval);
+    loop {
+        tokio::time::delay_for(interval).await;
+
+        poll_balances(&pool, &feed).await;
+    }
+}
+
+/// One cycle of `watch_balances`.
+async fn poll_balances(pool: &PgPool, feed: &BalanceFeed) {
+    if let Err(e) = SubscriptionManager::new(pool).check_all().await {
+        error!("Failed to check subscribed balances: {}", e);
+    }
+    feed.poll(pool).await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_balance, poll_balances, AlertAction, BalanceFeed, SubscriptionManager};
+    use crate::models::test::db_pool;
+
+    #[test]
+    pub fn test_check_balance() {
+        // Alerted once in a low-balance episode.
+        assert_eq!(check_balance(5.0, 10.0, false), AlertAction::Alert);
+        assert_eq!(check_balance(4.0, 10.0, true), AlertAction::None);
+        // Recharged, and alerted again on the next drop.
+        assert_eq!(check_balance(50.0, 10.0, true), AlertAction::Reset);
+        assert_eq!(check_balance(50.0, 10.0, false), AlertAction::None);
+        assert_eq!(check_balance(9.0, 10.0, false), AlertAction::Alert);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    pub async fn test_check_all_out_of_actix() {
+        let pool = db_pool().await;
+        sqlx::query(
+            "INSERT INTO dormitory.balance (room, total_balance, ts) VALUES (99001, 3.0, now())",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query(
+            "INSERT INTO dormitory.balance_subscription (uid, room, threshold) VALUES (99001, 99001, 10.0)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        // Balances are checked in a plain tokio task, where alerts must not spawn local tasks.
+        let task_pool = pool.clone();
+        let result = tokio::spawn(async move { SubscriptionManager::new(&task_pool).check_all().await })
+            .await
+            .unwrap();
+        let alerted = SubscriptionManager::new(&pool).list_alerts(99001).await;
+
+        sqlx::query("DELETE FROM dormitory.balance_subscription WHERE uid = 99001")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM dormitory.balance WHERE room = 99001")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM public.notifications WHERE uid = 99001")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(result.is_ok());
+        assert_eq!(alerted.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    pub async fn test_alert_and_push_in_one_cycle() {
+        let pool = db_pool().await;
+        sqlx::query(
+            "INSERT INTO dormitory.balance (room, total_balance, ts) VALUES (99002, 3.0, now())",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query(
+            "INSERT INTO dormitory.balance_subscription (uid, room, threshold) VALUES (99002, 99002, 10.0)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let feed = BalanceFeed::new();
+        let (_, mut pushed) = feed.subscribe(99002);
+
+        // The low-balance alert doesn't stop the balance pushed in the same cycle.
+        let task_pool = pool.clone();
+        let (alerted, pushed) = tokio::spawn(async move {
+            poll_balances(&task_pool, &feed).await;
+            let alerted = SubscriptionManager::new(&task_pool).list_alerts(99002).await;
+            (alerted, pushed.try_recv())
+        })
+        .await
+        .unwrap();
+
+        sqlx::query("DELETE FROM dormitory.balance_subscription WHERE uid = 99002")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM dormitory.balance WHERE room = 99002")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM public.notifications WHERE uid = 99002")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(alerted.unwrap().len(), 1);
+        assert!(pushed.unwrap().contains("99002"));
+    }
+}
@@ -0,0 +1,277 @@
+//! This module provides counters for rate limits and quotas. Counters are stored in memory by default,
+//! or in the database so that they can be shared across server instances and survive restarts.
+use crate::clock;
+use crate::error::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Counter store for rate limits and quotas.
+#[derive(Clone)]
+pub enum QuotaStore {
+    /// Counters in memory: key -> (window, count).
+    Memory(Arc<Mutex<HashMap<String, (String, i64)>>>),
+    /// Counters in table public.quota_counter.
+    Database(PgPool),
+}
+
+/// Counter of a key in a window.
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct QuotaCounter {
+    /// Counter key.
+    pub key: String,
+    /// Window key, like "2020-09-01" for daily quotas.
+    #[serde(rename = "window")]
+    pub window_key: String,
+    /// Hit count in the window.
+    pub count: i64,
+}
+
+/// Prefix of the keys of counters belong to the user. Keys are like "user:10:upload".
+pub fn user_key_prefix(uid: i32) -> String {
+    format!("user:{}:", uid)
+}
+
+/// Window key of the current day, like "2020-09-01".
+pub fn daily_window() -> String {
+    clock::today().format("%Y-%m-%d").to_string()
+}
+
+/// Index of the fixed window of the length at the time, counted from the Unix epoch. Its string
+/// is the window key of rate limits.
+pub fn window_index(now: DateTime<Utc>, length: Duration) -> i64 {
+    now.timestamp_millis() / (length.as_millis() as i64).max(1)
+}
+
+/// Time left before the window at the time ends.
+pub fn window_remaining(now: DateTime<Utc>, length: Duration) -> Duration {
+    let length = (length.as_millis() as i64).max(1);
+    let elapsed = now.timestamp_millis().rem_euclid(length);
+    Duration::from_millis((length - elapsed) as u64)
+}
+
+impl QuotaStore {
+    pub fn memory() -> Self {
+        QuotaStore::Memory(Default::default())
+    }
+
+    pub fn database(pool: PgPool) -> Self {
+        QuotaStore::Database(pool)
+    }
+
+    /// Increase the counter of the key in the window, and return whether it's still within the limit.
+    pub async fn hit(&self, key: &str, window: &str, limit: i64) -> Result<bool> {
+        let count = match self {
+            QuotaStore::Memory(counters) => {
+                let mut counters = counters.lock().unwrap();
+                let counter = counters
+                    .entry(key.to_string())
+                    .or_insert_with(|| (window.to_string(), 0));
+                // Reset the counter when a new window comes.
+                if counter.0 != window {
+                    *counter = (window.to_string(), 0);
+                }
+                counter.1 += 1;
+                counter.1
+            }
+            QuotaStore::Database(pool) => {
+                let (count,): (i64,) = sqlx::query_as(
+                    "INSERT INTO public.quota_counter (key, window_key, count) VALUES ($1, $2, 1)
+                        ON CONFLICT (key, window_key)
+                        DO UPDATE SET count = quota_counter.count + 1
+                        RETURNING count",
+                )
+                .bind(key)
+                .bind(window)
+                .fetch_one(pool)
+                .await?;
+                // A new window begins, and past ones are of no use, like in memory.
+                if count == 1 {
+                    sqlx::query("DELETE FROM public.quota_counter WHERE key = $1 AND window_key <> $2")
+                        .bind(key)
+                        .bind(window)
+                        .execute(pool)
+                        .await?;
+                }
+                count
+            }
+        };
+        Ok(count <= limit)
+    }
+
+    /// Get the counter of the key in the window.
+    pub async fn get(&self, key: &str, window: &str) -> Result<i64> {
+        match self {
+            QuotaStore::Memory(counters) => {
+                let counters = counters.lock().unwrap();
+                Ok(counters
+                    .get(key)
+                    .filter(|x| x.0 == window)
+                    .map(|x| x.1)
+                    .unwrap_or_default())
+            }
+            QuotaStore::Database(pool) => {
+                let count: Option<(i64,)> = sqlx::query_as(
+                    "SELECT count FROM public.quota_counter WHERE key = $1 AND window_key = $2",
+                )
+                .bind(key)
+                .bind(window)
+                .fetch_optional(pool)
+                .await?;
+                Ok(count.map(|x| x.0).unwrap_or_default())
+            }
+        }
+    }
+
+    /// List counters whose key starts with the prefix.
+    pub async fn list(&self, prefix: &str) -> Result<Vec<QuotaCounter>> {
+        match self {
+            QuotaStore::Memory(counters) => {
+                let counters = counters.lock().unwrap();
+                let mut result: Vec<QuotaCounter> = counters
+                    .iter()
+                    .filter(|(key, _)| key.starts_with(prefix))
+                    .map(|(key, (window, count))| QuotaCounter {
+                        key: key.clone(),
+                        window_key: window.clone(),
+                        count: *count,
+                    })
+                    .collect();
+                result.sort_by(|a, b| a.key.cmp(&b.key));
+                Ok(result)
+            }
+            QuotaStore::Database(pool) => {
+                let counters = sqlx::query_as(
+                    "SELECT key, window_key, count FROM public.quota_counter
+                        WHERE left(key, length($1)) = $1
+                        ORDER BY key, window_key",
+                )
+                .bind(prefix)
+                .fetch_all(pool)
+                .await?;
+                Ok(counters)
+            }
+        }
+    }
+
+    /// Clear counters whose key starts with the prefix.
+    pub async fn clear_prefix(&self, prefix: &str) -> Result<()> {
+        match self {
+            QuotaStore::Memory(counters) => {
+                counters.lock().unwrap().retain(|key, _| !key.starts_with(prefix));
+            }
+            QuotaStore::Database(pool) => {
+                sqlx::query("DELETE FROM public.quota_counter WHERE left(key, length($1)) = $1")
+                    .bind(prefix)
+                    .execute(pool)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Clear all the counters of the key.
+    pub async fn clear(&self, key: &str) -> Result<()> {
+        match self {
+            QuotaStore::Memory(counters) => {
+                counters.lock().unwrap().remove(key);
+            }
+            QuotaStore::Database(pool) => {
+                sqlx::query("DELETE FROM public.quota_counter WHERE key = $1")
+                    .bind(key)
+                    .execute(pool)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::test::db_pool;
+    use chrono::TimeZone;
+
+    #[tokio::test]
+    async fn test_memory_quota() {
+        let store = QuotaStore::memory();
+
+        assert!(store.hit("user:1", "2020-09-01", 2).await.unwrap());
+        assert!(store.hit("user:1", "2020-09-01", 2).await.unwrap());
+        assert!(!store.hit("user:1", "2020-09-01", 2).await.unwrap());
+        // Counter is reset in a new window.
+        assert!(store.hit("user:1", "2020-09-02", 2).await.unwrap());
+        assert_eq!(store.get("user:1", "2020-09-02").await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_memory_quota_shared_by_clones() {
+        // Each worker holds a clone of the app state.
+        let store = QuotaStore::memory();
+        let worker = store.clone();
+
+        assert!(store.hit("user:1", "2020-09-01", 1).await.unwrap());
+        assert!(!worker.hit("user:1", "2020-09-01", 1).await.unwrap());
+        assert_eq!(store.get("user:1", "2020-09-01").await.unwrap(), 2);
+        // Another window, or another key, is not counted.
+        assert_eq!(store.get("user:1", "2020-09-02").await.unwrap(), 0);
+        assert_eq!(worker.get("user:2", "2020-09-01").await.unwrap(), 0);
+        // No hit is allowed with a zero limit.
+        assert!(!store.hit("user:2", "2020-09-01", 0).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_clear_user_quota() {
+        let store = QuotaStore::memory();
+        let key = format!("{}upload", user_key_prefix(1));
+
+        assert!(store.hit(&key, "2020-09-01", 1).await.unwrap());
+        assert!(!store.hit(&key, "2020-09-01", 1).await.unwrap());
+        store.hit("user:10:upload", "2020-09-01", 1).await.unwrap();
+
+        let counters = store.list(&user_key_prefix(1)).await.unwrap();
+        assert_eq!(counters.len(), 1);
+        assert_eq!(counters[0].count, 2);
+
+        store.clear_prefix(&user_key_prefix(1)).await.unwrap();
+        assert!(store.hit(&key, "2020-09-01", 1).await.unwrap());
+        // Counters of other users are kept.
+        assert_eq!(store.get("user:10:upload", "2020-09-01").await.unwrap(), 1);
+    }
+
+    #[test]
+    fn test_fixed_window() {
+        let length = Duration::from_secs(60);
+        let now = Utc.timestamp(600, 0);
+
+        assert_eq!(window_index(now, length), 10);
+        assert_eq!(window_remaining(now, length), length);
+        let later = now + chrono::Duration::milliseconds(59_500);
+        assert_eq!(window_index(later, length), 10);
+        assert_eq!(window_remaining(later, length), Duration::from_millis(500));
+        assert_eq!(window_index(now + chrono::Duration::seconds(60), length), 11);
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    async fn test_database_quota_shared() {
+        // Two instances, each with its own pool.
+        let store1 = QuotaStore::database(db_pool().await);
+        let store2 = QuotaStore::database(db_pool().await);
+        let window = daily_window();
+
+        store1.clear("test:quota").await.unwrap();
+        assert!(store1.hit("test:quota", &window, 2).await.unwrap());
+        assert!(store2.hit("test:quota", &window, 2).await.unwrap());
+        assert!(!store1.hit("test:quota", &window, 2).await.unwrap());
+        assert!(!store2.hit("test:quota", &window, 2).await.unwrap());
+        // Counters of past windows are removed once a new one begins.
+        assert!(store1.hit("test:quota", "next", 2).await.unwrap());
+        assert_eq!(store2.get("test:quota", &window).await.unwrap(), 0);
+        store2.clear("test:quota").await.unwrap();
+    }
+}
@@ -32,7 +32,7 @@ pub async fn query_notice(pool: &PgPool, query: &str, page: &PageView) -> Result
             OFFSET $2 LIMIT $3;",
     )
     .bind(query)
-    .bind(page.offset(20) as i32)
+    .bind(page.offset(20))
     .bind(page.count(20) as i32)
     .fetch_all(pool)
     .await?;
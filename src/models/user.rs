@@ -1,13 +1,28 @@
 //! This module provides the ability to create, update and delete users including authentication tokens.
 
 mod authserver;
+pub mod batch;
+mod bootstrap;
+pub mod captcha;
+mod card;
+pub mod deletion;
+pub mod export;
 mod identity;
+pub mod lockout;
+pub mod login_audit;
 mod person;
+pub mod revocation;
+pub mod totp;
+pub mod validation;
+pub mod verification;
 pub(crate) mod wechat;
 
-use chrono::NaiveDateTime;
+use chrono::{DateTime, Local, NaiveDateTime};
 use serde::{Deserialize, Serialize};
 
+pub use bootstrap::ADMIN_BOOTSTRAP;
+pub use card::is_card_number;
+pub use identity::{is_student_id, MaskedIdentity};
 pub use person::get_default_avatar;
 
 /* Constants at the edge between self and database. */
@@ -17,7 +32,7 @@ pub const LOGIN_BY_WECHAT: i32 = 0;
 pub const LOGIN_BY_PASSWORD: i32 = 1;
 pub const LOGIN_BY_CAMPUS_WEB: i32 = 2;
 
-#[derive(thiserror::Error, Debug, ToPrimitive)]
+#[derive(thiserror::Error, Debug, ToPrimitive, FromPrimitive)]
 pub enum UserError {
     #[error("账户已禁用")]
     Disabled = 50,
@@ -33,6 +48,44 @@ pub enum UserError {
     AuthTypeNotAllowed = 55,
     #[error("凭据无效")]
     LoginFailed = 56,
+    #[error("学号格式错误")]
+    InvalidStudentId = 57,
+    #[error("不能使用默认密码, 请先在校园网修改")]
+    DefaultSecret = 58,
+    #[error("尚未实名认证")]
+    IdentityNeeded = 59,
+    #[error("无法连接微信服务")]
+    WechatNetworkFailed = 60,
+    #[error("验证码错误")]
+    VerifyCodeMismatch = 61,
+    #[error("验证码已失效, 请重新获取")]
+    VerifyCodeExpired = 62,
+    #[error("手机号或邮箱格式错误")]
+    InvalidContact = 63,
+    #[error("不能撤销、删除或禁用最后一个管理员")]
+    LastAdmin = 64,
+    #[error("用户名已被占用")]
+    AccountExists = 65,
+    #[error("找不到该校园卡号对应的学号")]
+    NoSuchStudentNo = 66,
+    #[error("图形验证码错误")]
+    CaptchaMismatch = 67,
+    #[error("图形验证码已失效, 请刷新")]
+    CaptchaExpired = 68,
+    #[error("需要两步验证码")]
+    TotpNeeded = 69,
+    #[error("两步验证码错误或已使用")]
+    TotpMismatch = 70,
+    #[error("已启用两步验证")]
+    TotpEnabled = 71,
+    #[error("尚未申请两步验证")]
+    TotpNotEnrolled = 72,
+    #[error("该学号不属于当前用户")]
+    IdentityNotOwned = 73,
+    #[error("该学号已被其他用户绑定")]
+    AlreadyBound = 74,
+    #[error("登录失败次数过多, 账户已临时锁定, 请稍后再试")]
+    AccountLocked = 75,
 }
 
 /* Models */
@@ -78,8 +131,11 @@ pub struct Person {
     #[serde(skip_serializing)]
     pub language: Option<String>,
     /// Account create time.
-    #[serde(rename = "createTime")]
+    #[serde(rename = "createTime", serialize_with = "crate::models::serialize_utc_time")]
     pub create_time: NaiveDateTime,
+    /// Last time the profile or role was changed.
+    #[serde(rename = "updateTime")]
+    pub updated_at: DateTime<Local>,
 }
 
 /// User real name and other personal information.
@@ -93,8 +149,8 @@ pub struct Identity {
     /// Student id
     #[serde(rename = "studentId")]
     pub student_id: String,
-    /// OA secret(password)
-    #[serde(rename = "oaSecret")]
+    /// OA secret(password), never serialized.
+    #[serde(rename = "oaSecret", skip_serializing)]
     pub oa_secret: Option<String>,
     /// Whether OA certified or not
     #[serde(rename = "oaCertified")]
@@ -102,4 +158,7 @@ pub struct Identity {
     /// ID card number
     #[serde(rename = "identityNumber")]
     pub identity_number: Option<String>,
+    /// Whether it's the identity used by default, among identities of the user.
+    #[serde(rename = "isPrimary")]
+    pub is_primary: bool,
 }
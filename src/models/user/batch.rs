@@ -0,0 +1,175 @@
+//! Create users in batch, like a freshman cohort. Users are created in one transaction, and each in
+//! a savepoint, so that bad rows are reported one by one without rolling back others.
+
+use super::validation::{check_account, check_nick_name, check_password};
+use super::{get_default_avatar, UserError, LOGIN_BY_PASSWORD};
+use crate::error::{ApiError, Result};
+use crate::models::begin;
+use chrono::Utc;
+use serde::Deserialize;
+use sqlx::{Connection, PgConnection, PgPool};
+
+/// User to create.
+#[derive(Debug, Deserialize)]
+pub struct NewUser {
+    #[serde(rename = "nickName")]
+    pub nick_name: String,
+    /// Username to log in with password.
+    pub account: Option<String>,
+    pub password: Option<String>,
+}
+
+impl NewUser {
+    /// Check the user with the rules of registration.
+    pub fn validate(&self) -> Result<()> {
+        check_nick_name(&self.nick_name)?;
+        if let Some(account) = &self.account {
+            check_account(account, self.password.as_deref())?;
+        }
+        if let Some(password) = &self.password {
+            check_password(password, self.account.as_deref())?;
+        }
+        Ok(())
+    }
+}
+
+/// Insert the user, and return the uid.
+async fn insert(conn: &mut PgConnection, user: &NewUser) -> Result<i32> {
+    user.validate()?;
+
+    let (uid,): (i32,) = sqlx::query_as(
+        "INSERT INTO public.person (nick_name, avatar, create_time) VALUES ($1, $2, $3) RETURNING uid",
+    )
+    .bind(user.nick_name.trim())
+    .bind(get_default_avatar())
+    .bind(Utc::now().naive_local())
+    .fetch_one(&mut *conn)
+    .await?;
+
+    if let (Some(account), Some(password)) = (&user.account, &user.password) {
+        let account = account.trim();
+        let taken: Option<(i32,)> = sqlx::query_as(
+            "SELECT uid FROM public.authentication WHERE login_type = $1 AND account = $2",
+        )
+        .bind(LOGIN_BY_PASSWORD)
+        .bind(account)
+        .fetch_optional(&mut *conn)
+        .await?;
+        if taken.is_some() {
+            return Err(ApiError::new(UserError::AccountExists));
+        }
+        sqlx::query(
+            "INSERT INTO public.authentication (uid, login_type, account, credential) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(uid)
+        .bind(LOGIN_BY_PASSWORD)
+        .bind(account)
+        .bind(password)
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(uid)
+}
+
+/// Create users, and return the uid or the error of each. In all-or-nothing mode, nothing is
+/// created if any of them fails.
+pub async fn create_users(
+    pool: &PgPool,
+    users: &[NewUser],
+    all_or_nothing: bool,
+) -> Result<Vec<Result<i32>>> {
+    let mut tx = begin(pool).await?;
+    let mut results = Vec::with_capacity(users.len());
+
+    for user in users {
+        let mut savepoint = tx.begin().await?;
+        let result = insert(&mut savepoint, user).await;
+
+        if result.is_ok() {
+            savepoint.commit().await?;
+        } else {
+            savepoint.rollback().await?;
+        }
+        results.push(result);
+    }
+
+    if all_or_nothing && results.iter().any(Result::is_err) {
+        tx.rollback().await?;
+    } else {
+        tx.commit().await?;
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::test::db_pool;
+    use crate::models::user::Person;
+
+    fn user(nick_name: &str, account: Option<&str>, password: Option<&str>) -> NewUser {
+        NewUser {
+            nick_name: nick_name.to_string(),
+            account: account.map(ToString::to_string),
+            password: password.map(ToString::to_string),
+        }
+    }
+
+    #[test]
+    pub fn test_validate() {
+        assert!(user("Alice", None, None).validate().is_ok());
+        assert!(user("Alice", Some("alice"), Some("p@ssw0rd")).validate().is_ok());
+        assert!(user(" ", None, None).validate().is_err());
+        // Account without password.
+        assert!(user("Alice", Some("alice"), None).validate().is_err());
+        assert!(user("Alice", None, Some("p@ssw0rd")).validate().is_err());
+        assert_eq!(
+            user("Alice", Some("alice"), Some("12345X")).validate(),
+            Err(ApiError::new(UserError::DefaultSecret))
+        );
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    async fn test_mixed_batch() {
+        let pool = db_pool().await;
+        let account = format!("batch-{}", uuid::Uuid::new_v4());
+        let users = vec![
+            user("Alice", Some(&account), Some("p@ssw0rd")),
+            user("Bob", Some("bob"), Some("123456")),
+            user("Carol", None, None),
+        ];
+        let count_accounts = || {
+            sqlx::query_as::<_, (i64,)>("SELECT COUNT(*) FROM public.authentication WHERE account = $1")
+                .bind(&account)
+        };
+
+        // All or nothing.
+        let results = create_users(&pool, &users, true).await.unwrap();
+        assert!(results[0].is_ok() && results[2].is_ok());
+        assert_eq!(results[1], Err(ApiError::new(UserError::DefaultSecret)));
+        assert_eq!(count_accounts().fetch_one(&pool).await.unwrap().0, 0);
+        let uid = *results[0].as_ref().unwrap();
+        assert!(Person::get(&pool, uid).await.is_err());
+
+        // Others are created despite the bad row.
+        let results = create_users(&pool, &users, false).await.unwrap();
+        assert_eq!(results[1], Err(ApiError::new(UserError::DefaultSecret)));
+        assert_eq!(count_accounts().fetch_one(&pool).await.unwrap().0, 1);
+        let uids: Vec<i32> = vec![*results[0].as_ref().unwrap(), *results[2].as_ref().unwrap()];
+        // The account is taken now.
+        let results = create_users(&pool, &users[..1], false).await.unwrap();
+        assert_eq!(results[0], Err(ApiError::new(UserError::AccountExists)));
+
+        sqlx::query("DELETE FROM public.authentication WHERE account = $1")
+            .bind(&account)
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM public.person WHERE uid = ANY($1)")
+            .bind(&uids)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,105 @@
+use super::person::update_authentication_in;
+use super::{Authentication, Person};
+use crate::error::Result;
+use crate::models::begin;
+use sqlx::PgPool;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+lazy_static! {
+    /// The bootstrap capability of the server process.
+    pub static ref ADMIN_BOOTSTRAP: AdminBootstrap = AdminBootstrap::new();
+}
+
+/// One-time capability to create the first administrator with a configured secret.
+pub struct AdminBootstrap {
+    used: AtomicBool,
+}
+
+/// The bootstrap capability taken by a request. It's given back on drop unless spent, so that a
+/// failed grant doesn't use up the secret.
+pub struct BootstrapPermit<'a> {
+    bootstrap: &'a AdminBootstrap,
+    spent: bool,
+}
+
+impl BootstrapPermit<'_> {
+    /// Use up the capability after the administrator is granted.
+    pub fn spend(mut self) {
+        self.spent = true;
+    }
+}
+
+impl Drop for BootstrapPermit<'_> {
+    fn drop(&mut self) {
+        if !self.spent {
+            self.bootstrap.used.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+impl AdminBootstrap {
+    pub fn new() -> Self {
+        Self {
+            used: AtomicBool::new(false),
+        }
+    }
+
+    /// Take the capability if the given secret matches the configured one and it's not used up.
+    /// Only one request holds it at a time.
+    pub fn acquire(&self, given: Option<&str>, configured: Option<&str>) -> Option<BootstrapPermit<'_>> {
+        match (given, configured) {
+            (Some(given), Some(configured)) if !configured.is_empty() && given == configured => {
+                match self.used.swap(true, Ordering::SeqCst) {
+                    true => None,
+                    false => Some(BootstrapPermit {
+                        bootstrap: self,
+                        spent: false,
+                    }),
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Person {
+    /// Bind authentication of the user, and grant the user administrator if there is no
+    /// administrator, in one transaction. Return whether it's granted.
+    pub async fn bind_first_admin(&self, client: &PgPool, auth: &Authentication) -> Result<bool> {
+        let mut tx = begin(client).await?;
+
+        update_authentication_in(&mut tx, self.uid, auth).await?;
+        let granted: Option<(i32,)> = sqlx::query_as(
+            "UPDATE public.person SET is_admin = true
+                WHERE uid = $1 AND NOT EXISTS (SELECT uid FROM public.person WHERE is_admin = true)
+                RETURNING uid",
+        )
+        .bind(self.uid)
+        .fetch_optional(&mut tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(granted.is_some())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AdminBootstrap;
+
+    #[test]
+    pub fn test_bootstrap_once() {
+        let bootstrap = AdminBootstrap::new();
+
+        assert!(bootstrap.acquire(Some("wrong"), Some("secret")).is_none());
+        assert!(bootstrap.acquire(Some(""), Some("")).is_none());
+        let permit = bootstrap.acquire(Some("secret"), Some("secret")).unwrap();
+        // Held by another request.
+        assert!(bootstrap.acquire(Some("secret"), Some("secret")).is_none());
+        // Given back if not spent, like when the bind failed.
+        drop(permit);
+        let permit = bootstrap.acquire(Some("secret"), Some("secret")).unwrap();
+        permit.spend();
+        assert!(bootstrap.acquire(Some("secret"), Some("secret")).is_none());
+    }
+}
@@ -0,0 +1,199 @@
+//! Image captchas required on registration, so that accounts can't be created by scripts easily.
+//! Captchas are kept in memory until answered or expired, and each can be answered only once.
+//! The store is bounded, and the oldest captchas are dropped first when it's full.
+
+mod image;
+
+use super::UserError;
+use crate::config::CONFIG;
+use crate::error::{ApiError, Result};
+use rand::seq::SliceRandom;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Characters of a captcha.
+const CAPTCHA_LENGTH: usize = 5;
+/// Max captchas kept, which take about 1 MB.
+const MAX_CAPTCHAS: usize = 10000;
+
+lazy_static! {
+    /// Captchas issued.
+    pub static ref CAPTCHAS: CaptchaStore =
+        CaptchaStore::new(Duration::from_secs(CONFIG.captcha.ttl), MAX_CAPTCHAS);
+}
+
+/// Captcha issued, with the answer drawn in the image.
+pub struct Captcha {
+    pub id: String,
+    /// Image in PNG.
+    pub image: Vec<u8>,
+}
+
+/// Answer of a captcha issued.
+struct Issued {
+    answer: String,
+    issue_time: Instant,
+}
+
+/// Captchas by id, and ids in the order issued, which is also the order to expire since all
+/// captchas live for the same time. Ids answered are left in the order until popped.
+#[derive(Default)]
+struct Captchas {
+    by_id: HashMap<String, Issued>,
+    order: VecDeque<(Instant, String)>,
+}
+
+pub struct CaptchaStore {
+    issued: Mutex<Captchas>,
+    ttl: Duration,
+    capacity: usize,
+}
+
+/// Generate an answer of `CAPTCHA_LENGTH` characters.
+fn generate_answer() -> String {
+    let mut rng = rand::thread_rng();
+
+    (0..CAPTCHA_LENGTH)
+        .map(|_| image::GLYPHS.choose(&mut rng).unwrap().0)
+        .collect()
+}
+
+impl CaptchaStore {
+    /// Create an empty store of at most `capacity` captchas, which expire after `ttl`.
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            issued: Default::default(),
+            ttl,
+            capacity,
+        }
+    }
+
+    /// Issue a new captcha.
+    pub fn issue(&self) -> Captcha {
+        let answer = generate_answer();
+        let image = image::draw(&answer);
+        let id = uuid::Uuid::new_v4().to_string();
+
+        let now = Instant::now();
+        let mut issued = self.issued.lock().unwrap();
+        // Expired ones are dropped here from the oldest, so that unanswered captchas don't pile up.
+        while let Some((issue_time, old_id)) = issued.order.front() {
+            if now.duration_since(*issue_time) < self.ttl && issued.order.len() < self.capacity {
+                break;
+            }
+            let old_id = old_id.clone();
+            issued.by_id.remove(&old_id);
+            issued.order.pop_front();
+        }
+        if self.capacity > 0 {
+            issued.order.push_back((now, id.clone()));
+            issued.by_id.insert(
+                id.clone(),
+                Issued {
+                    answer,
+                    issue_time: now,
+                },
+            );
+        }
+        Captcha { id, image }
+    }
+
+    /// Check the answer, case insensitively. The captcha is consumed even if the answer is wrong,
+    /// so that it can't be guessed repeatedly.
+    pub fn verify(&self, id: &str, answer: &str) -> Result<()> {
+        let issued = self.issued.lock().unwrap().by_id.remove(id);
+
+        match issued {
+            Some(x) if x.issue_time.elapsed() < self.ttl => {
+                if x.answer.eq_ignore_ascii_case(answer.trim()) {
+                    Ok(())
+                } else {
+                    Err(ApiError::new(UserError::CaptchaMismatch))
+                }
+            }
+            _ => Err(ApiError::new(UserError::CaptchaExpired)),
+        }
+    }
+
+    /// Answer of the captcha, for tests.
+    #[cfg(test)]
+    fn answer_of(&self, id: &str) -> String {
+        self.issued.lock().unwrap().by_id[id].answer.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_correct_answer() {
+        let store = CaptchaStore::new(Duration::from_secs(60), 10);
+        let captcha = store.issue();
+        let answer = store.answer_of(&captcha.id);
+
+        assert_eq!(answer.len(), CAPTCHA_LENGTH);
+        assert!(store.verify(&captcha.id, &answer.to_lowercase()).is_ok());
+        // Used once only.
+        assert_eq!(
+            store.verify(&captcha.id, &answer),
+            Err(ApiError::new(UserError::CaptchaExpired))
+        );
+    }
+
+    #[test]
+    pub fn test_wrong_answer() {
+        let store = CaptchaStore::new(Duration::from_secs(60), 10);
+        let captcha = store.issue();
+        let answer = store.answer_of(&captcha.id);
+
+        assert_eq!(
+            store.verify(&captcha.id, "wrong"),
+            Err(ApiError::new(UserError::CaptchaMismatch))
+        );
+        // Consumed by the wrong answer.
+        assert_eq!(
+            store.verify(&captcha.id, &answer),
+            Err(ApiError::new(UserError::CaptchaExpired))
+        );
+        assert_eq!(
+            store.verify("no-such-id", &answer),
+            Err(ApiError::new(UserError::CaptchaExpired))
+        );
+    }
+
+    #[test]
+    pub fn test_expired_captcha() {
+        let store = CaptchaStore::new(Duration::from_millis(20), 10);
+        let captcha = store.issue();
+        let answer = store.answer_of(&captcha.id);
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(
+            store.verify(&captcha.id, &answer),
+            Err(ApiError::new(UserError::CaptchaExpired))
+        );
+        // Expired ones are dropped on the next issue.
+        store.issue();
+        assert_eq!(store.issued.lock().unwrap().by_id.len(), 1);
+        assert_eq!(store.issued.lock().unwrap().order.len(), 1);
+    }
+
+    #[test]
+    pub fn test_store_capacity() {
+        let store = CaptchaStore::new(Duration::from_secs(60), 3);
+        let first = store.issue();
+        let answer = store.answer_of(&first.id);
+        let captchas: Vec<_> = (0..3).map(|_| store.issue()).collect();
+
+        // The oldest one is dropped for new ones.
+        assert_eq!(store.issued.lock().unwrap().by_id.len(), 3);
+        assert_eq!(
+            store.verify(&first.id, &answer),
+            Err(ApiError::new(UserError::CaptchaExpired))
+        );
+        let answer = store.answer_of(&captchas[2].id);
+        assert!(store.verify(&captchas[2].id, &answer).is_ok());
+    }
+}
@@ -0,0 +1,131 @@
+//! Draw captcha text in a grayscale PNG, with a 5x7 dot matrix font, random shear and noise.
+
+use crate::models::file::{encode_png, PNG_GRAYSCALE};
+use rand::Rng;
+
+pub const WIDTH: usize = 120;
+pub const HEIGHT: usize = 40;
+/// Pixels of a font cell.
+const SCALE: usize = 4;
+
+/// Glyphs of characters used in captchas. Each row is five bits, and the highest is the left.
+/// Characters easy to confuse, like `0` and `O`, are not included.
+pub const GLYPHS: &[(char, [u8; 7])] = &[
+    ('A', [0x0E, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11]),
+    ('B', [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E]),
+    ('C', [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E]),
+    ('D', [0x1E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x1E]),
+    ('E', [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F]),
+    ('F', [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10]),
+    ('G', [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F]),
+    ('H', [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11]),
+    ('J', [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C]),
+    ('K', [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11]),
+    ('L', [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F]),
+    ('M', [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11]),
+    ('N', [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11]),
+    ('P', [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10]),
+    ('Q', [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D]),
+    ('R', [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11]),
+    ('S', [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E]),
+    ('T', [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04]),
+    ('U', [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E]),
+    ('V', [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04]),
+    ('W', [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A]),
+    ('X', [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11]),
+    ('Y', [0x11, 0x11, 0x0A, 0x04, 0x04, 0x04, 0x04]),
+    ('Z', [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F]),
+    ('2', [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F]),
+    ('3', [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E]),
+    ('4', [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02]),
+    ('5', [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E]),
+    ('6', [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E]),
+    ('7', [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08]),
+    ('8', [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E]),
+    ('9', [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C]),
+];
+
+/// Grayscale pixels, row by row.
+struct Canvas {
+    pixels: Vec<u8>,
+}
+
+impl Canvas {
+    fn new() -> Self {
+        Self {
+            pixels: vec![255; WIDTH * HEIGHT],
+        }
+    }
+
+    fn set(&mut self, x: i32, y: i32, value: u8) {
+        if (0..WIDTH as i32).contains(&x) && (0..HEIGHT as i32).contains(&y) {
+            self.pixels[y as usize * WIDTH + x as usize] = value;
+        }
+    }
+
+    /// Draw the glyph with its top left at (x, y), sheared by `shear` pixels per row.
+    fn glyph(&mut self, rows: &[u8; 7], x: i32, y: i32, shear: i32, value: u8) {
+        for (row, bits) in rows.iter().enumerate() {
+            for column in 0..5 {
+                if bits & (0x10 >> column) == 0 {
+                    continue;
+                }
+                let left = x + (column * SCALE) as i32 + shear * row as i32;
+                let top = y + (row * SCALE) as i32;
+                for dy in 0..SCALE as i32 {
+                    for dx in 0..SCALE as i32 {
+                        self.set(left + dx, top + dy, value);
+                    }
+                }
+            }
+        }
+    }
+
+    fn line(&mut self, (x0, y0): (i32, i32), (x1, y1): (i32, i32), value: u8) {
+        let steps = (x1 - x0).abs().max((y1 - y0).abs()).max(1);
+        for i in 0..=steps {
+            self.set(x0 + (x1 - x0) * i / steps, y0 + (y1 - y0) * i / steps, value);
+        }
+    }
+}
+
+/// Draw the text, which consists of characters in `GLYPHS`, as a PNG image.
+pub fn draw(text: &str) -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let mut canvas = Canvas::new();
+    let step = (WIDTH as i32 - 12) / text.chars().count().max(1) as i32;
+
+    for _ in 0..WIDTH * HEIGHT / 12 {
+        let (x, y) = (rng.gen_range(0..WIDTH), rng.gen_range(0..HEIGHT));
+        canvas.set(x as i32, y as i32, rng.gen_range(120..230));
+    }
+    for (i, c) in text.chars().enumerate() {
+        if let Some((_, rows)) = GLYPHS.iter().find(|(x, _)| *x == c) {
+            let x = 6 + step * i as i32 + rng.gen_range(-2..=2);
+            let y = 6 + rng.gen_range(-4..=4);
+            canvas.glyph(rows, x, y, rng.gen_range(-1..=1), rng.gen_range(0..90));
+        }
+    }
+    for _ in 0..3 {
+        let from = (0, rng.gen_range(0..HEIGHT as i32));
+        let to = (WIDTH as i32 - 1, rng.gen_range(0..HEIGHT as i32));
+        canvas.line(from, to, rng.gen_range(40..120));
+    }
+    encode_png(WIDTH, HEIGHT, PNG_GRAYSCALE, &canvas.pixels)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_png_header() {
+        let png = draw("AB23");
+
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[16..20], &(WIDTH as u32).to_be_bytes());
+        assert_eq!(&png[20..24], &(HEIGHT as u32).to_be_bytes());
+        assert!(png.ends_with(&[0xAE, 0x42, 0x60, 0x82]));
+    }
+}
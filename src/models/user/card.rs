@@ -0,0 +1,93 @@
+//! Campus card numbers as an alternative to student ids on login. Card numbers are mapped to
+//! student ids in table `public.card_number`, and resolved on login if no account is stored as is.
+
+use super::identity::is_student_id;
+use super::{Identity, UserError};
+use crate::config::{StudentIdConfig, CONFIG};
+use crate::error::{ApiError, Result};
+use sqlx::PgPool;
+
+/// Lengths of campus card numbers.
+const CARD_NUMBER_LENGTHS: std::ops::RangeInclusive<usize> = 5..=12;
+
+/// Whether the account looks like a card number. Accounts in the format of student ids are always
+/// taken as student ids.
+pub fn is_card_number(rules: &StudentIdConfig, account: &str) -> bool {
+    CARD_NUMBER_LENGTHS.contains(&account.len())
+        && account.chars().all(|c| c.is_ascii_digit())
+        && !is_student_id(rules, account)
+}
+
+/// Get the student id of the account by the rules.
+async fn resolve(client: &PgPool, rules: &StudentIdConfig, account: &str) -> Result<String> {
+    if is_student_id(rules, account) {
+        return Ok(account.to_string());
+    }
+    if !is_card_number(rules, account) {
+        return Err(ApiError::new(UserError::InvalidStudentId));
+    }
+    let student_id: Option<(String,)> =
+        sqlx::query_as("SELECT student_id FROM public.card_number WHERE card_no = $1")
+            .bind(account)
+            .fetch_optional(client)
+            .await?;
+    student_id
+        .map(|(x,)| x)
+        .ok_or(ApiError::new(UserError::NoSuchStudentNo))
+}
+
+impl Identity {
+    /// Get the student id of the account, which is either a student id or a card number.
+    pub async fn resolve_student_id(client: &PgPool, account: &str) -> Result<String> {
+        resolve(client, &CONFIG.student_id, account).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::test::db_pool;
+
+    #[test]
+    pub fn test_card_number() {
+        let rules = StudentIdConfig::default();
+
+        assert!(is_card_number(&rules, "16427"));
+        assert!(is_card_number(&rules, "20210083"));
+        // Student ids.
+        assert!(!is_card_number(&rules, "2110400106"));
+        assert!(!is_card_number(&rules, "211040010"));
+        assert!(!is_card_number(&rules, "2021A083"));
+        assert!(!is_card_number(&rules, "1234"));
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    async fn test_resolve_student_id() {
+        let pool = db_pool().await;
+        sqlx::query(
+            "INSERT INTO public.card_number (card_no, student_id) VALUES ('98765', '2110400106')",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let rules = StudentIdConfig::default();
+
+        // Student ids are returned as is, without looking up.
+        assert_eq!(resolve(&pool, &rules, "2110400107").await.unwrap(), "2110400107");
+        assert_eq!(resolve(&pool, &rules, "98765").await.unwrap(), "2110400106");
+        assert_eq!(
+            resolve(&pool, &rules, "98764").await,
+            Err(ApiError::new(UserError::NoSuchStudentNo))
+        );
+        assert_eq!(
+            resolve(&pool, &rules, "98-76").await,
+            Err(ApiError::new(UserError::InvalidStudentId))
+        );
+
+        sqlx::query("DELETE FROM public.card_number WHERE card_no = '98765'")
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,151 @@
+//! Soft-deleted users. Rows of them are kept for audit and recovery, with `deleted_at` set. Uids
+//! of them are kept in memory, so that checking a token doesn't query the database.
+
+use super::person::check_last_admin_in;
+use super::UserError;
+use crate::error::{ApiError, Result};
+use crate::models::begin;
+use sqlx::{Done, PgPool, Postgres, Transaction};
+use std::collections::HashSet;
+use std::sync::RwLock;
+
+lazy_static! {
+    /// Uids of deleted users in memory.
+    static ref DELETED_USERS: RwLock<HashSet<i32>> = Default::default();
+}
+
+/// Load deleted users from database on startup.
+pub async fn load_deleted_users(pool: &PgPool) -> Result<()> {
+    let uids: Vec<(i32,)> = sqlx::query_as("SELECT uid FROM public.person WHERE deleted_at IS NOT NULL")
+        .fetch_all(pool)
+        .await?;
+
+    DELETED_USERS
+        .write()
+        .unwrap()
+        .extend(uids.into_iter().map(|x| x.0));
+    Ok(())
+}
+
+/// Mark the user deleted, so that the user can't log in and tokens of the user are rejected. The
+/// last administrator can't be deleted.
+pub async fn delete_user(pool: &PgPool, uid: i32) -> Result<()> {
+    let mut tx = begin(pool).await?;
+    delete_user_in(&mut tx, uid).await?;
+    tx.commit().await?;
+
+    DELETED_USERS.write().unwrap().insert(uid);
+    Ok(())
+}
+
+async fn delete_user_in(tx: &mut Transaction<'_, Postgres>, uid: i32) -> Result<()> {
+    check_last_admin_in(tx, uid).await?;
+    let result =
+        sqlx::query("UPDATE public.person SET deleted_at = now() WHERE uid = $1 AND deleted_at IS NULL")
+            .bind(uid)
+            .execute(&mut *tx)
+            .await?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::new(UserError::NoSuchUser));
+    }
+    Ok(())
+}
+
+/// Restore a deleted user.
+pub async fn restore_user(pool: &PgPool, uid: i32) -> Result<()> {
+    let result = sqlx::query(
+        "UPDATE public.person SET deleted_at = NULL WHERE uid = $1 AND deleted_at IS NOT NULL",
+    )
+    .bind(uid)
+    .execute(pool)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::new(UserError::NoSuchUser));
+    }
+
+    DELETED_USERS.write().unwrap().remove(&uid);
+    Ok(())
+}
+
+/// Whether the user is deleted.
+pub fn is_deleted(uid: i32) -> bool {
+    DELETED_USERS.read().unwrap().contains(&uid)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::test::db_pool;
+    use crate::models::user::{Authentication, Person};
+
+    #[tokio::test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    async fn test_soft_delete() {
+        let pool = db_pool().await;
+        let mut person = Person::new();
+        person.register(&pool).await.unwrap();
+        let auth = Authentication::from_password(format!("test-{}", person.uid), "secret".to_string());
+        person.update_authentication(&pool, &auth).await.unwrap();
+
+        delete_user(&pool, person.uid).await.unwrap();
+        assert!(is_deleted(person.uid));
+        assert!(auth.password_login(&pool).await.is_err());
+        assert!(Person::get(&pool, person.uid).await.is_err());
+        // Deleted already.
+        assert!(delete_user(&pool, person.uid).await.is_err());
+        // Data is retained.
+        let (count,): (i64,) = sqlx::query_as(
+            "SELECT COUNT(*) FROM public.person WHERE uid = $1 AND deleted_at IS NOT NULL",
+        )
+        .bind(person.uid)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(count, 1);
+
+        restore_user(&pool, person.uid).await.unwrap();
+        assert!(!is_deleted(person.uid));
+        assert_eq!(auth.password_login(&pool).await.unwrap().uid, person.uid);
+
+        sqlx::query("DELETE FROM public.authentication WHERE uid = $1")
+            .bind(person.uid)
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM public.person WHERE uid = $1")
+            .bind(person.uid)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    async fn test_delete_last_admin() {
+        let pool = db_pool().await;
+        let (mut alice, mut bob) = (Person::new(), Person::new());
+        alice.register(&pool).await.unwrap();
+        bob.register(&pool).await.unwrap();
+
+        // Other administrators in the database are revoked in the transaction, which is rolled back.
+        let mut tx = pool.begin().await.unwrap();
+        sqlx::query("UPDATE public.person SET is_admin = (uid = $1 OR uid = $2)")
+            .bind(alice.uid)
+            .bind(bob.uid)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        delete_user_in(&mut tx, alice.uid).await.unwrap();
+        // Bob is the last administrator.
+        let result = delete_user_in(&mut tx, bob.uid).await;
+        assert_eq!(result, Err(ApiError::new(UserError::LastAdmin)));
+        tx.rollback().await.unwrap();
+
+        sqlx::query("DELETE FROM public.person WHERE uid = $1 OR uid = $2")
+            .bind(alice.uid)
+            .bind(bob.uid)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+}
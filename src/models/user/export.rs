@@ -0,0 +1,177 @@
+//! Export the user list in CSV, for spreadsheets. Users are read in chunks ordered by uid, so that
+//! the whole table is never held in memory.
+
+use super::Person;
+use crate::error::{ApiError, Result};
+use crate::models::CommonError;
+use sqlx::PgPool;
+
+/// Columns can be exported.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportColumn {
+    Uid,
+    NickName,
+    Avatar,
+    IsAdmin,
+    IsDisabled,
+    Gender,
+    Country,
+    Province,
+    City,
+    CreateTime,
+}
+
+/// Columns exported if not selected.
+pub const DEFAULT_COLUMNS: &[ExportColumn] = &[
+    ExportColumn::Uid,
+    ExportColumn::NickName,
+    ExportColumn::IsAdmin,
+    ExportColumn::CreateTime,
+];
+
+impl ExportColumn {
+    /// Column name, the same as the field in JSON.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ExportColumn::Uid => "uid",
+            ExportColumn::NickName => "nickName",
+            ExportColumn::Avatar => "avatar",
+            ExportColumn::IsAdmin => "isAdmin",
+            ExportColumn::IsDisabled => "isDisabled",
+            ExportColumn::Gender => "gender",
+            ExportColumn::Country => "country",
+            ExportColumn::Province => "province",
+            ExportColumn::City => "city",
+            ExportColumn::CreateTime => "createTime",
+        }
+    }
+
+    fn value(&self, person: &Person) -> String {
+        let optional = |x: &Option<String>| x.clone().unwrap_or_default();
+
+        match self {
+            ExportColumn::Uid => person.uid.to_string(),
+            ExportColumn::NickName => person.nick_name.clone(),
+            ExportColumn::Avatar => person.avatar.clone(),
+            ExportColumn::IsAdmin => person.is_admin.to_string(),
+            ExportColumn::IsDisabled => person.is_disabled.to_string(),
+            ExportColumn::Gender => person.gender.to_string(),
+            ExportColumn::Country => optional(&person.country),
+            ExportColumn::Province => optional(&person.province),
+            ExportColumn::City => optional(&person.city),
+            ExportColumn::CreateTime => person.create_time.format("%Y-%m-%d %H:%M:%S").to_string(),
+        }
+    }
+
+    /// Parse comma separated column names, like `uid,nickName`.
+    pub fn parse_list(names: &str) -> Result<Vec<Self>> {
+        const ALL: &[ExportColumn] = &[
+            ExportColumn::Uid,
+            ExportColumn::NickName,
+            ExportColumn::Avatar,
+            ExportColumn::IsAdmin,
+            ExportColumn::IsDisabled,
+            ExportColumn::Gender,
+            ExportColumn::Country,
+            ExportColumn::Province,
+            ExportColumn::City,
+            ExportColumn::CreateTime,
+        ];
+
+        let columns = names
+            .split(',')
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+            .map(|name| {
+                ALL.iter()
+                    .find(|x| x.name() == name)
+                    .copied()
+                    .ok_or(ApiError::new(CommonError::Parameter))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        if columns.is_empty() {
+            return Err(ApiError::new(CommonError::Parameter));
+        }
+        Ok(columns)
+    }
+}
+
+/// Quote the field if needed. Fields which look like formulas are prefixed with `'`, so that
+/// spreadsheets don't evaluate nick names.
+pub(crate) fn csv_field(value: &str) -> String {
+    let value = match value.chars().next() {
+        Some('=') | Some('+') | Some('-') | Some('@') => format!("'{}", value),
+        _ => value.to_string(),
+    };
+    if value.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+/// Header line of the CSV.
+pub fn csv_header(columns: &[ExportColumn]) -> String {
+    let names: Vec<&str> = columns.iter().map(ExportColumn::name).collect();
+    format!("{}\r\n", names.join(","))
+}
+
+/// Line of the person in the CSV.
+pub fn csv_row(person: &Person, columns: &[ExportColumn]) -> String {
+    let fields: Vec<String> = columns.iter().map(|x| csv_field(&x.value(person))).collect();
+    format!("{}\r\n", fields.join(","))
+}
+
+impl Person {
+    /// List users whose uid is greater than `after`, in order of uid. Deleted users are excluded.
+    pub async fn list_after(client: &PgPool, after: i32, limit: i64) -> Result<Vec<Self>> {
+        let users: Vec<Person> = sqlx::query_as(
+            "SELECT uid, nick_name, avatar, is_disabled, is_admin, gender, country, province, city, language, create_time, updated_at
+                FROM public.person WHERE deleted_at IS NULL AND uid > $1 ORDER BY uid LIMIT $2",
+        )
+        .bind(after)
+        .bind(limit)
+        .fetch_all(client)
+        .await?;
+        Ok(users)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::NaiveDate;
+
+    #[test]
+    pub fn test_parse_columns() {
+        assert_eq!(
+            ExportColumn::parse_list("uid, nickName").unwrap(),
+            [ExportColumn::Uid, ExportColumn::NickName]
+        );
+        assert!(ExportColumn::parse_list("uid,password").is_err());
+        assert!(ExportColumn::parse_list(",").is_err());
+    }
+
+    #[test]
+    pub fn test_csv_lines() {
+        let mut person = Person::new();
+        person.uid = 25;
+        person.nick_name = "Kite, \"the\" bird".to_string();
+        person.city = Some("=1+1".to_string());
+        person.create_time = NaiveDate::from_ymd(2021, 9, 1).and_hms(8, 30, 0);
+        let columns = [
+            ExportColumn::Uid,
+            ExportColumn::NickName,
+            ExportColumn::Country,
+            ExportColumn::City,
+            ExportColumn::CreateTime,
+        ];
+
+        assert_eq!(csv_header(&columns), "uid,nickName,country,city,createTime\r\n");
+        assert_eq!(
+            csv_row(&person, &columns),
+            "25,\"Kite, \"\"the\"\" bird\",,'=1+1,2021-09-01 08:30:00\r\n"
+        );
+    }
+}
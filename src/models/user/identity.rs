@@ -1,11 +1,57 @@
 use super::Identity;
+use crate::cache::Cache;
+use crate::config::{StudentIdConfig, CONFIG};
 use crate::error::Result;
+use serde::Serialize;
+use sqlx::PgPool;
+use std::future::Future;
+use std::time::Duration;
+
+lazy_static! {
+    /// Student ids verified on the auth server recently, with the bcrypt hash of the secret used.
+    static ref OA_VERIFIED: Cache<String, String> =
+        Cache::new(Duration::from_secs(CONFIG.server.oa_verify_ttl));
+}
+
+/// Cost of bcrypt hashes of verified secrets. They live no longer than the ttl of the cache.
+const OA_SECRET_COST: u32 = 10;
 
 async fn oa_password_check(account: &String, password: &String) -> Result<()> {
     super::authserver::portal_login(account, password).await?;
     Ok(())
 }
 
+/// Skip the check if the same secret of the student id passed it within the ttl of the cache.
+async fn check_with_cache<F, Fut>(
+    cache: &Cache<String, String>,
+    student_id: &str,
+    oa_secret: &str,
+    check: F,
+) -> Result<()>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let secret = oa_secret.to_string();
+    if let Some(hash) = cache.get(&student_id.to_string()) {
+        let secret = secret.clone();
+        let verified = tokio::task::spawn_blocking(move || bcrypt::verify(secret, &hash))
+            .await
+            .map_err(anyhow::Error::from)?
+            .map_err(anyhow::Error::from)?;
+        if verified {
+            return Ok(());
+        }
+    }
+    check().await?;
+    let hash = tokio::task::spawn_blocking(move || bcrypt::hash(secret, OA_SECRET_COST))
+        .await
+        .map_err(anyhow::Error::from)?
+        .map_err(anyhow::Error::from)?;
+    cache.insert(student_id.to_string(), hash);
+    Ok(())
+}
+
 impl Identity {
     pub fn new(uid: i32, student_id: String) -> Self {
         Self {
@@ -15,9 +61,53 @@ impl Identity {
         }
     }
 
-    pub async fn validate_oa_account(student_id: &String, oa_secret: &String) -> Result<()> {
-        oa_password_check(student_id, oa_secret).await?;
-        Ok(())
+    /// Verify the account, a student id or a card number, on the auth server, unless it was
+    /// verified with the same secret in `CONFIG.server.oa_verify_ttl` seconds. Return the student id.
+    pub async fn validate_oa_account(
+        client: &PgPool,
+        account: &str,
+        oa_secret: &String,
+    ) -> Result<String> {
+        let student_id = Self::resolve_student_id(client, account).await?;
+        check_with_cache(&OA_VERIFIED, &student_id, oa_secret, || {
+            oa_password_check(&student_id, oa_secret)
+        })
+        .await?;
+        Ok(student_id)
+    }
+
+    /// Always verify the account on the auth server, before the credential is changed. Return the
+    /// student id.
+    pub async fn validate_oa_account_fresh(
+        client: &PgPool,
+        account: &str,
+        oa_secret: &String,
+    ) -> Result<String> {
+        let student_id = Self::resolve_student_id(client, account).await?;
+        OA_VERIFIED.remove(&student_id);
+        check_with_cache(&OA_VERIFIED, &student_id, oa_secret, || {
+            oa_password_check(&student_id, oa_secret)
+        })
+        .await?;
+        Ok(student_id)
+    }
+
+    /// Get the OA secret of the certified student id, to fetch data on behalf of the student.
+    pub async fn get_oa_secret(client: &PgPool, student_id: &str) -> Result<Option<String>> {
+        let secret: Option<(String,)> = sqlx::query_as(
+            "SELECT oa_secret FROM public.identities
+            WHERE student_id = $1 AND oa_certified AND oa_secret IS NOT NULL
+            LIMIT 1",
+        )
+        .bind(student_id)
+        .fetch_optional(client)
+        .await?;
+        Ok(secret.map(|(x,)| x))
+    }
+
+    /// Whether the secret is the default one, six right characters of the id card number.
+    pub fn is_default_digit(oa_secret: &str) -> bool {
+        Self::validate_identity_number(oa_secret)
     }
 
     pub fn validate_identity_number(identity_number: &str) -> bool {
@@ -25,3 +115,171 @@ impl Identity {
         return re.is_match(identity_number) && identity_number.len() == 6;
     }
 }
+
+/// Whether the id is in the format of student ids.
+pub fn is_student_id(rules: &StudentIdConfig, student_id: &str) -> bool {
+    rules.lengths.contains(&student_id.chars().count()) && rules.pattern.is_match(student_id)
+}
+
+/// Whether the student id belongs to a postgraduate or other non-undergraduate student.
+pub fn is_not_undergraduate(rules: &StudentIdConfig, student_id: &str) -> bool {
+    student_id
+        .chars()
+        .nth(rules.discriminator)
+        .map(|c| rules.non_undergraduate_marks.contains(c))
+        .unwrap_or(false)
+}
+
+/// Identity for display, with the student id masked and without any secret.
+#[derive(Serialize)]
+pub struct MaskedIdentity {
+    /// Person uid
+    pub uid: i32,
+    /// Real name
+    #[serde(rename = "realName")]
+    pub real_name: String,
+    /// Masked student id, like "21xxxx06"
+    #[serde(rename = "studentId")]
+    pub student_id: String,
+    /// Whether OA certified or not
+    #[serde(rename = "oaCertified")]
+    pub oa_certified: bool,
+    /// Whether the student id is masked.
+    pub masked: bool,
+    /// Whether it's the identity used by default.
+    #[serde(rename = "isPrimary")]
+    pub is_primary: bool,
+}
+
+/// Mask the student id except the first two and the last two characters.
+pub fn mask_student_id(student_id: &str) -> String {
+    let chars: Vec<char> = student_id.chars().collect();
+    if chars.len() <= 4 {
+        return "x".repeat(chars.len());
+    }
+    chars
+        .iter()
+        .enumerate()
+        .map(|(i, c)| if i < 2 || i >= chars.len() - 2 { *c } else { 'x' })
+        .collect()
+}
+
+impl From<Identity> for MaskedIdentity {
+    fn from(identity: Identity) -> Self {
+        Self {
+            uid: identity.uid,
+            real_name: identity.real_name,
+            student_id: mask_student_id(&identity.student_id),
+            oa_certified: identity.oa_certified,
+            masked: true,
+            is_primary: identity.is_primary,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Pattern;
+    use crate::error::ApiError;
+    use crate::models::user::UserError;
+
+    #[test]
+    pub fn test_mask_student_id() {
+        assert_eq!(mask_student_id("21100106"), "21xxxx06");
+        assert_eq!(mask_student_id("2110400106"), "21xxxxxx06");
+        assert_eq!(mask_student_id("123"), "xxx");
+    }
+
+    #[test]
+    pub fn test_default_student_id_rules() {
+        let rules = StudentIdConfig::default();
+
+        assert!(is_student_id(&rules, "2110400106"));
+        assert!(is_student_id(&rules, "211040010"));
+        assert!(!is_student_id(&rules, "21100106"));
+        assert!(!is_student_id(&rules, "21104-0106"));
+        assert!(!is_not_undergraduate(&rules, "2110400106"));
+    }
+
+    #[test]
+    pub fn test_custom_student_id_rules() {
+        let rules = StudentIdConfig {
+            lengths: vec![8],
+            pattern: Pattern::new("^[0-9]{2}[0-9G][0-9]+$"),
+            discriminator: 2,
+            non_undergraduate_marks: "G".to_string(),
+        };
+
+        assert!(is_student_id(&rules, "21100106"));
+        assert!(is_student_id(&rules, "21G00106"));
+        assert!(!is_student_id(&rules, "2110400106"));
+        assert!(!is_student_id(&rules, "21X00106"));
+        assert!(is_not_undergraduate(&rules, "21G00106"));
+        assert!(!is_not_undergraduate(&rules, "21100106"));
+        assert!(!is_not_undergraduate(&rules, "21"));
+    }
+
+    #[test]
+    pub fn test_default_digit() {
+        assert!(Identity::is_default_digit("12345X"));
+        assert!(Identity::is_default_digit("123456"));
+        assert!(!Identity::is_default_digit("1234567"));
+        assert!(!Identity::is_default_digit("abc123"));
+    }
+
+    #[tokio::test]
+    async fn test_oa_check_cached() {
+        let cache = Cache::new(Duration::from_secs(60));
+        let calls = std::cell::Cell::new(0);
+        let check = || async {
+            calls.set(calls.get() + 1);
+            Ok(())
+        };
+
+        check_with_cache(&cache, "2110400106", "password", check)
+            .await
+            .unwrap();
+        check_with_cache(&cache, "2110400106", "password", check)
+            .await
+            .unwrap();
+        assert_eq!(calls.get(), 1);
+        let hash = cache.get(&"2110400106".to_string()).unwrap();
+        assert!(!hash.contains("password"));
+        // Hashes are salted.
+        assert_ne!(hash, bcrypt::hash("password", OA_SECRET_COST).unwrap());
+
+        // Another secret is checked again.
+        check_with_cache(&cache, "2110400106", "another", check)
+            .await
+            .unwrap();
+        assert_eq!(calls.get(), 2);
+
+        // Failures are not cached.
+        let fail = || async { Err(ApiError::new(UserError::OaSecretFailed)) };
+        assert!(check_with_cache(&cache, "2110400107", "password", fail)
+            .await
+            .is_err());
+        check_with_cache(&cache, "2110400107", "password", check)
+            .await
+            .unwrap();
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[test]
+    pub fn test_secret_not_serialized() {
+        let identity = Identity {
+            uid: 1,
+            student_id: "21100106".to_string(),
+            oa_secret: Some("password".to_string()),
+            ..Identity::default()
+        };
+        let full = serde_json::to_string(&identity).unwrap();
+        assert!(!full.contains("password"));
+
+        let masked = serde_json::to_value(MaskedIdentity::from(identity)).unwrap();
+        assert_eq!(masked["studentId"], "21xxxx06");
+        assert_eq!(masked["masked"], true);
+        assert!(masked.get("oaSecret").is_none());
+    }
+}
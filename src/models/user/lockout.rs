@@ -0,0 +1,224 @@
+//! Lock an account temporarily after failed logins, so that a targeted account can't be guessed
+//! slowly under the rate limits. Accounts are unlocked after the cooldown, or by an administrator.
+//! Failures are counted in the quota store under keys of the user, so that instances share them,
+//! and administrators can find them by uid.
+
+use super::UserError;
+use crate::config::LockoutConfig;
+use crate::error::{ApiError, Result};
+use crate::models::quota::{user_key_prefix, window_index, QuotaStore};
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use std::time::Duration;
+
+/// Whether the error is a wrong credential, rather than a failure of the auth server or a bad
+/// request.
+pub fn is_credential_failure(e: &ApiError) -> bool {
+    *e == ApiError::new(UserError::LoginFailed) || *e == ApiError::new(UserError::OaSecretFailed)
+}
+
+/// Prefix of counter keys of the login account: of its user if there is one, or of the account
+/// itself, like a student id not registered yet.
+pub async fn account_key_prefix(pool: &PgPool, account: &str) -> Result<String> {
+    let account = account.trim();
+    let uid: Option<(i32,)> =
+        sqlx::query_as("SELECT uid FROM public.authentication WHERE account = $1 ORDER BY uid LIMIT 1")
+            .bind(account)
+            .fetch_optional(pool)
+            .await?;
+
+    Ok(match uid {
+        Some((uid,)) => user_key_prefix(uid),
+        None => format!("account:{}:", account),
+    })
+}
+
+/// Failed logins of accounts, counted in fixed windows of `duration`. An account is locked once
+/// it fails `max_failures` times in a window, until the next window ends, that is `duration` to
+/// twice of it.
+pub struct Lockout<'a> {
+    quota: &'a QuotaStore,
+    /// Failures to lock the account, or 0 to never lock.
+    max_failures: u32,
+    duration: Duration,
+}
+
+impl<'a> Lockout<'a> {
+    pub fn new(quota: &'a QuotaStore, config: &LockoutConfig) -> Self {
+        Self {
+            quota,
+            max_failures: config.max_failures,
+            duration: Duration::from_secs(config.duration),
+        }
+    }
+
+    /// Refuse the account of the key prefix if it's locked.
+    pub async fn check(&self, prefix: &str, now: DateTime<Utc>) -> Result<()> {
+        if self.max_failures == 0 {
+            return Ok(());
+        }
+        let key = format!("{}lockout", prefix);
+        let current = window_index(now, self.duration);
+
+        for window in &[current - 1, current] {
+            if self.quota.get(&key, &window.to_string()).await? >= self.max_failures as i64 {
+                return Err(ApiError::new(UserError::AccountLocked));
+            }
+        }
+        Ok(())
+    }
+
+    /// Count the result of the login. A wrong credential adds a failure and may lock the account,
+    /// and a success clears its failures. A success is refused if the account has been locked in
+    /// the meantime. Failures of unknown accounts are not kept, so that made-up accounts don't
+    /// fill the store.
+    pub async fn track<T>(
+        &self,
+        prefix: &str,
+        known: bool,
+        now: DateTime<Utc>,
+        result: Result<T>,
+    ) -> Result<T> {
+        self.check(prefix, now).await?;
+        let key = format!("{}lockout", prefix);
+
+        match &result {
+            Ok(_) => self.quota.clear(&key).await?,
+            Err(e) if is_credential_failure(e) && known && self.max_failures > 0 => {
+                let window = window_index(now, self.duration).to_string();
+                self.quota.hit(&key, &window, self.max_failures as i64).await?;
+            }
+            Err(_) => (),
+        }
+        result
+    }
+
+    /// Unlock the account and clear its failures. Return whether it was locked.
+    pub async fn unlock(&self, prefix: &str, now: DateTime<Utc>) -> Result<bool> {
+        let was_locked = self.check(prefix, now).await.is_err();
+
+        self.quota.clear(&format!("{}lockout", prefix)).await?;
+        Ok(was_locked)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn failed() -> Result<()> {
+        Err(ApiError::new(UserError::LoginFailed))
+    }
+
+    fn config(max_failures: u32) -> LockoutConfig {
+        LockoutConfig {
+            max_failures,
+            duration: 60,
+        }
+    }
+
+    /// Start of a window.
+    fn start() -> DateTime<Utc> {
+        Utc.timestamp(6000, 0)
+    }
+
+    #[tokio::test]
+    pub async fn test_lockout() {
+        let quota = QuotaStore::memory();
+        let store = Lockout::new(&quota, &config(3));
+        let now = start();
+
+        // Failures of the auth server are not counted.
+        let result: Result<()> = Err(ApiError::new(UserError::OaNetworkFailed));
+        assert!(store.track("user:1:", true, now, result).await.is_err());
+        for _ in 0..2 {
+            assert!(store.track("user:1:", true, now, failed()).await.is_err());
+        }
+        // A success clears the failures.
+        assert!(store.track("user:1:", true, now, Ok(())).await.is_ok());
+        for _ in 0..3 {
+            assert_eq!(store.check("user:1:", now).await, Ok(()));
+            assert_eq!(store.track("user:1:", true, now, failed()).await, failed());
+        }
+        assert_eq!(
+            store.check("user:1:", now).await,
+            Err(ApiError::new(UserError::AccountLocked))
+        );
+        // A correct secret is still refused during the lock.
+        assert_eq!(
+            store
+                .track("user:1:", true, now + chrono::Duration::seconds(30), Ok(()))
+                .await,
+            Err(ApiError::new(UserError::AccountLocked))
+        );
+        // Other accounts are not affected.
+        assert_eq!(store.check("user:2:", now).await, Ok(()));
+        // Failures are kept under the user.
+        assert_eq!(quota.list("user:1:").await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    pub async fn test_auto_unlock() {
+        let quota = QuotaStore::memory();
+        let store = Lockout::new(&quota, &config(2));
+        let now = start() + chrono::Duration::seconds(59);
+
+        for _ in 0..2 {
+            let _ = store.track("user:1:", true, now, failed()).await;
+        }
+        // Locked in the next window too.
+        assert!(store
+            .check("user:1:", now + chrono::Duration::seconds(60))
+            .await
+            .is_err());
+        let later = now + chrono::Duration::seconds(61);
+        assert_eq!(store.check("user:1:", later).await, Ok(()));
+        // Failures start over after the lock.
+        let _ = store.track("user:1:", true, later, failed()).await;
+        assert_eq!(store.check("user:1:", later).await, Ok(()));
+    }
+
+    #[tokio::test]
+    pub async fn test_manual_unlock() {
+        let quota = QuotaStore::memory();
+        let store = Lockout::new(&quota, &config(1));
+        let now = start();
+
+        let _ = store.track("user:1:", true, now, failed()).await;
+        assert!(store.check("user:1:", now).await.is_err());
+        assert!(store.unlock("user:1:", now).await.unwrap());
+        assert_eq!(store.track("user:1:", true, now, Ok(())).await, Ok(()));
+        assert!(!store.unlock("user:1:", now).await.unwrap());
+
+        // Never locked if disabled.
+        let store = Lockout::new(&quota, &config(0));
+        for _ in 0..10 {
+            let _ = store.track("user:1:", true, now, failed()).await;
+        }
+        assert_eq!(store.check("user:1:", now).await, Ok(()));
+    }
+
+    #[tokio::test]
+    pub async fn test_failures_expire() {
+        let quota = QuotaStore::memory();
+        let store = Lockout::new(&quota, &config(3));
+        let now = start();
+
+        // Failures in different windows are not added up.
+        for i in 0..5 {
+            let time = now + chrono::Duration::seconds(60 * i);
+            let _ = store.track("user:1:", true, time, failed()).await;
+        }
+        assert_eq!(
+            store.check("user:1:", now + chrono::Duration::seconds(240)).await,
+            Ok(())
+        );
+        // Unknown accounts are not kept.
+        for _ in 0..5 {
+            let _ = store.track("account:nobody:", false, now, failed()).await;
+        }
+        assert_eq!(store.check("account:nobody:", now).await, Ok(()));
+        assert!(quota.list("account:").await.unwrap().is_empty());
+    }
+}
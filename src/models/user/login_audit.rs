@@ -0,0 +1,267 @@
+//! Login attempts, in table `public.login_attempt`, for security review. Accounts are stored as
+//! keyed digests, which can't be reversed by hashing all student ids without the server secret,
+//! and secrets are never stored. A failed record never fails the login.
+
+use super::UserError;
+use crate::error::{ApiError, Result};
+use crate::models::PageView;
+use chrono::{DateTime, Local};
+use hmac::{Hmac, Mac, NewMac};
+use log::warn;
+use num_traits::FromPrimitive;
+use serde::Serialize;
+use sha2::Sha256;
+use sqlx::PgPool;
+
+/// Purpose of the key derived from the server secret for account digests.
+pub const ACCOUNT_KEY_PURPOSE: &str = "login_audit";
+
+pub const OUTCOME_SUCCESS: &str = "success";
+pub const OUTCOME_FAILURE: &str = "failure";
+
+/// One login attempt.
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct LoginAttempt {
+    pub id: i64,
+    /// HMAC-SHA256 digest of the account, which is the student id for campus login.
+    #[serde(rename = "accountHash")]
+    pub account_hash: Option<String>,
+    pub ip: String,
+    #[serde(rename = "loginType")]
+    pub login_type: i32,
+    /// `success` or `failure`.
+    pub outcome: String,
+    /// Uid of the user logged in.
+    pub uid: Option<i32>,
+    /// Error code of the failure.
+    pub code: Option<i32>,
+    /// Name of the `UserError` variant, or `Upstream` if the auth server or agents failed.
+    pub reason: Option<String>,
+    #[serde(rename = "time")]
+    pub ts: DateTime<Local>,
+}
+
+/// Conditions of login attempts to list. `None` matches all.
+#[derive(Default)]
+pub struct LoginAttemptFilter {
+    pub outcome: Option<String>,
+    pub from: Option<DateTime<Local>>,
+    pub to: Option<DateTime<Local>>,
+}
+
+/// Digest of the account, so that attempts on the same account can be told without storing it.
+pub fn hash_account(account: &str, key: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC accepts keys of any size");
+    mac.update(account.as_bytes());
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+/// Reason of the failed login.
+pub fn failure_reason(e: &ApiError) -> Option<String> {
+    // Codes of other modules may collide with ones of `UserError`.
+    if e.kind == Some(std::any::type_name::<UserError>()) {
+        if let Some(e) = UserError::from_u16(e.code) {
+            return Some(format!("{:?}", e));
+        }
+    }
+    if e.is_upstream_failure() {
+        return Some("Upstream".to_string());
+    }
+    None
+}
+
+/// Record the login attempt, with the user on success. Errors are logged only.
+pub async fn record(
+    client: &PgPool,
+    key: &[u8],
+    account: Option<&str>,
+    ip: &str,
+    login_type: i32,
+    result: std::result::Result<i32, &ApiError>,
+) {
+    let (outcome, uid, code, reason) = match result {
+        Ok(uid) => (OUTCOME_SUCCESS, Some(uid), None, None),
+        Err(e) => (OUTCOME_FAILURE, None, Some(e.code as i32), failure_reason(e)),
+    };
+    let result = sqlx::query(
+        "INSERT INTO public.login_attempt (account_hash, ip, login_type, outcome, uid, code, reason, ts)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, now())",
+    )
+    .bind(account.map(|x| hash_account(x, key)))
+    .bind(ip)
+    .bind(login_type)
+    .bind(outcome)
+    .bind(uid)
+    .bind(code)
+    .bind(&reason)
+    .execute(client)
+    .await;
+
+    if let Err(e) = result {
+        warn!("Failed to record login attempt from {}: {}", ip, e);
+    }
+}
+
+/// List login attempts matching the filter, the latest first.
+pub async fn list(
+    client: &PgPool,
+    filter: &LoginAttemptFilter,
+    page: &PageView,
+    max_count: u16,
+) -> Result<Vec<LoginAttempt>> {
+    let attempts: Vec<LoginAttempt> = sqlx::query_as(
+        "SELECT id, account_hash, ip, login_type, outcome, uid, code, reason, ts FROM public.login_attempt
+            WHERE ($1::text IS NULL OR outcome = $1)
+                AND ($2::timestamptz IS NULL OR ts >= $2) AND ($3::timestamptz IS NULL OR ts < $3)
+            ORDER BY ts DESC, id DESC OFFSET $4 LIMIT $5",
+    )
+    .bind(&filter.outcome)
+    .bind(filter.from)
+    .bind(filter.to)
+    .bind(page.offset(max_count))
+    .bind(page.count(max_count) as i64)
+    .fetch_all(client)
+    .await?;
+    Ok(attempts)
+}
+
+/// Count login attempts matching the filter.
+pub async fn count(client: &PgPool, filter: &LoginAttemptFilter) -> Result<i64> {
+    let (count,): (i64,) = sqlx::query_as(
+        "SELECT COUNT(*) FROM public.login_attempt
+            WHERE ($1::text IS NULL OR outcome = $1)
+                AND ($2::timestamptz IS NULL OR ts >= $2) AND ($3::timestamptz IS NULL OR ts < $3)",
+    )
+    .bind(&filter.outcome)
+    .bind(filter.from)
+    .bind(filter.to)
+    .fetch_one(client)
+    .await?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bridge::HostError;
+    use crate::models::test::db_pool;
+    use crate::models::CommonError;
+
+    const KEY: &[u8] = b"secret";
+
+    #[test]
+    fn test_failure_reason() {
+        let reason_of = |e: ApiError| failure_reason(&e);
+
+        assert_eq!(
+            reason_of(ApiError::new(UserError::OaSecretFailed)).unwrap(),
+            "OaSecretFailed"
+        );
+        assert_eq!(
+            reason_of(ApiError::new(UserError::LoginFailed)).unwrap(),
+            "LoginFailed"
+        );
+        assert_eq!(
+            reason_of(ApiError::new(UserError::AuthTypeNotAllowed)).unwrap(),
+            "AuthTypeNotAllowed"
+        );
+        assert_eq!(
+            reason_of(ApiError::new(UserError::DefaultSecret)).unwrap(),
+            "DefaultSecret"
+        );
+        assert_eq!(
+            reason_of(ApiError::new(UserError::OaNetworkFailed)).unwrap(),
+            "OaNetworkFailed"
+        );
+        assert_eq!(reason_of(ApiError::new(HostError::Timeout)).unwrap(), "Upstream");
+        assert_eq!(reason_of(ApiError::new(CommonError::Parameter)), None);
+    }
+
+    #[test]
+    fn test_failure_reason_of_colliding_code() {
+        // An error of another module with the code of `UserError::LoginFailed`.
+        let e = ApiError {
+            kind: Some("OtherError"),
+            ..ApiError::new(UserError::LoginFailed)
+        };
+
+        assert_eq!(failure_reason(&e), None);
+    }
+
+    #[test]
+    fn test_hash_account() {
+        let hash = hash_account("1910200101", KEY);
+
+        assert_eq!(hash.len(), 64);
+        assert!(!hash.contains("1910200101"));
+        assert_eq!(hash, hash_account("1910200101", KEY));
+        assert_ne!(hash, hash_account("1910200101", b"other"));
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    async fn test_record_failure_reasons() {
+        let pool = db_pool().await;
+        let ip = "TESTLA";
+        let from = Local::now();
+        let cleanup = || sqlx::query("DELETE FROM public.login_attempt WHERE ip = $1").bind(ip);
+        cleanup().execute(&pool).await.unwrap();
+
+        let failures = [
+            ApiError::new(UserError::OaSecretFailed),
+            ApiError::new(UserError::AuthTypeNotAllowed),
+            ApiError::new(UserError::DefaultSecret),
+            ApiError::new(UserError::OaNetworkFailed),
+        ];
+        for e in failures.iter() {
+            record(&pool, KEY, Some("1910200101"), ip, 2, Err(e)).await;
+        }
+        record(&pool, KEY, Some("1910200101"), ip, 2, Ok(10)).await;
+
+        let filter = LoginAttemptFilter {
+            outcome: Some(OUTCOME_FAILURE.to_string()),
+            from: Some(from),
+            to: None,
+        };
+        let attempts: Vec<_> = list(&pool, &filter, &PageView::new(), 100)
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|x| x.ip == ip)
+            .collect();
+        let mut reasons: Vec<_> = attempts.iter().map(|x| x.reason.clone().unwrap()).collect();
+        reasons.sort();
+        assert_eq!(
+            reasons,
+            [
+                "AuthTypeNotAllowed",
+                "DefaultSecret",
+                "OaNetworkFailed",
+                "OaSecretFailed"
+            ]
+        );
+        assert!(attempts
+            .iter()
+            .all(|x| x.account_hash.as_deref() == Some(hash_account("1910200101", KEY).as_str())));
+
+        let filter = LoginAttemptFilter {
+            outcome: Some(OUTCOME_SUCCESS.to_string()),
+            from: Some(from),
+            to: None,
+        };
+        let attempts = list(&pool, &filter, &PageView::new(), 100).await.unwrap();
+        assert!(attempts.iter().any(|x| x.ip == ip && x.uid == Some(10)));
+        // Out of the time range.
+        let filter = LoginAttemptFilter {
+            to: Some(from),
+            ..filter
+        };
+        assert!(list(&pool, &filter, &PageView::new(), 100)
+            .await
+            .unwrap()
+            .iter()
+            .all(|x| x.ip != ip));
+
+        cleanup().execute(&pool).await.unwrap();
+    }
+}
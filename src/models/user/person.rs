@@ -3,8 +3,27 @@ use super::{LOGIN_BY_PASSWORD, LOGIN_BY_WECHAT};
 use crate::error::ApiError;
 use crate::error::Result;
 use crate::models::user::LOGIN_BY_CAMPUS_WEB;
-use chrono::Utc;
-use sqlx::PgPool;
+use crate::models::{begin, CommonError, PageView};
+use chrono::{Local, Utc};
+use sqlx::postgres::PgDatabaseError;
+use sqlx::{Done, PgPool, Postgres, Transaction};
+
+/// Unique index of student ids of identities, so that a student id is bound to one user at most.
+const STUDENT_ID_INDEX: &str = "identities_student_id_idx";
+/// Unique index of student ids of campus authentication.
+const CAMPUS_ACCOUNT_INDEX: &str = "authentication_campus_account_idx";
+
+/// Return `UserError::AlreadyBound` if the student id is bound to another user.
+fn check_bound(e: sqlx::Error) -> ApiError {
+    let constraint = e
+        .as_database_error()
+        .and_then(|x| x.try_downcast_ref::<PgDatabaseError>())
+        .and_then(|x| x.constraint());
+    match constraint {
+        Some(STUDENT_ID_INDEX) | Some(CAMPUS_ACCOUNT_INDEX) => ApiError::new(UserError::AlreadyBound),
+        _ => e.into(),
+    }
+}
 
 impl Authentication {
     pub fn from_password(username: String, password: String) -> Self {
@@ -33,13 +52,26 @@ impl Authentication {
             credential: Some(password),
         }
     }
+    /// Whether the account of the login type is registered.
+    pub async fn exists(&self, client: &PgPool) -> Result<bool> {
+        let (exists,): (bool,) = sqlx::query_as(
+            "SELECT EXISTS(SELECT 1 FROM public.authentication WHERE login_type = $1 AND account = $2)",
+        )
+        .bind(self.login_type)
+        .bind(&self.account)
+        .fetch_one(client)
+        .await?;
+        Ok(exists)
+    }
+
     /// Login by a password, return a Person structure if success. Otherwise, an UserError will be returned.
     pub async fn password_login(&self, client: &PgPool) -> Result<Person> {
         let user: Option<Person> = sqlx::query_as(
-            "SELECT p.uid, nick_name, avatar, is_disabled, is_admin, gender, country, province, city, language, create_time
+            "SELECT p.uid, nick_name, avatar, is_disabled, is_admin, gender, country, province, city, language, create_time, updated_at
                 FROM public.person p
                 RIGHT JOIN authentication auth on p.uid = auth.uid
-                WHERE auth.login_type = 1 AND auth.account = $1 AND auth.credential = $2 LIMIT 1"
+                WHERE auth.login_type = 1 AND auth.account = $1 AND auth.credential = $2 AND p.deleted_at IS NULL
+                LIMIT 1"
         )
             .bind(&self.account)
             .bind(&self.credential)
@@ -53,10 +85,10 @@ impl Authentication {
 
     pub async fn wechat_login(&self, client: &PgPool) -> Result<Person> {
         let user: Option<Person> = sqlx::query_as(
-            "SELECT p.uid, nick_name, avatar, is_disabled, is_admin, gender, country, province, city, language, create_time
+            "SELECT p.uid, nick_name, avatar, is_disabled, is_admin, gender, country, province, city, language, create_time, updated_at
                 FROM public.person p
                 RIGHT JOIN authentication auth on p.uid = auth.uid
-                WHERE auth.login_type = 0 AND auth.account = $1 LIMIT 1"
+                WHERE auth.login_type = 0 AND auth.account = $1 AND p.deleted_at IS NULL LIMIT 1"
         )
             .bind(&self.account)
             .fetch_optional(client)
@@ -108,50 +140,28 @@ impl Person {
 
     /// Bind authentication, if auth type already exists, this function will override the old record.
     pub async fn update_authentication(&self, client: &PgPool, auth: &Authentication) -> Result<()> {
-        // Note: Alter username is not allowed.
-        let _ = sqlx::query(
-            "INSERT INTO
-                    authentication (uid, login_type, account, credential) VALUES ($1, $2, $3, $4)
-                ON CONFLICT (uid, login_type)
-                DO UPDATE SET credential = $4",
-        )
-        .bind(self.uid)
-        .bind(auth.login_type)
-        .bind(&auth.account)
-        .bind(&auth.credential)
-        .execute(client)
-        .await?;
+        let mut tx = begin(client).await?;
+        update_authentication_in(&mut tx, self.uid, auth).await?;
+        tx.commit().await?;
 
         Ok(())
     }
 
     pub async fn register(&mut self, client: &PgPool) -> Result<()> {
-        let uid: Option<(i32,)> = sqlx::query_as(
-            "INSERT INTO public.person
-                (nick_name, avatar, country, province, city, language, create_time)
-                VALUES ($1, $2, $3, $4, $5, $6, $7)
-                RETURNING uid",
-        )
-        .bind(&self.nick_name)
-        .bind(&self.avatar)
-        .bind(&self.country)
-        .bind(&self.province)
-        .bind(&self.city)
-        .bind(&self.language)
-        .bind(&self.create_time)
-        .fetch_optional(client)
-        .await?;
-        if let Some((uid_value,)) = uid {
-            self.uid = uid_value;
-        }
-        // TODO: update code here.
+        let mut tx = begin(client).await?;
+        register_in(&mut tx, self).await?;
+        tx.commit().await?;
+
         Ok(())
     }
 
-    pub async fn update(&self, client: &PgPool) -> Result<()> {
-        sqlx::query(
-            "UPDATE public.person SET gender = $1, country = $2, province = $3, city = $4, avatar = $5\
-                WHERE uid = $6",
+    /// Update the person and return the new row version. If `version` is given, the update only
+    /// happens when it matches the current row version.
+    pub async fn update(&self, client: &PgPool, version: Option<&str>) -> Result<String> {
+        let new_version: Option<(String,)> = sqlx::query_as(
+            "UPDATE public.person SET gender = $1, country = $2, province = $3, city = $4, avatar = $5, updated_at = now()
+                WHERE uid = $6 AND ($7::text IS NULL OR xmin::text = $7)
+                RETURNING xmin::text",
         )
         .bind(self.gender)
         .bind(&self.country)
@@ -159,26 +169,84 @@ impl Person {
         .bind(&self.city)
         .bind(&self.avatar)
         .bind(self.uid)
-        .execute(client)
+        .bind(version)
+        .fetch_optional(client)
+        .await?;
+        check_version(new_version, version)
+    }
+
+    /// Disable or enable the user and return the new row version, which is checked like `update`.
+    /// The last administrator can't be disabled.
+    pub async fn set_disabled(
+        client: &PgPool,
+        uid: i32,
+        is_disabled: bool,
+        version: Option<&str>,
+    ) -> Result<String> {
+        let mut tx = begin(client).await?;
+
+        if is_disabled {
+            check_last_admin_in(&mut tx, uid).await?;
+        }
+        let new_version: Option<(String,)> = sqlx::query_as(
+            "UPDATE public.person SET is_disabled = $2, updated_at = now()
+                WHERE uid = $1 AND deleted_at IS NULL AND ($3::text IS NULL OR xmin::text = $3)
+                RETURNING xmin::text",
+        )
+        .bind(uid)
+        .bind(is_disabled)
+        .bind(version)
+        .fetch_optional(&mut tx)
         .await?;
+        let new_version = check_version(new_version, version)?;
+        tx.commit().await?;
+        Ok(new_version)
+    }
+
+    /// Grant or revoke administrator of the user. The last administrator can't be revoked, or no
+    /// one could manage the server any more.
+    pub async fn set_admin(client: &PgPool, uid: i32, is_admin: bool) -> Result<()> {
+        let mut tx = begin(client).await?;
+
+        set_admin_in(&mut tx, uid, is_admin).await?;
+        tx.commit().await?;
         Ok(())
     }
 
-    pub async fn list(client: &PgPool, page_index: u32, page_size: u32) -> Result<Vec<Self>> {
+    /// Get row version of the person, which changes on every update.
+    pub async fn get_version(client: &PgPool, uid: i32) -> Result<String> {
+        let version: Option<(String,)> =
+            sqlx::query_as("SELECT xmin::text FROM public.person WHERE uid = $1")
+                .bind(uid)
+                .fetch_optional(client)
+                .await?;
+        version.map(|x| x.0).ok_or(ApiError::new(UserError::NoSuchUser))
+    }
+
+    /// List users in order of uid, with at most `max_count` users per page.
+    pub async fn list(client: &PgPool, page: &PageView, max_count: u16) -> Result<Vec<Self>> {
         let users: Vec<Person> = sqlx::query_as(
-            "SELECT uid, nick_name, avatar, is_disabled, is_admin, gender, country, province, city, language, create_time
-                 FROM public.person LIMIT $1 OFFSET $2")
-            .bind(page_size as i32)
-            .bind(((page_index - 1) * page_size) as i32)
+            "SELECT uid, nick_name, avatar, is_disabled, is_admin, gender, country, province, city, language, create_time, updated_at
+                 FROM public.person WHERE deleted_at IS NULL ORDER BY uid LIMIT $1 OFFSET $2")
+            .bind(page.count(max_count) as i64)
+            .bind(page.offset(max_count))
             .fetch_all(client)
             .await?;
         Ok(users)
     }
 
+    pub async fn count(client: &PgPool) -> Result<i64> {
+        let (count,): (i64,) =
+            sqlx::query_as("SELECT COUNT(*) FROM public.person WHERE deleted_at IS NULL")
+                .fetch_one(client)
+                .await?;
+        Ok(count)
+    }
+
     pub async fn get(client: &PgPool, uid: i32) -> Result<Person> {
         let user: Option<Person> = sqlx::query_as(
-            "SELECT uid, nick_name, avatar, is_disabled, is_admin, gender, country, province, city, language, create_time
-                FROM public.person WHERE uid = $1 LIMIT 1",
+            "SELECT uid, nick_name, avatar, is_disabled, is_admin, gender, country, province, city, language, create_time, updated_at
+                FROM public.person WHERE uid = $1 AND deleted_at IS NULL LIMIT 1",
         )
             .bind(uid)
             .fetch_optional(client)
@@ -186,6 +254,18 @@ impl Person {
         user.ok_or(ApiError::new(UserError::NoSuchUser))
     }
 
+    /// Whether the user is an administrator, or `None` if the user is not found, deleted or
+    /// disabled.
+    pub async fn get_active_role(client: &PgPool, uid: i32) -> Result<Option<bool>> {
+        let role: Option<(bool,)> = sqlx::query_as(
+            "SELECT is_admin FROM public.person WHERE uid = $1 AND deleted_at IS NULL AND is_disabled = false",
+        )
+        .bind(uid)
+        .fetch_optional(client)
+        .await?;
+        Ok(role.map(|x| x.0))
+    }
+
     pub async fn fuzzy_query(
         client: &PgPool,
         query_string: &String,
@@ -193,7 +273,7 @@ impl Person {
         count: u32,
     ) -> Result<Vec<Person>> {
         let users: Vec<Person> = sqlx::query_as(
-            "SELECT nick_name, avatar, is_disabled, is_admin, gender, country, province, city, language, create_time
+            "SELECT nick_name, avatar, is_disabled, is_admin, gender, country, province, city, language, create_time, updated_at
                 FROM public.person WHERE nick_name = $1
                 LIMIT $2 OFFSET $3",
         )
@@ -205,18 +285,147 @@ impl Person {
         Ok(users)
     }
 
-    /// Get identity info
+    /// Find the user by wechat openid, stored in the person row or, for users bound before, in the
+    /// wechat authentication. A new user is created if not found. Concurrent logins of the same
+    /// openid are serialized, so that only one user is created.
+    pub async fn find_or_create_by_wechat(client: &PgPool, openid: &str) -> Result<Person> {
+        let mut tx = begin(client).await?;
+        sqlx::query("SELECT pg_advisory_xact_lock(hashtext('wechat:' || $1))")
+            .bind(openid)
+            .execute(&mut tx)
+            .await?;
+        let user: Option<Person> = sqlx::query_as(
+            "SELECT uid, nick_name, avatar, is_disabled, is_admin, gender, country, province, city, language, create_time, updated_at
+                FROM public.person
+                WHERE deleted_at IS NULL AND (wechat_openid = $1
+                    OR uid IN (SELECT uid FROM public.authentication WHERE login_type = $2 AND account = $1))
+                LIMIT 1",
+        )
+        .bind(openid)
+        .bind(LOGIN_BY_WECHAT)
+        .fetch_optional(&mut tx)
+        .await?;
+
+        let user = match user {
+            Some(user) => user,
+            None => {
+                let mut user = Person::new();
+                user.nick_name = DEFAULT_WECHAT_NICK_NAME.to_string();
+                register_in(&mut tx, &mut user).await?;
+                let auth = Authentication::from_wechat(&openid.to_string());
+                update_authentication_in(&mut tx, user.uid, &auth).await?;
+                user
+            }
+        };
+        sqlx::query(
+            "UPDATE public.person SET wechat_openid = $2 WHERE uid = $1 AND wechat_openid IS DISTINCT FROM $2",
+        )
+        .bind(user.uid)
+        .bind(openid)
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(user)
+    }
+
+    /// Get the primary identity info
     pub async fn get_identity(client: &PgPool, uid: i32) -> Result<Option<Identity>> {
+        Self::select_identity(client, uid, None).await
+    }
+
+    /// Get the identity of the student id, or the primary one if it's not given.
+    pub async fn select_identity(
+        client: &PgPool,
+        uid: i32,
+        student_id: Option<&str>,
+    ) -> Result<Option<Identity>> {
         let identity: Option<Identity> = sqlx::query_as(
-            "SELECT uid, real_name, student_id, oa_secret, oa_certified, identity_number
-            FROM public.identities WHERE uid = $1",
+            "SELECT uid, real_name, student_id, oa_secret, oa_certified, identity_number, is_primary
+            FROM public.identities
+            WHERE uid = $1 AND (student_id = $2 OR ($2 IS NULL AND is_primary))",
         )
         .bind(uid)
+        .bind(student_id)
         .fetch_optional(client)
         .await?;
         Ok(identity)
     }
 
+    /// Get the identity whose campus credential is used for agent requests on behalf of the user.
+    /// The primary identity is used by default, and `None` is returned if there's no identity.
+    /// Return `UserError::IdentityNotOwned` if the student id is given but not of the user.
+    pub async fn select_owned_identity(
+        client: &PgPool,
+        uid: i32,
+        student_id: Option<&str>,
+    ) -> Result<Option<Identity>> {
+        let identity = Self::select_identity(client, uid, student_id).await?;
+        if identity.is_none() && student_id.is_some() {
+            return Err(ApiError::new(UserError::IdentityNotOwned));
+        }
+        Ok(identity)
+    }
+
+    /// List identities of the user, the primary one first.
+    pub async fn list_identities(client: &PgPool, uid: i32) -> Result<Vec<Identity>> {
+        let identities: Vec<Identity> = sqlx::query_as(
+            "SELECT uid, real_name, student_id, oa_secret, oa_certified, identity_number, is_primary
+            FROM public.identities
+            WHERE uid = $1
+            ORDER BY is_primary DESC, student_id",
+        )
+        .bind(uid)
+        .fetch_all(client)
+        .await?;
+        Ok(identities)
+    }
+
+    /// Replace the OA secret of the identity, and of the campus authentication by the student id,
+    /// after it's changed on campus.
+    /// Update the OA secret of the identity, and return the new row version of the person, which is
+    /// checked like `update`.
+    pub async fn update_oa_secret(
+        &self,
+        client: &PgPool,
+        student_id: &str,
+        oa_secret: &str,
+        version: Option<&str>,
+    ) -> Result<String> {
+        let mut tx = begin(client).await?;
+
+        let new_version: Option<(String,)> = sqlx::query_as(
+            "UPDATE public.person SET updated_at = now()
+                WHERE uid = $1 AND ($2::text IS NULL OR xmin::text = $2)
+                RETURNING xmin::text",
+        )
+        .bind(self.uid)
+        .bind(version)
+        .fetch_optional(&mut tx)
+        .await?;
+        let new_version = check_version(new_version, version)?;
+        sqlx::query(
+            "UPDATE public.identities SET oa_secret = $3, oa_certified = true
+            WHERE uid = $1 AND student_id = $2",
+        )
+        .bind(self.uid)
+        .bind(student_id)
+        .bind(oa_secret)
+        .execute(&mut tx)
+        .await?;
+        sqlx::query(
+            "UPDATE public.authentication SET credential = $3
+            WHERE uid = $1 AND account = $2 AND login_type = $4",
+        )
+        .bind(self.uid)
+        .bind(student_id)
+        .bind(oa_secret)
+        .bind(LOGIN_BY_CAMPUS_WEB)
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(new_version)
+    }
+
     /// Set identity info
     pub async fn set_identity(&self, client: &PgPool, identity: &mut Identity) -> Result<()> {
         if let Some(id_number) = &identity.identity_number {
@@ -226,25 +435,119 @@ impl Person {
         }
         if let Some(oa_secret) = &identity.oa_secret {
             // Throw UserError::OaSecretFailed if password is wrong.
-            Identity::validate_oa_account(&identity.student_id, oa_secret).await?;
+            // Card numbers are saved as the student ids.
+            identity.student_id =
+                Identity::validate_oa_account(client, &identity.student_id, oa_secret).await?;
             identity.oa_certified = true;
         }
-        let _ = sqlx::query(
-            "INSERT INTO public.identities (uid, real_name, student_id, oa_secret, oa_certified, identity_number)
-                VALUES ($1, $2, $3, $4, true, $5)
-                ON CONFLICT (uid)
-                DO UPDATE SET oa_secret = $4, oa_certified = true, identity_number = $5;")
+        let mut tx = begin(client).await?;
+        lock_student_id(&mut tx, &identity.student_id).await?;
+        let (bound,): (bool,) = sqlx::query_as(
+            "SELECT EXISTS(SELECT 1 FROM public.identities WHERE student_id = $1 AND uid <> $2)",
+        )
+        .bind(&identity.student_id)
+        .bind(self.uid)
+        .fetch_one(&mut tx)
+        .await?;
+        if bound {
+            return Err(ApiError::new(UserError::AlreadyBound));
+        }
+
+        // The first identity is primary, and a new primary one replaces the old.
+        let (has_primary,): (bool,) = sqlx::query_as(
+            "SELECT EXISTS(SELECT 1 FROM public.identities WHERE uid = $1 AND student_id <> $2 AND is_primary)",
+        )
+        .bind(self.uid)
+        .bind(&identity.student_id)
+        .fetch_one(&mut tx)
+        .await?;
+        identity.is_primary = identity.is_primary || !has_primary;
+        if identity.is_primary {
+            sqlx::query(
+                "UPDATE public.identities SET is_primary = false WHERE uid = $1 AND student_id <> $2",
+            )
+            .bind(self.uid)
+            .bind(&identity.student_id)
+            .execute(&mut tx)
+            .await?;
+        }
+        sqlx::query(
+            "INSERT INTO public.identities (uid, real_name, student_id, oa_secret, oa_certified, identity_number, is_primary)
+                VALUES ($1, $2, $3, $4, true, $5, $6)
+                ON CONFLICT (uid, student_id)
+                DO UPDATE SET oa_secret = $4, oa_certified = true, identity_number = $5, is_primary = $6;")
             .bind(self.uid)
             .bind(&identity.real_name)
             .bind(&identity.student_id)
             .bind(&identity.oa_secret)
             .bind(&identity.identity_number)
-            .execute(client)
-            .await?;
+            .bind(identity.is_primary)
+            .execute(&mut tx)
+            .await
+            .map_err(check_bound)?;
+        tx.commit().await?;
         Ok(())
     }
+
+    /// Move the identity of the student id to the user, with the campus authentication by it,
+    /// unless the user has one already. It's primary if the user has no primary identity, and the
+    /// next identity of the last owner becomes primary if needed. Return the uid of the last owner,
+    /// or `None` if the student id is not bound.
+    pub async fn reassign_identity(client: &PgPool, student_id: &str, uid: i32) -> Result<Option<i32>> {
+        let mut tx = begin(client).await?;
+
+        let owner: Option<(i32,)> =
+            sqlx::query_as("SELECT uid FROM public.identities WHERE student_id = $1 FOR UPDATE")
+                .bind(student_id)
+                .fetch_optional(&mut tx)
+                .await?;
+        let owner = match owner {
+            Some((owner,)) if owner != uid => owner,
+            other => return Ok(other.map(|(x,)| x)),
+        };
+        sqlx::query(
+            "UPDATE public.identities
+            SET uid = $2, is_primary = NOT EXISTS(SELECT 1 FROM public.identities WHERE uid = $2 AND is_primary)
+            WHERE student_id = $1",
+        )
+        .bind(student_id)
+        .bind(uid)
+        .execute(&mut tx)
+        .await?;
+        sqlx::query(
+            "UPDATE public.identities SET is_primary = true
+            WHERE uid = $1 AND NOT EXISTS(SELECT 1 FROM public.identities WHERE uid = $1 AND is_primary)
+                AND student_id = (SELECT MIN(student_id) FROM public.identities WHERE uid = $1)",
+        )
+        .bind(owner)
+        .execute(&mut tx)
+        .await?;
+        sqlx::query(
+            "UPDATE public.authentication SET uid = $2
+            WHERE login_type = $3 AND account = $1
+                AND NOT EXISTS(SELECT 1 FROM public.authentication WHERE uid = $2 AND login_type = $3)",
+        )
+        .bind(student_id)
+        .bind(uid)
+        .bind(LOGIN_BY_CAMPUS_WEB)
+        .execute(&mut tx)
+        .await?;
+        sqlx::query(
+            "DELETE FROM public.authentication WHERE uid = $1 AND login_type = $2 AND account = $3",
+        )
+        .bind(owner)
+        .bind(LOGIN_BY_CAMPUS_WEB)
+        .bind(student_id)
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(Some(owner))
+    }
 }
 
+/// Nick name of users created by wechat login, which can be changed later.
+const DEFAULT_WECHAT_NICK_NAME: &str = "微信用户";
+
 /// Default avatar for new user.
 pub fn get_default_avatar() -> &'static str {
     "https://kite.sunnysab.cn/static/icon.png"
@@ -264,6 +567,480 @@ impl Default for Person {
             city: None,
             language: None,
             create_time: Utc::now().naive_local(),
+            updated_at: Local::now(),
+        }
+    }
+}
+
+/// Create the user, and set its uid. See `Person::register`.
+async fn register_in(tx: &mut Transaction<'_, Postgres>, user: &mut Person) -> Result<()> {
+    let (uid,): (i32,) = sqlx::query_as(
+        "INSERT INTO public.person
+            (nick_name, avatar, country, province, city, language, create_time)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            RETURNING uid",
+    )
+    .bind(&user.nick_name)
+    .bind(&user.avatar)
+    .bind(&user.country)
+    .bind(&user.province)
+    .bind(&user.city)
+    .bind(&user.language)
+    .bind(user.create_time)
+    .fetch_one(&mut *tx)
+    .await?;
+    user.uid = uid;
+    Ok(())
+}
+
+/// Bind authentication of the user, see `Person::update_authentication`.
+/// Serialize binds of the student id until the transaction ends, so that the check of its owner
+/// holds even where the unique indexes are not created yet.
+async fn lock_student_id(tx: &mut Transaction<'_, Postgres>, student_id: &str) -> Result<()> {
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtext($1))")
+        .bind(student_id)
+        .execute(&mut *tx)
+        .await?;
+    Ok(())
+}
+
+pub(super) async fn update_authentication_in(
+    tx: &mut Transaction<'_, Postgres>,
+    uid: i32,
+    auth: &Authentication,
+) -> Result<()> {
+    if auth.login_type == LOGIN_BY_CAMPUS_WEB {
+        lock_student_id(tx, &auth.account).await?;
+        let (bound,): (bool,) = sqlx::query_as(
+            "SELECT EXISTS(SELECT 1 FROM public.authentication WHERE login_type = $1 AND account = $2 AND uid <> $3)",
+        )
+        .bind(LOGIN_BY_CAMPUS_WEB)
+        .bind(&auth.account)
+        .bind(uid)
+        .fetch_one(&mut *tx)
+        .await?;
+        if bound {
+            return Err(ApiError::new(UserError::AlreadyBound));
+        }
+    }
+    // Note: Alter username is not allowed.
+    let _ = sqlx::query(
+        "INSERT INTO
+                authentication (uid, login_type, account, credential) VALUES ($1, $2, $3, $4)
+            ON CONFLICT (uid, login_type)
+            DO UPDATE SET credential = $4",
+    )
+    .bind(uid)
+    .bind(auth.login_type)
+    .bind(&auth.account)
+    .bind(&auth.credential)
+    .execute(&mut *tx)
+    .await
+    .map_err(check_bound)?;
+
+    Ok(())
+}
+
+/// Get the new row version of an update that checks the expected `version`, if given. No row updated
+/// means the version mismatches, or the user doesn't exist.
+fn check_version(new_version: Option<(String,)>, version: Option<&str>) -> Result<String> {
+    match (new_version, version) {
+        (Some((v,)), _) => Ok(v),
+        (None, Some(_)) => Err(ApiError::new(CommonError::PreconditionFailed)),
+        (None, None) => Err(ApiError::new(UserError::NoSuchUser)),
+    }
+}
+
+/// Refuse if the user is the last enabled administrator, before the user is revoked, deleted or
+/// disabled. Administrators are locked, so that two of them can't remove each other at the same time.
+pub(super) async fn check_last_admin_in(tx: &mut Transaction<'_, Postgres>, uid: i32) -> Result<()> {
+    let admins: Vec<(i32,)> = sqlx::query_as(
+        "SELECT uid FROM public.person WHERE is_admin AND NOT is_disabled AND deleted_at IS NULL FOR UPDATE",
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    if admins.len() == 1 && admins[0].0 == uid {
+        return Err(ApiError::new(UserError::LastAdmin));
+    }
+    Ok(())
+}
+
+async fn set_admin_in(tx: &mut Transaction<'_, Postgres>, uid: i32, is_admin: bool) -> Result<()> {
+    if !is_admin {
+        check_last_admin_in(tx, uid).await?;
+    }
+
+    let result =
+        sqlx::query("UPDATE public.person SET is_admin = $2, updated_at = now() WHERE uid = $1 AND deleted_at IS NULL")
+            .bind(uid)
+            .bind(is_admin)
+            .execute(&mut *tx)
+            .await?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::new(UserError::NoSuchUser));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::test::db_pool;
+    use chrono::DateTime;
+
+    #[test]
+    pub fn test_serialize_timestamps() {
+        let value = serde_json::to_value(Person::default()).unwrap();
+
+        for key in &["createTime", "updateTime"] {
+            let time = value[key].as_str().unwrap();
+            assert!(DateTime::parse_from_rfc3339(time).is_ok(), "{}: {}", key, time);
+        }
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    async fn test_update_if_match() {
+        let pool = db_pool().await;
+        let mut person = Person::new();
+
+        person.nick_name = "test".to_string();
+        person.register(&pool).await.unwrap();
+        let stale = Person::get_version(&pool, person.uid).await.unwrap();
+        let current = person.update(&pool, Some(&stale)).await.unwrap();
+        assert_ne!(stale, current);
+
+        // The row has changed since the stale version.
+        let result = person.update(&pool, Some(&stale)).await;
+        assert_eq!(result, Err(ApiError::new(CommonError::PreconditionFailed)));
+        assert!(person.update(&pool, Some(&current)).await.is_ok());
+
+        sqlx::query("DELETE FROM public.person WHERE uid = $1")
+            .bind(person.uid)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    async fn test_status_and_secret_if_match() {
+        let pool = db_pool().await;
+        let mut person = Person::new();
+
+        person.register(&pool).await.unwrap();
+        let stale = Person::get_version(&pool, person.uid).await.unwrap();
+        let current = Person::set_disabled(&pool, person.uid, true, Some(&stale))
+            .await
+            .unwrap();
+        assert!(Person::get(&pool, person.uid).await.unwrap().is_disabled);
+
+        let result = Person::set_disabled(&pool, person.uid, false, Some(&stale)).await;
+        assert_eq!(result, Err(ApiError::new(CommonError::PreconditionFailed)));
+        let result = person
+            .update_oa_secret(&pool, "1910000000", "secret", Some(&stale))
+            .await;
+        assert_eq!(result, Err(ApiError::new(CommonError::PreconditionFailed)));
+        let current = person
+            .update_oa_secret(&pool, "1910000000", "secret", Some(&current))
+            .await
+            .unwrap();
+        Person::set_disabled(&pool, person.uid, false, Some(&current))
+            .await
+            .unwrap();
+        assert!(!Person::get(&pool, person.uid).await.unwrap().is_disabled);
+
+        sqlx::query("DELETE FROM public.person WHERE uid = $1")
+            .bind(person.uid)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    async fn test_list_pages() {
+        let pool = db_pool().await;
+        let before = Person::count(&pool).await.unwrap();
+        let mut uids = Vec::new();
+        for _ in 0..3 {
+            let mut person = Person::new();
+            person.register(&pool).await.unwrap();
+            uids.push(person.uid);
         }
+        let total = Person::count(&pool).await.unwrap();
+        assert_eq!(total, before + 3);
+
+        let mut listed = Vec::new();
+        for index in 1..=(total as u16).div_ceil(2) {
+            let page = PageView {
+                index: Some(index),
+                count: Some(2),
+            };
+            let users = Person::list(&pool, &page, 100).await.unwrap();
+            assert!(users.len() <= 2);
+            listed.extend(users.into_iter().map(|x| x.uid));
+        }
+        // Each user is listed once, in order.
+        assert_eq!(listed.len() as i64, total);
+        assert!(listed.windows(2).all(|x| x[0] < x[1]));
+        assert!(uids.iter().all(|x| listed.contains(x)));
+
+        sqlx::query("DELETE FROM public.person WHERE uid = ANY($1)")
+            .bind(&uids)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    async fn test_multiple_identities() {
+        let pool = db_pool().await;
+        sqlx::query("DELETE FROM public.identities WHERE student_id IN ('TEST000001', 'TEST000002')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let mut person = Person::new();
+        person.register(&pool).await.unwrap();
+
+        let mut undergraduate = Identity::new(person.uid, "TEST000001".to_string());
+        person.set_identity(&pool, &mut undergraduate).await.unwrap();
+        assert!(undergraduate.is_primary);
+        let mut graduate = Identity::new(person.uid, "TEST000002".to_string());
+        person.set_identity(&pool, &mut graduate).await.unwrap();
+        assert!(!graduate.is_primary);
+
+        let primary = Person::get_identity(&pool, person.uid).await.unwrap().unwrap();
+        assert_eq!(primary.student_id, "TEST000001");
+        let selected = Person::select_identity(&pool, person.uid, Some("TEST000002"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(selected.student_id, "TEST000002");
+        // Credentials for agent requests, of the primary identity by default.
+        let selected = Person::select_owned_identity(&pool, person.uid, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(selected.student_id, "TEST000001");
+        let selected = Person::select_owned_identity(&pool, person.uid, Some("TEST000002"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(selected.student_id, "TEST000002");
+        // Of another user.
+        let result = Person::select_owned_identity(&pool, person.uid, Some("TEST000003")).await;
+        assert!(matches!(result, Err(e) if e == ApiError::new(UserError::IdentityNotOwned)));
+        assert!(Person::select_owned_identity(&pool, -1, None)
+            .await
+            .unwrap()
+            .is_none());
+
+        graduate.is_primary = true;
+        person.set_identity(&pool, &mut graduate).await.unwrap();
+        let identities = Person::list_identities(&pool, person.uid).await.unwrap();
+        let ids: Vec<_> = identities
+            .iter()
+            .map(|x| (x.student_id.as_str(), x.is_primary))
+            .collect();
+        assert_eq!(ids, vec![("TEST000002", true), ("TEST000001", false)]);
+
+        sqlx::query("DELETE FROM public.identities WHERE uid = $1")
+            .bind(person.uid)
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM public.person WHERE uid = $1")
+            .bind(person.uid)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    async fn test_unique_student_id() {
+        let pool = db_pool().await;
+        let (student_id, other_id) = ("TEST000041", "TEST000042");
+        let cleanup = |uids: Vec<i32>| {
+            let pool = pool.clone();
+            async move {
+                sqlx::query("DELETE FROM public.identities WHERE student_id IN ($1, $2)")
+                    .bind(student_id)
+                    .bind(other_id)
+                    .execute(&pool)
+                    .await
+                    .unwrap();
+                sqlx::query("DELETE FROM public.authentication WHERE login_type = $1 AND account = $2")
+                    .bind(LOGIN_BY_CAMPUS_WEB)
+                    .bind(student_id)
+                    .execute(&pool)
+                    .await
+                    .unwrap();
+                for uid in uids {
+                    sqlx::query("DELETE FROM public.person WHERE uid = $1")
+                        .bind(uid)
+                        .execute(&pool)
+                        .await
+                        .unwrap();
+                }
+            }
+        };
+        cleanup(vec![]).await;
+        let (mut owner, mut other) = (Person::new(), Person::new());
+        owner.register(&pool).await.unwrap();
+        other.register(&pool).await.unwrap();
+        let auth = || Authentication::from_campus_auth(student_id.to_string(), "secret".to_string());
+        let is_bound =
+            |result: Result<()>| matches!(result, Err(e) if e == ApiError::new(UserError::AlreadyBound));
+
+        owner
+            .set_identity(&pool, &mut Identity::new(owner.uid, student_id.to_string()))
+            .await
+            .unwrap();
+        owner
+            .set_identity(&pool, &mut Identity::new(owner.uid, other_id.to_string()))
+            .await
+            .unwrap();
+        owner.update_authentication(&pool, &auth()).await.unwrap();
+        // Bound by another user.
+        let result = other
+            .set_identity(&pool, &mut Identity::new(other.uid, student_id.to_string()))
+            .await;
+        assert!(is_bound(result));
+        assert!(is_bound(other.update_authentication(&pool, &auth()).await));
+        // Binding again by the owner is fine.
+        owner
+            .set_identity(&pool, &mut Identity::new(owner.uid, student_id.to_string()))
+            .await
+            .unwrap();
+
+        // Reassigned by an administrator.
+        let last_owner = Person::reassign_identity(&pool, student_id, other.uid)
+            .await
+            .unwrap();
+        assert_eq!(last_owner, Some(owner.uid));
+        let identity = Person::get_identity(&pool, other.uid).await.unwrap().unwrap();
+        assert_eq!(identity.student_id, student_id);
+        assert!(identity.is_primary);
+        // The next identity of the last owner becomes primary.
+        let identity = Person::get_identity(&pool, owner.uid).await.unwrap().unwrap();
+        assert_eq!(identity.student_id, other_id);
+        let (auth_uid,): (i32,) = sqlx::query_as(
+            "SELECT uid FROM public.authentication WHERE login_type = $1 AND account = $2",
+        )
+        .bind(LOGIN_BY_CAMPUS_WEB)
+        .bind(student_id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(auth_uid, other.uid);
+        let result = owner
+            .set_identity(&pool, &mut Identity::new(owner.uid, student_id.to_string()))
+            .await;
+        assert!(is_bound(result));
+        // Already of the user, or not bound.
+        let result = Person::reassign_identity(&pool, student_id, other.uid).await;
+        assert_eq!(result.unwrap(), Some(other.uid));
+        assert_eq!(
+            Person::reassign_identity(&pool, "TEST000043", other.uid)
+                .await
+                .unwrap(),
+            None
+        );
+
+        cleanup(vec![owner.uid, other.uid]).await;
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    async fn test_find_or_create_by_wechat() {
+        let pool = db_pool().await;
+        let openid = format!("test-openid-{}", uuid::Uuid::new_v4());
+
+        let created = Person::find_or_create_by_wechat(&pool, &openid).await.unwrap();
+        let found = Person::find_or_create_by_wechat(&pool, &openid).await.unwrap();
+        assert_eq!(created.uid, found.uid);
+        // Concurrent first logins create one user.
+        let racing_openid = format!("test-openid-{}", uuid::Uuid::new_v4());
+        let logins = (0..4).map(|_| Person::find_or_create_by_wechat(&pool, &racing_openid));
+        let racing: Vec<i32> = futures::future::join_all(logins)
+            .await
+            .into_iter()
+            .map(|x| x.unwrap().uid)
+            .collect();
+        assert!(racing.iter().all(|&x| x == racing[0]));
+        // Bound by wechat authentication before.
+        let mut legacy = Person::new();
+        legacy.register(&pool).await.unwrap();
+        let legacy_openid = format!("test-openid-{}", uuid::Uuid::new_v4());
+        legacy
+            .update_authentication(&pool, &Authentication::from_wechat(&legacy_openid))
+            .await
+            .unwrap();
+        let found = Person::find_or_create_by_wechat(&pool, &legacy_openid)
+            .await
+            .unwrap();
+        assert_eq!(found.uid, legacy.uid);
+        let (stored,): (Option<String>,) =
+            sqlx::query_as("SELECT wechat_openid FROM public.person WHERE uid = $1")
+                .bind(legacy.uid)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(stored, Some(legacy_openid));
+
+        let uids = vec![created.uid, legacy.uid, racing[0]];
+        sqlx::query("DELETE FROM public.authentication WHERE uid = ANY($1)")
+            .bind(&uids)
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM public.person WHERE uid = ANY($1)")
+            .bind(&uids)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    async fn test_set_admin() {
+        let pool = db_pool().await;
+        let (mut alice, mut bob) = (Person::new(), Person::new());
+        alice.register(&pool).await.unwrap();
+        bob.register(&pool).await.unwrap();
+        let is_admin = |uid: i32| {
+            sqlx::query_as::<_, (bool,)>("SELECT is_admin FROM public.person WHERE uid = $1").bind(uid)
+        };
+
+        // Other administrators in the database are revoked in the transaction, which is rolled back.
+        let mut tx = pool.begin().await.unwrap();
+        sqlx::query("UPDATE public.person SET is_admin = false")
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        // Promote.
+        set_admin_in(&mut tx, alice.uid, true).await.unwrap();
+        set_admin_in(&mut tx, bob.uid, true).await.unwrap();
+        assert!(is_admin(alice.uid).fetch_one(&mut tx).await.unwrap().0);
+        // Demote.
+        set_admin_in(&mut tx, alice.uid, false).await.unwrap();
+        assert!(!is_admin(alice.uid).fetch_one(&mut tx).await.unwrap().0);
+        // Bob is the last administrator.
+        let result = set_admin_in(&mut tx, bob.uid, false).await;
+        assert_eq!(result, Err(ApiError::new(UserError::LastAdmin)));
+        assert!(is_admin(bob.uid).fetch_one(&mut tx).await.unwrap().0);
+        let result = set_admin_in(&mut tx, -1, true).await;
+        assert_eq!(result, Err(ApiError::new(UserError::NoSuchUser)));
+        tx.rollback().await.unwrap();
+
+        sqlx::query("DELETE FROM public.person WHERE uid = $1 OR uid = $2")
+            .bind(alice.uid)
+            .bind(bob.uid)
+            .execute(&pool)
+            .await
+            .unwrap();
     }
 }
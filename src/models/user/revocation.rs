@@ -0,0 +1,195 @@
+//! Revoked tokens, which are rejected before they expire. Revocations are stored in the database,
+//! and all unexpired ones are kept in memory, so that checking a token doesn't query the database.
+//! They're synced from the database periodically, so that revocations on other instances take
+//! effect. Tokens of a user can be revoked at once, when tokens issued before are rejected.
+
+use crate::error::Result;
+use chrono::Utc;
+use log::error;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// `revoked_at` before which is in seconds, as stored by earlier versions, rather than milliseconds.
+const SECONDS_BEFORE: i64 = 100_000_000_000;
+
+lazy_static! {
+    /// Revoked tokens in memory.
+    static ref REVOKED_TOKENS: RevokedSet = RevokedSet::new();
+    /// Uid to the time in unix timestamp of milliseconds, before which tokens of the user are revoked.
+    static ref REVOKED_USERS: RwLock<HashMap<i32, i64>> = Default::default();
+}
+
+/// Token jti to its expiry time in unix timestamp.
+struct RevokedSet {
+    items: RwLock<HashMap<Uuid, i64>>,
+}
+
+impl RevokedSet {
+    fn new() -> Self {
+        Self {
+            items: Default::default(),
+        }
+    }
+
+    /// Add a revoked token, and drop expired ones which are rejected anyway.
+    fn insert(&self, jti: Uuid, exp: i64, now: i64) {
+        let mut items = self.items.write().unwrap();
+
+        items.retain(|_, item_exp| *item_exp >= now);
+        items.insert(jti, exp);
+    }
+
+    fn contains(&self, jti: &Uuid) -> bool {
+        self.items.read().unwrap().contains_key(jti)
+    }
+}
+
+/// Load unexpired revoked tokens from database, on startup and periodically.
+pub async fn load_revoked_tokens(pool: &PgPool) -> Result<()> {
+    let now = Utc::now().timestamp();
+    let tokens: Vec<(Uuid, i64)> =
+        sqlx::query_as("SELECT jti, exp FROM public.revoked_tokens WHERE exp >= $1")
+            .bind(now)
+            .fetch_all(pool)
+            .await?;
+
+    for (jti, exp) in tokens {
+        REVOKED_TOKENS.insert(jti, exp, now);
+    }
+
+    let users: Vec<(i32, i64)> = sqlx::query_as("SELECT uid, revoked_at FROM public.revoked_users")
+        .fetch_all(pool)
+        .await?;
+    REVOKED_USERS
+        .write()
+        .unwrap()
+        .extend(
+            users
+                .into_iter()
+                .map(|(uid, revoked_at)| match revoked_at < SECONDS_BEFORE {
+                    true => (uid, revoked_at * 1000),
+                    false => (uid, revoked_at),
+                }),
+        );
+    Ok(())
+}
+
+/// Load revoked tokens every `interval`, until the process exits.
+pub async fn sync_revoked_tokens(pool: PgPool, interval: Duration) {
+    loop {
+        tokio::time::delay_for(interval).await;
+
+        if let Err(e) = load_revoked_tokens(&pool).await {
+            error!("Failed to sync revoked tokens: {}", e);
+        }
+    }
+}
+
+/// Revoke the token, so that it's rejected before expiry.
+pub async fn revoke_token(pool: &PgPool, jti: Uuid, exp: i64) -> Result<()> {
+    let now = Utc::now().timestamp();
+
+    sqlx::query("INSERT INTO public.revoked_tokens (jti, exp) VALUES ($1, $2) ON CONFLICT DO NOTHING")
+        .bind(jti)
+        .bind(exp)
+        .execute(pool)
+        .await?;
+    sqlx::query("DELETE FROM public.revoked_tokens WHERE exp < $1")
+        .bind(now)
+        .execute(pool)
+        .await?;
+
+    REVOKED_TOKENS.insert(jti, exp, now);
+    Ok(())
+}
+
+/// Revoke all tokens of the user issued till now. The user has to log in again.
+pub async fn revoke_user_tokens(pool: &PgPool, uid: i32) -> Result<()> {
+    let now = Utc::now().timestamp_millis();
+
+    sqlx::query(
+        "INSERT INTO public.revoked_users (uid, revoked_at) VALUES ($1, $2)
+            ON CONFLICT (uid) DO UPDATE SET revoked_at = $2",
+    )
+    .bind(uid)
+    .bind(now)
+    .execute(pool)
+    .await?;
+
+    REVOKED_USERS.write().unwrap().insert(uid, now);
+    Ok(())
+}
+
+/// Whether the token is revoked.
+pub fn is_revoked(jti: &Uuid) -> bool {
+    REVOKED_TOKENS.contains(jti)
+}
+
+/// Whether the token of the user issued at `issued_at` in milliseconds is revoked with all tokens
+/// of the user.
+pub fn is_user_revoked(uid: i32, issued_at: i64) -> bool {
+    match REVOKED_USERS.read().unwrap().get(&uid) {
+        Some(revoked_at) => issued_at < *revoked_at,
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_user_revoked, load_revoked_tokens, RevokedSet, REVOKED_USERS};
+    use crate::models::test::db_pool;
+    use uuid::Uuid;
+
+    #[test]
+    pub fn test_revoked_set() {
+        let set = RevokedSet::new();
+        let (expired, revoked) = (Uuid::new_v4(), Uuid::new_v4());
+
+        set.insert(expired, 100, 0);
+        assert!(set.contains(&expired));
+        // Expired tokens are pruned on next insertion.
+        set.insert(revoked, 300, 200);
+        assert!(!set.contains(&expired));
+        assert!(set.contains(&revoked));
+        assert!(!set.contains(&Uuid::new_v4()));
+    }
+
+    #[test]
+    pub fn test_revoked_user() {
+        // Uids of real users are positive.
+        REVOKED_USERS.write().unwrap().insert(-1, 1_000_500);
+
+        assert!(is_user_revoked(-1, 1_000_000));
+        assert!(is_user_revoked(-1, 1_000_499));
+        // Tokens issued in the same second, after the revocation, are accepted.
+        assert!(!is_user_revoked(-1, 1_000_500));
+        assert!(!is_user_revoked(-1, 1_000_900));
+        assert!(!is_user_revoked(-2, 0));
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    async fn test_load_revoked_users() {
+        let pool = db_pool().await;
+        // Revoked by another instance, and by an earlier version in seconds.
+        sqlx::query(
+            "INSERT INTO public.revoked_users (uid, revoked_at) VALUES (-3, 1600000000500), (-4, 1600000000)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        load_revoked_tokens(&pool).await.unwrap();
+        sqlx::query("DELETE FROM public.revoked_users WHERE uid < 0")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(is_user_revoked(-3, 1_600_000_000_499));
+        assert!(!is_user_revoked(-3, 1_600_000_000_500));
+        assert!(is_user_revoked(-4, 1_599_999_999_999));
+        assert!(!is_user_revoked(-4, 1_600_000_000_000));
+    }
+}
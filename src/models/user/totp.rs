@@ -0,0 +1,253 @@
+//! Optional TOTP two-factor authentication of administrators, as in RFC 6238. Codes are six digits
+//! of HMAC-SHA1 with a step of 30 seconds, which authenticator apps support by default. Secrets are
+//! stored in table `public.user_totp`, with the last step accepted so that a code can't be replayed.
+
+use super::UserError;
+use crate::error::{ApiError, Result};
+use chrono::Utc;
+use rand::RngCore;
+use sqlx::{Done, PgPool};
+use totp_lite::{totp_custom, Sha1};
+
+/// Seconds of a step.
+const STEP: u64 = 30;
+/// Digits of a code.
+const DIGITS: u32 = 6;
+/// Bytes of a secret, as recommended by RFC 4226.
+const SECRET_LENGTH: usize = 20;
+
+/// Encode in base32 without padding, as secrets are given to authenticator apps.
+fn base32_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+    let mut result = String::with_capacity((data.len() * 8).div_ceil(5));
+    let (mut buffer, mut bits) = (0u32, 0);
+
+    for byte in data {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            result.push(ALPHABET[(buffer >> bits) as usize & 0x1f] as char);
+        }
+    }
+    if bits > 0 {
+        result.push(ALPHABET[(buffer << (5 - bits)) as usize & 0x1f] as char);
+    }
+    result
+}
+
+/// Secret of a user.
+#[derive(sqlx::FromRow)]
+struct StoredSecret {
+    secret: Vec<u8>,
+    /// Whether the enrollment is confirmed by a code.
+    enabled: bool,
+    /// The last step accepted.
+    last_step: i64,
+}
+
+impl StoredSecret {
+    /// Find the step of the code within `skew` steps around `now`, which must be later than the
+    /// last step accepted.
+    fn check(&self, code: &str, now: u64, skew: u64) -> Result<i64> {
+        let current = now / STEP;
+        let code = code.trim();
+
+        (current.saturating_sub(skew)..=current + skew)
+            .find(|step| totp_custom::<Sha1>(STEP, DIGITS, &self.secret, step * STEP) == code)
+            .map(|step| step as i64)
+            .filter(|step| *step > self.last_step)
+            .ok_or_else(|| ApiError::new(UserError::TotpMismatch))
+    }
+}
+
+/// Uri of the secret to be scanned by authenticator apps, in the `otpauth://` scheme.
+fn otpauth_uri(issuer: &str, account: &str, secret: &[u8]) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+        issuer = urlencoding::encode(issuer),
+        account = urlencoding::encode(account),
+        secret = base32_encode(secret),
+        digits = DIGITS,
+        period = STEP,
+    )
+}
+
+fn now() -> u64 {
+    Utc::now().timestamp() as u64
+}
+
+pub struct TotpManager<'a> {
+    pool: &'a PgPool,
+}
+
+impl<'a> TotpManager<'a> {
+    pub fn new(pool: &'a PgPool) -> Self {
+        Self { pool }
+    }
+
+    async fn query(&self, uid: i32) -> Result<Option<StoredSecret>> {
+        let stored =
+            sqlx::query_as("SELECT secret, enabled, last_step FROM public.user_totp WHERE uid = $1")
+                .bind(uid)
+                .fetch_optional(self.pool)
+                .await?;
+        Ok(stored)
+    }
+
+    /// Whether the user has confirmed two-factor authentication.
+    pub async fn is_enabled(&self, uid: i32) -> Result<bool> {
+        Ok(self.query(uid).await?.map(|x| x.enabled).unwrap_or(false))
+    }
+
+    /// Generate a new secret for the user, which replaces an unconfirmed one, and return the
+    /// `otpauth://` uri of it.
+    pub async fn enroll(&self, uid: i32, issuer: &str) -> Result<String> {
+        let mut secret = vec![0u8; SECRET_LENGTH];
+        rand::thread_rng().fill_bytes(&mut secret);
+
+        let result = sqlx::query(
+            "INSERT INTO public.user_totp (uid, secret, enabled, last_step)
+                VALUES ($1, $2, FALSE, 0)
+                ON CONFLICT (uid)
+                DO UPDATE SET secret = $2, last_step = 0 WHERE user_totp.enabled = FALSE",
+        )
+        .bind(uid)
+        .bind(&secret)
+        .execute(self.pool)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(ApiError::new(UserError::TotpEnabled));
+        }
+        Ok(otpauth_uri(issuer, &uid.to_string(), &secret))
+    }
+
+    /// Accept the code of the user if it's in the window of `skew` steps and not used before.
+    /// Codes of an unconfirmed secret are accepted only if `confirming`, which enables it.
+    async fn accept(&self, uid: i32, code: &str, skew: u64, confirming: bool) -> Result<()> {
+        let stored = self
+            .query(uid)
+            .await?
+            .filter(|x| x.enabled != confirming)
+            .ok_or_else(|| ApiError::new(UserError::TotpNotEnrolled))?;
+        let step = stored.check(code, now(), skew)?;
+
+        // Conditioned on the last step, so that concurrent requests with the same code can't both
+        // succeed.
+        let result = sqlx::query(
+            "UPDATE public.user_totp SET enabled = TRUE, last_step = $2 WHERE uid = $1 AND last_step < $2",
+        )
+        .bind(uid)
+        .bind(step)
+        .execute(self.pool)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(ApiError::new(UserError::TotpMismatch));
+        }
+        Ok(())
+    }
+
+    /// Confirm the enrollment by a code from the authenticator app.
+    pub async fn confirm(&self, uid: i32, code: &str, skew: u64) -> Result<()> {
+        self.accept(uid, code, skew, true).await
+    }
+
+    /// Check the code on login.
+    pub async fn verify(&self, uid: i32, code: &str, skew: u64) -> Result<()> {
+        self.accept(uid, code, skew, false).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::test::db_pool;
+
+    fn stored(last_step: i64) -> StoredSecret {
+        StoredSecret {
+            // Secret of test vectors in RFC 6238.
+            secret: b"12345678901234567890".to_vec(),
+            enabled: true,
+            last_step,
+        }
+    }
+
+    #[test]
+    pub fn test_otpauth_uri() {
+        assert_eq!(
+            base32_encode(b"12345678901234567890"),
+            "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ"
+        );
+        assert_eq!(base32_encode(b"f"), "MY");
+
+        let uri = otpauth_uri("Kite Admin", "5", b"12345678901234567890");
+        assert_eq!(
+            uri,
+            "otpauth://totp/Kite%20Admin:5?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&issuer=Kite%20Admin\
+             &algorithm=SHA1&digits=6&period=30"
+        );
+    }
+
+    #[test]
+    pub fn test_valid_code() {
+        // The 8-digit code at 59 is 94287082 in RFC 6238.
+        let secret = stored(0);
+        assert_eq!(secret.check("287082", 59, 1), Ok(1));
+        // The previous step is in the window.
+        assert_eq!(secret.check("287082", 89, 1), Ok(1));
+        assert_eq!(secret.check(" 287082 ", 59, 0), Ok(1));
+    }
+
+    #[test]
+    pub fn test_replayed_or_expired_code() {
+        let mismatch = Err(ApiError::new(UserError::TotpMismatch));
+
+        // Used already.
+        assert_eq!(stored(1).check("287082", 59, 1), mismatch);
+        // Out of the window.
+        assert_eq!(stored(0).check("287082", 119, 1), mismatch);
+        assert_eq!(stored(0).check("287082", 89, 0), mismatch);
+        assert_eq!(stored(0).check("000000", 59, 1), mismatch);
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    async fn test_enrollment() {
+        let pool = db_pool().await;
+        let uid = -324;
+        sqlx::query("DELETE FROM public.user_totp WHERE uid = $1")
+            .bind(uid)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let totp = TotpManager::new(&pool);
+
+        assert_eq!(
+            totp.verify(uid, "000000", 1).await,
+            Err(ApiError::new(UserError::TotpNotEnrolled))
+        );
+        let uri = totp.enroll(uid, "Kite").await.unwrap();
+        assert!(uri.starts_with("otpauth://totp/Kite:-324?secret="));
+        assert!(!totp.is_enabled(uid).await.unwrap());
+
+        let secret = totp.query(uid).await.unwrap().unwrap().secret;
+        let code = totp_custom::<Sha1>(STEP, DIGITS, &secret, now());
+        totp.confirm(uid, &code, 1).await.unwrap();
+        assert!(totp.is_enabled(uid).await.unwrap());
+        // The code used on confirmation can't be used to log in.
+        assert_eq!(
+            totp.verify(uid, &code, 1).await,
+            Err(ApiError::new(UserError::TotpMismatch))
+        );
+        assert_eq!(
+            totp.enroll(uid, "Kite").await,
+            Err(ApiError::new(UserError::TotpEnabled))
+        );
+
+        sqlx::query("DELETE FROM public.user_totp WHERE uid = $1")
+            .bind(uid)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+}
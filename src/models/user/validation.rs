@@ -0,0 +1,319 @@
+//! Check whether a user can be created, without creating it, so that forms and admin tools can
+//! tell problems of each field before submitting. Nothing is written.
+
+use super::identity::is_student_id;
+use super::lockout::{account_key_prefix, Lockout};
+use super::verification::Contact;
+use super::{Identity, UserError, LOGIN_BY_PASSWORD};
+use crate::config::StudentIdConfig;
+use crate::error::{ApiError, Result};
+use crate::models::CommonError;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+/// Fields of the user to create. Each field given is checked.
+#[derive(Debug, Default, Deserialize)]
+pub struct Candidate {
+    #[serde(rename = "nickName")]
+    pub nick_name: Option<String>,
+    /// Phone or email to verify on registration.
+    pub contact: Option<String>,
+    /// Username to log in with password.
+    pub account: Option<String>,
+    pub password: Option<String>,
+    #[serde(rename = "studentId")]
+    pub student_id: Option<String>,
+    #[serde(rename = "oaSecret")]
+    pub oa_secret: Option<String>,
+}
+
+/// Result of the check of a field.
+#[derive(Debug, Serialize)]
+pub struct Check {
+    /// Name of the field, like `studentId`.
+    pub field: &'static str,
+    pub passed: bool,
+    /// Error which the creation would fail with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ApiError>,
+}
+
+impl Check {
+    fn new(field: &'static str, result: Result<()>) -> Self {
+        Self {
+            field,
+            passed: result.is_ok(),
+            error: result.err(),
+        }
+    }
+}
+
+fn not_blank(value: &str) -> Result<()> {
+    match value.trim().is_empty() {
+        true => Err(ApiError::new(CommonError::Parameter)),
+        false => Ok(()),
+    }
+}
+
+fn not_default_secret(secret: &str) -> Result<()> {
+    not_blank(secret)?;
+    match Identity::is_default_digit(secret) {
+        true => Err(ApiError::new(UserError::DefaultSecret)),
+        false => Ok(()),
+    }
+}
+
+/// Check the nickname of a new user.
+pub(super) fn check_nick_name(nick_name: &str) -> Result<()> {
+    not_blank(nick_name)
+}
+
+/// Check the account to log in with password, which is given with the password.
+pub(super) fn check_account(account: &str, password: Option<&str>) -> Result<()> {
+    match password {
+        Some(_) => not_blank(account),
+        None => Err(ApiError::new(CommonError::Parameter)),
+    }
+}
+
+/// Check the password, which is given with the account.
+pub(super) fn check_password(password: &str, account: Option<&str>) -> Result<()> {
+    match account {
+        Some(_) => not_default_secret(password),
+        None => Err(ApiError::new(CommonError::Parameter)),
+    }
+}
+
+impl Candidate {
+    /// Checks of fields on their own, without the database or the auth server.
+    pub fn check_formats(&self, rules: &StudentIdConfig) -> Vec<Check> {
+        let mut checks = Vec::new();
+
+        if let Some(nick_name) = &self.nick_name {
+            checks.push(Check::new("nickName", check_nick_name(nick_name)));
+        }
+        if let Some(contact) = &self.contact {
+            checks.push(Check::new("contact", Contact::parse(contact).map(|_| ())));
+        }
+        if let Some(account) = &self.account {
+            let result = check_account(account, self.password.as_deref());
+            checks.push(Check::new("account", result));
+        }
+        if let Some(password) = &self.password {
+            let result = check_password(password, self.account.as_deref());
+            checks.push(Check::new("password", result));
+        }
+        if let Some(student_id) = &self.student_id {
+            let result = match is_student_id(rules, student_id) {
+                true => Ok(()),
+                false => Err(ApiError::new(UserError::InvalidStudentId)),
+            };
+            checks.push(Check::new("studentId", result));
+        }
+        if let Some(oa_secret) = &self.oa_secret {
+            checks.push(Check::new("oaSecret", not_default_secret(oa_secret)));
+        }
+        checks
+    }
+
+    /// Check all fields given, including whether the account and the student id are taken. The
+    /// OA secret is verified on the auth server only if `check_oa` is set, and counted as a login
+    /// of the student id, so that a locked account is refused.
+    pub async fn check(
+        &self,
+        pool: &PgPool,
+        lockout: &Lockout<'_>,
+        rules: &StudentIdConfig,
+        check_oa: bool,
+    ) -> Result<Vec<Check>> {
+        let mut checks = self.check_formats(rules);
+
+        for check in checks.iter_mut().filter(|x| x.passed) {
+            let result = match check.field {
+                "account" => {
+                    let (taken,): (bool,) = sqlx::query_as(
+                        "SELECT EXISTS(SELECT 1 FROM public.authentication WHERE login_type = $1 AND account = $2)",
+                    )
+                    .bind(LOGIN_BY_PASSWORD)
+                    .bind(self.account.as_deref().unwrap_or_default().trim())
+                    .fetch_one(pool)
+                    .await?;
+                    match taken {
+                        true => Err(ApiError::new(UserError::AccountExists)),
+                        false => Ok(()),
+                    }
+                }
+                "studentId" => {
+                    let (bound,): (bool,) = sqlx::query_as(
+                        "SELECT EXISTS(SELECT 1 FROM public.identities WHERE student_id = $1)",
+                    )
+                    .bind(self.student_id.as_deref().unwrap_or_default())
+                    .fetch_one(pool)
+                    .await?;
+                    match bound {
+                        true => Err(ApiError::new(UserError::AlreadyBound)),
+                        false => Ok(()),
+                    }
+                }
+                "oaSecret" if check_oa => match (&self.student_id, &self.oa_secret) {
+                    (Some(student_id), Some(oa_secret)) => {
+                        let student_id = student_id.trim();
+                        let prefix = account_key_prefix(pool, student_id).await?;
+                        lockout.check(&prefix, Utc::now()).await?;
+                        let result = Identity::validate_oa_account(pool, student_id, oa_secret)
+                            .await
+                            .map(|_| ());
+                        lockout.track(&prefix, true, Utc::now(), result).await
+                    }
+                    _ => Err(ApiError::new(CommonError::Parameter)),
+                },
+                _ => continue,
+            };
+            *check = Check::new(check.field, result);
+        }
+        Ok(checks)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{LockoutConfig, Pattern};
+    use crate::models::quota::QuotaStore;
+    use crate::models::test::db_pool;
+    use crate::models::user::{Authentication, Person};
+
+    fn rules() -> StudentIdConfig {
+        StudentIdConfig {
+            lengths: vec![10],
+            pattern: Pattern::new("^(TEST)?[0-9]+$"),
+            ..Default::default()
+        }
+    }
+
+    fn errors(checks: &[Check]) -> Vec<(&'static str, Option<u16>)> {
+        checks
+            .iter()
+            .map(|x| (x.field, x.error.as_ref().map(|e| e.code)))
+            .collect()
+    }
+
+    #[test]
+    pub fn test_check_formats() {
+        let candidate = Candidate {
+            nick_name: Some(" ".to_string()),
+            contact: Some("13800000000".to_string()),
+            account: Some("alice".to_string()),
+            password: Some("12345X".to_string()),
+            student_id: Some("21104-0106".to_string()),
+            oa_secret: Some("p@ssw0rd".to_string()),
+        };
+
+        assert_eq!(
+            errors(&candidate.check_formats(&rules())),
+            [
+                ("nickName", Some(CommonError::Parameter as u16)),
+                ("contact", None),
+                ("account", None),
+                ("password", Some(UserError::DefaultSecret as u16)),
+                ("studentId", Some(UserError::InvalidStudentId as u16)),
+                ("oaSecret", None),
+            ]
+        );
+        // Fields not given are not checked, and an account needs a password.
+        let candidate = Candidate {
+            account: Some("alice".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            errors(&candidate.check_formats(&rules())),
+            [("account", Some(CommonError::Parameter as u16))]
+        );
+        let candidate = Candidate {
+            password: Some("p@ssw0rd".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            errors(&candidate.check_formats(&rules())),
+            [("password", Some(CommonError::Parameter as u16))]
+        );
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    async fn test_dry_run() {
+        let pool = db_pool().await;
+        let (student_id, account) = ("TEST000051", "test-validation");
+        let count = || async {
+            let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM public.person")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+            count
+        };
+        let mut owner = Person::new();
+        owner.register(&pool).await.unwrap();
+        owner
+            .set_identity(&pool, &mut Identity::new(owner.uid, student_id.to_string()))
+            .await
+            .unwrap();
+        owner
+            .update_authentication(
+                &pool,
+                &Authentication::from_password(account.to_string(), "p@ssw0rd".to_string()),
+            )
+            .await
+            .unwrap();
+
+        let persons = count().await;
+        let quota = QuotaStore::memory();
+        let lockout = Lockout::new(&quota, &LockoutConfig::default());
+        let taken = Candidate {
+            nick_name: Some("Alice".to_string()),
+            account: Some(account.to_string()),
+            password: Some("p@ssw0rd".to_string()),
+            student_id: Some(student_id.to_string()),
+            oa_secret: Some("123456".to_string()),
+            ..Default::default()
+        };
+        let checks = taken.check(&pool, &lockout, &rules(), true).await.unwrap();
+        assert_eq!(
+            errors(&checks),
+            [
+                ("nickName", None),
+                ("account", Some(UserError::AccountExists as u16)),
+                ("password", None),
+                ("studentId", Some(UserError::AlreadyBound as u16)),
+                // The auth server is not asked with a default secret.
+                ("oaSecret", Some(UserError::DefaultSecret as u16)),
+            ]
+        );
+        let free = Candidate {
+            account: Some(format!("{}-free", account)),
+            password: Some("p@ssw0rd".to_string()),
+            student_id: Some("TEST000052".to_string()),
+            ..Default::default()
+        };
+        let checks = free.check(&pool, &lockout, &rules(), false).await.unwrap();
+        assert!(checks.iter().all(|x| x.passed));
+        // Nothing is created.
+        assert_eq!(count().await, persons);
+
+        sqlx::query("DELETE FROM public.identities WHERE uid = $1")
+            .bind(owner.uid)
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM public.authentication WHERE uid = $1")
+            .bind(owner.uid)
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM public.person WHERE uid = $1")
+            .bind(owner.uid)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,271 @@
+//! Verification codes which prove the ownership of a phone or an email on registration. Only
+//! hashes of codes are stored, in table `public.verification_codes`.
+
+mod sender;
+
+use super::UserError;
+use crate::error::{ApiError, Result};
+use chrono::{Duration, NaiveDateTime, Utc};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+
+pub use sender::CODE_SENDER;
+
+/// Wrong attempts allowed before the code is invalidated.
+const MAX_ATTEMPTS: i32 = 5;
+
+lazy_static! {
+    static ref PHONE_PATTERN: regex::Regex = regex::Regex::new(r"^1[0-9]{10}$").unwrap();
+    static ref EMAIL_PATTERN: regex::Regex = regex::Regex::new(r"^[^@\s]+@[^@\s]+\.[^@\s]+$").unwrap();
+}
+
+/// Phone or email to receive codes.
+#[derive(Debug, PartialEq)]
+pub enum Contact {
+    Phone(String),
+    Email(String),
+}
+
+impl Contact {
+    /// Parse a mainland mobile phone number or an email address.
+    pub fn parse(contact: &str) -> Result<Self> {
+        let contact = contact.trim();
+
+        if PHONE_PATTERN.is_match(contact) {
+            Ok(Contact::Phone(contact.to_string()))
+        } else if EMAIL_PATTERN.is_match(contact) {
+            Ok(Contact::Email(contact.to_lowercase()))
+        } else {
+            Err(ApiError::new(UserError::InvalidContact))
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Contact::Phone(x) | Contact::Email(x) => x,
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Contact::Phone(_) => "phone",
+            Contact::Email(_) => "email",
+        }
+    }
+}
+
+/// Generate a six-digit code.
+pub fn generate_code() -> String {
+    format!("{:06}", rand::thread_rng().gen_range(0..1_000_000))
+}
+
+fn hash_code(contact: &Contact, code: &str) -> String {
+    format!(
+        "{:x}",
+        Sha256::digest(format!("{}:{}", contact.as_str(), code).as_bytes())
+    )
+}
+
+/// Code record of a contact.
+#[derive(sqlx::FromRow)]
+struct StoredCode {
+    code_hash: String,
+    expire_time: NaiveDateTime,
+    /// Wrong attempts.
+    attempts: i32,
+}
+
+impl StoredCode {
+    fn check(&self, contact: &Contact, code: &str, now: NaiveDateTime) -> Result<()> {
+        if now >= self.expire_time || self.attempts >= MAX_ATTEMPTS {
+            return Err(ApiError::new(UserError::VerifyCodeExpired));
+        }
+        if hash_code(contact, code) != self.code_hash {
+            return Err(ApiError::new(UserError::VerifyCodeMismatch));
+        }
+        Ok(())
+    }
+}
+
+pub struct VerificationManager<'a> {
+    pool: &'a PgPool,
+}
+
+impl<'a> VerificationManager<'a> {
+    pub fn new(pool: &'a PgPool) -> Self {
+        Self { pool }
+    }
+
+    /// Generate a code for the contact, which replaces the previous one, and expires after `ttl`
+    /// seconds.
+    pub async fn issue(&self, contact: &Contact, ttl: i64) -> Result<String> {
+        let code = generate_code();
+        let expire_time = Utc::now().naive_local() + Duration::seconds(ttl);
+
+        sqlx::query(
+            "INSERT INTO public.verification_codes (contact, code_hash, expire_time, attempts)
+                VALUES ($1, $2, $3, 0)
+                ON CONFLICT (contact)
+                DO UPDATE SET code_hash = $2, expire_time = $3, attempts = 0",
+        )
+        .bind(contact.as_str())
+        .bind(hash_code(contact, &code))
+        .bind(expire_time)
+        .execute(self.pool)
+        .await?;
+        Ok(code)
+    }
+
+    /// Check the code of the contact. The code is consumed if matched, or its wrong attempts are
+    /// counted. The code is checked and deleted in one statement, so that concurrent requests
+    /// can't use it twice.
+    pub async fn verify(&self, contact: &Contact, code: &str) -> Result<()> {
+        let now = Utc::now().naive_local();
+        let consumed: Option<(String,)> = sqlx::query_as(
+            "DELETE FROM public.verification_codes
+                WHERE contact = $1 AND code_hash = $2 AND expire_time > $3 AND attempts < $4
+                RETURNING contact",
+        )
+        .bind(contact.as_str())
+        .bind(hash_code(contact, code))
+        .bind(now)
+        .bind(MAX_ATTEMPTS)
+        .fetch_optional(self.pool)
+        .await?;
+        if consumed.is_some() {
+            return Ok(());
+        }
+
+        // Tell why with the code before this attempt.
+        let stored: Option<StoredCode> = sqlx::query_as(
+            "UPDATE public.verification_codes SET attempts = attempts + 1 WHERE contact = $1
+                RETURNING code_hash, expire_time, attempts - 1 AS attempts",
+        )
+        .bind(contact.as_str())
+        .fetch_optional(self.pool)
+        .await?;
+        match stored.map(|x| x.check(contact, code, now)) {
+            Some(Err(e)) => Err(e),
+            // Consumed by another request, or none issued.
+            _ => Err(ApiError::new(UserError::VerifyCodeExpired)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::test::db_pool;
+
+    fn stored(contact: &Contact, code: &str, expire_time: NaiveDateTime) -> StoredCode {
+        StoredCode {
+            code_hash: hash_code(contact, code),
+            expire_time,
+            attempts: 0,
+        }
+    }
+
+    #[test]
+    pub fn test_parse_contact() {
+        assert_eq!(
+            Contact::parse("13800000000").unwrap(),
+            Contact::Phone("13800000000".to_string())
+        );
+        assert_eq!(
+            Contact::parse(" Someone@Example.com").unwrap(),
+            Contact::Email("someone@example.com".to_string())
+        );
+        assert!(Contact::parse("12345").is_err());
+        assert!(Contact::parse("someone@").is_err());
+    }
+
+    #[test]
+    pub fn test_generate_code() {
+        let code = generate_code();
+        assert_eq!(code.len(), 6);
+        assert!(code.chars().all(|c| c.is_ascii_digit()));
+    }
+
+    #[test]
+    pub fn test_code_match() {
+        let contact = Contact::Phone("13800000000".to_string());
+        let now = Utc::now().naive_local();
+        let code = stored(&contact, "123456", now + Duration::seconds(300));
+
+        assert!(code.check(&contact, "123456", now).is_ok());
+        assert_ne!(code.code_hash, "123456");
+        // The same code of another contact.
+        let another = Contact::Phone("13800000001".to_string());
+        assert!(code.check(&another, "123456", now).is_err());
+    }
+
+    #[test]
+    pub fn test_wrong_code() {
+        let contact = Contact::Email("someone@example.com".to_string());
+        let now = Utc::now().naive_local();
+        let mut code = stored(&contact, "123456", now + Duration::seconds(300));
+
+        let result = code.check(&contact, "654321", now);
+        assert_eq!(result, Err(ApiError::new(UserError::VerifyCodeMismatch)));
+        // Invalidated after too many wrong attempts.
+        code.attempts = MAX_ATTEMPTS;
+        let result = code.check(&contact, "123456", now);
+        assert_eq!(result, Err(ApiError::new(UserError::VerifyCodeExpired)));
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    async fn test_issue_and_verify() {
+        let pool = db_pool().await;
+        let manager = VerificationManager::new(&pool);
+        let contact = Contact::Email("test@example.com".to_string());
+
+        let code = manager.issue(&contact, 300).await.unwrap();
+        let wrong = format!("{:06}", (code.parse::<u32>().unwrap() + 1) % 1_000_000);
+        let result = manager.verify(&contact, &wrong).await;
+        assert_eq!(result, Err(ApiError::new(UserError::VerifyCodeMismatch)));
+        manager.verify(&contact, &code).await.unwrap();
+        // Consumed.
+        let result = manager.verify(&contact, &code).await;
+        assert_eq!(result, Err(ApiError::new(UserError::VerifyCodeExpired)));
+
+        // Used once by concurrent requests.
+        let code = manager.issue(&contact, 300).await.unwrap();
+        let results = futures::future::join_all((0..4).map(|_| manager.verify(&contact, &code))).await;
+        assert_eq!(results.iter().filter(|x| x.is_ok()).count(), 1);
+
+        // Invalidated after too many wrong attempts.
+        let code = manager.issue(&contact, 300).await.unwrap();
+        let wrong = format!("{:06}", (code.parse::<u32>().unwrap() + 1) % 1_000_000);
+        for _ in 0..MAX_ATTEMPTS {
+            let result = manager.verify(&contact, &wrong).await;
+            assert_eq!(result, Err(ApiError::new(UserError::VerifyCodeMismatch)));
+        }
+        let result = manager.verify(&contact, &code).await;
+        assert_eq!(result, Err(ApiError::new(UserError::VerifyCodeExpired)));
+
+        let code = manager.issue(&contact, 0).await.unwrap();
+        let result = manager.verify(&contact, &code).await;
+        assert_eq!(result, Err(ApiError::new(UserError::VerifyCodeExpired)));
+
+        sqlx::query("DELETE FROM public.verification_codes WHERE contact = $1")
+            .bind(contact.as_str())
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    pub fn test_code_expiry() {
+        let contact = Contact::Phone("13800000000".to_string());
+        let now = Utc::now().naive_local();
+        let code = stored(&contact, "123456", now + Duration::seconds(300));
+
+        assert!(code
+            .check(&contact, "123456", now + Duration::seconds(299))
+            .is_ok());
+        let result = code.check(&contact, "123456", now + Duration::seconds(300));
+        assert_eq!(result, Err(ApiError::new(UserError::VerifyCodeExpired)));
+    }
+}
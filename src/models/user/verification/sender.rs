@@ -0,0 +1,86 @@
+use super::Contact;
+use crate::config::{CodeSenderBackend, CONFIG};
+use crate::error::{ApiError, Result};
+use actix_web::client::Client;
+use futures::future::{ok, LocalBoxFuture};
+use log::info;
+use serde::Serialize;
+
+lazy_static! {
+    /// Code sender selected by config.
+    pub static ref CODE_SENDER: Box<dyn CodeSender> = sender_from_config();
+}
+
+/// Send verification codes to phones by SMS, or to emails.
+pub trait CodeSender: Send + Sync {
+    fn send<'a>(&'a self, contact: &'a Contact, code: &'a str) -> LocalBoxFuture<'a, Result<()>>;
+}
+
+/// Sender which only logs codes.
+pub struct NoopSender;
+
+impl CodeSender for NoopSender {
+    fn send<'a>(&'a self, contact: &'a Contact, code: &'a str) -> LocalBoxFuture<'a, Result<()>> {
+        info!("Verification code for {}: {}", contact.as_str(), code);
+        Box::pin(ok(()))
+    }
+}
+
+/// Sender which posts codes in JSON to a gateway, which sends SMS or emails by the contact type.
+pub struct WebhookSender {
+    url: String,
+}
+
+#[derive(Serialize)]
+struct SendRequest<'a> {
+    /// "phone" or "email".
+    #[serde(rename = "type")]
+    contact_type: &'a str,
+    contact: &'a str,
+    code: &'a str,
+}
+
+impl WebhookSender {
+    pub fn new(url: &str) -> Self {
+        Self { url: url.to_string() }
+    }
+}
+
+impl CodeSender for WebhookSender {
+    fn send<'a>(&'a self, contact: &'a Contact, code: &'a str) -> LocalBoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let request = SendRequest {
+                contact_type: contact.type_name(),
+                contact: contact.as_str(),
+                code,
+            };
+            let response = Client::default()
+                .post(&self.url)
+                .send_json(&request)
+                .await
+                .map_err(|e| ApiError::from(format!("While posting to code gateway: {}", e)))?;
+
+            if !response.status().is_success() {
+                return Err(ApiError::from(format!(
+                    "Code gateway responded {}",
+                    response.status()
+                )));
+            }
+            Ok(())
+        })
+    }
+}
+
+fn sender_from_config() -> Box<dyn CodeSender> {
+    match CONFIG.verification.sender {
+        CodeSenderBackend::Noop => Box::new(NoopSender),
+        CodeSenderBackend::Webhook => {
+            let url = CONFIG
+                .verification
+                .webhook
+                .as_ref()
+                .expect("verification.webhook is required by the webhook sender");
+            Box::new(WebhookSender::new(url))
+        }
+    }
+}
@@ -2,8 +2,10 @@ use actix_web::client::Client;
 use serde::Deserialize;
 use serde_json;
 
+use super::UserError;
 use crate::config::CONFIG;
 use crate::error::ApiError;
+use log::warn;
 
 #[macro_export]
 macro_rules! make_parameter {
@@ -38,10 +40,10 @@ macro_rules! wx_function {
                     let body_json: $structure = serde_json::from_slice(body_string.as_ref())?;
                     return Ok(body_json);
                 }
-                Err(e) => Err(ApiError::from(format!(
-                    "While connecting to wechat services: {}",
-                    e
-                ))),
+                Err(e) => {
+                    warn!("While connecting to wechat services: {}", e);
+                    Err(ApiError::new(UserError::WechatNetworkFailed))
+                }
             }
         } // End of function.
     }; // End of pattern.
@@ -2,32 +2,76 @@
 //! then calls business logic functions. Server controls database as it do
 //! some permission check in acl_middleware
 
-use crate::bridge::AgentManager;
-use crate::config::CONFIG;
+use crate::bridge::{AgentManager, AgentTransport, RequestLimiter};
+use crate::cache;
+use crate::config::{self, LogFormat, QuotaBackend, CONFIG};
+use crate::models::edu::ScoreCache;
+use crate::models::event::EventCache;
+use crate::models::pay::BalanceFeed;
+use crate::models::quota::QuotaStore;
+use crate::models::user::{deletion, revocation};
+use crate::models::{file, notification, pay};
 use actix_http::http::HeaderValue;
-use actix_web::{web, App, HttpResponse, HttpServer};
+use actix_http::RequestHead;
+use actix_web::middleware::Condition;
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use log::{error, info};
 use middlewares::reject::Reject;
 use sqlx::postgres::{PgPool, PgPoolOptions};
 use std::io::Read;
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::sync::{Arc, RwLock};
+use tokio::signal::unix::{signal, SignalKind};
 
 mod auth;
 mod handlers;
+mod idempotency;
+mod log_file;
 mod middlewares;
 mod response;
+mod tls;
+mod version;
+mod warmer;
+
+pub use auth::AdminRequired;
+pub use response::ApiResponse;
+
+/// Prefix of attachment routes, which accept uploads larger than other requests.
+const UPLOAD_PREFIX: &str = "/api/v1/attachment";
+/// Path of freshman imports, which are larger than other bodies.
+const IMPORT_PATH: &str = "/api/v1/freshman/import";
 
 #[derive(Clone)]
 pub struct AppState {
     pool: PgPool,
     host: AgentManager,
+    /// Transport of agent requests, which is `host` except in tests.
+    agents: Arc<dyn AgentTransport>,
+    quota: QuotaStore,
+    scores: ScoreCache,
+    events: EventCache,
+    balances: Arc<BalanceFeed>,
 }
 
 pub async fn server_main() -> std::io::Result<()> {
-    // Create database pool.
+    // Fail on bad settings and keys before anything else.
+    config::check_startup_config();
+    crate::jwt::init_keys();
+    crate::clock::init(
+        crate::clock::Zone::configured(CONFIG.timezone.as_deref(), CONFIG.server.utc_offset)
+            .expect("Timezone is checked on startup"),
+    );
+    init_trusted_hops(CONFIG.server.trusted_hops);
+
+    // Create database pool. Sessions use the same timezone, so that dates in SQL agree.
+    let set_zone = format!("SET TIME ZONE {};", crate::clock::zone().to_sql());
     let pool = PgPoolOptions::new()
         .max_connections(10)
-        .after_connect(|conn| {
+        .connect_timeout(std::time::Duration::from_secs(CONFIG.server.db_timeout))
+        .after_connect(move |conn| {
+            let set_zone = set_zone.clone();
             Box::pin(async move {
-                conn.execute("SET TIME ZONE 'Asia/Shanghai';").await?;
+                conn.execute(set_zone.as_str()).await?;
                 Ok(())
             })
         })
@@ -37,7 +81,13 @@ pub async fn server_main() -> std::io::Result<()> {
 
     // Logger
     set_logger("kite.log");
-    let log_string = "%a - - [%t] \"%r\" %s %b %D \"%{User-Agent}i\"";
+
+    revocation::load_revoked_tokens(&pool)
+        .await
+        .expect("Could not load revoked tokens");
+    deletion::load_deleted_users(&pool)
+        .await
+        .expect("Could not load deleted users");
 
     // Load white list
     let mut file = std::fs::File::open("ip-whitelist.txt").unwrap();
@@ -46,36 +96,261 @@ pub async fn server_main() -> std::io::Result<()> {
     drop(file);
 
     // Websocket server.
-    let ws_host = AgentManager::new();
+    let ws_host = AgentManager::new(
+        std::time::Duration::from_secs(CONFIG.host.agent_info_ttl),
+        RequestLimiter::from(&CONFIG.host),
+    );
 
+    let quota = match CONFIG.server.quota_store {
+        QuotaBackend::Memory => QuotaStore::memory(),
+        QuotaBackend::Database => QuotaStore::database(pool.clone()),
+    };
+    // Requests are counted only to warm caches.
+    let hot_keys = if CONFIG.server.cache_warmer {
+        cache::MAX_HOT_KEYS
+    } else {
+        0
+    };
     let app_state = AppState {
         pool: pool,
         host: ws_host.clone(),
+        agents: Arc::new(ws_host.clone()),
+        quota,
+        scores: ScoreCache::new(
+            std::time::Duration::from_secs(CONFIG.server.score_cache_ttl),
+            hot_keys,
+        ),
+        events: EventCache::new(
+            std::time::Duration::from_secs(CONFIG.server.event_cache_ttl),
+            hot_keys,
+        ),
+        balances: Arc::new(BalanceFeed::new()),
     };
 
+    // Reload hot settings on SIGHUP.
+    tokio::spawn(async {
+        let mut hangup = signal(SignalKind::hangup()).expect("Failed to listen SIGHUP");
+        while hangup.recv().await.is_some() {
+            if let Err(e) = config::reload_config() {
+                error!("Failed to reload config: {}", e);
+            }
+        }
+    });
+
+    // Notifiers are not `Send`, so notifications are delivered on the actix system.
+    actix_web::rt::spawn(notification::deliver_all());
+    tokio::spawn(revocation::sync_revoked_tokens(
+        app_state.pool.clone(),
+        std::time::Duration::from_secs(CONFIG.server.revocation_sync_interval),
+    ));
+    tokio::spawn(file::expire_uploads(
+        app_state.pool.clone(),
+        CONFIG.server.attachment.clone(),
+        std::time::Duration::from_secs(CONFIG.server.upload_ttl),
+    ));
+    tokio::spawn(pay::watch_balances(
+        app_state.pool.clone(),
+        app_state.balances.clone(),
+        std::time::Duration::from_secs(CONFIG.server.balance_poll_interval),
+    ));
+
+    if CONFIG.server.cache_warmer {
+        tokio::spawn(warmer::warm_caches(
+            app_state.clone(),
+            std::time::Duration::from_secs(CONFIG.server.cache_warm_interval),
+            CONFIG.server.cache_warm_keys,
+        ));
+    }
+
+    let host = ws_host.clone();
     tokio::spawn(async move {
         ws_host.agent_main().await.unwrap_or_else(|e| {
             panic!("Failed to run websocket host: {}", e);
         });
     });
 
+    // Metrics on a separate listener, which is usually not exposed to the public.
+    let metrics_server = match &CONFIG.metrics.bind {
+        Some(bind) => {
+            let state = app_state.clone();
+            let server = HttpServer::new(move || {
+                App::new()
+                    .data(state.clone())
+                    .data(CONFIG.metrics.clone())
+                    .service(handlers::status::get_metrics)
+            })
+            .workers(1)
+            .disable_signals()
+            .bind(bind.as_str())?
+            .run();
+            Some(server)
+        }
+        None => None,
+    };
+
+    let mut body_limit = CONFIG.body_limit.clone();
+    body_limit
+        .entry(UPLOAD_PREFIX.to_string())
+        .or_insert(CONFIG.server.max_attachment_size);
+    body_limit
+        .entry(IMPORT_PATH.to_string())
+        .or_insert(CONFIG.server.max_import_size);
+
+    middlewares::acl::init_anonymous_routes();
+
     // Run actix-web services.
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
+            // Tags are computed from bodies before compression.
+            .wrap(middlewares::etag::ConditionalGet::new(&CONFIG.etag_routes))
+            .wrap(middlewares::skip_compress::SkipCompressed::new(
+                &CONFIG.server.compressed_types,
+                CONFIG.server.compress_min_size,
+            ))
             .wrap(actix_web::middleware::Compress::default())
-            // .wrap(middlewares::acl::Auth)
-            .wrap(actix_web::middleware::Logger::new(log_string))
+            .wrap(middlewares::cache_control::CacheControl::new(
+                &CONFIG.cache_control,
+            ))
+            .wrap(middlewares::login_limit::LoginLimit::new(
+                app_state.quota.clone(),
+                app_state.pool.clone(),
+                || {
+                    let hot = config::hot_config();
+                    (
+                        hot.login_max_failures,
+                        std::time::Duration::from_secs(hot.login_window),
+                    )
+                },
+            ))
+            .wrap(middlewares::body_limit::BodyLimit::new(
+                CONFIG.server.max_body_size,
+                &body_limit,
+            ))
+            // Inside the account check, which stashes the token with the current role.
+            .wrap(middlewares::acl::Auth::new())
+            .wrap(Condition::new(
+                CONFIG.server.verify_token_user,
+                middlewares::acl::AccountCheck::new(
+                    app_state.pool.clone(),
+                    std::time::Duration::from_secs(CONFIG.server.token_user_ttl),
+                ),
+            ))
+            .wrap(middlewares::ip_limit::IpLimit::new(
+                app_state.quota.clone(),
+                || {
+                    let hot = config::hot_config();
+                    (hot.rate_limit_rps, hot.rate_limit_burst)
+                },
+                &CONFIG.rate_limit.allowlist,
+            ))
+            // Answer preflight requests before authentication.
+            .wrap(middlewares::cors::Cors::new(&CONFIG.cors))
+            .wrap(middlewares::logger::SampledLogger::new())
+            .wrap(middlewares::metrics::Metrics)
+            .wrap(middlewares::request_id::RequestIdHeader::new(
+                &CONFIG.server.request_id_header,
+            ))
+            // Error messages of all middlewares inside are localized.
+            .wrap(middlewares::locale::Localize)
             // .wrap(Reject::new(&buffer))
             .data(app_state.clone())
+            .data(CONFIG.metrics.clone())
+            // The body limit is checked by the middleware, so that JSON bodies are not limited
+            // to the default 32 KiB of actix-web.
+            .app_data(web::JsonConfig::default().limit(CONFIG.server.max_body_size))
             .configure(routes)
     })
-    .bind(&CONFIG.server.bind.as_str())?
-    .run()
-    .await
+    .shutdown_timeout(CONFIG.server.shutdown_grace)
+    .disable_signals();
+    let listeners = bind_listeners(&CONFIG.server.bind)?;
+    let https_port = listeners[0].local_addr()?.port();
+    let server = match &CONFIG.server.tls {
+        Some(tls_config) => {
+            let cert = Arc::new(tls::ReloadableCert::load(tls_config)?);
+            if let Err(e) = cert.clone().watch() {
+                error!("Failed to watch TLS certificate, it won't be reloaded: {}", e);
+            }
+            let tls_config = tls::server_config(cert);
+            listeners.into_iter().try_fold(server, |server, listener| {
+                server.listen_rustls(listener, tls_config.clone())
+            })?
+        }
+        None => listeners
+            .into_iter()
+            .try_fold(server, |server, listener| server.listen(listener))?,
+    }
+    .run();
+
+    // Plain HTTP listener which redirects to HTTPS, in the same system as the main server.
+    let redirect_server = match CONFIG.server.tls.as_ref().and_then(|x| x.redirect_bind.as_ref()) {
+        Some(bind) => {
+            let server = HttpServer::new(move || {
+                App::new()
+                    .data(tls::HttpsPort(https_port))
+                    .default_service(web::route().to(tls::redirect_to_https))
+            })
+            .workers(1)
+            .disable_signals()
+            .bind(bind.as_str())?
+            .run();
+            Some(server)
+        }
+        None => None,
+    };
+
+    // Stop gracefully on SIGINT and SIGTERM, with in-flight requests completed.
+    let handle = server.clone();
+    tokio::spawn(async move {
+        let mut interrupt = signal(SignalKind::interrupt()).expect("Failed to listen SIGINT");
+        let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen SIGTERM");
+
+        tokio::select! {
+            _ = interrupt.recv() => (),
+            _ = terminate.recv() => (),
+        }
+        info!("Shutting down, waiting for in-flight requests.");
+        if let Some(metrics_server) = metrics_server {
+            metrics_server.stop(true).await;
+        }
+        if let Some(redirect_server) = redirect_server {
+            redirect_server.stop(true).await;
+        }
+        handle.stop(true).await;
+    });
+    server.await?;
+
+    // Requests to agents are all from handlers, so they are drained after the HTTP server.
+    host.shutdown(std::time::Duration::from_secs(CONFIG.server.shutdown_grace))
+        .await;
+    Ok(())
+}
+
+/// Bind all the addresses. Addresses failed to bind are skipped, unless none succeeds.
+fn bind_listeners(addrs: &[String]) -> std::io::Result<Vec<TcpListener>> {
+    let mut listeners = Vec::new();
+
+    for addr in addrs {
+        match TcpListener::bind(addr.as_str()) {
+            Ok(listener) => {
+                info!("Listening on {}", listener.local_addr()?);
+                listeners.push(listener);
+            }
+            Err(e) => error!("Failed to bind {}: {}", addr, e),
+        }
+    }
+    if listeners.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AddrNotAvailable,
+            format!("None of the addresses {:?} can be bound", addrs),
+        ));
+    }
+    Ok(listeners)
 }
 
 fn routes(app: &mut web::ServiceConfig) {
-    use handlers::{attachment, edu, event, freshman, motto, notice, pay, search, status, user};
+    use handlers::{
+        admin, attachment, edu, event, freshman, motto, notice, notification, pay, search, status, user,
+    };
 
     app.service(
         // API scope: version 1
@@ -84,14 +359,42 @@ fn routes(app: &mut web::ServiceConfig) {
             .route("/", web::get().to(|| HttpResponse::Ok().body("Hello world")))
             // User routes
             .service(user::login)
+            .service(user::wechat_login)
+            .service(user::refresh_token)
+            .service(user::logout)
             .service(user::bind_authentication)
+            .service(user::update_oa_secret)
             .service(user::list_users)
+            // Before `/user/{uid}`.
+            .service(user::export_users)
             .service(user::create_user)
+            .service(user::validate_user)
+            .service(user::send_verify_code)
+            .service(user::get_captcha)
+            .service(user::enroll_totp)
+            .service(user::confirm_totp)
             .service(user::get_user_detail)
             .service(user::update_user_detail)
+            .service(user::create_users)
+            .service(user::set_user_role)
+            .service(user::set_user_status)
+            .service(user::delete_user)
+            .service(user::unlock_account)
+            .service(user::restore_user)
+            .service(user::get_user_overview)
             .service(user::get_user_identity)
+            .service(user::list_user_identities)
             .service(user::set_user_identity)
+            .service(user::reassign_user_identity)
+            // Notification routes
+            .service(notification::count_unread)
+            .service(notification::mark_read)
+            .service(notification::stream_notifications)
             // Freshman routes
+            // Before `/freshman/{account}`.
+            .service(freshman::search_freshmen)
+            .service(freshman::import_freshmen)
+            .service(freshman::set_privacy)
             .service(freshman::get_basic_info)
             .service(freshman::update_account)
             .service(freshman::get_roommate)
@@ -101,40 +404,88 @@ fn routes(app: &mut web::ServiceConfig) {
             .service(freshman::post_analysis_log)
             // Attachment routes
             .service(attachment::query_attachment)
+            .service(attachment::sign_attachment)
+            .service(attachment::download_attachment)
+            .service(attachment::get_thumbnail)
             .service(attachment::upload_file)
+            .service(attachment::init_upload)
+            .service(attachment::get_upload)
+            .service(attachment::upload_chunk)
+            .service(attachment::complete_upload)
             .service(attachment::list_attachments)
+            .service(attachment::delete_attachment)
             // Motto routes
             .service(motto::get_one_motto)
+            .service(motto::list_mottos)
+            .service(motto::create_motto)
+            .service(motto::update_motto)
+            .service(motto::delete_motto)
             // Event and activity routes
             .service(event::list_events)
+            .service(event::get_event)
+            .service(event::recommend_events)
             // Edu management and course-related routes
             .service(edu::get_planned_course)
             .service(edu::query_major)
             .service(edu::list_course_classes)
             .service(edu::query_course)
+            .service(edu::get_gpa)
+            .service(edu::export_scores)
             // System status routes
             .service(status::get_timestamp)
             .service(status::get_system_status)
             .service(status::get_agent_list)
+            .service(status::get_health)
+            // Administration routes
+            .service(admin::list_agents)
+            .service(admin::drain_agents)
+            .service(admin::disconnect_agent)
+            .service(admin::get_agent_latency)
+            .service(admin::get_agent_stats)
+            .service(admin::get_user_limits)
+            .service(admin::clear_user_limits)
+            .service(admin::list_audit_log)
+            .service(admin::list_login_attempts)
             // Pay and room balance
             .service(pay::query_room_balance)
+            .service(pay::watch_room_balance)
             .service(pay::query_room_bills_by_day)
             .service(pay::query_room_bills_by_hour)
             .service(pay::query_room_consumption_rank)
+            .service(pay::query_balance_history)
+            .service(pay::query_room_balances)
+            .service(pay::subscribe_balance)
+            .service(pay::list_balance_alerts)
             // Get Notices
             .service(notice::get_notices)
             // Search module
             .service(search::search),
     );
+    // Served only on the separate listener if it's configured.
+    if CONFIG.metrics.bind.is_none() && CONFIG.metrics.token.is_some() {
+        app.service(status::get_metrics);
+    }
 }
 
 fn set_logger(path: &str) {
-    fern::Dispatch::new()
+    let dispatch = match CONFIG.server.log_format {
         // Perform allocation-free log formatting
-        .format(|out, message, _| out.finish(format_args!("{}", message)))
+        LogFormat::Text => {
+            fern::Dispatch::new().format(|out, message, _| out.finish(format_args!("{}", message)))
+        }
+        LogFormat::Json => fern::Dispatch::new().format(|out, message, record| {
+            out.finish(format_args!(
+                "{}",
+                middlewares::logger::format_json(message, record)
+            ))
+        }),
+    };
+    dispatch
         .level(log::LevelFilter::Info)
         // .chain(std::io::stdout())
-        .chain(fern::log_file(path).expect("Could not open log file."))
+        .chain(Box::new(
+            log_file::RotatingFile::open(path, CONFIG.log.clone()).expect("Could not open log file."),
+        ) as Box<dyn std::io::Write + Send>)
         .apply()
         .expect("Failed to set logger.");
 }
@@ -143,12 +494,104 @@ use serde::{Deserialize, Serialize};
 use sqlx::Executor;
 
 /// User Jwt token carried in each request.
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct JwtToken {
     /// UID of current user.
     pub uid: i32,
     /// current user role.
     pub is_admin: bool,
+    /// Expiry time in unix timestamp.
+    pub exp: i64,
+    /// Issue time in unix timestamp.
+    #[serde(default)]
+    pub iat: i64,
+    /// Issue time in unix timestamp of milliseconds, to tell tokens issued in the same second as a
+    /// revocation of the user.
+    #[serde(default)]
+    pub iat_ms: i64,
+    /// Token id, used to revoke the token.
+    pub jti: uuid::Uuid,
+}
+
+impl JwtToken {
+    /// Issue a token which expires in `CONFIG.server.token_ttl` seconds.
+    pub fn new(uid: i32, is_admin: bool) -> Self {
+        Self::with_ttl(uid, is_admin, CONFIG.server.token_ttl)
+    }
+
+    pub fn with_ttl(uid: i32, is_admin: bool, ttl: i64) -> Self {
+        let now = chrono::Utc::now();
+
+        Self {
+            uid,
+            is_admin,
+            exp: now.timestamp() + ttl,
+            iat: now.timestamp(),
+            iat_ms: now.timestamp_millis(),
+            jti: uuid::Uuid::new_v4(),
+        }
+    }
+
+    /// Issue time in milliseconds. Tokens issued before `iat_ms` added are taken as issued at the
+    /// start of the second.
+    pub fn issued_at(&self) -> i64 {
+        match self.iat_ms {
+            0 => self.iat * 1000,
+            iat_ms => iat_ms,
+        }
+    }
+}
+
+lazy_static! {
+    /// Proxies in front of the service, set on startup. See `ServerConfig::trusted_hops`.
+    static ref TRUSTED_HOPS: RwLock<Option<usize>> = RwLock::new(None);
+}
+
+/// Set the number of trusted proxies on startup.
+pub fn init_trusted_hops(hops: Option<usize>) {
+    *TRUSTED_HOPS.write().unwrap() = hops;
+}
+
+fn parse_ip(addr: &str) -> Option<IpAddr> {
+    addr.parse::<IpAddr>()
+        .ok()
+        .or_else(|| addr.parse::<SocketAddr>().ok().map(|x| x.ip()))
+}
+
+/// IP of the client behind `hops` proxies. Each proxy appends the address it received the request
+/// from to `X-Forwarded-For`, so the entry appended by the outermost trusted proxy is the client,
+/// and entries on its left may be forged. The peer is the client if there is no proxy or no entry.
+pub fn forwarded_ip(peer: Option<IpAddr>, forwarded_for: Option<&str>, hops: usize) -> Option<IpAddr> {
+    let entries: Vec<&str> = forwarded_for
+        .map(|x| x.split(',').map(str::trim).filter(|x| !x.is_empty()).collect())
+        .unwrap_or_default();
+    if hops == 0 || entries.is_empty() {
+        return peer;
+    }
+    // Fewer entries than proxies, the leftmost is the best guess.
+    let index = entries.len().saturating_sub(hops);
+    parse_ip(entries[index]).or(peer)
+}
+
+/// IP of the client of the request, without the port which changes with connections. The peer
+/// address is used, and `X-Forwarded-For` is only read if `trusted_hops` is configured.
+pub fn request_ip(head: &RequestHead) -> String {
+    let hops = TRUSTED_HOPS.read().unwrap().unwrap_or(0);
+    let forwarded_for: Vec<&str> = head
+        .headers()
+        .get_all("X-Forwarded-For")
+        .filter_map(|x| x.to_str().ok())
+        .collect();
+    let forwarded_for = forwarded_for.join(",");
+
+    forwarded_ip(head.peer_addr.map(|x| x.ip()), Some(&forwarded_for), hops)
+        .map(|x| x.to_string())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+/// IP of the client, without the port which changes with connections.
+pub fn client_ip(req: &HttpRequest) -> String {
+    request_ip(req.head())
 }
 
 fn get_auth_bearer_value(auth_string: &HeaderValue) -> Option<&str> {
@@ -162,3 +605,34 @@ fn get_auth_bearer_value(auth_string: &HeaderValue) -> Option<&str> {
     }
     None
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_bind_listeners() {
+        let addrs = vec!["127.0.0.1:0".to_string(), "256.0.0.1:80".to_string()];
+        let listeners = bind_listeners(&addrs).unwrap();
+        assert_eq!(listeners.len(), 1);
+
+        let addrs = vec!["256.0.0.1:80".to_string()];
+        assert!(bind_listeners(&addrs).is_err());
+    }
+
+    #[test]
+    pub fn test_forwarded_ip() {
+        let peer = Some("10.0.0.2".parse().unwrap());
+        let ip = |forwarded_for, hops| forwarded_ip(peer, forwarded_for, hops).unwrap().to_string();
+
+        // Behind the load balancer, the entry it appends is the client, and a forged one is not.
+        assert_eq!(ip(Some("1.1.1.1, 2.2.2.2"), 1), "2.2.2.2");
+        assert_eq!(ip(Some("1.1.1.1, 2.2.2.2, 10.0.0.3"), 2), "2.2.2.2");
+        assert_eq!(ip(Some("2.2.2.2:5000"), 1), "2.2.2.2");
+        assert_eq!(ip(Some("1.1.1.1"), 3), "1.1.1.1");
+        // Without proxies the header is ignored.
+        assert_eq!(ip(Some("1.1.1.1"), 0), "10.0.0.2");
+        assert_eq!(ip(None, 1), "10.0.0.2");
+        assert_eq!(ip(Some("unknown"), 1), "10.0.0.2");
+    }
+}
@@ -1,29 +1,103 @@
 use super::get_auth_bearer_value;
+use crate::error::ApiError;
 use crate::jwt::decode_jwt;
+use crate::models::user::deletion::is_deleted;
+use crate::models::user::revocation::{is_revoked, is_user_revoked};
+use crate::models::CommonError;
 use crate::services::JwtToken;
 use actix_http::{Error, Payload, PayloadStream};
-use actix_web::error::ErrorUnauthorized;
+use actix_web::error::{ErrorUnauthorized, InternalError};
+use actix_web::http::StatusCode;
 use actix_web::{FromRequest, HttpRequest};
 use futures::future::{err, ok, Ready};
 
+/// Decode the token, unless it's revoked or its user is deleted.
+pub fn verify_token(jwt_string: &str) -> Option<JwtToken> {
+    decode_jwt::<JwtToken>(jwt_string).filter(|token| {
+        !is_revoked(&token.jti)
+            && !is_user_revoked(token.uid, token.issued_at())
+            && !is_deleted(token.uid)
+    })
+}
+
+/// Get the token stashed by the auth middleware, or decode it from the header.
+fn get_token(req: &HttpRequest) -> Option<JwtToken> {
+    if let Some(token) = req.extensions().get::<JwtToken>() {
+        return Some(token.clone());
+    }
+    // Get authentication header.
+    if let Some(auth_string) = req.headers().get("Authorization") {
+        // If authentication type is "Bearer"
+        if let Some(token) = get_auth_bearer_value(auth_string).and_then(verify_token) {
+            // Keep it for other extractors and the access logger.
+            req.extensions_mut().insert(token.clone());
+            return Some(token);
+        }
+    }
+    None
+}
+
 impl FromRequest for JwtToken {
     type Error = Error;
     type Future = Ready<Result<Self, Self::Error>>;
     type Config = ();
 
     fn from_request(req: &HttpRequest, _: &mut Payload<PayloadStream>) -> Self::Future {
-        // Get authentication header.
-        if let Some(auth_string) = req.headers().get("Authorization") {
-            // If authentication type is "Bearer"
-            if let Some(jwt_string) = get_auth_bearer_value(auth_string) {
-                // Unpack JWT to verify credential
-                if let Some(token) = decode_jwt::<JwtToken>(jwt_string) {
-                    return ok(token);
-                }
+        match get_token(req) {
+            Some(token) => ok(token),
+            None => err(ErrorUnauthorized("Unauthorized")),
+        }
+    }
+}
+
+/// Token of an administrator. Requests of other users are rejected with 403 Forbidden.
+pub struct AdminRequired(pub JwtToken);
+
+impl FromRequest for AdminRequired {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload<PayloadStream>) -> Self::Future {
+        match get_token(req) {
+            Some(token) if token.is_admin => ok(AdminRequired(token)),
+            Some(_) => {
+                let response = ApiError::new(CommonError::Forbidden).response(StatusCode::FORBIDDEN);
+                err(InternalError::from_response("Forbidden", response).into())
             }
+            None => err(ErrorUnauthorized("Unauthorized")),
         }
-        err(ErrorUnauthorized("Unauthorized"))
     }
 }
 
-// TODO: Implement ServiceRequest for JwtToken
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    pub fn test_admin_required() {
+        actix_web::rt::System::new("test").block_on(async {
+            let req = TestRequest::default().to_http_request();
+            req.extensions_mut().insert(JwtToken::with_ttl(1, false, 60));
+            let e = AdminRequired::extract(&req).await.err().unwrap();
+            assert_eq!(e.as_response_error().status_code(), StatusCode::FORBIDDEN);
+
+            let req = TestRequest::default().to_http_request();
+            req.extensions_mut().insert(JwtToken::with_ttl(1, true, 60));
+            assert!(AdminRequired::extract(&req).await.is_ok());
+        });
+    }
+
+    #[test]
+    pub fn test_token_from_extensions() {
+        actix_web::rt::System::new("test").block_on(async {
+            let req = TestRequest::default().to_http_request();
+            assert!(JwtToken::extract(&req).await.is_err());
+
+            let token = JwtToken::with_ttl(1, false, 60);
+            req.extensions_mut().insert(token.clone());
+            assert_eq!(JwtToken::extract(&req).await.unwrap(), token);
+        });
+    }
+}
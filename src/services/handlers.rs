@@ -1,9 +1,11 @@
+pub mod admin;
 pub mod attachment;
 pub mod edu;
 pub mod event;
 pub mod freshman;
 pub mod motto;
 pub mod notice;
+pub mod notification;
 pub mod pay;
 pub mod search;
 pub mod status;
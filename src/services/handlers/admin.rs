@@ -0,0 +1,302 @@
+//! This module includes interfaces for administrators to maintain the server.
+use crate::bridge::HostError;
+use crate::config::CONFIG;
+use crate::error::{ApiError, Result};
+use crate::models::audit::{self, AuditAction, AuditFilter};
+use crate::models::quota::user_key_prefix;
+use crate::models::user::login_audit::{self, LoginAttemptFilter, OUTCOME_FAILURE, OUTCOME_SUCCESS};
+use crate::models::{with_db_retry, CommonError, PageView};
+use crate::services::response::{ApiResponse, PagedResponse};
+use crate::services::{AdminRequired, AppState};
+use actix_web::{delete, get, post, web, HttpResponse};
+use chrono::{DateTime, Local};
+use log::warn;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/**********************************************************************
+    Interfaces in this module:
+    list_agents()              <-- GET /admin/agents
+    drain_agents()             <-- POST /admin/agents/drain
+    disconnect_agent()         <-- POST /admin/agent/{id}/disconnect
+    get_agent_latency()        <-- GET /admin/agent/latency
+    get_agent_stats()          <-- GET /admin/agent-stats
+    get_user_limits()          <-- GET /admin/limits/{uid}
+    clear_user_limits()        <-- DELETE /admin/limits/{uid}
+    list_audit_log()           <-- GET /admin/audit
+    list_login_attempts()      <-- GET /admin/audit/login
+*********************************************************************/
+
+/// Show state of each agent connection.
+#[get("/admin/agents")]
+pub async fn list_agents(app: web::Data<AppState>, _: AdminRequired) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(app.host.connections().await)))
+}
+
+/// Close all agent connections once their in-flight requests complete, so that agents reconnect
+/// and the pool is rebuilt, like after the campus system restarts.
+#[post("/admin/agents/drain")]
+pub async fn drain_agents(app: web::Data<AppState>, admin: AdminRequired) -> Result<HttpResponse> {
+    warn!("Agent connections drained by administrator {}.", admin.0.uid);
+    let report = app
+        .host
+        .drain(Duration::from_secs(CONFIG.host.drain_timeout))
+        .await;
+    audit::record(&app.pool, admin.0.uid, AuditAction::DrainAgents, report.drained).await;
+
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(report)))
+}
+
+/// Kick an agent so that it can reconnect cleanly. The agent id is its remote address.
+#[post("/admin/agent/{id}/disconnect")]
+pub async fn disconnect_agent(
+    app: web::Data<AppState>,
+    admin: AdminRequired,
+    id: web::Path<String>,
+) -> Result<HttpResponse> {
+    let addr: SocketAddr = id
+        .into_inner()
+        .parse()
+        .map_err(|_| ApiError::new(CommonError::Parameter))?;
+
+    app.host
+        .disconnect(&addr)
+        .await
+        .map_err(|_| ApiError::new(HostError::NoSuchAgent))?;
+    warn!("Agent {} disconnected by administrator {}.", addr, admin.0.uid);
+    audit::record(&app.pool, admin.0.uid, AuditAction::DisconnectAgent, addr).await;
+
+    Ok(HttpResponse::Ok().json(ApiResponse::empty()))
+}
+
+/// Show latency of recent agent requests with percentiles.
+#[get("/admin/agent/latency")]
+pub async fn get_agent_latency(app: web::Data<AppState>, _: AdminRequired) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(app.host.latency())))
+}
+
+/// Show request counts, retries and recent latency of agent requests by payload type.
+#[get("/admin/agent-stats")]
+pub async fn get_agent_stats(app: web::Data<AppState>, _: AdminRequired) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(app.host.stats())))
+}
+
+/// Show rate-limit and quota counters of the user.
+#[get("/admin/limits/{uid}")]
+pub async fn get_user_limits(
+    app: web::Data<AppState>,
+    _: AdminRequired,
+    uid: web::Path<i32>,
+) -> Result<HttpResponse> {
+    let counters = app.quota.list(&user_key_prefix(uid.into_inner())).await?;
+
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(counters)))
+}
+
+/// Reset rate-limit and quota counters of the user.
+#[delete("/admin/limits/{uid}")]
+pub async fn clear_user_limits(
+    app: web::Data<AppState>,
+    admin: AdminRequired,
+    uid: web::Path<i32>,
+) -> Result<HttpResponse> {
+    let uid = uid.into_inner();
+
+    app.quota.clear_prefix(&user_key_prefix(uid)).await?;
+    warn!("Limits of user {} cleared by administrator {}.", uid, admin.0.uid);
+    audit::record(&app.pool, admin.0.uid, AuditAction::ClearLimits, uid).await;
+
+    Ok(HttpResponse::Ok().json(ApiResponse::empty()))
+}
+
+#[derive(Deserialize)]
+pub struct ListAuditLog {
+    pub index: Option<u16>,
+    pub count: Option<u16>,
+    /// Uid of the user who did it.
+    pub actor: Option<i32>,
+    /// Action name like `user.delete`.
+    pub action: Option<String>,
+}
+
+/// Max audit records per page.
+const MAX_AUDIT_PAGE_SIZE: u16 = 100;
+
+/// List administrative actions, the latest first.
+#[get("/admin/audit")]
+pub async fn list_audit_log(
+    app: web::Data<AppState>,
+    _: AdminRequired,
+    query: web::Query<ListAuditLog>,
+) -> Result<HttpResponse> {
+    let query = query.into_inner();
+    let filter = AuditFilter {
+        actor: query.actor,
+        action: query.action.filter(|x| !x.is_empty()),
+    };
+    let page = PageView {
+        index: query.index,
+        count: query.count,
+    };
+    let records = with_db_retry(|| audit::list(&app.pool, &filter, &page, MAX_AUDIT_PAGE_SIZE)).await?;
+    let total = with_db_retry(|| audit::count(&app.pool, &filter)).await?;
+
+    let response = PagedResponse::new(records, &page, MAX_AUDIT_PAGE_SIZE, total);
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(response)))
+}
+
+#[derive(Deserialize)]
+pub struct ListLoginAttempts {
+    pub index: Option<u16>,
+    pub count: Option<u16>,
+    /// `success` or `failure`.
+    pub outcome: Option<String>,
+    /// Start of the time range, inclusive.
+    pub from: Option<DateTime<Local>>,
+    /// End of the time range, exclusive.
+    pub to: Option<DateTime<Local>>,
+}
+
+/// List login attempts with reasons of failures, the latest first.
+#[get("/admin/audit/login")]
+pub async fn list_login_attempts(
+    app: web::Data<AppState>,
+    _: AdminRequired,
+    query: web::Query<ListLoginAttempts>,
+) -> Result<HttpResponse> {
+    let query = query.into_inner();
+    let outcome = query.outcome.filter(|x| !x.is_empty());
+    if let Some(outcome) = &outcome {
+        if outcome != OUTCOME_SUCCESS && outcome != OUTCOME_FAILURE {
+            return Err(ApiError::new(CommonError::Parameter));
+        }
+    }
+    let filter = LoginAttemptFilter {
+        outcome,
+        from: query.from,
+        to: query.to,
+    };
+    let page = PageView {
+        index: query.index,
+        count: query.count,
+    };
+    let attempts =
+        with_db_retry(|| login_audit::list(&app.pool, &filter, &page, MAX_AUDIT_PAGE_SIZE)).await?;
+    let total = with_db_retry(|| login_audit::count(&app.pool, &filter)).await?;
+
+    let response = PagedResponse::new(attempts, &page, MAX_AUDIT_PAGE_SIZE, total);
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(response)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bridge::{AgentManager, RequestLimiter};
+    use crate::models::edu::ScoreCache;
+    use crate::models::event::EventCache;
+    use crate::models::quota::QuotaStore;
+    use crate::models::test::db_pool;
+    use crate::models::user::{Authentication, Person, UserError};
+    use crate::services::middlewares::login_limit::LoginLimit;
+    use crate::services::JwtToken;
+    use actix_http::http::StatusCode;
+    use actix_service::Service;
+    use actix_web::{test, App, HttpMessage};
+
+    #[test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    pub fn test_clear_login_limit() {
+        actix_web::rt::System::new("test").block_on(async {
+            let pool = db_pool().await;
+            let mut person = Person::new();
+            person.register(&pool).await.unwrap();
+            let account = format!("test-limits-{}", person.uid);
+            let auth = Authentication::from_password(account.clone(), "secret".to_string());
+            person.update_authentication(&pool, &auth).await.unwrap();
+
+            let quota = QuotaStore::memory();
+            let host = AgentManager::new(
+                Duration::from_secs(60),
+                RequestLimiter::new(16, 16, Duration::from_secs(1)),
+            );
+            let state = AppState {
+                pool: pool.clone(),
+                host: host.clone(),
+                agents: std::sync::Arc::new(host),
+                quota: quota.clone(),
+                scores: ScoreCache::new(Duration::from_secs(60), crate::cache::MAX_HOT_KEYS),
+                events: EventCache::new(Duration::from_secs(60), crate::cache::MAX_HOT_KEYS),
+                balances: Default::default(),
+            };
+            let mut app = test::init_service(
+                App::new()
+                    .data(state)
+                    .wrap(LoginLimit::new(quota, pool.clone(), || {
+                        (2, Duration::from_secs(3600))
+                    }))
+                    .wrap_fn(|req, srv| {
+                        req.extensions_mut().insert(JwtToken::with_ttl(1, true, 60));
+                        srv.call(req)
+                    })
+                    .service(clear_user_limits)
+                    .route(
+                        "/api/v1/session",
+                        web::post().to(|| async {
+                            Err::<HttpResponse, _>(ApiError::new(UserError::LoginFailed))
+                        }),
+                    ),
+            )
+            .await;
+            // From different clients, so that only the account is limited.
+            let login = |client: u8| {
+                test::TestRequest::post()
+                    .uri("/api/v1/session")
+                    .peer_addr(format!("1.1.1.{}:5000", client).parse().unwrap())
+                    .set_payload(format!("loginType=2&account={}&credential=x", account))
+                    .to_request()
+            };
+
+            for client in 0..2 {
+                test::call_service(&mut app, login(client)).await;
+            }
+            let res = test::call_service(&mut app, login(2)).await;
+            assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+            let req = test::TestRequest::delete()
+                .uri(&format!("/admin/limits/{}", person.uid))
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let res = test::call_service(&mut app, login(3)).await;
+            assert_ne!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+            let (audited,): (i64,) = sqlx::query_as(
+                "SELECT COUNT(*) FROM public.audit_log WHERE action = $1 AND target = $2",
+            )
+            .bind(AuditAction::ClearLimits.as_str())
+            .bind(person.uid.to_string())
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+            assert_eq!(audited, 1);
+
+            sqlx::query("DELETE FROM public.audit_log WHERE action = $1 AND target = $2")
+                .bind(AuditAction::ClearLimits.as_str())
+                .bind(person.uid.to_string())
+                .execute(&pool)
+                .await
+                .unwrap();
+            sqlx::query("DELETE FROM public.authentication WHERE uid = $1")
+                .bind(person.uid)
+                .execute(&pool)
+                .await
+                .unwrap();
+            sqlx::query("DELETE FROM public.person WHERE uid = $1")
+                .bind(person.uid)
+                .execute(&pool)
+                .await
+                .unwrap();
+        });
+    }
+}
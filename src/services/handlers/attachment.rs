@@ -1,17 +1,53 @@
 use crate::config::CONFIG;
 use crate::error::{ApiError, Result};
-use crate::models::file::{get_attachment_url_prefix, get_file_extension};
-use crate::models::file::{Attachment, AttachmentBasic, AttachmentError, AttachmentManager};
-use crate::models::{CommonError, PageView};
-use crate::services::{response::ApiResponse, AppState, JwtToken};
-use actix_web::{get, post, web, HttpResponse};
-use futures::TryStreamExt;
-use tokio::io::AsyncWriteExt;
+use crate::models::audit::{self, AuditAction};
+use crate::models::file::scanner::{self, ScanResult, SCANNER};
+use crate::models::file::signed_url::{self, SIGNED_URL_TTL};
+use crate::models::file::{blob_key, get_file_extension, is_valid_hash, part_path, sanitize_filename};
+use crate::models::file::{has_thumbnail, spawn_thumbnail, thumbnail_key};
+use crate::models::file::{
+    Attachment, AttachmentBasic, AttachmentError, AttachmentFilter, AttachmentManager, ChunkAction,
+    Storage, UploadCheck, UploadManager, STORAGE,
+};
+use crate::models::{with_db_retry, CommonError, PageView};
+use crate::services::response::{ApiResponse, PagedResponse};
+use crate::services::{idempotency, AppState, JwtToken};
+use actix_web::body::{Body, SizedStream};
+use actix_web::dev::BodyEncoding;
+use actix_web::http::header::{
+    ContentEncoding, HeaderValue, ACCEPT_RANGES, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_RANGE, ETAG,
+    IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, RANGE,
+};
+use actix_web::{delete, get, post, put, web, HttpRequest, HttpResponse};
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use futures::{StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::SeekFrom;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use uuid::Uuid;
 
-const MAX_ATTACHMENT_SIZE: usize = 2 * 1024 * 1024;
+/// Scan the file received, before it's stored. Detections are recorded in the audit log, with the
+/// file name and the threat.
+async fn scan_upload(app: &AppState, uid: i32, name: &str, path: &str) -> Result<()> {
+    let content = tokio::fs::read(path)
+        .await
+        .map_err(|_| ApiError::new(AttachmentError::NotFound))?;
+    let timeout = Duration::from_secs(CONFIG.scan.timeout);
+
+    match scanner::scan(SCANNER.as_ref(), &content, timeout).await? {
+        ScanResult::Clean => Ok(()),
+        ScanResult::Infected(threat) => {
+            let target = format!("{} ({})", name, threat);
+            audit::record(&app.pool, uid, AuditAction::RejectUpload, target).await;
+            Err(ApiError::new(AttachmentError::Infected))
+        }
+    }
+}
 
 /// Upload attachment handler.
-/// Attachments may be stored on ECS or local storage in the services, and now is local storage.
+/// Files are received in local directory, and then moved to the storage selected by config.
 /// Adapted from https://github.com/actix/examples/.
 /// Note: client can upload multiple files, so we use payload.try_next() to iterate all the files.
 /// There is also a while loop and iteration for streams in each file.
@@ -24,81 +60,363 @@ pub async fn upload_file(
 ) -> Result<HttpResponse> {
     let uid = token.ok_or(ApiError::new(CommonError::Forbidden))?.uid;
 
+    let max_size = CONFIG.server.max_attachment_size;
     // Check file size
     for (header, value) in req.headers() {
         if header.to_string().to_ascii_lowercase() == "content-length" {
             let file_size_str = value.to_str().unwrap_or_default();
-            let file_size: usize = file_size_str.parse().unwrap_or(MAX_ATTACHMENT_SIZE + 1);
+            let file_size: usize = file_size_str.parse().unwrap_or(max_size + 1);
 
-            if file_size > MAX_ATTACHMENT_SIZE {
+            if file_size > max_size {
                 return Err(ApiError::new(AttachmentError::TooLarge));
             }
         }
     }
 
-    // Iterate files over multipart stream
-    while let Some(mut field) = payload
-        .try_next()
-        .await
-        .map_err(|_| ApiError::new(AttachmentError::NoPayload))?
-    {
-        // Get filename and sanitize it.
-        // See also:
-        // https://docs.rs/actix-http/1.0.1/actix_http/http/header/struct.ContentDisposition.html
-        let content_type = field.content_disposition().unwrap();
-        // Ignore normal text form data.
-        if content_type.get_filename().is_none() {
-            continue;
-        }
-        let file_ext = get_file_extension(content_type.get_filename().unwrap_or_default());
-
-        // New random uuid for this new file.
-        let uuid = uuid::Uuid::new_v4();
-        let path = format!("{}/upload/{}.{}", &CONFIG.server.attachment, uuid, file_ext);
-        let file = tokio::fs::File::create(&path)
-            .await
-            .map_err(|_| ApiError::new(AttachmentError::FailedToWrite))?;
-        let mut writer = tokio::io::BufWriter::new(file);
+    // Retries of the same upload create one attachment. The body is streamed, so the key is bound
+    // to its length only.
+    let scope = idempotency::user_scope("POST /attachment", uid);
+    let fingerprint = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .map(|x| x.as_bytes().to_vec())
+        .unwrap_or_default();
+    let respond =
+        |attachment: serde_json::Value| Ok(HttpResponse::Ok().json(ApiResponse::normal(attachment)));
 
-        let mut file_size = 0;
-        while let Some(chunk) = field
-            .try_next()
-            .await
-            .map_err(|_| ApiError::new(AttachmentError::Interrupted))?
-        {
-            file_size += chunk.len();
-            if let Err(_) = writer.write_all(&chunk).await {
+    let pool = app.pool.clone();
+
+    let ttl = idempotency::KeyTtl::configured();
+    idempotency::run_once(
+        &pool,
+        &req,
+        &scope,
+        &fingerprint,
+        ttl,
+        || async move {
+            // Iterate files over multipart stream
+            while let Some(mut field) = payload
+                .try_next()
+                .await
+                .map_err(|_| ApiError::new(AttachmentError::NoPayload))?
+            {
+                // Get filename and sanitize it.
+                // See also:
+                // https://docs.rs/actix-http/1.0.1/actix_http/http/header/struct.ContentDisposition.html
+                let content_type = field.content_disposition().unwrap();
+                // Ignore normal text form data.
+                if content_type.get_filename().is_none() {
+                    continue;
+                }
+                let filename = sanitize_filename(content_type.get_filename().unwrap_or_default())
+                    .map_err(ApiError::new)?;
+                let file_ext = get_file_extension(&filename);
+
+                // New random uuid for this new file. The extension is decided after the content is checked.
+                let uuid = uuid::Uuid::new_v4();
+                let temp_path = format!("{}/upload/{}.part", &CONFIG.server.attachment, uuid);
+                let file = tokio::fs::File::create(&temp_path)
+                    .await
+                    .map_err(|_| ApiError::new(AttachmentError::FailedToWrite))?;
+                let mut writer = tokio::io::BufWriter::new(file);
+
+                // Detect file type by leading bytes, because the declared content type can't be trusted.
+                let mut check = UploadCheck::new(
+                    max_size,
+                    &CONFIG.server.allowed_attachment_types,
+                    &CONFIG.server.denied_signatures,
+                );
+                let mut hasher = Sha256::new();
+                while let Some(chunk) = field
+                    .try_next()
+                    .await
+                    .map_err(|_| ApiError::new(AttachmentError::Interrupted))?
+                {
+                    if let Err(e) = check.feed(&chunk) {
+                        drop(writer);
+                        let _ = tokio::fs::remove_file(&temp_path).await;
+                        return Err(ApiError::new(e));
+                    }
+                    hasher.update(&chunk);
+                    if let Err(_) = writer.write_all(&chunk).await {
+                        drop(writer);
+                        tokio::fs::remove_file(&temp_path).await;
+                        return Err(ApiError::new(AttachmentError::FailedToWrite));
+                    }
+                }
+                writer.flush().await;
                 drop(writer);
-                tokio::fs::remove_file(&path).await;
-                return Err(ApiError::new(AttachmentError::FailedToWrite));
+
+                let ext = match check.finish(&file_ext) {
+                    Ok(ext) => ext,
+                    Err(e) => {
+                        let _ = tokio::fs::remove_file(&temp_path).await;
+                        return Err(ApiError::new(e));
+                    }
+                };
+                if let Err(e) = scan_upload(&app, uid, &filename, &temp_path).await {
+                    let _ = tokio::fs::remove_file(&temp_path).await;
+                    return Err(e);
+                }
+                // Files of the same content are stored once.
+                let hash = format!("{:x}", hasher.finalize());
+                let key = blob_key(&hash, &ext);
+
+                let attachment = Attachment::with_id(uuid)
+                    .set_uploader(uid)
+                    .set_name(&filename)
+                    .set_file("", String::new(), check.size() as i32);
+                let manager = AttachmentManager::new(&app.pool);
+                let attachment = manager
+                    .create_deduplicated(attachment, &hash, &temp_path, &key, STORAGE.as_ref())
+                    .await?;
+                if has_thumbnail(&ext) {
+                    spawn_thumbnail(
+                        STORAGE.as_ref(),
+                        CONFIG.server.attachment.clone(),
+                        key,
+                        CONFIG.server.thumbnail_size,
+                    );
+                }
+                return Ok(serde_json::to_value(attachment)?);
             }
+            return Err(ApiError::new(AttachmentError::NoPayload));
+        },
+        respond,
+    )
+    .await
+}
+
+#[derive(Deserialize)]
+pub struct UploadInit {
+    /// File name, whose extension is kept if it matches the content.
+    pub name: String,
+    pub size: i64,
+    /// SHA-256 of the whole file in lowercase hex.
+    pub hash: String,
+}
+
+/// Start a chunked upload, for large files over unreliable networks.
+#[post("/attachment/upload/init")]
+pub async fn init_upload(
+    app: web::Data<AppState>,
+    token: Option<JwtToken>,
+    form: web::Form<UploadInit>,
+) -> Result<HttpResponse> {
+    let uid = token.ok_or(ApiError::new(CommonError::Forbidden))?.uid;
+    let mut form = form.into_inner();
+    form.name = sanitize_filename(&form.name).map_err(ApiError::new)?;
+
+    if form.size <= 0 || !is_valid_hash(&form.hash) {
+        return Err(ApiError::new(CommonError::Parameter));
+    }
+    if form.size as usize > CONFIG.server.max_attachment_size {
+        return Err(ApiError::new(AttachmentError::TooLarge));
+    }
+    let session = UploadManager::new(&app.pool)
+        .create(uid, &form.name, form.size, &form.hash)
+        .await?;
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(session)))
+}
+
+/// Get progress of the upload, to resume from the next chunk.
+#[get("/attachment/upload/{upload_id}")]
+pub async fn get_upload(
+    app: web::Data<AppState>,
+    token: Option<JwtToken>,
+    id: web::Path<Uuid>,
+) -> Result<HttpResponse> {
+    let uid = token.ok_or(ApiError::new(CommonError::Forbidden))?.uid;
+    let (manager, id) = (UploadManager::new(&app.pool), id.into_inner());
+    let session = with_db_retry(|| manager.get(id, uid)).await?;
+
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(session)))
+}
+
+/// Append the n-th chunk, counted from 0, to the upload.
+#[put("/attachment/upload/{upload_id}/chunk/{n}")]
+pub async fn upload_chunk(
+    app: web::Data<AppState>,
+    token: Option<JwtToken>,
+    path: web::Path<(Uuid, i32)>,
+    mut payload: web::Payload,
+) -> Result<HttpResponse> {
+    let uid = token.ok_or(ApiError::new(CommonError::Forbidden))?.uid;
+    let (id, n) = path.into_inner();
+    let manager = UploadManager::new(&app.pool);
+    let session = manager.get(id, uid).await?;
+
+    if session.check_chunk(n).map_err(ApiError::new)? == ChunkAction::Duplicate {
+        return Ok(HttpResponse::Ok().json(ApiResponse::normal(session)));
+    }
+    let write_failed = |_| ApiError::new(AttachmentError::FailedToWrite);
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        // Received chunks are kept.
+        .truncate(false)
+        .open(part_path(&CONFIG.server.attachment, &id))
+        .await
+        .map_err(write_failed)?;
+    // Drop the bytes of an interrupted chunk.
+    file.set_len(session.received as u64)
+        .await
+        .map_err(write_failed)?;
+    file.seek(SeekFrom::End(0)).await.map_err(write_failed)?;
+
+    let mut writer = tokio::io::BufWriter::new(file);
+    let mut received = session.received;
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|_| ApiError::new(AttachmentError::Interrupted))?;
+        received += chunk.len() as i64;
+        if received > session.size {
+            return Err(ApiError::new(AttachmentError::TooLarge));
         }
-        writer.flush().await;
+        writer.write_all(&chunk).await.map_err(write_failed)?;
+    }
+    writer.flush().await.map_err(write_failed)?;
+    manager.advance(id, n, received).await?;
 
-        let attachment = Attachment::with_id(uuid).set_uploader(uid).set_file(
-            get_attachment_url_prefix(),
-            path,
-            file_size as i32,
+    let session = manager.get(id, uid).await?;
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(session)))
+}
+
+/// Check the uploaded file against the declared size and hash, and create the attachment.
+#[post("/attachment/upload/{upload_id}/complete")]
+pub async fn complete_upload(
+    app: web::Data<AppState>,
+    token: Option<JwtToken>,
+    id: web::Path<Uuid>,
+) -> Result<HttpResponse> {
+    let uid = token.ok_or(ApiError::new(CommonError::Forbidden))?.uid;
+    let id = id.into_inner();
+    let manager = UploadManager::new(&app.pool);
+    let session = manager.get(id, uid).await?;
+
+    if session.received != session.size {
+        return Err(ApiError::new(AttachmentError::IncompleteUpload));
+    }
+    let temp_path = part_path(&CONFIG.server.attachment, &id);
+    let mut file = tokio::fs::File::open(&temp_path)
+        .await
+        .map_err(|_| ApiError::new(AttachmentError::NotFound))?;
+
+    let mut check = UploadCheck::new(
+        CONFIG.server.max_attachment_size,
+        &CONFIG.server.allowed_attachment_types,
+        &CONFIG.server.denied_signatures,
+    );
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut result = Ok(());
+    loop {
+        let n = file
+            .read(&mut buffer)
+            .await
+            .map_err(|_| ApiError::new(AttachmentError::NotFound))?;
+        if n == 0 {
+            break;
+        }
+        if let Err(e) = check.feed(&buffer[..n]) {
+            result = Err(e);
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    drop(file);
+    let hash = format!("{:x}", hasher.finalize());
+    let result = result
+        .and_then(|_| match hash == session.hash {
+            true => Ok(()),
+            false => Err(AttachmentError::ChecksumMismatch),
+        })
+        .and_then(|_| check.finish(&get_file_extension(&session.name)));
+
+    let result = match result {
+        Ok(ext) => scan_upload(&app, uid, &session.name, &temp_path)
+            .await
+            .map(|_| ext),
+        Err(e) => Err(ApiError::new(e)),
+    };
+    let ext = match result {
+        Ok(ext) => ext,
+        // The scanner may be back later, so the upload can be completed again.
+        Err(e) if e == ApiError::new(AttachmentError::ScanFailed) => return Err(e),
+        // Otherwise the upload can't be fixed by resending chunks, so it's dropped.
+        Err(e) => {
+            manager.remove(id).await?;
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            return Err(e);
+        }
+    };
+    let mut attachment = Attachment::with_id(Uuid::new_v4())
+        .set_uploader(uid)
+        .set_name(&session.name);
+    attachment.size = session.size as i32;
+    let key = blob_key(&hash, &ext);
+    let attachment = AttachmentManager::new(&app.pool)
+        .create_deduplicated(attachment, &hash, &temp_path, &key, STORAGE.as_ref())
+        .await?;
+    manager.remove(id).await?;
+    if has_thumbnail(&ext) {
+        spawn_thumbnail(
+            STORAGE.as_ref(),
+            CONFIG.server.attachment.clone(),
+            key,
+            CONFIG.server.thumbnail_size,
         );
-        let manager = AttachmentManager::new(&app.pool);
-        manager.create(&attachment).await?;
-        return Ok(HttpResponse::Ok().json(ApiResponse::normal(attachment)));
     }
-    return Err(ApiError::new(AttachmentError::NoPayload));
+
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(attachment)))
 }
 
+#[derive(Deserialize)]
+pub struct ListAttachments {
+    pub index: Option<u16>,
+    pub count: Option<u16>,
+    /// Uid of uploader.
+    pub uploader: Option<i32>,
+    /// Fragment of file name.
+    pub name: Option<String>,
+}
+
+/// Max attachments per page in the list.
+const MAX_ATTACHMENT_PAGE_SIZE: u16 = 100;
+
+/// List attachments. Administrators may list any attachments, and other users their own uploads.
 #[get("/attachment")]
 pub async fn list_attachments(
     app: web::Data<AppState>,
     token: Option<JwtToken>,
-    page: web::Query<PageView>,
+    query: web::Query<ListAttachments>,
 ) -> Result<HttpResponse> {
-    let token = token.ok_or(ApiError::new(CommonError::Forbidden))?;
-    if !token.is_admin {
-        return Err(ApiError::new(CommonError::Forbidden));
-    }
-    let attachments = AttachmentManager::new(&app.pool).list(page.into_inner()).await?;
-    Ok(HttpResponse::Ok().json(&ApiResponse::normal(attachments)))
+    let token = token.ok_or(ApiError::new(CommonError::LoginNeeded))?;
+    let query = query.into_inner();
+
+    let uploader = match query.uploader {
+        _ if token.is_admin => query.uploader,
+        Some(uid) if uid != token.uid => return Err(ApiError::new(CommonError::Forbidden)),
+        _ => Some(token.uid),
+    };
+    let filter = AttachmentFilter {
+        uploader,
+        name: query.name.filter(|x| !x.is_empty()),
+    };
+    let page = PageView {
+        index: query.index,
+        count: query.count,
+    };
+    let manager = AttachmentManager::new(&app.pool);
+    let attachments: Vec<Attachment> =
+        with_db_retry(|| manager.list(&filter, &page, MAX_ATTACHMENT_PAGE_SIZE))
+            .await?
+            .into_iter()
+            .map(|x| x.with_url_from(STORAGE.as_ref()))
+            .collect();
+    let total = with_db_retry(|| manager.count(&filter)).await?;
+
+    let response = PagedResponse::new(attachments, &page, MAX_ATTACHMENT_PAGE_SIZE, total);
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(response)))
 }
 
 #[get("/attachment/{attachment_id}")]
@@ -107,7 +425,10 @@ pub async fn query_attachment(
     token: Option<JwtToken>,
     id: web::Path<(uuid::Uuid,)>,
 ) -> Result<HttpResponse> {
-    let attachment = AttachmentManager::new(&app.pool).query(id.into_inner().0).await?;
+    let attachment = AttachmentManager::new(&app.pool)
+        .query(id.into_inner().0)
+        .await?
+        .with_url_from(STORAGE.as_ref());
     if let Some(token) = token {
         if token.is_admin {
             return Ok(HttpResponse::Ok().json(&ApiResponse::normal(attachment)));
@@ -116,3 +437,550 @@ pub async fn query_attachment(
     let result: AttachmentBasic = attachment.into();
     Ok(HttpResponse::Ok().json(&ApiResponse::normal(result)))
 }
+
+/// Get the thumbnail in PNG of an image attachment, by the same users as the download. It's
+/// generated in a while after uploaded, and only for PNG images.
+#[get("/attachment/{attachment_id}/thumbnail")]
+pub async fn get_thumbnail(
+    req: HttpRequest,
+    app: web::Data<AppState>,
+    token: Option<JwtToken>,
+    id: web::Path<uuid::Uuid>,
+    query: web::Query<SignedQuery>,
+) -> Result<HttpResponse> {
+    let id = id.into_inner();
+    let signed = verify_signed_query(&id, query.into_inner())?;
+    let manager = AttachmentManager::new(&app.pool);
+    let attachment = with_db_retry(|| manager.query(id)).await?;
+    check_access(&attachment, token, signed)?;
+
+    let path = attachment
+        .path
+        .as_ref()
+        .ok_or(ApiError::new(AttachmentError::NotFound))?;
+    if !has_thumbnail(&get_file_extension(path)) {
+        return Err(ApiError::new(AttachmentError::NoThumbnail));
+    }
+    let key = thumbnail_key(path);
+    let validators = Validators::new(&key, &attachment);
+    thumbnail_response(STORAGE.as_ref(), &key, &validators, &req, validators.max_age()).await
+}
+
+/// Respond the thumbnail, or `304 Not Modified` if the copy of the client is fresh. Thumbnails
+/// are generated after the upload, so a copy is only fresh if the thumbnail exists, and
+/// `NoThumbnail` is returned before that.
+async fn thumbnail_response(
+    storage: &dyn Storage,
+    key: &str,
+    validators: &Validators,
+    req: &HttpRequest,
+    max_age: u32,
+) -> Result<HttpResponse> {
+    storage.size(key).await.map_err(|e| {
+        if e == ApiError::new(AttachmentError::NotFound) {
+            ApiError::new(AttachmentError::NoThumbnail)
+        } else {
+            e
+        }
+    })?;
+    if validators.not_modified(req) {
+        return Ok(validators.respond(HttpResponse::NotModified().finish(), max_age));
+    }
+
+    let content = storage.get(key).await?;
+    let response = HttpResponse::Ok().content_type("image/png").body(content);
+    Ok(validators.respond(response, max_age))
+}
+
+/// Delete the attachment and its file, by the uploader or administrator.
+#[delete("/attachment/{attachment_id}")]
+pub async fn delete_attachment(
+    app: web::Data<AppState>,
+    token: Option<JwtToken>,
+    id: web::Path<uuid::Uuid>,
+) -> Result<HttpResponse> {
+    let token = token.ok_or(ApiError::new(CommonError::LoginNeeded))?;
+    let id = id.into_inner();
+    let manager = AttachmentManager::new(&app.pool);
+    let attachment = manager.query(id).await?;
+
+    if attachment.uploader != token.uid && !token.is_admin {
+        return Err(ApiError::new(CommonError::Forbidden));
+    }
+    // The file is removed with the last attachment linked to it.
+    manager.delete(id, STORAGE.as_ref()).await?;
+    audit::record(&app.pool, token.uid, AuditAction::DeleteAttachment, id).await;
+
+    Ok(HttpResponse::Ok().json(ApiResponse::empty()))
+}
+
+/// Get a signed link to download the attachment without the access token, which expires in
+/// `SIGNED_URL_TTL` seconds.
+#[get("/attachment/{attachment_id}/sign")]
+pub async fn sign_attachment(
+    app: web::Data<AppState>,
+    token: Option<JwtToken>,
+    id: web::Path<uuid::Uuid>,
+) -> Result<HttpResponse> {
+    let token = token.ok_or(ApiError::new(CommonError::LoginNeeded))?;
+    let id = id.into_inner();
+    let manager = AttachmentManager::new(&app.pool);
+    let attachment = with_db_retry(|| manager.query(id)).await?;
+
+    if attachment.uploader != token.uid && !token.is_admin {
+        return Err(ApiError::new(CommonError::Forbidden));
+    }
+    let expires = Utc::now().timestamp() + SIGNED_URL_TTL;
+    let key = CONFIG.server.derive_key(signed_url::KEY_PURPOSE);
+    let signature = signed_url::sign(&id, expires, &key);
+
+    #[derive(Serialize)]
+    struct SignedUrl {
+        url: String,
+        expires: i64,
+    }
+    let url = format!(
+        "/api/v1/attachment/{}/download?expires={}&signature={}",
+        id, expires, signature
+    );
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(SignedUrl { url, expires })))
+}
+
+/// Byte range requested in the `Range` header.
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    /// No range, or a range not supported, so the whole content is sent.
+    Full,
+    /// Start and end, both inclusive.
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// Parse a single range like "bytes=0-499", "bytes=500-" or "bytes=-500" of the content in `len`
+/// bytes. Multiple ranges are not supported.
+fn parse_range(header: Option<&str>, len: u64) -> ByteRange {
+    let spec = match header.and_then(|x| x.trim().strip_prefix("bytes=")) {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return ByteRange::Full,
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(x) => x,
+        None => return ByteRange::Full,
+    };
+    let (start, end) = match (start.parse::<u64>(), end) {
+        // Suffix range, the last n bytes.
+        (Err(_), end) if start.is_empty() => match end.parse::<u64>() {
+            Ok(n) if n > 0 && len > 0 => (len.saturating_sub(n), len - 1),
+            Ok(_) => return ByteRange::Unsatisfiable,
+            Err(_) => return ByteRange::Full,
+        },
+        (Ok(start), "") => (start, len.saturating_sub(1)),
+        (Ok(start), end) => match end.parse::<u64>() {
+            Ok(end) if end >= start => (start, end.min(len.saturating_sub(1))),
+            _ => return ByteRange::Full,
+        },
+        _ => return ByteRange::Full,
+    };
+    if start >= len {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial(start, end)
+}
+
+/// Stream `len` bytes of the stored file from `start`, as the body of a response.
+async fn sized_body(storage: &dyn Storage, key: &str, start: u64, len: u64) -> Result<Body> {
+    let chunks = storage.read_range(key, start, len).await?;
+
+    Ok(Body::from(SizedStream::new(len, chunks.err_into())))
+}
+
+/// Respond the stored file in `len` bytes, or the part of it requested in the `Range` header.
+async fn ranged_response(
+    storage: &dyn Storage,
+    key: &str,
+    len: u64,
+    range: Option<&str>,
+) -> Result<HttpResponse> {
+    let mut response = match parse_range(range, len) {
+        ByteRange::Full => HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .header(ACCEPT_RANGES, "bytes")
+            .body(sized_body(storage, key, 0, len).await?),
+        ByteRange::Partial(start, end) => HttpResponse::PartialContent()
+            .content_type("application/octet-stream")
+            .header(ACCEPT_RANGES, "bytes")
+            .header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, len))
+            .body(sized_body(storage, key, start, end - start + 1).await?),
+        ByteRange::Unsatisfiable => HttpResponse::RangeNotSatisfiable()
+            .header(CONTENT_RANGE, format!("bytes */{}", len))
+            .finish(),
+    };
+    // Ranges are of the content as it is, so it's never compressed.
+    response.encoding(ContentEncoding::Identity);
+    Ok(response)
+}
+
+/// Validators of a stored file, to answer conditional requests with `304 Not Modified` and save
+/// downloading unchanged files again.
+struct Validators {
+    etag: String,
+    last_modified: NaiveDateTime,
+    /// Files stored by content hashes never change under their keys.
+    immutable: bool,
+}
+
+impl Validators {
+    /// Files stored by content hashes are tagged by the hashes, and the others by upload time and
+    /// size, so that the content is not read to tag it.
+    fn new(key: &str, attachment: &Attachment) -> Self {
+        let name = key.rsplit('/').next().unwrap_or_default();
+        // Thumbnails are keyed like "<hash>.thumb.png".
+        let stem = name.split('.').next().unwrap_or_default();
+        let immutable = is_valid_hash(stem);
+        let etag = if immutable {
+            format!("\"{}\"", stem)
+        } else {
+            format!(
+                "\"{:x}-{:x}\"",
+                attachment.upload_time.timestamp(),
+                attachment.size
+            )
+        };
+
+        Self {
+            etag,
+            last_modified: attachment.upload_time,
+            immutable,
+        }
+    }
+
+    fn max_age(&self) -> u32 {
+        if self.immutable {
+            CONFIG.server.blob_max_age
+        } else {
+            CONFIG.server.attachment_max_age
+        }
+    }
+
+    fn http_date(&self) -> String {
+        Utc.from_utc_datetime(&self.last_modified)
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string()
+    }
+
+    /// Whether the copy of the client is fresh. `If-Modified-Since` is ignored if `If-None-Match`
+    /// is sent, as RFC 7232 says.
+    fn not_modified(&self, req: &HttpRequest) -> bool {
+        let header = |name| req.headers().get(name).and_then(|x| x.to_str().ok());
+
+        if let Some(tags) = header(IF_NONE_MATCH) {
+            // Weak comparison, which is enough for GET.
+            return tags
+                .split(',')
+                .map(|x| x.trim().trim_start_matches("W/"))
+                .any(|x| x == "*" || x == self.etag);
+        }
+        match header(IF_MODIFIED_SINCE).and_then(|x| DateTime::parse_from_rfc2822(x).ok()) {
+            Some(since) => self.last_modified.timestamp() <= since.timestamp(),
+            None => false,
+        }
+    }
+
+    /// Attach validators and `Cache-Control` to the response.
+    fn respond(&self, mut response: HttpResponse, max_age: u32) -> HttpResponse {
+        // Attachments are private to their uploaders, so they're not cached by shared caches.
+        let cache_control = if self.immutable {
+            format!("private, max-age={}, immutable", max_age)
+        } else {
+            format!("private, max-age={}", max_age)
+        };
+        let headers = response.headers_mut();
+
+        for (name, value) in [
+            (ETAG, self.etag.clone()),
+            (LAST_MODIFIED, self.http_date()),
+            (CACHE_CONTROL, cache_control),
+        ] {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(name, value);
+            }
+        }
+        response
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SignedQuery {
+    pub expires: Option<i64>,
+    pub signature: Option<String>,
+}
+
+/// Whether the query has a signed link, which is checked before the attachment is loaded, so that
+/// forged links can't tell which ids exist.
+fn verify_signed_query(id: &uuid::Uuid, query: SignedQuery) -> Result<bool> {
+    match query {
+        SignedQuery {
+            expires: Some(expires),
+            signature: Some(signature),
+        } => {
+            let now = Utc::now().timestamp();
+            let key = CONFIG.server.derive_key(signed_url::KEY_PURPOSE);
+            if !signed_url::verify(id, expires, &signature, now, &key) {
+                return Err(ApiError::new(AttachmentError::InvalidSignature));
+            }
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Allow the uploader or administrator, or anyone with a signed link.
+fn check_access(attachment: &Attachment, token: Option<JwtToken>, signed: bool) -> Result<()> {
+    if signed {
+        return Ok(());
+    }
+    let token = token.ok_or(ApiError::new(CommonError::LoginNeeded))?;
+    if attachment.uploader != token.uid && !token.is_admin {
+        return Err(ApiError::new(CommonError::Forbidden));
+    }
+    Ok(())
+}
+
+/// Download the attachment, by the uploader or administrator, or by a signed link. A range of it
+/// can be requested in the `Range` header, to resume an interrupted download.
+#[get("/attachment/{attachment_id}/download")]
+pub async fn download_attachment(
+    req: HttpRequest,
+    app: web::Data<AppState>,
+    token: Option<JwtToken>,
+    id: web::Path<uuid::Uuid>,
+    query: web::Query<SignedQuery>,
+) -> Result<HttpResponse> {
+    let id = id.into_inner();
+    let signed = verify_signed_query(&id, query.into_inner())?;
+    let manager = AttachmentManager::new(&app.pool);
+    let attachment = with_db_retry(|| manager.query(id)).await?;
+    check_access(&attachment, token, signed)?;
+
+    let path = attachment
+        .path
+        .as_ref()
+        .ok_or(ApiError::new(AttachmentError::NotFound))?;
+    let validators = Validators::new(path, &attachment);
+    if validators.not_modified(&req) {
+        return Ok(validators.respond(HttpResponse::NotModified().finish(), validators.max_age()));
+    }
+
+    // Streamed, so that large files are never read into memory as a whole.
+    let len = STORAGE.size(path).await?;
+    let range = req.headers().get(RANGE).and_then(|x| x.to_str().ok());
+    let response = ranged_response(STORAGE.as_ref(), path, len, range).await?;
+    Ok(validators.respond(response, validators.max_age()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::file::storage::LocalStorage;
+    use actix_web::body::{BodySize, MessageBody, ResponseBody};
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App, ResponseError};
+    use chrono::{DateTime, Local, TimeZone};
+
+    fn body_of(response: &HttpResponse) -> &[u8] {
+        match response.body() {
+            ResponseBody::Body(Body::Bytes(bytes)) => bytes,
+            _ => &[],
+        }
+    }
+
+    fn attachment(path: &str) -> Attachment {
+        Attachment {
+            id: Uuid::new_v4(),
+            name: "a.png".to_string(),
+            uploader: 1,
+            upload_time: NaiveDateTime::from_timestamp(1_600_000_000, 0),
+            updated_at: Local.timestamp(1_600_000_000, 0),
+            path: Some(path.to_string()),
+            size: 100,
+            is_deleted: false,
+            url: None,
+        }
+    }
+
+    #[test]
+    pub fn test_validators() {
+        let hash = "a".repeat(64);
+        let key = blob_key(&hash, "png");
+        let validators = Validators::new(&key, &attachment(&key));
+        assert_eq!(validators.etag, format!("\"{}\"", hash));
+        assert!(validators.immutable);
+        assert_eq!(validators.http_date(), "Sun, 13 Sep 2020 12:26:40 GMT");
+        // The thumbnail is of the same content.
+        assert!(Validators::new(&thumbnail_key(&key), &attachment(&key)).immutable);
+
+        let legacy = "/var/kite/upload/0a1b.png";
+        let validators = Validators::new(legacy, &attachment(legacy));
+        assert_eq!(validators.etag, "\"5f5e1000-64\"");
+        assert!(!validators.immutable);
+    }
+
+    #[test]
+    pub fn test_serialize_timestamps() {
+        let value = serde_json::to_value(attachment("a.png")).unwrap();
+
+        let upload_time = DateTime::parse_from_rfc3339(value["uploadTime"].as_str().unwrap()).unwrap();
+        let update_time = DateTime::parse_from_rfc3339(value["updateTime"].as_str().unwrap()).unwrap();
+        assert_eq!(upload_time.timestamp(), 1_600_000_000);
+        assert_eq!(update_time.timestamp(), 1_600_000_000);
+    }
+
+    #[test]
+    pub fn test_conditional_request() {
+        actix_web::rt::System::new("test").block_on(async {
+            let hash = "a".repeat(64);
+            let key = blob_key(&hash, "png");
+            let mut app = test::init_service(App::new().route(
+                "/file",
+                web::get().to(move |req: HttpRequest| {
+                    let validators = Validators::new(&key, &attachment(&key));
+                    let response = if validators.not_modified(&req) {
+                        HttpResponse::NotModified().finish()
+                    } else {
+                        HttpResponse::Ok().body("content")
+                    };
+                    futures::future::ready(validators.respond(response, 60))
+                }),
+            ))
+            .await;
+
+            let req = test::TestRequest::get().uri("/file").to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let etag = res.headers().get(ETAG).unwrap().clone();
+            let last_modified = res.headers().get(LAST_MODIFIED).unwrap().clone();
+            assert_eq!(
+                res.headers().get(CACHE_CONTROL).unwrap(),
+                "private, max-age=60, immutable"
+            );
+
+            let req = test::TestRequest::get()
+                .uri("/file")
+                .header(IF_NONE_MATCH, etag)
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+            assert!(res.headers().contains_key(ETAG));
+
+            let req = test::TestRequest::get()
+                .uri("/file")
+                .header(IF_MODIFIED_SINCE, last_modified)
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+
+            // A stale tag wins over the date.
+            let req = test::TestRequest::get()
+                .uri("/file")
+                .header(IF_NONE_MATCH, "\"stale\"")
+                .header(IF_MODIFIED_SINCE, "Mon, 01 Jan 2024 00:00:00 GMT")
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+        });
+    }
+
+    #[test]
+    pub fn test_parse_range() {
+        assert_eq!(parse_range(None, 100), ByteRange::Full);
+        assert_eq!(parse_range(Some("bytes=0-49"), 100), ByteRange::Partial(0, 49));
+        assert_eq!(parse_range(Some("bytes=50-"), 100), ByteRange::Partial(50, 99));
+        assert_eq!(parse_range(Some("bytes=-10"), 100), ByteRange::Partial(90, 99));
+        assert_eq!(parse_range(Some("bytes=-200"), 100), ByteRange::Partial(0, 99));
+        // The end is clamped to the content.
+        assert_eq!(parse_range(Some("bytes=90-200"), 100), ByteRange::Partial(90, 99));
+        assert_eq!(parse_range(Some("bytes=100-"), 100), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range(Some("bytes=-0"), 100), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range(Some("bytes=0-"), 0), ByteRange::Unsatisfiable);
+        // Not supported or malformed, ignored.
+        assert_eq!(parse_range(Some("bytes=0-1,5-6"), 100), ByteRange::Full);
+        assert_eq!(parse_range(Some("items=0-1"), 100), ByteRange::Full);
+        assert_eq!(parse_range(Some("bytes=5-1"), 100), ByteRange::Full);
+    }
+
+    async fn read_body(mut response: HttpResponse) -> Vec<u8> {
+        let chunks: Vec<_> = response.take_body().collect().await;
+        chunks
+            .into_iter()
+            .map(|x| x.unwrap())
+            .collect::<Vec<_>>()
+            .concat()
+    }
+
+    #[tokio::test]
+    async fn test_thumbnail_response() {
+        let dir = std::env::temp_dir().join(format!("kite-test-{}", Uuid::new_v4()));
+        let dir = dir.to_str().unwrap();
+        std::fs::create_dir_all(format!("{}/upload", dir)).unwrap();
+        let storage = LocalStorage::new(dir, "https://example.com/upload/");
+        let key = thumbnail_key(&blob_key(&"a".repeat(64), "png"));
+        let validators = Validators::new(&key, &attachment(&key));
+        let req = test::TestRequest::get()
+            .header(IF_NONE_MATCH, validators.etag.clone())
+            .to_http_request();
+
+        // Not generated yet, though the tag matches.
+        let e = thumbnail_response(&storage, &key, &validators, &req, 60)
+            .await
+            .unwrap_err();
+        assert_eq!(e, ApiError::new(AttachmentError::NoThumbnail));
+        assert_eq!(e.status_code(), StatusCode::NOT_FOUND);
+
+        std::fs::write(format!("{}/upload/{}", dir, key), b"png").unwrap();
+        let response = thumbnail_response(&storage, &key, &validators, &req, 60)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        let req = test::TestRequest::get().to_http_request();
+        let response = thumbnail_response(&storage, &key, &validators, &req, 60)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(read_body(response).await, b"png");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ranged_response() {
+        let dir = std::env::temp_dir().join(format!("kite-test-{}", Uuid::new_v4()));
+        let dir = dir.to_str().unwrap();
+        std::fs::create_dir_all(format!("{}/upload", dir)).unwrap();
+        let storage = LocalStorage::new(dir, "https://example.com/upload/");
+        let content: Vec<u8> = (0..100).collect();
+        std::fs::write(format!("{}/upload/a.bin", dir), &content).unwrap();
+
+        let response = ranged_response(&storage, "a.bin", 100, Some("bytes=10-19"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers().get(CONTENT_RANGE).unwrap(), "bytes 10-19/100");
+        assert_eq!(response.headers().get(ACCEPT_RANGES).unwrap(), "bytes");
+        assert_eq!(read_body(response).await, &content[10..20]);
+
+        let response = ranged_response(&storage, "a.bin", 100, Some("bytes=100-"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers().get(CONTENT_RANGE).unwrap(), "bytes */100");
+
+        let response = ranged_response(&storage, "a.bin", 100, None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get(ACCEPT_RANGES).unwrap(), "bytes");
+        // Streamed in the size known.
+        assert_eq!(response.body().size(), BodySize::Sized(100));
+        assert_eq!(read_body(response).await, content);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,12 +1,22 @@
 //! This module includes interfaces about course, major and score.
 
-use crate::error::Result;
-use crate::models::edu::{self, CourseBase, CourseClass, Major, PlannedCourse};
-use crate::models::{CommonError, PageView};
+use crate::clock;
+use crate::config::GpaScheme;
+use crate::config::CONFIG;
+use crate::error::{ApiError, Result};
+use crate::models::edu::{
+    self, fetch_scores, transcript, CachedScores, CourseBase, CourseClass, EduError, GpaReport, Major,
+    PlannedCourse, ScoreError,
+};
+use crate::models::user::Person;
+use crate::models::{with_db_retry, CommonError, PageView};
 use crate::services::response::ApiResponse;
-use crate::services::AppState;
-use actix_web::{get, web, HttpResponse};
-use chrono::Datelike;
+use crate::services::{AppState, JwtToken};
+use actix_web::web::Bytes;
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use chrono::{DateTime, Datelike, Local};
+use futures::future::ok;
+use futures::{stream, StreamExt};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize)]
@@ -20,7 +30,7 @@ pub async fn query_major(
     query: web::Query<ListMajor>,
 ) -> Result<HttpResponse> {
     let parameters: ListMajor = query.into_inner();
-    let results = Major::query(&app.pool, &parameters.q).await?;
+    let results = with_db_retry(|| Major::query(&app.pool, &parameters.q)).await?;
 
     Ok(HttpResponse::Ok().json(&ApiResponse::normal(results)))
 }
@@ -36,10 +46,8 @@ pub async fn get_planned_course(
     major_code: web::Path<String>,
     query: web::Query<ListPlannedCourse>,
 ) -> Result<HttpResponse> {
-    use chrono::Local;
-
     // Get local current year as the default year.
-    let mut year = Local::today().naive_local().year() as i16;
+    let mut year = clock::today().year() as i16;
     // If user submit a year and it's valid, then use user defined year.
     // This limit is to reduce possible unnecessary database queries
     if let Some(input_year) = query.into_inner().year {
@@ -47,7 +55,7 @@ pub async fn get_planned_course(
             year = input_year;
         }
     }
-    let results = PlannedCourse::query(&app.pool, &major_code, year).await?;
+    let results = with_db_retry(|| PlannedCourse::query(&app.pool, &major_code, year)).await?;
     Ok(HttpResponse::Ok().json(&ApiResponse::normal(results)))
 }
 
@@ -70,9 +78,9 @@ pub async fn list_course_classes(
         }
     }
     let term_string = term.unwrap_or(edu::get_current_term());
-    let course = CourseBase::get(&app.pool, &course_code, &term_string).await?;
+    let course = with_db_retry(|| CourseBase::get(&app.pool, &course_code, &term_string)).await?;
     if let Some(course) = course {
-        let classes = CourseClass::list(&app.pool, &course_code, &term_string).await?;
+        let classes = with_db_retry(|| CourseClass::list(&app.pool, &course_code, &term_string)).await?;
         #[derive(Serialize)]
         struct Response {
             pub course: CourseBase,
@@ -108,6 +116,247 @@ pub async fn query_course(
         return Ok(HttpResponse::Ok().json(&ApiResponse::empty()));
     }
     let term_string = term.unwrap_or(edu::get_current_term());
-    let course = CourseBase::query(&app.pool, &parameters.q, &term_string, &page).await?;
+    let keyword = &parameters.q;
+    let course = with_db_retry(|| CourseBase::query(&app.pool, keyword, &term_string, &page)).await?;
     Ok(HttpResponse::Ok().json(&ApiResponse::normal(course)))
 }
+
+#[derive(Debug, Deserialize)]
+pub struct GpaQuery {
+    /// Fetch scores again instead of using the cache.
+    #[serde(default)]
+    pub force: bool,
+    /// Student id of the identity, the primary one by default.
+    #[serde(rename = "studentId")]
+    pub student_id: Option<String>,
+    /// Name of the grade point table, `CONFIG.server.gpa_scheme` by default.
+    pub scheme: Option<String>,
+}
+
+/// Whether the route is configured to serve cached data when agents fail.
+fn is_stale_on_error(routes: &[String], path: &str) -> bool {
+    routes.iter().any(|x| path.starts_with(x.as_str()))
+}
+
+/// Grade point table of the name, or the default one.
+fn select_gpa_scheme(name: Option<&str>) -> Result<(&str, &'static GpaScheme)> {
+    let name = name.unwrap_or(&CONFIG.server.gpa_scheme);
+    let scheme = CONFIG
+        .gpa_schemes
+        .get(name)
+        .ok_or(ApiError::new(CommonError::Parameter))?;
+    Ok((name, scheme))
+}
+
+/// Scores of the identity selected by the user, with the student id.
+async fn load_scores(
+    app: &AppState,
+    uid: i32,
+    student_id: Option<&str>,
+    force: bool,
+    stale_on_error: bool,
+) -> Result<(String, CachedScores)> {
+    let identity = Person::select_owned_identity(&app.pool, uid, student_id)
+        .await?
+        .filter(|x| x.oa_certified && x.oa_secret.is_some())
+        .ok_or(ApiError::new(EduError::OaAccountNeeded))?;
+    let (account, credential) = (identity.student_id, identity.oa_secret.unwrap_or_default());
+
+    let agents = app.agents.clone();
+    let student_id = account.clone();
+    let scores = app
+        .scores
+        .get(&student_id, force, stale_on_error, move || {
+            fetch_scores(agents, account, credential)
+        })
+        .await?;
+    Ok((student_id, scores))
+}
+
+#[get("/edu/gpa")]
+pub async fn get_gpa(
+    app: web::Data<AppState>,
+    token: Option<JwtToken>,
+    query: web::Query<GpaQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse> {
+    let token = token.ok_or(ApiError::new(CommonError::LoginNeeded))?;
+    let (scheme_name, scheme) = select_gpa_scheme(query.scheme.as_deref())?;
+    let student_id = query.student_id.as_deref();
+    let stale_on_error = is_stale_on_error(&CONFIG.server.stale_on_error, req.path());
+    let (_, scores) = load_scores(&app, token.uid, student_id, query.force, stale_on_error).await?;
+
+    #[derive(Serialize)]
+    struct GpaResponse {
+        #[serde(flatten)]
+        report: GpaReport,
+        /// Time the scores are fetched.
+        #[serde(rename = "fetchTime")]
+        fetch_time: DateTime<Local>,
+        /// Scores are served from the cache because the campus system can't be reached.
+        stale: bool,
+        /// Name of the grade point table used.
+        scheme: String,
+        /// Number of courses left out because the campus system sent them malformed.
+        #[serde(rename = "errorCount")]
+        error_count: usize,
+        errors: Vec<ScoreError>,
+    }
+    let response = GpaResponse {
+        report: edu::compute_gpa(&scores.terms, scheme),
+        fetch_time: scores.fetch_time,
+        stale: scores.stale,
+        scheme: scheme_name.to_string(),
+        error_count: scores.errors.len(),
+        errors: scores.errors.to_vec(),
+    };
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(response)))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportScores {
+    /// Only `csv` is supported now.
+    pub format: Option<String>,
+    #[serde(default)]
+    pub force: bool,
+    #[serde(rename = "studentId")]
+    pub student_id: Option<String>,
+    pub scheme: Option<String>,
+}
+
+/// Header of the number of courses left out of the transcript because they are malformed.
+const SCORE_ERRORS: &str = "X-Score-Errors";
+
+/// Export scores of the user as a CSV transcript, with the GPA summary at the end. Rows are sent
+/// term by term.
+#[get("/edu/scores/export")]
+pub async fn export_scores(
+    app: web::Data<AppState>,
+    token: Option<JwtToken>,
+    query: web::Query<ExportScores>,
+    req: HttpRequest,
+) -> Result<HttpResponse> {
+    let token = token.ok_or(ApiError::new(CommonError::LoginNeeded))?;
+    let query = query.into_inner();
+
+    if query.format.as_deref().unwrap_or("csv") != "csv" {
+        return Err(ApiError::new(CommonError::Parameter));
+    }
+    let (_, scheme) = select_gpa_scheme(query.scheme.as_deref())?;
+    let student_id = query.student_id.as_deref();
+    let stale_on_error = is_stale_on_error(&CONFIG.server.stale_on_error, req.path());
+    let (student_id, scores) =
+        load_scores(&app, token.uid, student_id, query.force, stale_on_error).await?;
+
+    let summary = Bytes::from(transcript::csv_summary(&edu::compute_gpa(&scores.terms, scheme)));
+    let terms = scores.terms.clone();
+    let rows = stream::iter(0..terms.len()).map(move |i| {
+        let (term, scores) = &terms[i];
+        Ok::<_, actix_web::Error>(Bytes::from(transcript::csv_term(term, scores, scheme)))
+    });
+    let body = stream::once(ok(Bytes::from(transcript::CSV_HEADER)))
+        .chain(rows)
+        .chain(stream::once(ok(summary)));
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/csv; charset=utf-8")
+        .header(
+            "Content-Disposition",
+            format!("attachment; filename=\"scores-{}.csv\"", student_id),
+        )
+        .header(SCORE_ERRORS, scores.errors.len().to_string())
+        .streaming(body))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bridge::{AgentManager, ErrorResponse, MockTransport, RequestLimiter};
+    use crate::bridge::{CourseScore, CourseScoreLine, RequestPayload, ResponsePayload};
+    use crate::models::edu::ScoreCache;
+    use crate::models::event::EventCache;
+    use crate::models::quota::QuotaStore;
+    use crate::models::test::db_pool;
+    use crate::models::user::Identity;
+    use sqlx::PgPool;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn state(pool: PgPool, agents: Arc<MockTransport>) -> AppState {
+        AppState {
+            pool,
+            host: AgentManager::new(
+                Duration::from_secs(60),
+                RequestLimiter::new(16, 16, Duration::from_secs(1)),
+            ),
+            agents,
+            quota: QuotaStore::memory(),
+            scores: ScoreCache::new(Duration::from_secs(60), crate::cache::MAX_HOT_KEYS),
+            events: EventCache::new(Duration::from_secs(60), crate::cache::MAX_HOT_KEYS),
+            balances: Default::default(),
+        }
+    }
+
+    #[test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    pub fn test_load_scores_from_mock_agent() {
+        actix_web::rt::System::new("test").block_on(async {
+            let pool = db_pool().await;
+            let student_id = "25TEST0347";
+            let cleanup = || {
+                sqlx::query("DELETE FROM public.identities WHERE student_id = $1").bind(student_id)
+            };
+            cleanup().execute(&pool).await.unwrap();
+            let mut person = Person::new();
+            person.register(&pool).await.unwrap();
+            let mut identity = Identity::new(person.uid, student_id.to_string());
+            person.set_identity(&pool, &mut identity).await.unwrap();
+
+            // A course in each term, named by the term.
+            let agents = Arc::new(MockTransport::new(|request| match request {
+                RequestPayload::ScoreList(request) => {
+                    Ok(ResponsePayload::ScoreList(
+                        vec![CourseScore {
+                            course_code: "B1001".to_string(),
+                            course_name: request.term.clone(),
+                            course_credit: 4.0,
+                            detail: CourseScoreLine::PassFail(true),
+                        }]
+                        .into(),
+                    ))
+                }
+                _ => Err(ErrorResponse {
+                    code: 2,
+                    msg: "Unavailable".to_string(),
+                }),
+            }));
+            let app = state(pool.clone(), agents.clone());
+
+            // Not certified yet.
+            let result = load_scores(&app, person.uid, None, false, false).await;
+            assert!(matches!(result, Err(e) if e == ApiError::new(EduError::OaAccountNeeded)));
+            assert_eq!(agents.sent(), 0);
+
+            sqlx::query(
+                "UPDATE public.identities SET oa_secret = 'secret', oa_certified = true WHERE student_id = $1",
+            )
+            .bind(student_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+            let (id, scores) = load_scores(&app, person.uid, None, false, false).await.unwrap();
+            assert_eq!(id, student_id);
+            // A request for each term since enrollment.
+            let terms = edu::terms_since(student_id, &edu::get_current_term());
+            assert_eq!(agents.sent(), terms.len());
+            assert_eq!(scores.terms.len(), terms.len());
+            assert_eq!(scores.terms[0].0, "2025B");
+            assert_eq!(scores.terms[0].1[0].course_name, "2025B");
+            // Served from the cache.
+            load_scores(&app, person.uid, None, false, false).await.unwrap();
+            assert_eq!(agents.sent(), terms.len());
+
+            cleanup().execute(&pool).await.unwrap();
+        });
+    }
+}
@@ -1,18 +1,23 @@
 //! This module includes interfaces about the event and sign.
-use crate::error::Result;
-use crate::models::{event, PageView};
-use crate::services::response::ApiResponse;
-use crate::services::AppState;
+use crate::clock;
+use crate::error::{ApiError, Result};
+use crate::models::event::EventFilter;
+use crate::models::user::Person;
+use crate::models::{event, with_db_retry, CommonError, PageView};
+use crate::services::response::{ApiResponse, PagedResponse};
+use crate::services::{AppState, JwtToken};
 use actix_web::{get, web, HttpResponse};
+use chrono::NaiveDate;
 use serde::Deserialize;
 
 /**********************************************************************
     Interfaces in this module:
     list_events()         <-- get  /event
+    get_event()           <-- get  /event/{event_id}
+    recommend_events()    <-- get  /activity/recommend
 
     // TODO: implementing.
     create_event()        <-- post /event
-    get_event()           <-- get  /event/{event_id}
     get_participants()    <-- get  /event/{event_id}/participant
     participate()         <-- post /event/{event_id}/participant
 *********************************************************************/
@@ -20,18 +25,87 @@ use serde::Deserialize;
 #[derive(Debug, Deserialize)]
 pub struct ListEvent {
     refresh: Option<bool>,
+    /// Show events for all audiences instead of the caller's.
+    all: Option<bool>,
+    /// Tag of events.
+    category: Option<String>,
+    /// Fragment of titles.
+    keyword: Option<String>,
+    /// First day of the range, in which events are held.
+    from: Option<NaiveDate>,
+    /// Last day of the range.
+    to: Option<NaiveDate>,
 }
 
+/// Max events per page in the list.
+const MAX_EVENT_PAGE_SIZE: u16 = 50;
+
 #[get("/event")]
 pub async fn list_events(
     app: web::Data<AppState>,
+    token: Option<JwtToken>,
     page: web::Query<PageView>,
     form: web::Query<ListEvent>,
 ) -> Result<HttpResponse> {
-    let parameters: PageView = page.into_inner();
+    let page: PageView = page.into_inner();
+    let form = form.into_inner();
+    if let (Some(from), Some(to)) = (form.from, form.to) {
+        if from > to {
+            return Err(ApiError::new(CommonError::Parameter));
+        }
+    }
+
+    // Filter events by the audience derived from caller's identity, unless all events are requested.
+    let mut audience = None;
+    if let (Some(token), false) = (token, form.all.unwrap_or(false)) {
+        if let Some(identity) = with_db_retry(|| Person::get_identity(&app.pool, token.uid)).await? {
+            let today = clock::today();
+            audience = Some(event::audience_of(&identity.student_id, today));
+        }
+    }
+    let filter = EventFilter {
+        audience,
+        category: form.category.filter(|x| !x.is_empty()),
+        keyword: form.keyword.filter(|x| !x.is_empty()),
+        from: form.from,
+        to: form.to,
+    };
+    let events =
+        with_db_retry(|| event::Event::list(&app.pool, &filter, &page, MAX_EVENT_PAGE_SIZE)).await?;
+    app.events.invalidate_changed(&events);
+    let total = with_db_retry(|| event::Event::count(&app.pool, &filter)).await?;
+
+    let response = PagedResponse::new(events, &page, MAX_EVENT_PAGE_SIZE, total);
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(response)))
+}
+
+/// Get the event with its description.
+#[get("/event/{event_id}")]
+pub async fn get_event(
+    app: web::Data<AppState>,
+    token: Option<JwtToken>,
+    id: web::Path<i32>,
+) -> Result<HttpResponse> {
+    token.ok_or(ApiError::new(CommonError::LoginNeeded))?;
+    let id = id.into_inner();
+    let pool = &app.pool;
+    let event = app
+        .events
+        .get(id, || with_db_retry(|| event::Event::get(pool, id)))
+        .await?;
+
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(&*event)))
+}
 
-    let event_summaries =
-        event::Event::list(&app.pool, parameters.index() as u32, parameters.count(10) as u32).await?;
+/// Recommend events which the user might like.
+#[get("/activity/recommend")]
+pub async fn recommend_events(
+    app: web::Data<AppState>,
+    token: Option<JwtToken>,
+    page: web::Query<PageView>,
+) -> Result<HttpResponse> {
+    let token = token.ok_or(ApiError::new(CommonError::LoginNeeded))?;
+    let events = with_db_retry(|| event::Event::recommend(&app.pool, token.uid)).await?;
 
-    Ok(HttpResponse::Ok().json(&ApiResponse::normal(event_summaries)))
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(PagedResponse::paginate(events, &page, 50))))
 }
@@ -1,9 +1,15 @@
 //! This module includes interfaces about freshman queries.
-use crate::error::Result;
-use crate::models::freshman::{FreshmanAnalysis, FreshmanManager, NewMate, PeopleFamiliar};
-use crate::models::CommonError;
-use crate::services::{response::ApiResponse, AppState, JwtToken};
+use crate::error::{ApiError, Result};
+use crate::models::audit::{self, AuditAction};
+use crate::models::freshman::import::{parse_csv, upsert_freshmen, RowError};
+use crate::models::freshman::{
+    sample, FreshmanAnalysis, FreshmanManager, MateFilter, NewMate, PeopleFamiliar,
+};
+use crate::models::{with_db_retry, CommonError};
+use crate::services::{response::ApiResponse, AdminRequired, AppState, JwtToken};
 use actix_web::{get, post, put, web, HttpResponse};
+use futures::TryStreamExt;
+use log::warn;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize)]
@@ -11,14 +17,124 @@ pub struct FreshmanReqSecret {
     pub secret: String,
 }
 
+/// Import freshmen from a CSV file uploaded in multipart form. Valid rows are imported, and bad
+/// ones are reported by line.
+#[post("/freshman/import")]
+pub async fn import_freshmen(
+    app: web::Data<AppState>,
+    admin: AdminRequired,
+    mut payload: actix_multipart::Multipart,
+) -> Result<HttpResponse> {
+    let mut data = Vec::new();
+    // Take the first file in the form.
+    while let Some(mut field) = payload
+        .try_next()
+        .await
+        .map_err(|_| ApiError::new(CommonError::Parameter))?
+    {
+        let is_file = field
+            .content_disposition()
+            .is_some_and(|x| x.get_filename().is_some());
+        if !is_file {
+            continue;
+        }
+        while let Some(chunk) = field
+            .try_next()
+            .await
+            .map_err(|_| ApiError::new(CommonError::Parameter))?
+        {
+            data.extend_from_slice(&chunk);
+        }
+        break;
+    }
+    if data.is_empty() {
+        return Err(ApiError::new(CommonError::Parameter));
+    }
+
+    let (rows, errors) = parse_csv(&data)?;
+    upsert_freshmen(&app.pool, &rows).await?;
+    audit::record(&app.pool, admin.0.uid, AuditAction::ImportFreshmen, rows.len()).await;
+
+    #[derive(Serialize)]
+    struct ImportResponse {
+        imported: usize,
+        errors: Vec<RowError>,
+    }
+    let response = ImportResponse {
+        imported: rows.len(),
+        errors,
+    };
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(response)))
+}
+
+/// Max count of freshmen found by name.
+const MAX_SEARCH_RESULTS: i64 = 20;
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    /// Name or part of it.
+    pub q: String,
+}
+
+/// Search freshmen by name, for freshmen bound to their accounts. Only freshmen visible to others
+/// are found.
+#[get("/freshman/search")]
+pub async fn search_freshmen(
+    app: web::Data<AppState>,
+    token: JwtToken,
+    query: web::Query<SearchQuery>,
+) -> Result<HttpResponse> {
+    let keyword = query.q.trim();
+    if keyword.is_empty() {
+        return Err(CommonError::Parameter.into());
+    }
+    let manager = FreshmanManager::new(&app.pool);
+    if !manager.is_bound(token.uid).await? {
+        return Err(ApiError::new(CommonError::Forbidden));
+    }
+
+    #[derive(Serialize)]
+    struct Resp {
+        pub people: Vec<PeopleFamiliar>,
+    }
+    let people = with_db_retry(|| manager.search(keyword, token.uid, MAX_SEARCH_RESULTS)).await?;
+
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(Resp { people })))
+}
+
+#[derive(Deserialize)]
+pub struct PrivacySettings {
+    #[serde(rename = "visiblePhone")]
+    pub visible_phone: Option<bool>,
+    #[serde(rename = "visibleContact")]
+    pub visible_contact: Option<bool>,
+    #[serde(rename = "hideMe")]
+    pub hide_me: Option<bool>,
+}
+
+/// Set which details are shown to roommates, classmates and others, for the freshman bound to the
+/// user.
+#[put("/freshman/privacy")]
+pub async fn set_privacy(
+    app: web::Data<AppState>,
+    token: JwtToken,
+    form: web::Form<PrivacySettings>,
+) -> Result<HttpResponse> {
+    let form = form.into_inner();
+    let privacy = FreshmanManager::new(&app.pool)
+        .set_privacy(token.uid, form.visible_phone, form.visible_contact, form.hide_me)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(privacy)))
+}
+
 #[get("/freshman/{account}")]
 pub async fn get_basic_info(
     app: web::Data<AppState>,
-    token: Option<JwtToken>,
+    token: JwtToken,
     path: web::Path<String>,
     form: web::Query<FreshmanReqSecret>,
 ) -> Result<HttpResponse> {
-    let token = token.unwrap();
     let parameters: FreshmanReqSecret = form.into_inner();
     let account = path.into_inner();
     let secret = parameters.secret;
@@ -30,6 +146,9 @@ pub async fn get_basic_info(
     let freshman = manager.query(&account, secret.as_str()).await?;
     if freshman.uid.is_none() && !manager.is_bound(token.uid).await? {
         manager.bind(&freshman.student_id, Some(token.uid)).await?;
+        if let Err(e) = manager.notify_roommates(&freshman.student_id).await {
+            warn!("Failed to notify roommates of {}: {}", freshman.student_id, e);
+        }
     }
     Ok(HttpResponse::Ok().json(ApiResponse::normal(freshman)))
 }
@@ -44,11 +163,10 @@ pub struct UpdateInfo {
 #[put("/freshman/{account}")]
 pub async fn update_account(
     app: web::Data<AppState>,
-    token: Option<JwtToken>,
+    _: JwtToken,
     path: web::Path<String>,
     form: web::Form<UpdateInfo>,
 ) -> Result<HttpResponse> {
-    let _ = token.unwrap();
     let account = path.into_inner();
     let form = form.into_inner();
     let secret = form.secret;
@@ -73,11 +191,10 @@ pub async fn update_account(
 #[get("/freshman/{account}/roommate")]
 pub async fn get_roommate(
     app: web::Data<AppState>,
-    token: Option<JwtToken>,
+    _: JwtToken,
     path: web::Path<String>,
     secret: web::Query<FreshmanReqSecret>,
 ) -> Result<HttpResponse> {
-    let _ = token.unwrap();
     let account = path.into_inner();
     let secret = secret.into_inner().secret;
 
@@ -99,11 +216,10 @@ pub async fn get_roommate(
 #[get("/freshman/{account}/familiar")]
 pub async fn get_people_familiar(
     app: web::Data<AppState>,
-    token: Option<JwtToken>,
+    _: JwtToken,
     path: web::Path<String>,
     secret: web::Query<FreshmanReqSecret>,
 ) -> Result<HttpResponse> {
-    let _ = token.unwrap();
     let account = path.into_inner();
     let secret = secret.into_inner().secret;
 
@@ -122,16 +238,39 @@ pub async fn get_people_familiar(
     Ok(HttpResponse::Ok().json(ApiResponse::normal(Resp { people_familiar })))
 }
 
+#[derive(Deserialize)]
+pub struct ClassmateQuery {
+    pub secret: String,
+    /// Only classmates of the college, ignored if no freshman is in it.
+    pub college: Option<String>,
+    /// Only classmates of the major, ignored if no freshman is in it.
+    pub major: Option<String>,
+    /// Return a random sample of classmates by the seed, instead of all of them.
+    pub seed: Option<u64>,
+    /// Size of the sample.
+    pub limit: Option<usize>,
+}
+
+/// Default and max size of the classmate sample.
+const DEFAULT_SAMPLE_SIZE: usize = 6;
+const MAX_SAMPLE_SIZE: usize = 50;
+
 #[get("/freshman/{account}/classmate")]
 pub async fn get_classmate(
     app: web::Data<AppState>,
-    token: Option<JwtToken>,
+    _: JwtToken,
     path: web::Path<String>,
-    secret: web::Query<FreshmanReqSecret>,
+    query: web::Query<ClassmateQuery>,
 ) -> Result<HttpResponse> {
-    let _ = token.unwrap();
     let account = path.into_inner();
-    let secret = secret.into_inner().secret;
+    let ClassmateQuery {
+        secret,
+        college,
+        major,
+        seed,
+        limit,
+    } = query.into_inner();
+    let filter = MateFilter { college, major };
 
     #[derive(Serialize)]
     struct Resp {
@@ -141,8 +280,16 @@ pub async fn get_classmate(
     let classmates = freshman_manager
         .query(&account, &secret)
         .await?
-        .get_classmates(&app.pool)
+        .get_classmates(&app.pool, filter)
         .await?;
+    // Sampled after hidden classmates are dropped and contacts are redacted.
+    let classmates = match seed {
+        Some(seed) => {
+            let limit = limit.unwrap_or(DEFAULT_SAMPLE_SIZE).clamp(1, MAX_SAMPLE_SIZE);
+            sample(classmates, seed, limit)
+        }
+        None => classmates,
+    };
 
     Ok(HttpResponse::Ok().json(ApiResponse::normal(Resp { classmates })))
 }
@@ -150,11 +297,10 @@ pub async fn get_classmate(
 #[get("/freshman/{account}/analysis")]
 pub async fn get_analysis_data(
     app: web::Data<AppState>,
-    token: Option<JwtToken>,
+    _: JwtToken,
     path: web::Path<String>,
     secret: web::Query<FreshmanReqSecret>,
 ) -> Result<HttpResponse> {
-    let _ = token.unwrap();
     let account = path.into_inner();
     let secret = secret.into_inner().secret;
 
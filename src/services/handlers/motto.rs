@@ -1,17 +1,40 @@
+use crate::clock;
 use crate::error::Result;
-use crate::models::motto::Motto;
-use crate::models::motto::{MOTTO_MAX_SIZE, MOTTO_MIN_SIZE};
+use crate::models::audit::{self, AuditAction};
+use crate::models::motto::{Motto, MottoPage};
+use crate::models::motto::{MOTTO_MAX_PAGE_SIZE, MOTTO_MAX_SIZE, MOTTO_MIN_SIZE};
+use crate::models::with_db_retry;
 use crate::services::response::ApiResponse;
-use crate::services::AppState;
-use actix_web::{get, web, HttpResponse};
+use crate::services::{AdminRequired, AppState};
+use actix_web::{delete, get, post, put, web, HttpResponse};
 use serde::Deserialize;
 
+/// How to choose the motto.
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MottoMode {
+    /// Another motto on each request.
+    #[default]
+    Random,
+    /// The same motto all the day, which changes at midnight.
+    Daily,
+}
+
 #[derive(Deserialize)]
 pub struct MottoRequest {
     #[serde(rename = "minLength")]
     pub min_length: Option<u8>,
     #[serde(rename = "maxLength")]
     pub max_length: Option<u8>,
+    #[serde(default)]
+    pub mode: MottoMode,
+    /// Choose in the category only, or in all mottos if not given.
+    pub category: Option<String>,
+}
+
+/// The value if it's not blank.
+fn not_blank(value: &Option<String>) -> Option<&str> {
+    value.as_deref().map(str::trim).filter(|x| !x.is_empty())
 }
 
 #[get("/motto")]
@@ -20,12 +43,138 @@ pub async fn get_one_motto(
     form: web::Query<MottoRequest>,
 ) -> Result<HttpResponse> {
     let parameter = form.into_inner();
-    let motto = Motto::random_choice(
+    let min_length = parameter.min_length.unwrap_or(MOTTO_MIN_SIZE);
+    let max_length = parameter.max_length.unwrap_or(MOTTO_MAX_SIZE);
+    let category = not_blank(&parameter.category);
+    let motto = match parameter.mode {
+        MottoMode::Random => {
+            with_db_retry(|| Motto::random_choice(&app.pool, min_length, max_length, category)).await?
+        }
+        MottoMode::Daily => {
+            with_db_retry(|| {
+                Motto::daily_choice(&app.pool, min_length, max_length, category, clock::today())
+            })
+            .await?
+        }
+    };
+
+    Ok(HttpResponse::Ok().json(&ApiResponse::normal(motto)))
+}
+
+#[derive(Deserialize)]
+pub struct MottoListRequest {
+    /// Last motto id of the previous page.
+    pub cursor: Option<i32>,
+    pub size: Option<u16>,
+}
+
+#[get("/motto/list")]
+pub async fn list_mottos(
+    app: web::Data<AppState>,
+    form: web::Query<MottoListRequest>,
+) -> Result<HttpResponse> {
+    let parameter = form.into_inner();
+    let size = parameter
+        .size
+        .filter(|x| (1..=MOTTO_MAX_PAGE_SIZE).contains(x))
+        .unwrap_or(20);
+    let page = with_db_retry(|| MottoPage::list(&app.pool, parameter.cursor.unwrap_or(0), size)).await?;
+
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(page)))
+}
+
+#[derive(Deserialize)]
+pub struct MottoForm {
+    /// Author or its book.
+    pub source: Option<String>,
+    pub category: Option<String>,
+    pub content: String,
+}
+
+#[post("/motto")]
+pub async fn create_motto(
+    admin: AdminRequired,
+    app: web::Data<AppState>,
+    form: web::Form<MottoForm>,
+) -> Result<HttpResponse> {
+    let motto = Motto::create(
         &app.pool,
-        parameter.min_length.unwrap_or(MOTTO_MIN_SIZE),
-        parameter.max_length.unwrap_or(MOTTO_MAX_SIZE),
+        not_blank(&form.source),
+        not_blank(&form.category),
+        &form.content,
     )
     .await?;
+    audit::record(&app.pool, admin.0.uid, AuditAction::CreateMotto, motto.id).await;
 
-    Ok(HttpResponse::Ok().json(&ApiResponse::normal(motto)))
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(motto)))
+}
+
+#[put("/motto/{id}")]
+pub async fn update_motto(
+    admin: AdminRequired,
+    app: web::Data<AppState>,
+    id: web::Path<i32>,
+    form: web::Form<MottoForm>,
+) -> Result<HttpResponse> {
+    let motto = Motto::update(
+        &app.pool,
+        id.into_inner(),
+        not_blank(&form.source),
+        not_blank(&form.category),
+        &form.content,
+    )
+    .await?;
+    audit::record(&app.pool, admin.0.uid, AuditAction::UpdateMotto, motto.id).await;
+
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(motto)))
+}
+
+#[delete("/motto/{id}")]
+pub async fn delete_motto(
+    admin: AdminRequired,
+    app: web::Data<AppState>,
+    id: web::Path<i32>,
+) -> Result<HttpResponse> {
+    let motto = Motto::delete(&app.pool, id.into_inner()).await?;
+    audit::record(&app.pool, admin.0.uid, AuditAction::DeleteMotto, motto.id).await;
+
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(motto)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::services::JwtToken;
+    use actix_web::dev::Service;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App, HttpMessage};
+
+    #[test]
+    pub fn test_non_admin_rejected() {
+        actix_web::rt::System::new("test").block_on(async {
+            // Requests carry a token of ordinary user, as stashed by the auth middleware.
+            let mut app = test::init_service(
+                App::new()
+                    .wrap_fn(|req, srv| {
+                        req.extensions_mut().insert(JwtToken::with_ttl(1, false, 60));
+                        srv.call(req)
+                    })
+                    .service(create_motto)
+                    .service(update_motto)
+                    .service(delete_motto),
+            )
+            .await;
+
+            let requests = vec![
+                test::TestRequest::post().uri("/motto"),
+                test::TestRequest::put().uri("/motto/1"),
+                test::TestRequest::delete().uri("/motto/1"),
+            ];
+            for request in requests {
+                let request = request.set_form(&[("content", "知足长乐。")]).to_request();
+                let res = test::call_service(&mut app, request).await;
+                assert_eq!(res.status(), StatusCode::FORBIDDEN);
+            }
+        });
+    }
 }
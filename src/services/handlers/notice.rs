@@ -1,12 +1,30 @@
+use crate::cache::Cache;
+use crate::config::hot_config;
 use crate::error::Result;
 use crate::models::notice::Notice;
+use crate::models::with_db_retry;
 use crate::services::response::ApiResponse;
 use crate::services::AppState;
 use actix_web::{get, web, HttpResponse};
+use std::time::Duration;
+
+lazy_static! {
+    /// Last notices, served when the database is unavailable.
+    static ref NOTICE_CACHE: Cache<(), Vec<Notice>> = Cache::new(Duration::from_secs(60));
+}
 
 #[get("/notice")]
 pub async fn get_notices(app: web::Data<AppState>) -> Result<HttpResponse> {
-    let notices = Notice::get(&app.pool).await?;
+    let notices = match with_db_retry(|| Notice::get(&app.pool)).await {
+        Ok(notices) => {
+            NOTICE_CACHE.insert((), notices.clone());
+            notices
+        }
+        Err(e) if e.is_unavailable() && hot_config().serve_stale => {
+            NOTICE_CACHE.get_stale(&()).ok_or(e)?
+        }
+        Err(e) => return Err(e),
+    };
 
     Ok(HttpResponse::Ok().json(ApiResponse::normal(notices)))
 }
@@ -0,0 +1,201 @@
+//! This module includes interfaces about notifications to the current user.
+use crate::error::{ApiError, Result};
+use crate::models::notification::{self, Notification, NotificationManager};
+use crate::models::{with_db_retry, CommonError};
+use crate::services::{response::ApiResponse, AppState, JwtToken};
+use actix_web::dev::BodyEncoding;
+use actix_web::http::ContentEncoding;
+use actix_web::web::Bytes;
+use actix_web::{get, put, web, HttpRequest, HttpResponse};
+use futures::{stream, Stream, StreamExt};
+use log::warn;
+use serde::Serialize;
+use sqlx::PgPool;
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, RecvError};
+
+/**********************************************************************
+    Interfaces in this module:
+    count_unread()         <-- GET /user/me/notifications/count
+    mark_read()            <-- PUT /user/me/notifications/{id}
+    stream_notifications() <-- GET /notifications/stream
+*********************************************************************/
+
+#[get("/user/me/notifications/count")]
+pub async fn count_unread(app: web::Data<AppState>, token: Option<JwtToken>) -> Result<HttpResponse> {
+    let token = token.ok_or(ApiError::new(CommonError::LoginNeeded))?;
+    let manager = NotificationManager::new(&app.pool);
+    let count = with_db_retry(|| manager.count_unread(token.uid)).await?;
+
+    #[derive(Serialize)]
+    struct Response {
+        count: i64,
+    }
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(Response { count })))
+}
+
+#[put("/user/me/notifications/{id}")]
+pub async fn mark_read(
+    app: web::Data<AppState>,
+    token: Option<JwtToken>,
+    id: web::Path<i32>,
+) -> Result<HttpResponse> {
+    let token = token.ok_or(ApiError::new(CommonError::LoginNeeded))?;
+    NotificationManager::new(&app.pool)
+        .mark_read(token.uid, id.into_inner())
+        .await?;
+
+    Ok(HttpResponse::Ok().json(ApiResponse::empty()))
+}
+
+/// Interval of heartbeat comments, which keep proxies from closing idle streams.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+/// Max notifications replayed on reconnection or lagging.
+const MAX_REPLAY_COUNT: i64 = 100;
+
+/// Format the notification as a server-sent event, with its id as the event id.
+fn sse_event(notification: &Notification) -> Bytes {
+    let data = serde_json::to_string(notification).unwrap_or_default();
+    Bytes::from(format!(
+        "id: {}\nevent: notification\ndata: {}\n\n",
+        notification.id, data
+    ))
+}
+
+struct EventState {
+    pool: PgPool,
+    uid: i32,
+    receiver: broadcast::Receiver<Notification>,
+    /// Id of the last event sent.
+    last_id: i32,
+    /// Notifications to send before the received ones.
+    pending: VecDeque<Notification>,
+}
+
+/// Events of notifications to the user, starting with `pending` ones. Notifications missed when
+/// lagging behind the channel are loaded from the database.
+fn notification_events(
+    pool: PgPool,
+    uid: i32,
+    receiver: broadcast::Receiver<Notification>,
+    last_id: i32,
+    pending: Vec<Notification>,
+) -> impl Stream<Item = Bytes> {
+    let state = EventState {
+        pool,
+        uid,
+        receiver,
+        last_id,
+        pending: pending.into(),
+    };
+    stream::unfold(state, |mut state| async move {
+        loop {
+            if let Some(notification) = state.pending.pop_front() {
+                // Replayed ones may be received again.
+                if notification.id <= state.last_id {
+                    continue;
+                }
+                state.last_id = notification.id;
+                return Some((sse_event(&notification), state));
+            }
+            match state.receiver.recv().await {
+                Ok(notification) if notification.uid == state.uid => {
+                    state.pending.push_back(notification)
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(_)) => {
+                    let missed = NotificationManager::new(&state.pool)
+                        .list_after(state.uid, state.last_id, MAX_REPLAY_COUNT)
+                        .await;
+                    match missed {
+                        Ok(missed) => state.pending.extend(missed),
+                        Err(e) => {
+                            warn!("Failed to load missed notifications of user {}: {}", state.uid, e)
+                        }
+                    }
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+/// Stream notifications to the current user as server-sent events. Notifications after the
+/// `Last-Event-ID` are sent first on reconnection.
+#[get("/notifications/stream")]
+pub async fn stream_notifications(
+    app: web::Data<AppState>,
+    token: Option<JwtToken>,
+    req: HttpRequest,
+) -> Result<HttpResponse> {
+    let token = token.ok_or(ApiError::new(CommonError::LoginNeeded))?;
+    let last_event_id = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.trim().parse::<i32>().ok());
+
+    // Subscribe before querying, so that nothing created in between is missed.
+    let receiver = notification::subscribe();
+    let manager = NotificationManager::new(&app.pool);
+    let (last_id, pending) = match last_event_id {
+        Some(id) => (id, manager.list_after(token.uid, id, MAX_REPLAY_COUNT).await?),
+        None => (manager.latest_id(token.uid).await?, vec![]),
+    };
+
+    let events = notification_events(app.pool.clone(), token.uid, receiver, last_id, pending);
+    let heartbeats =
+        tokio::time::interval(HEARTBEAT_INTERVAL).map(|_| Bytes::from_static(b": heartbeat\n\n"));
+    let body = stream::select(events, heartbeats).map(Ok::<_, actix_web::Error>);
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header("Cache-Control", "no-cache")
+        // Compression buffers events.
+        .encoding(ContentEncoding::Identity)
+        .streaming(Box::pin(body)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::Utc;
+
+    fn notification(id: i32, uid: i32) -> Notification {
+        Notification {
+            id,
+            uid,
+            title: format!("Notification {}", id),
+            content: None,
+            is_read: false,
+            create_time: Utc::now().naive_local(),
+        }
+    }
+
+    #[test]
+    pub fn test_event_delivery() {
+        actix_web::rt::System::new("test").block_on(async {
+            // Not connected unless lagging.
+            let pool = PgPool::connect_lazy("postgres://localhost/kite").unwrap();
+            let (sender, receiver) = broadcast::channel(16);
+            // Replayed after the last event id 3.
+            let replayed = vec![notification(4, 10)];
+            let events = Box::pin(notification_events(pool, 10, receiver, 3, replayed));
+
+            assert!(sender.send(notification(4, 10)).is_ok());
+            assert!(sender.send(notification(5, 20)).is_ok());
+            assert!(sender.send(notification(6, 10)).is_ok());
+            drop(sender);
+
+            let events: Vec<String> = events
+                .map(|x| String::from_utf8(x.to_vec()).unwrap())
+                .collect()
+                .await;
+            assert_eq!(events.len(), 2);
+            assert!(events[0].starts_with("id: 4\nevent: notification\ndata: {"));
+            assert!(events[0].ends_with("}\n\n"));
+            assert!(events[1].starts_with("id: 6\n"));
+        });
+    }
+}
@@ -1,25 +1,107 @@
 //! This module includes interfaces for querying electricity bill and expenses record.
-use crate::error::Result;
-use crate::models::pay::BalanceManager;
+use crate::clock;
+use crate::error::{ApiError, Result};
+use crate::models::pay::{
+    BalanceFeed, BalanceManager, ElectricityBalance, SubscriptionManager, MAX_BATCH_ROOMS,
+};
+use crate::models::{with_db_retry, CommonError};
+use crate::services::auth::verify_token;
 use crate::services::response::ApiResponse;
-use crate::services::AppState;
-use actix_web::{get, web, HttpResponse};
-use chrono::{Duration, Local};
+use crate::services::version::{ClientVersion, Versioned};
+use crate::services::{AppState, JwtToken};
+use actix::{Actor, ActorContext, AsyncContext, StreamHandler};
+use actix_web::http::header::SEC_WEBSOCKET_PROTOCOL;
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use actix_web_actors::ws;
+use chrono::{DateTime, Duration, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::ops::Sub;
+use std::sync::Arc;
+use std::time::Instant;
 
 /**********************************************************************
     Interfaces in this module:
     query_room_balance()         <-- GET  /pay/room/{room}
     query_consumption_bill()     <-- GET  /pay/consumption/{studentId}
+    query_balance_history()      <-- GET  /pay/electricity/{room}/history
+    query_room_balances()        <-- POST /pay/electricity/batch
+    subscribe_balance()          <-- POST /pay/electricity/subscribe
+    list_balance_alerts()        <-- GET  /pay/electricity/notifications
+    watch_room_balance()         <-- GET  /ws/electricity/{room}
 *********************************************************************/
 
+/// Electricity balance for clients before 0.8.0, without power field.
+#[derive(Serialize)]
+pub struct LegacyElectricityBalance {
+    pub room: i32,
+    pub balance: f32,
+    pub ts: DateTime<Local>,
+}
+
+impl From<ElectricityBalance> for LegacyElectricityBalance {
+    fn from(balance: ElectricityBalance) -> Self {
+        Self {
+            room: balance.room,
+            balance: balance.balance,
+            ts: balance.ts,
+        }
+    }
+}
+
+impl Versioned for ElectricityBalance {
+    type Legacy = LegacyElectricityBalance;
+    const SINCE: ClientVersion = ClientVersion(0, 8, 0);
+}
+
 #[get("/pay/room/{room}")]
-pub async fn query_room_balance(app: web::Data<AppState>, form: web::Path<i32>) -> Result<HttpResponse> {
+pub async fn query_room_balance(
+    app: web::Data<AppState>,
+    version: ClientVersion,
+    form: web::Path<i32>,
+) -> Result<HttpResponse> {
     let room = form.into_inner();
-    let manager = BalanceManager::new(&app.pool);
-    let result = manager.query_last_balance(room).await?;
+    let result = with_db_retry(|| BalanceManager::new(&app.pool).query_last_balance(room)).await?;
 
-    Ok(HttpResponse::Ok().json(&ApiResponse::normal(result)))
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(result.for_version(version))))
+}
+
+#[derive(Deserialize)]
+pub struct BatchBalanceForm {
+    rooms: Vec<i32>,
+}
+
+/// Balance or error of a room in a batch.
+#[derive(Serialize)]
+struct BatchBalance {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    balance: Option<ElectricityBalance>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ApiError>,
+}
+
+#[post("/pay/electricity/batch")]
+pub async fn query_room_balances(
+    app: web::Data<AppState>,
+    form: web::Json<BatchBalanceForm>,
+) -> Result<HttpResponse> {
+    let rooms = form.into_inner().rooms;
+    if rooms.is_empty() || rooms.len() > MAX_BATCH_ROOMS {
+        return Err(ApiError::new(CommonError::Parameter));
+    }
+    let results = BalanceManager::new(&app.pool).query_last_balances(&rooms).await;
+    let response: BTreeMap<i32, BatchBalance> = results
+        .into_iter()
+        .map(|(room, result)| {
+            let (balance, error) = match result {
+                Ok(balance) => (Some(balance), None),
+                Err(e) => (None, Some(e)),
+            };
+            (room, BatchBalance { balance, error })
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(response)))
 }
 
 #[get("/pay/room/{room}/rank")]
@@ -28,8 +110,8 @@ pub async fn query_room_consumption_rank(
     form: web::Path<i32>,
 ) -> Result<HttpResponse> {
     let room = form.into_inner();
-    let manager = BalanceManager::new(&app.pool);
-    let result = manager.query_recent_consumption_rank(room).await?;
+    let result =
+        with_db_retry(|| BalanceManager::new(&app.pool).query_recent_consumption_rank(room)).await?;
 
     Ok(HttpResponse::Ok().json(&ApiResponse::normal(result)))
 }
@@ -49,19 +131,23 @@ pub async fn query_room_bills_by_day(
     let room = form.into_inner();
     let parameters = parameters.into_inner();
     let start_date = parameters.start.unwrap_or_else(|| {
-        chrono::Local::today()
+        clock::today()
             .sub(Duration::days(7))
             .format("%Y-%m-%d")
             .to_string()
     });
     let end_date = parameters
         .end
-        .unwrap_or_else(|| chrono::Local::today().format("%Y-%m-%d").to_string());
+        .unwrap_or_else(|| clock::today().format("%Y-%m-%d").to_string());
 
-    let manager = BalanceManager::new(&app.pool);
-    let result = manager
-        .query_statistics_by_day(room, start_date, end_date)
-        .await?;
+    let result = with_db_retry(|| {
+        BalanceManager::new(&app.pool).query_statistics_by_day(
+            room,
+            start_date.clone(),
+            end_date.clone(),
+        )
+    })
+    .await?;
 
     Ok(HttpResponse::Ok().json(&ApiResponse::normal(result)))
 }
@@ -72,11 +158,292 @@ pub async fn query_room_bills_by_hour(
     form: web::Path<i32>,
 ) -> Result<HttpResponse> {
     let room = form.into_inner();
-    let manager = BalanceManager::new(&app.pool);
-
     let start_time = chrono::offset::Local::now().sub(Duration::days(1));
     let end_time = chrono::Local::now();
-    let result = manager.query_balance_by_hour(room, start_time, end_time).await?;
+    let result = with_db_retry(|| {
+        BalanceManager::new(&app.pool).query_balance_by_hour(room, start_time, end_time)
+    })
+    .await?;
 
     Ok(HttpResponse::Ok().json(&ApiResponse::normal(result)))
 }
+
+/// Default days of balance history.
+const DEFAULT_HISTORY_DAYS: i32 = 7;
+/// Max days of balance history in one request.
+const MAX_HISTORY_DAYS: i32 = 90;
+
+#[derive(serde::Deserialize)]
+pub struct HistoryQuery {
+    days: Option<i32>,
+}
+
+fn history_days(days: Option<i32>) -> i32 {
+    days.unwrap_or(DEFAULT_HISTORY_DAYS).clamp(1, MAX_HISTORY_DAYS)
+}
+
+#[get("/pay/electricity/{room}/history")]
+pub async fn query_balance_history(
+    app: web::Data<AppState>,
+    form: web::Path<i32>,
+    parameters: web::Query<HistoryQuery>,
+) -> Result<HttpResponse> {
+    let room = form.into_inner();
+    let days = history_days(parameters.into_inner().days);
+    let result =
+        with_db_retry(|| BalanceManager::new(&app.pool).query_balance_history(room, days)).await?;
+
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(result)))
+}
+
+#[derive(serde::Deserialize)]
+pub struct SubscribeForm {
+    room: i32,
+    /// Alert when the balance drops below.
+    threshold: f32,
+}
+
+#[post("/pay/electricity/subscribe")]
+pub async fn subscribe_balance(
+    app: web::Data<AppState>,
+    token: Option<JwtToken>,
+    form: web::Form<SubscribeForm>,
+) -> Result<HttpResponse> {
+    let token = token.ok_or(ApiError::new(CommonError::LoginNeeded))?;
+    let form = form.into_inner();
+    if !form.threshold.is_finite() || form.threshold <= 0.0 {
+        return Err(ApiError::new(CommonError::Parameter));
+    }
+    SubscriptionManager::new(&app.pool)
+        .subscribe(token.uid, form.room, form.threshold)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(ApiResponse::empty()))
+}
+
+#[get("/pay/electricity/notifications")]
+pub async fn list_balance_alerts(
+    app: web::Data<AppState>,
+    token: Option<JwtToken>,
+) -> Result<HttpResponse> {
+    let token = token.ok_or(ApiError::new(CommonError::LoginNeeded))?;
+    let manager = SubscriptionManager::new(&app.pool);
+    let alerts = with_db_retry(|| manager.list_alerts(token.uid)).await?;
+
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(alerts)))
+}
+
+/// Interval of pings to websocket clients.
+const WS_PING_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+/// Websocket clients are disconnected if nothing is heard in this time.
+const WS_CLIENT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(90);
+
+/// Websocket session pushing balances of a room.
+struct BalanceSession {
+    room: i32,
+    feed: Arc<BalanceFeed>,
+    /// Subscription id in the feed.
+    id: Option<u64>,
+    /// Balance in JSON sent on connection.
+    initial: String,
+    last_heard: Instant,
+}
+
+impl Actor for BalanceSession {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.text(std::mem::take(&mut self.initial));
+
+        let (id, updates) = self.feed.subscribe(self.room);
+        self.id = Some(id);
+        ctx.add_stream(updates);
+        ctx.run_interval(WS_PING_INTERVAL, |session, ctx| {
+            if session.last_heard.elapsed() > WS_CLIENT_TIMEOUT {
+                ctx.stop();
+            } else {
+                ctx.ping(b"");
+            }
+        });
+    }
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        if let Some(id) = self.id.take() {
+            self.feed.unsubscribe(self.room, id);
+        }
+    }
+}
+
+/// Balance updates from the feed.
+impl StreamHandler<String> for BalanceSession {
+    fn handle(&mut self, balance: String, ctx: &mut Self::Context) {
+        ctx.text(balance);
+    }
+
+    // The session lives on when the feed has nothing more.
+    fn finished(&mut self, _: &mut Self::Context) {}
+}
+
+/// Messages from the client, which is not expected to say anything but pongs.
+impl StreamHandler<std::result::Result<ws::Message, ws::ProtocolError>> for BalanceSession {
+    fn handle(
+        &mut self,
+        message: std::result::Result<ws::Message, ws::ProtocolError>,
+        ctx: &mut Self::Context,
+    ) {
+        self.last_heard = Instant::now();
+        match message {
+            Ok(ws::Message::Ping(x)) => ctx.pong(&x),
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Err(_) => ctx.stop(),
+            _ => (),
+        }
+    }
+}
+
+/// Subprotocol of websocket clients passing the token as `Sec-WebSocket-Protocol: kite.bearer,
+/// <token>`, since browsers can't set other headers on websocket.
+const WS_BEARER_PROTOCOL: &str = "kite.bearer";
+
+/// Token passed along the bearer subprotocol, which is kept out of URLs and so access logs.
+fn protocol_token(req: &HttpRequest) -> Option<&str> {
+    let protocols: Vec<&str> = req
+        .headers()
+        .get(SEC_WEBSOCKET_PROTOCOL)?
+        .to_str()
+        .ok()?
+        .split(',')
+        .map(str::trim)
+        .collect();
+    if !protocols.contains(&WS_BEARER_PROTOCOL) {
+        return None;
+    }
+    protocols.into_iter().find(|x| *x != WS_BEARER_PROTOCOL)
+}
+
+/// Push the balance of the room over websocket, once on connection and then on every change.
+#[get("/ws/electricity/{room}")]
+pub async fn watch_room_balance(
+    app: web::Data<AppState>,
+    token: Option<JwtToken>,
+    room: web::Path<i32>,
+    req: HttpRequest,
+    stream: web::Payload,
+) -> Result<HttpResponse> {
+    let authorized = token.is_some() || protocol_token(&req).and_then(verify_token).is_some();
+    if !authorized {
+        return Err(ApiError::new(CommonError::LoginNeeded));
+    }
+    let room = room.into_inner();
+    let balance = with_db_retry(|| BalanceManager::new(&app.pool).query_last_balance(room)).await?;
+
+    let session = BalanceSession {
+        room,
+        feed: app.balances.clone(),
+        id: None,
+        initial: serde_json::to_string(&balance)?,
+        last_heard: Instant::now(),
+    };
+    ws::start_with_protocols(session, &[WS_BEARER_PROTOCOL], &req, stream)
+        .map_err(|_| ApiError::new(CommonError::Parameter))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_versioned_balance() {
+        let balance = || ElectricityBalance {
+            room: 101,
+            balance: 6.0,
+            power: 10.0,
+            ts: Local::now(),
+        };
+        let legacy = serde_json::to_value(balance().for_version(ClientVersion(0, 7, 9))).unwrap();
+        let full = serde_json::to_value(balance().for_version(ClientVersion(0, 8, 0))).unwrap();
+
+        assert!(legacy.get("power").is_none());
+        assert_eq!(legacy["balance"], 6.0);
+        assert_eq!(full["power"], 10.0);
+        // Clients without a version get the legacy shape.
+        let unknown = serde_json::to_value(balance().for_version(ClientVersion::OLDEST)).unwrap();
+        assert!(unknown.get("power").is_none());
+    }
+
+    #[test]
+    pub fn test_history_days() {
+        assert_eq!(history_days(None), DEFAULT_HISTORY_DAYS);
+        assert_eq!(history_days(Some(30)), 30);
+        assert_eq!(history_days(Some(0)), 1);
+        assert_eq!(history_days(Some(3650)), MAX_HISTORY_DAYS);
+    }
+
+    #[test]
+    pub fn test_protocol_token() {
+        let request = |protocols: &str| {
+            actix_web::test::TestRequest::get()
+                .header(SEC_WEBSOCKET_PROTOCOL, protocols)
+                .to_http_request()
+        };
+
+        assert_eq!(protocol_token(&request("kite.bearer, a.b.c")), Some("a.b.c"));
+        assert_eq!(protocol_token(&request("a.b.c,kite.bearer")), Some("a.b.c"));
+        // Not the bearer subprotocol.
+        assert_eq!(protocol_token(&request("chat, a.b.c")), None);
+        assert_eq!(protocol_token(&request("kite.bearer")), None);
+        let request = actix_web::test::TestRequest::get().to_http_request();
+        assert_eq!(protocol_token(&request), None);
+    }
+
+    #[test]
+    pub fn test_push_over_websocket() {
+        use futures::{SinkExt, StreamExt};
+
+        actix_web::rt::System::new("test").block_on(async {
+            let feed = Arc::new(BalanceFeed::new());
+            let server_feed = feed.clone();
+            let mut server = actix_web::test::start(move || {
+                let feed = server_feed.clone();
+                actix_web::App::new().route(
+                    "/ws",
+                    web::get().to(move |req: HttpRequest, stream: web::Payload| {
+                        let session = BalanceSession {
+                            room: 101,
+                            feed: feed.clone(),
+                            id: None,
+                            initial: "initial".to_string(),
+                            last_heard: Instant::now(),
+                        };
+                        futures::future::ready(ws::start(session, &req, stream))
+                    }),
+                )
+            });
+            let text = |frame: Option<std::result::Result<ws::Frame, _>>| match frame {
+                Some(Ok(ws::Frame::Text(x))) => String::from_utf8(x.to_vec()).unwrap(),
+                _ => panic!("Not a text frame"),
+            };
+
+            let mut client = server.ws_at("/ws").await.unwrap();
+            assert_eq!(text(client.next().await), "initial");
+            let balance = ElectricityBalance {
+                room: 101,
+                balance: 6.0,
+                power: 10.0,
+                ts: Local::now(),
+            };
+            assert_eq!(feed.publish(&balance), 1);
+            let pushed: serde_json::Value = serde_json::from_str(&text(client.next().await)).unwrap();
+            assert_eq!(pushed["balance"], 6.0);
+
+            // Unsubscribed once the client leaves.
+            client.send(ws::Message::Close(None)).await.unwrap();
+            while client.next().await.is_some() {}
+            actix_web::rt::time::delay_for(std::time::Duration::from_millis(50)).await;
+            assert!(feed.rooms().is_empty());
+        });
+    }
+}
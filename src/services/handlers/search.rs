@@ -1,6 +1,6 @@
 use crate::error::{ApiError, Result};
 use crate::models::search::query_notice;
-use crate::models::{CommonError, PageView};
+use crate::models::{with_db_retry, CommonError, PageView};
 use crate::services::{response::ApiResponse, AppState, JwtToken};
 use actix_web::{get, web, HttpResponse};
 
@@ -25,7 +25,7 @@ pub async fn search(
             if token.is_none() {
                 return Err(ApiError::new(CommonError::LoginNeeded));
             }
-            let result = query_notice(&app.pool, &query, &page).await?;
+            let result = with_db_retry(|| query_notice(&app.pool, &query, &page)).await?;
             Ok(HttpResponse::Ok().json(ApiResponse::normal(result)))
         }
         _ => Ok(HttpResponse::Ok().json(ApiResponse::empty())),
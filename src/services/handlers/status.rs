@@ -1,9 +1,18 @@
-use crate::error::Result;
+use crate::config::MetricsConfig;
+use crate::error::{ApiError, Result};
+use crate::metrics;
+use crate::models::CommonError;
 use crate::services::response::ApiResponse;
-use crate::services::AppState;
-use actix_web::{get, web, HttpResponse};
+use crate::services::{get_auth_bearer_value, AppState, JwtToken};
+use actix_web::http::StatusCode;
+use actix_web::{get, web, HttpRequest, HttpResponse};
 use chrono::Utc;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::time::Duration;
+
+/// Max time to wait for the database in health check.
+const HEALTH_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
 
 #[get("/status/timestamp")]
 pub async fn get_timestamp() -> Result<HttpResponse> {
@@ -48,10 +57,162 @@ pub async fn get_system_status() -> Result<HttpResponse> {
     Ok(HttpResponse::Ok().json(&ApiResponse::normal(SystemStatus { load_avg, memory })))
 }
 
+/// Check whether the database is reachable in `timeout`. Any failure means the database is not
+/// ready to serve.
+async fn probe_db(pool: &PgPool, timeout: Duration) -> Result<()> {
+    match tokio::time::timeout(timeout, sqlx::query("SELECT 1").execute(pool)).await {
+        Ok(Ok(_)) => Ok(()),
+        _ => Err(ApiError::new(CommonError::Unavailable)),
+    }
+}
+
+/// Health check for load balancers. Respond 503 if the database is unreachable.
+#[get("/health")]
+pub async fn get_health(app: web::Data<AppState>) -> Result<HttpResponse> {
+    #[derive(Serialize)]
+    struct Health {
+        pub db: &'static str,
+    }
+
+    probe_db(&app.pool, HEALTH_PROBE_TIMEOUT).await?;
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(Health { db: "ok" })))
+}
+
+#[derive(Deserialize)]
+pub struct AgentListQuery {
+    /// Query each agent for its info, instead of the cached one. Admins only, since it costs a
+    /// round trip to every agent.
+    refresh: Option<bool>,
+}
+
 #[get("/status/agent")]
-pub async fn get_agent_list(app: web::Data<AppState>) -> Result<HttpResponse> {
+pub async fn get_agent_list(
+    app: web::Data<AppState>,
+    query: web::Query<AgentListQuery>,
+    token: Option<JwtToken>,
+) -> Result<HttpResponse> {
+    let refresh = query.refresh.unwrap_or(false);
+    if refresh {
+        let token = token.ok_or(ApiError::new(CommonError::LoginNeeded))?;
+        if !token.is_admin {
+            return Err(ApiError::new(CommonError::Forbidden));
+        }
+    }
     let host = &app.host;
-    let agents = host.get_agent_list().await;
+    let agents = host.get_agent_list(refresh).await;
 
     Ok(HttpResponse::Ok().json(agents))
 }
+
+/// Compare secrets in time independent of where they differ, so that they can't be guessed by
+/// timing.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Metrics in Prometheus format, for the bearer of the configured token if any.
+#[get("/metrics")]
+pub async fn get_metrics(
+    app: web::Data<AppState>,
+    config: web::Data<MetricsConfig>,
+    req: HttpRequest,
+) -> Result<HttpResponse> {
+    if let Some(token) = &config.token {
+        let bearer = req.headers().get("Authorization").and_then(get_auth_bearer_value);
+        let authorized = bearer.map(|x| constant_time_eq(x.as_bytes(), token.as_bytes()));
+        if authorized != Some(true) {
+            return Ok(ApiError::new(CommonError::Forbidden).response(StatusCode::FORBIDDEN));
+        }
+    }
+
+    metrics::DB_POOL_SIZE.set(app.pool.size() as i64);
+    metrics::DB_POOL_IDLE.set(app.pool.num_idle() as i64);
+    metrics::AGENTS_CONNECTED.set(app.host.agent_count().await as i64);
+    let (in_use, waiting) = app.host.utilization();
+    metrics::AGENT_PERMITS_IN_USE.set(in_use as i64);
+    metrics::AGENT_REQUESTS_WAITING.set(waiting as i64);
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::gather()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bridge::{AgentManager, RequestLimiter};
+    use crate::models::edu::ScoreCache;
+    use crate::models::event::EventCache;
+    use crate::models::quota::QuotaStore;
+    use actix_web::{test, App};
+    use sqlx::postgres::PgPoolOptions;
+
+    #[test]
+    pub fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"token", b"token"));
+        assert!(!constant_time_eq(b"token", b"tokem"));
+        assert!(!constant_time_eq(b"token", b"token2"));
+        assert!(!constant_time_eq(b"", b"token"));
+    }
+
+    #[test]
+    pub fn test_probe_unreachable_db() {
+        actix_web::rt::System::new("test").block_on(async move {
+            // Nothing listens on the port.
+            let pool = PgPoolOptions::new()
+                .connect_timeout(Duration::from_secs(10))
+                .connect_lazy("postgres://postgres@127.0.0.1:1/postgres")
+                .unwrap();
+
+            let e = probe_db(&pool, Duration::from_millis(500)).await.unwrap_err();
+            assert!(e.is_unavailable());
+        });
+    }
+
+    #[test]
+    pub fn test_metrics_endpoint() {
+        actix_web::rt::System::new("test").block_on(async move {
+            let host = AgentManager::new(
+                Duration::from_secs(60),
+                RequestLimiter::new(16, 16, Duration::from_secs(1)),
+            );
+            let state = AppState {
+                pool: PgPoolOptions::new()
+                    .connect_lazy("postgres://postgres@127.0.0.1:1/postgres")
+                    .unwrap(),
+                host: host.clone(),
+                agents: std::sync::Arc::new(host),
+                quota: QuotaStore::memory(),
+                scores: ScoreCache::new(Duration::from_secs(60), crate::cache::MAX_HOT_KEYS),
+                events: EventCache::new(Duration::from_secs(60), crate::cache::MAX_HOT_KEYS),
+                balances: Default::default(),
+            };
+            let config = MetricsConfig {
+                bind: None,
+                token: Some("secret".to_string()),
+            };
+            let mut app =
+                test::init_service(App::new().data(state).data(config).service(get_metrics)).await;
+
+            let req = test::TestRequest::get().uri("/metrics").to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+            let req = test::TestRequest::get()
+                .uri("/metrics")
+                .header("Authorization", "Bearer secret")
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let body = test::read_body(res).await;
+            let text = std::str::from_utf8(&body).unwrap();
+            // Each sample line is a name with optional labels, and a number.
+            for line in text.lines().filter(|x| !x.starts_with('#')) {
+                let value = line.rsplit(' ').next().unwrap();
+                assert!(value.parse::<f64>().is_ok(), "Bad sample line: {}", line);
+            }
+            assert!(text.contains("kite_db_pool_idle_connections 0"));
+            assert!(text.contains("kite_agents_connected 0"));
+        });
+    }
+}
@@ -1,13 +1,41 @@
+use crate::clock;
+use crate::config::CONFIG;
 use crate::error::{ApiError, Result};
-use crate::jwt::encode_jwt;
+use crate::jwt::{decode_jwt_with_leeway, encode_jwt};
+use crate::models::audit::{self, AuditAction};
 use crate::models::file::AvatarManager;
+use crate::models::freshman::FreshmanManager;
+use crate::models::notification::NotificationManager;
+use crate::models::quota::QuotaStore;
+use crate::models::user::batch::{self, NewUser};
+use crate::models::user::captcha::CAPTCHAS;
+use crate::models::user::deletion;
+use crate::models::user::export::{csv_header, csv_row, ExportColumn, DEFAULT_COLUMNS};
+use crate::models::user::lockout::{account_key_prefix, Lockout};
+use crate::models::user::login_audit;
+use crate::models::user::revocation::{is_revoked, is_user_revoked, revoke_token, revoke_user_tokens};
+use crate::models::user::totp::TotpManager;
+use crate::models::user::validation::{Candidate, Check};
+use crate::models::user::verification::{Contact, VerificationManager, CODE_SENDER};
 use crate::models::user::wechat::{get_session_by_code, WxSession};
-use crate::models::user::{get_default_avatar, Authentication, Identity, Person, UserError};
+use crate::models::user::{
+    get_default_avatar, is_card_number, is_student_id, Authentication, Identity, MaskedIdentity, Person,
+    UserError, ADMIN_BOOTSTRAP,
+};
 use crate::models::user::{LOGIN_BY_CAMPUS_WEB, LOGIN_BY_PASSWORD, LOGIN_BY_WECHAT};
-use crate::models::CommonError;
-use crate::services::{response::ApiResponse, AppState, JwtToken};
-use actix_web::{get, post, put, web, HttpResponse};
+use crate::models::{with_db_retry, CommonError, PageView};
+use crate::services::response::{ApiResponse, PagedResponse};
+use crate::services::{
+    client_ip, get_auth_bearer_value, idempotency, AdminRequired, AppState, JwtToken,
+};
+use actix_web::web::Bytes;
+use actix_web::{delete, get, post, put, web, HttpRequest, HttpResponse};
+use chrono::Utc;
+use futures::future::ok;
+use futures::{stream, StreamExt};
+use log::warn;
 use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
 
 #[derive(Debug, Deserialize)]
 pub struct AuthParameters {
@@ -21,11 +49,44 @@ pub struct AuthParameters {
     account: Option<String>,
     // Used in _LOGIN_BY_PASSWORD, password
     credential: Option<String>,
+    // Two-factor code, required by administrators who enabled it
+    #[serde(rename = "otpCode")]
+    otp_code: Option<String>,
 }
 
 #[post("/session")]
-pub async fn login(app: web::Data<AppState>, form: web::Form<AuthParameters>) -> Result<HttpResponse> {
+pub async fn login(
+    app: web::Data<AppState>,
+    req: HttpRequest,
+    form: web::Form<AuthParameters>,
+) -> Result<HttpResponse> {
     let parameters: AuthParameters = form.into_inner();
+    let login_type = parameters.login_type;
+    let account = parameters.account.clone();
+
+    let result = authenticate(&app.pool, &app.quota, parameters)
+        .await
+        .and_then(|user| {
+            let uid = user.uid;
+            login_response(user).map(|response| (uid, response))
+        });
+    let outcome = result.as_ref().map(|(uid, _)| *uid);
+    login_audit::record(
+        &app.pool,
+        &CONFIG.server.derive_key(login_audit::ACCOUNT_KEY_PURPOSE),
+        account.as_deref(),
+        &client_ip(&req),
+        login_type,
+        outcome,
+    )
+    .await;
+    result.map(|(_, response)| response)
+}
+
+/// Check the credential and the second factor, and return the user.
+async fn authenticate(pool: &PgPool, quota: &QuotaStore, parameters: AuthParameters) -> Result<Person> {
+    let otp_code = parameters.otp_code.clone();
+    let lockout = Lockout::new(quota, &CONFIG.lockout);
     let user: Person;
 
     match parameters {
@@ -36,8 +97,12 @@ pub async fn login(app: web::Data<AppState>, form: web::Form<AuthParameters>) ->
             credential: Some(password),
             ..
         } => {
-            let auth: Authentication = Authentication::from_password(username, password);
-            user = auth.password_login(&app.pool).await?;
+            let prefix = account_key_prefix(pool, &username).await?;
+            lockout.check(&prefix, Utc::now()).await?;
+            let auth: Authentication = Authentication::from_password(username.clone(), password);
+            let result = auth.password_login(pool).await;
+            let known = result.is_ok() || auth.exists(pool).await?;
+            user = lockout.track(&prefix, known, Utc::now(), result).await?;
         }
         // Login by wechat.
         AuthParameters {
@@ -47,7 +112,7 @@ pub async fn login(app: web::Data<AppState>, form: web::Form<AuthParameters>) ->
         } => {
             let wechat_token: WxSession = get_session_by_code(wechat_code.as_str()).await?;
             let auth: Authentication = Authentication::from_wechat(&wechat_token.openid);
-            user = auth.wechat_login(&app.pool).await?;
+            user = auth.wechat_login(pool).await?;
         }
         // Login by campus web (student id and password).
         AuthParameters {
@@ -56,65 +121,250 @@ pub async fn login(app: web::Data<AppState>, form: web::Form<AuthParameters>) ->
             credential: Some(password),
             ..
         } => {
-            let mut auth = Authentication::from_campus_auth(account.clone(), password.clone());
-            let result = auth.campus_login(&app.pool).await;
-
-            match result {
-                Ok(u) => user = u,
-                Err(e) => {
-                    Identity::validate_oa_account(&account, &password).await?;
-                    // If password failed, verify on auth-server to update.
-                    if e == ApiError::new(UserError::LoginFailed) {
-                        auth.campus_update(&app.pool).await?;
-                        user = auth.campus_login(&app.pool).await?;
-                    } else {
-                        return Err(e);
-                    }
-                }
+            // Students may log in with the campus card number instead, which is looked up only if
+            // no account is stored as is, so that other accounts of digits still log in.
+            let auth = Authentication::from_campus_auth(account.clone(), password.clone());
+            let account = if is_card_number(&CONFIG.student_id, &account) && !auth.exists(pool).await? {
+                Identity::resolve_student_id(pool, &account).await?
+            } else {
+                account
             };
+            // Checked before the auth server, so that a correct secret is refused during the lock.
+            let prefix = account_key_prefix(pool, &account).await?;
+            lockout.check(&prefix, Utc::now()).await?;
+            let result = campus_login(pool, &account, &password).await;
+            let known = is_student_id(&CONFIG.student_id, &account);
+            user = lockout.track(&prefix, known, Utc::now(), result).await?;
         }
         _ => {
             return Err(ApiError::new(CommonError::Parameter));
         }
     }
+    check_second_factor(pool, &user, otp_code.as_deref()).await?;
+    Ok(user)
+}
+
+/// Log in by student id and the OA secret, which is verified on the auth server if it doesn't
+/// match the one stored.
+async fn campus_login(pool: &PgPool, account: &str, password: &str) -> Result<Person> {
+    let mut auth = Authentication::from_campus_auth(account.to_string(), password.to_string());
+
+    match auth.campus_login(pool).await {
+        Ok(user) => Ok(user),
+        Err(e) => {
+            Identity::validate_oa_account_fresh(pool, account, &password.to_string()).await?;
+            // If password failed, verify on auth-server to update.
+            if e == ApiError::new(UserError::LoginFailed) {
+                auth.campus_update(pool).await?;
+                auth.campus_login(pool).await
+            } else {
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Require a current code of administrators who enabled two-factor authentication.
+async fn check_second_factor(pool: &PgPool, user: &Person, code: Option<&str>) -> Result<()> {
+    let totp = TotpManager::new(pool);
+
+    if !user.is_admin || !totp.is_enabled(user.uid).await? {
+        return Ok(());
+    }
+    let code = code.ok_or_else(|| ApiError::new(UserError::TotpNeeded))?;
+    totp.verify(user.uid, code, CONFIG.totp.skew).await
+}
+
+#[derive(Serialize)]
+struct LoginResponse {
+    token: String,
+    data: Person,
+}
+
+/// Issue a token for the user logged in.
+fn login_response(user: Person) -> Result<HttpResponse> {
+    if user.is_disabled {
+        return Err(ApiError::new(UserError::Disabled));
+    }
+    let token = encode_jwt(&JwtToken::new(user.uid, user.is_admin))?;
+    let resp = LoginResponse { token, data: user };
+    Ok(HttpResponse::Ok().json(&ApiResponse::normal(resp)))
+}
+
+#[derive(Deserialize)]
+pub struct WechatLogin {
+    /// The code provided by wechat `wx.login()`.
+    code: String,
+    /// Two-factor code, required by administrators who enabled it.
+    #[serde(rename = "otpCode")]
+    otp_code: Option<String>,
+}
+
+/// Login by wechat mini-program. A new user is created for an unknown wechat user.
+#[post("/session/wechat")]
+pub async fn wechat_login(
+    app: web::Data<AppState>,
+    form: web::Form<WechatLogin>,
+) -> Result<HttpResponse> {
+    let session = get_session_by_code(&form.code).await?;
+    let user = Person::find_or_create_by_wechat(&app.pool, &session.openid).await?;
+
+    check_second_factor(&app.pool, &user, form.otp_code.as_deref()).await?;
+    login_response(user)
+}
+
+/// Generate a two-factor secret for the administrator. It's enabled after confirmed by a code.
+#[post("/user/2fa/enroll")]
+pub async fn enroll_totp(app: web::Data<AppState>, admin: AdminRequired) -> Result<HttpResponse> {
+    let uri = TotpManager::new(&app.pool)
+        .enroll(admin.0.uid, &CONFIG.totp.issuer)
+        .await?;
+
+    #[derive(Serialize)]
+    struct EnrollResponse {
+        uri: String,
+    }
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(EnrollResponse { uri })))
+}
+
+#[derive(Deserialize)]
+pub struct TotpCode {
+    /// Code from the authenticator app.
+    code: String,
+}
+
+/// Confirm the two-factor enrollment, after which codes are required on login.
+#[post("/user/2fa/verify")]
+pub async fn confirm_totp(
+    app: web::Data<AppState>,
+    admin: AdminRequired,
+    form: web::Form<TotpCode>,
+) -> Result<HttpResponse> {
+    TotpManager::new(&app.pool)
+        .confirm(admin.0.uid, &form.code, CONFIG.totp.skew)
+        .await?;
+    audit::record(&app.pool, admin.0.uid, AuditAction::EnableTotp, admin.0.uid).await;
+
+    Ok(HttpResponse::Ok().json(ApiResponse::empty()))
+}
+
+/// Issue a new token by a valid one, or one expired within `CONFIG.server.refresh_grace` seconds.
+#[post("/session/refresh")]
+pub async fn refresh_token(app: web::Data<AppState>, req: HttpRequest) -> Result<HttpResponse> {
+    let token = req
+        .headers()
+        .get("Authorization")
+        .and_then(get_auth_bearer_value)
+        .and_then(|x| decode_jwt_with_leeway::<JwtToken>(x, CONFIG.server.refresh_grace))
+        .filter(|x| !is_revoked(&x.jti) && !is_user_revoked(x.uid, x.issued_at()))
+        .ok_or(ApiError::new(CommonError::LoginNeeded))?;
+
+    // The user may be disabled, or the role may change since the token issued.
+    let user = Person::get(&app.pool, token.uid).await?;
     if user.is_disabled {
         return Err(ApiError::new(UserError::Disabled));
     }
 
     #[derive(Serialize)]
-    struct LoginResponse {
+    struct RefreshResponse {
         token: String,
-        data: Person,
     }
-    let token = encode_jwt(&JwtToken {
-        uid: user.uid,
-        is_admin: user.is_admin,
-    })?;
-    let resp = LoginResponse { token, data: user };
-    Ok(HttpResponse::Ok().json(&ApiResponse::normal(resp)))
+    let token = encode_jwt(&JwtToken::new(user.uid, user.is_admin))?;
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(RefreshResponse { token })))
+}
+
+/// Revoke the current token.
+#[post("/session/logout")]
+pub async fn logout(app: web::Data<AppState>, token: Option<JwtToken>) -> Result<HttpResponse> {
+    let token = token.ok_or(ApiError::new(CommonError::LoginNeeded))?;
+
+    revoke_token(&app.pool, token.jti, token.exp).await?;
+    Ok(HttpResponse::Ok().json(ApiResponse::empty()))
 }
 
 #[derive(Deserialize)]
 pub struct ListUsers {
     #[serde(rename = "pageSize")]
-    pub page_size: Option<u32>,
-    pub index: Option<u32>,
+    pub page_size: Option<u16>,
+    pub index: Option<u16>,
 }
 
+/// Max users per page in the user list.
+const MAX_USER_PAGE_SIZE: u16 = 100;
+
 #[get("/user")]
-pub async fn list_users(app: web::Data<AppState>, form: web::Query<ListUsers>) -> Result<HttpResponse> {
+pub async fn list_users(
+    app: web::Data<AppState>,
+    _: AdminRequired,
+    form: web::Query<ListUsers>,
+) -> Result<HttpResponse> {
     let parameter = form.into_inner();
-    let userlist = Person::list(
-        &app.pool,
-        parameter.index.unwrap_or(1),
-        parameter.page_size.unwrap_or(20),
-    )
-    .await?;
+    let page = PageView {
+        index: parameter.index,
+        count: parameter.page_size,
+    };
+    let users = with_db_retry(|| Person::list(&app.pool, &page, MAX_USER_PAGE_SIZE)).await?;
+    let total = with_db_retry(|| Person::count(&app.pool)).await?;
 
-    Ok(HttpResponse::Ok().json(&ApiResponse::normal(userlist)))
+    let response = PagedResponse::new(users, &page, MAX_USER_PAGE_SIZE, total);
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(response)))
 }
 
 #[derive(Deserialize)]
+pub struct ExportUsers {
+    /// Only `csv` is supported.
+    pub format: Option<String>,
+    /// Comma separated column names, like `uid,nickName`.
+    pub columns: Option<String>,
+}
+
+/// Users read from the database at a time when exporting.
+const EXPORT_CHUNK_SIZE: i64 = 500;
+
+/// Export users as a CSV file. Users are read and sent in chunks.
+#[get("/user/export")]
+pub async fn export_users(
+    app: web::Data<AppState>,
+    _: AdminRequired,
+    query: web::Query<ExportUsers>,
+) -> Result<HttpResponse> {
+    let query = query.into_inner();
+
+    if query.format.as_deref().unwrap_or("csv") != "csv" {
+        return Err(ApiError::new(CommonError::Parameter));
+    }
+    let columns = match &query.columns {
+        Some(names) => ExportColumn::parse_list(names)?,
+        None => DEFAULT_COLUMNS.to_vec(),
+    };
+
+    let header = Bytes::from(csv_header(&columns));
+    // The last uid sent, or `None` when all are sent.
+    let rows = stream::unfold(Some(0), move |last| {
+        let pool = app.pool.clone();
+        let columns = columns.clone();
+        async move {
+            let users = match Person::list_after(&pool, last?, EXPORT_CHUNK_SIZE).await {
+                Ok(users) => users,
+                Err(e) => return Some((Err(actix_web::Error::from(e)), None)),
+            };
+            let next = match users.len() as i64 {
+                n if n < EXPORT_CHUNK_SIZE => None,
+                _ => users.last().map(|x| x.uid),
+            };
+            let chunk: String = users.iter().map(|x| csv_row(x, &columns)).collect();
+            Some((Ok(Bytes::from(chunk)), next))
+        }
+    });
+    let body = stream::once(ok::<_, actix_web::Error>(header)).chain(Box::pin(rows));
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/csv; charset=utf-8")
+        .header("Content-Disposition", "attachment; filename=\"users.csv\"")
+        .streaming(body))
+}
+
+#[derive(Deserialize, Serialize)]
 pub struct SubmittedPerson {
     /// Nickname. For users uses wechat to register, use wehcat name by default.
     #[serde(rename = "nickName")]
@@ -129,57 +379,199 @@ pub struct SubmittedPerson {
     pub city: Option<String>,
     /// Language code, like zh-cn
     pub language: Option<String>,
+    /// Phone or email verified on registration.
+    pub contact: Option<String>,
+    /// Code sent to the contact.
+    #[serde(rename = "verifyCode")]
+    pub verify_code: Option<String>,
+    /// Captcha issued by `/captcha`, required on registration.
+    #[serde(rename = "captchaId")]
+    pub captcha_id: Option<String>,
+    #[serde(rename = "captchaAnswer")]
+    pub captcha_answer: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct VerifyCodeRequest {
+    /// Phone or email.
+    contact: String,
+}
+
+/// Send a verification code to the phone or email, which is required on registration.
+#[post("/user/verify/send")]
+pub async fn send_verify_code(
+    app: web::Data<AppState>,
+    form: web::Form<VerifyCodeRequest>,
+) -> Result<HttpResponse> {
+    let contact = Contact::parse(&form.contact)?;
+
+    let key = format!("verify:{}", contact.as_str());
+    let window = clock::now_local().format("%Y-%m-%d %H").to_string();
+    if !app
+        .quota
+        .hit(&key, &window, CONFIG.verification.max_sends_per_hour)
+        .await?
+    {
+        return Err(ApiError::new(CommonError::TooManyRequests));
+    }
+    let code = VerificationManager::new(&app.pool)
+        .issue(&contact, CONFIG.verification.code_ttl)
+        .await?;
+    CODE_SENDER.send(&contact, &code).await?;
+
+    Ok(HttpResponse::Ok().json(ApiResponse::empty()))
+}
+
+/// Issue a captcha, which is required on registration.
+#[get("/captcha")]
+pub async fn get_captcha() -> Result<HttpResponse> {
+    let captcha = CAPTCHAS.issue();
+
+    #[derive(Serialize)]
+    struct CaptchaResponse {
+        id: String,
+        /// Image in data URL.
+        image: String,
+    }
+
+    let resp = CaptchaResponse {
+        id: captcha.id,
+        image: format!("data:image/png;base64,{}", base64::encode(&captcha.image)),
+    };
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(resp)))
 }
 
 #[post("/user")]
 pub async fn create_user(
     app: web::Data<AppState>,
     form: web::Form<SubmittedPerson>,
+    req: HttpRequest,
 ) -> Result<HttpResponse> {
     let parameters: SubmittedPerson = form.into_inner();
-    let mut user: Person = Person::new();
+    // Retries of the same registration create one user. The key is bound to the form, which has
+    // the verification code, so that others can't get the user by the key.
+    let scope = idempotency::ip_scope("POST /user", &req);
+    let fingerprint = serde_json::to_vec(&parameters)?;
 
-    if parameters.nick_name.is_none() {
-        return Err(ApiError::new(CommonError::Parameter));
+    let pool = app.pool.clone();
+
+    #[derive(Serialize)]
+    struct CreateResponse {
+        uid: i32,
+        token: String,
     }
-    user.nick_name = parameters.nick_name.unwrap();
-    user.country = parameters.country;
-    user.province = parameters.province;
-    user.city = parameters.city;
-    user.language = parameters.language;
+    // The token is issued for each response, and never stored with the key.
+    let respond = |uid: i32| {
+        let token = encode_jwt(&JwtToken::new(uid, false))?;
+        Ok(HttpResponse::Ok().json(ApiResponse::normal(CreateResponse { uid, token })))
+    };
 
-    if let Some(avatar_url) = parameters.avatar {
-        let avatar_storage = AvatarManager::new(&app.pool);
-        let stored_url = avatar_storage.save(0, &avatar_url).await?.url;
+    let ttl = idempotency::KeyTtl::configured();
+    idempotency::run_once(
+        &pool,
+        &req,
+        &scope,
+        &fingerprint,
+        ttl,
+        || async move {
+            let mut user: Person = Person::new();
 
-        user.avatar = stored_url.unwrap_or(get_default_avatar().to_string());
+            let (contact, verify_code) = match (&parameters.contact, &parameters.verify_code) {
+                (Some(contact), Some(code)) if parameters.nick_name.is_some() => (contact, code),
+                _ => return Err(ApiError::new(CommonError::Parameter)),
+            };
+            // Checked before the verification code, so that codes can't be guessed by scripts.
+            if CONFIG.captcha.enabled {
+                match (&parameters.captcha_id, &parameters.captcha_answer) {
+                    (Some(id), Some(answer)) => CAPTCHAS.verify(id, answer)?,
+                    _ => return Err(ApiError::new(CommonError::Parameter)),
+                }
+            }
+            let contact = Contact::parse(contact)?;
+            VerificationManager::new(&app.pool)
+                .verify(&contact, verify_code)
+                .await?;
+
+            user.nick_name = parameters.nick_name.unwrap();
+            user.country = parameters.country;
+            user.province = parameters.province;
+            user.city = parameters.city;
+            user.language = parameters.language;
+
+            if let Some(avatar_url) = parameters.avatar {
+                let avatar_storage = AvatarManager::new(&app.pool);
+                let stored_url = avatar_storage.save(0, &avatar_url).await?.url;
+
+                user.avatar = stored_url.unwrap_or(get_default_avatar().to_string());
+            }
+            user.register(&app.pool).await?;
+            // Users register themselves before signing in.
+            audit::record(&app.pool, audit::ANONYMOUS, AuditAction::CreateUser, user.uid).await;
+
+            Ok(user.uid)
+        },
+        respond,
+    )
+    .await
+}
+
+#[derive(Deserialize)]
+pub struct ValidateQuery {
+    /// Verify the OA secret on the auth server too.
+    #[serde(rename = "checkOa", default)]
+    pub check_oa: bool,
+}
+
+/// Check whether a user can be created with the fields given, without creating it. Visitors get
+/// format checks only, since whether an account is taken or an OA secret is correct is private.
+#[post("/user/validate")]
+pub async fn validate_user(
+    app: web::Data<AppState>,
+    token: Option<JwtToken>,
+    query: web::Query<ValidateQuery>,
+    form: web::Form<Candidate>,
+) -> Result<HttpResponse> {
+    let is_admin = token.map(|x| x.is_admin) == Some(true);
+    if query.check_oa && !is_admin {
+        return Err(ApiError::new(CommonError::Forbidden));
+    }
+    let checks = match is_admin {
+        true => {
+            form.check(
+                &app.pool,
+                &Lockout::new(&app.quota, &CONFIG.lockout),
+                &CONFIG.student_id,
+                query.check_oa,
+            )
+            .await?
+        }
+        false => form.check_formats(&CONFIG.student_id),
+    };
+    if checks.is_empty() {
+        return Err(ApiError::new(CommonError::Parameter));
     }
-    user.register(&app.pool).await?;
 
     #[derive(Serialize)]
-    struct CreateResponse {
-        uid: i32,
-        token: String,
+    struct ValidateResponse {
+        valid: bool,
+        checks: Vec<Check>,
     }
 
-    let resp = CreateResponse {
-        uid: user.uid,
-        token: encode_jwt(&JwtToken {
-            uid: user.uid,
-            is_admin: user.is_admin,
-        })?,
+    let resp = ValidateResponse {
+        valid: checks.iter().all(|x| x.passed),
+        checks,
     };
-    Ok(HttpResponse::Ok().body(ApiResponse::normal(resp).to_string()))
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(resp)))
 }
 
 #[put("/user/{uid}")]
 pub async fn update_user_detail(
-    token: Option<JwtToken>,
+    token: JwtToken,
     app: web::Data<AppState>,
     form: web::Form<SubmittedPerson>,
     uid: web::Path<i32>,
+    req: HttpRequest,
 ) -> Result<HttpResponse> {
-    let token = token.unwrap();
     let uid = uid.into_inner();
 
     if !token.is_admin && uid != token.uid {
@@ -211,20 +603,222 @@ pub async fn update_user_detail(
         };
         person.avatar = final_url.unwrap_or(get_default_avatar().to_string());
     }
-    person.update(&app.pool).await?;
+    let version = person
+        .update(&app.pool, expected_version(&req).as_deref())
+        .await?;
+
+    Ok(HttpResponse::Ok()
+        .header("ETag", entity_tag(&version))
+        .json(ApiResponse::normal(person)))
+}
+
+#[derive(Deserialize)]
+pub struct BatchQuery {
+    /// Create nothing if any user fails.
+    #[serde(rename = "allOrNothing", default)]
+    pub all_or_nothing: bool,
+}
+
+/// Max users created in a batch.
+const MAX_USER_BATCH: usize = 500;
+
+/// Create users in batch. The uid or error of each user is returned in order, and uids are left
+/// out if the batch is rolled back in all-or-nothing mode.
+#[post("/user/batch")]
+pub async fn create_users(
+    app: web::Data<AppState>,
+    admin: AdminRequired,
+    query: web::Query<BatchQuery>,
+    users: web::Json<Vec<NewUser>>,
+) -> Result<HttpResponse> {
+    let users = users.into_inner();
+    if users.is_empty() || users.len() > MAX_USER_BATCH {
+        return Err(ApiError::new(CommonError::Parameter));
+    }
+    let results = batch::create_users(&app.pool, &users, query.all_or_nothing).await?;
+
+    #[derive(Serialize)]
+    struct BatchResult {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        uid: Option<i32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<ApiError>,
+    }
+    #[derive(Serialize)]
+    struct BatchResponse {
+        committed: bool,
+        results: Vec<BatchResult>,
+    }
+    let committed = !query.all_or_nothing || results.iter().all(Result::is_ok);
+    let mut response = BatchResponse {
+        committed,
+        results: Vec::with_capacity(results.len()),
+    };
+    for result in results {
+        let (uid, error) = match result {
+            Ok(uid) if committed => {
+                audit::record(&app.pool, admin.0.uid, AuditAction::CreateUser, uid).await;
+                (Some(uid), None)
+            }
+            Ok(_) => (None, None),
+            Err(e) => (None, Some(e)),
+        };
+        response.results.push(BatchResult { uid, error });
+    }
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(response)))
+}
+
+#[derive(Deserialize)]
+pub struct RoleForm {
+    #[serde(rename = "isAdmin")]
+    pub is_admin: bool,
+}
+
+/// Grant or revoke administrator of the user. Tokens issued to the user before are revoked, so
+/// that the user logs in again with the new role.
+#[put("/user/{uid}/role")]
+pub async fn set_user_role(
+    app: web::Data<AppState>,
+    admin: AdminRequired,
+    uid: web::Path<i32>,
+    form: web::Form<RoleForm>,
+) -> Result<HttpResponse> {
+    let uid = uid.into_inner();
+    let is_admin = form.into_inner().is_admin;
+
+    Person::set_admin(&app.pool, uid, is_admin).await?;
+    revoke_user_tokens(&app.pool, uid).await?;
+    let action = if is_admin {
+        AuditAction::GrantAdmin
+    } else {
+        AuditAction::RevokeAdmin
+    };
+    audit::record(&app.pool, admin.0.uid, action, uid).await;
+
+    Ok(HttpResponse::Ok().json(ApiResponse::empty()))
+}
+
+#[derive(Deserialize)]
+pub struct StatusForm {
+    #[serde(rename = "isDisabled")]
+    pub is_disabled: bool,
+}
+
+/// Disable or enable the user. A disabled user can't log in, and tokens issued before are revoked.
+#[put("/user/{uid}/status")]
+pub async fn set_user_status(
+    app: web::Data<AppState>,
+    admin: AdminRequired,
+    uid: web::Path<i32>,
+    form: web::Form<StatusForm>,
+    req: HttpRequest,
+) -> Result<HttpResponse> {
+    let uid = uid.into_inner();
+    let is_disabled = form.into_inner().is_disabled;
+
+    let version =
+        Person::set_disabled(&app.pool, uid, is_disabled, expected_version(&req).as_deref()).await?;
+    let action = if is_disabled {
+        revoke_user_tokens(&app.pool, uid).await?;
+        AuditAction::DisableUser
+    } else {
+        AuditAction::EnableUser
+    };
+    audit::record(&app.pool, admin.0.uid, action, uid).await;
+
+    Ok(HttpResponse::Ok()
+        .header("ETag", entity_tag(&version))
+        .json(ApiResponse::empty()))
+}
+
+/// Soft-delete the user. The user row and data are kept, and can be restored.
+#[delete("/user/{uid}")]
+pub async fn delete_user(
+    app: web::Data<AppState>,
+    admin: AdminRequired,
+    uid: web::Path<i32>,
+) -> Result<HttpResponse> {
+    let uid = uid.into_inner();
+
+    deletion::delete_user(&app.pool, uid).await?;
+    audit::record(&app.pool, admin.0.uid, AuditAction::DeleteUser, uid).await;
+    Ok(HttpResponse::Ok().json(ApiResponse::empty()))
+}
+
+/// Restore a soft-deleted user.
+#[post("/user/{uid}/restore")]
+pub async fn restore_user(
+    app: web::Data<AppState>,
+    admin: AdminRequired,
+    uid: web::Path<i32>,
+) -> Result<HttpResponse> {
+    let uid = uid.into_inner();
+
+    deletion::restore_user(&app.pool, uid).await?;
+    audit::record(&app.pool, admin.0.uid, AuditAction::RestoreUser, uid).await;
+    Ok(HttpResponse::Ok().json(ApiResponse::empty()))
+}
+
+#[derive(Deserialize)]
+pub struct UnlockForm {
+    /// Student id or username locked after failed logins.
+    account: String,
+}
+
+/// Unlock an account locked after failed logins, before the cooldown elapses.
+#[post("/user/unlock")]
+pub async fn unlock_account(
+    app: web::Data<AppState>,
+    admin: AdminRequired,
+    form: web::Form<UnlockForm>,
+) -> Result<HttpResponse> {
+    let account = form.account.trim();
+    let prefix = account_key_prefix(&app.pool, account).await?;
+    let was_locked = Lockout::new(&app.quota, &CONFIG.lockout)
+        .unlock(&prefix, Utc::now())
+        .await?;
+    audit::record(&app.pool, admin.0.uid, AuditAction::UnlockAccount, account).await;
+
+    #[derive(Serialize)]
+    struct UnlockResponse {
+        #[serde(rename = "wasLocked")]
+        was_locked: bool,
+    }
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(UnlockResponse { was_locked })))
+}
+
+/// Quote the row version as an entity tag.
+fn entity_tag(version: &str) -> String {
+    format!("\"{}\"", version)
+}
 
-    Ok(HttpResponse::Ok().json(ApiResponse::normal(person)))
+/// Get the row version expected by the request, if `If-Match` header is given.
+fn expected_version(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get("If-Match")
+        .and_then(|x| x.to_str().ok())
+        .and_then(parse_if_match)
+}
+
+/// Get the row version expected by `If-Match` header. `*` matches any version, so `None` is returned.
+fn parse_if_match(value: &str) -> Option<String> {
+    let value = value.trim();
+
+    if value == "*" {
+        return None;
+    }
+    Some(value.trim_matches('"').to_string())
 }
 
 #[post("/user/{uid}/authentication")]
 pub async fn bind_authentication(
     app: web::Data<AppState>,
-    token: Option<JwtToken>,
+    token: JwtToken,
     form: web::Form<AuthParameters>,
     uid: web::Path<i32>,
+    req: HttpRequest,
 ) -> Result<HttpResponse> {
     let parameters: AuthParameters = form.into_inner();
-    let token = token.unwrap();
     let uid = uid.into_inner();
 
     if token.uid != uid && !token.is_admin {
@@ -232,15 +826,14 @@ pub async fn bind_authentication(
     }
     let user = Person::get(&app.pool, uid).await?;
 
-    match parameters {
+    let auth = match parameters {
         AuthParameters {
             login_type: LOGIN_BY_WECHAT,
             wechat_code: Some(wechat_code),
             ..
         } => {
             let wechat_token: WxSession = get_session_by_code(wechat_code.as_str()).await?;
-            let auth: Authentication = Authentication::from_wechat(&wechat_token.openid);
-            user.update_authentication(&app.pool, &auth).await?;
+            Authentication::from_wechat(&wechat_token.openid)
         }
         AuthParameters {
             login_type: LOGIN_BY_PASSWORD,
@@ -253,8 +846,7 @@ pub async fn bind_authentication(
             if !token.is_admin {
                 return Err(ApiError::new(UserError::AuthTypeNotAllowed));
             }
-            let auth = Authentication::from_password(username, password);
-            user.update_authentication(&app.pool, &auth).await?;
+            Authentication::from_password(username, password)
         }
         AuthParameters {
             login_type: LOGIN_BY_CAMPUS_WEB,
@@ -262,54 +854,139 @@ pub async fn bind_authentication(
             credential: Some(password),
             ..
         } => {
-            let auth = Authentication::from_campus_auth(account.clone(), password.clone());
-
-            Identity::validate_oa_account(&account, &password).await?;
-            user.update_authentication(&app.pool, &auth).await?;
+            let account = Identity::validate_oa_account_fresh(&app.pool, &account, &password).await?;
+            Authentication::from_campus_auth(account, password)
         }
         _ => {
             return Err(ApiError::new(CommonError::Parameter));
         }
+    };
+
+    // Grant the first administrator if the bootstrap secret is given, only after the credential
+    // is verified, and together with the bind.
+    let bootstrap_secret = req
+        .headers()
+        .get("X-Bootstrap-Secret")
+        .and_then(|x| x.to_str().ok());
+    match ADMIN_BOOTSTRAP.acquire(bootstrap_secret, CONFIG.server.bootstrap_secret.as_deref()) {
+        Some(permit) => {
+            if user.bind_first_admin(&app.pool, &auth).await? {
+                permit.spend();
+                warn!("User {} is granted administrator by bootstrap secret.", uid);
+                audit::record(&app.pool, token.uid, AuditAction::GrantAdmin, uid).await;
+            }
+        }
+        None => user.update_authentication(&app.pool, &auth).await?,
     }
     Ok(HttpResponse::Ok().json(ApiResponse::empty()))
 }
 
-#[get("/user/{uid}")]
-pub async fn get_user_detail(
+#[derive(Deserialize)]
+pub struct SecretUpdate {
+    /// New OA secret.
+    #[serde(rename = "oaSecret")]
+    pub oa_secret: String,
+    /// Student id of the identity, the primary one by default.
+    #[serde(rename = "studentId")]
+    pub student_id: Option<String>,
+}
+
+/// Update the OA secret stored, after the student changed it on campus.
+#[put("/user/{uid}/authentication")]
+pub async fn update_oa_secret(
     app: web::Data<AppState>,
-    token: Option<JwtToken>,
+    token: JwtToken,
     uid: web::Path<i32>,
+    form: web::Form<SecretUpdate>,
+    req: HttpRequest,
 ) -> Result<HttpResponse> {
-    if let None = token {
+    let uid = uid.into_inner();
+
+    if token.uid != uid && !token.is_admin {
         return Err(ApiError::new(CommonError::Forbidden));
     }
-    let token = token.unwrap();
+    let SecretUpdate {
+        oa_secret,
+        student_id,
+    } = form.into_inner();
+    if Identity::is_default_digit(&oa_secret) {
+        return Err(ApiError::new(UserError::DefaultSecret));
+    }
+    let person = Person::get(&app.pool, uid).await?;
+    let identity = Person::select_identity(&app.pool, uid, student_id.as_deref())
+        .await?
+        .ok_or(ApiError::new(UserError::IdentityNeeded))?;
+
+    Identity::validate_oa_account_fresh(&app.pool, &identity.student_id, &oa_secret).await?;
+    let version = person
+        .update_oa_secret(
+            &app.pool,
+            &identity.student_id,
+            &oa_secret,
+            expected_version(&req).as_deref(),
+        )
+        .await?;
+
+    Ok(HttpResponse::Ok()
+        .header("ETag", entity_tag(&version))
+        .json(ApiResponse::empty()))
+}
+
+#[get("/user/{uid}")]
+pub async fn get_user_detail(
+    app: web::Data<AppState>,
+    token: JwtToken,
+    uid: web::Path<i32>,
+) -> Result<HttpResponse> {
     let uid = uid.into_inner();
 
     if uid != token.uid && !token.is_admin {
         return Err(ApiError::new(CommonError::Forbidden));
     }
-    let user = Person::get(&app.pool, uid).await?;
-    Ok(HttpResponse::Ok().json(&ApiResponse::normal(&user)))
+    let user = with_db_retry(|| Person::get(&app.pool, uid)).await?;
+    let version = with_db_retry(|| Person::get_version(&app.pool, uid)).await?;
+    Ok(HttpResponse::Ok()
+        .header("ETag", entity_tag(&version))
+        .json(&ApiResponse::normal(&user)))
 }
 
 #[get("/user/{uid}/identity")]
 pub async fn get_user_identity(
     app: web::Data<AppState>,
-    token: Option<JwtToken>,
+    token: JwtToken,
     uid: web::Path<i32>,
 ) -> Result<HttpResponse> {
     let uid = uid.into_inner();
-    let token = token.unwrap();
 
     if token.uid != uid && !token.is_admin {
         return Err(ApiError::new(CommonError::Forbidden));
     }
-    let identity = Person::get_identity(&app.pool, uid).await?;
+    let identity = with_db_retry(|| Person::get_identity(&app.pool, uid)).await?;
     if let None = identity {
         return Err(ApiError::new(UserError::NoSuchUser));
     }
-    Ok(HttpResponse::Ok().json(&ApiResponse::normal(identity.unwrap())))
+    let masked: MaskedIdentity = identity.unwrap().into();
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(masked)))
+}
+
+/// All identities of the user, the primary one first.
+#[get("/user/{uid}/identities")]
+pub async fn list_user_identities(
+    app: web::Data<AppState>,
+    token: JwtToken,
+    uid: web::Path<i32>,
+) -> Result<HttpResponse> {
+    let uid = uid.into_inner();
+
+    if token.uid != uid && !token.is_admin {
+        return Err(ApiError::new(CommonError::Forbidden));
+    }
+    let identities: Vec<MaskedIdentity> = with_db_retry(|| Person::list_identities(&app.pool, uid))
+        .await?
+        .into_iter()
+        .map(MaskedIdentity::from)
+        .collect();
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(identities)))
 }
 
 #[derive(Deserialize)]
@@ -326,22 +1003,54 @@ pub struct IdentityPost {
     /// ID card number
     #[serde(rename = "identityNumber")]
     pub identity_number: Option<String>,
+    /// Use the identity by default. The first identity of a user is always primary.
+    #[serde(rename = "isPrimary", default)]
+    pub is_primary: bool,
+    /// College on the portal, to create the freshman record on the first binding.
+    pub college: Option<String>,
+    /// Class on the portal, to create the freshman record on the first binding.
+    pub class: Option<String>,
+}
+
+/// Move the identity of the student id to the user, along with the campus authentication by it,
+/// when it's bound by another user by mistake.
+#[put("/user/{uid}/identity/{student_id}")]
+pub async fn reassign_user_identity(
+    app: web::Data<AppState>,
+    admin: AdminRequired,
+    path: web::Path<(i32, String)>,
+) -> Result<HttpResponse> {
+    let (uid, student_id) = path.into_inner();
+
+    Person::get(&app.pool, uid).await?;
+    let owner = Person::reassign_identity(&app.pool, &student_id, uid)
+        .await?
+        .ok_or(ApiError::new(CommonError::Parameter))?;
+    if owner != uid {
+        warn!(
+            "Student id {} is moved from user {} to {} by administrator {}.",
+            student_id, owner, uid, admin.0.uid
+        );
+        let target = format!("{}->{}", student_id, uid);
+        audit::record(&app.pool, admin.0.uid, AuditAction::ReassignIdentity, target).await;
+    }
+    Ok(HttpResponse::Ok().json(ApiResponse::empty()))
 }
 
 #[post("/user/{uid}/identity")]
 pub async fn set_user_identity(
     app: web::Data<AppState>,
-    token: Option<JwtToken>,
+    token: JwtToken,
     uid: web::Path<i32>,
     data: web::Form<IdentityPost>,
 ) -> Result<HttpResponse> {
     let uid = uid.into_inner();
-    let token = token.unwrap();
 
     if token.uid != uid && !token.is_admin {
         return Err(ApiError::new(CommonError::Forbidden));
     }
     let identity_post = data.into_inner();
+    let (college, class) = (identity_post.college, identity_post.class);
     let mut identity = Identity {
         uid,
         real_name: identity_post.real_name,
@@ -349,11 +1058,24 @@ pub async fn set_user_identity(
         oa_secret: identity_post.oa_secret,
         oa_certified: false,
         identity_number: identity_post.identity_number,
+        is_primary: identity_post.is_primary,
     };
     let person = Person::get(&app.pool, uid).await?;
+    let first_bind = Person::get_identity(&app.pool, uid).await?.is_none();
     person.set_identity(&app.pool, &mut identity).await?;
 
-    if identity.oa_certified {
+    if first_bind && CONFIG.server.freshman_on_bind {
+        let manager = FreshmanManager::new(&app.pool);
+        manager
+            .init_from_identity(&identity, college.as_deref(), class.as_deref())
+            .await?;
+        if let Err(e) = manager.notify_roommates(&identity.student_id).await {
+            warn!("Failed to notify roommates of {}: {}", identity.student_id, e);
+        }
+    }
+
+    // Campus authentication follows the primary identity.
+    if identity.oa_certified && identity.is_primary {
         let auth = Authentication::from_campus_auth(
             identity.student_id.clone(),
             identity.oa_secret.unwrap_or_default(),
@@ -363,3 +1085,109 @@ pub async fn set_user_identity(
     }
     Ok(HttpResponse::Ok().json(&ApiResponse::empty()))
 }
+
+/// Overview of the current user, sections of which are loaded separately. A failed section is
+/// left empty and its name is listed in `errors`, so that other sections are still returned.
+#[derive(Serialize)]
+pub struct UserOverview {
+    profile: Option<Person>,
+    identity: Option<MaskedIdentity>,
+    /// Whether the student identity is bound.
+    bound: Option<bool>,
+    #[serde(rename = "unreadCount")]
+    unread_count: Option<i64>,
+    features: Vec<String>,
+    /// Names of sections failed to load.
+    errors: Vec<&'static str>,
+}
+
+impl UserOverview {
+    fn assemble(
+        profile: Result<Person>,
+        identity: Result<Option<Identity>>,
+        unread_count: Result<i64>,
+        features: Vec<String>,
+    ) -> Self {
+        let mut errors = Vec::new();
+        let mut section = |name, ok: bool| {
+            if !ok {
+                errors.push(name);
+            }
+        };
+
+        section("profile", profile.is_ok());
+        section("identity", identity.is_ok());
+        section("unreadCount", unread_count.is_ok());
+
+        let identity = identity.ok();
+        Self {
+            profile: profile.ok(),
+            bound: identity.as_ref().map(Option::is_some),
+            identity: identity.flatten().map(MaskedIdentity::from),
+            unread_count: unread_count.ok(),
+            features,
+            errors,
+        }
+    }
+}
+
+#[get("/user/me/overview")]
+pub async fn get_user_overview(
+    app: web::Data<AppState>,
+    token: Option<JwtToken>,
+) -> Result<HttpResponse> {
+    let token = token.ok_or(ApiError::new(CommonError::LoginNeeded))?;
+
+    let overview = UserOverview::assemble(
+        Person::get(&app.pool, token.uid).await,
+        Person::get_identity(&app.pool, token.uid).await,
+        NotificationManager::new(&app.pool).count_unread(token.uid).await,
+        CONFIG.server.features.clone(),
+    );
+    Ok(HttpResponse::Ok().json(ApiResponse::normal(overview)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_user_overview() {
+        let identity = Identity {
+            uid: 1,
+            real_name: "张三".to_string(),
+            student_id: "1910000000".to_string(),
+            oa_certified: true,
+            ..Identity::default()
+        };
+        let overview = UserOverview::assemble(
+            Ok(Person::new()),
+            Ok(Some(identity)),
+            Ok(3),
+            vec!["notification".to_string()],
+        );
+
+        assert!(overview.profile.is_some());
+        assert!(overview.identity.is_some());
+        assert_eq!(overview.bound, Some(true));
+        assert_eq!(overview.unread_count, Some(3));
+        assert_eq!(overview.features, vec!["notification"]);
+        assert!(overview.errors.is_empty());
+
+        let overview = UserOverview::assemble(
+            Ok(Person::new()),
+            Err(ApiError::new(CommonError::Unavailable)),
+            Ok(0),
+            vec![],
+        );
+        assert_eq!(overview.bound, None);
+        assert_eq!(overview.errors, vec!["identity"]);
+    }
+
+    #[test]
+    pub fn test_parse_if_match() {
+        assert_eq!(parse_if_match(&entity_tag("1234")), Some("1234".to_string()));
+        assert_eq!(parse_if_match(" \"1234\" "), Some("1234".to_string()));
+        assert_eq!(parse_if_match("*"), None);
+    }
+}
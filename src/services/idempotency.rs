@@ -0,0 +1,208 @@
+//! Requests with `Idempotency-Key` header are processed once for each key, and retries are answered
+//! with the result of the first one.
+use crate::config::CONFIG;
+use crate::error::{ApiError, Result};
+use crate::models::idempotency::{Claim, IdempotencyManager};
+use crate::models::CommonError;
+use crate::services::client_ip;
+use actix_web::{HttpRequest, HttpResponse};
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use std::future::Future;
+use std::time::Duration;
+
+/// Header carrying the idempotency key.
+const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+/// Max length of idempotency keys.
+const MAX_KEY_LENGTH: usize = 255;
+
+/// How long keys are kept.
+#[derive(Clone, Copy)]
+pub struct KeyTtl {
+    /// Keys claimed longer ago are forgotten.
+    pub window: Duration,
+    /// A key whose first request hasn't finished in the lease, like when the client disconnected,
+    /// can be claimed again.
+    pub lease: Duration,
+}
+
+impl KeyTtl {
+    pub fn configured() -> Self {
+        Self {
+            window: Duration::from_secs(CONFIG.server.idempotency_window),
+            lease: Duration::from_secs(CONFIG.server.idempotency_lease),
+        }
+    }
+}
+
+/// Scope of keys of anonymous requests, by client IP.
+pub fn ip_scope(endpoint: &str, req: &HttpRequest) -> String {
+    format!("{} ip:{}", endpoint, client_ip(req))
+}
+
+/// Scope of keys of requests by the user.
+pub fn user_scope(endpoint: &str, uid: i32) -> String {
+    format!("{} user:{}", endpoint, uid)
+}
+
+/// Run `process` once for each key in the scope, and answer the result by `respond`. The result
+/// is stored in JSON for retries, so secrets like tokens should be issued in `respond` rather than
+/// stored. A key is bound to the request by `fingerprint`, like a digest of the body, and reusing
+/// it for another request fails. Requests without a key are always
+/// processed, and failed ones can be retried with the same key.
+pub async fn run_once<T, F, Fut, R>(
+    pool: &PgPool,
+    req: &HttpRequest,
+    scope: &str,
+    fingerprint: &[u8],
+    ttl: KeyTtl,
+    process: F,
+    respond: R,
+) -> Result<HttpResponse>
+where
+    T: Serialize + DeserializeOwned,
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<T>>,
+    R: FnOnce(T) -> Result<HttpResponse>,
+{
+    let key = match req.headers().get(IDEMPOTENCY_KEY) {
+        Some(key) => key.to_str().map_err(|_| ApiError::new(CommonError::Parameter))?,
+        None => return respond(process().await?),
+    };
+    if key.is_empty() || key.len() > MAX_KEY_LENGTH {
+        return Err(ApiError::new(CommonError::Parameter));
+    }
+
+    let fingerprint = format!("{:x}", Sha256::digest(fingerprint));
+    let manager = IdempotencyManager::new(pool);
+    match manager
+        .claim(scope, key, &fingerprint, ttl.window, ttl.lease)
+        .await?
+    {
+        Claim::Done(stored) => return respond(serde_json::from_str(&stored)?),
+        Claim::Pending => return Err(ApiError::new(CommonError::RequestInProgress)),
+        Claim::Mismatch => return Err(ApiError::new(CommonError::Parameter)),
+        Claim::New => (),
+    }
+    match process().await {
+        Ok(result) => {
+            manager
+                .complete(scope, key, &serde_json::to_string(&result)?)
+                .await?;
+            respond(result)
+        }
+        Err(e) => {
+            if let Err(e) = manager.release(scope, key).await {
+                warn!("Failed to release idempotency key {:?}: {}", key, e);
+            }
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::test::db_pool;
+    use actix_web::test::TestRequest;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    pub fn test_ip_scope_without_port() {
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.1:12345".parse().unwrap())
+            .to_http_request();
+        assert_eq!(ip_scope("POST /user", &req), "POST /user ip:10.0.0.1");
+        assert_eq!(user_scope("POST /attachment", 10), "POST /attachment user:10");
+    }
+
+    #[test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    pub fn test_run_once() {
+        actix_web::rt::System::new("test").block_on(async {
+            let pool = db_pool().await;
+            let scope = "POST /test user:99999";
+            sqlx::query("DELETE FROM public.idempotency_key WHERE scope = $1")
+                .bind(scope)
+                .execute(&pool)
+                .await
+                .unwrap();
+
+            // Count rows created.
+            let created = AtomicUsize::new(0);
+            let create = || async { Ok(created.fetch_add(1, Ordering::SeqCst) + 1) };
+            let fail = || async { Err(ApiError::new(CommonError::Unavailable)) };
+            // Secrets are issued on each response rather than stored.
+            let issued = AtomicUsize::new(0);
+            let respond = |id: usize| {
+                issued.fetch_add(1, Ordering::SeqCst);
+                Ok(HttpResponse::Ok().json(id))
+            };
+            let req = |key: &str| {
+                TestRequest::default()
+                    .header(IDEMPOTENCY_KEY, key)
+                    .to_http_request()
+            };
+            let ttl = KeyTtl {
+                window: Duration::from_secs(60),
+                lease: Duration::from_secs(60),
+            };
+            let body_of = |response: HttpResponse| match response.body().as_ref() {
+                Some(actix_web::body::Body::Bytes(bytes)) => bytes.to_vec(),
+                _ => unreachable!(),
+            };
+            let run = |key: &'static str, body: &'static [u8], ttl: KeyTtl| {
+                let req = req(key);
+                let (pool, create, respond) = (&pool, &create, &respond);
+                async move { run_once(pool, &req, scope, body, ttl, create, respond).await }
+            };
+
+            let first = run("a", b"1", ttl).await.unwrap();
+            let retry = run("a", b"1", ttl).await.unwrap();
+            assert_eq!(created.load(Ordering::SeqCst), 1);
+            assert_eq!(issued.load(Ordering::SeqCst), 2);
+            assert_eq!(body_of(first), body_of(retry));
+            // The key of another request.
+            assert_eq!(
+                run("a", b"2", ttl).await.unwrap_err(),
+                ApiError::new(CommonError::Parameter)
+            );
+            // Another key creates another one.
+            run("b", b"1", ttl).await.unwrap();
+            assert_eq!(created.load(Ordering::SeqCst), 2);
+            // Failed requests can be retried.
+            assert!(run_once(&pool, &req("c"), scope, b"1", ttl, fail, respond)
+                .await
+                .is_err());
+            run("c", b"1", ttl).await.unwrap();
+            assert_eq!(created.load(Ordering::SeqCst), 3);
+
+            // The first request was dropped before it finished.
+            let manager = IdempotencyManager::new(&pool);
+            let fingerprint = format!("{:x}", Sha256::digest(b"1"));
+            let claim = manager.claim(scope, "d", &fingerprint, ttl.window, ttl.lease);
+            assert_eq!(claim.await.unwrap(), Claim::New);
+            assert_eq!(
+                run("d", b"1", ttl).await.unwrap_err(),
+                ApiError::new(CommonError::RequestInProgress)
+            );
+            // Claimed again after the lease.
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+            let expired = KeyTtl {
+                lease: Duration::from_secs(0),
+                ..ttl
+            };
+            run("d", b"1", expired).await.unwrap();
+            assert_eq!(created.load(Ordering::SeqCst), 4);
+
+            sqlx::query("DELETE FROM public.idempotency_key WHERE scope = $1")
+                .bind(scope)
+                .execute(&pool)
+                .await
+                .unwrap();
+        });
+    }
+}
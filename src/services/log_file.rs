@@ -0,0 +1,237 @@
+//! Log file rotated by size and by day. Rotated files are renamed with the time of rotation,
+//! like `kite.log.20210901-000000-000`, and then compressed in background. Only the latest
+//! archives are kept.
+
+use crate::config::LogConfig;
+use chrono::{DateTime, Local, NaiveDate};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::thread::JoinHandle;
+
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    /// Size of the current file.
+    size: u64,
+    /// Day when the current file is opened.
+    date: NaiveDate,
+    config: LogConfig,
+    /// Record being written. fern writes a record in pieces and then flushes, so records are
+    /// written on flush as a whole, and never split into two files.
+    pending: Vec<u8>,
+    /// Background compression of the latest archive.
+    archiving: Option<JoinHandle<()>>,
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Compress the rotated file, and remove the oldest archives except the latest `keep` ones.
+fn archive(rotated: &Path, keep: usize) -> io::Result<()> {
+    let mut archive_path = rotated.as_os_str().to_owned();
+    archive_path.push(".gz");
+
+    let mut encoder = GzEncoder::new(File::create(&archive_path)?, Compression::default());
+    io::copy(&mut File::open(rotated)?, &mut encoder)?;
+    encoder.finish()?;
+    fs::remove_file(rotated)?;
+
+    // Archives of a log file start with its name.
+    let dir = rotated
+        .parent()
+        .filter(|x| !x.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    let prefix = rotated
+        .file_name()
+        .and_then(|x| x.to_str())
+        .and_then(|x| x.rsplit_once('.').map(|x| x.0))
+        .unwrap_or_default()
+        .to_string();
+    let mut archives: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|x| x.path()))
+        .filter(|x| {
+            let name = x.file_name().and_then(|x| x.to_str()).unwrap_or_default();
+            name.starts_with(&format!("{}.", prefix)) && name.ends_with(".gz")
+        })
+        .collect();
+    // Names end with the time of rotation, so the oldest ones come first.
+    archives.sort();
+    let count = archives.len().saturating_sub(keep);
+    for old in &archives[..count] {
+        fs::remove_file(old)?;
+    }
+    Ok(())
+}
+
+impl RotatingFile {
+    pub fn open(path: &str, config: LogConfig) -> io::Result<Self> {
+        let path = PathBuf::from(path);
+        let file = open_append(&path)?;
+        let metadata = file.metadata()?;
+        // So that a file left by the last run is rotated if it's of another day.
+        let date = metadata
+            .modified()
+            .map(|x| DateTime::<Local>::from(x).date().naive_local())
+            .unwrap_or_else(|_| Local::today().naive_local());
+
+        Ok(Self {
+            path,
+            file,
+            size: metadata.len(),
+            date,
+            config,
+            pending: Vec::new(),
+            archiving: None,
+        })
+    }
+
+    /// Whether to rotate before writing `incoming` bytes on `today`.
+    fn should_rotate(&self, incoming: usize, today: NaiveDate) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        let oversize = self.config.max_size > 0 && self.size + incoming as u64 > self.config.max_size;
+        let outdated = self.config.daily && today != self.date;
+        oversize || outdated
+    }
+
+    /// Rename the current file, open a new one, and archive the old one in background.
+    fn rotate(&mut self, now: DateTime<Local>) -> io::Result<()> {
+        let mut rotated = self.path.as_os_str().to_owned();
+        rotated.push(format!(".{}", now.format("%Y%m%d-%H%M%S-%3f")));
+        let rotated = PathBuf::from(rotated);
+
+        self.file.flush()?;
+        fs::rename(&self.path, &rotated)?;
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        self.date = now.date().naive_local();
+
+        let keep = self.config.keep;
+        let previous = self.archiving.take();
+        self.archiving = Some(std::thread::spawn(move || {
+            // One at a time, so that old archives are counted correctly.
+            if let Some(previous) = previous {
+                let _ = previous.join();
+            }
+            if let Err(e) = archive(&rotated, keep) {
+                eprintln!("Failed to archive log file {}: {}", rotated.display(), e);
+            }
+        }));
+        Ok(())
+    }
+
+    #[cfg(test)]
+    fn wait_archived(&mut self) {
+        if let Some(archiving) = self.archiving.take() {
+            archiving.join().unwrap();
+        }
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let now = Local::now();
+        if self.should_rotate(self.pending.len(), now.date().naive_local()) {
+            // Keep writing to the current file if it can't be rotated.
+            if let Err(e) = self.rotate(now) {
+                eprintln!("Failed to rotate log file {}: {}", self.path.display(), e);
+            }
+        }
+
+        self.file.write_all(&self.pending)?;
+        self.size += self.pending.len() as u64;
+        self.pending.clear();
+        self.file.flush()
+    }
+}
+
+impl Drop for RotatingFile {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(max_size: u64, keep: usize) -> LogConfig {
+        LogConfig {
+            max_size,
+            daily: true,
+            keep,
+        }
+    }
+
+    fn list(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|x| x.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    pub fn test_should_rotate() {
+        let dir = std::env::temp_dir().join(format!("kite-test-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("kite.log");
+        let mut file = RotatingFile::open(path.to_str().unwrap(), config(100, 3)).unwrap();
+        let today = file.date;
+
+        // An empty file is never rotated, or a long record rotates it again and again.
+        assert!(!file.should_rotate(200, today));
+        file.size = 90;
+        assert!(!file.should_rotate(10, today));
+        assert!(file.should_rotate(11, today));
+        assert!(file.should_rotate(1, today.succ()));
+        file.config.daily = false;
+        assert!(!file.should_rotate(1, today.succ()));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    pub fn test_rotate_by_size() {
+        let dir = std::env::temp_dir().join(format!("kite-test-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("kite.log");
+        let mut file = RotatingFile::open(path.to_str().unwrap(), config(20, 2)).unwrap();
+
+        // Each record is 9 bytes, written in pieces like fern does, so a file holds 2 records.
+        for i in 0..10 {
+            write!(file, "record {}", i).unwrap();
+            file.write_all(b"\n").unwrap();
+            file.flush().unwrap();
+            // Distinct names of archives.
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+        file.wait_archived();
+
+        // 4 files are rotated, and the oldest 2 are removed.
+        let names = list(&dir);
+        assert_eq!(names.len(), 3);
+        assert_eq!(names[0], "kite.log");
+        assert!(names[1].starts_with("kite.log.") && names[1].ends_with(".gz"));
+        assert!(names[2].starts_with("kite.log.") && names[2].ends_with(".gz"));
+        // No line is lost or split at the boundary.
+        let mut content = String::new();
+        let archive = File::open(dir.join(&names[2])).unwrap();
+        io::Read::read_to_string(&mut flate2::read::GzDecoder::new(archive), &mut content).unwrap();
+        assert_eq!(content, "record 6\nrecord 7\n");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "record 8\nrecord 9\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
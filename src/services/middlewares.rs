@@ -1,3 +1,13 @@
 pub mod acl;
+pub mod body_limit;
+pub mod cache_control;
+pub mod cors;
+pub mod etag;
+pub mod ip_limit;
+pub mod locale;
 pub mod logger;
+pub mod login_limit;
+pub mod metrics;
 pub mod reject;
+pub mod request_id;
+pub mod skip_compress;
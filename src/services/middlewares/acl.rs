@@ -1,18 +1,37 @@
+use crate::cache::Cache;
+use crate::config::CONFIG;
 use crate::error::ApiError;
-use crate::jwt::*;
+use crate::models::user::Person;
 use crate::models::CommonError;
+use crate::services::auth::verify_token;
 use crate::services::{get_auth_bearer_value, JwtToken};
-use actix_http::http::Method;
+use actix_http::http::{Method, StatusCode};
 use actix_service::{Service, Transform};
 use actix_web::{
     dev::{ServiceRequest, ServiceResponse},
-    Error, HttpResponse,
+    Error, HttpMessage, ResponseError,
 };
-use futures::future::{ok, Either, Ready};
+use futures::future::{ok, Either, LocalBoxFuture, Ready};
+use sqlx::PgPool;
+use std::cell::RefCell;
+use std::rc::Rc;
 use std::result::Result;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
-pub struct Auth;
+/// Refuse requests without a valid token, except ones to anonymous routes.
+pub struct Auth {
+    patterns: &'static [RoutePattern],
+}
+
+impl Auth {
+    /// Check requests against anonymous routes configured, or the default ones.
+    pub fn new() -> Self {
+        Self {
+            patterns: &ANONYMOUS_PATTERNS,
+        }
+    }
+}
 
 impl<S, B> Transform<S> for Auth
 where
@@ -27,12 +46,16 @@ where
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        ok(AuthMiddleware { service })
+        ok(AuthMiddleware {
+            service,
+            patterns: self.patterns,
+        })
     }
 }
 
 pub struct AuthMiddleware<S> {
     service: S,
+    patterns: &'static [RoutePattern],
 }
 
 impl<S, B> Service for AuthMiddleware<S>
@@ -52,46 +75,488 @@ where
     fn call(&mut self, req: ServiceRequest) -> Self::Future {
         // 检查请求的 path 和请求方法
         // 对可匿名访问的页面予以放行
-        if check_anonymous_list(req.method(), req.path()) {
+        if is_anonymous(self.patterns, req.method(), req.path()) {
             return Either::Left(self.service.call(req));
         }
 
+        // The token is stashed by the account check with the current role if it's enabled, which
+        // mustn't be replaced with the role in the token.
+        if req.extensions().get::<JwtToken>().is_some() {
+            return Either::Left(self.service.call(req));
+        }
         // For logined users, they can access all of the resources, and then each module will check
-        // whether they can do or not.
-        if let Some(auth_string) = req.headers().get("Authorization") {
-            // If authentication type is "Bearer"
-            if let Some(jwt_string) = get_auth_bearer_value(auth_string) {
-                // Unpack JWT to verify credential
-                if validate_jwt::<JwtToken>(jwt_string) {
-                    return Either::Left(self.service.call(req));
+        // whether they can do or not. Revoked tokens and tokens of deleted users are refused.
+        let token = req
+            .headers()
+            .get("Authorization")
+            .and_then(get_auth_bearer_value)
+            .and_then(verify_token);
+        if let Some(token) = token {
+            // Stash the token so that handlers don't decode it again.
+            req.extensions_mut().insert(token);
+            return Either::Left(self.service.call(req));
+        }
+        let response = ApiError::new(CommonError::LoginNeeded).error_response();
+        return Either::Right(ok(req.into_response(response.into_body())));
+    }
+}
+
+/// Roles of active users by uid, or `None` for users not found, deleted or disabled.
+type RoleCache = Cache<i32, Option<bool>>;
+
+/// Check users of tokens against the database, since tokens are trusted until they expire. Tokens
+/// of users not found, deleted or disabled are rejected with 403 Forbidden, and others are stashed
+/// with the current role for handlers. Users are cached for `ttl` to save a query per request.
+pub struct AccountCheck {
+    pool: PgPool,
+    roles: RoleCache,
+}
+
+impl AccountCheck {
+    pub fn new(pool: PgPool, ttl: Duration) -> Self {
+        Self {
+            pool,
+            roles: Cache::new(ttl),
+        }
+    }
+}
+
+impl<S, B> Transform<S> for AccountCheck
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = AccountCheckMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AccountCheckMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            pool: self.pool.clone(),
+            roles: self.roles.clone(),
+        })
+    }
+}
+
+pub struct AccountCheckMiddleware<S> {
+    // The service is called after the user looked up.
+    service: Rc<RefCell<S>>,
+    pool: PgPool,
+    roles: RoleCache,
+}
+
+/// The token with the current role, or `None` if the user is not active.
+async fn check_account(
+    pool: &PgPool,
+    roles: &RoleCache,
+    mut token: JwtToken,
+) -> crate::error::Result<Option<JwtToken>> {
+    let role = match roles.get(&token.uid) {
+        Some(role) => role,
+        None => {
+            let role = Person::get_active_role(pool, token.uid).await?;
+            roles.insert(token.uid, role);
+            role
+        }
+    };
+    Ok(role.map(|is_admin| {
+        token.is_admin = is_admin;
+        token
+    }))
+}
+
+impl<S, B> Service for AccountCheckMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        // Requests without a valid token are left to handlers.
+        let token = req
+            .headers()
+            .get("Authorization")
+            .and_then(get_auth_bearer_value)
+            .and_then(verify_token);
+        let token = match token {
+            Some(token) => token,
+            None => return Box::pin(self.service.call(req)),
+        };
+        let service = self.service.clone();
+        let (pool, roles) = (self.pool.clone(), self.roles.clone());
+
+        Box::pin(async move {
+            match check_account(&pool, &roles, token).await {
+                Ok(Some(token)) => {
+                    req.extensions_mut().insert(token);
+                    let fut = service.borrow_mut().call(req);
+                    fut.await
+                }
+                Ok(None) => {
+                    let response = ApiError::new(CommonError::Forbidden).response(StatusCode::FORBIDDEN);
+                    Ok(req.into_response(response.into_body()))
                 }
+                Err(e) => Ok(req.into_response(e.error_response().into_body())),
             }
+        })
+    }
+}
+
+/// Routes which can be accessed without login by default, in the form of "METHOD path pattern".
+/// Method "*" matches any method. In patterns, `{name}` matches one segment, and a trailing `*`
+/// matches one or more segments. Overridden by `server.anonymous_routes` in config.
+static DEFAULT_ANONYMOUS_ROUTES: &[&str] = &[
+    "* /",
+    "* /api/v1/",
+    "GET /api/v1/health",
+    // Checked by the handler with the metrics token.
+    "GET /metrics",
+    "POST /api/v1/session",
+    "POST /api/v1/session/wechat",
+    // Expired tokens are checked by the handler.
+    "POST /api/v1/session/refresh",
+    "POST /api/v1/user",
+    "POST /api/v1/user/validate",
+    "POST /api/v1/user/verify/send",
+    "GET /api/v1/captcha",
+    "GET /api/v1/event",
+    "GET /api/v1/motto",
+    "GET /api/v1/motto/list",
+    "GET /api/v1/edu/course",
+    "GET /api/v1/edu/course/{code}",
+    "GET /api/v1/edu/major",
+    "GET /api/v1/edu/major/{code}",
+    "GET /api/v1/pay/room/*",
+    "GET /api/v1/pay/electricity/{room}/history",
+    "POST /api/v1/pay/electricity/batch",
+    // Browsers can't set headers on websockets, the token in the subprotocol is checked by the
+    // handler.
+    "GET /api/v1/ws/electricity/{room}",
+    "POST /api/v1/freshman/{account}/analysis/log",
+    "* /agent/",
+    "* /api/v1/notice",
+    "GET /static/*",
+    "GET /console/*",
+    "GET /api/v1/status/*",
+    "GET /api/v1/search/*",
+    // Signed links carry their own credential.
+    "GET /api/v1/attachment/{id}/download",
+    "GET /api/v1/attachment/{id}/thumbnail",
+];
+
+/// Methods allowed in route patterns, besides "*".
+const PATTERN_METHODS: &[&str] = &["GET", "POST", "PUT", "PATCH", "DELETE", "HEAD", "OPTIONS"];
+
+lazy_static! {
+    static ref ANONYMOUS_PATTERNS: Vec<RoutePattern> =
+        anonymous_routes(CONFIG.server.anonymous_routes.as_deref())
+            .unwrap_or_else(|e| panic!("Invalid anonymous route: {}", e));
+}
+
+/// Compile anonymous routes configured, or the default ones if not configured.
+fn anonymous_routes(configured: Option<&[String]>) -> Result<Vec<RoutePattern>, String> {
+    match configured {
+        Some(routes) => routes.iter().map(|x| RoutePattern::parse(x)).collect(),
+        None => DEFAULT_ANONYMOUS_ROUTES
+            .iter()
+            .map(|x| RoutePattern::parse(x))
+            .collect(),
+    }
+}
+
+/// Compile anonymous routes on startup, and panic on malformed ones rather than on the first
+/// request.
+pub fn init_anonymous_routes() {
+    lazy_static::initialize(&ANONYMOUS_PATTERNS);
+}
+
+enum Segment {
+    Literal(String),
+    /// `{name}`, matches any segment.
+    Param,
+    /// Trailing `*`, matches the rest segments.
+    Rest,
+}
+
+/// Route pattern compiled from method and path pattern.
+struct RoutePattern {
+    method: Option<Method>,
+    segments: Vec<Segment>,
+}
+
+impl RoutePattern {
+    /// Parse a route in the form of "METHOD path pattern", and check that it's well-formed.
+    fn parse(route: &str) -> Result<Self, String> {
+        let mut parts = route.split_whitespace();
+        let (method, path) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(path), None) => (method, path),
+            _ => return Err(format!("{:?} is not in the form of \"METHOD /path\"", route)),
+        };
+        if method != "*" && !PATTERN_METHODS.contains(&method) {
+            return Err(format!("unknown method {:?} in {:?}", method, route));
         }
-        return Either::Right(ok(req.into_response(
-            HttpResponse::Ok()
-                .json(ApiError::new(CommonError::LoginNeeded))
-                .into_body(),
-        )));
-    }
-}
-
-fn check_anonymous_list(method: &Method, path: &str) -> bool {
-    // TODO: Use regex expression.
-    match path {
-        "/" => true,
-        "/api/v1/" => true,
-        "/api/v1/session" => method == Method::POST,
-        "/api/v1/user" => method == Method::POST,
-        "/api/v1/event" => method == Method::GET,
-        "/api/v1/motto" => method == Method::GET,
-        "/agent/" => true,
-        "/api/v1/notice" => true,
-        _ => {
-            method == Method::GET
-                && (path.starts_with("/static/")
-                    || path.starts_with("/console/")
-                    || path.starts_with("/api/v1/status/")
-                    || path.starts_with("/api/v1/search/"))
+        if !path.starts_with('/') {
+            return Err(format!("path of {:?} doesn't start with '/'", route));
         }
+        let segments: Vec<&str> = path.split('/').collect();
+        for (i, segment) in segments.iter().enumerate() {
+            let is_param = segment.len() > 2 && segment.starts_with('{') && segment.ends_with('}');
+            let is_rest = *segment == "*" && i == segments.len() - 1;
+            let is_literal = !segment.contains(['{', '}', '*']);
+
+            if !(is_param || is_rest || is_literal) {
+                return Err(format!("bad segment {:?} in {:?}", segment, route));
+            }
+        }
+        Ok(Self::new(method, path))
+    }
+
+    fn new(method: &str, path: &str) -> Self {
+        let method = match method {
+            "*" => None,
+            m => Some(Method::from_bytes(m.as_bytes()).expect("Invalid method in route pattern")),
+        };
+        let segments = path
+            .split('/')
+            .map(|x| match x {
+                "*" => Segment::Rest,
+                x if x.starts_with('{') && x.ends_with('}') => Segment::Param,
+                x => Segment::Literal(x.to_string()),
+            })
+            .collect();
+
+        Self { method, segments }
+    }
+
+    fn matches(&self, method: &Method, path: &str) -> bool {
+        if let Some(m) = &self.method {
+            if m != method {
+                return false;
+            }
+        }
+        let mut parts = path.split('/');
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Rest => return parts.next().is_some(),
+                Segment::Param => {
+                    if parts.next().filter(|x| !x.is_empty()).is_none() {
+                        return false;
+                    }
+                }
+                Segment::Literal(literal) => {
+                    if parts.next() != Some(literal.as_str()) {
+                        return false;
+                    }
+                }
+            }
+        }
+        parts.next().is_none()
+    }
+}
+
+fn is_anonymous(patterns: &[RoutePattern], method: &Method, path: &str) -> bool {
+    patterns.iter().any(|x| x.matches(method, path))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::test::db_pool;
+    use crate::models::user::deletion::delete_user;
+    use actix_web::{test, web, App, HttpResponse};
+
+    #[test]
+    pub fn test_anonymous_list() {
+        let patterns = anonymous_routes(None).unwrap();
+        let check_anonymous_list = |method, path| is_anonymous(&patterns, method, path);
+
+        assert!(check_anonymous_list(&Method::GET, "/"));
+        assert!(check_anonymous_list(&Method::POST, "/api/v1/session"));
+        assert!(!check_anonymous_list(&Method::GET, "/api/v1/session"));
+        assert!(check_anonymous_list(&Method::POST, "/api/v1/session/refresh"));
+        assert!(check_anonymous_list(&Method::POST, "/api/v1/session/wechat"));
+        assert!(check_anonymous_list(&Method::POST, "/api/v1/user"));
+        assert!(!check_anonymous_list(&Method::GET, "/api/v1/user"));
+        assert!(check_anonymous_list(&Method::POST, "/api/v1/user/verify/send"));
+        assert!(!check_anonymous_list(&Method::POST, "/api/v1/user/1/restore"));
+        assert!(check_anonymous_list(&Method::GET, "/api/v1/motto/list"));
+        assert!(check_anonymous_list(&Method::GET, "/metrics"));
+        assert!(check_anonymous_list(&Method::PUT, "/api/v1/notice"));
+        assert!(check_anonymous_list(&Method::GET, "/static/upload/a.jpg"));
+        assert!(!check_anonymous_list(&Method::GET, "/static"));
+        assert!(!check_anonymous_list(&Method::POST, "/static/a.jpg"));
+        assert!(check_anonymous_list(&Method::GET, "/api/v1/search/notice"));
+        assert!(check_anonymous_list(
+            &Method::GET,
+            "/api/v1/attachment/1234/download"
+        ));
+        assert!(!check_anonymous_list(&Method::GET, "/api/v1/attachment/1234"));
+        assert!(!check_anonymous_list(
+            &Method::GET,
+            "/api/v1/attachment//download"
+        ));
+        assert!(!check_anonymous_list(&Method::GET, "/api/v1/user/1"));
+        assert!(check_anonymous_list(&Method::GET, "/api/v1/edu/course/B1234"));
+        assert!(check_anonymous_list(
+            &Method::GET,
+            "/api/v1/pay/room/10101/bill/days"
+        ));
+        assert!(check_anonymous_list(&Method::GET, "/api/v1/ws/electricity/10101"));
+        assert!(!check_anonymous_list(
+            &Method::POST,
+            "/api/v1/pay/electricity/subscribe"
+        ));
+        assert!(check_anonymous_list(
+            &Method::GET,
+            "/api/v1/attachment/1234/thumbnail"
+        ));
+    }
+
+    #[test]
+    pub fn test_route_pattern() {
+        let pattern = RoutePattern::new("GET", "/event/{id}");
+        assert!(pattern.matches(&Method::GET, "/event/1"));
+        assert!(!pattern.matches(&Method::DELETE, "/event/1"));
+        assert!(!pattern.matches(&Method::GET, "/event/1/sign"));
+        assert!(!pattern.matches(&Method::GET, "/event"));
+
+        let pattern = RoutePattern::new("*", "/event/{id}/sign/{uid}");
+        assert!(pattern.matches(&Method::POST, "/event/1/sign/2"));
+        assert!(!pattern.matches(&Method::POST, "/event/1/sign"));
+    }
+
+    #[test]
+    pub fn test_configured_anonymous_routes() {
+        let configured = vec!["GET /api/v1/edu/calendar".to_string(), "* /open/*".to_string()];
+        let patterns = anonymous_routes(Some(&configured)).unwrap();
+
+        assert!(is_anonymous(&patterns, &Method::GET, "/api/v1/edu/calendar"));
+        assert!(is_anonymous(&patterns, &Method::POST, "/open/a/b"));
+        // Default routes are replaced.
+        assert!(!is_anonymous(&patterns, &Method::GET, "/api/v1/health"));
+    }
+
+    #[test]
+    pub fn test_auth() {
+        let configured = vec!["GET /open".to_string()];
+        let patterns: &'static [RoutePattern] =
+            Box::leak(Box::new(anonymous_routes(Some(&configured)).unwrap()));
+        let auth = move || Auth { patterns };
+        let ok = || HttpResponse::Ok().finish();
+
+        actix_web::rt::System::new("test").block_on(async move {
+            let mut app = test::init_service(
+                App::new()
+                    .wrap(auth())
+                    .route("/open", web::get().to(ok))
+                    .route("/private", web::get().to(ok)),
+            )
+            .await;
+            let req = test::TestRequest::get().uri("/open").to_request();
+            assert!(test::call_service(&mut app, req).await.status().is_success());
+            let req = test::TestRequest::get().uri("/private").to_request();
+            let body: serde_json::Value = test::read_response_json(&mut app, req).await;
+            assert_eq!(body["code"], CommonError::LoginNeeded as u16);
+
+            // A token stashed by the account check, with the current role, is kept.
+            let stashed = JwtToken::with_ttl(10, true, 60);
+            let expected = stashed.clone();
+            let mut app = test::init_service(
+                App::new()
+                    .wrap(auth())
+                    .wrap_fn(move |req, srv| {
+                        req.extensions_mut().insert(stashed.clone());
+                        srv.call(req)
+                    })
+                    .route(
+                        "/private",
+                        web::get().to(|token: JwtToken| HttpResponse::Ok().json(token)),
+                    ),
+            )
+            .await;
+            let req = test::TestRequest::get().uri("/private").to_request();
+            let token: JwtToken = test::read_response_json(&mut app, req).await;
+            assert_eq!(token, expected);
+        });
+    }
+
+    #[test]
+    pub fn test_malformed_anonymous_routes() {
+        let parse = |route: &str| anonymous_routes(Some(&[route.to_string()]));
+
+        assert!(parse("GET /api/v1/event/{id}").is_ok());
+        assert!(parse("/api/v1/event").is_err());
+        assert!(parse("get /api/v1/event").is_err());
+        assert!(parse("GET api/v1/event").is_err());
+        assert!(parse("GET /api/v1/event extra").is_err());
+        assert!(parse("GET /static/*/a").is_err());
+        assert!(parse("GET /api/v1/event/{}").is_err());
+        assert!(parse("GET /api/v1/event/{id").is_err());
+    }
+
+    #[tokio::test]
+    #[ignore] // Requires a database, run with DATABASE_URL set.
+    async fn test_check_account() {
+        let pool = db_pool().await;
+        let mut person = Person::new();
+        person.register(&pool).await.unwrap();
+        let uid = person.uid;
+        let set_admin = |is_admin: bool| {
+            sqlx::query("UPDATE public.person SET is_admin = $2 WHERE uid = $1")
+                .bind(uid)
+                .bind(is_admin)
+        };
+        let token = JwtToken::with_ttl(uid, true, 60);
+        // Looked up on each request.
+        let roles = RoleCache::new(Duration::from_secs(0));
+
+        // An active user, whose role is read from the database.
+        let checked = check_account(&pool, &roles, token.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(checked.uid, uid);
+        assert!(!checked.is_admin);
+
+        // The role changed since the token is issued.
+        set_admin(true).execute(&pool).await.unwrap();
+        let checked = check_account(&pool, &roles, token.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(checked.is_admin);
+        // The cached role is used until expired.
+        let cached = RoleCache::new(Duration::from_secs(60));
+        check_account(&pool, &cached, token.clone()).await.unwrap();
+        set_admin(false).execute(&pool).await.unwrap();
+        let checked = check_account(&pool, &cached, token.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(checked.is_admin);
+        let checked = check_account(&pool, &roles, token.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!checked.is_admin);
+
+        // A soft-deleted user.
+        delete_user(&pool, uid).await.unwrap();
+        assert!(check_account(&pool, &roles, token).await.unwrap().is_none());
     }
 }
@@ -0,0 +1,224 @@
+//! Limit the size of request bodies before handlers read them, so that a huge body can't exhaust
+//! memory in deserialization. Routes like uploads may have larger limits by path prefix.
+
+use crate::error::ApiError;
+use crate::models::CommonError;
+use actix_http::error::PayloadError;
+use actix_http::http::header::CONTENT_LENGTH;
+use actix_http::Payload;
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::{Error, HttpMessage, ResponseError};
+use futures::future::{ok, Either, LocalBoxFuture, Ready};
+use futures::StreamExt;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// Route prefix to max body size in bytes.
+pub type LimitTable = HashMap<String, usize>;
+
+pub struct BodyLimit {
+    default: usize,
+    table: Arc<LimitTable>,
+}
+
+impl BodyLimit {
+    pub fn new(default: usize, table: &LimitTable) -> Self {
+        Self {
+            default,
+            table: Arc::new(table.clone()),
+        }
+    }
+}
+
+/// Get the limit of the path. The longest configured prefix decides it, or the default applies.
+pub fn limit_for(path: &str, table: &LimitTable, default: usize) -> usize {
+    table
+        .iter()
+        .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, limit)| *limit)
+        .unwrap_or(default)
+}
+
+impl<S, B> Transform<S> for BodyLimit
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = BodyLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(BodyLimitMiddleware {
+            service,
+            default: self.default,
+            table: self.table.clone(),
+        })
+    }
+}
+
+pub struct BodyLimitMiddleware<S> {
+    service: S,
+    default: usize,
+    table: Arc<LimitTable>,
+}
+
+fn too_large<B>(req: ServiceRequest) -> ServiceResponse<B> {
+    let response = ApiError::new(CommonError::PayloadTooLarge).error_response();
+    req.into_response(response.into_body())
+}
+
+impl<S, B> Service for BodyLimitMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<
+        Ready<Result<Self::Response, Self::Error>>,
+        LocalBoxFuture<'static, Result<Self::Response, Self::Error>>,
+    >;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let limit = limit_for(req.path(), &self.table, self.default);
+        let length = req.headers().get(CONTENT_LENGTH).map(|x| {
+            x.to_str()
+                .ok()
+                .and_then(|x| x.parse::<usize>().ok())
+                // An unreadable length is never trusted.
+                .unwrap_or(usize::MAX)
+        });
+
+        let exceeded = Rc::new(Cell::new(false));
+        match length {
+            Some(length) if length > limit => return Either::Left(ok(too_large(req))),
+            Some(_) => {}
+            // Chunked bodies are counted while read, and the response is replaced if the limit
+            // is exceeded.
+            None => {
+                let flag = exceeded.clone();
+                let mut received = 0;
+                let payload = req.take_payload().map(move |chunk| {
+                    let chunk = chunk?;
+                    received += chunk.len();
+                    if received > limit {
+                        flag.set(true);
+                        return Err(PayloadError::Overflow);
+                    }
+                    Ok(chunk)
+                });
+                req.set_payload(Payload::Stream(Box::pin(payload)));
+            }
+        }
+
+        let fut = self.service.call(req);
+        Either::Right(Box::pin(async move {
+            let res = fut.await?;
+
+            if exceeded.get() {
+                let response = ApiError::new(CommonError::PayloadTooLarge).error_response();
+                return Ok(res.into_response(response.into_body()));
+            }
+            Ok(res)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_http::http::StatusCode;
+    use actix_web::{test, web, App, HttpResponse};
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    struct Form {
+        #[allow(dead_code)]
+        name: String,
+    }
+
+    #[test]
+    pub fn test_limit_for() {
+        let mut table = LimitTable::new();
+        table.insert("/api/v1/attachment".to_string(), 1000);
+        table.insert("/api/v1/attachment/upload".to_string(), 10);
+
+        assert_eq!(limit_for("/api/v1/user", &table, 100), 100);
+        assert_eq!(limit_for("/api/v1/attachment", &table, 100), 1000);
+        assert_eq!(limit_for("/api/v1/attachment/upload/1", &table, 100), 10);
+    }
+
+    #[test]
+    pub fn test_body_limit() {
+        actix_web::rt::System::new("test").block_on(async {
+            let mut table = LimitTable::new();
+            table.insert("/upload".to_string(), 1024);
+            let mut app = test::init_service(
+                App::new()
+                    .wrap(BodyLimit::new(64, &table))
+                    .route(
+                        "/user",
+                        web::post().to(|_: web::Json<Form>| HttpResponse::Ok().finish()),
+                    )
+                    .route(
+                        "/upload",
+                        web::post().to(|body: web::Bytes| {
+                            assert_eq!(body.len(), 512);
+                            HttpResponse::Ok().finish()
+                        }),
+                    ),
+            )
+            .await;
+
+            let req = test::TestRequest::post()
+                .uri("/user")
+                .set_json(&serde_json::json!({"name": "a".repeat(100)}))
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+            let body: serde_json::Value = test::read_body_json(res).await;
+            assert_eq!(body["code"], CommonError::PayloadTooLarge as u16);
+
+            let req = test::TestRequest::post()
+                .uri("/user")
+                .set_json(&serde_json::json!({"name": "a"}))
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+
+            // A larger limit of the route.
+            let req = test::TestRequest::post()
+                .uri("/upload")
+                .set_payload(vec![0u8; 512])
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+
+            // A chunked body without length.
+            let mut req = test::TestRequest::post()
+                .uri("/user")
+                .header("content-type", "application/json")
+                .set_payload(format!("{{\"name\": \"{}\"}}", "a".repeat(100)))
+                .to_request();
+            req.headers_mut().remove(CONTENT_LENGTH);
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        });
+    }
+}
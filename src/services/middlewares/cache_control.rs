@@ -0,0 +1,123 @@
+//! Attach `Cache-Control` header to responses by route, according to `CONFIG.cache_control`.
+
+use actix_http::http::header::{HeaderValue, CACHE_CONTROL};
+use actix_http::http::Method;
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::Error;
+use futures::future::{ok, LocalBoxFuture, Ready};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// Route prefix to max age in seconds. Zero means `no-store`.
+pub type CacheTable = HashMap<String, u32>;
+
+pub struct CacheControl {
+    table: Arc<CacheTable>,
+}
+
+impl CacheControl {
+    pub fn new(table: &CacheTable) -> Self {
+        Self {
+            table: Arc::new(table.clone()),
+        }
+    }
+}
+
+/// Get `Cache-Control` value for the request. Mutating requests are never cached, and the longest
+/// configured prefix of the path decides the value for the others.
+pub fn cache_control_for(method: &Method, path: &str, table: &CacheTable) -> Option<String> {
+    if method != Method::GET && method != Method::HEAD {
+        return Some("no-store".to_string());
+    }
+    table
+        .iter()
+        .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, max_age)| match max_age {
+            0 => "no-store".to_string(),
+            n => format!("public, max-age={}", n),
+        })
+}
+
+impl<S, B> Transform<S> for CacheControl
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = CacheControlMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(CacheControlMiddleware {
+            service,
+            table: self.table.clone(),
+        })
+    }
+}
+
+pub struct CacheControlMiddleware<S> {
+    service: S,
+    table: Arc<CacheTable>,
+}
+
+impl<S, B> Service for CacheControlMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let value = cache_control_for(req.method(), req.path(), &self.table);
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let mut res = fut.await?;
+
+            if let Some(value) = value.and_then(|x| HeaderValue::from_str(&x).ok()) {
+                // Handlers may set the header by themselves.
+                if !res.headers().contains_key(CACHE_CONTROL) {
+                    res.headers_mut().insert(CACHE_CONTROL, value);
+                }
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_cache_control_for() {
+        let mut table = CacheTable::new();
+        table.insert("/api/v1/motto".to_string(), 60);
+        table.insert("/api/v1/user".to_string(), 0);
+
+        let get = |path| cache_control_for(&Method::GET, path, &table);
+        assert_eq!(get("/api/v1/motto"), Some("public, max-age=60".to_string()));
+        assert_eq!(
+            get("/api/v1/user/me/notifications/count"),
+            Some("no-store".to_string())
+        );
+        assert_eq!(get("/api/v1/event"), None);
+        assert_eq!(
+            cache_control_for(&Method::POST, "/api/v1/motto", &table),
+            Some("no-store".to_string())
+        );
+    }
+}
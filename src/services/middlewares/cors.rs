@@ -0,0 +1,188 @@
+//! Cross-origin resource sharing for web frontend, according to `CONFIG.cors`. Preflight requests
+//! are answered here and never reach handlers.
+
+use crate::config::CorsConfig;
+use actix_http::http::header::{
+    HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+};
+use actix_http::http::Method;
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::{Error, HttpResponse};
+use futures::future::{ok, Either, LocalBoxFuture, Ready};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// Header needed by the JWT flow.
+const AUTHORIZATION: &str = "Authorization";
+
+pub struct Cors {
+    config: Arc<CorsConfig>,
+}
+
+impl Cors {
+    pub fn new(config: &CorsConfig) -> Self {
+        let mut config = config.clone();
+
+        if !config
+            .allowed_headers
+            .iter()
+            .any(|x| x.eq_ignore_ascii_case(AUTHORIZATION))
+        {
+            config.allowed_headers.push(AUTHORIZATION.to_string());
+        }
+        Self {
+            config: Arc::new(config),
+        }
+    }
+}
+
+/// Whether requests from the origin are allowed.
+pub fn is_origin_allowed(origin: &str, config: &CorsConfig) -> bool {
+    config.allowed_origins.iter().any(|x| x == "*" || x == origin)
+}
+
+/// Build the response to a preflight request from an allowed origin.
+fn preflight_response(origin: &HeaderValue, config: &CorsConfig) -> HttpResponse {
+    HttpResponse::NoContent()
+        .header(ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone())
+        .header(ACCESS_CONTROL_ALLOW_METHODS, config.allowed_methods.join(", "))
+        .header(ACCESS_CONTROL_ALLOW_HEADERS, config.allowed_headers.join(", "))
+        .header(ACCESS_CONTROL_MAX_AGE, config.max_age.to_string())
+        .header(VARY, "Origin")
+        .finish()
+}
+
+impl<S, B> Transform<S> for Cors
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = CorsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(CorsMiddleware {
+            service,
+            config: self.config.clone(),
+        })
+    }
+}
+
+pub struct CorsMiddleware<S> {
+    service: S,
+    config: Arc<CorsConfig>,
+}
+
+impl<S, B> Service for CorsMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<
+        Ready<Result<Self::Response, Self::Error>>,
+        LocalBoxFuture<'static, Result<Self::Response, Self::Error>>,
+    >;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        // Requests from the same origin or not from browsers.
+        let origin = match req.headers().get(ORIGIN) {
+            Some(origin) => origin.clone(),
+            None => return Either::Right(Box::pin(self.service.call(req))),
+        };
+        let allowed = origin
+            .to_str()
+            .map(|x| is_origin_allowed(x, &self.config))
+            .unwrap_or(false);
+
+        if req.method() == Method::OPTIONS && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD) {
+            let response = if allowed {
+                preflight_response(&origin, &self.config)
+            } else {
+                HttpResponse::Forbidden().finish()
+            };
+            return Either::Left(ok(req.into_response(response.into_body())));
+        }
+
+        let fut = self.service.call(req);
+        Either::Right(Box::pin(async move {
+            let mut res = fut.await?;
+
+            if allowed {
+                res.headers_mut().insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+                res.headers_mut().insert(VARY, HeaderValue::from_static("Origin"));
+            }
+            Ok(res)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_http::http::StatusCode;
+    use actix_web::{test, web, App};
+
+    #[test]
+    pub fn test_cors() {
+        let config = CorsConfig {
+            allowed_origins: vec!["https://kite.example.com".to_string()],
+            allowed_headers: vec!["Content-Type".to_string()],
+            ..Default::default()
+        };
+
+        actix_web::rt::System::new("test").block_on(async move {
+            let mut app = test::init_service(App::new().wrap(Cors::new(&config)).route(
+                "/api/v1/motto",
+                web::get().to(|| HttpResponse::Ok().body("motto")),
+            ))
+            .await;
+            let preflight = |origin: &str| {
+                test::TestRequest::with_uri("/api/v1/motto")
+                    .method(Method::OPTIONS)
+                    .header(ORIGIN, origin)
+                    .header(ACCESS_CONTROL_REQUEST_METHOD, "GET")
+                    .to_request()
+            };
+
+            let res = test::call_service(&mut app, preflight("https://kite.example.com")).await;
+            assert_eq!(res.status(), StatusCode::NO_CONTENT);
+            let headers = res.headers();
+            assert_eq!(
+                headers.get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(),
+                "https://kite.example.com"
+            );
+            assert_eq!(
+                headers.get(ACCESS_CONTROL_ALLOW_HEADERS).unwrap(),
+                "Content-Type, Authorization"
+            );
+
+            let res = test::call_service(&mut app, preflight("https://evil.example.com")).await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+            let req = test::TestRequest::with_uri("/api/v1/motto")
+                .header(ORIGIN, "https://kite.example.com")
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(
+                res.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(),
+                "https://kite.example.com"
+            );
+        });
+    }
+}
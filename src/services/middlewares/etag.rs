@@ -0,0 +1,222 @@
+//! Tag JSON responses of selected routes with `ETag`, a digest of the body, and answer `304 Not
+//! Modified` without the body if the client has it already, by `If-None-Match`. Routes are opted
+//! in by prefixes in `CONFIG.etag_routes`, so that others are never buffered.
+
+use actix_http::body::{Body, BodySize, MessageBody, ResponseBody};
+use actix_http::http::header::{HeaderValue, CONTENT_TYPE, ETAG, IF_NONE_MATCH};
+use actix_http::http::{Method, StatusCode};
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::web::BytesMut;
+use actix_web::Error;
+use futures::future::{ok, Either, LocalBoxFuture, Ready};
+use futures::StreamExt;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// Larger bodies are sent as they are, rather than buffered to compute the tag.
+const MAX_TAGGED_SIZE: u64 = 1024 * 1024;
+
+pub struct ConditionalGet {
+    routes: Arc<Vec<String>>,
+}
+
+impl ConditionalGet {
+    pub fn new(routes: &[String]) -> Self {
+        Self {
+            routes: Arc::new(routes.to_vec()),
+        }
+    }
+}
+
+/// Weak tag of the body. Tags are weak because the body may be compressed on the way.
+pub fn entity_tag(body: &[u8]) -> String {
+    let digest = Sha256::digest(body);
+    let hex: String = digest.iter().take(16).map(|x| format!("{:02x}", x)).collect();
+    format!("W/\"{}\"", hex)
+}
+
+/// Whether `If-None-Match` has the tag, compared weakly, or is `*`.
+pub fn none_match(if_none_match: &str, tag: &str) -> bool {
+    let opaque = |x: &str| x.trim().trim_start_matches("W/").to_string();
+
+    if_none_match.trim() == "*" || if_none_match.split(',').any(|x| opaque(x) == opaque(tag))
+}
+
+impl<S, B> Transform<S> for ConditionalGet
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = ConditionalGetMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(ConditionalGetMiddleware {
+            service,
+            routes: self.routes.clone(),
+        })
+    }
+}
+
+pub struct ConditionalGetMiddleware<S> {
+    service: S,
+    routes: Arc<Vec<String>>,
+}
+
+/// Whether the response is a JSON body small enough to tag, and not tagged by the handler.
+fn is_taggable<B: MessageBody>(res: &ServiceResponse<B>) -> bool {
+    let is_json = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .map(|x| x.starts_with("application/json"))
+        == Some(true);
+    let is_small = match res.response().body().size() {
+        BodySize::Sized(size) => size <= MAX_TAGGED_SIZE,
+        _ => false,
+    };
+    res.status() == StatusCode::OK && is_json && is_small && !res.headers().contains_key(ETAG)
+}
+
+impl<S, B> Service for ConditionalGetMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<S::Future, LocalBoxFuture<'static, Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let selected = (req.method() == Method::GET || req.method() == Method::HEAD)
+            && self.routes.iter().any(|x| req.path().starts_with(x.as_str()));
+        if !selected {
+            return Either::Left(self.service.call(req));
+        }
+        let if_none_match = req
+            .headers()
+            .get(IF_NONE_MATCH)
+            .and_then(|x| x.to_str().ok())
+            .map(ToString::to_string);
+        let fut = self.service.call(req);
+
+        Either::Right(Box::pin(async move {
+            let mut res = fut.await?;
+            if !is_taggable(&res) {
+                return Ok(res);
+            }
+            let mut body = Box::pin(res.take_body());
+            let mut content = BytesMut::new();
+            while let Some(chunk) = body.next().await {
+                content.extend_from_slice(&chunk?);
+            }
+            let tag = entity_tag(&content);
+
+            let not_modified = if_none_match.map(|x| none_match(&x, &tag)) == Some(true);
+            let mut res = res.map_body(|head, _| {
+                if not_modified {
+                    head.status = StatusCode::NOT_MODIFIED;
+                    head.headers.remove(CONTENT_TYPE);
+                    ResponseBody::Other(Body::None)
+                } else {
+                    ResponseBody::Other(Body::Bytes(content.freeze()))
+                }
+            });
+            if let Ok(value) = HeaderValue::from_str(&tag) {
+                res.headers_mut().insert(ETAG, value);
+            }
+            Ok(res)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::services::response::ApiResponse;
+    use actix_web::{test, web, App, HttpResponse};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    pub fn test_none_match() {
+        let tag = entity_tag(b"{}");
+
+        assert!(tag.starts_with("W/\""));
+        assert_eq!(tag.len(), 36);
+        assert_ne!(tag, entity_tag(b"[]"));
+        assert!(none_match(&tag, &tag));
+        assert!(none_match(
+            &format!("\"x\", {}", tag.trim_start_matches("W/")),
+            &tag
+        ));
+        assert!(none_match("*", &tag));
+        assert!(!none_match("\"x\"", &tag));
+    }
+
+    #[test]
+    pub fn test_conditional_get() {
+        actix_web::rt::System::new("test").block_on(async {
+            let version = web::Data::new(AtomicUsize::new(1));
+            let mut app = test::init_service(
+                App::new()
+                    .wrap(ConditionalGet::new(&["/motto".to_string()]))
+                    .app_data(version.clone())
+                    .route(
+                        "/motto",
+                        web::get().to(|version: web::Data<AtomicUsize>| {
+                            HttpResponse::Ok().json(ApiResponse::normal(version.load(Ordering::SeqCst)))
+                        }),
+                    )
+                    .route(
+                        "/user",
+                        web::get().to(|| HttpResponse::Ok().json(ApiResponse::normal(1))),
+                    ),
+            )
+            .await;
+            let get = |uri: &str, tag: Option<&str>| {
+                let req = test::TestRequest::get().uri(uri);
+                match tag {
+                    Some(tag) => req.header(IF_NONE_MATCH, tag).to_request(),
+                    None => req.to_request(),
+                }
+            };
+
+            let res = test::call_service(&mut app, get("/motto", None)).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let tag = res.headers().get(ETAG).unwrap().to_str().unwrap().to_string();
+            let body = test::read_body(res).await;
+            assert_eq!(tag, entity_tag(&body));
+
+            // The client has the body already.
+            let res = test::call_service(&mut app, get("/motto", Some(&tag))).await;
+            assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+            assert_eq!(res.headers().get(ETAG).unwrap().to_str().unwrap(), tag);
+            assert!(test::read_body(res).await.is_empty());
+
+            // Changed since.
+            version.store(2, Ordering::SeqCst);
+            let res = test::call_service(&mut app, get("/motto", Some(&tag))).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_ne!(res.headers().get(ETAG).unwrap().to_str().unwrap(), tag);
+            assert!(!test::read_body(res).await.is_empty());
+
+            // Routes not selected are untouched.
+            let res = test::call_service(&mut app, get("/user", Some("*"))).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert!(res.headers().get(ETAG).is_none());
+        });
+    }
+}
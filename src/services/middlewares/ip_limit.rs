@@ -0,0 +1,276 @@
+//! Limit requests per client IP on all endpoints, so that public ones like `/metrics` and user
+//! creation can't be flooded. Clients in the allowlist, like internal services and the load
+//! balancer, are not limited. Requests are counted in the quota store, so that the limit holds
+//! across instances. The limit is read on each request, so that reloaded settings take effect.
+
+use crate::error::{self, ApiError};
+use crate::ipset::{convert_ipv4_addr_to_u32, IpSet};
+use crate::models::quota::{window_index, window_remaining, QuotaStore};
+use crate::models::CommonError;
+use crate::services::request_ip;
+use actix_http::http::header::RETRY_AFTER;
+use actix_http::http::HeaderValue;
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::{Error, ResponseError};
+use chrono::{DateTime, Utc};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use std::cell::RefCell;
+use std::net::IpAddr;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// Requests of each client, counted in fixed windows in the quota store, so that instances share
+/// them.
+pub struct IpLimiter {
+    quota: QuotaStore,
+    /// Max requests in the window.
+    burst: i64,
+    window: Duration,
+}
+
+impl IpLimiter {
+    /// Allow `burst` requests in `burst / rps` seconds, that is `rps` requests per second on average.
+    pub fn new(quota: QuotaStore, rps: u32, burst: u32) -> Self {
+        let burst = burst.max(1);
+
+        Self {
+            quota,
+            burst: burst as i64,
+            window: Duration::from_secs_f64(burst as f64 / rps.max(1) as f64),
+        }
+    }
+
+    /// Count the request of the client, and get how long to wait if it has sent too many in the
+    /// window. Rejected requests are counted too, and wait for the same window.
+    pub async fn check(&self, client: &str, now: DateTime<Utc>) -> error::Result<Option<Duration>> {
+        let key = format!("ip:{}:requests", client);
+        let window = window_index(now, self.window).to_string();
+
+        match self.quota.hit(&key, &window, self.burst).await? {
+            true => Ok(None),
+            false => Ok(Some(window_remaining(now, self.window))),
+        }
+    }
+}
+
+/// Value of `Retry-After` in whole seconds, at least one.
+fn retry_after(wait: Duration) -> u64 {
+    let seconds = wait.as_secs() + (wait.subsec_nanos() > 0) as u64;
+    seconds.max(1)
+}
+
+/// Get current `(rps, burst)` of the limit. Zero rps disables the limit.
+pub type RateSettings = fn() -> (u32, u32);
+
+pub struct IpLimit {
+    quota: QuotaStore,
+    settings: RateSettings,
+    allowlist: Arc<IpSet>,
+}
+
+impl IpLimit {
+    pub fn new(quota: QuotaStore, settings: RateSettings, allowlist: &[String]) -> Self {
+        let mut ip_set = IpSet::new();
+        allowlist.iter().for_each(|cidr| ip_set.load(cidr));
+
+        Self {
+            quota,
+            settings,
+            allowlist: Arc::new(ip_set),
+        }
+    }
+}
+
+impl<S, B> Transform<S> for IpLimit
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = IpLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(IpLimitMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            quota: self.quota.clone(),
+            settings: self.settings,
+            allowlist: self.allowlist.clone(),
+        })
+    }
+}
+
+pub struct IpLimitMiddleware<S> {
+    // The service is called after the request counted.
+    service: Rc<RefCell<S>>,
+    quota: QuotaStore,
+    settings: RateSettings,
+    allowlist: Arc<IpSet>,
+}
+
+impl<S> IpLimitMiddleware<S> {
+    fn is_allowed(&self, client: &str) -> bool {
+        match client.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => self.allowlist.contain(convert_ipv4_addr_to_u32(&ip.octets())),
+            _ => false,
+        }
+    }
+}
+
+impl<S, B> Service for IpLimitMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let client = request_ip(req.head());
+        let (rps, burst) = (self.settings)();
+
+        if rps == 0 || self.is_allowed(&client) {
+            return Box::pin(self.service.call(req));
+        }
+        let service = self.service.clone();
+        let limiter = IpLimiter::new(self.quota.clone(), rps, burst);
+
+        Box::pin(async move {
+            if let Some(wait) = limiter.check(&client, Utc::now()).await? {
+                let mut response = ApiError::new(CommonError::TooManyRequests).error_response();
+                response
+                    .headers_mut()
+                    .insert(RETRY_AFTER, HeaderValue::from(retry_after(wait)));
+                return Ok(req.into_response(response.into_body()));
+            }
+            let fut = service.borrow_mut().call(req);
+            fut.await
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_http::http::StatusCode;
+    use actix_web::{test, web, App, HttpResponse};
+    use chrono::TimeZone;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[tokio::test]
+    pub async fn test_ip_limiter() {
+        // 2 requests in a second.
+        let quota = QuotaStore::memory();
+        let limiter = IpLimiter::new(quota.clone(), 2, 2);
+        let now = Utc.timestamp(1000, 0);
+        let after = |ms| now + chrono::Duration::milliseconds(ms);
+
+        assert_eq!(limiter.check("1.1.1.1", now).await, Ok(None));
+        assert_eq!(limiter.check("1.1.1.1", after(400)).await, Ok(None));
+        let wait = limiter.check("1.1.1.1", after(500)).await.unwrap().unwrap();
+        assert_eq!(wait, Duration::from_millis(500));
+        assert_eq!(retry_after(wait), 1);
+        // Other clients are not affected.
+        assert_eq!(limiter.check("2.2.2.2", now).await, Ok(None));
+        // Requests are counted again in the next window.
+        assert_eq!(limiter.check("1.1.1.1", after(1000)).await, Ok(None));
+        assert_eq!(limiter.check("1.1.1.1", after(1000)).await, Ok(None));
+        assert!(limiter.check("1.1.1.1", after(1000)).await.unwrap().is_some());
+        // Clients are counted in the shared store.
+        assert_eq!(quota.list("ip:").await.unwrap().len(), 2);
+    }
+
+    #[test]
+    pub fn test_ip_limit() {
+        actix_web::rt::System::new("test").block_on(async {
+            let allowlist = vec!["10.0.0.0/8".to_string()];
+            let mut app = test::init_service(
+                App::new()
+                    .wrap(IpLimit::new(QuotaStore::memory(), || (1, 3), &allowlist))
+                    .route("/", web::get().to(|| HttpResponse::Ok().finish())),
+            )
+            .await;
+            let get = |peer: &str| {
+                test::TestRequest::get()
+                    .peer_addr(peer.parse().unwrap())
+                    .to_request()
+            };
+
+            for _ in 0..3 {
+                let res = test::call_service(&mut app, get("1.1.1.1:5000")).await;
+                assert_eq!(res.status(), StatusCode::OK);
+            }
+            let res = test::call_service(&mut app, get("1.1.1.1:5001")).await;
+            assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+            let retry_after: u64 = res
+                .headers()
+                .get(RETRY_AFTER)
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .parse()
+                .unwrap();
+            assert!((1..=3).contains(&retry_after));
+            let body: serde_json::Value = test::read_body_json(res).await;
+            assert_eq!(body["code"], CommonError::TooManyRequests as u16);
+
+            // Clients in the allowlist are never limited.
+            for _ in 0..10 {
+                let res = test::call_service(&mut app, get("10.1.2.3:5000")).await;
+                assert_eq!(res.status(), StatusCode::OK);
+            }
+            // A forged `X-Forwarded-For` doesn't bypass the limit without trusted proxies.
+            let req = test::TestRequest::get()
+                .peer_addr("1.1.1.1:5002".parse().unwrap())
+                .header("X-Forwarded-For", "10.0.0.1")
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        });
+    }
+    #[test]
+    pub fn test_reload_ip_limit() {
+        static RPS: AtomicU32 = AtomicU32::new(0);
+
+        actix_web::rt::System::new("test").block_on(async {
+            let settings = || (RPS.load(Ordering::Relaxed), 1);
+            let mut app = test::init_service(
+                App::new()
+                    .wrap(IpLimit::new(QuotaStore::memory(), settings, &[]))
+                    .route("/", web::get().to(|| HttpResponse::Ok().finish())),
+            )
+            .await;
+            let get = || {
+                test::TestRequest::get()
+                    .peer_addr("1.1.1.1:5000".parse().unwrap())
+                    .to_request()
+            };
+
+            // Not limited with zero rps.
+            for _ in 0..3 {
+                let res = test::call_service(&mut app, get()).await;
+                assert_eq!(res.status(), StatusCode::OK);
+            }
+            // Limited once the settings change, without rebuilding the app.
+            RPS.store(1, Ordering::Relaxed);
+            let res = test::call_service(&mut app, get()).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let res = test::call_service(&mut app, get()).await;
+            assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        });
+    }
+}
@@ -0,0 +1,130 @@
+//! Choose the language of error messages by the `lang` parameter, or the `Accept-Language` header,
+//! for handlers and middlewares inside.
+
+use crate::error::{Language, LANGUAGE};
+use actix_http::http::header::ACCEPT_LANGUAGE;
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::{web, Error};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+#[derive(Deserialize)]
+struct LanguageQuery {
+    lang: Option<String>,
+}
+
+/// Language requested, or the default one.
+fn language_of(req: &ServiceRequest) -> Language {
+    let by_query = web::Query::<LanguageQuery>::from_query(req.query_string())
+        .ok()
+        .and_then(|x| x.lang.as_deref().and_then(Language::from_tag));
+    let by_header = || {
+        req.headers()
+            .get(ACCEPT_LANGUAGE)
+            .and_then(|x| x.to_str().ok())
+            .and_then(Language::from_accept_language)
+    };
+
+    by_query.or_else(by_header).unwrap_or_default()
+}
+
+pub struct Localize;
+
+impl<S, B> Transform<S> for Localize
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = LocalizeMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(LocalizeMiddleware {
+            service: Rc::new(RefCell::new(service)),
+        })
+    }
+}
+
+pub struct LocalizeMiddleware<S> {
+    // Called in the scope of the language, so that rejections of middlewares inside are localized.
+    service: Rc<RefCell<S>>,
+}
+
+impl<S, B> Service for LocalizeMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let language = language_of(&req);
+        let service = self.service.clone();
+
+        Box::pin(LANGUAGE.scope(language, async move {
+            let fut = service.borrow_mut().call(req);
+            fut.await
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::{ApiError, Result};
+    use crate::models::user::UserError;
+    use actix_web::{test, App, HttpResponse};
+
+    #[test]
+    pub fn test_localized_error() {
+        actix_web::rt::System::new("test").block_on(async move {
+            let mut app =
+                test::init_service(App::new().wrap(Localize).route(
+                    "/",
+                    web::get().to(|| async {
+                        Result::<HttpResponse>::Err(ApiError::new(UserError::NoSuchUser))
+                    }),
+                ))
+                .await;
+            let request = |uri: &str, language: &str| {
+                test::TestRequest::get()
+                    .uri(uri)
+                    .header("Accept-Language", language)
+                    .to_request()
+            };
+
+            let english: serde_json::Value =
+                test::read_response_json(&mut app, request("/", "en-US,zh;q=0.5")).await;
+            let chinese: serde_json::Value =
+                test::read_response_json(&mut app, request("/", "zh-CN")).await;
+            let by_query: serde_json::Value =
+                test::read_response_json(&mut app, request("/?lang=en", "zh-CN")).await;
+            let default: serde_json::Value =
+                test::read_response_json(&mut app, request("/", "fr")).await;
+
+            assert_eq!(english["code"], 51);
+            assert_eq!(chinese["code"], 51);
+            assert_eq!(english["msg"], "No such user.");
+            assert_eq!(chinese["msg"], "找不到用户");
+            assert_eq!(by_query["msg"], "No such user.");
+            assert_eq!(default["msg"], "找不到用户");
+        });
+    }
+}
@@ -1 +1,245 @@
+//! Access logger with sampling. Successful requests are logged one in every N, while requests
+//! failed, with 4xx and 5xx responses or API errors, are always logged. N is read from hot config
+//! on each request.
 
+use super::request_id::RequestId;
+use crate::config::hot_config;
+use crate::error::ApiError;
+use crate::services::{request_ip, JwtToken};
+use actix_http::body::{BodySize, MessageBody};
+use actix_http::http::StatusCode;
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::{Error, HttpMessage};
+use chrono::Local;
+use futures::future::{ok, LocalBoxFuture, Ready};
+use log::info;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+/// Fields of the request being logged, attached to JSON log lines.
+#[derive(Default, Clone)]
+pub struct LogContext {
+    pub request_id: Option<String>,
+    pub uid: Option<i32>,
+    /// Code of the API error, which is returned with HTTP 200 mostly.
+    pub code: Option<u16>,
+}
+
+thread_local! {
+    static LOG_CONTEXT: RefCell<LogContext> = Default::default();
+}
+
+/// Run `f` with the request fields available to the JSON formatter. `f` must not yield, or other
+/// requests on the same worker may log with these fields.
+pub fn with_log_context<R>(context: LogContext, f: impl FnOnce() -> R) -> R {
+    LOG_CONTEXT.with(|x| *x.borrow_mut() = context);
+    let result = f();
+    LOG_CONTEXT.with(|x| *x.borrow_mut() = LogContext::default());
+    result
+}
+
+/// Format a log record as a line of JSON.
+pub fn format_json(message: &fmt::Arguments, record: &log::Record) -> String {
+    #[derive(Serialize)]
+    struct Line<'a> {
+        timestamp: String,
+        level: &'a str,
+        target: &'a str,
+        message: String,
+        #[serde(rename = "requestId", skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        uid: Option<i32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        code: Option<u16>,
+    }
+
+    let context = LOG_CONTEXT.with(|x| x.borrow().clone());
+    let line = Line {
+        timestamp: Local::now().to_rfc3339(),
+        level: record.level().as_str(),
+        target: record.target(),
+        message: message.to_string(),
+        request_id: context.request_id,
+        uid: context.uid,
+        code: context.code,
+    };
+    serde_json::to_string(&line).unwrap_or_default()
+}
+
+pub struct SampledLogger {
+    /// Count of successful requests.
+    counter: Arc<AtomicU64>,
+}
+
+impl SampledLogger {
+    pub fn new() -> Self {
+        Self {
+            counter: Default::default(),
+        }
+    }
+}
+
+/// Decide whether to log the request. `code` is of the API error if failed, and `seq` is the
+/// sequence of the successful request.
+pub fn should_log(status: StatusCode, code: Option<u16>, seq: u64, rate: u32) -> bool {
+    if status.is_client_error() || status.is_server_error() || code.is_some() {
+        return true;
+    }
+    rate <= 1 || seq.is_multiple_of(rate as u64)
+}
+
+impl<S, B> Transform<S> for SampledLogger
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = SampledLoggerMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(SampledLoggerMiddleware {
+            service,
+            counter: self.counter.clone(),
+        })
+    }
+}
+
+pub struct SampledLoggerMiddleware<S> {
+    service: S,
+    counter: Arc<AtomicU64>,
+}
+
+impl<S, B> Service for SampledLoggerMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let start_time = Instant::now();
+        let peer = request_ip(req.head());
+        let request_line = format!("{} {} {:?}", req.method(), req.uri(), req.version());
+        let user_agent = req
+            .headers()
+            .get("User-Agent")
+            .and_then(|x| x.to_str().ok())
+            .unwrap_or("-")
+            .to_string();
+        let request_id = req.extensions().get::<RequestId>().map(|x| x.0.clone());
+        let rate = hot_config().log_sample_rate;
+        let counter = self.counter.clone();
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await?;
+            let status = res.status();
+            // API errors are returned with HTTP 200 mostly, so the outcome is the error code.
+            let code = res
+                .response()
+                .error()
+                .and_then(|e| e.as_error::<ApiError>())
+                .map(|e| e.code);
+            let seq = if status.is_success() && code.is_none() {
+                counter.fetch_add(1, Ordering::Relaxed)
+            } else {
+                0
+            };
+
+            if should_log(status, code, seq, rate) {
+                let size = match res.response().body().size() {
+                    BodySize::Sized(n) => n.to_string(),
+                    _ => "-".to_string(),
+                };
+                let context = LogContext {
+                    request_id,
+                    // Stashed once the token is checked.
+                    uid: res.request().extensions().get::<JwtToken>().map(|x| x.uid),
+                    code,
+                };
+                // Duration in milliseconds, like `%D` of the actix logger before, so that log parsers
+                // keep working.
+                with_log_context(context, || {
+                    info!(
+                        "{} - - [{}] \"{}\" {} {} {:.6} \"{}\"",
+                        peer,
+                        Local::now().format("%d/%b/%Y:%H:%M:%S %z"),
+                        request_line,
+                        status.as_u16(),
+                        size,
+                        start_time.elapsed().as_secs_f64() * 1000.0,
+                        user_agent
+                    )
+                });
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_sampled_logging() {
+        let logged = (0..10)
+            .filter(|seq| should_log(StatusCode::OK, None, *seq, 5))
+            .count();
+        assert_eq!(logged, 2);
+
+        for seq in 0..10 {
+            assert!(should_log(StatusCode::NOT_FOUND, None, seq, 5));
+            assert!(should_log(StatusCode::INTERNAL_SERVER_ERROR, None, seq, 5));
+            assert!(should_log(StatusCode::OK, None, seq, 1));
+            // API errors with HTTP 200.
+            assert!(should_log(StatusCode::OK, Some(5), seq, 5));
+        }
+    }
+
+    #[test]
+    pub fn test_format_json() {
+        let record = log::Record::builder()
+            .level(log::Level::Info)
+            .target("kite::access")
+            .build();
+        let context = LogContext {
+            request_id: Some("abc".to_string()),
+            uid: Some(10),
+            code: Some(5),
+        };
+
+        let line = with_log_context(context, || format_json(&format_args!("GET /"), &record));
+        let line: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["target"], "kite::access");
+        assert_eq!(line["message"], "GET /");
+        assert_eq!(line["requestId"], "abc");
+        assert_eq!(line["uid"], 10);
+        assert_eq!(line["code"], 5);
+
+        // Out of request.
+        let line = format_json(&format_args!("Agent exited."), &record);
+        assert!(!line.contains("uid"));
+    }
+}
@@ -0,0 +1,286 @@
+//! Limit failed login attempts per client IP and per account, to prevent brute-forcing passwords
+//! through `POST /api/v1/session`, or OA secrets through `POST /api/v1/user/validate?checkOa=true`.
+//! Failures of an account are kept under its user, so that administrators can find and clear them.
+
+use crate::error::{self, ApiError};
+use crate::models::quota::{window_index, QuotaStore};
+use crate::models::user::lockout::{account_key_prefix, is_credential_failure};
+use crate::models::CommonError;
+use crate::services::request_ip;
+use actix_http::h1;
+use actix_http::http::Method;
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::web::BytesMut;
+use actix_web::{Error, HttpMessage, ResponseError};
+use chrono::{DateTime, Utc};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use futures::StreamExt;
+use sqlx::PgPool;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// Path of login interface.
+const LOGIN_PATH: &str = "/api/v1/session";
+/// Path of user validation, which verifies OA secrets with `checkOa=true`.
+const VALIDATE_PATH: &str = "/api/v1/user/validate";
+
+/// Failures of each key, counted in fixed windows in the quota store, so that instances share
+/// them.
+pub struct LoginLimiter {
+    quota: QuotaStore,
+    /// Max failures in the window.
+    max_failures: usize,
+    window: Duration,
+}
+
+impl LoginLimiter {
+    pub fn new(quota: QuotaStore, max_failures: usize, window: Duration) -> Self {
+        Self {
+            quota,
+            max_failures,
+            window,
+        }
+    }
+
+    /// Whether the key has failed too many times in the window.
+    pub async fn is_blocked(&self, key: &str, now: DateTime<Utc>) -> error::Result<bool> {
+        let window = window_index(now, self.window).to_string();
+
+        Ok(self.quota.get(key, &window).await? >= self.max_failures as i64)
+    }
+
+    pub async fn record_failure(&self, key: &str, now: DateTime<Utc>) -> error::Result<()> {
+        let window = window_index(now, self.window).to_string();
+
+        self.quota.hit(key, &window, self.max_failures as i64).await?;
+        Ok(())
+    }
+}
+
+/// Get the field from the url-encoded form or query string.
+fn get_field(form: &[u8], field: &str) -> Option<String> {
+    std::str::from_utf8(form)
+        .ok()?
+        .split('&')
+        .filter_map(|x| x.split_once('='))
+        .find(|(key, _)| *key == field)
+        .and_then(|(_, value)| urlencoding::decode(&value.replace('+', " ")).ok())
+}
+
+/// Field of the account in the form, if the request verifies a credential.
+fn account_field(path: &str, query: &str) -> Option<&'static str> {
+    match path {
+        LOGIN_PATH => Some("account"),
+        VALIDATE_PATH if get_field(query.as_bytes(), "checkOa").as_deref() == Some("true") => {
+            Some("studentId")
+        }
+        _ => None,
+    }
+}
+
+/// Get current `(max_failures, window)` of the limit, read on each request so that reloaded
+/// settings take effect.
+pub type LoginSettings = fn() -> (usize, Duration);
+
+pub struct LoginLimit {
+    quota: QuotaStore,
+    settings: LoginSettings,
+    /// Accounts are looked up to count failures under their users.
+    pool: PgPool,
+}
+
+impl LoginLimit {
+    pub fn new(quota: QuotaStore, pool: PgPool, settings: LoginSettings) -> Self {
+        Self {
+            quota,
+            settings,
+            pool,
+        }
+    }
+}
+
+impl<S, B> Transform<S> for LoginLimit
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = LoginLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(LoginLimitMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            quota: self.quota.clone(),
+            settings: self.settings,
+            pool: self.pool.clone(),
+        })
+    }
+}
+
+pub struct LoginLimitMiddleware<S> {
+    // The service is called after the request body read.
+    service: Rc<RefCell<S>>,
+    quota: QuotaStore,
+    settings: LoginSettings,
+    pool: PgPool,
+}
+
+impl<S, B> Service for LoginLimitMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let field = match account_field(req.path(), req.query_string()) {
+            Some(field) if req.method() == Method::POST => field,
+            _ => return Box::pin(self.service.call(req)),
+        };
+        let service = self.service.clone();
+        let (max_failures, window) = (self.settings)();
+        let limiter = LoginLimiter::new(self.quota.clone(), max_failures, window);
+        let pool = self.pool.clone();
+
+        Box::pin(async move {
+            // Read the form to get the account, and put it back for the handler.
+            let mut body = BytesMut::new();
+            let mut payload = req.take_payload();
+            while let Some(chunk) = payload.next().await {
+                body.extend_from_slice(&chunk?);
+            }
+            let ip = request_ip(req.head());
+            let mut keys = vec![format!("ip:{}:login", ip)];
+            if let Some(account) = get_field(&body, field) {
+                keys.push(format!("{}login", account_key_prefix(&pool, &account).await?));
+            }
+            let (_, mut h1_payload) = h1::Payload::create(true);
+            h1_payload.unread_data(body.freeze());
+            req.set_payload(h1_payload.into());
+
+            let now = Utc::now();
+            for key in &keys {
+                if limiter.is_blocked(key, now).await? {
+                    let response = ApiError::new(CommonError::TooManyRequests).error_response();
+                    return Ok(req.into_response(response.into_body()));
+                }
+            }
+
+            let fut = service.borrow_mut().call(req);
+            let res = fut.await?;
+            // Wrong credentials are returned as `ApiError`, while other errors, like a bad request
+            // or an unavailable auth server, are not counted. Results of validation are in the
+            // body, so each check counts.
+            let failed = field != "account"
+                || res
+                    .response()
+                    .error()
+                    .and_then(|e| e.as_error::<ApiError>())
+                    .map(is_credential_failure)
+                    == Some(true);
+            if failed {
+                for key in &keys {
+                    limiter.record_failure(key, now).await?;
+                }
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::test::db_pool;
+    use crate::models::user::UserError;
+    use actix_http::http::StatusCode;
+    use actix_web::{test, web, App, HttpResponse};
+    use chrono::TimeZone;
+
+    #[tokio::test]
+    pub async fn test_login_limiter() {
+        let quota = QuotaStore::memory();
+        let limiter = LoginLimiter::new(quota.clone(), 2, Duration::from_secs(60));
+        let now = Utc.timestamp(600, 0);
+
+        limiter.record_failure("ip:1:login", now).await.unwrap();
+        assert!(!limiter.is_blocked("ip:1:login", now).await.unwrap());
+        limiter.record_failure("ip:1:login", now).await.unwrap();
+        assert!(limiter.is_blocked("ip:1:login", now).await.unwrap());
+        assert!(!limiter.is_blocked("ip:2:login", now).await.unwrap());
+        // Failures of past windows are forgotten.
+        let later = now + chrono::Duration::seconds(60);
+        assert!(!limiter.is_blocked("ip:1:login", later).await.unwrap());
+        // Failures are kept in the shared store.
+        assert_eq!(quota.get("ip:1:login", "10").await.unwrap(), 2);
+    }
+
+    #[test]
+    pub fn test_get_field() {
+        assert_eq!(
+            get_field(b"loginType=2&account=1910000000&credential=a%26b", "account"),
+            Some("1910000000".to_string())
+        );
+        assert_eq!(get_field(b"loginType=0&wxCode=abc", "account"), None);
+    }
+
+    #[test]
+    pub fn test_account_field() {
+        assert_eq!(account_field(LOGIN_PATH, ""), Some("account"));
+        assert_eq!(account_field(VALIDATE_PATH, "checkOa=true"), Some("studentId"));
+        assert_eq!(account_field(VALIDATE_PATH, "checkOa=false"), None);
+        assert_eq!(account_field(VALIDATE_PATH, ""), None);
+        assert_eq!(account_field("/api/v1/user", ""), None);
+    }
+
+    #[test]
+    #[ignore]
+    pub fn test_login_limit() {
+        actix_web::rt::System::new("test").block_on(async {
+            let limit = LoginLimit::new(QuotaStore::memory(), db_pool().await, || {
+                (3, Duration::from_secs(3600))
+            });
+            let mut app = test::init_service(App::new().wrap(limit).route(
+                LOGIN_PATH,
+                web::post().to(|form: web::Bytes| async move {
+                    // The form is still readable by the handler.
+                    assert!(!form.is_empty());
+                    Err::<HttpResponse, _>(ApiError::new(UserError::LoginFailed))
+                }),
+            ))
+            .await;
+            let login = |account: &str| {
+                test::TestRequest::post()
+                    .uri(LOGIN_PATH)
+                    .set_payload(format!("loginType=2&account={}&credential=x", account))
+                    .to_request()
+            };
+
+            for _ in 0..3 {
+                let res = test::call_service(&mut app, login("1910000000")).await;
+                assert_eq!(res.status(), StatusCode::OK);
+            }
+            let res = test::call_service(&mut app, login("1910000000")).await;
+            assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+            // The same client is blocked for other accounts too.
+            let res = test::call_service(&mut app, login("1910000001")).await;
+            assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        });
+    }
+}
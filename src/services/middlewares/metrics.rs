@@ -0,0 +1,141 @@
+//! Record duration of each request by method, route pattern, status and result. Routes are
+//! labeled by their patterns like `/api/v1/user/{uid}`, and unknown methods as `other`, so that
+//! labels don't grow with requests.
+
+use crate::error::ApiError;
+use crate::metrics::HTTP_REQUESTS;
+use actix_http::http::Method;
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::Error;
+use futures::future::{ok, LocalBoxFuture, Ready};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+/// Label of requests which match no route.
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// Label of the method, or `other` for extension methods, which clients can make up.
+fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::DELETE => "DELETE",
+        Method::PATCH => "PATCH",
+        Method::HEAD => "HEAD",
+        Method::OPTIONS => "OPTIONS",
+        _ => "other",
+    }
+}
+
+pub struct Metrics;
+
+impl<S, B> Transform<S> for Metrics
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = MetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(MetricsMiddleware { service })
+    }
+}
+
+pub struct MetricsMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for MetricsMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let start_time = Instant::now();
+        let method = method_label(req.method());
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await?;
+            let route = res
+                .request()
+                .match_pattern()
+                .unwrap_or_else(|| UNMATCHED_ROUTE.to_string());
+
+            // API errors are returned with HTTP 200 mostly.
+            let failed = res
+                .response()
+                .error()
+                .and_then(|e| e.as_error::<ApiError>())
+                .is_some();
+            let result = if failed { "error" } else { "ok" };
+
+            HTTP_REQUESTS
+                .with_label_values(&[method, &route, res.status().as_str(), result])
+                .observe(start_time.elapsed().as_secs_f64());
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::metrics::gather;
+    use crate::models::CommonError;
+    use actix_web::{test, web, App, HttpResponse};
+
+    #[test]
+    pub fn test_route_pattern_label() {
+        actix_web::rt::System::new("test").block_on(async {
+            let mut app = test::init_service(
+                App::new()
+                    .wrap(Metrics)
+                    .route(
+                        "/test-metrics/{id}",
+                        web::get().to(|| HttpResponse::Ok().finish()),
+                    )
+                    .route(
+                        "/test-metrics-error",
+                        web::get().to(|| async {
+                            Err::<HttpResponse, _>(ApiError::new(CommonError::Parameter))
+                        }),
+                    ),
+            )
+            .await;
+
+            let req = test::TestRequest::get().uri("/test-metrics/42").to_request();
+            test::call_service(&mut app, req).await;
+            let req = test::TestRequest::get().uri("/test-metrics-error").to_request();
+            test::call_service(&mut app, req).await;
+            let req = test::TestRequest::default()
+                .method(Method::from_bytes(b"MADEUP").unwrap())
+                .uri("/test-metrics/42")
+                .to_request();
+            test::call_service(&mut app, req).await;
+
+            let text = gather();
+            assert!(text.contains("result=\"ok\",route=\"/test-metrics/{id}\",status=\"200\""));
+            assert!(text.contains("result=\"error\",route=\"/test-metrics-error\",status=\"200\""));
+            assert!(!text.contains("/test-metrics/42"));
+            assert!(text.contains("method=\"other\""));
+            assert!(!text.contains("MADEUP"));
+        });
+    }
+}
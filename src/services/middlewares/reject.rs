@@ -3,7 +3,8 @@ use crate::ipset;
 use crate::models::CommonError;
 use actix_service::{Service, Transform};
 use actix_web::dev::{ServiceRequest, ServiceResponse};
-use actix_web::{Error, HttpResponse};
+use actix_web::http::StatusCode;
+use actix_web::Error;
 use futures::future::{ok, Either, Ready};
 use std::net::Ipv4Addr;
 use std::str::FromStr;
@@ -77,10 +78,7 @@ where
                 return Either::Left(self.service.call(req));
             }
         }
-        return Either::Right(ok(req.into_response(
-            HttpResponse::Forbidden()
-                .json(ApiError::new(CommonError::AddrNotSupported))
-                .into_body(),
-        )));
+        let response = ApiError::new(CommonError::AddrNotSupported).response(StatusCode::FORBIDDEN);
+        return Either::Right(ok(req.into_response(response.into_body())));
     }
 }
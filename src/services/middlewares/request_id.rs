@@ -0,0 +1,128 @@
+//! Tag each request with an id, so that a client error can be correlated with server logs and
+//! agent round-trips. The id comes from the request header if the client or the gateway sets one,
+//! or is generated, and is echoed back in the response header.
+
+use crate::bridge::TRACE_ID;
+use actix_http::http::header::{HeaderName, HeaderValue};
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::{Error, HttpMessage};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use std::task::{Context, Poll};
+
+/// Max length of id accepted from request header.
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+/// Id of current request, stored in request extensions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestId(pub String);
+
+/// Use the id from the header if it's sane, or generate a new one.
+fn request_id_from(header: Option<&HeaderValue>) -> RequestId {
+    let id = header
+        .and_then(|x| x.to_str().ok())
+        .filter(|x| !x.is_empty() && x.len() <= MAX_REQUEST_ID_LEN)
+        .map(ToString::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    RequestId(id)
+}
+
+pub struct RequestIdHeader {
+    header: HeaderName,
+}
+
+impl RequestIdHeader {
+    pub fn new(header: &str) -> Self {
+        Self {
+            header: HeaderName::from_bytes(header.as_bytes()).expect("Invalid request id header name"),
+        }
+    }
+}
+
+impl<S, B> Transform<S> for RequestIdHeader
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestIdMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestIdMiddleware {
+            service,
+            header: self.header.clone(),
+        })
+    }
+}
+
+pub struct RequestIdMiddleware<S> {
+    service: S,
+    header: HeaderName,
+}
+
+impl<S, B> Service for RequestIdMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let request_id = request_id_from(req.headers().get(&self.header));
+        let header = self.header.clone();
+
+        req.extensions_mut().insert(request_id.clone());
+        // Requests to agents in handlers are traced with the id.
+        let fut = TRACE_ID.scope(request_id.0.clone(), self.service.call(req));
+
+        Box::pin(async move {
+            let mut res = fut.await?;
+
+            if let Ok(value) = HeaderValue::from_str(&request_id.0) {
+                res.headers_mut().insert(header, value);
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::{test, web, App, HttpResponse};
+
+    #[test]
+    pub fn test_request_id() {
+        actix_web::rt::System::new("test").block_on(async move {
+            let mut app =
+                test::init_service(App::new().wrap(RequestIdHeader::new("X-Request-Id")).route(
+                    "/",
+                    // Visible to the code in handlers.
+                    web::get().to(|| HttpResponse::Ok().body(TRACE_ID.with(Clone::clone))),
+                ))
+                .await;
+
+            let req = test::TestRequest::with_uri("/")
+                .header("X-Request-Id", "from-gateway")
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.headers().get("X-Request-Id").unwrap(), "from-gateway");
+            assert_eq!(test::read_body(res).await, "from-gateway");
+
+            let res = test::call_service(&mut app, test::TestRequest::with_uri("/").to_request()).await;
+            let id = res.headers().get("X-Request-Id").unwrap().to_str().unwrap();
+            assert!(uuid::Uuid::parse_str(id).is_ok());
+        });
+    }
+}
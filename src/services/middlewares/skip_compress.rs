@@ -0,0 +1,170 @@
+//! Skip response compression for content which is compressed already, like images and archives,
+//! and for small responses, so that `Compress` doesn't waste CPU on them. `Compress` chooses the
+//! encoding by `Accept-Encoding` of the request. This middleware must be wrapped inside `Compress`.
+
+use actix_http::http::header::{ContentEncoding, CONTENT_TYPE};
+use actix_service::{Service, Transform};
+use actix_web::dev::{BodyEncoding, BodySize, MessageBody, ServiceRequest, ServiceResponse};
+use actix_web::Error;
+use futures::future::{ok, LocalBoxFuture, Ready};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+pub struct SkipCompressed {
+    /// Content type prefixes which are not compressed again.
+    types: Arc<Vec<String>>,
+    /// Responses smaller than this size in bytes are not compressed.
+    min_size: u64,
+}
+
+impl SkipCompressed {
+    pub fn new(types: &[String], min_size: u64) -> Self {
+        Self {
+            types: Arc::new(types.to_vec()),
+            min_size,
+        }
+    }
+}
+
+/// Whether the content type is in the list of compressed types.
+pub fn is_compressed(content_type: &str, types: &[String]) -> bool {
+    let content_type = content_type.trim().to_ascii_lowercase();
+
+    types.iter().any(|x| content_type.starts_with(x.as_str()))
+}
+
+/// Whether the body is known to be smaller than `min_size`. Streams of unknown size are compressed.
+pub fn is_small(size: BodySize, min_size: u64) -> bool {
+    match size {
+        BodySize::None | BodySize::Empty => true,
+        BodySize::Sized(n) => n < min_size,
+        BodySize::Stream => false,
+    }
+}
+
+impl<S, B> Transform<S> for SkipCompressed
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = SkipCompressedMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(SkipCompressedMiddleware {
+            service,
+            types: self.types.clone(),
+            min_size: self.min_size,
+        })
+    }
+}
+
+pub struct SkipCompressedMiddleware<S> {
+    service: S,
+    types: Arc<Vec<String>>,
+    min_size: u64,
+}
+
+impl<S, B> Service for SkipCompressedMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let types = self.types.clone();
+        let min_size = self.min_size;
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let mut res = fut.await?;
+            let compressed = res
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|x| x.to_str().ok())
+                .map(|x| is_compressed(x, &types))
+                .unwrap_or(false);
+            let small = is_small(res.response().body().size(), min_size);
+
+            // `Compress` keeps the response as it is with identity encoding.
+            if compressed || small {
+                res.response_mut().encoding(ContentEncoding::Identity);
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_http::http::header::{ACCEPT_ENCODING, CONTENT_ENCODING};
+    use actix_web::middleware::Compress;
+    use actix_web::{test, web, App, HttpResponse};
+
+    #[test]
+    pub fn test_skip_compressed() {
+        let types = vec!["image/jpeg".to_string()];
+        let body = "a".repeat(4096);
+
+        actix_web::rt::System::new("test").block_on(async move {
+            let jpeg = body.clone();
+            let mut app = test::init_service(
+                App::new()
+                    .wrap(SkipCompressed::new(&types, 1024))
+                    .wrap(Compress::default())
+                    .route(
+                        "/a.jpg",
+                        web::get().to(move || {
+                            HttpResponse::Ok().content_type("image/jpeg").body(jpeg.clone())
+                        }),
+                    )
+                    .route(
+                        "/a.txt",
+                        web::get().to(move || {
+                            HttpResponse::Ok().content_type("text/plain").body(body.clone())
+                        }),
+                    )
+                    .route(
+                        "/small.txt",
+                        web::get().to(|| HttpResponse::Ok().content_type("text/plain").body("small")),
+                    ),
+            )
+            .await;
+
+            let get = |path| {
+                test::TestRequest::get()
+                    .uri(path)
+                    .header(ACCEPT_ENCODING, "gzip")
+                    .to_request()
+            };
+            let res = test::call_service(&mut app, get("/a.jpg")).await;
+            assert!(!res.headers().contains_key(CONTENT_ENCODING));
+
+            let res = test::call_service(&mut app, get("/small.txt")).await;
+            assert!(!res.headers().contains_key(CONTENT_ENCODING));
+            assert_eq!(test::read_body(res).await, "small");
+
+            let res = test::call_service(&mut app, get("/a.txt")).await;
+            assert_eq!(res.headers().get(CONTENT_ENCODING).unwrap(), "gzip");
+            // Not compressed unless accepted.
+            let req = test::TestRequest::get().uri("/a.txt").to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert!(!res.headers().contains_key(CONTENT_ENCODING));
+        });
+    }
+}
@@ -1,3 +1,5 @@
+use crate::error::ApiError;
+use crate::models::PageView;
 use serde::Serialize;
 
 /// Common response type for Kite http server.
@@ -7,12 +9,34 @@ pub struct ApiResponse<T = ()> {
     code: u16,
     #[serde(skip_serializing_if = "Option::is_none")]
     data: Option<T>,
+    /// Message of the error, which clients may show to users.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    msg: Option<String>,
 }
 
 impl ApiResponse<()> {
     /// Response a success code but with no payload.
     pub fn empty() -> Self {
-        ApiResponse { code: 0, data: None }
+        ApiResponse {
+            code: 0,
+            data: None,
+            msg: None,
+        }
+    }
+
+    /// Response an error code and its message.
+    pub fn error(code: u16, msg: Option<String>) -> Self {
+        ApiResponse {
+            code,
+            data: None,
+            msg,
+        }
+    }
+}
+
+impl From<&ApiError> for ApiResponse<()> {
+    fn from(e: &ApiError) -> Self {
+        Self::error(e.code, e.localized_msg())
     }
 }
 
@@ -25,10 +49,47 @@ impl<T> ApiResponse<T> {
         ApiResponse {
             code: 0,
             data: Some(data),
+            msg: None,
         }
     }
 }
 
+/// Data of one page in list interfaces.
+#[derive(Debug, Serialize)]
+pub struct PagedResponse<T> {
+    /// Items in this page.
+    pub items: Vec<T>,
+    /// Page index, starts from 1.
+    pub index: u16,
+    /// Max item count per page.
+    pub count: u16,
+    /// Total item count.
+    pub total: i64,
+}
+
+impl<T> PagedResponse<T> {
+    /// Make a page from items queried with the page parameters.
+    pub fn new(items: Vec<T>, page: &PageView, max_count: u16, total: i64) -> Self {
+        Self {
+            items,
+            index: page.index(),
+            count: page.count(max_count),
+            total,
+        }
+    }
+
+    /// Take a page from all items.
+    pub fn paginate(all: Vec<T>, page: &PageView, max_count: u16) -> Self {
+        let total = all.len() as i64;
+        let items = all
+            .into_iter()
+            .skip(page.offset(max_count) as usize)
+            .take(page.count(max_count) as usize)
+            .collect();
+        Self::new(items, page, max_count, total)
+    }
+}
+
 impl<T> ToString for ApiResponse<T>
 where
     T: Serialize,
@@ -41,3 +102,47 @@ where
         String::from("Critical: Could not serialize error message.")
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::user::UserError;
+    use crate::models::CommonError;
+    use actix_web::body::{Body, ResponseBody};
+    use actix_web::http::StatusCode;
+    use actix_web::{HttpResponse, ResponseError};
+    use serde_json::{json, Value};
+
+    fn json_of(response: &HttpResponse) -> Value {
+        match response.body() {
+            ResponseBody::Body(Body::Bytes(bytes)) => serde_json::from_slice(bytes).unwrap(),
+            _ => Value::Null,
+        }
+    }
+
+    #[test]
+    pub fn test_error_shape() {
+        let response = ApiError::new(CommonError::Forbidden).error_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(json_of(&response), json!({"code": 5, "msg": "请求的权限不足"}));
+
+        // Module errors are rendered as the same shape.
+        let response = UserError::NoSuchUser.error_response();
+        let body = json_of(&response);
+        assert_eq!(body["code"], json!(UserError::NoSuchUser as u16));
+        assert_eq!(body["msg"], json!(UserError::NoSuchUser.to_string()));
+        assert!(body.get("data").is_none());
+
+        let response = CommonError::TooManyRequests.error_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(json_of(&response)["code"], json!(12));
+    }
+
+    #[test]
+    pub fn test_normal_shape() {
+        let body = serde_json::to_value(ApiResponse::normal(42)).unwrap();
+        assert_eq!(body, json!({"code": 0, "data": 42}));
+        let body = serde_json::to_value(ApiResponse::empty()).unwrap();
+        assert_eq!(body, json!({"code": 0}));
+    }
+}
@@ -0,0 +1,106 @@
+//! This module reads the client version from request header, so that handlers can serialize
+//! the response shape which the client knows.
+
+use actix_http::{Error, Payload, PayloadStream};
+use actix_web::{FromRequest, HttpRequest};
+use futures::future::{ok, Ready};
+use serde::Serialize;
+
+/// Header field which carries client version like "1.2.0".
+const CLIENT_VERSION_HEADER: &str = "X-Client-Version";
+
+/// Client version, in the form of (major, minor, patch).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ClientVersion(pub u16, pub u16, pub u16);
+
+impl ClientVersion {
+    /// Version used when the header is missing or invalid. Clients which don't send it are taken as
+    /// the oldest ones, so the legacy shape is returned.
+    pub const OLDEST: ClientVersion = ClientVersion(0, 0, 0);
+
+    /// Parse version string like "1.2.0" or "1.2". Missing parts are regarded as 0.
+    pub fn parse(version: &str) -> Option<Self> {
+        let mut parts = version.trim().split('.').map(|x| x.parse::<u16>());
+
+        let major = parts.next()?.ok()?;
+        let minor = parts.next().unwrap_or(Ok(0)).ok()?;
+        let patch = parts.next().unwrap_or(Ok(0)).ok()?;
+        Some(ClientVersion(major, minor, patch))
+    }
+}
+
+impl FromRequest for ClientVersion {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload<PayloadStream>) -> Self::Future {
+        let version = req
+            .headers()
+            .get(CLIENT_VERSION_HEADER)
+            .and_then(|x| x.to_str().ok())
+            .and_then(ClientVersion::parse)
+            .unwrap_or(ClientVersion::OLDEST);
+        ok(version)
+    }
+}
+
+/// Response data which has a legacy shape for older clients.
+pub trait Versioned: Serialize + Sized {
+    /// Response shape for older clients.
+    type Legacy: Serialize + From<Self>;
+    /// Clients before this version receive the legacy shape.
+    const SINCE: ClientVersion;
+
+    /// Convert the data to the shape of the given client version.
+    fn for_version(self, version: ClientVersion) -> VersionedBody<Self, Self::Legacy> {
+        if version < Self::SINCE {
+            VersionedBody::Legacy(self.into())
+        } else {
+            VersionedBody::Full(self)
+        }
+    }
+}
+
+/// Response data in full or legacy shape.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum VersionedBody<T, L> {
+    Full(T),
+    Legacy(L),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    pub fn test_parse_client_version() {
+        assert_eq!(ClientVersion::parse("1.2.3"), Some(ClientVersion(1, 2, 3)));
+        assert_eq!(ClientVersion::parse("1.2"), Some(ClientVersion(1, 2, 0)));
+        assert_eq!(ClientVersion::parse("a.b"), None);
+        assert!(ClientVersion(0, 9, 0) < ClientVersion(1, 0, 0));
+    }
+
+    #[test]
+    pub fn test_default_client_version() {
+        actix_web::rt::System::new("test").block_on(async {
+            let req = TestRequest::default().to_http_request();
+            assert_eq!(ClientVersion::extract(&req).await.unwrap(), ClientVersion::OLDEST);
+
+            let req = TestRequest::default()
+                .header(CLIENT_VERSION_HEADER, "next")
+                .to_http_request();
+            assert_eq!(ClientVersion::extract(&req).await.unwrap(), ClientVersion::OLDEST);
+
+            let req = TestRequest::default()
+                .header(CLIENT_VERSION_HEADER, "0.8.1")
+                .to_http_request();
+            assert_eq!(
+                ClientVersion::extract(&req).await.unwrap(),
+                ClientVersion(0, 8, 1)
+            );
+        });
+    }
+}
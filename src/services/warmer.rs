@@ -0,0 +1,44 @@
+//! Refresh cached scores and events requested most before they expire, so that users mostly hit
+//! warm data instead of waiting for agents or the database.
+
+use super::AppState;
+use crate::error::ApiError;
+use crate::metrics::CACHE_KEYS_WARMED;
+use crate::models::edu::{fetch_scores, EduError};
+use crate::models::event::Event;
+use crate::models::user::Identity;
+use log::{debug, info};
+use std::time::Duration;
+
+/// Warm caches every `interval`, refreshing at most `count` keys of each cache, until the process
+/// exits.
+pub async fn warm_caches(state: AppState, interval: Duration, count: usize) {
+    info!("Warming caches every {:?}.", interval);
+    // Keys which would expire before the next round, with a round to spare for slow agents.
+    let within = interval * 2;
+
+    loop {
+        tokio::time::delay_for(interval).await;
+
+        let (pool, host) = (&state.pool, &state.host);
+        let fetch = |student_id: String| {
+            let (pool, agents) = (pool.clone(), state.agents.clone());
+            async move {
+                let secret = Identity::get_oa_secret(&pool, &student_id)
+                    .await?
+                    .ok_or_else(|| ApiError::new(EduError::OaAccountNeeded))?;
+                fetch_scores(agents, student_id, secret).await
+            }
+        };
+        let scores = state.scores.warm(count, within, || host.is_idle(), fetch).await;
+        let events = state.events.warm(count, within, |id| Event::get(pool, id)).await;
+
+        CACHE_KEYS_WARMED
+            .with_label_values(&["scores"])
+            .inc_by(scores as u64);
+        CACHE_KEYS_WARMED
+            .with_label_values(&["events"])
+            .inc_by(events as u64);
+        debug!("Warmed {} scores and {} events.", scores, events);
+    }
+}